@@ -0,0 +1,83 @@
+//! Boot a large synthetic task tree, with and without trace logging, and
+//! read its task files with lazily and eagerly parsed command lines.
+//! Compare changes with `cargo bench --bench boot -- --save-baseline before`
+//! and `--baseline before` afterwards.
+
+use alfad::{
+    config::{self, payload::Payload, TaskConfig},
+    task::{self, TaskState},
+};
+use criterion::{criterion_group, criterion_main, Criterion, SamplingMode};
+use futures::future::join_all;
+use std::{fs, io, time::Duration};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt, prelude::*, reload};
+
+const TASKS: usize = 1000;
+
+/// Markers in a binary tree, every task waits for its parent
+fn tasks() -> Vec<TaskConfig> {
+    (0..TASKS)
+        .map(|i| {
+            let mut config = TaskConfig::new(format!("task-{i}"));
+            config.payload = Payload::Marker;
+            if i > 0 {
+                config.after(&format!("task-{}", (i - 1) / 2));
+            }
+            config
+        })
+        .collect()
+}
+
+/// Every boot leaks its tasks like init does, about 1 KiB per task, so the
+/// time per benchmark is kept short
+fn boot(c: &mut Criterion) {
+    let (filter, levels) = reload::Layer::new(LevelFilter::OFF);
+    tracing_subscriber::registry().with(filter).with(fmt::layer().with_writer(io::sink)).init();
+
+    let mut group = c.benchmark_group("boot");
+    group
+        .sampling_mode(SamplingMode::Flat)
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(1));
+    for level in [LevelFilter::OFF, LevelFilter::TRACE] {
+        levels.reload(level).unwrap();
+        group.bench_function(format!("{TASKS} tasks, log {level}"), |b| {
+            b.iter(|| {
+                let tasks = task::start(tasks());
+                let done = tasks.0.values().map(|task| task.wait_until(TaskState::has_concluded));
+                smol::block_on(join_all(done));
+            })
+        });
+    }
+    levels.reload(LevelFilter::OFF).unwrap();
+    group.finish();
+}
+
+/// Task files of the same tree, every task runs a few command lines
+fn task_files() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for i in 0..TASKS {
+        let after = if i > 0 { format!("after: task-{}\n", (i - 1) / 2) } else { String::new() };
+        let cmd = "  :-env -i PATH=/bin true\n  sh -c 'echo \"$HOME\" > /dev/null'\n  ip link set lo up\n";
+        fs::write(dir.path().join(format!("task-{i}.task")), format!("name: task-{i}\ncmd: |\n{cmd}{after}")).unwrap();
+    }
+    dir
+}
+
+/// Command lines are parsed when a task runs first, alfad-compile parses
+/// them all up front
+fn read(c: &mut Criterion) {
+    let dir = task_files();
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10).warm_up_time(Duration::from_millis(500)).measurement_time(Duration::from_secs(2));
+    group.bench_function(format!("{TASKS} tasks, lazy"), |b| b.iter(|| config::read_yaml_configs(dir.path(), Vec::new())));
+    group.bench_function(format!("{TASKS} tasks, eager"), |b| {
+        b.iter(|| config::parse_payloads(config::read_yaml_configs(dir.path(), Vec::new())))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, boot, read);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+//! Embeds the commit alfad is built from, reported by `alfad-ctl version`,
+//! and when it was built, the earliest time the system clock can be right
+
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=ALFAD_GIT_HASH");
+    // Builds from a release tarball have no repository, packagers can set it
+    let hash = env::var("ALFAD_GIT_HASH").ok().or_else(git_hash).unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=ALFAD_GIT_HASH={hash}");
+
+    // Reproducible builds set it to the time of the last change
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let built = env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse().ok()).unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default()
+    });
+    println!("cargo:rustc-env=ALFAD_BUILD_TIME={built}");
+}
+
+fn git_hash() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+    let hash = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    for path in ["../.git/HEAD", "../.git/refs"].into_iter().filter(|path| Path::new(path).exists()) {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    (output.status.success() && !hash.is_empty()).then_some(hash)
+}
@@ -1,13 +1,23 @@
-use crate::def::{APLT_COMPILE, APLT_CTL, APLT_INIT, APLT_MAIN};
+use crate::{
+    adopt::AdoptError,
+    applet,
+    config::{InvalidRespawn, Respawn},
+    def::APLT_MAIN,
+    inhibit::{self, InhibitMode, InhibitWhat, DEFAULT_TTL},
+    protocol::ErrorKind,
+    task::SignalError,
+};
 use clap::{Parser, ValueEnum};
 use std::{
     fmt::{Debug, Display},
     str::FromStr,
+    time::Duration,
 };
 use strum::{Display, EnumIter};
 use thiserror::Error;
 
 #[derive(Debug, Parser)]
+#[command(after_help = crate::client::EXIT_CODES)]
 pub enum Action {
     /// Kill a task
     Kill {
@@ -15,6 +25,9 @@ pub enum Action {
         #[clap(long)]
         /// Send SIGKILL instead of SIGTERM
         force: bool,
+        #[clap(long)]
+        /// Also act on a task marked as protected
+        force_protected: bool,
     },
     /// Kill a task and prevent respawn
     Deactivate {
@@ -22,27 +35,223 @@ pub enum Action {
         #[clap(long)]
         /// Send SIGKILL instead of SIGTERM
         force: bool,
+        #[clap(long)]
+        /// Also act on a task marked as protected
+        force_protected: bool,
+    },
+    /// Stop a task, or all members of a group, and keep it down until it
+    /// is started again
+    Stop {
+        task: String,
+        #[clap(long)]
+        /// Send SIGKILL instead of SIGTERM
+        force: bool,
+    },
+    /// Like stop, but also across reboots if persist_disabled is set
+    Disable {
+        task: String,
+        #[clap(long)]
+        /// Send SIGKILL instead of SIGTERM
+        force: bool,
     },
-    /// Start a task
+    /// Allow a stopped or disabled task to run again, without starting it
+    Enable { task: String },
+    /// Start a task, or all members of a group
     Start {
         task: String,
         #[clap(long)]
         /// Ignore conditions and start immediately
         force: bool,
     },
+    /// Change how often a task is respawned until alfad restarts, e.g. "no"
+    /// or "retry:5", and count its attempts from 0 again
+    SetRespawn { task: String, policy: Respawn },
+    /// Supervise a process started outside of alfad as the task, if it
+    /// matches the task's `adopt` pattern
+    Adopt { task: String, pid: i32 },
     /// Restart a task
     Restart {
         task: String,
         #[clap(long)]
         /// Ignore conditions and restart immediately
         force: bool,
+        #[clap(long)]
+        /// Also act on a task marked as protected
+        force_protected: bool,
+    },
+    /// Restart a task if it is running, otherwise do nothing
+    #[clap(alias = "condrestart")]
+    TryRestart { task: String },
+    /// Start a task, or all members of a group, unless it is disabled
+    CondStart { task: String },
+    /// Stop all tasks and power off the machine
+    Poweroff {
+        #[clap(long)]
+        /// Delay, e.g. "+5m", "+30s" or "now"
+        when: Option<Delay>,
+        #[clap(long)]
+        /// Ignore inhibitors that block it
+        force: bool,
+    },
+    /// Reboot the machine
+    Reboot {
+        #[clap(long)]
+        /// Delay, e.g. "+5m", "+30s" or "now"
+        when: Option<Delay>,
+        #[clap(long)]
+        /// Ignore inhibitors that block it
+        force: bool,
+    },
+    /// Halt the machine
+    Halt {
+        #[clap(long)]
+        /// Delay, e.g. "+5m", "+30s" or "now"
+        when: Option<Delay>,
+        #[clap(long)]
+        /// Ignore inhibitors that block it
+        force: bool,
     },
     System {
         command: SystemCommand,
+        #[clap(long)]
+        /// Delay, e.g. "+5m", "+30s" or "now"
+        when: Option<Delay>,
+        #[clap(long)]
+        /// Ignore inhibitors that block it
+        force: bool,
+    },
+    /// Show or cancel a scheduled poweroff, reboot or halt
+    Shutdown {
+        #[clap(long)]
+        /// Cancel the scheduled command
+        cancel: bool,
+    },
+    /// Hold off poweroff, reboot and halt, prints the id of the lease
+    Inhibit {
+        #[clap(value_enum)]
+        what: InhibitWhat,
+        /// Name of the holder, e.g. "backup"
+        #[clap(value_parser = inhibit::holder_name)]
+        who: String,
+        /// Shown to whoever wants to shut down
+        why: String,
+        #[clap(long, value_enum, default_value_t)]
+        /// Delay the shutdown for a while, or refuse it unless forced
+        mode: InhibitMode,
+        #[clap(long)]
+        /// Time until the lease expires, e.g. "+2h", 5 minutes by default
+        ttl: Option<Delay>,
+    },
+    /// Release a lease taken with inhibit
+    Uninhibit { id: u64 },
+    /// Show the leases that hold off a shutdown
+    Inhibitors,
+    /// Report the state of a service sd_notify style, e.g.
+    /// "TIME_SYNCHRONIZED=1" from an NTP client. Unknown variables are ignored.
+    Notify {
+        #[clap(required = true, value_parser = assignment)]
+        assignments: Vec<String>,
+    },
+    /// Reset the boot counter, as if the boot completed successfully
+    MarkBootGood,
+    /// Show the effective configuration of a task
+    Cat { task: String },
+    /// Show how often the state of a task changed and was waited for, and
+    /// how long changes waited for its lock
+    #[cfg(feature = "diagnostics")]
+    Diag { task: String },
+    /// Compare the task files with the running configuration and stage the
+    /// changes, they are only applied when confirmed with --apply
+    #[command(group = clap::ArgGroup::new("applying").args(["apply", "apply_now"]))]
+    Reload {
+        #[clap(long)]
+        /// Apply the changes staged by the last reload, if it was recent
+        apply: bool,
+        #[clap(long)]
+        /// Apply the changes in the task files without staging them
+        apply_now: bool,
+        #[clap(long, requires = "applying")]
+        /// Restart the changed tasks that are running, instead of applying
+        /// their changes once they are started again
+        restart_changed: bool,
+    },
+    /// Show what the client and the daemon were built from
+    Version,
+    /// Look for tasks in states they should not be in, leftover state files
+    /// and task files that changed since the boot
+    Doctor {
+        #[clap(long)]
+        /// Print JSON instead of text
+        json: bool,
+    },
+    /// Show all tasks with their state
+    List {
+        #[clap(long)]
+        /// Print JSON instead of a table
+        json: bool,
+        #[clap(long, value_enum, default_value_t)]
+        /// Color the table, by default only if stdout is a terminal
+        color: ColorChoice,
+        #[clap(long, short)]
+        /// Show the command line each task is running
+        verbose: bool,
     },
 }
 
-#[derive(Parser, Debug, Clone, ValueEnum, Display, EnumIter)]
+/// When the client colors its output, the daemon never does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Time until a system command is performed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delay(pub Duration);
+
+impl FromStr for Delay {
+    type Err = ActionError;
+
+    /// Accepts "now", "+N" (minutes, like shutdown(8)) or "+N" followed by
+    /// one of the units s, m or h.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "now" {
+            return Ok(Self(Duration::ZERO));
+        }
+        let error = || ActionError::InvalidDelay(s.to_owned());
+        let s = s.strip_prefix('+').ok_or_else(error)?;
+        let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(index) => s.split_at(index),
+            None => (s, "m"),
+        };
+        let number: u64 = number.parse().map_err(|_| error())?;
+        let seconds = match unit {
+            "s" => Some(number),
+            "m" => number.checked_mul(60),
+            "h" => number.checked_mul(3600),
+            _ => None,
+        };
+        Ok(Self(Duration::from_secs(seconds.ok_or_else(error)?)))
+    }
+}
+
+impl Display for Delay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "+{}s", self.0.as_secs())
+    }
+}
+
+/// A single `VARIABLE=value` for notify, without whitespace
+fn assignment(word: &str) -> Result<String, String> {
+    match word.split_once('=') {
+        Some((variable, _)) if !variable.is_empty() && !word.contains(char::is_whitespace) => Ok(word.to_owned()),
+        _ => Err(format!("'{word}' is not VARIABLE=value")),
+    }
+}
+
+#[derive(Parser, Debug, Clone, PartialEq, Eq, ValueEnum, Display, EnumIter)]
 #[strum(serialize_all = "snake_case")]
 pub enum SystemCommand {
     Poweroff,
@@ -55,25 +264,108 @@ impl FromStr for Action {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let c = if let Some((action, payload)) = s.split_once(' ') {
             let task = payload.to_owned();
+            // Only for the actions refused on protected tasks, before force-
+            if let Some(action) = action.strip_prefix("protected-") {
+                let (action, force) = action.strip_prefix("force-").map_or((action, false), |action| (action, true));
+                return match action {
+                    "kill" => Ok(Action::Kill { task, force, force_protected: true }),
+                    "deactivate" => Ok(Action::Deactivate { task, force, force_protected: true }),
+                    "restart" => Ok(Action::Restart { task, force, force_protected: true }),
+                    _ => Err(ActionError::ActionNotFound(s.to_owned())),
+                };
+            }
             match action {
-                "kill" => Action::Kill { task, force: false },
-                "force-kill" => Action::Kill { task, force: true },
-                "deactivate" => Action::Kill { task, force: false },
-                "force-deactivate" => Action::Kill { task, force: true },
-                "restart" => Action::Restart { task, force: false },
-                "force-restart" => Action::Restart { task, force: true },
+                "kill" => Action::Kill { task, force: false, force_protected: false },
+                "force-kill" => Action::Kill { task, force: true, force_protected: false },
+                "stop" => Action::Stop { task, force: false },
+                "force-stop" => Action::Stop { task, force: true },
+                "disable" => Action::Disable { task, force: false },
+                "force-disable" => Action::Disable { task, force: true },
+                "enable" => Action::Enable { task },
+                "deactivate" => Action::Deactivate { task, force: false, force_protected: false },
+                "force-deactivate" => Action::Deactivate { task, force: true, force_protected: false },
+                "restart" => Action::Restart { task, force: false, force_protected: false },
+                "force-restart" => Action::Restart { task, force: true, force_protected: false },
                 "start" => Action::Start { task, force: false },
                 "force-start" => Action::Start { task, force: true },
-                "system" => Action::System {
-                    command: match payload {
-                        "poweroff" => SystemCommand::Poweroff,
-                        "restart" => SystemCommand::Restart,
-                        "halt" => SystemCommand::Halt,
-                        _ => return Err(ActionError::ActionNotFound(s.to_owned())),
-                    },
-                },
+                "try-restart" => Action::TryRestart { task },
+                "cond-start" => Action::CondStart { task },
+                "system" | "force-system" => {
+                    let (command, when) = match payload.split_once(' ') {
+                        Some((command, when)) => (command, Some(when.parse()?)),
+                        None => (payload, None),
+                    };
+                    Action::System {
+                        command: match command {
+                            "poweroff" => SystemCommand::Poweroff,
+                            "restart" => SystemCommand::Restart,
+                            "halt" => SystemCommand::Halt,
+                            _ => return Err(ActionError::ActionNotFound(s.to_owned())),
+                        },
+                        when,
+                        force: action == "force-system",
+                    }
+                }
+                "shutdown" if payload == "cancel" => Action::Shutdown { cancel: true },
+                "set-respawn" => {
+                    let (task, policy) = payload.rsplit_once(' ').ok_or_else(|| ActionError::SyntaxError(s.to_owned()))?;
+                    Action::SetRespawn { task: task.to_owned(), policy: policy.parse()? }
+                }
+                "adopt" => {
+                    let (task, pid) = payload.rsplit_once(' ').ok_or_else(|| ActionError::SyntaxError(s.to_owned()))?;
+                    let pid = pid.parse().map_err(|_| ActionError::SyntaxError(s.to_owned()))?;
+                    Action::Adopt { task: task.to_owned(), pid }
+                }
+                "inhibit" => {
+                    let syntax_error = || ActionError::SyntaxError(s.to_owned());
+                    let mut words = payload.splitn(5, ' ');
+                    let mut word = || words.next().ok_or_else(syntax_error);
+                    let what = InhibitWhat::from_str(word()?, false).map_err(|_| syntax_error())?;
+                    let mode = InhibitMode::from_str(word()?, false).map_err(|_| syntax_error())?;
+                    let ttl = Some(word()?.parse()?);
+                    let who = word()?.to_owned();
+                    let why = word().unwrap_or_default().to_owned();
+                    Action::Inhibit { what, who, why, mode, ttl }
+                }
+                "uninhibit" => {
+                    let id = payload.parse().map_err(|_| ActionError::SyntaxError(s.to_owned()))?;
+                    Action::Uninhibit { id }
+                }
+                "notify" => {
+                    let assignments = payload.split(' ').map(assignment).collect::<Result<_, _>>();
+                    Action::Notify { assignments: assignments.map_err(|_| ActionError::SyntaxError(s.to_owned()))? }
+                }
+                "reload" => {
+                    let (apply, restart_changed) = match payload.strip_suffix(" restart-changed") {
+                        Some(apply) => (apply, true),
+                        None => (payload, false),
+                    };
+                    match apply {
+                        "apply" => Action::Reload { apply: true, apply_now: false, restart_changed },
+                        "apply-now" => Action::Reload { apply: false, apply_now: true, restart_changed },
+                        _ => return Err(ActionError::SyntaxError(s.to_owned())),
+                    }
+                }
+                "cat" => Action::Cat { task },
+                #[cfg(feature = "diagnostics")]
+                "diag" => Action::Diag { task },
                 _ => return Err(ActionError::ActionNotFound(s.to_owned())),
             }
+        } else if s == "shutdown" {
+            Action::Shutdown { cancel: false }
+        } else if s == "inhibitors" {
+            Action::Inhibitors
+        } else if s == "mark-boot-good" {
+            Action::MarkBootGood
+        } else if s == "version" {
+            Action::Version
+        } else if s == "reload" {
+            Action::Reload { apply: false, apply_now: false, restart_changed: false }
+        } else if s == "doctor" {
+            Action::Doctor { json: false }
+        } else if s == "list" {
+            // The output format is up to the client, the daemon always sends JSON
+            Action::List { json: false, color: ColorChoice::Auto, verbose: false }
         } else {
             return Err(ActionError::SyntaxError(s.to_owned()));
         };
@@ -84,38 +376,114 @@ impl FromStr for Action {
 impl Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Action::Kill { task, force } => {
+            Action::Kill { task, force, force_protected } => {
+                if *force_protected {
+                    f.write_str("protected-")?;
+                }
                 if *force {
                     f.write_str("force-")?;
                 }
                 f.write_str("kill ")?;
                 f.write_str(task)
             }
-            Action::Deactivate { task, force } => {
+            Action::Deactivate { task, force, force_protected } => {
+                if *force_protected {
+                    f.write_str("protected-")?;
+                }
                 if *force {
                     f.write_str("force-")?;
                 }
                 f.write_str("deactivate ")?;
                 f.write_str(task)
             }
+            Action::Stop { task, force } => {
+                if *force {
+                    f.write_str("force-")?;
+                }
+                f.write_str("stop ")?;
+                f.write_str(task)
+            }
+            Action::Disable { task, force } => {
+                if *force {
+                    f.write_str("force-")?;
+                }
+                f.write_str("disable ")?;
+                f.write_str(task)
+            }
+            Action::Enable { task } => write!(f, "enable {task}"),
+            Action::SetRespawn { task, policy } => write!(f, "set-respawn {task} {policy}"),
+            Action::Adopt { task, pid } => write!(f, "adopt {task} {pid}"),
             Action::Start { task, force } => {
                 if *force {
                     f.write_str("force-")?;
                 }
-                f.write_str("start")?;
+                f.write_str("start ")?;
                 f.write_str(task)
             }
-            Action::Restart { task, force } => {
+            Action::Restart { task, force, force_protected } => {
+                if *force_protected {
+                    f.write_str("protected-")?;
+                }
                 if *force {
                     f.write_str("force-")?;
                 }
                 f.write_str("restart ")?;
                 f.write_str(task)
             }
-            Action::System { command } => {
+            Action::TryRestart { task } => write!(f, "try-restart {task}"),
+            Action::CondStart { task } => write!(f, "cond-start {task}"),
+            Action::Poweroff { when, force } => {
+                Display::fmt(&Action::System { command: SystemCommand::Poweroff, when: *when, force: *force }, f)
+            }
+            Action::Reboot { when, force } => {
+                Display::fmt(&Action::System { command: SystemCommand::Restart, when: *when, force: *force }, f)
+            }
+            Action::Halt { when, force } => {
+                Display::fmt(&Action::System { command: SystemCommand::Halt, when: *when, force: *force }, f)
+            }
+            Action::System { command, when, force } => {
+                if *force {
+                    f.write_str("force-")?;
+                }
                 f.write_str("system ")?;
-                Display::fmt(command, f)
+                Display::fmt(command, f)?;
+                if let Some(when) = when {
+                    write!(f, " {when}")?;
+                }
+                Ok(())
+            }
+            Action::Shutdown { cancel } => {
+                f.write_str("shutdown")?;
+                if *cancel {
+                    f.write_str(" cancel")?;
+                }
+                Ok(())
             }
+            Action::Inhibit { what, who, why, mode, ttl } => {
+                write!(f, "inhibit {what} {mode} {} {who} {why}", ttl.unwrap_or(Delay(DEFAULT_TTL)))
+            }
+            Action::Uninhibit { id } => write!(f, "uninhibit {id}"),
+            Action::Inhibitors => f.write_str("inhibitors"),
+            Action::Notify { assignments } => write!(f, "notify {}", assignments.join(" ")),
+            Action::MarkBootGood => f.write_str("mark-boot-good"),
+            Action::Cat { task } => write!(f, "cat {task}"),
+            #[cfg(feature = "diagnostics")]
+            Action::Diag { task } => write!(f, "diag {task}"),
+            Action::Version => f.write_str("version"),
+            Action::Reload { apply, apply_now, restart_changed } => {
+                f.write_str("reload")?;
+                if *apply {
+                    f.write_str(" apply")?;
+                } else if *apply_now {
+                    f.write_str(" apply-now")?;
+                }
+                if *restart_changed {
+                    f.write_str(" restart-changed")?;
+                }
+                Ok(())
+            }
+            Action::Doctor { .. } => f.write_str("doctor"),
+            Action::List { .. } => f.write_str("list"),
         }
     }
 }
@@ -128,21 +496,242 @@ pub enum ActionError {
     #[error("Unknown action '{}'", .0)]
     ActionNotFound(String),
 
-    #[error("Task does not exist '{}'", .0)]
-    TaskNotFound(String),
+    #[error(
+        "Task does not exist '{}'{}",
+        .0,
+        .1.as_ref().map(|x| format!(", did you mean '{x}'?")).unwrap_or_default()
+    )]
+    TaskNotFound(String, Option<String>),
+
+    #[error("Invalid delay '{}', expected \"now\" or something like \"+5m\"", .0)]
+    InvalidDelay(String),
+
+    #[error(transparent)]
+    InvalidRespawn(#[from] InvalidRespawn),
+
+    #[error(transparent)]
+    Signal(#[from] SignalError),
+
+    #[error("{} did not stop within {:?}, try again with --force", .0, .1)]
+    NotStopped(String, Duration),
+
+    #[error(transparent)]
+    Adopt(#[from] AdoptError),
+
+    #[error("{} is inhibited by {}, try again with --force", .0, .1.join("; "))]
+    Inhibited(SystemCommand, Vec<String>),
+
+    #[error("{} is protected, try again with --force-protected to {} it anyway", .0, .1)]
+    Protected(String, &'static str),
+
+    #[error("There is no inhibitor lease #{}", .0)]
+    NoLease(u64),
+
+    #[error("Boot counting is not configured")]
+    NoBootCounter,
+
+    #[error("No reload staged within the last {}s, run reload first", .0.as_secs())]
+    NothingStaged(Duration),
+
+    #[error("Could not update the boot counter: {}", .0)]
+    BootCounter(#[from] std::io::Error),
+
+    #[error("Could not remember the disabled tasks: {}", .0)]
+    Persist(std::io::Error),
 
     #[error(
         "Do not call this binary directly as {:?}! Name or link to an applet expected instead.
 The following applets are available:
 
-  - {}
-  - {}
-  - {}
-",
+{}",
         APLT_MAIN,
-        APLT_INIT,
-        APLT_CTL,
-        APLT_COMPILE
+        applet::list()
     )]
     MainAppletCalled,
 }
+
+/// What the client is told went wrong
+impl From<&ActionError> for ErrorKind {
+    fn from(error: &ActionError) -> Self {
+        match error {
+            ActionError::SyntaxError(_)
+            | ActionError::ActionNotFound(_)
+            | ActionError::InvalidDelay(_)
+            | ActionError::InvalidRespawn(_)
+            | ActionError::MainAppletCalled => ErrorKind::Usage,
+            ActionError::TaskNotFound(..) => ErrorKind::TaskNotFound,
+            ActionError::NotStopped(..) => ErrorKind::Timeout,
+            ActionError::Signal(_)
+            | ActionError::Adopt(_)
+            | ActionError::Inhibited(..)
+            | ActionError::Protected(..)
+            | ActionError::NoLease(_)
+            | ActionError::NoBootCounter
+            | ActionError::NothingStaged(_)
+            | ActionError::BootCounter(_)
+            | ActionError::Persist(_) => ErrorKind::Failed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, ActionError, ColorChoice, Delay, SystemCommand};
+    use crate::protocol::ErrorKind;
+    use crate::config::Respawn;
+    use clap::Parser;
+    use std::{str::FromStr, time::Duration};
+
+    fn round_trip(action: Action) -> String {
+        let line = action.to_string();
+        let parsed = Action::from_str(&line).unwrap();
+        assert_eq!(parsed.to_string(), line);
+        line
+    }
+
+    #[test]
+    fn delay_parsing() {
+        assert_eq!("now".parse::<Delay>().unwrap(), Delay(Duration::ZERO));
+        assert_eq!("+5".parse::<Delay>().unwrap(), Delay(Duration::from_secs(300)));
+        assert_eq!("+5m".parse::<Delay>().unwrap(), Delay(Duration::from_secs(300)));
+        assert_eq!("+30s".parse::<Delay>().unwrap(), Delay(Duration::from_secs(30)));
+        assert_eq!("+2h".parse::<Delay>().unwrap(), Delay(Duration::from_secs(7200)));
+        for invalid in ["5m", "+", "+m", "+5d", "+-5", "+99999999999999999999h", "later"] {
+            invalid.parse::<Delay>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn aliases_serialize_like_system() {
+        let when = Some(Delay(Duration::from_secs(300)));
+        assert_eq!(round_trip(Action::Poweroff { when: None, force: false }), "system poweroff");
+        assert_eq!(round_trip(Action::Reboot { when: None, force: true }), "force-system restart");
+        assert_eq!(round_trip(Action::Halt { when, force: false }), "system halt +300s");
+        let system = Action::System { command: SystemCommand::Poweroff, when, force: true };
+        assert_eq!(round_trip(system), "force-system poweroff +300s");
+    }
+
+    #[test]
+    fn shutdown_round_trip() {
+        assert_eq!(round_trip(Action::Shutdown { cancel: false }), "shutdown");
+        assert_eq!(round_trip(Action::Shutdown { cancel: true }), "shutdown cancel");
+        assert_eq!(round_trip(Action::List { json: true, color: ColorChoice::Never, verbose: true }), "list");
+        assert_eq!(round_trip(Action::Cat { task: "foo".into() }), "cat foo");
+        #[cfg(feature = "diagnostics")]
+        assert_eq!(round_trip(Action::Diag { task: "foo".into() }), "diag foo");
+        assert_eq!(round_trip(Action::MarkBootGood), "mark-boot-good");
+        assert_eq!(round_trip(Action::Version), "version");
+        let reload = |apply, apply_now, restart_changed| Action::Reload { apply, apply_now, restart_changed };
+        assert_eq!(round_trip(reload(false, false, false)), "reload");
+        assert_eq!(round_trip(reload(true, false, false)), "reload apply");
+        assert_eq!(round_trip(reload(false, true, true)), "reload apply-now restart-changed");
+        for invalid in ["reload restart-changed", "reload apply later", "reload apply restart-changed now"] {
+            Action::from_str(invalid).unwrap_err();
+        }
+        assert_eq!(round_trip(Action::Doctor { json: true }), "doctor");
+    }
+
+    #[test]
+    fn task_actions_round_trip() {
+        let task = || "foo".to_owned();
+        for force in [false, true] {
+            let prefix = if force { "force-" } else { "" };
+            for force_protected in [false, true] {
+                let prefix = if force_protected { format!("protected-{prefix}") } else { prefix.to_owned() };
+                let kill = Action::Kill { task: task(), force, force_protected };
+                assert_eq!(round_trip(kill), format!("{prefix}kill foo"));
+                let deactivate = Action::Deactivate { task: task(), force, force_protected };
+                assert_eq!(round_trip(deactivate), format!("{prefix}deactivate foo"));
+                let restart = Action::Restart { task: task(), force, force_protected };
+                assert_eq!(round_trip(restart), format!("{prefix}restart foo"));
+            }
+            assert_eq!(round_trip(Action::Start { task: task(), force }), format!("{prefix}start foo"));
+            assert_eq!(round_trip(Action::Stop { task: task(), force }), format!("{prefix}stop foo"));
+            assert_eq!(round_trip(Action::Disable { task: task(), force }), format!("{prefix}disable foo"));
+        }
+        assert_eq!(round_trip(Action::Enable { task: task() }), "enable foo");
+        assert_eq!(round_trip(Action::TryRestart { task: task() }), "try-restart foo");
+        assert_eq!(Action::parse_from(["alfad-ctl", "condrestart", "foo"]).to_string(), "try-restart foo");
+        let action = Action::parse_from(["alfad-ctl", "kill", "builtin::ctl::daemon", "--force", "--force-protected"]);
+        assert_eq!(action.to_string(), "protected-force-kill builtin::ctl::daemon");
+        for invalid in ["protected-start foo", "protected-force-stop foo", "force-protected-kill foo"] {
+            Action::from_str(invalid).unwrap_err();
+        }
+        assert_eq!(round_trip(Action::CondStart { task: task() }), "cond-start foo");
+        assert_eq!(round_trip(Action::Adopt { task: task(), pid: 42 }), "adopt foo 42");
+        assert_eq!(Action::parse_from(["alfad-ctl", "adopt", "foo", "42"]).to_string(), "adopt foo 42");
+        for invalid in ["adopt foo", "adopt foo bar", "adopt foo -"] {
+            Action::from_str(invalid).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn set_respawn() {
+        let set = |policy| Action::SetRespawn { task: "foo".to_owned(), policy };
+        assert_eq!(round_trip(set(Respawn::No)), "set-respawn foo no");
+        assert_eq!(round_trip(set(Respawn::Retry(5))), "set-respawn foo retry:5");
+        let action = Action::parse_from(["alfad-ctl", "set-respawn", "foo", "retry:3"]);
+        assert_eq!(action.to_string(), "set-respawn foo retry:3");
+        for invalid in ["set-respawn foo", "set-respawn foo yes", "set-respawn foo retry:", "set-respawn foo retry:-1"] {
+            Action::from_str(invalid).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn cli_aliases() {
+        let action = Action::parse_from(["alfad-ctl", "poweroff", "--when", "+5m"]);
+        assert_eq!(action.to_string(), "system poweroff +300s");
+        let action = Action::parse_from(["alfad-ctl", "system", "restart"]);
+        assert_eq!(action.to_string(), "system restart");
+        let action = Action::parse_from(["alfad-ctl", "shutdown", "--cancel"]);
+        assert_eq!(action.to_string(), "shutdown cancel");
+        let action = Action::parse_from(["alfad-ctl", "reboot", "--force"]);
+        assert_eq!(action.to_string(), "force-system restart");
+        let action = Action::parse_from(["alfad-ctl", "reload", "--apply", "--restart-changed"]);
+        assert_eq!(action.to_string(), "reload apply restart-changed");
+        Action::try_parse_from(["alfad-ctl", "reload", "--apply", "--apply-now"]).unwrap_err();
+        Action::try_parse_from(["alfad-ctl", "reload", "--restart-changed"]).unwrap_err();
+    }
+
+    #[test]
+    fn inhibitors() {
+        let action = Action::parse_from(["alfad-ctl", "inhibit", "shutdown", "backup", "Nightly backup", "--mode", "block"]);
+        assert_eq!(round_trip(action), "inhibit shutdown block +300s backup Nightly backup");
+        let action = Action::parse_from(["alfad-ctl", "inhibit", "shutdown", "upgrade", "", "--ttl", "+2h"]);
+        assert_eq!(action.to_string(), "inhibit shutdown delay +7200s upgrade ");
+        let trimmed = Action::from_str("inhibit shutdown delay +7200s upgrade");
+        assert!(matches!(trimmed, Ok(Action::Inhibit { why, .. }) if why.is_empty()));
+        Action::try_parse_from(["alfad-ctl", "inhibit", "shutdown", "nightly backup", "why"]).unwrap_err();
+        for invalid in ["inhibit shutdown delay", "inhibit boot delay +5s who why", "inhibit shutdown never +5s who why"] {
+            Action::from_str(invalid).unwrap_err();
+        }
+
+        assert_eq!(round_trip(Action::Uninhibit { id: 3 }), "uninhibit 3");
+        Action::from_str("uninhibit three").unwrap_err();
+        assert_eq!(round_trip(Action::Inhibitors), "inhibitors");
+    }
+
+    #[test]
+    fn notify() {
+        let action = Action::parse_from(["alfad-ctl", "notify", "TIME_SYNCHRONIZED=1", "STATUS=synced"]);
+        assert_eq!(round_trip(action), "notify TIME_SYNCHRONIZED=1 STATUS=synced");
+        Action::try_parse_from(["alfad-ctl", "notify"]).unwrap_err();
+        Action::try_parse_from(["alfad-ctl", "notify", "STATUS=in sync"]).unwrap_err();
+        for invalid in ["notify SYNCHRONIZED", "notify =1", "notify A=1  B=2"] {
+            Action::from_str(invalid).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        let kind = |s: &str| ErrorKind::from(&Action::from_str(s).unwrap_err());
+        assert_eq!(kind("frobnicate"), ErrorKind::Usage);
+        assert_eq!(kind("frobnicate foo"), ErrorKind::Usage);
+        assert_eq!(kind("system poweroff later"), ErrorKind::Usage);
+        assert_eq!(kind("set-respawn foo yes"), ErrorKind::Usage);
+        assert_eq!(ErrorKind::from(&ActionError::TaskNotFound("foo".into(), None)), ErrorKind::TaskNotFound);
+        assert_eq!(ErrorKind::from(&ActionError::NotStopped("foo".into(), Duration::from_secs(5))), ErrorKind::Timeout);
+        assert_eq!(ErrorKind::from(&ActionError::NoBootCounter), ErrorKind::Failed);
+        assert_eq!(ErrorKind::from(&ActionError::Protected("foo".into(), "kill")), ErrorKind::Failed);
+    }
+}
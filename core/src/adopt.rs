@@ -0,0 +1,237 @@
+use crate::{
+    config::Adopt,
+    task::{ChildProcess, ExitReason, ProcFs, ProcessTable, TaskContext, TaskState},
+};
+use nix::{
+    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    unistd::Pid,
+};
+use regex::Regex;
+use std::{fs, ops::ControlFlow, path::Path, time::Duration};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+/// How often an adopted process is looked at. Usually it is not a child of
+/// alfad, so there is nothing to wait for.
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AdoptError {
+    #[error("{} does not adopt processes, it has no adopt pattern", .0)]
+    NotAllowed(String),
+    #[error("{} is running already", .0)]
+    Running(String),
+    #[error("There is no process {}", .0)]
+    NoProcess(i32),
+    #[error("Process {pid} runs '{cmdline}', which does not match '{pattern}'")]
+    Mismatch { pid: i32, cmdline: String, pattern: String },
+    #[error("Invalid adopt pattern: {}", .0)]
+    InvalidPattern(String),
+}
+
+/// Whether `adopt` allows taking over `pid`
+pub fn check(adopt: &Adopt, pid: i32, processes: &dyn ProcessTable) -> Result<ChildProcess, AdoptError> {
+    let start_time = Some(pid)
+        .filter(|pid| *pid > 0 && !processes.is_zombie(*pid))
+        .and_then(|pid| processes.start_time(pid))
+        .ok_or(AdoptError::NoProcess(pid))?;
+    let pattern = Regex::new(&adopt.pattern).map_err(|error| AdoptError::InvalidPattern(error.to_string()))?;
+    let cmdline = processes.cmdline(pid).unwrap_or_default();
+    if !pattern.is_match(&cmdline) {
+        return Err(AdoptError::Mismatch { pid, cmdline, pattern: adopt.pattern.clone() });
+    }
+    Ok(ChildProcess { pid, start_time: Some(start_time) })
+}
+
+/// Let `context` supervise `pid` instead of starting its payload the next
+/// time it would
+pub fn hand_over(context: &TaskContext, pid: i32) -> Result<(), AdoptError> {
+    hand_over_with(context, pid, &ProcFs)
+}
+
+fn hand_over_with(context: &TaskContext, pid: i32, processes: &dyn ProcessTable) -> Result<(), AdoptError> {
+    let name = &context.config.name;
+    let adopt = context.config.adopt.as_ref().ok_or_else(|| AdoptError::NotAllowed(name.clone()))?;
+    let state = context.state_now();
+    if state.is_running() || state == TaskState::Terminating {
+        return Err(AdoptError::Running(name.clone()));
+    }
+    context.adoptee.set(Some(check(adopt, pid, processes)?));
+    Ok(())
+}
+
+fn read_pidfile(path: &Path) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// The process `context` is about to supervise instead of starting its
+/// payload: one handed over by alfad-ctl, or the one in its pidfile
+pub fn pending(context: &TaskContext) -> Option<ChildProcess> {
+    pending_with(context, &ProcFs)
+}
+
+fn pending_with(context: &TaskContext, processes: &dyn ProcessTable) -> Option<ChildProcess> {
+    let name = &context.config.name;
+    if let Some(child) = context.adoptee.get() {
+        context.adoptee.set(None);
+        if child.is_alive(processes) {
+            return Some(child);
+        }
+        warn!(task = name, pid = child.pid, "Exited before it could be adopted");
+    }
+    let adopt = context.config.adopt.as_ref()?;
+    let pidfile = adopt.pidfile.as_deref()?;
+    let pid = read_pidfile(pidfile)?;
+    check(adopt, pid, processes).map_err(|error| debug!(task = name, ?pidfile, %error, "Not adopting")).ok()
+}
+
+/// Supervise an adopted process until it exits. Only processes orphaned
+/// while alfad runs as pid 1 can be reaped, the exit status of all others
+/// is unknown and counts as failure.
+pub async fn watch(context: &TaskContext, child: ChildProcess) -> ControlFlow<TaskState> {
+    watch_with(context, child, &ProcFs).await
+}
+
+async fn watch_with(context: &TaskContext, child: ChildProcess, processes: &dyn ProcessTable) -> ControlFlow<TaskState> {
+    let name = &context.config.name;
+    info!(task = name, pid = child.pid, "Adopted");
+    context.child.set(Some(child));
+    let status = loop {
+        if !child.is_alive(processes) {
+            break None;
+        }
+        if processes.is_zombie(child.pid) {
+            break waitpid(Pid::from_raw(child.pid), Some(WaitPidFlag::WNOHANG)).ok();
+        }
+        smol::Timer::after(POLL).await;
+    };
+    context.child.set(None);
+    info!(task = name, pid = child.pid, ?status, "Adopted process exited");
+    ControlFlow::Break(TaskState::Concluded(match status {
+        Some(WaitStatus::Exited(_, 0)) => ExitReason::Done,
+        _ => ExitReason::Failed,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, hand_over_with, pending_with, watch_with, AdoptError};
+    use crate::{
+        config::{Adopt, TaskConfig},
+        task::{ChildProcess, ExitReason, ProcessTable, TaskContext, TaskState},
+    };
+    use std::{
+        collections::HashMap,
+        fs,
+        ops::ControlFlow,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    /// Processes by pid, with start time and command line
+    #[derive(Default)]
+    struct FakeProcesses {
+        processes: HashMap<i32, (u64, &'static str)>,
+        zombie: AtomicBool,
+    }
+
+    impl ProcessTable for FakeProcesses {
+        fn start_time(&self, pid: i32) -> Option<u64> {
+            self.processes.get(&pid).map(|(start_time, _)| *start_time)
+        }
+
+        fn cmdline(&self, pid: i32) -> Option<String> {
+            self.processes.get(&pid).map(|(_, cmdline)| cmdline.to_string())
+        }
+
+        fn is_zombie(&self, _: i32) -> bool {
+            self.zombie.load(Ordering::Relaxed)
+        }
+
+        fn children(&self, _: i32) -> Vec<i32> {
+            Vec::new()
+        }
+    }
+
+    fn processes() -> FakeProcesses {
+        FakeProcesses { processes: HashMap::from([(42, (7, "/usr/sbin/sshd -D")), (43, (8, "sshd-session"))]), ..Default::default() }
+    }
+
+    fn adopt(pattern: &str) -> Adopt {
+        Adopt { pattern: pattern.into(), pidfile: None }
+    }
+
+    fn task(adopt: Option<Adopt>) -> TaskContext {
+        TaskContext::new(TaskConfig { adopt, ..TaskConfig::new("sshd".into()) })
+    }
+
+    #[test]
+    fn safety_checks() {
+        let processes = processes();
+        assert_eq!(check(&adopt("sshd -D$"), 42, &processes), Ok(ChildProcess { pid: 42, start_time: Some(7) }));
+        assert_eq!(
+            check(&adopt("sshd -D$"), 43, &processes),
+            Err(AdoptError::Mismatch { pid: 43, cmdline: "sshd-session".into(), pattern: "sshd -D$".into() })
+        );
+        assert_eq!(check(&adopt(".*"), 44, &processes), Err(AdoptError::NoProcess(44)));
+        assert_eq!(check(&adopt(".*"), -1, &processes), Err(AdoptError::NoProcess(-1)));
+        assert!(matches!(check(&adopt("("), 42, &processes), Err(AdoptError::InvalidPattern(_))));
+        processes.zombie.store(true, Ordering::Relaxed);
+        assert_eq!(check(&adopt(".*"), 42, &processes), Err(AdoptError::NoProcess(42)));
+    }
+
+    #[test]
+    fn hand_over() {
+        let processes = processes();
+        assert_eq!(hand_over_with(&task(None), 42, &processes), Err(AdoptError::NotAllowed("sshd".into())));
+
+        let context = task(Some(adopt("sshd")));
+        smol::block_on(context.update_state(TaskState::Running(0)));
+        assert_eq!(hand_over_with(&context, 42, &processes), Err(AdoptError::Running("sshd".into())));
+        smol::block_on(context.update_state(TaskState::Concluded(ExitReason::Failed)));
+        hand_over_with(&context, 42, &processes).unwrap();
+
+        // Taken once, then the payload starts again
+        assert_eq!(pending_with(&context, &processes).map(|child| child.pid), Some(42));
+        assert_eq!(pending_with(&context, &processes), None);
+
+        // Gone in the meantime
+        hand_over_with(&context, 42, &processes).unwrap();
+        assert_eq!(pending_with(&context, &FakeProcesses::default()), None);
+    }
+
+    #[test]
+    fn pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sshd.pid");
+        let processes = processes();
+        let context = task(Some(Adopt { pidfile: Some(path.clone()), ..adopt("sshd -D") }));
+        assert_eq!(pending_with(&context, &processes), None);
+
+        fs::write(&path, "42\n").unwrap();
+        assert_eq!(pending_with(&context, &processes).map(|child| child.pid), Some(42));
+        // Recycled by something else
+        fs::write(&path, "43\n").unwrap();
+        assert_eq!(pending_with(&context, &processes), None);
+        fs::write(&path, "garbage").unwrap();
+        assert_eq!(pending_with(&context, &processes), None);
+    }
+
+    #[test]
+    fn watch_until_exit() {
+        let processes = processes();
+        let context = task(Some(adopt("sshd")));
+        let child = ChildProcess { pid: 42, start_time: Some(7) };
+        smol::block_on(async {
+            let watch = watch_with(&context, child, &processes);
+            let exit = async {
+                smol::Timer::after(super::POLL * 2).await;
+                assert_eq!(context.child.get(), Some(child));
+                // Not a child of this process, so not reaped
+                processes.zombie.store(true, Ordering::Relaxed);
+            };
+            let (flow, ()) = futures::join!(watch, exit);
+            assert_eq!(flow, ControlFlow::Break(TaskState::Concluded(ExitReason::Failed)));
+        });
+        assert_eq!(context.child.get(), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! The boot report of a machine that is not at hand, from its event log,
+//! see [`crate::event_log`]. The failure summary and the startup report
+//! come from the same code alfad logs them with once the boot is complete.
+
+use crate::{
+    builtin::boot::{dependents, Summary, BOOT_COMPLETE},
+    config::TaskConfig,
+    event_log::CacheEvent,
+    graph::{timelines, StartupReport},
+    task::{StateEvent, TaskState},
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    fs,
+    path::Path,
+    time::Duration,
+};
+
+/// The events of an event log, in the order they were written
+#[derive(Debug, Default)]
+pub struct Events {
+    pub states: Vec<StateEvent>,
+    pub caches: Vec<CacheEvent>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    State(StateEvent),
+    Cache(CacheEvent),
+}
+
+/// The events in an event log, one JSON object per line. The last line may
+/// have been cut short by a crash and is left out then.
+pub fn parse_events(text: &str) -> Result<Events> {
+    let lines: Vec<_> = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()).collect();
+    let cut_short = !text.ends_with('\n');
+    let mut events = Events::default();
+    for (i, (number, line)) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(Line::State(event)) => events.states.push(event),
+            Ok(Line::Cache(event)) => events.caches.push(event),
+            Err(_) if cut_short && i + 1 == lines.len() => {}
+            Err(error) => return Err(error).with_context(|| format!("Line {} is no state change or cache write", number + 1)),
+        }
+    }
+    Ok(events)
+}
+
+pub fn read_events(path: &Path) -> Result<Events> {
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    parse_events(&text).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// What an event log tells about the boot
+#[derive(Debug)]
+pub struct Analysis<'a> {
+    /// After the first state change, `None` if the log ends before the boot
+    /// was complete
+    pub complete: Option<Duration>,
+    pub summary: Summary<'a>,
+    pub report: StartupReport,
+    /// The last regeneration of the task cache
+    pub cache: Option<&'a CacheEvent>,
+}
+
+/// The boot in `events`, until target::boot-complete concluded. Only
+/// `configs` tell who waited for whom, without them nobody is blamed for
+/// the wait of others and tasks waiting for the boot to complete count as
+/// still waiting.
+pub fn analyze<'a>(events: &'a Events, configs: &'a [TaskConfig]) -> Analysis<'a> {
+    let cache = events.caches.last();
+    let events = &events.states;
+    let start = events.iter().map(|event| event.at).min();
+    let end = events
+        .iter()
+        .find(|event| event.task == BOOT_COMPLETE && matches!(event.state, TaskState::Concluded(_)))
+        .map(|event| event.at);
+    let boot: Vec<_> = events.iter().filter(|event| end.is_none_or(|end| event.at <= end)).collect();
+
+    let skip = dependents(BOOT_COMPLETE, configs.iter());
+    let states: BTreeMap<&str, TaskState> = boot.iter().map(|event| (event.task.as_str(), event.state)).collect();
+    let mut summary = Summary::default();
+    for (name, state) in states.iter().filter(|(name, _)| !skip.contains(*name)) {
+        summary.add(name, *state);
+    }
+
+    let known: HashSet<_> = configs.iter().map(|config| config.name.as_str()).collect();
+    let unknown: Vec<_> =
+        states.keys().filter(|name| !known.contains(*name)).map(|name| TaskConfig::new(name.to_string())).collect();
+    let boot: Vec<_> = boot.into_iter().cloned().collect();
+    let report = StartupReport::new(configs.iter().chain(&unknown), &timelines(&boot));
+
+    let complete = start.zip(end).map(|(start, end)| end.duration_since(start).unwrap_or_default());
+    Analysis { complete, summary, report, cache }
+}
+
+impl Display for Analysis<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.complete {
+            Some(after) => write!(f, "Boot complete after {after:.1?}: {}", self.summary.counts())?,
+            None => write!(f, "The log ends before the boot was complete: {}", self.summary.counts())?,
+        }
+        if let Some(failures) = self.summary.failures() {
+            write!(f, "\n{failures}")?;
+        }
+        if !self.summary.waiting.is_empty() {
+            write!(f, "\nTasks still waiting: {}", self.summary.waiting.join(", "))?;
+        }
+        if !self.report.is_empty() {
+            write!(f, "\n{}", self.report)?;
+        }
+        if let Some(cache) = self.cache {
+            write!(f, "\nTask cache: {cache}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{analyze, parse_events, read_events};
+    use crate::config::TaskConfig;
+    use std::path::Path;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/events");
+
+    /// udev and network, getty after mount
+    fn configs() -> Vec<TaskConfig> {
+        let mut network = TaskConfig::new("network".into());
+        network.after("udev");
+        let mut getty = TaskConfig::new("getty".into());
+        getty.after("mount");
+        vec![TaskConfig::new("udev".into()), TaskConfig::new("mount".into()), network, getty]
+    }
+
+    #[test]
+    fn boot_report() {
+        let events = read_events(&Path::new(FIXTURES).join("boot.log")).unwrap();
+        let configs = configs();
+        assert_eq!(
+            analyze(&events, &configs).to_string(),
+            "Boot complete after 3.5s: 2 concluded, 1 running, 1 failed, 0 still waiting
+1 task(s) did not complete:
+  network  Failed       alfad-ctl cat network
+Run `alfad-ctl list` to see the state of all tasks
+Slowest tasks: udev 2.0s, network 1.0s, mount 500.0ms, target::boot-complete 500.0ms
+Longest waited for: udev 2.0s, mount 500.0ms
+Task cache: /etc/alfad/alfad.bin written for the next boot, 4 tasks, 212 bytes"
+        );
+
+        // Without the task files there is nobody to blame
+        let analysis = analyze(&events, &[]);
+        assert_eq!(analysis.summary.counts(), "2 concluded, 1 running, 1 failed, 0 still waiting");
+        assert!(analysis.report.delaying.is_empty());
+        assert_eq!(analysis.report.slowest.len(), 4);
+    }
+
+    #[test]
+    fn incomplete_boot() {
+        let events = read_events(&Path::new(FIXTURES).join("crashed.log")).unwrap();
+        assert_eq!(events.states.len(), 9);
+        let configs = configs();
+        assert_eq!(
+            analyze(&events, &configs).to_string(),
+            "The log ends before the boot was complete: 1 concluded, 2 running, 0 failed, 1 still waiting
+Tasks still waiting: network
+Slowest tasks: mount 500.0ms
+Longest waited for: mount 500.0ms"
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let line = r#"{"task":"udev","state":"Waiting","at":{"secs_since_epoch":1,"nanos_since_epoch":0}}"#;
+        assert_eq!(parse_events(&format!("{line}\n\n{line}\n")).unwrap().states.len(), 2);
+        let error = parse_events(&format!("{line}\nnot json\n{line}\n")).unwrap_err();
+        assert_eq!(error.to_string(), "Line 2 is no state change or cache write");
+        // Only a last line without its newline may be cut short
+        parse_events(&format!("{line}\n{{\"task\":\"ud\n")).unwrap_err();
+        assert_eq!(parse_events(&format!("{line}\n{{\"task\":\"ud")).unwrap().states.len(), 1);
+
+        let cache = r#"{"cache":"alfad.bin","at":{"secs_since_epoch":2,"nanos_since_epoch":0},"outcome":{"Err":"denied"}}"#;
+        let events = parse_events(&format!("{line}\n{cache}\n")).unwrap();
+        assert_eq!((events.states.len(), events.caches.len()), (1, 1));
+        assert_eq!(events.caches[0].outcome, Err("denied".into()));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    action::{ActionError, SystemCommand},
+    def::{
+        APLT_CHECK, APLT_COMPILE, APLT_CTL, APLT_HALT, APLT_INIT, APLT_INSTALL, APLT_MAIN, APLT_POWEROFF, APLT_REBOOT,
+        APLT_RUN, APLT_SHUTDOWN, APLT_USER_SESSION,
+    },
+};
+use std::path::Path;
+use strum::{EnumIter, IntoEnumIterator};
+
+/// Everything the alfad binary can be called as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum Applet {
+    Init,
+    Ctl,
+    Compile,
+    Check,
+    Poweroff,
+    Reboot,
+    Halt,
+    Shutdown,
+    Install,
+    Run,
+    UserSession,
+}
+
+impl Applet {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Applet::Init => APLT_INIT,
+            Applet::Ctl => APLT_CTL,
+            Applet::Compile => APLT_COMPILE,
+            Applet::Check => APLT_CHECK,
+            Applet::Poweroff => APLT_POWEROFF,
+            Applet::Reboot => APLT_REBOOT,
+            Applet::Halt => APLT_HALT,
+            Applet::Shutdown => APLT_SHUTDOWN,
+            Applet::Install => APLT_INSTALL,
+            Applet::Run => APLT_RUN,
+            Applet::UserSession => APLT_USER_SESSION,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Applet::Init => "Start the init system (run as PID 1)",
+            Applet::Ctl => "Send commands to the running init system",
+            Applet::Compile => "Byte-compile the configuration into a cache file",
+            Applet::Check => "Validate the configuration without booting",
+            Applet::Poweroff => "Stop all tasks and power off the machine",
+            Applet::Reboot => "Reboot the machine",
+            Applet::Halt => "Halt the machine",
+            Applet::Shutdown => "Power off (-h/-P), reboot (-r) or halt (-H) the machine now",
+            Applet::Install => "Create (or remove) the links for all applets",
+            Applet::Run => "Run a single task file in the foreground",
+            Applet::UserSession => "Supervise the services of a login session",
+        }
+    }
+
+    /// Directory (relative to the install prefix) the applet is linked into,
+    /// `None` for applets that only work as `alfad <applet>`.
+    pub fn install_dir(&self) -> Option<&'static str> {
+        match self {
+            Applet::Init | Applet::Poweroff | Applet::Reboot | Applet::Halt | Applet::Shutdown => Some("sbin"),
+            Applet::Ctl | Applet::Compile | Applet::Check => Some("usr/bin"),
+            Applet::Install | Applet::Run | Applet::UserSession => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::iter().find(|applet| applet.name() == name)
+    }
+
+    /// Applets that can be called through a link named after them
+    pub fn linked() -> impl Iterator<Item = Self> {
+        Self::iter().filter(|applet| applet.install_dir().is_some())
+    }
+}
+
+/// Translate the arguments of the poweroff, reboot, halt and shutdown applets
+/// (without the applet name itself) into the system command to send.
+///
+/// Only the "now" subset of the traditional shutdown syntax is supported.
+pub fn system_command(applet: Applet, args: &[String]) -> Result<SystemCommand, ActionError> {
+    let syntax_error = || ActionError::SyntaxError(format!("{} {}", applet.name(), args.join(" ")));
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let command = match (applet, args.as_slice()) {
+        (Applet::Poweroff, []) => SystemCommand::Poweroff,
+        (Applet::Reboot, []) => SystemCommand::Restart,
+        (Applet::Halt, []) => SystemCommand::Halt,
+        (Applet::Shutdown, ["now"] | ["-h" | "-P", "now"]) => SystemCommand::Poweroff,
+        (Applet::Shutdown, ["-r", "now"]) => SystemCommand::Restart,
+        (Applet::Shutdown, ["-H", "now"]) => SystemCommand::Halt,
+        _ => return Err(syntax_error()),
+    };
+    Ok(command)
+}
+
+/// What to do, based on the name the binary was called as
+#[derive(Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Run an applet, the arguments start with the applet name
+    Run(Applet, Vec<String>),
+    /// Print all available applets
+    ListApplets,
+    /// Neither a known applet nor called with one as argument
+    Unknown(String),
+}
+
+/// Figure out the applet from `argv[0]`, or from `argv[1]` when called as
+/// the main binary itself (busybox style).
+pub fn dispatch(args: impl IntoIterator<Item = String>) -> Dispatch {
+    let mut args = args.into_iter();
+    let argv0 = args.next().unwrap_or_default();
+    let name = Path::new(&argv0).file_name().and_then(|x| x.to_str()).unwrap_or_default().to_owned();
+
+    if let Some(applet) = Applet::from_name(&name).filter(|applet| applet.install_dir().is_some()) {
+        return Dispatch::Run(applet, [name].into_iter().chain(args).collect());
+    }
+    if name != APLT_MAIN {
+        return Dispatch::Unknown(name);
+    }
+
+    match args.next() {
+        Some(arg) if arg == "--list-applets" => Dispatch::ListApplets,
+        Some(arg) => match Applet::from_name(&arg) {
+            Some(applet) => Dispatch::Run(applet, [arg].into_iter().chain(args).collect()),
+            None => Dispatch::Unknown(arg),
+        },
+        None => Dispatch::Unknown(name),
+    }
+}
+
+/// Table of all applets with their descriptions
+pub fn list() -> String {
+    Applet::iter().map(|applet| format!("  {:<16}{}\n", applet.name(), applet.description())).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dispatch, system_command, Applet, Dispatch};
+    use crate::action::SystemCommand;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn applet_from_argv0() {
+        assert_eq!(dispatch(args(&["init"])), Dispatch::Run(Applet::Init, args(&["init"])));
+        assert_eq!(dispatch(args(&["/sbin/init"])), Dispatch::Run(Applet::Init, args(&["init"])));
+        assert_eq!(
+            dispatch(args(&["/usr/bin/alfad-ctl", "kill", "foo"])),
+            Dispatch::Run(Applet::Ctl, args(&["alfad-ctl", "kill", "foo"]))
+        );
+        assert_eq!(dispatch(args(&["./poweroff"])), Dispatch::Run(Applet::Poweroff, args(&["poweroff"])));
+        assert_eq!(dispatch(args(&["reboot"])), Dispatch::Run(Applet::Reboot, args(&["reboot"])));
+    }
+
+    #[test]
+    fn applet_from_argv1() {
+        assert_eq!(dispatch(args(&["alfad", "halt"])), Dispatch::Run(Applet::Halt, args(&["halt"])));
+        assert_eq!(
+            dispatch(args(&["./alfad", "alfad-ctl", "start", "foo"])),
+            Dispatch::Run(Applet::Ctl, args(&["alfad-ctl", "start", "foo"]))
+        );
+        assert_eq!(
+            dispatch(args(&["/usr/sbin/alfad", "alfad-compile"])),
+            Dispatch::Run(Applet::Compile, args(&["alfad-compile"]))
+        );
+    }
+
+    #[test]
+    fn system_command_translation() {
+        let table = [
+            (Applet::Poweroff, &[][..], SystemCommand::Poweroff),
+            (Applet::Reboot, &[], SystemCommand::Restart),
+            (Applet::Halt, &[], SystemCommand::Halt),
+            (Applet::Shutdown, &["now"], SystemCommand::Poweroff),
+            (Applet::Shutdown, &["-h", "now"], SystemCommand::Poweroff),
+            (Applet::Shutdown, &["-P", "now"], SystemCommand::Poweroff),
+            (Applet::Shutdown, &["-r", "now"], SystemCommand::Restart),
+            (Applet::Shutdown, &["-H", "now"], SystemCommand::Halt),
+        ];
+        for (applet, arguments, expected) in table {
+            let command = system_command(applet, &args(arguments)).unwrap();
+            assert_eq!(command.to_string(), expected.to_string(), "{applet:?} {arguments:?}");
+        }
+    }
+
+    #[test]
+    fn system_command_rejects_unsupported() {
+        system_command(Applet::Poweroff, &args(&["-f"])).unwrap_err();
+        system_command(Applet::Shutdown, &args(&[])).unwrap_err();
+        system_command(Applet::Shutdown, &args(&["-h", "+5"])).unwrap_err();
+        system_command(Applet::Shutdown, &args(&["-r"])).unwrap_err();
+        system_command(Applet::Shutdown, &args(&["-x", "now"])).unwrap_err();
+    }
+
+    #[test]
+    fn list_applets() {
+        assert_eq!(dispatch(args(&["alfad", "--list-applets"])), Dispatch::ListApplets);
+        assert_eq!(dispatch(args(&["./alfad", "--list-applets"])), Dispatch::ListApplets);
+        assert_eq!(
+            dispatch(args(&["poweroff", "--list-applets"])),
+            Dispatch::Run(Applet::Poweroff, args(&["poweroff", "--list-applets"]))
+        );
+    }
+
+    #[test]
+    fn unknown_applets() {
+        assert_eq!(dispatch(args(&["alfad"])), Dispatch::Unknown("alfad".into()));
+        assert_eq!(dispatch(args(&["./alfad"])), Dispatch::Unknown("alfad".into()));
+        assert_eq!(dispatch(args(&["/sbin/powerof"])), Dispatch::Unknown("powerof".into()));
+        assert_eq!(dispatch(args(&["alfad", "alfad"])), Dispatch::Unknown("alfad".into()));
+        assert_eq!(dispatch(args(&["alfad", "nope"])), Dispatch::Unknown("nope".into()));
+        // Would shadow coreutils
+        assert_eq!(dispatch(args(&["/usr/bin/install", "-m", "644"])), Dispatch::Unknown("install".into()));
+        assert_eq!(dispatch(args(&["alfad", "install"])), Dispatch::Run(Applet::Install, args(&["install"])));
+        assert_eq!(dispatch(args(&["/usr/bin/run", "x.task"])), Dispatch::Unknown("run".into()));
+        assert_eq!(dispatch(args(&["alfad", "run", "x.task"])), Dispatch::Run(Applet::Run, args(&["run", "x.task"])));
+        assert_eq!(dispatch(args(&[])), Dispatch::Unknown("".into()));
+    }
+}
@@ -0,0 +1,271 @@
+use super::{
+    bootcount::{counter, mark_good},
+    ctl::ctl_available,
+    IntoConfig,
+};
+use crate::{
+    action::{Delay, SystemCommand},
+    builtin_fn,
+    config::{
+        autogen,
+        defaults::{BootFailurePolicy, Defaults},
+        yaml::TaskConfigYaml,
+        TaskConfig,
+    },
+    desired::DesiredState,
+    graph::{timelines, StartupReport},
+    perform_action::schedule,
+    task::{ContextMap, ExitReason, TaskContext, TaskState},
+    version,
+};
+use anyhow::Result;
+use futures::{future::join_all, select, FutureExt};
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    ops::ControlFlow,
+    process::Stdio,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+pub const BOOT_COMPLETE: &str = "target::boot-complete";
+
+/// How long to wait for the remaining tasks to settle before the boot is
+/// considered complete anyway.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time to intervene before a failed boot restarts the machine
+const REBOOT_GRACE: Duration = Duration::from_secs(30);
+
+const EMERGENCY_SHELL: &str = "/bin/sh";
+
+builtin_fn!(BootComplete: boot_complete);
+
+impl IntoConfig for BootComplete {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml { name: BOOT_COMPLETE.to_string(), cmd: Self::box_fn(), ..Default::default() }
+    }
+}
+
+/// A task has settled once it is not waiting for anything anymore.
+pub fn is_settled(state: &TaskState) -> bool {
+    !matches!(state, TaskState::Created | TaskState::Waiting)
+}
+
+/// Names of all tasks that (transitively) wait for `name` themselves and
+/// therefore can't settle before it.
+pub fn dependents<'a>(name: &'a str, configs: impl Iterator<Item = &'a TaskConfig> + Clone) -> HashSet<&'a str> {
+    let mut found = HashSet::from([name]);
+    loop {
+        let before = found.len();
+        for config in configs.clone() {
+            if config.after.iter().chain(config.with.iter()).any(|dep| found.contains(dep.as_str())) {
+                found.insert(&config.name);
+            }
+        }
+        if found.len() == before {
+            return found;
+        }
+    }
+}
+
+async fn boot_complete(context: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
+    let skip = dependents(&context.config.name, context_map.0.values().map(|task| &*task.config));
+    let waiting = context_map.0.keys().filter(|name| !skip.contains(*name)).map(|name| context_map.wait_until(name, is_settled));
+    select! {
+        _ = join_all(waiting).fuse() => (),
+        _ = smol::Timer::after(BOOT_TIMEOUT).fuse() => warn!("Not all tasks settled within {BOOT_TIMEOUT:?}"),
+    }
+
+    // Tasks that were stopped or disabled on purpose did not fail
+    let mut summary = Summary::default();
+    let tasks = context_map.0.iter().filter(|(name, task)| !skip.contains(*name) && task.desired.get() == DesiredState::Enabled);
+    for (name, task) in tasks {
+        summary.add(name, task.state().await);
+    }
+    summary.log();
+    let report = startup_report(context_map);
+    if !report.is_empty() {
+        info!("{report}");
+    }
+
+    if let Some(counter) = counter().filter(|_| summary.failed.is_empty()) {
+        if let Err(error) = mark_good(counter.as_ref()) {
+            error!("Could not mark the boot as good: {error}");
+        }
+    }
+    if summary.failed.is_empty() {
+        autogen::spawn(version::loaded(), &Defaults::load(), super::all());
+    }
+
+    match reaction(Defaults::load().on_boot_failure, &summary) {
+        Reaction::Nothing => {}
+        Reaction::Shell => emergency_shell().await,
+        Reaction::Reboot(grace) => {
+            error!("Restarting in {}s because of failed tasks, cancel with `alfad-ctl shutdown --cancel`", grace.as_secs());
+            if let Err(error) = schedule(SystemCommand::Restart, Some(Delay(grace)), false, context_map) {
+                error!("Not restarting: {error}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Slowest tasks and what held up others, from the state changes so far.
+/// The boot starts with the first of them.
+fn startup_report(context_map: ContextMap<'_>) -> StartupReport {
+    let events: Vec<_> = context_map.0.values().flat_map(TaskContext::history).collect();
+    StartupReport::new(context_map.0.values().map(|task| &*task.config), &timelines(&events))
+}
+
+/// Consequence of the boot failure policy
+#[derive(Debug, PartialEq, Eq)]
+enum Reaction {
+    Nothing,
+    Shell,
+    Reboot(Duration),
+}
+
+fn reaction(policy: BootFailurePolicy, summary: &Summary) -> Reaction {
+    match policy {
+        _ if summary.failed.is_empty() => Reaction::Nothing,
+        BootFailurePolicy::Ignore => Reaction::Nothing,
+        BootFailurePolicy::Emergency => Reaction::Shell,
+        BootFailurePolicy::Reboot => Reaction::Reboot(REBOOT_GRACE),
+    }
+}
+
+async fn emergency_shell() {
+    error!("Starting an emergency shell, boot continues once it exits");
+    let status = smol::process::Command::new(EMERGENCY_SHELL)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .await;
+    if let Err(error) = status {
+        error!("Could not start {EMERGENCY_SHELL}: {error}");
+    }
+}
+
+/// States of the tasks once the boot is complete
+#[derive(Debug, Default)]
+pub struct Summary<'a> {
+    done: usize,
+    running: usize,
+    pub failed: Vec<(&'a str, ExitReason)>,
+    pub waiting: Vec<&'a str>,
+}
+
+impl<'a> Summary<'a> {
+    pub fn add(&mut self, name: &'a str, state: TaskState) {
+        match state {
+            TaskState::Running(_) => self.running += 1,
+            TaskState::Concluded(reason @ (ExitReason::Failed | ExitReason::Deactivated)) => self.failed.push((name, reason)),
+            TaskState::Concluded(_) => self.done += 1,
+            TaskState::Created | TaskState::Waiting | TaskState::Terminating => self.waiting.push(name),
+        }
+    }
+
+    /// How many tasks are in which state
+    pub fn counts(&self) -> String {
+        format!(
+            "{} concluded, {} running, {} failed, {} still waiting",
+            self.done,
+            self.running,
+            self.failed.len(),
+            self.waiting.len()
+        )
+    }
+
+    fn log(&self) {
+        info!("Boot complete: {}", self.counts());
+        if let Some(failures) = self.failures() {
+            warn!("{failures}");
+        }
+        if !self.waiting.is_empty() {
+            warn!("Tasks still waiting: {}", self.waiting.join(", "));
+        }
+        if !ctl_available() {
+            error!("The control channel is not available, alfad-ctl will not work during this boot");
+        }
+    }
+
+    /// Table of the failed tasks with their exit reasons and where to look next
+    pub fn failures(&self) -> Option<String> {
+        if self.failed.is_empty() {
+            return None;
+        }
+        let mut failed = self.failed.clone();
+        failed.sort_by_key(|(name, _)| *name);
+        let width = failed.iter().map(|(name, _)| name.len()).max().unwrap_or_default();
+        let mut text = format!("{} task(s) did not complete:\n", failed.len());
+        for (name, reason) in failed {
+            let _ = writeln!(text, "  {name:<width$}  {:<11}  alfad-ctl cat {name}", reason.to_string());
+        }
+        text.push_str("Run `alfad-ctl list` to see the state of all tasks");
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dependents, reaction, Reaction, Summary, REBOOT_GRACE};
+    use crate::{
+        config::{defaults::BootFailurePolicy, TaskConfig},
+        task::{ExitReason, TaskState},
+    };
+    use std::collections::HashSet;
+
+    #[test]
+    fn dependents_are_transitive() {
+        let mut a = TaskConfig::new("a".into());
+        a.after("target");
+        let mut b = TaskConfig::new("b".into());
+        b.with.push("a".into());
+        let c = TaskConfig::new("c".into());
+        let configs = [TaskConfig::new("target".into()), a, b, c];
+
+        assert_eq!(dependents("target", configs.iter()), HashSet::from(["target", "a", "b"]));
+    }
+
+    fn summary() -> Summary<'static> {
+        let mut summary = Summary::default();
+        summary.add("mount", TaskState::Concluded(ExitReason::Done));
+        summary.add("getty", TaskState::Running(0));
+        summary.add("network", TaskState::Concluded(ExitReason::Failed));
+        summary.add("dhcp", TaskState::Concluded(ExitReason::Deactivated));
+        summary.add("loop", TaskState::Waiting);
+        summary
+    }
+
+    #[test]
+    fn failure_summary() {
+        assert_eq!(Summary::default().failures(), None);
+        assert_eq!(
+            summary().failures().unwrap(),
+            "2 task(s) did not complete:
+  dhcp     Deactivated  alfad-ctl cat dhcp
+  network  Failed       alfad-ctl cat network
+Run `alfad-ctl list` to see the state of all tasks"
+        );
+    }
+
+    #[test]
+    fn boot_failure_policy() {
+        let clean = Summary::default();
+        for policy in [BootFailurePolicy::Ignore, BootFailurePolicy::Emergency, BootFailurePolicy::Reboot] {
+            assert_eq!(reaction(policy, &clean), Reaction::Nothing);
+        }
+        let failed = summary();
+        assert_eq!(reaction(BootFailurePolicy::Ignore, &failed), Reaction::Nothing);
+        assert_eq!(reaction(BootFailurePolicy::Emergency, &failed), Reaction::Shell);
+        assert_eq!(reaction(BootFailurePolicy::Reboot, &failed), Reaction::Reboot(REBOOT_GRACE));
+
+        // Tasks that are only waiting don't count as failed
+        let mut waiting = Summary::default();
+        waiting.add("loop", TaskState::Waiting);
+        assert_eq!(reaction(BootFailurePolicy::Reboot, &waiting), Reaction::Nothing);
+    }
+}
@@ -0,0 +1,149 @@
+use super::{Backoff, IntoConfig};
+use crate::{
+    builtin_fn,
+    config::{defaults::Defaults, yaml::TaskConfigYaml},
+    task::{ContextMap, TaskContext, TaskState},
+};
+use anyhow::Result;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    ops::ControlFlow,
+    path::PathBuf,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Storage of the number of boots that did not complete successfully yet.
+/// The bootloader reads it to decide when to fall back to the other slot.
+pub trait BootCounter {
+    fn count(&self) -> io::Result<u32>;
+    /// Count another boot attempt, returns the new count
+    fn increment(&self) -> io::Result<u32>;
+    /// Mark the current boot as good
+    fn clear(&self) -> io::Result<()>;
+}
+
+/// Counter kept as a decimal number in a file, a missing file counts as 0
+#[derive(Debug)]
+pub struct FileCounter {
+    path: PathBuf,
+}
+
+impl FileCounter {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Replace the file atomically, so a power loss never leaves a torn count
+    fn write(&self, count: u32) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{count}")?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl BootCounter for FileCounter {
+    fn count(&self) -> io::Result<u32> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => text.trim().parse().map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn increment(&self) -> io::Result<u32> {
+        let count = self.count()?.saturating_add(1);
+        self.write(count)?;
+        Ok(count)
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        self.write(0)
+    }
+}
+
+/// The configured boot counter, `None` if boot counting is disabled
+pub fn counter() -> Option<Box<dyn BootCounter + Send + Sync>> {
+    Defaults::load().boot_counter.map(|path| Box::new(FileCounter::new(path)) as _)
+}
+
+/// Clear the counter after a successful boot or on request of the operator
+pub fn mark_good(counter: &dyn BootCounter) -> io::Result<()> {
+    let previous = counter.count().unwrap_or_default();
+    counter.clear()?;
+    info!("Marked boot as good (after {previous} attempt(s))");
+    Ok(())
+}
+
+builtin_fn!(CountBoot: count_boot);
+
+impl IntoConfig for CountBoot {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml { name: "builtin::boot::count".to_string(), cmd: Self::box_fn(), ..Default::default() }
+    }
+}
+
+/// The counter usually lives on a file system that is mounted by a task
+const COUNT_BACKOFF: Backoff = Backoff::new(10, Duration::from_millis(100), Duration::from_secs(5));
+
+async fn count_boot(_: &TaskContext, _context: ContextMap<'static>) -> Result<()> {
+    let Some(counter) = counter() else {
+        return Ok(());
+    };
+    let count = COUNT_BACKOFF.retry("Counting the boot attempt", |_| true, || async { counter.increment() }).await?;
+    if count > 1 {
+        warn!("This is boot attempt {count} since the last successful boot");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mark_good, BootCounter, FileCounter};
+    use std::fs;
+
+    #[test]
+    fn successful_boot() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = FileCounter::new(dir.path().join("count"));
+        assert_eq!(counter.count().unwrap(), 0);
+        assert_eq!(counter.increment().unwrap(), 1);
+        mark_good(&counter).unwrap();
+        assert_eq!(counter.count().unwrap(), 0);
+        assert_eq!(fs::read_to_string(dir.path().join("count")).unwrap(), "0\n");
+    }
+
+    #[test]
+    fn failed_boots_accumulate() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = FileCounter::new(dir.path().join("count"));
+        for expected in 1..=3 {
+            assert_eq!(counter.increment().unwrap(), expected);
+        }
+        assert_eq!(FileCounter::new(dir.path().join("count")).count().unwrap(), 3);
+        assert!(!dir.path().join("count.tmp").exists());
+    }
+
+    #[test]
+    fn manual_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = FileCounter::new(dir.path().join("count"));
+        fs::write(dir.path().join("count"), "7\n").unwrap();
+        mark_good(&counter).unwrap();
+        assert_eq!(counter.increment().unwrap(), 1);
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("count"), "seven").unwrap();
+        FileCounter::new(dir.path().join("count")).increment().unwrap_err();
+    }
+}
@@ -1,23 +1,43 @@
-use super::IntoConfig;
+use super::{Backoff, IntoConfig};
 use crate::{
     builtin_fn,
-    def::{APLT_CTL, DIR_RUN},
+    def::APLT_CTL,
+    instance::Instance,
+    state_cell::StateCell,
     task::{ContextMap, TaskContext, TaskState},
 };
-use crate::{config::yaml::TaskConfigYaml, task::ExitReason};
+use crate::{
+    client::split_request,
+    protocol::{self, Reply},
+    config::yaml::{RespawnYaml, TaskConfigYaml},
+    task::ExitReason,
+};
 use anyhow::Result;
-use nix::{sys::stat::Mode, unistd::mkfifo};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    libc::{ELOOP, ENXIO, O_NOCTTY, O_NOFOLLOW, O_NONBLOCK},
+    sys::stat::Mode,
+    unistd::mkfifo,
+};
 use smallvec::smallvec;
 use smol::{
-    fs::{create_dir_all, File},
-    io::{AsyncBufReadExt, BufReader},
+    fs::File,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
 };
 use std::{
+    fs::{self, Metadata, OpenOptions},
+    io,
     ops::ControlFlow,
-    path::{Path, PathBuf},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
     time::Duration,
 };
-use tracing::{error, info};
+use thiserror::Error;
+use tracing::{error, info, warn};
 
 builtin_fn!(CreateCtlPipe: create_ctl);
 
@@ -32,25 +52,150 @@ impl IntoConfig for CreateCtlPipe {
     }
 }
 
+/// Retry policy for bringing up the control channel while DIR_RUN might
+/// still be missing or read-only.
+const CTL_BACKOFF: Backoff = Backoff::new(10, Duration::from_millis(100), Duration::from_secs(5));
+
+/// The task that reads the control pipe
+pub const DAEMON: &str = "builtin::ctl::daemon";
+
+static CTL_READY: StateCell<bool> = StateCell::new(false);
+
+static CTL_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Whether the daemon has opened the control pipe and is reading commands.
+pub fn ctl_available() -> bool {
+    CTL_READY.get()
+}
+
+/// Serve the control pipe in `dir` instead of the instance's, e.g. to run the
+/// builtins in tests. Only works before the pipe is created, returns `dir`
+/// if another one was set already.
+pub fn set_ctl_dir(dir: PathBuf) -> Result<(), PathBuf> {
+    CTL_DIR.set(dir)
+}
+
+fn ctl_dir() -> &'static Path {
+    CTL_DIR.get().map(PathBuf::as_path).unwrap_or(Instance::current().run_dir())
+}
+
 async fn create_ctl(_: &TaskContext, _context: ContextMap<'static>) -> Result<()> {
-    create_dir_all(DIR_RUN).await?;
-    mkfifo(&PathBuf::from(DIR_RUN).join(APLT_CTL), Mode::S_IRWXU | Mode::S_IWOTH)?;
+    create_ctl_in(ctl_dir(), CTL_BACKOFF).await?;
+    crate::perform_action::on_shutdown(remove_ctl);
     Ok(())
 }
 
+async fn create_ctl_in(dir: &Path, backoff: Backoff) -> io::Result<PipeStatus> {
+    let path = &dir.join(APLT_CTL);
+    backoff
+        .retry(
+            "Creating the control pipe",
+            |_| true,
+            || async move {
+                fs::create_dir_all(dir)?;
+                let status = prepare_pipe(path)?;
+                match status {
+                    PipeStatus::InUse => info!("{path:?} is already being served, reusing it"),
+                    PipeStatus::Stale => warn!("Replacing stale control pipe {path:?}"),
+                    PipeStatus::Missing => {}
+                }
+                if status != PipeStatus::InUse {
+                    mkfifo(path, Mode::S_IRWXU | Mode::S_IWOTH)?;
+                }
+                Ok(status)
+            },
+        )
+        .await
+}
+
+/// What was found at the control pipe location before creating it
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipeStatus {
+    /// Nothing there, the pipe needs to be created
+    Missing,
+    /// A leftover (dead FIFO or foreign file) that has been removed
+    Stale,
+    /// A FIFO which somebody is still reading from
+    InUse,
+}
+
+/// Inspect the control pipe location and remove anything that is left over
+/// from an unclean shutdown, so the pipe can be created again.
+///
+/// A FIFO is considered alive if opening its write end without blocking
+/// succeeds, which means there is a reader on the other side.
+pub fn prepare_pipe(path: &Path) -> io::Result<PipeStatus> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(PipeStatus::Missing),
+        Err(error) => return Err(error),
+    };
+
+    if meta.file_type().is_fifo() {
+        match OpenOptions::new().write(true).custom_flags(O_NONBLOCK).open(path) {
+            Ok(_) => return Ok(PipeStatus::InUse),
+            Err(error) if error.raw_os_error() == Some(ENXIO) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    if meta.is_dir() {
+        fs::remove_dir(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(PipeStatus::Stale)
+}
+
+/// Unlink the control pipe, so the next boot starts from a clean state.
+fn remove_ctl() {
+    let path = ctl_path();
+    if let Err(error) = fs::remove_file(&path) {
+        if error.kind() != io::ErrorKind::NotFound {
+            error!("Could not remove {path:?}: {error}");
+        }
+    }
+}
+
 builtin_fn!(WaitForCommands: wait_for_commands);
 
+/// How often the daemon is restarted after a crash, the machine cannot be
+/// controlled without it
+const DAEMON_RESPAWN: usize = 10;
+
 impl IntoConfig for WaitForCommands {
     fn into_config(self) -> TaskConfigYaml {
         TaskConfigYaml {
-            name: "builtin::ctl::daemon".to_string(),
+            name: DAEMON.to_string(),
             after: smallvec!["builtin::ctl::create".to_owned()],
             cmd: Self::box_fn(),
+            respawn: RespawnYaml::Retry(DAEMON_RESPAWN),
             ..Default::default()
         }
     }
 }
 
+builtin_fn!(CtlReady: ctl_ready);
+
+impl IntoConfig for CtlReady {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml {
+            name: "builtin::ctl::ready".to_string(),
+            with: vec![DAEMON.to_owned()],
+            cmd: Self::box_fn(),
+            provides: vec!["ctl".to_owned()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Done once the daemon reads the control pipe, so tasks that use
+/// alfad-ctl can run after `feature::ctl`
+async fn ctl_ready(_: &TaskContext, _context: ContextMap<'static>) -> Result<()> {
+    CTL_READY.wait_until(|ready| *ready).await;
+    Ok(())
+}
+
 async fn wait_for_commands(context: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
     let mut buf = String::new();
     loop {
@@ -58,21 +203,34 @@ async fn wait_for_commands(context: &TaskContext, context_map: ContextMap<'stati
             context.update_state(TaskState::Concluded(ExitReason::Terminated)).await;
             break Ok(());
         };
-        let mut pipe = match create_pipe().await {
+        let mut pipe = match open_pipe(&ctl_path(), CTL_BACKOFF).await {
             Ok(x) => x,
             Err(error) => {
-                error!("Could not create pipe: {error}");
-                smol::Timer::after(Duration::from_secs(10)).await;
-                continue;
+                CTL_READY.set(false);
+                return Err(error.into());
             }
         };
+        CTL_READY.set(true);
         loop {
             match pipe.read_line(&mut buf).await {
                 Ok(bytes) if bytes > 0 => {
-                    let action = buf.trim();
-                    info!(action);
-                    if let Err(error) = crate::perform_action::perform(action, context_map).await {
-                        error!(%error);
+                    let (reply_to, body) = split_request(buf.trim());
+                    let (reply_fifo, uid) = open_reply(&Instance::current().reply_dir(), reply_to);
+                    let reply = protocol::answer(body, |action| async move {
+                        info!(action);
+                        match crate::perform_action::perform_as(action, uid, context_map).await {
+                            Ok(message) => Reply::Ok(message),
+                            Err(error) => {
+                                error!(%error);
+                                Reply::Error { message: error.to_string(), kind: (&error).into() }
+                            }
+                        }
+                    })
+                    .await;
+                    if let (Some(path), Some(file)) = (reply_to, reply_fifo) {
+                        if let Err(error) = send_reply(file, &reply).await {
+                            error!("Could not reply to {path:?}: {error}");
+                        }
                     }
                 }
                 _ => break,
@@ -83,11 +241,354 @@ async fn wait_for_commands(context: &TaskContext, context_map: ContextMap<'stati
     }
 }
 
-async fn create_pipe() -> Result<BufReader<File>> {
-    Ok(BufReader::new(
-        smol::fs::OpenOptions::new()
-            .read(true)
-            .open(&Path::new(if cfg!(debug_assertions) { "test" } else { DIR_RUN }).join(APLT_CTL))
-            .await?,
-    ))
+/// Open the client's reply FIFO in `dir` before its action runs, and the
+/// uid the action runs as: the owner of the FIFO that was opened. Without
+/// a valid FIFO the client is unknown. Opening fails right away if the
+/// client is not listening instead of blocking the daemon.
+fn open_reply(dir: &Path, path: Option<&Path>) -> (Option<fs::File>, Option<u32>) {
+    let Some(path) = path else {
+        return (None, None);
+    };
+    match open_reply_fifo(dir, path, requester(path)) {
+        Ok(file) => {
+            let uid = file.metadata().ok().map(|meta| meta.uid());
+            (Some(file), uid)
+        }
+        Err(error) => {
+            error!("Could not reply to {path:?}: {error}");
+            (None, None)
+        }
+    }
+}
+
+/// Write the reply into the client's FIFO
+async fn send_reply(file: fs::File, reply: &str) -> io::Result<()> {
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+    let mut file = File::from(file);
+    file.write_all(reply.as_bytes()).await?;
+    file.flush().await
+}
+
+#[derive(Debug, Error)]
+pub enum ReplyPathError {
+    #[error("Replies only go into FIFOs directly in {}", .0.display())]
+    Outside(PathBuf),
+    #[error("Refusing to follow a symlink")]
+    Symlink,
+    #[error("Not a FIFO")]
+    NotFifo,
+    #[error("Owned by uid {actual}, but requested by uid {expected}")]
+    Owner { expected: u32, actual: u32 },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The uid of the client, which names its reply FIFO after its pid. FIFOs
+/// carry no peer credentials, so it is taken from /proc instead.
+fn requester(path: &Path) -> Option<u32> {
+    let pid: u32 = path.file_name()?.to_str()?.parse().ok()?;
+    fs::metadata(Path::new("/proc").join(pid.to_string())).ok().map(|meta| meta.uid())
+}
+
+/// Open a client supplied reply path for writing, without ever writing to
+/// anything but a FIFO directly in `dir`, owned by `uid` if it is known.
+/// Checked before opening, so opening has no side effects on devices, and
+/// after, in case the file was swapped in between.
+pub fn open_reply_fifo(dir: &Path, path: &Path, uid: Option<u32>) -> Result<fs::File, ReplyPathError> {
+    let outside = || ReplyPathError::Outside(dir.to_owned());
+    let mut rest = path.strip_prefix(dir).map_err(|_| outside())?.components();
+    if !matches!((rest.next(), rest.next()), (Some(Component::Normal(_)), None)) {
+        return Err(outside());
+    }
+    if !fs::symlink_metadata(dir)?.is_dir() {
+        return Err(ReplyPathError::Symlink);
+    }
+    let check = |meta: &Metadata| {
+        if meta.file_type().is_symlink() {
+            return Err(ReplyPathError::Symlink);
+        }
+        if !meta.file_type().is_fifo() {
+            return Err(ReplyPathError::NotFifo);
+        }
+        match uid {
+            Some(expected) if meta.uid() != expected => Err(ReplyPathError::Owner { expected, actual: meta.uid() }),
+            _ => Ok(()),
+        }
+    };
+    check(&fs::symlink_metadata(path)?)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(O_NONBLOCK | O_NOFOLLOW | O_NOCTTY)
+        .open(path)
+        .map_err(|error| match error.raw_os_error() {
+            Some(ELOOP) => ReplyPathError::Symlink,
+            _ => error.into(),
+        })?;
+    check(&file.metadata()?)?;
+    Ok(file)
+}
+
+fn ctl_path() -> PathBuf {
+    ctl_dir().join(APLT_CTL)
+}
+
+/// Open the control pipe for reading. A missing pipe is retried, since
+/// `builtin::ctl::create` might still be waiting for DIR_RUN, anything
+/// else (e.g. permission denied) is fatal.
+///
+/// The write end is opened as well, so opening does not wait for the first
+/// client and reading does not hit the end of the pipe between clients.
+async fn open_pipe(path: &Path, backoff: Backoff) -> io::Result<BufReader<File>> {
+    let file = backoff
+        .retry(
+            "Opening the control pipe",
+            |error: &io::Error| error.kind() == io::ErrorKind::NotFound,
+            || smol::fs::OpenOptions::new().read(true).write(true).open(path),
+        )
+        .await?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{prepare_pipe, PipeStatus};
+    use nix::{libc::O_NONBLOCK, sys::stat::Mode, unistd::mkfifo};
+    use std::{
+        fs::{self, OpenOptions},
+        os::unix::fs::{FileTypeExt, OpenOptionsExt},
+    };
+
+    #[test]
+    fn missing_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(prepare_pipe(&dir.path().join("ctl")).unwrap(), PipeStatus::Missing);
+    }
+
+    #[test]
+    fn stale_fifo_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl");
+        mkfifo(&path, Mode::S_IRWXU).unwrap();
+
+        assert_eq!(prepare_pipe(&path).unwrap(), PipeStatus::Stale);
+        assert!(!path.exists());
+
+        // EEXIST is gone, the pipe can be created again
+        mkfifo(&path, Mode::S_IRWXU).unwrap();
+    }
+
+    #[test]
+    fn live_fifo_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl");
+        mkfifo(&path, Mode::S_IRWXU).unwrap();
+        let _reader = OpenOptions::new().read(true).custom_flags(O_NONBLOCK).open(&path).unwrap();
+
+        assert_eq!(prepare_pipe(&path).unwrap(), PipeStatus::InUse);
+        assert!(fs::symlink_metadata(&path).unwrap().file_type().is_fifo());
+    }
+
+    #[test]
+    fn foreign_file_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl");
+        fs::write(&path, "not a pipe").unwrap();
+
+        assert_eq!(prepare_pipe(&path).unwrap(), PipeStatus::Stale);
+        assert!(!path.exists());
+    }
+}
+
+#[cfg(test)]
+mod reply_test {
+    use super::{open_reply, open_reply_fifo, ReplyPathError};
+    use nix::{
+        libc::O_NONBLOCK,
+        sys::stat::Mode,
+        unistd::{getuid, mkfifo},
+    };
+    use std::{
+        fs::{self, File, OpenOptions},
+        os::unix::fs::{symlink, OpenOptionsExt},
+        path::Path,
+    };
+
+    /// A FIFO with a reader, like a client waiting for its reply
+    fn fifo(path: &Path) -> File {
+        mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        OpenOptions::new().read(true).custom_flags(O_NONBLOCK).open(path).unwrap()
+    }
+
+    #[test]
+    fn client_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("42");
+        let _reader = fifo(&path);
+        open_reply_fifo(dir.path(), &path, Some(getuid().as_raw())).unwrap();
+        open_reply_fifo(dir.path(), &path, None).unwrap();
+    }
+
+    /// The action runs as the owner of the FIFO that was opened, or as
+    /// nobody known if there is none
+    #[test]
+    fn requester_uid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("42");
+        let _reader = fifo(&path);
+        let (file, uid) = open_reply(dir.path(), Some(&path));
+        assert!(file.is_some());
+        assert_eq!(uid, Some(getuid().as_raw()));
+
+        let file = dir.path().join("43");
+        fs::write(&file, "").unwrap();
+        let outside = dir.path().join("sub/44");
+        for path in [None, Some(file.as_path()), Some(outside.as_path()), Some(Path::new("/run/var/alfad-reply/1"))] {
+            assert!(matches!(open_reply(dir.path(), path), (None, None)), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn outside_reply_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let replies = dir.path().join("reply");
+        fs::create_dir(&replies).unwrap();
+        let _reader = fifo(&dir.path().join("fifo"));
+        for path in [dir.path().join("fifo"), replies.join("../fifo"), replies.clone(), Path::new("fifo").to_owned()] {
+            let result = open_reply_fifo(&replies, &path, None);
+            assert!(matches!(result, Err(ReplyPathError::Outside(_))), "{path:?}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn symlink_attack() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("passwd");
+        fs::write(&target, "root:x:0:0").unwrap();
+        let link = dir.path().join("42");
+        symlink(&target, &link).unwrap();
+        assert!(matches!(open_reply_fifo(dir.path(), &link, None), Err(ReplyPathError::Symlink)));
+
+        // Also when the link points to a FIFO
+        let _reader = fifo(&dir.path().join("fifo"));
+        fs::remove_file(&link).unwrap();
+        symlink(dir.path().join("fifo"), &link).unwrap();
+        assert!(matches!(open_reply_fifo(dir.path(), &link, None), Err(ReplyPathError::Symlink)));
+
+        // Or the reply directory itself was replaced
+        let replies = dir.path().join("reply");
+        symlink(dir.path(), &replies).unwrap();
+        let result = open_reply_fifo(&replies, &replies.join("fifo"), None);
+        assert!(matches!(result, Err(ReplyPathError::Symlink)));
+        assert_eq!(fs::read_to_string(target).unwrap(), "root:x:0:0");
+    }
+
+    #[test]
+    fn wrong_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("42");
+        let _reader = fifo(&path);
+        let other = getuid().as_raw() + 1;
+        let result = open_reply_fifo(dir.path(), &path, Some(other));
+        assert!(matches!(result, Err(ReplyPathError::Owner { expected, .. }) if expected == other));
+    }
+
+    #[test]
+    fn not_a_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("42");
+        fs::write(&path, "").unwrap();
+        assert!(matches!(open_reply_fifo(dir.path(), &path, None), Err(ReplyPathError::NotFifo)));
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        assert!(matches!(open_reply_fifo(dir.path(), &path, None), Err(ReplyPathError::NotFifo)));
+    }
+}
+
+#[cfg(test)]
+mod backoff_test {
+    use super::{create_ctl_in, open_pipe, PipeStatus};
+    use crate::{builtin::Backoff, def::APLT_CTL};
+    use nix::{sys::stat::Mode, unistd::mkfifo};
+    use smol::io::AsyncBufReadExt;
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        os::unix::fs::FileTypeExt,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const FAST: Backoff = Backoff::new(50, Duration::from_millis(10), Duration::from_millis(50));
+
+    #[test]
+    fn run_dir_appears_late() {
+        let root = tempfile::tempdir().unwrap();
+        let blocker = root.path().join("run");
+        // A file where the run directory should be makes create_dir_all fail
+        // (even as root), just like a mount point that is not there yet.
+        fs::write(&blocker, "").unwrap();
+        let dir = blocker.join("var");
+
+        let remove = blocker.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::remove_file(remove).unwrap();
+        });
+
+        assert_eq!(smol::block_on(create_ctl_in(&dir, FAST)).unwrap(), PipeStatus::Missing);
+        assert!(fs::symlink_metadata(dir.join(APLT_CTL)).unwrap().file_type().is_fifo());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn pipe_appears_late() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join(APLT_CTL);
+
+        let fifo = path.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            mkfifo(&fifo, Mode::S_IRWXU).unwrap();
+        });
+
+        smol::block_on(open_pipe(&path, FAST)).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn serves_one_client_after_another() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join(APLT_CTL);
+        mkfifo(&path, Mode::S_IRWXU).unwrap();
+        // Without a client around
+        let mut pipe = smol::block_on(open_pipe(&path, FAST)).unwrap();
+        for action in ["list", "kill getty"] {
+            OpenOptions::new().write(true).open(&path).unwrap().write_all(format!("{action}\n").as_bytes()).unwrap();
+            let mut line = String::new();
+            smol::block_on(pipe.read_line(&mut line)).unwrap();
+            assert_eq!(line.trim(), action);
+        }
+    }
+
+    #[test]
+    fn unusable_pipe_fails_hard() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file");
+        fs::write(&file, "").unwrap();
+
+        let slow = Backoff::new(10, Duration::from_secs(10), Duration::from_secs(10));
+        let start = Instant::now();
+        smol::block_on(open_pipe(&file.join(APLT_CTL), slow)).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn run_dir_never_appears() {
+        let root = tempfile::tempdir().unwrap();
+        let blocker = root.path().join("run");
+        fs::write(&blocker, "").unwrap();
+
+        let backoff = Backoff::new(3, Duration::from_millis(1), Duration::from_millis(1));
+        smol::block_on(create_ctl_in(&blocker.join("var"), backoff)).unwrap_err();
+    }
 }
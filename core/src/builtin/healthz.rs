@@ -0,0 +1,293 @@
+//! Liveness and readiness probes over HTTP, for alfad as the entrypoint of
+//! a container. builtin::healthz serves them on `healthz_addr` from
+//! defaults.yaml, and does nothing if it is unset:
+//!
+//! - `/healthz` is 200 while builtin::ctl::daemon is Running, so alfad can
+//!   be controlled, and 503 otherwise
+//! - `/readyz` is 200 once `healthz_ready` is Done, target::boot-complete
+//!   unless set otherwise, and 503 before, with the tasks that are still
+//!   waiting to start
+//!
+//! Only as much HTTP/1.0 as probes need: GET and HEAD, one request per
+//! connection.
+
+use super::{boot::is_settled, ctl, IntoConfig};
+use crate::{
+    builtin_fn,
+    config::{defaults::Defaults, yaml::TaskConfigYaml},
+    desired::DesiredState,
+    task::{ContextMap, ExitReason, TaskContext, TaskState},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    Timer,
+};
+use std::{io, ops::ControlFlow, time::Duration};
+use tracing::{debug, info, warn};
+
+builtin_fn!(Healthz: healthz);
+
+impl IntoConfig for Healthz {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml { name: "builtin::healthz".to_string(), cmd: Self::box_fn(), ..Default::default() }
+    }
+}
+
+/// Probes send a few lines, anything longer is cut off
+const MAX_REQUEST: usize = 4096;
+
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after a connection could not be accepted, e.g. for lack of
+/// descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+async fn healthz(_: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
+    let defaults = Defaults::load();
+    let Some(addr) = defaults.healthz_addr else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(&addr).await.with_context(|| format!("Could not listen on {addr}"))?;
+    info!("Serving health probes on {addr}");
+    serve(listener, context_map, defaults.healthz_ready).await;
+    Ok(())
+}
+
+/// Answer probes on `listener` about `context_map`, with `ready` as the
+/// task `/readyz` waits for
+pub async fn serve(listener: TcpListener, context_map: ContextMap<'static>, ready: String) {
+    let ready: &'static str = Box::leak(ready.into_boxed_str());
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                smol::spawn(async move {
+                    if let Err(error) = handle(stream, context_map, ready).await {
+                        debug!(%peer, %error, "Health probe");
+                    }
+                })
+                .detach();
+            }
+            Err(error) => {
+                warn!(%error, "Could not accept a health probe");
+                Timer::after(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, context_map: ContextMap<'_>, ready: &str) -> io::Result<()> {
+    let timeout = async {
+        Timer::after(REQUEST_TIMEOUT).await;
+        Err(io::ErrorKind::TimedOut.into())
+    };
+    let request = smol::future::or(read_request(&mut stream), timeout).await?;
+    let (response, head) = respond(&request, context_map, ready);
+    stream.write_all(&response.to_bytes(head)).await?;
+    stream.flush().await
+}
+
+/// Up to the empty line after the headers
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 512];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && !request.windows(2).any(|window| window == b"\n\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() >= MAX_REQUEST {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn text(status: u16, body: impl Into<String>) -> Self {
+        Self { status, content_type: "text/plain", body: body.into() + "\n" }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+
+    /// The response on the wire, without the body to a HEAD request
+    pub fn to_bytes(&self, head: bool) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.0 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        if !head {
+            bytes.extend_from_slice(self.body.as_bytes());
+        }
+        bytes
+    }
+}
+
+/// Body of `/readyz`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// The task that has to be Done
+    pub marker: String,
+    /// Enabled tasks that did not start yet, by name
+    pub unfinished: Vec<String>,
+}
+
+/// The answer to `request`, and whether it was a HEAD request
+pub fn respond(request: &str, context_map: ContextMap<'_>, ready: &str) -> (Response, bool) {
+    let mut words = request.lines().next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target), Some(version), None) = (words.next(), words.next(), words.next(), words.next()) else {
+        return (Response::text(400, "Expected a request line like GET /healthz HTTP/1.0"), false);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return (Response::text(400, format!("Unsupported version {version}")), false);
+    }
+    let head = method == "HEAD";
+    if method != "GET" && !head {
+        return (Response::text(405, format!("Only GET and HEAD, not {method}")), false);
+    }
+    let response = match target.split('?').next().unwrap_or_default() {
+        "/healthz" => liveness(context_map),
+        "/readyz" => readiness(context_map, ready),
+        #[cfg(feature = "diagnostics")]
+        "/metrics" => Response::text(200, crate::diagnostics::metrics().trim_end()),
+        path => Response::text(404, format!("Unknown path {path}, try /healthz or /readyz")),
+    };
+    (response, head)
+}
+
+fn liveness(context_map: ContextMap<'_>) -> Response {
+    match context_map.0.get(ctl::DAEMON).map(|task| task.state_now()) {
+        Some(TaskState::Running(_)) => Response::text(200, "ok"),
+        Some(state) => Response::text(503, format!("{} is {}", ctl::DAEMON, state.name())),
+        None => Response::text(503, format!("There is no {}", ctl::DAEMON)),
+    }
+}
+
+fn readiness(context_map: ContextMap<'_>, marker: &str) -> Response {
+    let ready = context_map.0.get(marker).is_some_and(|task| task.state_now() == TaskState::Concluded(ExitReason::Done));
+    let mut unfinished: Vec<_> = context_map
+        .0
+        .iter()
+        .filter(|(_, task)| task.desired.get() == DesiredState::Enabled && !is_settled(&task.state_now()))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    unfinished.sort_unstable();
+    let body = Readiness { ready, marker: marker.to_owned(), unfinished };
+    Response {
+        status: if ready { 200 } else { 503 },
+        content_type: "application/json",
+        body: serde_json::to_string(&body).unwrap_or_default() + "\n",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{respond, Readiness};
+    use crate::{
+        config::yaml::TaskConfigYaml,
+        task::{ContextMap, ExitReason, TaskContext, TaskState},
+    };
+    use std::collections::HashMap;
+
+    fn tasks(states: &[(&'static str, TaskState)]) -> ContextMap<'static> {
+        let map = states.iter().map(|(name, _)| {
+            let config = TaskConfigYaml::new(name.to_string()).into_config().unwrap();
+            (*name, TaskContext::new(config))
+        });
+        let map = ContextMap(Box::leak(Box::new(map.collect::<HashMap<_, _>>())));
+        for (name, state) in states {
+            smol::block_on(map.0[name].update_state(*state));
+        }
+        map
+    }
+
+    fn get(path: &str, map: ContextMap<'_>) -> (u16, String) {
+        let (response, head) = respond(&format!("GET {path} HTTP/1.1\r\nHost: pod\r\n\r\n"), map, "target::ready");
+        assert!(!head);
+        (response.status, response.body)
+    }
+
+    #[test]
+    fn liveness() {
+        let running = tasks(&[("builtin::ctl::daemon", TaskState::Running(0))]);
+        assert_eq!(get("/healthz", running), (200, "ok\n".to_owned()));
+        let failed = tasks(&[("builtin::ctl::daemon", TaskState::Concluded(ExitReason::Failed))]);
+        assert_eq!(get("/healthz?verbose", failed), (503, "builtin::ctl::daemon is Failed\n".to_owned()));
+        assert_eq!(get("/healthz", tasks(&[])).0, 503);
+    }
+
+    #[test]
+    fn readiness() {
+        let map = tasks(&[
+            ("target::ready", TaskState::Waiting),
+            ("db", TaskState::Running(0)),
+            ("web", TaskState::Waiting),
+            ("cron", TaskState::Created),
+        ]);
+        let (status, body) = get("/readyz", map);
+        assert_eq!(status, 503);
+        let readiness: Readiness = serde_json::from_str(&body).unwrap();
+        assert_eq!(readiness.unfinished, ["cron", "target::ready", "web"]);
+        assert!(!readiness.ready);
+
+        let map = tasks(&[("target::ready", TaskState::Concluded(ExitReason::Done)), ("db", TaskState::Running(0))]);
+        let (status, body) = get("/readyz", map);
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<Readiness>(&body).unwrap(),
+            Readiness { ready: true, marker: "target::ready".into(), unfinished: Vec::new() }
+        );
+    }
+
+    #[test]
+    fn requests() {
+        let map = tasks(&[("builtin::ctl::daemon", TaskState::Running(0))]);
+        let status = |request: &str| respond(request, map, "target::ready").0.status;
+        assert_eq!(status("GET /status HTTP/1.0\r\n\r\n"), 404);
+        assert_eq!(status("POST /healthz HTTP/1.0\r\n\r\n"), 405);
+        assert_eq!(status("GET /healthz\r\n\r\n"), 400);
+        assert_eq!(status("GET /healthz HTTP/2\r\n\r\n"), 400);
+        assert_eq!(status(""), 400);
+
+        let (response, head) = respond("HEAD /healthz HTTP/1.0\r\n\r\n", map, "target::ready");
+        assert!(head);
+        assert_eq!(
+            String::from_utf8(response.to_bytes(head)).unwrap(),
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\n"
+        );
+        assert!(String::from_utf8(response.to_bytes(false)).unwrap().ends_with("\r\n\r\nok\n"));
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn metrics() {
+        let map = tasks(&[("builtin::ctl::daemon", TaskState::Running(0))]);
+        let (status, body) = get("/metrics", map);
+        assert_eq!(status, 200);
+        let total = body.lines().find_map(|line| line.strip_prefix("alfad_state_updates_total "));
+        assert!(total.is_some_and(|total| total.parse::<u64>().unwrap() >= 1), "{body}");
+        assert!(body.contains("# TYPE alfad_state_updates_per_second gauge\n"), "{body}");
+    }
+}
@@ -0,0 +1,270 @@
+//! Scripts that are run when tasks change state, for observability
+//! without wiring every task up with its own `on_failure`.
+//! builtin::hooks runs the executable files in the directories below
+//! `hooks` next to alfad.d, in the order of their names:
+//!
+//! - `task-started.d` when a task starts running
+//! - `task-failed.d` when a task fails, `EXIT_REASON` tells how
+//! - `boot-complete.d` once target::boot-complete is Done
+//!
+//! Scripts get `HOOK` and `TASK_NAME` on top of the environment of alfad.
+//! They run one after another, each for at most [`TIMEOUT`], and a task
+//! that keeps crashing runs its hooks at most once every [`INTERVAL`].
+//! What they do or how they end never changes the state of a task.
+//! The directories are read for every event, so scripts can be added
+//! while alfad runs.
+
+use super::{boot::BOOT_COMPLETE, IntoConfig};
+use crate::{
+    builtin_fn,
+    config::{self, yaml::TaskConfigYaml},
+    fd,
+    task::{ContextMap, ExitReason, StateEvent, TaskContext, TaskState},
+};
+use anyhow::Result;
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use smol::{future, process::Command, Timer};
+use std::{
+    collections::HashMap,
+    fs, io,
+    ops::ControlFlow,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+use strum::Display;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+builtin_fn!(RunHooks: run_hooks);
+
+impl IntoConfig for RunHooks {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml { name: "builtin::hooks".to_string(), cmd: Self::box_fn(), ..Default::default() }
+    }
+}
+
+/// Time a script gets before its process group is killed
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Least time between two runs of the same hook for the same task
+pub const INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Hook {
+    TaskStarted,
+    TaskFailed,
+    BootComplete,
+}
+
+impl Hook {
+    /// The hook a task entering `state` triggers, if any
+    pub fn of(task: &str, state: TaskState) -> Option<Self> {
+        match state {
+            TaskState::Running(0) => Some(Self::TaskStarted),
+            TaskState::Concluded(ExitReason::Failed) => Some(Self::TaskFailed),
+            TaskState::Concluded(ExitReason::Done) if task == BOOT_COMPLETE => Some(Self::BootComplete),
+            _ => None,
+        }
+    }
+
+    /// Where the scripts of this hook are, below `dir`
+    pub fn dir(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{self}.d"))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("Could not run it: {0}")]
+    Spawn(#[from] io::Error),
+    #[error("Killed after {0:?}")]
+    TimedOut(Duration),
+}
+
+/// Executable files in `dir` sorted by name, without hidden ones. A
+/// directory that does not exist has none.
+pub fn scripts(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut scripts: Vec<_> = entries
+        .flatten()
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter(|entry| fs::metadata(entry.path()).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0))
+        .map(|entry| entry.path())
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+/// Variables a script of `hook` gets for `task`, `exit` is how its last
+/// command line ended
+pub fn env(hook: Hook, task: &str, exit: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut env = vec![("HOOK", hook.to_string()), ("TASK_NAME", task.to_owned())];
+    if hook == Hook::TaskFailed {
+        env.push(("EXIT_REASON", exit.unwrap_or("failed").to_owned()));
+    }
+    env
+}
+
+/// Run `script` in a process group of its own and wait for it, at most
+/// for `timeout`
+pub async fn run(script: &Path, env: &[(&str, String)], timeout: Duration) -> Result<ExitStatus, HookError> {
+    let mut command = std::process::Command::new(script);
+    command.envs(env.iter().map(|(name, value)| (name, value))).process_group(0);
+    fd::stdio_only(&mut command);
+    let mut child = Command::from(command).stdin(Stdio::null()).spawn()?;
+    let expired = async {
+        Timer::after(timeout).await;
+        None
+    };
+    match future::or(async { Some(child.status().await) }, expired).await {
+        Some(status) => Ok(status?),
+        None => {
+            // Whatever the script started goes as well
+            let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+            let _ = child.status().await;
+            Err(HookError::TimedOut(timeout))
+        }
+    }
+}
+
+/// Runs hooks for state changes, see the module
+pub struct Hooks {
+    dir: PathBuf,
+    timeout: Duration,
+    interval: Duration,
+    last: HashMap<(Hook, String), Instant>,
+}
+
+impl Hooks {
+    pub fn new(dir: PathBuf, timeout: Duration, interval: Duration) -> Self {
+        Self { dir, timeout, interval, last: HashMap::new() }
+    }
+
+    /// Run the scripts `event` triggers, returns how many ran. `exit` is
+    /// how the last command line of the task ended.
+    pub async fn handle(&mut self, event: &StateEvent, exit: Option<&str>) -> usize {
+        let Some(hook) = Hook::of(&event.task, event.state) else {
+            return 0;
+        };
+        let scripts = scripts(&hook.dir(&self.dir));
+        if scripts.is_empty() {
+            return 0;
+        }
+        let now = Instant::now();
+        let key = (hook, event.task.clone());
+        if self.last.get(&key).is_some_and(|last| now.duration_since(*last) < self.interval) {
+            debug!(task = event.task, %hook, "Ran its hooks less than {:?} ago, skipping them", self.interval);
+            return 0;
+        }
+        self.last.insert(key, now);
+
+        let env = env(hook, &event.task, exit);
+        for script in &scripts {
+            match run(script, &env, self.timeout).await {
+                Ok(status) if status.success() => debug!(task = event.task, %hook, script = %script.display(), "Hook ran"),
+                Ok(status) => warn!(task = event.task, %hook, script = %script.display(), "Hook ended with {status}"),
+                Err(error) => warn!(task = event.task, %hook, script = %script.display(), "Hook: {error}"),
+            }
+        }
+        scripts.len()
+    }
+}
+
+async fn run_hooks(_: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
+    let events = context_map.subscribe();
+    let mut hooks = Hooks::new(config::root().join("hooks"), TIMEOUT, INTERVAL);
+    info!("Running hooks from {}", hooks.dir.display());
+    while let Ok(event) = events.recv().await {
+        let exit = context_map.0.get(event.task.as_str()).and_then(|task| task.exit.lock().unwrap().clone());
+        hooks.handle(&event, exit.as_deref()).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{env, run, scripts, Hook, HookError, Hooks};
+    use crate::task::{ExitReason, StateEvent, TaskState};
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    fn script(path: &Path, body: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn event(task: &str, state: TaskState) -> StateEvent {
+        StateEvent { task: task.to_owned(), state, at: SystemTime::now(), reason: None }
+    }
+
+    #[test]
+    fn scanning() {
+        let dir = tempfile::tempdir().unwrap();
+        script(&dir.path().join("20-notify"), "true");
+        script(&dir.path().join("10-log"), "true");
+        script(&dir.path().join(".10-log.swp"), "true");
+        fs::write(dir.path().join("README"), "Not executable").unwrap();
+        fs::create_dir(dir.path().join("30-dir")).unwrap();
+        let names: Vec<_> = scripts(dir.path()).iter().map(|path| path.file_name().unwrap().to_owned()).collect();
+        assert_eq!(names, ["10-log", "20-notify"]);
+        assert!(scripts(&dir.path().join("nonexistent")).is_empty());
+
+        assert_eq!(Hook::TaskFailed.dir(Path::new("/etc/alfad/hooks")), Path::new("/etc/alfad/hooks/task-failed.d"));
+        assert_eq!(Hook::of("sshd", TaskState::Running(0)), Some(Hook::TaskStarted));
+        assert_eq!(Hook::of("sshd", TaskState::Running(1)), None);
+        assert_eq!(Hook::of("sshd", TaskState::Concluded(ExitReason::Done)), None);
+        assert_eq!(Hook::of("target::boot-complete", TaskState::Concluded(ExitReason::Done)), Some(Hook::BootComplete));
+    }
+
+    #[test]
+    fn environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        script(&dir.path().join("env"), &format!("echo \"$HOOK $TASK_NAME ${{EXIT_REASON-unset}}\" >> {}", out.display()));
+        for hook in [Hook::TaskFailed, Hook::TaskStarted] {
+            let vars = env(hook, "sshd", Some("exit status: 1"));
+            let status = smol::block_on(run(&dir.path().join("env"), &vars, Duration::from_secs(5))).unwrap();
+            assert!(status.success());
+        }
+        assert_eq!(fs::read_to_string(&out).unwrap(), "task-failed sshd exit status: 1\ntask-started sshd unset\n");
+        assert_eq!(env(Hook::TaskFailed, "sshd", None)[2], ("EXIT_REASON", "failed".to_owned()));
+    }
+
+    #[test]
+    fn timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let hooks = dir.path().join("task-failed.d");
+        script(&hooks.join("10-hang"), "sleep 30 &\nsleep 30");
+        script(&hooks.join("20-after"), &format!("echo ran >> {}", out.display()));
+
+        let started = Instant::now();
+        let error = smol::block_on(run(&hooks.join("10-hang"), &[], Duration::from_millis(100))).unwrap_err();
+        assert!(matches!(error, HookError::TimedOut(_)), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The next script still runs, and a crash loop only once
+        let mut runner = Hooks::new(dir.path().to_owned(), Duration::from_millis(100), Duration::from_secs(60));
+        let failed = event("web", TaskState::Concluded(ExitReason::Failed));
+        assert_eq!(smol::block_on(runner.handle(&failed, None)), 2);
+        assert_eq!(smol::block_on(runner.handle(&failed, None)), 0);
+        assert_eq!(smol::block_on(runner.handle(&event("db", failed.state), None)), 2);
+        assert_eq!(smol::block_on(runner.handle(&event("db", TaskState::Running(0)), None)), 0);
+        assert_eq!(fs::read_to_string(&out).unwrap(), "ran\nran\n");
+        let error = smol::block_on(run(&dir.path().join("missing"), &[], Duration::from_secs(1))).unwrap_err();
+        assert!(matches!(error, HookError::Spawn(_)), "{error}");
+    }
+}
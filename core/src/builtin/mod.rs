@@ -1,25 +1,206 @@
 use crate::task::{ContextMap, ExitReason, TaskContext};
 use crate::{
-    config::{payload::Runnable, yaml::TaskConfigYaml},
+    config::{
+        payload::Runnable,
+        yaml::{PayloadYaml, TaskConfigYaml},
+    },
+    def::SRC_BUILTIN,
     task::TaskState,
 };
 use async_trait::async_trait;
-use futures::{ready, Future};
+use futures::Future;
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Display,
     ops::ControlFlow,
+    panic::{self, AssertUnwindSafe},
     pin::{pin, Pin},
+    sync::Mutex,
     task::Poll,
+    time::Duration,
 };
-use tracing::{debug, info};
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
 
+pub mod boot;
+pub mod bootcount;
 pub mod ctl;
+#[cfg(feature = "healthz")]
+pub mod healthz;
+pub mod hooks;
+pub mod reaper;
+pub mod signals;
+pub mod state;
+pub mod sweep;
+pub mod timesync;
+#[cfg(feature = "utmp")]
+pub mod utmp;
 
 pub trait IntoConfig {
     fn into_config(self) -> TaskConfigYaml;
 }
 
+/// Default configuration of every builtin, by the key task files refer to
+/// it with, e.g. `cmd: {builtin: ctl::daemon}`
+fn registry() -> Vec<(&'static str, TaskConfigYaml)> {
+    vec![
+        ("ctl::create", ctl::CreateCtlPipe.into_config()),
+        ("ctl::daemon", ctl::WaitForCommands.into_config()),
+        ("ctl::ready", ctl::CtlReady.into_config()),
+        ("boot::count", bootcount::CountBoot.into_config()),
+        ("state::dir", state::StateDir.into_config()),
+        ("boot::complete", boot::BootComplete.into_config()),
+        ("sweep", sweep::Sweep.into_config()),
+        ("time-sync-wait", timesync::TimeSyncWait.into_config()),
+        ("hooks", hooks::RunHooks.into_config()),
+        ("signals", signals::Signals.into_config()),
+        ("reaper", reaper::Reaper.into_config()),
+        #[cfg(feature = "utmp")]
+        ("utmp", utmp::RecordBoot.into_config()),
+        #[cfg(feature = "healthz")]
+        ("healthz", healthz::Healthz.into_config()),
+    ]
+}
+
+/// Tasks that are part of every configuration
+pub fn all() -> Vec<TaskConfigYaml> {
+    registry()
+        .into_iter()
+        .map(|(key, mut config)| {
+            if let PayloadYaml::Builtin(service) = &mut config.cmd {
+                *service = BuiltInService::new(key);
+            }
+            TaskConfigYaml { source: Some(SRC_BUILTIN.into()), ..config }
+        })
+        .collect()
+}
+
+pub fn keys() -> Vec<&'static str> {
+    registry().into_iter().map(|(key, _)| key).collect()
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Unknown builtin '{key}', available are {}", .available.join(", "))]
+pub struct UnknownBuiltin {
+    pub key: String,
+    pub available: Vec<&'static str>,
+}
+
+/// The builtin registered as `key`
+pub fn lookup(key: &str) -> Result<BuiltInService, UnknownBuiltin> {
+    keys()
+        .into_iter()
+        .find(|registered| *registered == key)
+        .map(BuiltInService::new)
+        .ok_or_else(|| UnknownBuiltin { key: key.to_owned(), available: keys() })
+}
+
+type Function = &'static (dyn Runnable + Sync + Send);
+
+/// Functions of builtins outside the registry by their key, see
+/// [`BuiltInService::register`]
+static FUNCTIONS: Mutex<Option<HashMap<Cow<'static, str>, Function>>> = Mutex::new(None);
+
+/// What the builtin `key` runs. Registry keys stand for the function of
+/// their default configuration.
+fn function(key: &str) -> Option<Function> {
+    let registered = |key: &str| FUNCTIONS.lock().unwrap().as_ref()?.get(key).copied();
+    registered(key).or_else(|| {
+        registry().into_iter().find(|(registry_key, _)| *registry_key == key).and_then(|(_, config)| match config.cmd {
+            PayloadYaml::Builtin(service) => registered(&service.key),
+            _ => None,
+        })
+    })
+}
+
+/// Drop the default configuration of builtins a task file took over
+pub fn without(builtin: Vec<TaskConfigYaml>, overridden: &[&str]) -> Vec<TaskConfigYaml> {
+    builtin.into_iter().filter(|config| !config.cmd.builtin_key().is_some_and(|key| overridden.contains(&key))).collect()
+}
+
+/// Bounded exponential backoff for builtins that depend on parts of the
+/// system which might not be up yet.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub attempts: usize,
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub const fn new(attempts: usize, initial: Duration, max: Duration) -> Self {
+        Self { attempts, initial, max }
+    }
+
+    pub fn delay(&self, attempt: usize) -> Duration {
+        self.initial.saturating_mul(1 << attempt.min(16)).min(self.max)
+    }
+
+    /// Run `op` until it succeeds, fails with an error that is not transient
+    /// or the attempts run out. Later attempts are logged louder.
+    pub async fn retry<T, E: Display, F: Future<Output = Result<T, E>>>(
+        &self, what: &str, is_transient: impl Fn(&E) -> bool, mut op: impl FnMut() -> F,
+    ) -> Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            let error = match op().await {
+                Ok(x) => return Ok(x),
+                Err(error) => error,
+            };
+            attempt += 1;
+            if !is_transient(&error) || attempt >= self.attempts {
+                error!("{what} failed after {attempt} attempt(s): {error}");
+                return Err(error);
+            }
+            let delay = self.delay(attempt - 1);
+            if attempt < self.attempts / 2 {
+                debug!("{what} failed ({error}), retrying in {delay:?}");
+            } else {
+                warn!("{what} failed ({error}), retrying in {delay:?}");
+            }
+            smol::Timer::after(delay).await;
+        }
+    }
+}
+
+/// A builtin by its key, the function is only looked up when the task runs.
+/// That keeps configurations plain data that can be cloned, compared and
+/// cached.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
 pub struct BuiltInService {
-    function: &'static (dyn Runnable + Sync + Send),
+    /// A registry key, or one given to [`BuiltInService::register`]
+    key: Cow<'static, str>,
+}
+
+impl BuiltInService {
+    fn new(key: &'static str) -> Self {
+        Self { key: Cow::Borrowed(key) }
+    }
+
+    /// A builtin outside the registry, e.g. one defined with
+    /// [`builtin_fn!`](crate::builtin_fn) or a stub. `key` has to be unique
+    /// to `function`, registering it again replaces the function.
+    pub fn register(key: impl Into<Cow<'static, str>>, function: Function) -> Self {
+        let key = key.into();
+        FUNCTIONS.lock().unwrap().get_or_insert_with(HashMap::new).insert(key.clone(), function);
+        Self { key }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// The cache stores the key, which has to be in the registry when it is
+/// loaded
+impl<'de> Deserialize<'de> for BuiltInService {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        lookup(&key).map_err(de::Error::custom)
+    }
 }
 
 #[async_trait]
@@ -29,36 +210,50 @@ impl Runnable for BuiltInService {
         context: &'a TaskContext,
         context_map: ContextMap<'static>,
     ) -> ControlFlow<TaskState> {
+        let Some(function) = function(&self.key) else {
+            error!(name = context.config.name, key = %self.key, "Unknown builtin");
+            return ControlFlow::Break(TaskState::Concluded(ExitReason::Failed));
+        };
         BuiltInServiceManager {
-            function: pin!(self.function.run(context, context_map)),
+            function: pin!(function.run(context, context_map)),
+            terminating: pin!(context.wait_until(|state| *state == TaskState::Terminating)),
             context,
         }
         .await
     }
 }
 
-pub struct BuiltInServiceManager<'a, T: Future<Output = ControlFlow<TaskState>>> {
+/// Ends the builtin once its task is Terminating. One that is force killed
+/// runs on until the driver of the task gives up on it.
+pub struct BuiltInServiceManager<'a, T: Future<Output = ControlFlow<TaskState>>, W: Future<Output = TaskState>> {
     function: Pin<&'a mut T>,
+    terminating: Pin<&'a mut W>,
     context: &'a TaskContext,
 }
 
-impl<'a, T: Future<Output = ControlFlow<TaskState>>> Future for BuiltInServiceManager<'a, T> {
+impl<'a, T: Future<Output = ControlFlow<TaskState>>, W: Future<Output = TaskState>> Future
+    for BuiltInServiceManager<'a, T, W>
+{
     type Output = ControlFlow<TaskState>;
 
     fn poll(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
-        let state = ready!(pin!(self.context.state()).poll(cx));
-        debug!(name = self.context.config.name, %state);
-        if state == TaskState::Terminating {
+        if self.terminating.as_mut().poll(cx).is_ready() {
             info!(name = self.context.config.name, "Terminating");
-            Poll::Ready(ControlFlow::Break(TaskState::Concluded(
-                ExitReason::Terminated,
-            )))
-        } else {
-            ready!(pin!(self.context.set_waker(cx.waker())).poll(cx));
-            self.function.as_mut().poll(cx)
+            return Poll::Ready(ControlFlow::Break(TaskState::Concluded(ExitReason::Terminated)));
+        }
+        // A panicking builtin must not take down the executor, it fails
+        // like any other task and is respawned if configured. Only works
+        // as long as the profiles in the workspace Cargo.toml unwind.
+        let function = &mut self.function;
+        match panic::catch_unwind(AssertUnwindSafe(|| function.as_mut().poll(cx))) {
+            Ok(poll) => poll,
+            Err(_) => {
+                error!(name = self.context.config.name, "Panicked");
+                Poll::Ready(ControlFlow::Break(TaskState::Concluded(ExitReason::Failed)))
+            }
         }
     }
 }
@@ -70,7 +265,8 @@ macro_rules! builtin_fn {
 
         impl $name {
             pub fn box_fn() -> $crate::config::yaml::PayloadYaml {
-                $crate::config::yaml::PayloadYaml::Builtin($crate::builtin::BuiltInService{ function: Box::leak(Box::new($name))})
+                let key = concat!(module_path!(), "::", stringify!($name));
+                $crate::config::yaml::PayloadYaml::Builtin($crate::builtin::BuiltInService::register(key, &$name))
             }
         }
 
@@ -94,3 +290,175 @@ macro_rules! builtin_fn {
 
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::{all, lookup, Backoff, BuiltInService};
+    use crate::{
+        config::yaml::{PayloadYaml, RespawnYaml, TaskConfigYaml},
+        task::{drive, ContextMap, ExitReason, TaskContext, TaskState},
+    };
+    use anyhow::Result;
+    use smol::Timer;
+    use std::{
+        collections::HashMap,
+        future::Future,
+        ops::ControlFlow,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    builtin_fn!(PanicOnce: panic_once);
+    builtin_fn!(Busy: busy);
+    builtin_fn!(Forever: forever);
+
+    async fn panic_once(_: &TaskContext, _context: ContextMap<'static>) -> Result<()> {
+        if CALLS.fetch_add(1, Ordering::SeqCst) == 0 {
+            panic!("first call");
+        }
+        Ok(())
+    }
+
+    /// Yields a lot, so the manager is polled over and over
+    async fn busy(_: &TaskContext, _context: ContextMap<'static>) -> Result<()> {
+        for _ in 0..10_000 {
+            smol::future::yield_now().await;
+        }
+        Ok(())
+    }
+
+    async fn forever(_: &TaskContext, _context: ContextMap<'static>) -> Result<()> {
+        smol::future::pending().await
+    }
+
+    fn task(cmd: PayloadYaml, respawn: RespawnYaml) -> (&'static TaskContext, ContextMap<'static>) {
+        let config = TaskConfigYaml { name: "builtin".to_owned(), cmd, respawn, ..Default::default() };
+        let context = Box::leak(Box::new(TaskContext::new(config.into_config().unwrap())));
+        (context, ContextMap(Box::leak(Box::new(HashMap::new()))))
+    }
+
+    /// Fail the test instead of hanging it
+    async fn timeout(future: impl Future<Output = ()>) {
+        let expired = async {
+            Timer::after(Duration::from_secs(30)).await;
+            panic!("stalled");
+        };
+        smol::future::or(future, expired).await
+    }
+
+    #[test]
+    fn panic_is_respawned() {
+        let (context, map) = task(PanicOnce::box_fn(), RespawnYaml::Retry(1));
+
+        smol::block_on(drive(context, map));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+        assert_eq!(smol::block_on(context.state()), TaskState::Concluded(ExitReason::Done));
+    }
+
+    #[test]
+    fn contended_state() {
+        let (context, map) = task(Busy::box_fn(), RespawnYaml::No);
+        let _readers: Vec<_> = (0..8)
+            .map(|_| {
+                smol::spawn(async move {
+                    loop {
+                        context.state().await;
+                        smol::future::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+
+        smol::block_on(timeout(drive(context, map)));
+        assert_eq!(smol::block_on(context.state()), TaskState::Concluded(ExitReason::Done));
+    }
+
+    #[test]
+    fn terminate_pending_builtin() {
+        let (context, map) = task(Forever::box_fn(), RespawnYaml::Retry(1));
+        let driver = smol::spawn(drive(context, map));
+
+        smol::block_on(timeout(async {
+            while !context.state().await.is_running() {
+                Timer::after(Duration::from_millis(1)).await;
+            }
+            context.update_state(TaskState::Terminating).await;
+            driver.await;
+        }));
+        assert_eq!(smol::block_on(context.state()), TaskState::Concluded(ExitReason::Terminated));
+    }
+
+    /// A copy of the configuration runs the same function, looked up by key
+    /// when it starts
+    #[test]
+    fn resolved_when_run() {
+        let (context, map) = task(Busy::box_fn(), RespawnYaml::No);
+        let copy = Box::leak(Box::new(TaskContext::new(context.config.clone())));
+        assert_eq!(*copy.config, *context.config);
+        smol::block_on(timeout(drive(copy, map)));
+        assert_eq!(smol::block_on(copy.state()), TaskState::Concluded(ExitReason::Done));
+
+        let unknown = BuiltInService { key: "builtin::test::Nonexistent".into() };
+        let (context, map) = task(PayloadYaml::Builtin(unknown), RespawnYaml::No);
+        smol::block_on(timeout(drive(context, map)));
+        assert_eq!(smol::block_on(context.state()), TaskState::Concluded(ExitReason::Failed));
+    }
+
+    #[test]
+    fn registry_keys() {
+        let sweep = all().into_iter().find(|config| config.name == "builtin::sweep").unwrap();
+        assert_eq!(sweep.cmd.builtin_key(), Some("sweep"));
+        assert!(super::function("sweep").is_some());
+        let service = lookup("sweep").unwrap();
+        let cached = serde_json::to_string(&service).unwrap();
+        assert_eq!(cached, "\"sweep\"");
+        assert_eq!(serde_json::from_str::<BuiltInService>(&cached).unwrap(), service);
+        // Only registry keys can be loaded from a cache
+        let fake = BuiltInService::register("builtin::test::fake", &Busy);
+        serde_json::from_str::<BuiltInService>(&serde_json::to_string(&fake).unwrap()).unwrap_err();
+        lookup("builtin::test::fake").unwrap_err();
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let backoff = Backoff::new(100, Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(400));
+        assert_eq!(backoff.delay(10), Duration::from_secs(1));
+        assert_eq!(backoff.delay(usize::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let backoff = Backoff::new(3, Duration::ZERO, Duration::ZERO);
+        let mut calls = 0;
+        let res: Result<(), &str> = smol::block_on(backoff.retry(
+            "test",
+            |_| true,
+            || {
+                calls += 1;
+                async { Err("nope") }
+            },
+        ));
+        assert!(res.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_stops_on_fatal_error() {
+        let backoff = Backoff::new(3, Duration::ZERO, Duration::ZERO);
+        let mut calls = 0;
+        let res: Result<(), &str> = smol::block_on(backoff.retry(
+            "test",
+            |_| false,
+            || {
+                calls += 1;
+                async { Err("fatal") }
+            },
+        ));
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+}
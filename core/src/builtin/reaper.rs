@@ -0,0 +1,105 @@
+//! Orphans alfad inherits as init, e.g. from daemons that fork twice, stay
+//! zombies until somebody waits for them. The task waits for the zombie
+//! children no task owns.
+
+use super::IntoConfig;
+use crate::{
+    builtin_fn,
+    config::yaml::TaskConfigYaml,
+    task::{ContextMap, ProcFs, ProcessTable, TaskContext, TaskState},
+};
+use anyhow::Result;
+use nix::{
+    sys::wait::{waitpid, WaitPidFlag},
+    unistd::Pid,
+};
+use std::{collections::HashSet, ops::ControlFlow, process, time::Duration};
+use tracing::debug;
+
+/// How often the children of alfad are looked at
+const INTERVAL: Duration = Duration::from_secs(5);
+
+builtin_fn!(Reaper: reap);
+
+impl IntoConfig for Reaper {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml { name: "builtin::reaper".to_string(), cmd: Self::box_fn(), ..Default::default() }
+    }
+}
+
+async fn reap(_: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
+    let parent = process::id() as i32;
+    let mut seen = HashSet::new();
+    loop {
+        smol::Timer::after(INTERVAL).await;
+        let owned: HashSet<_> = context_map.0.values().filter_map(|task| task.child.get()).map(|child| child.pid).collect();
+        let (orphans, zombies) = orphans(&ProcFs, parent, &owned, &seen);
+        for pid in orphans {
+            match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+                Ok(status) => debug!("Reaped orphan {pid}: {status:?}"),
+                Err(error) => debug!("Could not reap orphan {pid}: {error}"),
+            }
+        }
+        seen = zombies;
+    }
+}
+
+/// Zombie children of `parent` to reap and the ones to look at again next
+/// time. A zombie is only reaped if no task owns it and it already was one
+/// at the last look, `seen`, so whoever started it had time to wait for it.
+pub fn orphans(processes: &dyn ProcessTable, parent: i32, owned: &HashSet<i32>, seen: &HashSet<i32>) -> (Vec<i32>, HashSet<i32>) {
+    let mut zombies = processes.children(parent);
+    zombies.retain(|pid| !owned.contains(pid) && processes.is_zombie(*pid));
+    zombies.sort();
+    let (orphans, zombies): (Vec<_>, Vec<_>) = zombies.into_iter().partition(|pid| seen.contains(pid));
+    (orphans, zombies.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::orphans;
+    use crate::task::ProcessTable;
+    use std::collections::{HashMap, HashSet};
+
+    /// Children of pid 1 by pid, whether they are zombies
+    struct Children(HashMap<i32, bool>);
+
+    impl ProcessTable for Children {
+        fn start_time(&self, pid: i32) -> Option<u64> {
+            self.0.contains_key(&pid).then_some(0)
+        }
+
+        fn cmdline(&self, _: i32) -> Option<String> {
+            None
+        }
+
+        fn is_zombie(&self, pid: i32) -> bool {
+            self.0.get(&pid).copied().unwrap_or(false)
+        }
+
+        fn children(&self, parent: i32) -> Vec<i32> {
+            if parent == 1 {
+                self.0.keys().copied().collect()
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn only_orphans() {
+        let children = Children(HashMap::from([(10, true), (11, true), (12, false), (13, true)]));
+        let owned = HashSet::from([11]);
+
+        // Zombies get one more round to be waited for
+        let (reap, seen) = orphans(&children, 1, &owned, &HashSet::new());
+        assert!(reap.is_empty());
+        assert_eq!(seen, HashSet::from([10, 13]));
+
+        let (reap, seen) = orphans(&children, 1, &owned, &HashSet::from([10, 12]));
+        assert_eq!(reap, [10]);
+        assert_eq!(seen, HashSet::from([13]));
+
+        assert_eq!(orphans(&children, 2, &owned, &HashSet::from([10])), (Vec::new(), HashSet::new()));
+    }
+}
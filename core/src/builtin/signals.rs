@@ -0,0 +1,70 @@
+//! Signals sent to alfad, handled by a task so `alfad-ctl list` shows
+//! whether anything is listening
+
+use super::IntoConfig;
+use crate::{
+    action::SystemCommand,
+    builtin_fn,
+    config::yaml::{RespawnYaml, TaskConfigYaml},
+    instance::Instance,
+    perform_action::{perform, schedule},
+    task::{ContextMap, TaskContext, TaskState},
+};
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use nix::libc::{SIGABRT, SIGHUP, SIGPIPE, SIGTERM, SIGTSTP};
+use signal_hook::iterator::exfiltrator::WithOrigin;
+use signal_hook_async_std::SignalsInfo;
+use std::{io, ops::ControlFlow, sync::Mutex};
+use tracing::{info, warn};
+
+const SIGS: &[i32] = &[SIGABRT, SIGTERM, SIGHUP, SIGPIPE, SIGTSTP];
+
+/// How often the handler is restarted after it failed
+const RESPAWN: usize = 10;
+
+/// Signals queued since [`register`], until the task takes them
+static REGISTERED: Mutex<Option<SignalsInfo<WithOrigin>>> = Mutex::new(None);
+
+builtin_fn!(Signals: handle_signals);
+
+impl IntoConfig for Signals {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml {
+            name: "builtin::signals".to_string(),
+            cmd: Self::box_fn(),
+            respawn: RespawnYaml::Retry(RESPAWN),
+            ..Default::default()
+        }
+    }
+}
+
+/// Catch the signals alfad handles before any task starts, they are queued
+/// until `builtin::signals` runs
+pub fn register() -> io::Result<()> {
+    *REGISTERED.lock().unwrap() = Some(SignalsInfo::new(SIGS)?);
+    Ok(())
+}
+
+async fn handle_signals(_: &TaskContext, context: ContextMap<'static>) -> Result<()> {
+    let registered = REGISTERED.lock().unwrap().take();
+    let mut signals = match registered {
+        Some(signals) => signals,
+        None => SignalsInfo::<WithOrigin>::new(SIGS)?,
+    };
+    let instance = Instance::current();
+    while let Some(origin) = signals.next().await {
+        // The session is over, init ignores SIGTERM
+        if origin.signal == SIGTERM && instance.is_user() {
+            // Inhibitors can't keep a session that is over
+            _ = schedule(SystemCommand::Poweroff, None, true, context);
+        }
+        if origin.signal == SIGHUP {
+            match perform("reload", context).await {
+                Ok(report) => info!("Staged the changes in the task files:\n{report}"),
+                Err(error) => warn!(%error, "Could not reload task files"),
+            }
+        }
+    }
+    bail!("No more signals are delivered")
+}
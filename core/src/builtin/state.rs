@@ -0,0 +1,180 @@
+use super::IntoConfig;
+use crate::{
+    builtin_fn,
+    config::{defaults::Defaults, name, yaml::TaskConfigYaml},
+    def::DIR_STATE,
+    task::{ChildProcess, ContextMap, TaskContext, TaskState},
+};
+use anyhow::Result;
+use futures::future::join_all;
+use smallvec::smallvec;
+use smol::{future, Timer};
+use std::{
+    fs::{self, File, Permissions},
+    io::{self, Write},
+    ops::ControlFlow,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::warn;
+
+builtin_fn!(StateDir: state_dir);
+
+impl IntoConfig for StateDir {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml {
+            name: "builtin::state::dir".to_string(),
+            // The ctl pipe is created in the same place, once /run is usable
+            after: smallvec!["builtin::ctl::create".to_owned()],
+            cmd: Self::box_fn(),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn state_path() -> PathBuf {
+    if cfg!(debug_assertions) {
+        Path::new("test/state").to_owned()
+    } else {
+        DIR_STATE.into()
+    }
+}
+
+async fn state_dir(_: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
+    let dir = state_path();
+    // Left over from the previous boot if /run is not a tmpfs
+    match fs::remove_dir_all(&dir) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+        _ => {}
+    }
+    if Defaults::load().state_dir {
+        write_states(&dir, context_map).await?;
+    }
+    Ok(())
+}
+
+/// Keep one file per task in `dir` up to date, until cancelled
+pub async fn write_states(dir: &Path, context_map: ContextMap<'_>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::set_permissions(dir, Permissions::from_mode(0o755))?;
+    join_all(context_map.0.iter().map(|(name, task)| track(dir, name, task))).await;
+    Ok(())
+}
+
+async fn track(dir: &Path, name: &str, task: &TaskContext) {
+    let path = dir.join(name::file_name(name));
+    loop {
+        let (state, child) = (task.state().await, task.child.get());
+        let written = smol::unblock({
+            let path = path.clone();
+            move || write_state(&path, state, child)
+        });
+        if let Err(error) = written.await {
+            warn!("Could not write {}: {error}", path.display());
+        }
+        smol::future::or(
+            async {
+                task.wait_until(|x| *x != state).await;
+            },
+            async {
+                task.child.wait_until(|x| *x != child).await;
+            },
+        )
+        .await;
+    }
+}
+
+/// Write the state of every task once more, for the changes the trackers
+/// did not get to before the system goes down. Gives up after `timeout`.
+pub async fn write_final(dir: &Path, context_map: ContextMap<'_>, timeout: Duration) -> io::Result<()> {
+    let states: Vec<_> =
+        context_map.0.iter().map(|(name, task)| (dir.join(name::file_name(name)), task.state_now(), task.child.get())).collect();
+    let written = smol::unblock(move || states.into_iter().try_for_each(|(path, state, child)| write_state(&path, state, child)));
+    future::or(written, async {
+        Timer::after(timeout).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, format!("not done after {timeout:?}")))
+    })
+    .await
+}
+
+/// Replace the state file atomically, so readers never see a partial file
+fn write_state(path: &Path, state: TaskState, child: Option<ChildProcess>) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{name}.tmp"));
+    let mut file = File::create(&tmp)?;
+    file.set_permissions(Permissions::from_mode(0o644))?;
+    writeln!(file, "state={}", state.name())?;
+    if let Some(child) = child {
+        writeln!(file, "pid={}", child.pid)?;
+    }
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_final, write_states};
+    use crate::task::{ChildProcess, ContextMap, ExitReason, TaskContext, TaskState};
+    use smol::Timer;
+    use std::{collections::HashMap, fs, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+    /// Poll the file until it has the expected content
+    async fn expect(path: &Path, content: &str) {
+        for _ in 0..500 {
+            if fs::read_to_string(path).is_ok_and(|x| x == content) {
+                return;
+            }
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        panic!("{} is {:?}, expected {content:?}", path.display(), fs::read_to_string(path));
+    }
+
+    #[test]
+    fn states_are_tracked() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("state");
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([
+            ("mount", TaskContext::default()),
+            ("getty", TaskContext::default()),
+        ]))));
+        let (mount, getty) = (&map.0["mount"], &map.0["getty"]);
+
+        smol::block_on(smol::future::or(
+            async {
+                write_states(&dir, map).await.unwrap();
+                unreachable!()
+            },
+            async {
+                expect(&dir.join("mount"), "state=Created\n").await;
+                expect(&dir.join("getty"), "state=Created\n").await;
+
+                mount.update_state(TaskState::Running(0)).await;
+                mount.child.set(Some(ChildProcess { pid: 42, start_time: None }));
+                expect(&dir.join("mount"), "state=Running\npid=42\n").await;
+
+                mount.child.set(None);
+                mount.update_state(TaskState::Concluded(ExitReason::Done)).await;
+                getty.update_state(TaskState::Concluded(ExitReason::Failed)).await;
+                expect(&dir.join("mount"), "state=Done\n").await;
+                expect(&dir.join("getty"), "state=Failed\n").await;
+            },
+        ));
+
+        assert_eq!(fs::metadata(dir.join("mount")).unwrap().permissions().mode() & 0o777, 0o644);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn final_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([("getty", TaskContext::default())]))));
+        smol::block_on(async {
+            map.0["getty"].update_state(TaskState::Concluded(ExitReason::Terminated)).await;
+            write_final(dir.path(), map, Duration::from_secs(10)).await.unwrap();
+        });
+        assert_eq!(fs::read_to_string(dir.path().join("getty")).unwrap(), "state=Terminated\n");
+
+        let missing = smol::block_on(write_final(&dir.path().join("missing"), map, Duration::from_secs(10)));
+        assert_eq!(missing.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+}
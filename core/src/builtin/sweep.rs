@@ -0,0 +1,131 @@
+use super::IntoConfig;
+use crate::{
+    builtin_fn,
+    config::{defaults::Defaults, yaml::TaskConfigYaml},
+    task::{ContextMap, ExitReason, TaskContext, TaskState},
+};
+use anyhow::Result;
+use std::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tracing::warn;
+
+builtin_fn!(Sweep: sweep);
+
+impl IntoConfig for Sweep {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml { name: "builtin::sweep".to_string(), cmd: Self::box_fn(), ..Default::default() }
+    }
+}
+
+/// Time a task gets to notice its dependencies by itself before it counts
+/// as stalled
+const GRACE: Duration = Duration::from_secs(1);
+
+static MISSED_WAKEUPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Tasks that were found waiting for dependencies that were ready long
+/// before, since alfad started. Should stay 0.
+pub fn missed_wakeups() -> usize {
+    MISSED_WAKEUPS.load(Ordering::Relaxed)
+}
+
+async fn sweep(_: &TaskContext, context_map: ContextMap<'static>) -> Result<()> {
+    let interval = Defaults::load().sweep_interval;
+    if interval == 0 {
+        return Ok(());
+    }
+    loop {
+        smol::Timer::after(Duration::from_secs(interval)).await;
+        sweep_once(context_map, GRACE).await;
+    }
+}
+
+/// Wake every task that is still Waiting although its dependencies are in
+/// the states it waits for, returns their names
+pub async fn sweep_once<'a>(context_map: ContextMap<'a>, grace: Duration) -> Vec<&'a str> {
+    let candidates: Vec<_> = context_map.0.iter().filter(|(_, task)| is_stalled(task, context_map)).collect();
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+    // Most likely they are just about to move on
+    smol::Timer::after(grace).await;
+
+    let mut stalled = Vec::new();
+    for (name, task) in candidates.into_iter().filter(|(_, task)| is_stalled(task, context_map)) {
+        let config = &task.config;
+        let states: Vec<_> = config
+            .with
+            .iter()
+            .chain(config.after.iter())
+            .filter_map(|dependency| Some((dependency.as_str(), context_map.0.get(dependency.as_str())?.state_now())))
+            .collect();
+        let total = MISSED_WAKEUPS.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(task = name, with = ?config.with, after = ?config.after, ?states, total, "Missed a wakeup, waking it");
+        for dependency in config.with.iter().chain(config.after.iter()) {
+            if let Some(dependency) = context_map.0.get(dependency.as_str()) {
+                dependency.wake();
+            }
+        }
+        stalled.push(*name);
+    }
+    stalled
+}
+
+/// Whether `task` waits although nothing holds it back. Only certain
+/// cases count, the drive loop might have passed a `with` dependency that
+/// is not running anymore.
+fn is_stalled(task: &TaskContext, context_map: ContextMap<'_>) -> bool {
+    if task.state_now() != TaskState::Waiting {
+        return false;
+    }
+    let state = |name: &String| context_map.0.get(name.as_str()).map(TaskContext::state_now);
+    let config = &task.config;
+    if config.payload.is_marker() {
+        // Markers wait for any member to start
+        let started = |state: Option<TaskState>| {
+            state.is_some_and(|state| state.is_running() || state == TaskState::Concluded(ExitReason::Done))
+        };
+        return config.with.iter().map(state).any(started);
+    }
+    config.with.iter().map(state).all(|state| state.is_some_and(|state| state.is_running()))
+        && config.after.iter().map(state).all(|state| state == Some(TaskState::Concluded(ExitReason::Done)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::sweep_once;
+    use crate::{
+        config::TaskConfig,
+        task::{drive, ContextMap, ExitReason, TaskContext, TaskState},
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    #[test]
+    fn recovers_missed_wakeup() {
+        let mut b = TaskConfig::new("b".to_owned());
+        b.after("a");
+        let tasks = [TaskConfig::new("a".to_owned()), b].map(|config| (&*config.name.clone().leak(), TaskContext::new(config)));
+        let map = ContextMap(Box::leak(Box::new(HashMap::from(tasks))));
+        let (a, b) = (&map.0["a"], &map.0["b"]);
+
+        smol::block_on(async {
+            let driver = smol::spawn(drive(b, map));
+            b.wait_until(|state| *state == TaskState::Waiting).await;
+            smol::Timer::after(Duration::from_millis(50)).await;
+            assert!(sweep_once(map, Duration::ZERO).await.is_empty());
+
+            // Done without telling anyone
+            a.update_state_silently(TaskState::Concluded(ExitReason::Done));
+            smol::Timer::after(Duration::from_millis(50)).await;
+            assert_eq!(b.state().await, TaskState::Waiting);
+
+            assert_eq!(sweep_once(map, Duration::ZERO).await, ["b"]);
+            driver.await;
+            assert_eq!(b.state().await, TaskState::Concluded(ExitReason::Done));
+            assert!(sweep_once(map, Duration::ZERO).await.is_empty());
+        });
+    }
+}
@@ -0,0 +1,207 @@
+//! Holds back tasks that need a correct wall clock, e.g. to check
+//! certificates, on boards without a battery backed RTC that boot in 1970.
+//! The clock counts as set once it is later than the time alfad was built,
+//! or once an NTP client reports it with
+//! `alfad-ctl notify TIME_SYNCHRONIZED=1`, whichever comes first.
+//!
+//! Without either, builtin::time-sync-wait concludes Skipped after
+//! `time_sync_max_wait` seconds so the rest of the boot goes on. Tasks
+//! that are `after: feature::time::synchronized` keep waiting.
+
+use super::{BuiltInService, IntoConfig};
+use crate::{
+    clock,
+    config::{
+        defaults::Defaults,
+        payload::Runnable,
+        yaml::{PayloadYaml, TaskConfigYaml},
+    },
+    state_cell::StateCell,
+    task::{ContextMap, ExitReason, TaskContext, TaskState},
+    version,
+};
+use async_trait::async_trait;
+use futures::Future;
+use std::{
+    ops::ControlFlow,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use strum::Display;
+use tracing::{info, warn};
+
+pub struct TimeSyncWait;
+
+impl IntoConfig for TimeSyncWait {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml {
+            name: "builtin::time-sync-wait".to_string(),
+            cmd: PayloadYaml::Builtin(BuiltInService::register(concat!(module_path!(), "::TimeSyncWait"), &TimeSyncWait)),
+            provides: vec!["time::synchronized".to_owned()],
+            ..Default::default()
+        }
+    }
+}
+
+/// How often the clock is read, NTP clients step it without telling anyone
+const POLL: Duration = Duration::from_secs(1);
+
+static SYNCHRONIZED: StateCell<bool> = StateCell::new(false);
+
+/// An NTP client reported the clock as synchronized
+pub fn notify_synchronized() {
+    SYNCHRONIZED.set(true);
+}
+
+/// What told that the clock is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Source {
+    /// It reads later than the threshold
+    Clock,
+    /// An NTP client said so
+    Notification,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Synchronized(Source),
+    TimedOut,
+}
+
+/// The earliest time the clock can be right, when alfad was built
+pub fn threshold() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(version::BUILD_TIME.parse().unwrap_or_default())
+}
+
+/// Whether a clock that reads `now` can have been set
+pub fn is_set(now: SystemTime, threshold: SystemTime) -> bool {
+    now > threshold
+}
+
+/// Wait until the clock read by `wall` passes `threshold` or `notified`
+/// becomes true, for at most `max`. A clock that is set already wins over
+/// a notification, both win over a timeout that expires at the same time.
+pub async fn wait<F: Future<Output = ()>>(
+    wall: impl Fn() -> SystemTime, threshold: SystemTime, notified: &StateCell<bool>, max: Duration,
+    sleep: impl Fn(Duration) -> F,
+) -> Outcome {
+    let timeout = sleep(max);
+    let clock = async {
+        while !is_set(wall(), threshold) {
+            sleep(POLL).await;
+        }
+        Outcome::Synchronized(Source::Clock)
+    };
+    let notification = async {
+        notified.wait_until(|notified| *notified).await;
+        Outcome::Synchronized(Source::Notification)
+    };
+    let timeout = async {
+        timeout.await;
+        Outcome::TimedOut
+    };
+    smol::future::or(clock, smol::future::or(notification, timeout)).await
+}
+
+#[async_trait]
+impl Runnable for TimeSyncWait {
+    async fn run<'a>(&'a self, context: &'a TaskContext, _: ContextMap<'static>) -> ControlFlow<TaskState> {
+        let max = Duration::from_secs(Defaults::load().time_sync_max_wait);
+        match wait(SystemTime::now, threshold(), &SYNCHRONIZED, max, clock::sleep).await {
+            Outcome::Synchronized(source) => {
+                info!(%source, "System clock is set");
+                ControlFlow::Continue(())
+            }
+            Outcome::TimedOut => {
+                warn!("System clock is still not set after {max:?}, going on without it");
+                *context.skipped.lock().unwrap() = Some(format!("System clock not set within {max:?}"));
+                ControlFlow::Break(TaskState::Concluded(ExitReason::Skipped))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_set, threshold, wait, Outcome, Source};
+    use crate::{clock::VirtualClock, state_cell::StateCell};
+    use std::{
+        cell::Cell,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    const STEP: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(30);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Wait with a clock that starts an hour before the threshold, jumps
+    /// past it at `jump` and a notification at `notify`. Returns how it
+    /// ended and when.
+    fn race(jump: Option<Duration>, notify: Option<Duration>, max: Duration) -> (Outcome, Duration) {
+        let clock = VirtualClock::new();
+        let threshold = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let offset = Cell::new(Duration::ZERO);
+        let notified = StateCell::new(false);
+        let wall = || threshold - Duration::from_secs(3600) + offset.get() + clock.elapsed();
+        let happen = || {
+            if jump.is_some_and(|at| clock.elapsed() >= at) {
+                offset.set(Duration::from_secs(7200));
+            }
+            if notify.is_some_and(|at| clock.elapsed() >= at) {
+                notified.set(true);
+            }
+        };
+        happen();
+        smol::block_on(async {
+            let driver = async {
+                loop {
+                    // Let the waiting side see it before time moves on
+                    smol::future::yield_now().await;
+                    clock.advance(STEP);
+                    happen();
+                }
+            };
+            let outcome = smol::future::or(wait(wall, threshold, &notified, max, |duration| clock.sleep(duration)), driver).await;
+            (outcome, clock.elapsed())
+        })
+    }
+
+    #[test]
+    fn threshold_is_build_time() {
+        let built = threshold();
+        // Later than when this test was written
+        assert!(built > UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert!(is_set(SystemTime::now(), built));
+        assert!(!is_set(built, built));
+        assert!(!is_set(UNIX_EPOCH, built));
+        assert!(is_set(built + Duration::from_secs(1), built));
+    }
+
+    #[test]
+    fn clock_is_set() {
+        // Read right away, even without time to wait
+        assert_eq!(race(Some(Duration::ZERO), None, MAX), (Outcome::Synchronized(Source::Clock), Duration::ZERO));
+        assert_eq!(race(Some(Duration::ZERO), None, Duration::ZERO).0, Outcome::Synchronized(Source::Clock));
+        // A step is only noticed when the clock is read again
+        assert_eq!(race(Some(ms(2500)), None, MAX), (Outcome::Synchronized(Source::Clock), ms(3000)));
+    }
+
+    #[test]
+    fn first_source_wins() {
+        assert_eq!(race(Some(ms(10000)), Some(ms(1200)), MAX), (Outcome::Synchronized(Source::Notification), ms(1200)));
+        assert_eq!(race(Some(ms(2000)), Some(ms(5000)), MAX), (Outcome::Synchronized(Source::Clock), ms(2000)));
+        // Both at once, the clock is read first
+        assert_eq!(race(Some(ms(4000)), Some(ms(4000)), MAX).0, Outcome::Synchronized(Source::Clock));
+    }
+
+    #[test]
+    fn times_out() {
+        assert_eq!(race(None, None, MAX), (Outcome::TimedOut, MAX));
+        assert_eq!(race(Some(ms(40000)), Some(ms(31000)), MAX), (Outcome::TimedOut, MAX));
+        // Only a source that is there already beats a timeout of zero
+        assert_eq!(race(None, Some(Duration::ZERO), Duration::ZERO).0, Outcome::Synchronized(Source::Notification));
+    }
+}
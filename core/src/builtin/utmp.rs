@@ -0,0 +1,274 @@
+use super::IntoConfig;
+use crate::{
+    action::SystemCommand,
+    builtin_fn,
+    config::yaml::TaskConfigYaml,
+    def::{FILE_UTMP, FILE_WTMP},
+    task::{ContextMap, TaskContext, TaskState},
+};
+use anyhow::Result;
+use nix::libc::utmpx;
+use smallvec::smallvec;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem::{self, offset_of},
+    ops::ControlFlow,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, warn};
+
+builtin_fn!(RecordBoot: record_boot);
+
+impl IntoConfig for RecordBoot {
+    fn into_config(self) -> TaskConfigYaml {
+        TaskConfigYaml {
+            name: "builtin::utmp".to_string(),
+            cmd: Self::box_fn(),
+            // utmp lives in /run, which is only there once it is mounted
+            after: smallvec!["feature::fs::run".to_owned()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Size of a `struct utmp` on this architecture, 384 bytes on most
+pub const RECORD_SIZE: usize = mem::size_of::<utmpx>();
+
+/// Width of both fields of `ut_tv`. 32 bit on x86_64 to match 32 bit
+/// systems, but not on aarch64 for one.
+const TIME_SIZE: usize = (offset_of!(utmpx, ut_addr_v6) - offset_of!(utmpx, ut_tv)) / 2;
+
+const _: () = assert!(TIME_SIZE == 4 || TIME_SIZE == 8, "Unknown layout of struct utmp");
+
+/// alfad has no runlevels, this is what `who -r` shows while it runs
+pub const RUNLEVEL: u8 = b'5';
+
+/// Runlevel before the first one
+const NO_RUNLEVEL: u8 = b'N';
+
+/// Whether the boot made it into utmp, shutdown is only recorded then
+static RECORDED: AtomicBool = AtomicBool::new(false);
+
+/// `ut_type` of the records alfad writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i16)]
+pub enum RecordType {
+    RunLevel = 1,
+    BootTime = 2,
+}
+
+/// A utmp record as init writes them, everything else stays zeroed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub kind: RecordType,
+    pub pid: i32,
+    pub user: &'static str,
+    /// Kernel release, `last` shows it
+    pub host: String,
+    pub time: SystemTime,
+}
+
+impl Record {
+    pub fn boot(host: String, time: SystemTime) -> Self {
+        Self { kind: RecordType::BootTime, pid: 0, user: "reboot", host, time }
+    }
+
+    /// A change of runlevel, `who -r` finds both in the pid
+    pub fn runlevel(from: u8, to: u8, host: String, time: SystemTime) -> Self {
+        Self { kind: RecordType::RunLevel, pid: i32::from(from) * 256 + i32::from(to), user: "runlevel", host, time }
+    }
+
+    pub fn shutdown(command: &SystemCommand, host: String, time: SystemTime) -> Self {
+        let to = match command {
+            SystemCommand::Restart => b'6',
+            SystemCommand::Poweroff | SystemCommand::Halt => b'0',
+        };
+        Self { user: "shutdown", ..Self::runlevel(RUNLEVEL, to, host, time) }
+    }
+
+    /// The record in the layout of the C library's `struct utmp`
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        let mut put = |offset: usize, field: &[u8], size: usize| {
+            let len = field.len().min(size);
+            bytes[offset..offset + len].copy_from_slice(&field[..len]);
+        };
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put(offset_of!(utmpx, ut_type), &(self.kind as i16).to_ne_bytes(), 2);
+        put(offset_of!(utmpx, ut_pid), &self.pid.to_ne_bytes(), 4);
+        put(offset_of!(utmpx, ut_line), b"~", 32);
+        put(offset_of!(utmpx, ut_id), b"~~", 4);
+        put(offset_of!(utmpx, ut_user), self.user.as_bytes(), 32);
+        put(offset_of!(utmpx, ut_host), self.host.as_bytes(), 256);
+        let tv = offset_of!(utmpx, ut_tv);
+        put(tv, &time_field(time.as_secs()), TIME_SIZE);
+        put(tv + TIME_SIZE, &time_field(u64::from(time.subsec_micros())), TIME_SIZE);
+        bytes
+    }
+}
+
+/// `value` as a field of `ut_tv`, 32 bit seconds stop at 2038
+fn time_field(value: u64) -> Vec<u8> {
+    match TIME_SIZE {
+        4 => i32::try_from(value).unwrap_or(i32::MAX).to_ne_bytes().to_vec(),
+        _ => i64::try_from(value).unwrap_or(i64::MAX).to_ne_bytes().to_vec(),
+    }
+}
+
+/// Add `record` to the end of a wtmp style log
+pub fn append(path: &Path, record: &Record) -> io::Result<()> {
+    OpenOptions::new().append(true).open(path)?.write_all(&record.encode())
+}
+
+/// Overwrite the first record of the same type in utmp, or add it
+pub fn update(path: &Path, record: &Record) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let kind = (record.kind as i16).to_ne_bytes();
+    let offset = bytes.chunks_exact(RECORD_SIZE).position(|existing| existing[..2] == kind).unwrap_or(bytes.len() / RECORD_SIZE);
+    file.seek(SeekFrom::Start((offset * RECORD_SIZE) as u64))?;
+    file.write_all(&record.encode())
+}
+
+/// Start utmp over with the boot and the runlevel, and log both to wtmp if
+/// it exists
+fn record_boot_in(utmp: &Path, wtmp: &Path, host: String, boot: SystemTime, now: SystemTime) -> io::Result<()> {
+    let records = [Record::boot(host.clone(), boot), Record::runlevel(NO_RUNLEVEL, RUNLEVEL, host, now)];
+    let mut file = File::create(utmp)?;
+    records.iter().try_for_each(|record| file.write_all(&record.encode()))?;
+    if wtmp.exists() {
+        records.iter().try_for_each(|record| append(wtmp, record))?;
+    }
+    Ok(())
+}
+
+fn record_shutdown_in(utmp: &Path, wtmp: &Path, record: &Record) -> io::Result<()> {
+    update(utmp, record)?;
+    if wtmp.exists() {
+        append(wtmp, record)?;
+    }
+    Ok(())
+}
+
+fn kernel_release() -> String {
+    fs::read_to_string("/proc/sys/kernel/osrelease").map(|release| release.trim().to_owned()).unwrap_or_default()
+}
+
+/// Seconds since boot, the first field of /proc/uptime
+fn parse_uptime(uptime: &str) -> Option<Duration> {
+    uptime.split_whitespace().next()?.parse().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+fn boot_time(now: SystemTime) -> SystemTime {
+    fs::read_to_string("/proc/uptime")
+        .ok()
+        .and_then(|uptime| parse_uptime(&uptime))
+        .and_then(|uptime| now.checked_sub(uptime))
+        .unwrap_or(now)
+}
+
+async fn record_boot(_: &TaskContext, _: ContextMap<'static>) -> Result<()> {
+    let now = SystemTime::now();
+    if let Err(error) = record_boot_in(Path::new(FILE_UTMP), Path::new(FILE_WTMP), kernel_release(), boot_time(now), now) {
+        warn!("Could not record the boot in {FILE_UTMP}: {error}");
+        return Ok(());
+    }
+    RECORDED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Record the shutdown, if the boot was recorded
+pub fn record_shutdown(command: &SystemCommand) {
+    if !RECORDED.load(Ordering::Relaxed) {
+        debug!("Boot was not recorded in {FILE_UTMP}, not recording the shutdown either");
+        return;
+    }
+    let record = Record::shutdown(command, kernel_release(), SystemTime::now());
+    if let Err(error) = record_shutdown_in(Path::new(FILE_UTMP), Path::new(FILE_WTMP), &record) {
+        warn!("Could not record the shutdown in {FILE_UTMP}: {error}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        parse_uptime, record_boot_in, record_shutdown_in, time_field, Record, NO_RUNLEVEL, RECORD_SIZE, RUNLEVEL, TIME_SIZE,
+    };
+    use crate::action::SystemCommand;
+    use nix::libc::utmpx;
+    use std::{
+        fs,
+        mem::offset_of,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    // Written by glibc's updwtmp() on x86_64, see generate.c next to them
+    const BOOT: &[u8] = include_bytes!("../../tests/fixtures/utmp/boot.bin");
+    const RUNLEVEL_CHANGE: &[u8] = include_bytes!("../../tests/fixtures/utmp/runlevel.bin");
+    const SHUTDOWN: &[u8] = include_bytes!("../../tests/fixtures/utmp/shutdown.bin");
+
+    const HOST: &str = "6.1.0-alfad";
+
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)
+    }
+
+    #[test]
+    fn glibc_layout() {
+        assert_eq!(Record::boot(HOST.into(), time()).encode(), BOOT);
+        assert_eq!(Record::runlevel(NO_RUNLEVEL, RUNLEVEL, HOST.into(), time()).encode(), RUNLEVEL_CHANGE);
+        assert_eq!(Record::shutdown(&SystemCommand::Poweroff, HOST.into(), time()).encode(), SHUTDOWN);
+        assert_eq!(Record::shutdown(&SystemCommand::Restart, HOST.into(), time()).pid, i32::from(b'5') * 256 + i32::from(b'6'));
+
+        // Cut to the size of the field
+        let record = Record::boot("x".repeat(300), time()).encode();
+        assert_eq!(&record[76..332], "x".repeat(256).as_bytes());
+        assert_eq!(record[332], 0);
+    }
+
+    #[test]
+    fn time_fields() {
+        assert_eq!(time_field(1_700_000_000).len(), TIME_SIZE);
+        let after_2038 = Record::boot(HOST.into(), UNIX_EPOCH + Duration::from_secs(1 << 32)).encode();
+        let tv = offset_of!(utmpx, ut_tv);
+        let seconds = &after_2038[tv..tv + TIME_SIZE];
+        match TIME_SIZE {
+            4 => assert_eq!(seconds, i32::MAX.to_ne_bytes()),
+            _ => assert_eq!(seconds, (1_i64 << 32).to_ne_bytes()),
+        }
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!((RECORD_SIZE, TIME_SIZE), (384, 4));
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!((RECORD_SIZE, TIME_SIZE), (400, 8));
+    }
+
+    #[test]
+    fn boot_and_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let (utmp, wtmp) = (dir.path().join("utmp"), dir.path().join("wtmp"));
+        fs::write(&utmp, "stale from the last boot").unwrap();
+        record_boot_in(&utmp, &wtmp, HOST.into(), time(), time()).unwrap();
+        assert_eq!(fs::read(&utmp).unwrap(), [BOOT, RUNLEVEL_CHANGE].concat());
+        assert!(!wtmp.exists());
+
+        fs::write(&wtmp, "").unwrap();
+        record_boot_in(&utmp, &wtmp, HOST.into(), time(), time()).unwrap();
+        let shutdown = Record::shutdown(&SystemCommand::Poweroff, HOST.into(), time());
+        record_shutdown_in(&utmp, &wtmp, &shutdown).unwrap();
+        // utmp keeps the current runlevel only, wtmp all of them
+        assert_eq!(fs::read(&utmp).unwrap(), [BOOT, SHUTDOWN].concat());
+        assert_eq!(fs::read(&wtmp).unwrap(), [BOOT, RUNLEVEL_CHANGE, SHUTDOWN].concat());
+        assert_eq!(fs::read(&wtmp).unwrap().len(), 3 * RECORD_SIZE);
+    }
+
+    #[test]
+    fn uptime() {
+        assert_eq!(parse_uptime("12.50 40.00\n"), Some(Duration::from_millis(12_500)));
+        assert_eq!(parse_uptime(""), None);
+        assert_eq!(parse_uptime("-1 0"), None);
+    }
+}
@@ -0,0 +1,444 @@
+use crate::{
+    builtin,
+    config::{self, defaults::Defaults, schema, yaml::TaskConfigYaml, TaskConfig},
+    def::{APLT_CHECK, DIR_CFG_D, FILE_CFG_BT, FILE_DEFAULTS, SRC_BUILTIN},
+    ordering::{construct_markers, inherit_from_groups, reserved_prefix},
+    validate::{self, Resolver, Severity, ValidationReport},
+};
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Validate a configuration directory without booting
+#[derive(Debug, Parser)]
+#[command(name = APLT_CHECK)]
+pub struct CheckArgs {
+    /// Directory containing the task files
+    #[arg(default_value = DIR_CFG_D)]
+    dir: PathBuf,
+    /// Root file system the programs of the tasks are looked up in
+    #[arg(long, default_value = "/")]
+    root: PathBuf,
+    /// Also validate the task files against their JSON Schema, which
+    /// points out fields alfad ignores and suggests values
+    #[arg(long)]
+    schema_check: bool,
+}
+
+pub fn run(args: Vec<String>) -> Result<()> {
+    let args = CheckArgs::parse_from(args);
+    let mut report = check(&args.dir, &args.root, builtin::all());
+    if args.schema_check {
+        report.findings.extend(schema_check(&args.dir).findings);
+    }
+    for finding in report.findings.iter() {
+        println!("{finding}");
+    }
+    match report.errors() {
+        0 => Ok(()),
+        errors => bail!("{errors} error(s) in {}", args.dir.display()),
+    }
+}
+
+/// Run the task files in `dir` through the same steps as on boot, but
+/// collect every problem instead of skipping broken tasks. Programs are
+/// looked up in `root`, with the search path from the `defaults.yaml` next
+/// to `dir`. An `alfad.bin` next to `dir` is compared against the sources.
+pub fn check(dir: &Path, root: &Path, builtin: Vec<TaskConfigYaml>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let mut files: HashMap<String, PathBuf> = HashMap::new();
+    let mut configs = Vec::new();
+    let parent = dir.parent().unwrap_or(dir);
+    let defaults = Defaults::load_from(&parent.join(FILE_DEFAULTS), "");
+
+    let files_found = task_files(dir, &mut report);
+    if let Err(error) = defaults.limits.check_count(files_found.len()) {
+        report.push_file(Severity::Error, dir, error.to_string());
+    }
+    for file in files_found {
+        let size = fs::metadata(&file).map(|metadata| metadata.len()).unwrap_or_default();
+        if let Err(error) = defaults.limits.check_file_size(size) {
+            report.push_file(Severity::Error, &file, error.to_string());
+            continue;
+        }
+        let config = match fs::read_to_string(&file).map(|text| serde_yaml::from_str::<TaskConfigYaml>(&text)) {
+            Ok(Ok(config)) => config,
+            Ok(Err(error)) => {
+                report.push_file(Severity::Error, &file, error.to_string());
+                continue;
+            }
+            Err(error) => {
+                report.push_file(Severity::Error, &file, error.to_string());
+                continue;
+            }
+        };
+        if let Err(error) = config::name::check(&config.name) {
+            report.push_file(Severity::Error, &file, error.to_string());
+            continue;
+        }
+        if let Err(error) = defaults.limits.check_task(&config) {
+            report.push_file(Severity::Error, &file, error.to_string());
+            continue;
+        }
+        if let Some(prefix) = reserved_prefix(&config) {
+            report.push_file(Severity::Error, &file, config::reserved_message(&config.name, prefix));
+            continue;
+        }
+        if let Some(other) = files.get(&config.name) {
+            report.push_file(Severity::Error, &file, format!("{} is already defined in {}", config.name, other.display()));
+            continue;
+        }
+        files.insert(config.name.clone(), file.clone());
+        configs.push(TaskConfigYaml { source: Some(file), ..config });
+    }
+
+    let overridden: Vec<_> = configs.iter().filter_map(|config| config.cmd.builtin_key()).collect();
+    let builtin = builtin::without(builtin, &overridden);
+    configs.extend(builtin);
+    report.findings.extend(construct_markers(&mut configs, &defaults.groups).findings);
+    inherit_from_groups(&mut configs);
+
+    let names: HashSet<_> = configs.iter().map(|config| config.name.clone()).collect();
+    for config in configs.iter().filter(|config| !config.before.is_empty()) {
+        let (name, source) = (Some(config.name.as_str()), config.source.as_deref());
+        if !cfg!(feature = "before") {
+            let message = format!("{} wants to run before others, which is ignored without the before feature", config.name);
+            report.push(Severity::Warning, name, source, message);
+            continue;
+        }
+        for target in config.before.iter().filter(|target| !names.contains(*target)) {
+            let message = format!("{} tried to run before {target}, which does not exist", config.name);
+            report.push(Severity::Warning, name, source, message);
+        }
+    }
+    let configs = if cfg!(feature = "before") { crate::ordering::resolve_before(configs) } else { configs };
+
+    let mut configs: Vec<TaskConfig> = configs
+        .into_iter()
+        .filter_map(|config| {
+            let (name, source) = (config.name.clone(), config.source.clone());
+            config
+                .into_config()
+                .and_then(TaskConfig::parse_payload)
+                .map_err(|error| report.push(Severity::Error, Some(&name), source.as_deref(), format!("{name}: {error}")))
+                .ok()
+        })
+        .collect();
+    configs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let tree = validate::report(&configs, true);
+    report.findings.extend(tree.findings);
+
+    let programs = validate::programs(&configs, &Resolver::new(root, &defaults.path), Severity::Error);
+    report.findings.extend(programs.findings);
+
+    if let Some(parent) = dir.parent() {
+        check_binary(&parent.join(FILE_CFG_BT), &configs, &mut report);
+    }
+    report
+}
+
+/// Every task file in `dir` against the schema of task files, see
+/// [`schema`]. Fields alfad ignores are warnings, files that are no YAML
+/// at all are left to [`check`].
+pub fn schema_check(dir: &Path) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let schema = schema::task();
+    for file in task_files(dir, &mut report) {
+        let Some(value) = fs::read_to_string(&file).ok().and_then(|text| schema::read(&text).ok()) else {
+            continue;
+        };
+        for error in schema::validate(&schema, &value) {
+            let severity = if error.unknown_field { Severity::Warning } else { Severity::Error };
+            report.push_file(severity, &file, error.to_string());
+        }
+    }
+    report
+}
+
+/// All entries of the config directory, in a stable order
+fn task_files(dir: &Path, report: &mut ValidationReport) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            report.push_file(Severity::Error, dir, format!("Could not read config directory: {error}"));
+            return Vec::new();
+        }
+    };
+    let mut files: Vec<_> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+    files.sort();
+    files
+}
+
+/// Make sure a compiled configuration would boot the same tasks as the
+/// sources. Dependency lists are compared unordered, since their order
+/// depends on hash map iteration while resolving `before`.
+fn check_binary(path: &Path, configs: &[TaskConfig], report: &mut ValidationReport) {
+    let packed = match fs::read(path) {
+        Ok(packed) => packed,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return,
+        Err(error) => return report.push_file(Severity::Error, path, error.to_string()),
+    };
+    let compiled = match config::decode(&packed) {
+        Ok(compiled) => compiled,
+        Err(error) => return report.push_file(Severity::Error, path, error.to_string()),
+    };
+
+    let compiled: HashMap<_, _> = compiled.iter().map(|config| (config.name.as_str(), fingerprint(config))).collect();
+    let sources: HashMap<_, _> = configs.iter().map(|config| (config.name.as_str(), fingerprint(config))).collect();
+    // Builtins are only cached when a task file changed them
+    let builtin: HashSet<_> = configs
+        .iter()
+        .filter(|config| config.source.as_deref() == Some(Path::new(SRC_BUILTIN)))
+        .map(|config| config.name.as_str())
+        .collect();
+
+    let mut names: Vec<_> = compiled.keys().chain(sources.keys()).collect::<HashSet<_>>().into_iter().collect();
+    names.sort();
+    for name in names {
+        let message = match (sources.get(name), compiled.get(name)) {
+            (Some(_), None) if builtin.contains(name) => continue,
+            (Some(_), None) => format!("{name} is missing, recompile the configuration"),
+            (None, Some(_)) => format!("{name} does not exist in the sources anymore, recompile the configuration"),
+            (Some(source), Some(compiled)) if source != compiled => {
+                format!("{name} differs from its source, recompile the configuration")
+            }
+            _ => continue,
+        };
+        report.push_file(Severity::Error, path, message);
+    }
+}
+
+fn fingerprint(config: &TaskConfig) -> Option<Vec<u8>> {
+    let mut after = config.after.clone();
+    let mut after_states = config.after_states.clone();
+    let mut with = config.with.clone();
+    after.sort();
+    after_states.sort_by(|a, b| a.0.cmp(&b.0));
+    with.sort();
+    let descriptive = (&config.group, &config.description, &config.doc_url);
+    let dependencies = (after, after_states, with);
+    postcard::to_allocvec(&(&config.payload, dependencies, &config.respawn, descriptive, &config.env_keep)).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::check;
+    use crate::{
+        builtin,
+        config,
+        def::SRC_BUILTIN,
+        validate::Severity,
+    };
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+        path::Path,
+    };
+
+    fn fixture(dir: &Path, files: &[(&str, &str)]) {
+        fs::create_dir_all(dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+    }
+
+    /// A root file system containing the programs used by CLEAN
+    fn sysroot() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("bin")).unwrap();
+        for program in ["mount", "ip", "getty"] {
+            fs::write(root.path().join("bin").join(program), "").unwrap();
+            fs::set_permissions(root.path().join("bin").join(program), Permissions::from_mode(0o755)).unwrap();
+        }
+        root
+    }
+
+    const CLEAN: &[(&str, &str)] = &[
+        ("mount.task", "name: mount\ncmd: mount -a\nprovides: fs::run\n"),
+        ("network.task", "name: network\ncmd: ip link set eth0 up\nafter: mount\n"),
+        ("getty.task", "name: getty\ncmd: getty tty1\nafter: [mount, network]\nrespawn: 0\n"),
+    ];
+
+    #[test]
+    fn clean_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fixture(&dir, CLEAN);
+
+        let report = check(&dir, sysroot().path(), builtin::all());
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+    }
+
+    #[test]
+    fn builtin_override() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fixture(&dir, CLEAN);
+        fixture(&dir, &[("ctl.task", "name: builtin::ctl::create\ncmd: {builtin: ctl::create}\nafter: network\n")]);
+        let sysroot = sysroot();
+        fs::write(root.path().join("alfad.bin"), config::compile(&dir, builtin::all()).unwrap()).unwrap();
+        let report = check(&dir, sysroot.path(), builtin::all());
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+
+        // A builtin changed by `before` is part of the cache
+        fixture(&dir, &[("early.task", "name: early\ncmd: mount -a\nbefore: builtin::ctl::daemon\n")]);
+        fs::write(root.path().join("alfad.bin"), config::compile(&dir, builtin::all()).unwrap()).unwrap();
+        let report = check(&dir, sysroot.path(), builtin::all());
+        let ignored = usize::from(!cfg!(feature = "before"));
+        assert_eq!((report.findings.len(), report.errors()), (ignored, 0), "{:?}", report.findings);
+
+        fs::remove_file(root.path().join("alfad.bin")).unwrap();
+        fs::remove_file(dir.join("early.task")).unwrap();
+        fixture(&dir, &[("typo.task", "name: typo\ncmd: {builtin: ctl::craete}\n")]);
+        let report = check(&dir, sysroot.path(), builtin::all());
+        assert_eq!(report.errors(), 1);
+        assert!(report.findings[0].message.starts_with("typo: Unknown builtin 'ctl::craete', available are ctl::create"));
+    }
+
+    #[test]
+    fn missing_programs() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fixture(&dir, CLEAN);
+        let sysroot = sysroot();
+        fs::remove_file(sysroot.path().join("bin/ip")).unwrap();
+
+        let report = check(&dir, sysroot.path(), builtin::all());
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.findings[0].file.as_deref(), Some(dir.join("network.task").as_path()));
+
+        fs::write(root.path().join("defaults.yaml"), "path: /usr/bin\n").unwrap();
+        assert_eq!(check(&dir, sysroot.path(), builtin::all()).errors(), 3);
+    }
+
+    #[test]
+    fn broken_directory() {
+        let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../test/alfad.d"));
+        // The test tasks only run echo, sleep and false
+        let report = check(dir, Path::new("/"), builtin::all());
+
+        let errors = |file: &str| {
+            report
+                .findings
+                .iter()
+                .filter(|finding| finding.severity == Severity::Error)
+                .filter(|finding| finding.file.as_deref() == Some(&dir.join(file)))
+                .count()
+        };
+        assert_eq!(errors("broken-file.task"), 1);
+        assert_eq!(errors("loop-A.task"), 1);
+        assert_eq!(errors("with-self.task"), 1);
+        assert_eq!(errors("foo.task"), 0);
+        // The builtin has no file, but its dependency is missing
+        assert!(report.findings.iter().any(|finding| {
+            finding.task.as_deref() == Some("builtin::ctl::create") && finding.file.as_deref() == Some(Path::new(SRC_BUILTIN))
+        }));
+    }
+
+    #[test]
+    fn schema_check() {
+        let root = tempfile::tempdir().unwrap();
+        fixture(root.path(), &[("web.task", "name: web\ncmd: nginx\nrespawn_recheck: wiht\nafetr: db\n")]);
+        fixture(root.path(), &[("db.task", "name: db\ncmd: postgres\n")]);
+
+        let report = super::schema_check(root.path());
+        let findings: Vec<_> = report.findings.iter().map(|finding| (finding.severity, finding.message.as_str())).collect();
+        assert_eq!(
+            findings,
+            [
+                (Severity::Warning, "afetr: Unknown field afetr, alfad ignores it, did you mean after?"),
+                (Severity::Error, "respawn_recheck: 'wiht' is not one of none, with, all, did you mean 'with'?"),
+            ]
+        );
+        assert!(report.findings.iter().all(|finding| finding.file.as_deref() == Some(&root.path().join("web.task"))));
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let root = tempfile::tempdir().unwrap();
+        fixture(root.path(), &[("a.task", "name: a\ncmd: \"true\"\n"), ("b.task", "name: a\ncmd: \"false\"\n")]);
+
+        let report = check(root.path(), Path::new("/"), vec![]);
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.findings[0].file.as_deref(), Some(root.path().join("b.task").as_path()));
+    }
+
+    #[test]
+    fn reserved_names() {
+        let root = tempfile::tempdir().unwrap();
+        fixture(root.path(), &[("fake.task", "name: target::default\ncmd: \"true\"\n")]);
+        fixture(root.path(), &[("default.task", "name: target::multi-user\ncmd: marker\n")]);
+
+        let report = check(root.path(), Path::new("/"), vec![]);
+        assert_eq!(report.errors(), 1, "{:?}", report.findings);
+        assert_eq!(report.findings[0].file.as_deref(), Some(root.path().join("fake.task").as_path()));
+        assert!(report.findings[0].message.contains("reserved"));
+    }
+
+    #[test]
+    fn invalid_names() {
+        let root = tempfile::tempdir().unwrap();
+        fixture(root.path(), &[("evil.task", "name: \"../../etc/passwd\\nx\"\ncmd: \"true\"\n")]);
+        fixture(root.path(), &[("dash.task", "name: -rf\ncmd: \"true\"\n")]);
+
+        let report = check(root.path(), Path::new("/"), vec![]);
+        assert_eq!(report.errors(), 2, "{:?}", report.findings);
+        assert!(report.findings.iter().all(|finding| finding.message.starts_with("Task name") && !finding.message.contains('\n')));
+    }
+
+    #[test]
+    fn marker_names() {
+        let root = tempfile::tempdir().unwrap();
+        fixture(root.path(), &[("dhcp.task", "name: dhcp\ncmd: \"true\"\ngroup: network\nprovides: [network]\n")]);
+        fixture(root.path(), &[("ntp.task", "name: ntp\ncmd: \"true\"\nafter: [feature::network, dhcp, dhcp]\n")]);
+
+        let report = check(root.path(), Path::new("/"), vec![]);
+        assert_eq!((report.findings.len(), report.errors()), (2, 1), "{:?}", report.findings);
+        let file = |finding: usize| report.findings[finding].file.as_ref().unwrap().file_name().unwrap().to_owned();
+        assert_eq!((file(0), file(1)), ("ntp.task".into(), "dhcp.task".into()));
+    }
+
+    #[test]
+    fn compiled_config() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fixture(&dir, CLEAN);
+        let sysroot = sysroot();
+
+        fs::write(root.path().join("alfad.bin"), config::compile(&dir, builtin::all()).unwrap()).unwrap();
+        assert_eq!(check(&dir, sysroot.path(), builtin::all()).errors(), 0);
+
+        fixture(&dir, &[("network.task", "name: network\ncmd: ip link set eth1 up\nafter: mount\n")]);
+        assert_eq!(check(&dir, sysroot.path(), builtin::all()).errors(), 1);
+
+        fs::write(root.path().join("alfad.bin"), config::encode(config::CACHE_FORMAT + 1, &[]).unwrap()).unwrap();
+        let report = check(&dir, sysroot.path(), builtin::all());
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.findings[0].file.as_deref(), Some(root.path().join("alfad.bin").as_path()));
+    }
+
+    #[test]
+    fn limits() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fixture(&dir, CLEAN);
+        let sysroot = sysroot();
+        let limits = "limits:\n  max_dependencies: 1\n  max_file_size: 200\n";
+        fs::write(root.path().join("defaults.yaml"), limits).unwrap();
+        fixture(&dir, &[("network.task", "name: network\ncmd: ip link set eth1 up\nafter: [mount, udev]\n")]);
+        fixture(&dir, &[("huge.task", &format!("name: huge\ncmd: echo {}\n", "x".repeat(200)))]);
+
+        let report = check(&dir, sysroot.path(), builtin::all());
+        let message = |file: &str| {
+            let finding = report.findings.iter().find(|finding| finding.file.as_deref() == Some(&dir.join(file))).unwrap();
+            finding.message.clone()
+        };
+        assert_eq!(message("network.task"), "network has 2 entries in after, only 1 are allowed");
+        assert!(message("huge.task").starts_with("File has 222 bytes"));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{
+    action::ActionError,
+    def::APLT_CTL,
+    instance::Instance,
+    protocol::{self, ErrorKind, ProtocolError, Reply},
+};
+use nix::{libc::O_NONBLOCK, sys::stat::Mode, unistd::mkfifo};
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use strum::EnumIter;
+use thiserror::Error;
+
+/// How long to wait for the daemon to answer a request
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit codes of alfad-ctl, as listed in its help
+pub const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  any other error
+  2  usage error, the command could not be parsed
+  3  task not found
+  4  timeout, alfad did not answer or the task did not stop in time
+  5  alfad is not reachable
+  6  alfad speaks an incompatible protocol version";
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("alfad communication socket not found ({})", .0)]
+    Unreachable(io::Error),
+    #[error("Could not write to the alfad communication socket ({})", .0)]
+    NotSent(io::Error),
+    #[error("alfad did not answer within {:?}", REPLY_TIMEOUT)]
+    Timeout,
+    /// The daemon answered with an error
+    #[error("{}", .message)]
+    Refused { message: String, kind: ErrorKind },
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    IO(#[from] io::Error),
+}
+
+/// What alfad-ctl exits with, scripts rely on these staying the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum ExitCode {
+    Success = 0,
+    Failed = 1,
+    Usage = 2,
+    TaskNotFound = 3,
+    Timeout = 4,
+    Unreachable = 5,
+    Protocol = 6,
+}
+
+impl ExitCode {
+    /// The code for an error of any of the client paths
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(error) = error.downcast_ref::<ClientError>() {
+            error.into()
+        } else if error.downcast_ref::<ProtocolError>().is_some() {
+            ExitCode::Protocol
+        } else if let Some(error) = error.downcast_ref::<ActionError>() {
+            error.into()
+        } else {
+            ExitCode::Failed
+        }
+    }
+}
+
+impl From<ErrorKind> for ExitCode {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Failed => ExitCode::Failed,
+            ErrorKind::Usage => ExitCode::Usage,
+            ErrorKind::TaskNotFound => ExitCode::TaskNotFound,
+            ErrorKind::Timeout => ExitCode::Timeout,
+            ErrorKind::Protocol => ExitCode::Protocol,
+        }
+    }
+}
+
+impl From<&ActionError> for ExitCode {
+    fn from(error: &ActionError) -> Self {
+        ErrorKind::from(error).into()
+    }
+}
+
+impl From<&ClientError> for ExitCode {
+    fn from(error: &ClientError) -> Self {
+        match error {
+            ClientError::Unreachable(_) | ClientError::NotSent(_) => ExitCode::Unreachable,
+            ClientError::Timeout => ExitCode::Timeout,
+            ClientError::Refused { kind, .. } => (*kind).into(),
+            ClientError::Protocol(_) => ExitCode::Protocol,
+            ClientError::IO(_) => ExitCode::Failed,
+        }
+    }
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> Self {
+        code as i32
+    }
+}
+
+/// The body of a reply, or the error the daemon answered with
+pub fn body(reply: Reply) -> Result<String, ClientError> {
+    match reply {
+        Reply::Ok(body) => Ok(body),
+        Reply::Error { message, kind } => Err(ClientError::Refused { message, kind }),
+    }
+}
+
+/// A request line asking for the reply to be written into the FIFO at `reply_to`
+pub fn request_line(reply_to: &Path, action: &impl Display) -> String {
+    format!("@{} {action}", reply_to.display())
+}
+
+/// Split a request line into the FIFO to reply to (if any) and the action
+pub fn split_request(line: &str) -> (Option<&Path>, &str) {
+    match line.strip_prefix('@').and_then(|line| line.split_once(' ')) {
+        Some((path, action)) => (Some(Path::new(path)), action),
+        None => (None, line),
+    }
+}
+
+/// Write a line into the control pipe. Fails instead of blocking if
+/// nobody is reading on the other side. The daemon keeps the pipe open for
+/// writing itself, so the newline rather than EOF ends the request.
+pub fn send(line: &str) -> Result<(), ClientError> {
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(O_NONBLOCK)
+        .open(Instance::current().run_dir().join(APLT_CTL))
+        .map_err(ClientError::Unreachable)?
+        .write_all(format!("{line}\n").as_bytes())
+        .map_err(ClientError::NotSent)
+}
+
+/// Send an action and wait for the daemon to reply, in the newest
+/// protocol version both understand
+pub fn request(action: &impl Display) -> Result<Reply, ClientError> {
+    let dir = Instance::current().reply_dir();
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(process::id().to_string());
+    let _ = fs::remove_file(&path);
+    mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(io::Error::from)?;
+    let _cleanup = RemoveOnDrop(&path);
+
+    protocol::converse(&action.to_string(), |body| {
+        // Open the read end first, otherwise the daemon can not open the
+        // reply FIFO without blocking and drops the reply.
+        let reply = open_reply(&path)?;
+        send(&request_line(&path, &body))?;
+        read_reply(reply, REPLY_TIMEOUT)
+    })
+}
+
+fn open_reply(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).custom_flags(O_NONBLOCK).open(path)
+}
+
+/// Read the whole reply from the FIFO. Until the daemon opens the other end
+/// reads return nothing, so the FIFO is polled on a separate thread.
+fn read_reply(mut reply: File, timeout: Duration) -> Result<String, ClientError> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 512];
+        let result = loop {
+            match reply.read(&mut chunk) {
+                Ok(0) if !buf.is_empty() => break Ok(String::from_utf8_lossy(&buf).into_owned()),
+                Ok(bytes) if bytes > 0 => buf.extend_from_slice(&chunk[..bytes]),
+                Err(error) if error.kind() != io::ErrorKind::WouldBlock => break Err(error),
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let _ = tx.send(result);
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(reply) => Ok(reply?),
+        Err(_) => Err(ClientError::Timeout),
+    }
+}
+
+struct RemoveOnDrop<'a>(&'a Path);
+
+impl Drop for RemoveOnDrop<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{body, open_reply, read_reply, request_line, split_request, ClientError, ExitCode, EXIT_CODES};
+    use crate::{
+        action::{Action, ActionError},
+        protocol::{ErrorKind, ProtocolError, Reply},
+    };
+    use clap::CommandFactory;
+    use strum::IntoEnumIterator;
+    use nix::{libc::O_NONBLOCK, sys::stat::Mode, unistd::mkfifo};
+    use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path, thread, time::Duration};
+
+    #[test]
+    fn request_framing() {
+        let line = request_line(Path::new("/run/var/alfad-reply/42"), &"kill foo");
+        assert_eq!(line, "@/run/var/alfad-reply/42 kill foo");
+        assert_eq!(split_request(&line), (Some(Path::new("/run/var/alfad-reply/42")), "kill foo"));
+        assert_eq!(split_request("kill foo"), (None, "kill foo"));
+    }
+
+    #[test]
+    fn reply_through_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reply");
+        mkfifo(&path, Mode::S_IRWXU).unwrap();
+
+        let reply = open_reply(&path).unwrap();
+        // The daemon opens without blocking, which only works once the client listens
+        let writer = OpenOptions::new().write(true).custom_flags(O_NONBLOCK).open(&path).unwrap();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            (&writer).write_all(Reply::Ok("done".into()).to_string().as_bytes()).unwrap();
+        });
+        assert_eq!(read_reply(reply, Duration::from_secs(5)).unwrap().parse::<Reply>().unwrap(), Reply::Ok("done".into()));
+    }
+
+    #[test]
+    fn reply_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reply");
+        mkfifo(&path, Mode::S_IRWXU).unwrap();
+
+        let reply = open_reply(&path).unwrap();
+        assert!(matches!(read_reply(reply, Duration::from_millis(50)), Err(ClientError::Timeout)));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let code = |error: ClientError| ExitCode::of(&error.into());
+        let refused = |kind| ClientError::Refused { message: "nope".into(), kind };
+        assert_eq!(code(refused(ErrorKind::Failed)), ExitCode::Failed);
+        assert_eq!(code(refused(ErrorKind::Usage)), ExitCode::Usage);
+        assert_eq!(code(refused(ErrorKind::TaskNotFound)), ExitCode::TaskNotFound);
+        assert_eq!(code(refused(ErrorKind::Timeout)), ExitCode::Timeout);
+        assert_eq!(code(refused(ErrorKind::Protocol)), ExitCode::Protocol);
+        assert_eq!(code(ClientError::Timeout), ExitCode::Timeout);
+        assert_eq!(code(ClientError::Unreachable(std::io::ErrorKind::NotFound.into())), ExitCode::Unreachable);
+        assert_eq!(code(ClientError::NotSent(std::io::ErrorKind::WouldBlock.into())), ExitCode::Unreachable);
+        assert_eq!(code(ClientError::Protocol(ProtocolError::InvalidReply("?".into()))), ExitCode::Protocol);
+        assert_eq!(code(ClientError::IO(std::io::ErrorKind::PermissionDenied.into())), ExitCode::Failed);
+        assert_eq!(ExitCode::of(&ProtocolError::InvalidVersion("x".into()).into()), ExitCode::Protocol);
+        assert_eq!(ExitCode::of(&ActionError::MainAppletCalled.into()), ExitCode::Usage);
+        assert_eq!(ExitCode::of(&anyhow::anyhow!("anything")), ExitCode::Failed);
+        // Wrapped on the way up
+        assert_eq!(ExitCode::of(&anyhow::Error::from(ClientError::Timeout).context("version")), ExitCode::Timeout);
+
+        let error = body(Reply::Error { message: "Task does not exist 'foo'".into(), kind: ErrorKind::TaskNotFound }).unwrap_err();
+        assert_eq!(error.to_string(), "Task does not exist 'foo'");
+        assert_eq!(ExitCode::from(&error), ExitCode::TaskNotFound);
+        assert_eq!(body(Reply::Ok("done".into())).unwrap(), "done");
+    }
+
+    #[test]
+    fn exit_codes_documented() {
+        for code in ExitCode::iter() {
+            assert!(EXIT_CODES.lines().any(|line| line.trim_start().starts_with(&format!("{} ", i32::from(code)))), "{code:?}");
+        }
+        assert_eq!(EXIT_CODES.lines().count(), ExitCode::iter().count() + 1);
+        assert!(Action::command().render_help().to_string().contains(EXIT_CODES));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::state_cell::StateCell;
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Time that only moves when it is advanced, for tests of delayed actions
+/// and for simulating a boot
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: StateCell<Duration>,
+    /// Of everything sleeping, by time since the start
+    deadlines: Mutex<Vec<Duration>>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), elapsed: StateCell::new(Duration::ZERO), deadlines: Mutex::new(Vec::new()) }
+    }
+
+    /// Time since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+
+    pub fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+
+    /// Move forward, waking every sleeper that expires on the way
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+
+    /// When the first sleeper wakes up, `None` if nothing sleeps
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.deadlines.lock().unwrap().iter().min().copied()
+    }
+
+    /// Expires `duration` from now. The deadline is taken when this is
+    /// called, not when the future is first polled.
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + '_ {
+        let deadline = self.elapsed.get() + duration;
+        self.deadlines.lock().unwrap().push(deadline);
+        let pending = Pending { clock: self, deadline };
+        async move {
+            // Moved in as a whole, so the deadline goes once this is dropped
+            let pending = pending;
+            pending.clock.elapsed.wait_until(|elapsed| *elapsed >= pending.deadline).await;
+        }
+    }
+}
+
+/// Keeps a deadline in the list for as long as someone sleeps until it
+struct Pending<'a> {
+    clock: &'a VirtualClock,
+    deadline: Duration,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        let mut deadlines = self.clock.deadlines.lock().unwrap();
+        if let Some(index) = deadlines.iter().position(|deadline| *deadline == self.deadline) {
+            deadlines.swap_remove(index);
+        }
+    }
+}
+
+static VIRTUAL: OnceLock<VirtualClock> = OnceLock::new();
+
+/// Switch the whole process to virtual time, there is no way back
+pub fn use_virtual_time() {
+    VIRTUAL.get_or_init(VirtualClock::new);
+}
+
+/// Move virtual time forward, waking every timer that expires on the way
+pub fn advance(duration: Duration) {
+    if let Some(clock) = VIRTUAL.get() {
+        clock.advance(duration);
+    }
+}
+
+pub fn now() -> Instant {
+    match VIRTUAL.get() {
+        Some(clock) => clock.now(),
+        None => Instant::now(),
+    }
+}
+
+/// Expires `duration` from now. The deadline is taken when this is called,
+/// not when the future is first polled.
+pub fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    let virtual_sleep = VIRTUAL.get().map(|clock| clock.sleep(duration));
+    async move {
+        match virtual_sleep {
+            Some(sleep) => sleep.await,
+            None => {
+                smol::Timer::after(duration).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VirtualClock;
+    use smol::future;
+    use std::time::Duration;
+
+    #[test]
+    fn deadlines() {
+        let clock = VirtualClock::new();
+        assert_eq!(clock.next_deadline(), None);
+        let mut long = Box::pin(clock.sleep(Duration::from_secs(5)));
+        let mut short = Box::pin(clock.sleep(Duration::from_secs(2)));
+        assert_eq!(clock.next_deadline(), Some(Duration::from_secs(2)));
+        assert!(future::block_on(future::poll_once(&mut short)).is_none());
+
+        clock.advance(Duration::from_secs(2));
+        future::block_on(short.as_mut());
+        drop(short);
+        assert_eq!(clock.next_deadline(), Some(Duration::from_secs(5)));
+        assert!(future::block_on(future::poll_once(&mut long)).is_none());
+        // Given up on, not woken
+        drop(long);
+        assert_eq!(clock.next_deadline(), None);
+        assert_eq!(clock.elapsed(), Duration::from_secs(2));
+        assert!(future::block_on(future::poll_once(clock.sleep(Duration::ZERO))).is_some());
+    }
+}
@@ -1,140 +1,44 @@
-use crate::{
-    config::payload::Runnable,
-    task::{ContextMap, ExitReason, TaskContext, TaskState},
-};
+use super::{env::overlay, CommandLineError};
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
-use serde::{Deserialize, Serialize};
-use smol::process::Command;
-use std::{
-    env,
-    ops::{ControlFlow, Deref, DerefMut},
-    process::{ExitStatus, Stdio},
-    slice::Iter,
-    str::FromStr,
-};
-use thiserror::Error;
-use tracing::{debug, error, info};
+use std::env;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CommandLine {
-    ignore_env: bool,
-    ignore_return: bool,
-    args: Vec<String>,
-}
-
-const MAX_ENVVAR_RECURSION: usize = 100;
+pub(super) const MAX_ENVVAR_RECURSION: usize = 100;
+/// Longest argument after expanding variables, the kernel refuses longer
+/// ones anyway (MAX_ARG_STRLEN)
+pub const MAX_ARG_LENGTH: usize = 128 * 1024;
 
 lazy_static! {
     static ref FIND_ENVVAR: Regex = Regex::new(r"\$([_a-zA-Z0-9]+)").unwrap();
 }
 
-#[derive(Debug, Error)]
-pub enum CommandLineError {
-    #[error("Invalid Command: {}", .0)]
-    InvalidCommand(String),
-    #[error("Empty Command")]
-    EmptyCommand,
-    #[error(
-        "Maximum recursion depth of {} was reached during resolution of environment variables",
-        MAX_ENVVAR_RECURSION
-    )]
-    MaximumRecursion,
-    #[error(transparent)]
-    IO(#[from] smol::io::Error),
-}
-
-impl CommandLine {
-    pub fn to_args(&self) -> Result<Vec<String>, CommandLineError> {
-        self.args.iter().map(|s| insert_envvars(s)).collect()
-    }
-
-    pub fn to_command(&self) -> Result<Command, CommandLineError> {
-        let mut args = self.to_args()?.into_iter();
-        let program = args.next().ok_or(CommandLineError::EmptyCommand)?;
-        let mut command = Command::new(program);
-        command.stderr(Stdio::inherit()).stdout(Stdio::inherit());
-        command.args(args);
-        if self.ignore_env {
-            command.env_clear();
-        }
-        Ok(command)
-    }
-
-    pub fn spawn(&self) -> Result<Child, CommandLineError> {
-        Ok(Child(self.to_command()?.spawn()?, self.ignore_return))
-    }
-
-    async fn run_line(&self, context: &TaskContext) -> ControlFlow<TaskState> {
-        // let mut context = context.write().await;
-
-        debug!(cmd = ?self.args, "Running");
-        let mut child = match self.spawn() {
-            Ok(c) => c,
-            Err(CommandLineError::EmptyCommand) => return ControlFlow::Continue(()),
-            Err(e) => {
-                error!(%e);
-                return ControlFlow::Break(TaskState::Concluded(ExitReason::Failed));
-            }
-        };
-
-        (*context.child.write().await) = Some(child.id() as i32);
-
-        match child.status().await {
-            Ok(status) if status.success() => {
-                info!(?status);
-                (*context.child.write().await) = None;
-                ControlFlow::Continue(())
-            }
-            status => {
-                error!(exit = ?status);
-                ControlFlow::Break(TaskState::Concluded(ExitReason::Failed))
-            }
-        }
+/// Arguments as they are passed to the program, variables exported by
+/// other tasks come first unless the environment is ignored
+pub(super) fn expand(arg: &str, ignore_env: bool) -> Result<String, CommandLineError> {
+    if ignore_env {
+        return insert_envvars(arg);
     }
+    let overlay = overlay();
+    insert_vars(arg, |name| overlay.get(name).map(str::to_owned).or_else(|| env::var(name).ok()))
 }
 
-#[async_trait::async_trait]
-impl Runnable for CommandLine {
-    async fn run<'a>(
-        &'a self,
-        context: &'a TaskContext,
-        _context_map: ContextMap<'static>,
-    ) -> ControlFlow<TaskState> {
-        self.run_line(context).await
-    }
+/// Replace `$VAR` with the value of the environment variable, repeatedly
+/// until nothing changes. Unset variables are replaced with nothing.
+pub fn insert_envvars(s: &str) -> Result<String, CommandLineError> {
+    insert_vars(s, |name| env::var(name).ok())
 }
 
-impl FromStr for CommandLine {
-    type Err = CommandLineError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (s, ignore_env) = prefix_to_flag(s, ':');
-        let (s, ignore_return) = prefix_to_flag(s, '-');
-        let args = shlex::split(s).ok_or_else(|| CommandLineError::InvalidCommand(s.to_owned()))?;
-        Ok(Self {
-            ignore_env,
-            ignore_return,
-            args,
-        })
-    }
-}
-
-fn prefix_to_flag(s: &str, prefix: char) -> (&str, bool) {
-    if let Some(s) = s.strip_prefix(prefix) {
-        (s, true)
-    } else {
-        (s, false)
-    }
-}
-
-fn insert_envvars(s: &str) -> Result<String, CommandLineError> {
+/// Replace `$VAR` with what `lookup` has for it, like [`insert_envvars`]
+pub fn insert_vars(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, CommandLineError> {
     let mut haystack = s.to_owned();
     for _ in 0..MAX_ENVVAR_RECURSION {
         let new = FIND_ENVVAR
-            .replace_all(&haystack, |caps: &Captures| {
-                env::var(caps.get(1).unwrap().as_str()).unwrap_or_default()
-            })
+            .replace_all(&haystack, |caps: &Captures| lookup(caps.get(1).unwrap().as_str()).unwrap_or_default())
             .to_string();
+        // A variable that contains itself twice doubles in every round
+        if new.len() > MAX_ARG_LENGTH {
+            return Err(CommandLineError::TooLong);
+        }
         if new == haystack {
             return Ok(new);
         }
@@ -143,67 +47,11 @@ fn insert_envvars(s: &str) -> Result<String, CommandLineError> {
     Err(CommandLineError::MaximumRecursion)
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct CommandLines(Vec<CommandLine>);
-
-impl<'a> IntoIterator for &'a CommandLines {
-    type Item = &'a CommandLine;
-
-    type IntoIter = Iter<'a, CommandLine>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        self.0.iter()
-    }
-}
-
-impl Deref for CommandLines {
-    type Target = Vec<CommandLine>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for CommandLines {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-impl FromStr for CommandLines {
-    type Err = CommandLineError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(CommandLines(
-            s.lines()
-                .map(CommandLine::from_str)
-                .collect::<Result<Vec<_>, _>>()?,
-        ))
-    }
-}
-
-#[derive(Debug)]
-pub struct Child(pub smol::process::Child, pub bool);
-
-impl Child {
-    pub async fn status(&mut self) -> Result<ExitStatus, std::io::Error> {
-        let exit = self.0.status().await;
-        if self.1 {
-            return Ok(ExitStatus::default());
-        }
-        exit
-    }
-
-    pub(crate) fn id(&self) -> u32 {
-        self.0.id()
-    }
-}
-
 #[cfg(test)]
 mod test {
     use std::env;
 
-    use super::insert_envvars;
+    use super::{expand, insert_envvars, CommandLineError};
     // WARNING: All ENVVARS must have unique names since the test might run
     // in parallel inside one process which could cause race conditions
 
@@ -214,6 +62,14 @@ mod test {
         assert_eq!(r, "foo");
     }
 
+    #[test]
+    fn exported_vars() {
+        env::set_var("TEST_VAR_EXPORTED", "alfad");
+        crate::command_line::env::overlay().export("generator", vec![("TEST_VAR_EXPORTED".into(), "generator".into())]);
+        assert_eq!(expand("$TEST_VAR_EXPORTED", false).unwrap(), "generator");
+        assert_eq!(expand("$TEST_VAR_EXPORTED", true).unwrap(), "alfad");
+    }
+
     #[test]
     fn replace_var_in_text() {
         env::set_var("TEST_VAR_IN_TEXT", "foo");
@@ -242,4 +98,10 @@ mod test {
         env::set_var("TEST_VAR_2", "$TEST_VAR_INF_REC_1");
         insert_envvars("$TEST_VAR_INF_REC_1").unwrap_err();
     }
+
+    #[test]
+    fn catch_exponential_growth() {
+        env::set_var("TEST_VAR_DOUBLE", "$TEST_VAR_DOUBLE$TEST_VAR_DOUBLE");
+        assert!(matches!(insert_envvars("$TEST_VAR_DOUBLE"), Err(CommandLineError::TooLong)));
+    }
 }
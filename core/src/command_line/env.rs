@@ -0,0 +1,250 @@
+//! The environment of a command line. It is built in layers, each one
+//! overriding the ones before:
+//!
+//! 1. The environment alfad was started with. With the `:` prefix only
+//!    the variables in `env_keep` are kept from it.
+//! 2. The [`Overlay`], what tasks with `env_export` wrote to stdout before
+//!    the command line started. Not with the `:` prefix.
+//! 3. `locale` of defaults.yaml, of the group and of the task, in this
+//!    order and setting by setting, see [`Inherited`]
+//! 4. The variables in the task's `env_file`
+//! 5. The task's own `env`
+//!
+//! The `:` prefix only drops what alfad inherited and the overlay, what is
+//! configured for the task is set either way. `$VAR` in arguments is still
+//! expanded from the environment of alfad and, without the prefix, from
+//! the overlay.
+
+use crate::config::{defaults::Inherited, TaskConfig};
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, MutexGuard},
+};
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid variable '{}', names can't be empty or contain '=' and neither names nor values NUL", .0)]
+pub struct InvalidVariable(String);
+
+#[derive(Debug, Error)]
+pub enum EnvFileError {
+    #[error("Could not read {}: {}", .0.display(), .1)]
+    Read(PathBuf, io::Error),
+    #[error("{}:{}: expected VARIABLE=value", .0.display(), .1)]
+    Syntax(PathBuf, usize),
+}
+
+/// Whether `name` can be set to `value` for a process
+pub fn check(name: &str, value: &str) -> Result<(), InvalidVariable> {
+    match name.is_empty() || name.contains(['=', '\0']) || value.contains('\0') {
+        true => Err(InvalidVariable(name.to_owned())),
+        false => Ok(()),
+    }
+}
+
+/// The `VARIABLE=value` lines of `path`. Empty lines and lines starting
+/// with `#` are skipped, values are taken as written, quotes and all.
+pub fn read_file(path: &Path) -> Result<Vec<(String, String)>, EnvFileError> {
+    let text = fs::read_to_string(path).map_err(|error| EnvFileError::Read(path.to_owned(), error))?;
+    parse(&text).map_err(|line| EnvFileError::Syntax(path.to_owned(), line))
+}
+
+/// The number of the first line that is not `VARIABLE=value` on error
+fn parse(text: &str) -> Result<Vec<(String, String)>, usize> {
+    let mut vars = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once('=').filter(|(name, value)| check(name, value).is_ok()).ok_or(index + 1)?;
+        vars.push((name.to_owned(), value.to_owned()));
+    }
+    Ok(vars)
+}
+
+/// Variables exported by tasks with `env_export` for the command lines
+/// started after them, by name with the task that exported them
+#[derive(Debug, Default)]
+pub struct Overlay(BTreeMap<String, (String, String)>);
+
+static OVERLAY: Mutex<Overlay> = Mutex::new(Overlay(BTreeMap::new()));
+
+/// The variables exported so far
+pub fn overlay() -> MutexGuard<'static, Overlay> {
+    OVERLAY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Overlay {
+    /// Set `vars` for `task`, returns the variables that other tasks had
+    /// exported before with the task that did
+    pub fn export(&mut self, task: &str, vars: Vec<(String, String)>) -> Vec<(String, String)> {
+        let mut replaced = Vec::new();
+        for (name, value) in vars {
+            if let Some((_, previous)) = self.0.insert(name.clone(), (value, task.to_owned())) {
+                if previous != task {
+                    replaced.push((name, previous));
+                }
+            }
+        }
+        replaced
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|(value, _)| value.as_str())
+    }
+
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, (value, _))| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Add the `VARIABLE=value` lines `task` wrote to stdout to the overlay,
+/// the same syntax as an `env_file`. Nothing is added if a line is
+/// something else, its number is the error then. The same variable from
+/// two tasks is what the later one exported.
+pub fn export(task: &str, output: &str) -> Result<usize, usize> {
+    let vars = parse(output)?;
+    let count = vars.len();
+    for (variable, previous) in overlay().export(task, vars) {
+        warn!(task, variable, previous, "Replacing a variable exported by another task");
+    }
+    Ok(count)
+}
+
+/// What a command line gets as environment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Env {
+    /// With the `:` prefix, the only variables kept from the environment
+    /// of alfad. All of them are kept otherwise.
+    pub keep: Option<Vec<String>>,
+    /// Set on top, in this order
+    pub set: Vec<(String, String)>,
+}
+
+impl Env {
+    /// The environment of a command line of `config`, `defaults` has what
+    /// neither the task nor its group set
+    pub fn new(ignore_env: bool, config: &TaskConfig, defaults: &Inherited, overlay: &Overlay) -> Result<Self, EnvFileError> {
+        let keep = ignore_env.then(|| config.env_keep.as_ref().or(defaults.env_keep.as_ref()).cloned().unwrap_or_default());
+        let locale = config.locale.clone().inherit(&defaults.locale);
+        let owned = |(name, value): (&str, &str)| (name.to_owned(), value.to_owned());
+        let mut set: Vec<_> = overlay.vars().filter(|_| !ignore_env).map(owned).collect();
+        set.extend(locale.vars().map(owned));
+        if let Some(path) = &config.env_file {
+            set.extend(read_file(path)?);
+        }
+        set.extend(config.env.iter().map(|(name, value)| (name.clone(), value.clone())));
+        Ok(Self { keep, set })
+    }
+
+    /// What `name` ends up as, if alfad's own environment is `inherited`
+    pub fn get(&self, name: &str, inherited: impl Fn(&str) -> Option<String>) -> Option<String> {
+        match self.set.iter().rev().find(|(key, _)| key == name) {
+            Some((_, value)) => Some(value.clone()),
+            None if self.keep.as_ref().is_some_and(|keep| !keep.iter().any(|key| key == name)) => None,
+            None => inherited(name),
+        }
+    }
+
+    pub fn apply(&self, command: &mut Command) {
+        if let Some(keep) = &self.keep {
+            command.env_clear();
+            for key in keep {
+                if let Some(value) = env::var_os(key) {
+                    command.env(key, value);
+                }
+            }
+        }
+        command.envs(self.set.iter().map(|(name, value)| (name, value)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, parse, Env, EnvFileError, Overlay};
+    use crate::config::{defaults::Inherited, yaml::TaskConfigYaml, Locale};
+    use std::{fs, path::Path};
+
+    #[test]
+    fn env_file() {
+        let text = "# Written by the installer\n\nTZ=UTC\n  LANG=C.UTF-8  \nGREETING=\"hello world\"\nEMPTY=\nA=b=c\n";
+        let vars = parse(text).unwrap();
+        let vars: Vec<_> = vars.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(vars, [("TZ", "UTC"), ("LANG", "C.UTF-8"), ("GREETING", "\"hello world\""), ("EMPTY", ""), ("A", "b=c")]);
+        assert_eq!(parse("TZ=UTC\nexport\n"), Err(2));
+        assert_eq!(parse("=UTC\n"), Err(1));
+
+        let error = super::read_file(Path::new("/nonexistent/env")).unwrap_err();
+        assert!(matches!(error, EnvFileError::Read(..)), "{error}");
+        assert!(check("LC_ALL", "C").is_ok());
+        assert!(check("A\0", "b").is_err());
+        assert!(check("A", "b\0").is_err());
+    }
+
+    /// TZ set in every combination of layers, with and without the `:`
+    /// prefix and TZ in `env_keep`. The highest layer that sets it wins,
+    /// the prefix drops the first two.
+    #[test]
+    fn precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join("env");
+        fs::write(&env_file, "TZ=env_file\n").unwrap();
+        let tz = |set: bool, value: &str| Locale { tz: set.then(|| value.to_owned()), ..Default::default() };
+        let layers = ["alfad", "overlay", "defaults", "group", "task", "env_file", "env"];
+
+        for mask in 0..1 << layers.len() {
+            let set = |layer: usize| mask & 1 << layer != 0;
+            for (ignore_env, kept) in [(false, false), (true, false), (true, true)] {
+                let defaults = Inherited {
+                    env_keep: Some(kept.then(|| "TZ".to_owned()).into_iter().collect()),
+                    locale: tz(set(2), "defaults"),
+                    ..Default::default()
+                };
+                let group = Inherited { locale: tz(set(3), "group"), ..Default::default() };
+                let mut task = TaskConfigYaml::new("a".to_owned());
+                task.locale = tz(set(4), "task");
+                task.env_file = set(5).then(|| env_file.clone());
+                task.env = set(6).then(|| ("TZ".to_owned(), "env".to_owned())).into_iter().collect();
+                task.inherit(&group);
+                let config = task.into_config().unwrap();
+                let mut overlay = Overlay::default();
+                overlay.export("generator", set(1).then(|| ("TZ".to_owned(), "overlay".to_owned())).into_iter().collect());
+
+                let env = Env::new(ignore_env, &config, &defaults, &overlay).unwrap();
+                let inherited = |name: &str| (set(0) && name == "TZ").then(|| "alfad".to_owned());
+                let visible = |layer: usize| match layer {
+                    0 => !ignore_env || kept,
+                    1 => !ignore_env,
+                    _ => true,
+                };
+                let expected = (0..layers.len()).rev().find(|layer| set(*layer) && visible(*layer));
+                assert_eq!(
+                    env.get("TZ", inherited).as_deref(),
+                    expected.map(|layer| layers[layer]),
+                    "set in {:?}, ignore_env {ignore_env}, kept {kept}",
+                    (0..layers.len()).filter(|layer| set(*layer)).map(|layer| layers[layer]).collect::<Vec<_>>()
+                );
+                assert_eq!(env.get("LANG", |_| None), None);
+            }
+        }
+    }
+    #[test]
+    fn overlay() {
+        let mut overlay = Overlay::default();
+        let vars = |vars: &[(&str, &str)]| vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        assert!(overlay.export("board", vars(&[("BOARD_REV", "2"), ("BOARD_NAME", "pi")])).is_empty());
+        // Exporting again on a respawn replaces its own variables quietly
+        assert!(overlay.export("board", vars(&[("BOARD_REV", "3")])).is_empty());
+        assert_eq!(overlay.get("BOARD_REV"), Some("3"));
+
+        // The later task wins
+        let replaced = overlay.export("dtb", vars(&[("BOARD_REV", "4"), ("DTB", "bcm2711")]));
+        assert_eq!(replaced, [("BOARD_REV".to_owned(), "board".to_owned())]);
+        assert_eq!(overlay.vars().collect::<Vec<_>>(), [("BOARD_NAME", "pi"), ("BOARD_REV", "4"), ("DTB", "bcm2711")]);
+    }
+}
@@ -1,9 +1,540 @@
+//! Command lines of services. Without the `complex_commands` feature,
+//! `$VAR` in arguments is passed on as written instead of expanded.
+
 #[cfg(feature = "complex_commands")]
 mod complex;
+pub mod env;
 #[cfg(feature = "complex_commands")]
 pub use complex::*;
 
 #[cfg(not(feature = "complex_commands"))]
 mod simple;
 #[cfg(not(feature = "complex_commands"))]
-pub use simple::*;
+use simple::expand;
+
+use crate::{
+    config::{defaults::Defaults, payload::Runnable, TaskConfig},
+    fd,
+    logger::{self, LogPipe},
+    security::ExecLabels,
+    status::truncate,
+    task::{ChildProcess, ContextMap, ExitReason, TaskContext, TaskState},
+};
+use env::{Env, EnvFileError};
+use serde::{Deserialize, Serialize};
+use smol::{future, io::AsyncReadExt, process::Command, Timer};
+use std::{
+    fmt::Display,
+    ops::{ControlFlow, Deref, DerefMut},
+    process::{ExitStatus, Stdio},
+    slice::Iter,
+    str::FromStr,
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
+
+/// Longest line [`CommandLine::render`] returns, in terminal columns
+pub const MAX_RENDERED: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandLine {
+    ignore_env: bool,
+    ignore_return: bool,
+    args: Vec<String>,
+    /// Killed after this long
+    timeout: Option<Duration>,
+}
+
+#[derive(Debug, Error)]
+pub enum CommandLineError {
+    #[error("Invalid Command: {}", .0)]
+    InvalidCommand(String),
+    #[error("Empty Command")]
+    EmptyCommand,
+    #[cfg(feature = "complex_commands")]
+    #[error(
+        "Maximum recursion depth of {} was reached during resolution of environment variables",
+        complex::MAX_ENVVAR_RECURSION
+    )]
+    MaximumRecursion,
+    #[cfg(feature = "complex_commands")]
+    #[error("Argument is longer than {} bytes after resolution of environment variables", MAX_ARG_LENGTH)]
+    TooLong,
+    #[error(transparent)]
+    EnvFile(#[from] EnvFileError),
+    #[error(transparent)]
+    IO(#[from] smol::io::Error),
+}
+
+impl CommandLine {
+    /// A line with the flags given instead of written as prefixes, `run`
+    /// is split like a shell would
+    pub fn new(run: &str, ignore_env: bool, ignore_return: bool) -> Result<Self, CommandLineError> {
+        // Arguments are passed on as C strings, they can not contain NUL
+        let args = shlex::split(run)
+            .filter(|args| !args.iter().any(|arg| arg.contains('\0')))
+            .ok_or_else(|| CommandLineError::InvalidCommand(run.to_owned()))?;
+        Ok(Self { ignore_env, ignore_return, args, timeout: None })
+    }
+
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    pub fn ignore_env(&self) -> bool {
+        self.ignore_env
+    }
+
+    pub fn ignore_return(&self) -> bool {
+        self.ignore_return
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The arguments quoted for a shell, without the prefixes
+    pub fn line(&self) -> String {
+        shlex::try_join(self.args.iter().map(String::as_str)).unwrap_or_default()
+    }
+
+    /// The program as written, before variables are expanded
+    pub fn program(&self) -> Option<&str> {
+        self.args.first().map(String::as_str)
+    }
+
+    pub fn to_args(&self) -> Result<Vec<String>, CommandLineError> {
+        self.args.iter().map(|s| expand(s, self.ignore_env)).collect()
+    }
+
+    /// The same line with variables expanded the way running it would
+    pub fn expanded(&self) -> Result<Self, CommandLineError> {
+        Ok(Self { args: self.to_args()?, ..self.clone() })
+    }
+
+    /// The environment of this line of `config`, with the `:` prefix the
+    /// variables kept are the task's own list or its group's if it has one,
+    /// otherwise the global one from defaults.yaml
+    pub fn env(&self, config: &TaskConfig) -> Result<Env, EnvFileError> {
+        Env::new(self.ignore_env, config, &Defaults::load().inherited(), &env::overlay())
+    }
+
+    /// The command gets `env`, see [`env`] for how it is built from the
+    /// environment of alfad and the configuration. `$VAR` in arguments is
+    /// expanded from the environment of alfad and the overlay of exported
+    /// variables, if it is expanded at all. stdout and stderr go to `output` if the task has a logger,
+    /// otherwise where alfad's go. No other descriptors of alfad are passed
+    /// on. The command is executed with `labels`.
+    pub fn to_command(&self, env: &Env, labels: &ExecLabels, output: Option<&LogPipe>) -> Result<Command, CommandLineError> {
+        let mut args = self.to_args()?.into_iter();
+        let program = args.next().ok_or(CommandLineError::EmptyCommand)?;
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+        env.apply(&mut command);
+        fd::check_usage();
+        fd::stdio_only(&mut command);
+        labels.apply(&mut command);
+        // smol overrides stdio set before the conversion
+        let mut command = Command::from(command);
+        match output {
+            Some(pipe) => command.stderr(pipe.output()?).stdout(pipe.output()?),
+            None => command.stderr(Stdio::inherit()).stdout(Stdio::inherit()),
+        };
+        Ok(command)
+    }
+
+    /// With `capture`, stdout is piped to alfad instead
+    pub fn spawn(
+        &self,
+        env: &Env,
+        labels: &ExecLabels,
+        output: Option<&LogPipe>,
+        capture: bool,
+    ) -> Result<Child, CommandLineError> {
+        let mut command = self.to_command(env, labels, output)?;
+        if capture {
+            command.stdout(Stdio::piped());
+        }
+        Ok(Child(command.spawn()?, self.ignore_return))
+    }
+
+    async fn run_line(&self, context: &TaskContext) -> ControlFlow<TaskState> {
+        // let mut context = context.write().await;
+
+        let task = &context.config.name;
+        let mask = &context.config.mask_args;
+        debug!(task, cmd = %self.render(mask), "Running");
+        let ended = |how: String| *context.exit.lock().unwrap() = Some(how);
+        let labels = match ExecLabels::of(&context.config) {
+            Ok(labels) => labels,
+            Err(e) => {
+                error!(task, %e);
+                ended(e.to_string());
+                return ControlFlow::Break(TaskState::Concluded(ExitReason::Failed));
+            }
+        };
+        let spawned = self.env(&context.config).map_err(Into::into);
+        let capture = context.config.env_export;
+        let mut child = match spawned.and_then(|env| self.spawn(&env, &labels, logger::pipe(context).as_ref(), capture)) {
+            Ok(c) => c,
+            Err(CommandLineError::EmptyCommand) => return ControlFlow::Continue(()),
+            Err(e) => {
+                error!(task, %e);
+                ended(e.to_string());
+                return ControlFlow::Break(TaskState::Concluded(ExitReason::Failed));
+            }
+        };
+
+        context.child.set(ChildProcess::from_id(child.id()));
+        // Read while the command runs, it could fill the pipe otherwise
+        let exported = child.0.stdout.take().map(|mut stdout| {
+            smol::spawn(async move {
+                let mut output = String::new();
+                stdout.read_to_string(&mut output).await.map(|_| output)
+            })
+        });
+
+        let mut timed_out = false;
+        let status = match self.timeout {
+            Some(timeout) => {
+                let expired = async {
+                    Timer::after(timeout).await;
+                    None
+                };
+                match future::or(async { Some(child.status().await) }, expired).await {
+                    Some(status) => status,
+                    None => {
+                        warn!(task, cmd = %self.render(mask), ?timeout, "Timed out, killing it");
+                        timed_out = true;
+                        let _ = child.kill();
+                        child.status().await
+                    }
+                }
+            }
+            None => child.status().await,
+        };
+        context.child.set(None);
+        if let Some(reading) = exported {
+            match reading.await {
+                Ok(output) => context.exported.lock().unwrap().push_str(&output),
+                Err(error) => warn!(task, %error, "Could not read the variables to export"),
+            }
+        }
+        let how = match &status {
+            Ok(status) if timed_out => format!("{status} after the timeout"),
+            Ok(status) => status.to_string(),
+            Err(error) => error.to_string(),
+        };
+        ended(how);
+        match status {
+            Ok(status) if status.success() => {
+                info!(task, ?status);
+                ControlFlow::Continue(())
+            }
+            status => {
+                error!(task, exit = ?status);
+                ControlFlow::Break(TaskState::Concluded(ExitReason::Failed))
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Runnable for CommandLine {
+    async fn run<'a>(
+        &'a self,
+        context: &'a TaskContext,
+        _context_map: ContextMap<'static>,
+    ) -> ControlFlow<TaskState> {
+        self.run_line(context).await
+    }
+}
+
+impl FromStr for CommandLine {
+    type Err = CommandLineError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, ignore_env) = prefix_to_flag(s, ':');
+        let (s, ignore_return) = prefix_to_flag(s, '-');
+        Self::new(s, ignore_env, ignore_return)
+    }
+}
+
+/// Renders the line the way it would be written in a task file, so parsing
+/// the output results in the same command line again.
+impl Display for CommandLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, &[])
+    }
+}
+
+impl CommandLine {
+    /// The line for logs and alfad-ctl list: like in a task file, but the
+    /// arguments at the indices in `mask` are `***` and a line longer than
+    /// [`MAX_RENDERED`] is cut off
+    ///
+    /// ```
+    /// use alfad::command_line::CommandLine;
+    ///
+    /// let line: CommandLine = "-mount -o 'user=admin,password=x' /mnt".parse().unwrap();
+    /// assert_eq!(line.render(&[2, 9]), "-mount -o *** /mnt");
+    /// ```
+    pub fn render(&self, mask: &[usize]) -> String {
+        let mut rendered = String::new();
+        // Only fails for arguments shlex can't quote, which parsing rejects
+        let _ = self.write(&mut rendered, mask);
+        truncate(&rendered, MAX_RENDERED)
+    }
+
+    fn write(&self, out: &mut impl std::fmt::Write, mask: &[usize]) -> std::fmt::Result {
+        if self.ignore_env {
+            out.write_str(":")?;
+        }
+        if self.ignore_return {
+            out.write_str("-")?;
+        }
+        for (index, arg) in self.args.iter().enumerate() {
+            if index > 0 {
+                out.write_str(" ")?;
+            }
+            if mask.contains(&index) {
+                out.write_str("***")?;
+                continue;
+            }
+            let quoted = shlex::try_quote(arg).map_err(|_| std::fmt::Error)?;
+            // A program like "-x" must not be read back as the prefix, so it
+            // gets quoted even where shlex leaves it bare
+            let ambiguous =
+                !self.ignore_return && (quoted.starts_with('-') || (!self.ignore_env && quoted.starts_with(':')));
+            match index {
+                0 if ambiguous => write!(out, "'{}'", arg.replace('\'', r"'\''"))?,
+                _ => out.write_str(&quoted)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn prefix_to_flag(s: &str, prefix: char) -> (&str, bool) {
+    if let Some(s) = s.strip_prefix(prefix) {
+        (s, true)
+    } else {
+        (s, false)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandLines(Vec<CommandLine>);
+
+impl<'a> IntoIterator for &'a CommandLines {
+    type Item = &'a CommandLine;
+
+    type IntoIter = Iter<'a, CommandLine>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl Deref for CommandLines {
+    type Target = Vec<CommandLine>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for CommandLines {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl CommandLines {
+    /// Every line the way it would be written in a task file
+    pub fn rendered(&self) -> impl Iterator<Item = String> + '_ {
+        self.0.iter().map(ToString::to_string)
+    }
+
+    /// Numbered lines like the `Display` output, each one rendered with
+    /// [`CommandLine::render`]
+    pub fn render(&self, mask: &[usize]) -> String {
+        numbered(self.0.iter().map(|line| line.render(mask)))
+    }
+}
+
+/// The lines numbered from 1 on a single line, e.g. `1. mount -a; 2. swapon -a`
+impl Display for CommandLines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&numbered(self.rendered()))
+    }
+}
+
+fn numbered(lines: impl Iterator<Item = String>) -> String {
+    lines.enumerate().map(|(index, line)| format!("{}. {line}", index + 1)).collect::<Vec<_>>().join("; ")
+}
+
+impl FromIterator<CommandLine> for CommandLines {
+    fn from_iter<T: IntoIterator<Item = CommandLine>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl FromStr for CommandLines {
+    type Err = CommandLineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(CommandLines(
+            s.lines()
+                .map(CommandLine::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct Child(pub smol::process::Child, pub bool);
+
+impl Child {
+    pub async fn status(&mut self) -> Result<ExitStatus, std::io::Error> {
+        let exit = self.0.status().await;
+        if self.1 {
+            return Ok(ExitStatus::default());
+        }
+        exit
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.0.id()
+    }
+
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        self.0.kill()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, env};
+
+    use super::{env::Env, CommandLine, CommandLines, MAX_RENDERED};
+    use crate::{
+        config::{defaults::Defaults, yaml::TaskConfigYaml},
+        security::ExecLabels,
+        task::{drive, ContextMap, ExitReason, TaskContext, TaskState},
+    };
+    // WARNING: All ENVVARS must have unique names since the test might run
+    // in parallel inside one process which could cause race conditions
+
+    #[test]
+    fn reject_nul() {
+        "echo a\0b".parse::<CommandLine>().unwrap_err();
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for line in [
+            "echo hello",
+            ":echo hello",
+            "-false",
+            ":-env",
+            "echo 'hello world' '$HOME'",
+            "printf ''",
+            "'-x' a",
+            "'-^o' -e",
+            "':x'",
+            ":'-x'",
+            "-:x",
+        ] {
+            let parsed: CommandLine = line.parse().unwrap();
+            assert_eq!(parsed.to_string(), line);
+            let reparsed: CommandLine = parsed.to_string().parse().unwrap();
+            assert_eq!(reparsed.args, parsed.args);
+        }
+    }
+
+    #[test]
+    fn render() {
+        let line: CommandLine = ":curl -u admin:secret -o '/tmp/a b' example.org".parse().unwrap();
+        assert_eq!(line.render(&[]), line.to_string());
+        assert_eq!(line.render(&[2, 8]), ":curl -u *** -o '/tmp/a b' example.org");
+        assert_eq!(line.render(&[0]), ":*** -u admin:secret -o '/tmp/a b' example.org");
+        // Masking the program leaves nothing to mistake for a prefix
+        assert_eq!("'-x' a".parse::<CommandLine>().unwrap().render(&[0]), "*** a");
+
+        let long = format!("echo {}", "x".repeat(300)).parse::<CommandLine>().unwrap().render(&[]);
+        assert_eq!(long.chars().count(), MAX_RENDERED);
+        assert!(long.starts_with("echo xx") && long.ends_with("x…"), "{long}");
+
+        let lines: CommandLines = "mount -a\n-swapon -a /swap".parse().unwrap();
+        assert_eq!(lines.to_string(), "1. mount -a; 2. -swapon -a /swap");
+        assert_eq!(lines.render(&[1]), "1. mount ***; 2. -swapon *** /swap");
+    }
+
+    /// The same task file loads and runs with and without `complex_commands`,
+    /// only `$VAR` is left alone by the simple variant
+    #[test]
+    fn fixture_runs() {
+        env::set_var("TEST_VAR_FIXTURE", "expanded");
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().display();
+        let yaml = format!(
+            "name: fixture\ncmd:\n  - -false\n  - touch '{dir}/a b'\n  - {{run: touch {dir}/$TEST_VAR_FIXTURE, ignore_env: true, timeout: 5s}}\n"
+        );
+        let config = serde_yaml::from_str::<TaskConfigYaml>(&yaml).unwrap().into_config().unwrap();
+        assert_eq!(config.payload.command_count(), 3);
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([("fixture", TaskContext::new(config))]))));
+        let task = &map.0["fixture"];
+
+        smol::block_on(drive(task, map));
+        assert_eq!(smol::block_on(task.state()), TaskState::Concluded(ExitReason::Done));
+        assert!(std::path::Path::new(&format!("{dir}/a b")).exists());
+        let expanded = if cfg!(feature = "complex_commands") { "expanded" } else { "$TEST_VAR_FIXTURE" };
+        assert!(std::path::Path::new(&format!("{dir}/{expanded}")).exists());
+    }
+
+    fn succeeds(line: &str, env_keep: &[String]) -> bool {
+        succeeds_with(line, env_keep, &[])
+    }
+
+    fn succeeds_with(line: &str, env_keep: &[String], set: &[(&str, &str)]) -> bool {
+        let line: CommandLine = line.parse().unwrap();
+        let env = Env {
+            keep: line.ignore_env().then(|| env_keep.to_vec()),
+            set: set.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        };
+        let status = line
+            .to_command(&env, &ExecLabels::default(), None)
+            .and_then(|mut command| Ok(smol::block_on(command.status())?));
+        status.is_ok_and(|status| status.success())
+    }
+
+    #[test]
+    fn ignore_env_keeps_allowlist() {
+        let env_keep = Defaults::default().env_keep;
+        assert!(succeeds(":ls /", &env_keep));
+        // Programs are still found in the default search path of libc, but
+        // the child has no PATH of its own
+        assert!(succeeds(":printenv PATH", &env_keep));
+        assert!(!succeeds(":printenv PATH", &[]));
+
+        env::set_var("TEST_VAR_KEEP", "kept");
+        let check = ":printenv TEST_VAR_KEEP";
+        assert!(!succeeds(check, &env_keep));
+        assert!(succeeds(check, &["PATH".to_owned(), "TEST_VAR_KEEP".to_owned()]));
+        // Without the prefix, everything is inherited and the list is ignored
+        assert!(succeeds(&check[1..], &[]));
+    }
+
+    #[test]
+    fn configured_env_is_set() {
+        env::set_var("TEST_VAR_OVERRIDDEN", "alfad");
+        // Without `$`, which complex_commands expands from alfad's environment
+        let check = "sh -c 'printenv TEST_VAR_OVERRIDDEN | grep -qx task'";
+        assert!(!succeeds(check, &[]));
+        assert!(succeeds_with(check, &[], &[("TEST_VAR_OVERRIDDEN", "task")]));
+        // Set even if nothing is kept, the last value wins
+        let set = [("TEST_VAR_OVERRIDDEN", "file"), ("TEST_VAR_OVERRIDDEN", "task")];
+        assert!(succeeds_with(&format!(":{check}"), &[], &set));
+    }
+}
@@ -0,0 +1,171 @@
+//! Compile the cache after a boot that had to parse the task files because
+//! alfad.bin was missing, stale or corrupt, so the next boot does not have
+//! to. It runs once the boot is complete, on a thread of the lowest
+//! priority, and is turned off with `cache_autogen: false` in the defaults.
+
+use super::{defaults::Defaults, single::Origin, write_cache, yaml::TaskConfigYaml, CacheStats, WriteCacheError};
+use crate::{
+    def::FILE_CFG_BT,
+    event_log::{self, CacheEvent},
+    version::ConfigSource,
+};
+use nix::{
+    libc,
+    unistd::{access, AccessFlags},
+};
+use std::{
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+use tracing::{error, info, warn};
+
+/// Niceness of the thread compiling the cache
+const NICENESS: libc::c_int = 19;
+
+/// The task files to compile the cache from, if the configuration was
+/// parsed from them although `order` puts the cache first
+pub fn input<'a>(source: &'a ConfigSource, order: &[Origin]) -> Option<&'a Path> {
+    let (input, origin) = match source {
+        ConfigSource::Yaml { dir } => (dir, Origin::Dir),
+        ConfigSource::File { path } => (path, Origin::File),
+        ConfigSource::Cache { .. } => return None,
+    };
+    let order = Origin::order(order);
+    let position = |origin| order.iter().position(|other| *other == origin);
+    (position(Origin::Cache) < position(origin)).then_some(input.as_path())
+}
+
+/// The cache next to `input`, alfad.d or alfad.yaml
+pub fn target(input: &Path) -> PathBuf {
+    input.parent().unwrap_or(Path::new("")).join(FILE_CFG_BT)
+}
+
+/// Start compiling the cache if the configuration was loaded from `source`
+/// instead, `defaults` allow it and the configuration directory is writable
+pub fn spawn(source: Option<ConfigSource>, defaults: &Defaults, builtin: Vec<TaskConfigYaml>) -> Option<JoinHandle<()>> {
+    if !defaults.cache_autogen {
+        return None;
+    }
+    let input = input(source.as_ref()?, &defaults.config_order)?.to_owned();
+    let target = target(&input);
+    let event_log = defaults.event_log.clone();
+    if access(target.parent()?, AccessFlags::W_OK).is_err() {
+        info!("Not writing {target:?}, {:?} is read-only", target.parent()?);
+        return None;
+    }
+    let spawned = thread::Builder::new().name("cache-autogen".to_owned()).spawn(move || {
+        // Only this thread on Linux, the services starting up come first
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS) } != 0 {
+            error!("Could not lower the priority of compiling the cache");
+        }
+        let _ = regenerate(&input, &target, builtin, event_log.as_deref());
+    });
+    spawned.inspect_err(|error| error!("Could not start compiling the cache: {error}")).ok()
+}
+
+/// Write the cache at `target` from the task files in `input`, the outcome
+/// goes to the log and to the event log at `event_log`
+pub fn regenerate(
+    input: &Path, target: &Path, builtin: Vec<TaskConfigYaml>, event_log: Option<&Path>,
+) -> Result<CacheStats, WriteCacheError> {
+    let written = write_cache(input, target, builtin);
+    match &written {
+        Ok(stats) => info!("Wrote {target:?} from {input:?} for the next boot, {stats}"),
+        Err(error) => error!("Could not write {target:?} from {input:?}: {error}"),
+    }
+    if let Some(path) = event_log {
+        let outcome = written.as_ref().map(ToString::to_string).map_err(ToString::to_string);
+        let event = CacheEvent { cache: target.to_owned(), at: SystemTime::now(), outcome };
+        if let Err(error) = event_log::append(path, &event) {
+            warn!(path = %path.display(), %error, "Could not write the event log");
+        }
+    }
+    written
+}
+
+#[cfg(test)]
+mod test {
+    use super::{input, regenerate, spawn, target};
+    use crate::{
+        builtin,
+        config::{decode, defaults::Defaults, single::Origin},
+        event_log::CacheEvent,
+        version::ConfigSource,
+    };
+    use std::{fs, path::Path};
+
+    #[test]
+    fn trigger() {
+        let dir = ConfigSource::Yaml { dir: "/etc/alfad/alfad.d".into() };
+        let file = ConfigSource::File { path: "/etc/alfad/alfad.yaml".into() };
+        let cache = ConfigSource::Cache { path: "/etc/alfad/alfad.bin".into(), format: 1, checksum: 0 };
+
+        // alfad.yaml comes before the cache by default, alfad.d after it
+        assert_eq!(input(&dir, &[]), Some(Path::new("/etc/alfad/alfad.d")));
+        assert_eq!(input(&file, &[]), None);
+        assert_eq!(input(&cache, &[]), None);
+        assert_eq!(input(&file, &[Origin::Cache]), Some(Path::new("/etc/alfad/alfad.yaml")));
+        assert_eq!(input(&dir, &[Origin::Dir, Origin::Cache]), None);
+        assert_eq!(target(Path::new("/etc/alfad/alfad.d")), Path::new("/etc/alfad/alfad.bin"));
+    }
+
+    #[test]
+    fn atomic_write() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("mount.task"), "name: mount\ncmd: mount -a\n").unwrap();
+        let target = root.path().join("alfad.bin");
+        fs::write(&target, "ALFADBIN").unwrap();
+
+        let stats = regenerate(&dir, &target, builtin::all(), None).unwrap();
+        let cached = decode(&fs::read(&target).unwrap()).unwrap();
+        assert_eq!(stats.tasks, cached.len());
+        assert!(cached.iter().any(|config| config.name == "mount"));
+        // Nothing left next to it
+        let mut files: Vec<_> = fs::read_dir(root.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, ["alfad.bin", "alfad.d"]);
+
+        // A failed write leaves the cache as it was
+        let packed = fs::read(&target).unwrap();
+        fs::create_dir(root.path().join("alfad.tmp")).unwrap();
+        regenerate(&dir, &target, builtin::all(), None).unwrap_err();
+        assert_eq!(fs::read(&target).unwrap(), packed);
+    }
+
+    #[test]
+    fn event_log() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("mount.task"), "name: mount\ncmd: mount -a\n").unwrap();
+        let target = root.path().join("alfad.bin");
+        let log = root.path().join("events.log");
+
+        let stats = regenerate(&dir, &target, builtin::all(), Some(&log)).unwrap();
+        fs::create_dir(root.path().join("alfad.tmp")).unwrap();
+        let error = regenerate(&dir, &target, builtin::all(), Some(&log)).unwrap_err();
+        let events: Vec<CacheEvent> =
+            fs::read_to_string(&log).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let outcomes: Vec<_> = events.iter().map(|event| (event.cache.as_path(), event.outcome.clone())).collect();
+        assert_eq!(outcomes, [(target.as_path(), Ok(stats.to_string())), (target.as_path(), Err(error.to_string()))]);
+    }
+
+    #[test]
+    fn disabled() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fs::create_dir(&dir).unwrap();
+        let source = || Some(ConfigSource::Yaml { dir: dir.clone() });
+
+        let defaults = Defaults { cache_autogen: false, ..Defaults::default() };
+        assert!(spawn(source(), &defaults, builtin::all()).is_none());
+        assert!(spawn(None, &Defaults::default(), builtin::all()).is_none());
+        assert!(!root.path().join("alfad.bin").exists());
+
+        spawn(source(), &Defaults::default(), builtin::all()).unwrap().join().unwrap();
+        assert!(root.path().join("alfad.bin").exists());
+    }
+}
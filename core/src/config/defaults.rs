@@ -0,0 +1,372 @@
+use super::{
+    limits::Limits,
+    root,
+    single::{self, Origin},
+    Locale, Quorum,
+};
+use crate::{
+    builtin::boot::BOOT_COMPLETE,
+    def::{FILE_CFG_YAML, FILE_DEFAULTS},
+    instance::Instance,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use strum::{Display, EnumString};
+use tracing::{error, warn};
+
+/// What to do when tasks failed by the time the boot is complete
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum BootFailurePolicy {
+    /// Only report the failed tasks
+    #[default]
+    Ignore,
+    /// Start an emergency shell on the console
+    Emergency,
+    /// Restart the machine after a grace period
+    Reboot,
+}
+
+/// Settings that are not tied to a single task. They are read from
+/// `defaults.yaml` next to alfad.d, or the `defaults` in alfad.yaml if it
+/// has them, and can be overridden on the kernel command line with
+/// `alfad.<setting>=<value>`.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    pub on_boot_failure: BootFailurePolicy,
+    /// File counting the boots since the last successful one, for A/B
+    /// updates. Boot counting is disabled if unset.
+    pub boot_counter: Option<PathBuf>,
+    /// Keep a file with the state of every task in DIR_STATE
+    pub state_dir: bool,
+    /// Remember tasks disabled with alfad-ctl across reboots, see
+    /// [`crate::instance::Instance::disabled_file`]
+    pub persist_disabled: bool,
+    /// Seconds between checks for tasks that missed a wakeup, 0 disables
+    /// the checks
+    pub sweep_interval: u64,
+    /// Variables kept by command lines with the `:` prefix, unless the
+    /// task has its own list
+    pub env_keep: Vec<String>,
+    /// TZ, LANG and LC_ALL of the command lines of tasks that don't set
+    /// their own and whose group doesn't either
+    pub locale: Locale,
+    /// Timestamps of alfad's own messages in UTC. Otherwise they are in
+    /// local time, after TZ or /etc/localtime of alfad.
+    pub utc_logs: bool,
+    /// Search path for programs when checking the configuration
+    pub path: String,
+    /// Size limits of the task files, only read from the file
+    pub limits: Limits,
+    /// When group markers are Done, by group name. Groups wait for all
+    /// members unless listed here.
+    pub groups: HashMap<String, Quorum>,
+    /// Write a failure bundle when a task fails, unless the task says
+    /// otherwise
+    pub collect_failure_data: bool,
+    /// Failure bundles kept per task, older ones are removed
+    pub failure_bundles: usize,
+    /// Write a Chrome trace of the boot to this file, see [`crate::trace`]
+    pub trace_out: Option<PathBuf>,
+    /// Append every state change and cache regeneration to this file, see
+    /// [`crate::event_log`]
+    pub event_log: Option<PathBuf>,
+    /// Seconds a shutdown waits at most for inhibitors in delay mode, see
+    /// [`crate::inhibit`]
+    pub inhibit_delay_max: u64,
+    /// Seconds builtin::time-sync-wait waits for the system clock to be
+    /// set before it gives up, see [`crate::builtin::timesync`]
+    pub time_sync_max_wait: u64,
+    /// Address builtin::healthz serves the probes on, like "0.0.0.0:8080",
+    /// with the `healthz` feature. They are not served if unset.
+    pub healthz_addr: Option<String>,
+    /// Task that has to be Done for `/readyz`, see
+    /// [`crate::builtin::healthz`]
+    pub healthz_ready: String,
+    /// Where the configuration is loaded from, the first that can be
+    /// wins, see [`Origin::order`]
+    pub config_order: Vec<Origin>,
+    /// Threads of the executor driving the tasks, 0 for one per CPU
+    pub threads: usize,
+    /// Compile the cache after a boot that parsed the task files instead,
+    /// see [`super::autogen`]. Off for a read-only root file system.
+    pub cache_autogen: bool,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            on_boot_failure: Default::default(),
+            boot_counter: None,
+            state_dir: false,
+            persist_disabled: false,
+            sweep_interval: 30,
+            env_keep: ["PATH", "TERM", "LANG"].map(str::to_owned).into(),
+            locale: Locale::default(),
+            utc_logs: true,
+            path: "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_owned(),
+            limits: Limits::default(),
+            groups: HashMap::new(),
+            collect_failure_data: false,
+            failure_bundles: 5,
+            trace_out: None,
+            event_log: None,
+            inhibit_delay_max: 30,
+            time_sync_max_wait: 60,
+            healthz_addr: None,
+            healthz_ready: BOOT_COMPLETE.to_owned(),
+            config_order: Origin::DEFAULT.into(),
+            threads: 0,
+            cache_autogen: true,
+        }
+    }
+}
+
+/// Settings a member of a group can leave to the group, set in the marker
+/// file of the group, e.g. `group::web.task` with `cmd: marker`. A task's
+/// own setting wins over its group's, which wins over defaults.yaml. Only
+/// `env_keep` and `locale` have defaults, which are looked up when a command
+/// line runs so the kernel command line still applies. `locale` is
+/// inherited setting by setting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inherited {
+    pub env_keep: Option<Vec<String>>,
+    pub locale: Locale,
+    pub log_cmd: Option<String>,
+    pub selinux_context: Option<String>,
+    pub apparmor_profile: Option<String>,
+}
+
+impl Inherited {
+    /// Keep what is set, take the rest from `parent`
+    pub fn inherit(self, parent: &Self) -> Self {
+        Self {
+            env_keep: self.env_keep.or_else(|| parent.env_keep.clone()),
+            locale: self.locale.inherit(&parent.locale),
+            log_cmd: self.log_cmd.or_else(|| parent.log_cmd.clone()),
+            selinux_context: self.selinux_context.or_else(|| parent.selinux_context.clone()),
+            apparmor_profile: self.apparmor_profile.or_else(|| parent.apparmor_profile.clone()),
+        }
+    }
+}
+
+impl Defaults {
+    /// What tasks inherit when neither they nor their group set it
+    pub fn inherited(&self) -> Inherited {
+        Inherited { env_keep: Some(self.env_keep.clone()), locale: self.locale.clone(), ..Default::default() }
+    }
+
+    /// The kernel command line is only for the system instance
+    pub fn load() -> Self {
+        let cmdline = match Instance::current().is_user() {
+            true => String::new(),
+            false => fs::read_to_string("/proc/cmdline").unwrap_or_default(),
+        };
+        Self::load_in(root(), &cmdline)
+    }
+
+    /// The defaults in alfad.yaml in `root` if it has them, defaults.yaml
+    /// otherwise
+    pub fn load_in(root: &Path, cmdline: &str) -> Self {
+        let from_file = single::read_defaults(&root.join(FILE_CFG_YAML));
+        let mut defaults = from_file.unwrap_or_else(|| Self::read(&root.join(FILE_DEFAULTS)));
+        defaults.apply_cmdline(cmdline);
+        defaults
+    }
+
+    pub fn load_from(path: &Path, cmdline: &str) -> Self {
+        let mut defaults = Self::read(path);
+        defaults.apply_cmdline(cmdline);
+        defaults
+    }
+
+    fn read(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_yaml::from_str(&text).unwrap_or_else(|error| {
+                error!("Ignoring {path:?}: {error}");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn apply_cmdline(&mut self, cmdline: &str) {
+        let parameters = cmdline.split_whitespace().filter_map(|x| x.strip_prefix("alfad.")?.split_once('='));
+        for (key, value) in parameters {
+            match key {
+                "on_boot_failure" => match value.parse() {
+                    Ok(policy) => self.on_boot_failure = policy,
+                    Err(_) => warn!("Ignoring invalid alfad.on_boot_failure={value}"),
+                },
+                "boot_counter" => self.boot_counter = Some(value.into()),
+                "env_keep" => self.env_keep = value.split(',').filter(|x| !x.is_empty()).map(str::to_owned).collect(),
+                "tz" => self.locale.tz = Some(value.to_owned()).filter(|x| !x.is_empty()),
+                "lang" => self.locale.lang = Some(value.to_owned()).filter(|x| !x.is_empty()),
+                "lc_all" => self.locale.lc_all = Some(value.to_owned()).filter(|x| !x.is_empty()),
+                "utc_logs" => match value.parse() {
+                    Ok(utc) => self.utc_logs = utc,
+                    Err(_) => warn!("Ignoring invalid alfad.utc_logs={value}"),
+                },
+                "state_dir" => match value.parse() {
+                    Ok(enabled) => self.state_dir = enabled,
+                    Err(_) => warn!("Ignoring invalid alfad.state_dir={value}"),
+                },
+                "sweep_interval" => match value.parse() {
+                    Ok(seconds) => self.sweep_interval = seconds,
+                    Err(_) => warn!("Ignoring invalid alfad.sweep_interval={value}"),
+                },
+                "persist_disabled" => match value.parse() {
+                    Ok(enabled) => self.persist_disabled = enabled,
+                    Err(_) => warn!("Ignoring invalid alfad.persist_disabled={value}"),
+                },
+                "trace_out" => self.trace_out = Some(value.into()),
+                "event_log" => self.event_log = Some(value.into()),
+                "inhibit_delay_max" => match value.parse() {
+                    Ok(seconds) => self.inhibit_delay_max = seconds,
+                    Err(_) => warn!("Ignoring invalid alfad.inhibit_delay_max={value}"),
+                },
+                "time_sync_max_wait" => match value.parse() {
+                    Ok(seconds) => self.time_sync_max_wait = seconds,
+                    Err(_) => warn!("Ignoring invalid alfad.time_sync_max_wait={value}"),
+                },
+                "healthz_addr" => self.healthz_addr = Some(value.to_owned()).filter(|x| !x.is_empty()),
+                "healthz_ready" => self.healthz_ready = value.to_owned(),
+                "threads" => match value.parse() {
+                    Ok(threads) => self.threads = threads,
+                    Err(_) => warn!("Ignoring invalid alfad.threads={value}"),
+                },
+                "cache_autogen" => match value.parse() {
+                    Ok(enabled) => self.cache_autogen = enabled,
+                    Err(_) => warn!("Ignoring invalid alfad.cache_autogen={value}"),
+                },
+                "config_order" => match value.split(',').map(str::parse).collect() {
+                    Ok(order) => self.config_order = order,
+                    Err(_) => warn!("Ignoring invalid alfad.config_order={value}"),
+                },
+                _ => warn!("Ignoring unknown kernel parameter alfad.{key}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BootFailurePolicy, Defaults, Inherited, Limits, Locale, Origin, Quorum};
+    use std::fs;
+
+    #[test]
+    fn file_and_cmdline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("defaults.yaml");
+        assert_eq!(Defaults::load_from(&path, ""), Defaults::default());
+
+        fs::write(&path, "on_boot_failure: emergency\nboot_counter: /var/lib/alfad/boot-count\n").unwrap();
+        assert_eq!(Defaults::load_from(&path, "").boot_counter, Some("/var/lib/alfad/boot-count".into()));
+        assert_eq!(Defaults::load_from(&path, "alfad.boot_counter=/boot/count").boot_counter, Some("/boot/count".into()));
+        assert_eq!(Defaults::load_from(&path, "quiet root=/dev/sda1").on_boot_failure, BootFailurePolicy::Emergency);
+        assert_eq!(
+            Defaults::load_from(&path, "quiet alfad.on_boot_failure=reboot").on_boot_failure,
+            BootFailurePolicy::Reboot
+        );
+        assert_eq!(
+            Defaults::load_from(&path, "alfad.on_boot_failure=explode").on_boot_failure,
+            BootFailurePolicy::Emergency
+        );
+        assert!(!Defaults::load_from(&path, "").state_dir);
+        assert!(Defaults::load_from(&path, "alfad.state_dir=true").state_dir);
+        assert!(Defaults::load_from(&path, "alfad.persist_disabled=true").persist_disabled);
+        assert_eq!(Defaults::load_from(&path, "alfad.sweep_interval=0").sweep_interval, 0);
+        assert_eq!(Defaults::load_from(&path, "").env_keep, ["PATH", "TERM", "LANG"]);
+        assert_eq!(Defaults::load_from(&path, "alfad.env_keep=PATH,TZ").env_keep, ["PATH", "TZ"]);
+        assert!(Defaults::load_from(&path, "alfad.env_keep=").env_keep.is_empty());
+        let locale = Defaults::load_from(&path, "alfad.tz=UTC alfad.lc_all=C").locale;
+        assert_eq!(locale, Locale { tz: Some("UTC".into()), lang: None, lc_all: Some("C".into()) });
+        assert!(Defaults::load_from(&path, "").utc_logs);
+        assert!(!Defaults::load_from(&path, "alfad.utc_logs=false").utc_logs);
+        assert_eq!(Defaults::load_from(&path, "alfad.trace_out=/run/trace.json").trace_out, Some("/run/trace.json".into()));
+        let defaults = Defaults::load_from(&path, "alfad.event_log=/run/var/alfad-events.log");
+        assert_eq!(defaults.event_log, Some("/run/var/alfad-events.log".into()));
+        assert_eq!(Defaults::load_from(&path, "alfad.inhibit_delay_max=300").inhibit_delay_max, 300);
+        assert_eq!(Defaults::load_from(&path, "alfad.time_sync_max_wait=5").time_sync_max_wait, 5);
+        let defaults = Defaults::load_from(&path, "alfad.healthz_addr=[::]:8080 alfad.healthz_ready=target::serving");
+        assert_eq!((defaults.healthz_addr.as_deref(), defaults.healthz_ready.as_str()), (Some("[::]:8080"), "target::serving"));
+        assert_eq!(Defaults::load_from(&path, "").healthz_ready, "target::boot-complete");
+        assert_eq!((Defaults::load_from(&path, "").threads, Defaults::load_from(&path, "alfad.threads=2").threads), (0, 2));
+        assert_eq!(Defaults::load_from(&path, "alfad.config_order=cache,dir").config_order, [Origin::Cache, Origin::Dir]);
+        assert_eq!(Defaults::load_from(&path, "alfad.config_order=cache,nope").config_order, Origin::DEFAULT);
+        assert!(Defaults::load_from(&path, "").cache_autogen);
+        assert!(!Defaults::load_from(&path, "alfad.cache_autogen=false").cache_autogen);
+
+        fs::write(&path, "locale:\n  tz: Europe/Berlin\nutc_logs: false\n").unwrap();
+        let defaults = Defaults::load_from(&path, "alfad.tz=");
+        assert_eq!((defaults.locale, defaults.utc_logs), (Locale::default(), false));
+        assert_eq!(Defaults::load_from(&path, "").locale.tz.as_deref(), Some("Europe/Berlin"));
+
+        fs::write(&path, "groups:\n  network: any\n").unwrap();
+        assert_eq!(Defaults::load_from(&path, "").groups["network"], Quorum::Any);
+
+        fs::write(&path, "limits:\n  max_tasks: 20\n").unwrap();
+        let limits = Defaults::load_from(&path, "").limits;
+        assert_eq!((limits.max_tasks, limits.max_commands), (20, Limits::default().max_commands));
+    }
+
+    #[test]
+    fn inheritance() {
+        let task = Inherited {
+            locale: Locale { tz: Some("UTC".into()), ..Default::default() },
+            log_cmd: Some("logger -t task".into()),
+            ..Default::default()
+        };
+        let group = Inherited {
+            env_keep: Some(vec!["PATH".into(), "WEB_ROOT".into()]),
+            locale: Locale { tz: Some("Europe/Berlin".into()), lang: Some("de_DE.UTF-8".into()), lc_all: None },
+            log_cmd: Some("logger -t web".into()),
+            apparmor_profile: Some("web".into()),
+            ..Default::default()
+        };
+        let defaults = Defaults::default().inherited();
+        let resolved = task.clone().inherit(&group).inherit(&defaults);
+        assert_eq!(
+            resolved,
+            Inherited {
+                env_keep: group.env_keep.clone(),
+                // Setting by setting
+                locale: Locale { tz: Some("UTC".into()), lang: Some("de_DE.UTF-8".into()), lc_all: None },
+                log_cmd: task.log_cmd.clone(),
+                selinux_context: None,
+                apparmor_profile: Some("web".into())
+            }
+        );
+        assert_eq!(task.inherit(&Inherited::default()).inherit(&defaults).env_keep, defaults.env_keep);
+    }
+
+    #[test]
+    fn from_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("defaults.yaml"), "state_dir: true\n").unwrap();
+        assert!(Defaults::load_in(dir.path(), "").state_dir);
+
+        // Without defaults of its own, alfad.yaml leaves them to defaults.yaml
+        fs::write(dir.path().join("alfad.yaml"), "tasks: []\n").unwrap();
+        assert!(Defaults::load_in(dir.path(), "").state_dir);
+        fs::write(dir.path().join("alfad.yaml"), "defaults:\n  sweep_interval: 5\ntasks: []\n").unwrap();
+        let defaults = Defaults::load_in(dir.path(), "alfad.sweep_interval=7");
+        assert_eq!((defaults.state_dir, defaults.sweep_interval), (false, 7));
+    }
+
+    #[test]
+    fn invalid_file_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("defaults.yaml");
+        fs::write(&path, "on_boot_failure: explode\n").unwrap();
+        assert_eq!(Defaults::load_from(&path, ""), Defaults::default());
+        fs::write(&path, "typo: reboot\n").unwrap();
+        assert_eq!(Defaults::load_from(&path, ""), Defaults::default());
+    }
+}
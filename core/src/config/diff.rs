@@ -0,0 +1,181 @@
+//! What changed between two sets of tasks, e.g. the running configuration
+//! and the task files on disk
+
+use super::TaskConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+impl TaskConfig {
+    /// Names of the fields that differ from `other`, as written in a task
+    /// file. Empty exactly if both are equal.
+    pub fn changed_fields(&self, other: &Self) -> Vec<&'static str> {
+        // Taken apart, so a new field can't be forgotten here
+        let TaskConfig {
+            name,
+            payload,
+            with,
+            after,
+            after_states,
+            after_stopped,
+            stop_dependency,
+            restart_dependency,
+            before,
+            respawn,
+            respawn_recheck,
+            run_timeout,
+            group,
+            description,
+            doc_url,
+            env_keep,
+            quorum,
+            adopt,
+            log_cmd,
+            log_rate_limit,
+            log_timestamps,
+            requires_kernel,
+            requires_resources,
+            requires_privileges,
+            locale,
+            env_file,
+            env,
+            env_export,
+            mask_args,
+            selinux_context,
+            apparmor_profile,
+            security_required,
+            collect_failure_data,
+            protected,
+            source,
+        } = self;
+        let fields = [
+            ("name", *name == other.name),
+            ("cmd", *payload == other.payload),
+            ("with", *with == other.with),
+            ("after", *after == other.after && *after_states == other.after_states),
+            ("after_stopped", *after_stopped == other.after_stopped),
+            ("stop_dependency", *stop_dependency == other.stop_dependency),
+            ("restart_dependency", *restart_dependency == other.restart_dependency),
+            ("before", *before == other.before),
+            ("respawn", *respawn == other.respawn),
+            ("respawn_recheck", *respawn_recheck == other.respawn_recheck),
+            ("run_timeout", *run_timeout == other.run_timeout),
+            ("group", *group == other.group),
+            ("description", *description == other.description),
+            ("doc_url", *doc_url == other.doc_url),
+            ("env_keep", *env_keep == other.env_keep),
+            ("quorum", *quorum == other.quorum),
+            ("adopt", *adopt == other.adopt),
+            ("log_cmd", *log_cmd == other.log_cmd),
+            ("log_rate_limit", *log_rate_limit == other.log_rate_limit),
+            ("log_timestamps", *log_timestamps == other.log_timestamps),
+            ("requires_kernel", *requires_kernel == other.requires_kernel),
+            ("requires_resources", *requires_resources == other.requires_resources),
+            ("requires_privileges", *requires_privileges == other.requires_privileges),
+            ("locale", *locale == other.locale),
+            ("env_file", *env_file == other.env_file),
+            ("env", *env == other.env),
+            ("env_export", *env_export == other.env_export),
+            ("mask_args", *mask_args == other.mask_args),
+            ("selinux_context", *selinux_context == other.selinux_context),
+            ("apparmor_profile", *apparmor_profile == other.apparmor_profile),
+            ("security_required", *security_required == other.security_required),
+            ("collect_failure_data", *collect_failure_data == other.collect_failure_data),
+            ("protected", *protected == other.protected),
+            ("source", *source == other.source),
+        ];
+        fields.into_iter().filter(|(_, same)| !same).map(|(field, _)| field).collect()
+    }
+}
+
+/// A task that is in both sets, but not the same
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub task: String,
+    pub fields: Vec<String>,
+}
+
+/// Tasks by name, each list sorted
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<Change>,
+}
+
+impl ConfigDiff {
+    /// What it takes to get from `old` to `new`
+    pub fn new<'a>(old: impl IntoIterator<Item = &'a TaskConfig>, new: impl IntoIterator<Item = &'a TaskConfig>) -> Self {
+        let old: HashMap<_, _> = old.into_iter().map(|config| (config.name.as_str(), config)).collect();
+        let new: HashMap<_, _> = new.into_iter().map(|config| (config.name.as_str(), config)).collect();
+        let mut diff = Self {
+            added: new.keys().filter(|name| !old.contains_key(*name)).map(|name| name.to_string()).collect(),
+            removed: old.keys().filter(|name| !new.contains_key(*name)).map(|name| name.to_string()).collect(),
+            changed: old
+                .iter()
+                .filter_map(|(name, config)| {
+                    let fields = config.changed_fields(new.get(name)?);
+                    let fields = fields.into_iter().map(str::to_owned).collect::<Vec<_>>();
+                    (!fields.is_empty()).then(|| Change { task: name.to_string(), fields })
+                })
+                .collect(),
+        };
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort_by(|a, b| a.task.cmp(&b.task));
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Change, ConfigDiff};
+    use crate::config::{yaml::TaskConfigYaml, TaskConfig};
+
+    fn task(yaml: &str) -> TaskConfig {
+        serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config().unwrap()
+    }
+
+    #[test]
+    fn fields() {
+        let sshd = task("name: sshd\ncmd: sshd -D\nafter: [network]\n");
+        assert!(sshd.changed_fields(&task("name: sshd\ncmd: sshd -D\nafter: [network]\n")).is_empty());
+        assert_eq!(sshd, task("name: sshd\ncmd: sshd -D\nafter: [network]\n"));
+
+        let changed = task("name: sshd\ncmd: sshd -D -p 2222\nafter: [network]\nrespawn: 3\n");
+        assert_eq!(sshd.changed_fields(&changed), ["cmd", "respawn"]);
+        assert_ne!(sshd, changed);
+        // Prefixes and timeouts are part of the command
+        assert_eq!(sshd.changed_fields(&task("name: sshd\ncmd: -sshd -D\nafter: [network]\n")), ["cmd"]);
+        let timeout = task("name: sshd\ncmd:\n  - {run: sshd -D, timeout: 5s}\nafter: [network]\n");
+        assert_eq!(sshd.changed_fields(&timeout), ["cmd"]);
+        assert_eq!(sshd.changed_fields(&task("name: sshd\ncmd: marker\nafter: [network]\n")), ["cmd"]);
+        assert_eq!(sshd.changed_fields(&task("name: sshd\ncmd: sshd -D\n")), ["after"]);
+    }
+
+    #[test]
+    fn builtins() {
+        let builtin = || task("name: sweep\ncmd: {builtin: boot::count}\n");
+        assert_eq!(builtin(), builtin());
+        assert_eq!(builtin().changed_fields(&task("name: sweep\ncmd: {builtin: state::dir}\n")), ["cmd"]);
+    }
+
+    #[test]
+    fn sets() {
+        let old = [task("name: a\ncmd: a\n"), task("name: b\ncmd: b\n"), task("name: c\ncmd: c\n")];
+        let new = [task("name: d\ncmd: d\n"), task("name: b\ncmd: b\n"), task("name: a\ncmd: a --new\ngroup: x\n")];
+        let diff = ConfigDiff::new(&old, &new);
+        assert_eq!(
+            diff,
+            ConfigDiff {
+                added: vec!["d".into()],
+                removed: vec!["c".into()],
+                changed: vec![Change { task: "a".into(), fields: vec!["cmd".into(), "group".into()] }],
+            }
+        );
+        assert!(ConfigDiff::new(&old, &old).is_empty());
+    }
+}
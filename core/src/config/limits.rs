@@ -0,0 +1,138 @@
+use super::yaml::{CommandLineYaml, PayloadYaml, TaskConfigYaml};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Upper bounds for the configuration, so a broken or hostile task file
+/// can not use up the memory of a small board. Set in the `limits` section
+/// of `defaults.yaml`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Task files in alfad.d
+    pub max_tasks: usize,
+    /// Size of a single task file in bytes, checked before it is parsed
+    pub max_file_size: u64,
+    /// Lines in the `cmd` of a task
+    pub max_commands: usize,
+    /// Length of a single command line in bytes
+    pub max_line_length: usize,
+    /// Entries in each of `after`, `with`, `before` and `provides`
+    pub max_dependencies: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_tasks: 5000,
+            max_file_size: 1024 * 1024,
+            max_commands: 1000,
+            max_line_length: 64 * 1024,
+            max_dependencies: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("{count} task files, only {max} are allowed")]
+    TooManyTasks { count: usize, max: usize },
+    #[error("File has {size} bytes, only {max} are allowed")]
+    FileTooLarge { size: u64, max: u64 },
+    #[error("{task} has {count} command lines, only {max} are allowed")]
+    TooManyCommands { task: String, count: usize, max: usize },
+    #[error("Line {line} of {task} has {length} bytes, only {max} are allowed")]
+    LineTooLong { task: String, line: usize, length: usize, max: usize },
+    #[error("{task} has {count} entries in {field}, only {max} are allowed")]
+    TooManyDependencies { task: String, field: &'static str, count: usize, max: usize },
+}
+
+impl Limits {
+    pub fn check_count(&self, count: usize) -> Result<(), LimitError> {
+        if count > self.max_tasks {
+            return Err(LimitError::TooManyTasks { count, max: self.max_tasks });
+        }
+        Ok(())
+    }
+
+    pub fn check_file_size(&self, size: u64) -> Result<(), LimitError> {
+        if size > self.max_file_size {
+            return Err(LimitError::FileTooLarge { size, max: self.max_file_size });
+        }
+        Ok(())
+    }
+
+    pub fn check_task(&self, config: &TaskConfigYaml) -> Result<(), LimitError> {
+        let task = || config.name.clone();
+        let lines: Vec<&str> = match &config.cmd {
+            PayloadYaml::Service(cmd) => cmd.lines().collect(),
+            PayloadYaml::Lines(lines) => lines.iter().map(CommandLineYaml::run).collect(),
+            _ => Vec::new(),
+        };
+        if !lines.is_empty() {
+            let count = lines.len();
+            if count > self.max_commands {
+                return Err(LimitError::TooManyCommands { task: task(), count, max: self.max_commands });
+            }
+            if let Some((index, line)) = lines.iter().enumerate().find(|(_, line)| line.len() > self.max_line_length) {
+                let (line, length) = (index + 1, line.len());
+                return Err(LimitError::LineTooLong { task: task(), line, length, max: self.max_line_length });
+            }
+        }
+
+        let lists = [
+            ("after", config.after.len()),
+            ("with", config.with.len()),
+            ("before", config.before.len()),
+            ("provides", config.provides.len()),
+        ];
+        match lists.into_iter().find(|(_, count)| *count > self.max_dependencies) {
+            Some((field, count)) => {
+                Err(LimitError::TooManyDependencies { task: task(), field, count, max: self.max_dependencies })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LimitError, Limits};
+    use crate::config::yaml::TaskConfigYaml;
+
+    fn limits() -> Limits {
+        Limits { max_tasks: 2, max_file_size: 100, max_commands: 2, max_line_length: 10, max_dependencies: 2 }
+    }
+
+    fn check(yaml: &str) -> Result<(), LimitError> {
+        limits().check_task(&serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap())
+    }
+
+    #[test]
+    fn within_limits() {
+        check("name: a\ncmd: |\n  mount -a\n  true\nafter: [b, c]\nwith: d\n").unwrap();
+        limits().check_count(2).unwrap();
+        limits().check_file_size(100).unwrap();
+    }
+
+    #[test]
+    fn each_limit() {
+        assert_eq!(limits().check_count(3), Err(LimitError::TooManyTasks { count: 3, max: 2 }));
+        assert_eq!(limits().check_file_size(101), Err(LimitError::FileTooLarge { size: 101, max: 100 }));
+        assert_eq!(
+            check("name: a\ncmd: |\n  true\n  true\n  true\n"),
+            Err(LimitError::TooManyCommands { task: "a".into(), count: 3, max: 2 })
+        );
+        assert_eq!(
+            check("name: a\ncmd: |\n  true\n  echo 0123456789\n"),
+            Err(LimitError::LineTooLong { task: "a".into(), line: 2, length: 15, max: 10 })
+        );
+        assert_eq!(
+            check("name: a\ncmd: \"true\"\nafter: [b, c, d]\n"),
+            Err(LimitError::TooManyDependencies { task: "a".into(), field: "after", count: 3, max: 2 })
+        );
+        assert_eq!(
+            check("name: a\ncmd: \"true\"\nprovides: [b, c, d]\n"),
+            Err(LimitError::TooManyDependencies { task: "a".into(), field: "provides", count: 3, max: 2 })
+        );
+    }
+}
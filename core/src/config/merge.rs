@@ -0,0 +1,229 @@
+//! One configuration out of the cache, the task files changed after it was
+//! compiled and the builtins, for images updated with a new alfad.bin but
+//! stale task files or the other way around:
+//!
+//! - the cache is authoritative for the tasks it contains
+//! - task files in alfad.d modified after alfad.bin replace the cached task
+//!   of the same name or add one, with a warning either way
+//! - markers generated for their groups add their members to the cached
+//!   marker of the same name
+//! - builtins are added once per name, unless a task took over their name
+//!   or registry key
+//!
+//! The result is sorted like a configuration read from the task files.
+
+use super::{drop_errors, yaml::TaskConfigYaml, TaskConfig};
+use crate::{builtin, def::SRC_GENERATED, ordering::sort};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use tracing::{debug, warn};
+
+/// Merge `newer`, the task files modified after `cache` was compiled, and
+/// `builtin` into `cache`
+pub fn merge(cache: Vec<TaskConfig>, newer: Vec<TaskConfig>, builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
+    let mut configs = cache;
+    let mut index: HashMap<_, _> = configs.iter().enumerate().map(|(i, config)| (config.name.clone(), i)).collect();
+    let mut left = Vec::new();
+    for config in newer {
+        match index.get(&config.name).copied() {
+            Some(i) if is_generated(&config) && configs[i].payload.is_marker() => {
+                let marker = &mut configs[i];
+                add_missing(&mut marker.after, config.after);
+                add_missing(&mut marker.with, config.with);
+            }
+            Some(i) => {
+                warn!("{} in {:?} changed after alfad.bin was compiled, replacing the cached task", config.name, config.source);
+                let cached = std::mem::replace(&mut configs[i], config);
+                if cached.group != configs[i].group {
+                    left.extend(cached.group.map(|group| (format!("group::{group}"), cached.name)));
+                }
+            }
+            None => {
+                if !is_generated(&config) {
+                    warn!("{} in {:?} is not in alfad.bin, adding it", config.name, config.source);
+                }
+                index.insert(config.name.clone(), configs.len());
+                configs.push(config);
+            }
+        }
+    }
+
+    // Tasks that moved to another group are no members of the old one anymore
+    for (marker, member) in left {
+        if let Some(marker) = index.get(&marker).map(|i| &mut configs[*i]).filter(|marker| is_generated(marker)) {
+            marker.after.retain(|name| *name != member);
+            marker.with.retain(|name| *name != member);
+        }
+    }
+
+    if cfg!(feature = "before") {
+        resolve_before(&mut configs, &index);
+    }
+
+    let overridden: Vec<_> = configs.iter().filter_map(|config| config.payload.builtin_key()).collect();
+    let builtin = builtin::without(builtin, &overridden);
+    let mut names: HashSet<_> = configs.iter().map(|config| config.name.clone()).collect();
+    for config in builtin {
+        if !names.insert(config.name.clone()) {
+            debug!("{} is already configured, not adding the builtin", config.name);
+            continue;
+        }
+        configs.extend(drop_errors(config.into_config()));
+    }
+    sort(configs)
+}
+
+/// Add every task to the `after` of the tasks it runs before again, the
+/// cached ones might have been replaced
+fn resolve_before(configs: &mut [TaskConfig], index: &HashMap<String, usize>) {
+    let before: Vec<_> = configs
+        .iter()
+        .flat_map(|config| config.before.iter().filter_map(|name| index.get(name)).map(|i| (*i, config.name.clone())))
+        .collect();
+    for (i, name) in before {
+        add_missing(&mut configs[i].after, [name]);
+    }
+}
+
+fn add_missing(names: &mut Vec<String>, more: impl IntoIterator<Item = String>) {
+    for name in more {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+}
+
+fn is_generated(config: &TaskConfig) -> bool {
+    config.source.as_deref() == Some(Path::new(SRC_GENERATED))
+}
+
+#[cfg(test)]
+mod test {
+    use super::merge;
+    use crate::{
+        builtin,
+        config::{payload::Payload, TaskConfig},
+        def::{SRC_BUILTIN, SRC_GENERATED},
+    };
+    use std::path::Path;
+
+    fn task(name: &str, source: &str, cmd: &str) -> TaskConfig {
+        TaskConfig { payload: cmd.parse().unwrap(), source: Some(source.into()), ..TaskConfig::new(name.to_owned()) }
+    }
+
+    fn marker(name: &str, members: &[&str]) -> TaskConfig {
+        let members: Vec<_> = members.iter().map(|member| member.to_string()).collect();
+        TaskConfig { payload: Payload::Marker, with: members.clone(), after: members, ..task(name, SRC_GENERATED, "true") }
+    }
+
+    fn find<'a>(configs: &'a [TaskConfig], name: &str) -> &'a TaskConfig {
+        let mut found = configs.iter().filter(|config| config.name == name);
+        let config = found.next().unwrap_or_else(|| panic!("{name} is missing"));
+        assert!(found.next().is_none(), "{name} is there more than once");
+        config
+    }
+
+    fn names(configs: &[TaskConfig]) -> Vec<&str> {
+        let mut names: Vec<_> = configs.iter().map(|config| config.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    /// Every builtin is there exactly once
+    fn assert_builtins(configs: &[TaskConfig]) {
+        for config in builtin::all() {
+            find(configs, &config.name);
+        }
+    }
+
+    #[test]
+    fn cache_only() {
+        let cache = vec![task("mount", "alfad.d/mount", "mount -a"), task("getty", "alfad.d/getty", "getty")];
+        let configs = merge(cache.clone(), Vec::new(), builtin::all());
+        assert_eq!(configs.len(), cache.len() + builtin::all().len());
+        assert_eq!(find(&configs, "mount"), &cache[0]);
+        assert_builtins(&configs);
+    }
+
+    #[test]
+    fn yaml_only() {
+        let newer = vec![task("mount", "alfad.d/mount", "mount -a"), marker("group::early", &["mount"])];
+        let configs = merge(Vec::new(), newer.clone(), builtin::all());
+        assert_eq!(find(&configs, "mount"), &newer[0]);
+        assert_eq!(find(&configs, "group::early"), &newer[1]);
+        assert_builtins(&configs);
+    }
+
+    #[test]
+    fn overlap() {
+        let mut getty = task("getty", "alfad.d/getty", "getty tty1");
+        getty.group = Some("console".to_owned());
+        let cache = vec![
+            task("mount", "alfad.d/mount", "mount -a"),
+            getty,
+            task("sshd", "alfad.d/sshd", "sshd"),
+            marker("group::console", &["getty", "sshd"]),
+        ];
+        let mut newer_getty = task("getty", "alfad.d/getty", "getty tty2");
+        newer_getty.group = Some("login".to_owned());
+        let newer = vec![newer_getty.clone(), task("udev", "alfad.d/udev", "udevd"), marker("group::login", &["getty"])];
+
+        let configs = merge(cache, newer, Vec::new());
+        assert_eq!(names(&configs), ["getty", "group::console", "group::login", "mount", "sshd", "udev"]);
+        assert_eq!(find(&configs, "getty"), &newer_getty);
+        assert_eq!(find(&configs, "sshd").payload, "sshd".parse().unwrap());
+        assert_eq!(find(&configs, "group::console").after, ["sshd"]);
+        assert_eq!(find(&configs, "group::login").with, ["getty"]);
+    }
+
+    /// `before` of a newer task file reaches a cached task
+    #[cfg(feature = "before")]
+    #[test]
+    fn newer_before() {
+        let cache = vec![task("mount", "alfad.d/mount", "mount -a")];
+        let mut udev = task("udev", "alfad.d/udev", "udevd");
+        udev.before = vec!["mount".to_owned()];
+        let configs = merge(cache, vec![udev], Vec::new());
+        assert_eq!(find(&configs, "mount").after, ["udev"]);
+    }
+
+    #[test]
+    fn markers_are_combined() {
+        let cache = vec![task("sshd", "alfad.d/sshd", "sshd"), marker("group::net", &["sshd"])];
+        let newer = vec![task("dhcp", "alfad.d/dhcp", "dhcpcd"), marker("group::net", &["dhcp"])];
+        let configs = merge(cache, newer, Vec::new());
+        let net = find(&configs, "group::net");
+        assert_eq!(net.after, ["sshd", "dhcp"]);
+        assert_eq!(net.with, ["sshd", "dhcp"]);
+
+        // A marker file replaces the cached one like any task
+        let cache = vec![marker("group::net", &["sshd"])];
+        let newer = vec![TaskConfig { quorum: crate::config::Quorum::Any, ..task("group::net", "alfad.d/net", "true") }];
+        let configs = merge(cache, newer.clone(), Vec::new());
+        assert_eq!(find(&configs, "group::net"), &newer[0]);
+    }
+
+    #[test]
+    fn builtin_collision() {
+        // Taken over by registry key in the cache, by name in a newer task file
+        let mut daemon = TaskConfig::new("ctl-daemon".to_owned());
+        daemon.payload = Payload::Builtin(builtin::lookup("ctl::daemon").unwrap());
+        let cache = vec![daemon, task("builtin::sweep", SRC_BUILTIN, "true")];
+        let newer = vec![task("builtin::hooks", "alfad.d/hooks", "run-parts /etc/hooks")];
+
+        // Registered twice, added once
+        let mut registered = builtin::all();
+        registered.extend(builtin::all());
+        let configs = merge(cache, newer, registered);
+
+        assert!(configs.iter().all(|config| config.name != "builtin::ctl::daemon"));
+        assert_eq!(find(&configs, "ctl-daemon").payload.builtin_key(), Some("ctl::daemon"));
+        assert_eq!(find(&configs, "builtin::sweep").payload, "true".parse().unwrap());
+        assert_eq!(find(&configs, "builtin::hooks").source.as_deref(), Some(Path::new("alfad.d/hooks")));
+        for config in builtin::all().iter().filter(|config| config.name != "builtin::ctl::daemon") {
+            find(&configs, &config.name);
+        }
+    }
+}
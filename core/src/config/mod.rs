@@ -1,22 +1,49 @@
+pub mod autogen;
+pub mod defaults;
+pub mod diff;
+pub mod limits;
+pub mod merge;
+pub mod name;
 pub mod payload;
+pub mod schema;
+pub mod single;
+pub mod view;
 pub mod yaml;
-use self::{payload::Payload, yaml::TaskConfigYaml};
+use self::{
+    defaults::Defaults,
+    limits::Limits,
+    payload::Payload,
+    single::{Origin, SingleFile},
+    yaml::{TaskConfigYaml, Timeout},
+};
 use crate::{
-    ordering::{construct_markers, resolve_before, sort},
+    builtin,
+    command_line::CommandLine,
+    def::{APLT_MAIN, FILE_CFG_BT, FILE_CFG_YAML, FILE_DEFAULTS, FILE_KMSG, SRC_BUILTIN, SRC_GENERATED},
+    instance::Instance,
+    ordering::{construct_markers, inherit_from_groups, maybe_resolve_before, reserved_prefix, sort},
+    privilege::Privilege,
     validate,
+    version::{self, ConfigSource},
 };
 use serde::{Deserialize, Serialize};
 use smol::stream::StreamExt;
 use std::{
+    collections::{BTreeMap, HashMap},
     error::Error,
-    fmt::Debug,
-    fs::{self, read_dir, OpenOptions},
-    path::Path,
+    fmt::{Debug, Display},
+    fs::{self, read_dir, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
 };
-use tracing::{debug, info_span};
+use strum::EnumString;
+use thiserror::Error;
+use tracing::{debug, info_span, warn};
 use tracing::{error, instrument};
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 pub enum Respawn {
     /// Never retry this task (default)
     No,
@@ -33,7 +60,203 @@ impl Default for Respawn {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+impl FromStr for Respawn {
+    type Err = InvalidRespawn;
+
+    /// Accepts "no" or "retry:N"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "no" => Ok(Self::No),
+            Some(("retry", attempts)) => attempts.parse().map(Self::Retry).map_err(|_| InvalidRespawn(s.to_owned())),
+            _ => Err(InvalidRespawn(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Respawn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::No => f.write_str("no"),
+            Self::Retry(attempts) => write!(f, "retry:{attempts}"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid respawn policy '{}', expected \"no\" or something like \"retry:5\"", .0)]
+pub struct InvalidRespawn(String);
+
+/// When a marker is Done
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quorum {
+    /// Once every task in `after` is Done (default)
+    #[default]
+    All,
+    /// Once any task in `after` is Done
+    Any,
+}
+
+/// Which already running processes a task may take over instead of
+/// starting its own
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Adopt {
+    /// Regular expression the command line of the process has to match,
+    /// arguments joined by spaces
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Pid of a process to adopt whenever the task would start. Always
+    /// written, the cache has no way to tell a field is missing.
+    #[serde(default)]
+    pub pidfile: Option<PathBuf>,
+}
+
+/// Kernel a task needs, it is skipped on any other
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RequiresKernel {
+    /// Oldest release the task runs on, like "5.10"
+    #[serde(default)]
+    pub min_version: Option<String>,
+    /// Options that have to be built in or modules, `CONFIG_` may be left
+    /// out. Looked up in /boot/config-<release> or /proc/config.gz.
+    #[serde(default)]
+    pub config: Vec<String>,
+    /// What happens if the version or configuration can't be found out
+    #[serde(default)]
+    pub unknown: UnknownKernel,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownKernel {
+    /// Run the task anyway (default)
+    #[default]
+    Run,
+    Skip,
+}
+
+/// What has to be free on the system for a task to start
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequiresResources {
+    /// MemAvailable of /proc/meminfo
+    #[serde(default)]
+    pub min_free_mem_mb: Option<u64>,
+    #[serde(default)]
+    pub min_free_disk_mb: Option<FreeDisk>,
+    /// What happens while there is not enough
+    #[serde(default)]
+    pub unmet: UnmetResources,
+    /// How often to look again while waiting, 60s if unset
+    #[serde(default)]
+    pub resource_wait: Option<Timeout>,
+}
+
+/// Space available to unprivileged users on the file system of `path`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FreeDisk {
+    pub path: PathBuf,
+    pub mb: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmetResources {
+    /// Stay Waiting and look again every `resource_wait` (default)
+    #[default]
+    Wait,
+    Skip,
+}
+
+/// Locale and time zone of the command lines of a task. What is unset is
+/// left to the group and then to defaults.yaml, see [`crate::command_line::env`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Locale {
+    /// TZ, like "UTC" or "Europe/Berlin"
+    pub tz: Option<String>,
+    /// LANG, like "C.UTF-8"
+    pub lang: Option<String>,
+    /// LC_ALL, overrides LANG and every other LC_ variable
+    pub lc_all: Option<String>,
+}
+
+impl Locale {
+    /// Keep what is set, take the rest from `parent`
+    pub fn inherit(self, parent: &Self) -> Self {
+        Self {
+            tz: self.tz.or_else(|| parent.tz.clone()),
+            lang: self.lang.or_else(|| parent.lang.clone()),
+            lc_all: self.lc_all.or_else(|| parent.lc_all.clone()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The variables that are set
+    pub fn vars(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [("TZ", &self.tz), ("LANG", &self.lang), ("LC_ALL", &self.lc_all)]
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value.as_deref()?)))
+    }
+}
+
+/// At most `lines` lines of output every `per`, the rest is dropped, see
+/// [`crate::log_filter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogRateLimit {
+    pub lines: u32,
+    pub per: Timeout,
+}
+
+/// What a respawning task waits for again before it restarts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RespawnRecheck {
+    /// Restart right away
+    None,
+    /// Wait for the tasks in `with` to run again (default)
+    #[default]
+    With,
+    /// Wait for `with` and `after`, like on the first start
+    All,
+}
+
+/// The state a task in `after` is waited for, written after a colon like
+/// `db:running`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, EnumString, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum WaitKind {
+    /// Running, or Done already
+    Running,
+    /// Done (default)
+    #[default]
+    Done,
+    /// Concluded, whatever the result
+    Concluded,
+    /// Not waited for, the task only has to exist
+    Exists,
+}
+
+impl WaitKind {
+    /// Split an entry of `after` into the task name and the state. Only a
+    /// known state after a single colon counts, so `feature::fs::run` and
+    /// `a:b` stay names.
+    pub fn split(entry: &str) -> (&str, Self) {
+        let qualified = entry.rsplit_once(':').filter(|(name, _)| !name.is_empty() && !name.ends_with(':'));
+        match qualified.and_then(|(name, state)| Some((name, state.parse().ok()?))) {
+            Some((name, kind)) => (name, kind),
+            None => (entry, Self::Done),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct TaskConfig {
     pub name: String,
     // #[serde(default)]
@@ -42,9 +265,60 @@ pub struct TaskConfig {
     pub with: Vec<String>,
     // #[serde(default)]
     pub after: Vec<String>,
+    /// Tasks in `after` that are waited for until another state than Done
+    pub after_states: Vec<(String, WaitKind)>,
+    /// Waited for until they are stopped, see the task file
+    pub after_stopped: Vec<String>,
+    pub stop_dependency: bool,
+    pub restart_dependency: bool,
+    /// As written, already added to the `after` of those tasks if the
+    /// `before` feature is enabled
+    pub before: Vec<String>,
     // #[serde(default)]
     pub respawn: Respawn,
+    pub respawn_recheck: RespawnRecheck,
+    /// How long the command lines may run in all before the task is killed
+    /// and fails, meant for oneshots
+    pub run_timeout: Option<Timeout>,
     pub group: Option<String>,
+    pub description: Option<String>,
+    pub doc_url: Option<String>,
+    pub env_keep: Option<Vec<String>>,
+    /// Only used by markers
+    pub quorum: Quorum,
+    pub adopt: Option<Adopt>,
+    /// Gets the output of the task on stdin
+    pub log_cmd: Option<CommandLine>,
+    pub log_rate_limit: Option<LogRateLimit>,
+    /// Prefix every line of output with the time it was read
+    pub log_timestamps: bool,
+    pub requires_kernel: Option<RequiresKernel>,
+    pub requires_resources: Option<RequiresResources>,
+    pub requires_privileges: Vec<Privilege>,
+    /// Only what the task and its group set, defaults.yaml is looked up
+    /// when a command line runs
+    pub locale: Locale,
+    /// Read when a command line runs
+    pub env_file: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+    /// stdout of the command lines is `VARIABLE=value` lines for the tasks
+    /// that start after this one is Done, see [`crate::command_line::env`]
+    pub env_export: bool,
+    /// Arguments of the command lines, by their index in argv, that are
+    /// shown as `***` in logs and alfad-ctl list
+    pub mask_args: Vec<usize>,
+    pub selinux_context: Option<String>,
+    pub apparmor_profile: Option<String>,
+    /// Fail instead of running without a label if its LSM is not enabled
+    pub security_required: bool,
+    /// Write a failure bundle when the task fails, defaults.yaml decides
+    /// if unset
+    pub collect_failure_data: Option<bool>,
+    /// Kill, deactivate and restart need --force-protected
+    pub protected: bool,
+    /// File the task was read from, relative to the config directory in
+    /// the cache. Builtins and generated markers have a synthetic source.
+    pub source: Option<PathBuf>,
 }
 
 impl TaskConfig {
@@ -56,73 +330,474 @@ impl TaskConfig {
         self.after.push(name.to_owned());
         self
     }
+
+    /// The state the task `name` in `after` is waited for
+    pub fn wait_kind(&self, name: &str) -> WaitKind {
+        self.after_states.iter().find(|(task, _)| task == name).map_or(WaitKind::Done, |(_, kind)| *kind)
+    }
+
+    /// Parse the command lines now instead of when the task runs first
+    pub fn parse_payload(self) -> Result<Self, yaml::ConfigError> {
+        Ok(Self { payload: self.payload.parse()?, ..self })
+    }
+}
+
+/// Parse the command lines of every task, for tools that report errors up
+/// front. Tasks with invalid command lines are left out.
+pub fn parse_payloads(configs: Vec<TaskConfig>) -> Vec<TaskConfig> {
+    configs
+        .into_iter()
+        .filter_map(|config| {
+            let name = config.name.clone();
+            config.parse_payload().inspect_err(|error| error!("{name}: {error}")).ok()
+        })
+        .collect()
+}
+
+/// Directory containing alfad.d, alfad.yaml, alfad.bin and the defaults
+pub fn root() -> &'static Path {
+    Instance::current().config_dir().unwrap_or(Path::new(if cfg!(debug_assertions) { "test" } else { "/etc/alfad" }))
 }
 
 pub fn read_config(builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
-    let configs = if cfg!(debug_assertions) { "test" } else { "/etc/alfad" };
-    let configs = Path::new(configs);
+    read_config_with(root(), &Defaults::load().config_order, builtin)
+}
+
+/// Load the configuration in `root` in the order of its defaults, without
+/// the kernel command line
+pub fn read_config_in(root: &Path, builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
+    read_config_with(root, &Defaults::load_in(root, "").config_order, builtin)
+}
+
+/// Load the configuration from the first of `alfad.yaml`, `alfad.bin` and
+/// the task files in `alfad.d` in `root` that can be loaded, in `order`.
+/// Task files changed after `alfad.bin` was written are merged into it,
+/// see [`merge::merge`].
+pub fn read_config_with(root: &Path, order: &[Origin], builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
+    let (configs, source) = load(root, Origin::order(order), builtin);
+    version::set_loaded(source);
+    configs
+}
+
+/// What the task files say now, alfad.yaml or alfad.d in the configured
+/// order. The cache is left out, it is what was loaded.
+pub fn read_task_files(builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
+    let order = Origin::order(&Defaults::load().config_order).into_iter().filter(|origin| *origin != Origin::Cache).collect();
+    load(root(), order, builtin).0
+}
+
+fn load(root: &Path, order: Vec<Origin>, builtin: Vec<TaskConfigYaml>) -> (Vec<TaskConfig>, ConfigSource) {
+    for origin in order {
+        match origin {
+            Origin::File => {
+                let path = root.join(FILE_CFG_YAML);
+                if !path.exists() {
+                    continue;
+                }
+                match SingleFile::read(&path) {
+                    Ok(file) => return (from_single_file(&path, file, builtin), ConfigSource::File { path }),
+                    Err(error) => {
+                        let message = format!("Ignoring {path:?}: {error}");
+                        error!("{message}");
+                        kmsg(&message);
+                    }
+                }
+            }
+            Origin::Cache => {
+                let path = root.join(FILE_CFG_BT);
+                let Some((mut configs, checksum)) = read_binary(&path) else {
+                    continue;
+                };
+                join_sources(&mut configs, root);
+                let configs = merge::merge(configs, newer_task_files(root, &path), builtin);
+                return (configs, ConfigSource::Cache { path, format: CACHE_FORMAT, checksum });
+            }
+            Origin::Dir => break,
+        }
+    }
+    let dir = root.join("alfad.d");
+    (read_yaml_configs(&dir, builtin), ConfigSource::Yaml { dir })
+}
+
+/// The tasks in `path`, a single file like alfad.yaml or a directory of
+/// task files like alfad.d
+pub fn read_tasks(path: &Path, builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
+    if !path.is_file() {
+        return read_yaml_configs(path, builtin);
+    }
+    match SingleFile::read(path) {
+        Ok(file) => from_single_file(path, file, builtin),
+        Err(error) => {
+            error!("Could not read {path:?}: {error}");
+            Vec::new()
+        }
+    }
+}
+
+/// Parse the task files in `path`, alfad.d or alfad.yaml, into the cache
+/// format. Builtins are only needed to resolve the configuration and are
+/// left out of the result, unless a task file took one over or ordered
+/// itself before one.
+pub fn compile(path: &Path, builtin: Vec<TaskConfigYaml>) -> postcard::Result<Vec<u8>> {
+    let defaults: HashMap<_, _> = builtin
+        .iter()
+        .filter_map(|config| Some((config.cmd.builtin_key()?.to_owned(), config.after.to_vec())))
+        .collect();
+    let mut configs = parse_payloads(read_tasks(path, builtin));
+    configs.retain(|config| !is_default_builtin(config, &defaults));
+    strip_sources(&mut configs, path.parent().unwrap_or(path));
+    encode(CACHE_FORMAT, &configs)
+}
+
+#[derive(Debug, Error)]
+pub enum WriteCacheError {
+    #[error("Could not encode: {}", .0)]
+    Encode(#[from] postcard::Error),
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+    #[error("Could not write {:?}: {}", .0, .1)]
+    Write(PathBuf, io::Error),
+}
+
+/// [`compile`] the task files in `input` and replace the cache at `target`
+/// atomically, so neither a power loss nor a boot reading it meanwhile ever
+/// sees half a cache
+pub fn write_cache(input: &Path, target: &Path, builtin: Vec<TaskConfigYaml>) -> Result<CacheStats, WriteCacheError> {
+    let packed = compile(input, builtin)?;
+    let stats = CacheStats::new(&packed)?;
+    let tmp = target.with_extension("tmp");
+    let write = || -> io::Result<()> {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&packed)?;
+        file.sync_all()?;
+        fs::rename(&tmp, target)
+    };
+    write().map_err(|error| {
+        let _ = fs::remove_file(&tmp);
+        WriteCacheError::Write(target.to_owned(), error)
+    })?;
+    Ok(stats)
+}
+
+/// Whether `config` is a builtin as registered, with the dependencies in
+/// `defaults` by registry key. Those are added again when the cache is loaded.
+fn is_default_builtin(config: &TaskConfig, defaults: &HashMap<String, Vec<String>>) -> bool {
+    let Some(after) = config.payload.builtin_key().and_then(|key| defaults.get(key)) else {
+        return false;
+    };
+    config.source.as_deref() == Some(Path::new(SRC_BUILTIN))
+        && config.after.len() == after.len()
+        && config.after.iter().all(|name| after.contains(name))
+}
+
+/// Layout of the cache, raised whenever the encoding of [`TaskConfig`]
+/// changes. Caches of any other format are ignored.
+pub const CACHE_FORMAT: u32 = 2;
+/// Start of every cache file
+const CACHE_MAGIC: &[u8; 8] = b"ALFADBIN";
+/// Magic, format, length and checksum of the rest of the file
+const CACHE_HEADER: usize = CACHE_MAGIC.len() + 12;
+// Far beyond any real configuration, anything larger is corrupted
+const MAX_CACHE_SIZE: usize = 16 * 1024 * 1024;
+const MAX_TASKS: usize = 10_000;
+const MAX_NAME_LENGTH: usize = 4096;
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("Not a compiled configuration")]
+    Magic,
+    #[error("Size of {} bytes is implausible", .0)]
+    TooLarge(usize),
+    #[error("Expected {expected} bytes, found {actual}")]
+    Length { expected: usize, actual: usize },
+    #[error("Checksum mismatch")]
+    Checksum,
+    #[error("Cache format {}, but this build reads {}", .0, CACHE_FORMAT)]
+    Format(u32),
+    #[error("Could not decode: {}", .0)]
+    Decode(#[from] postcard::Error),
+    #[error("{} tasks are implausible", .0)]
+    TooManyTasks(usize),
+    #[error("Implausibly long name in {}", .0)]
+    TooLong(String),
+}
+
+/// Serialize `configs` into the cache format `format`, with a header that
+/// lets [`decode`] reject other formats, truncated and corrupted files
+/// before decoding them
+pub fn encode(format: u32, configs: &[TaskConfig]) -> postcard::Result<Vec<u8>> {
+    let body = postcard::to_allocvec(configs)?;
+    let mut packed = Vec::with_capacity(CACHE_HEADER + body.len());
+    packed.extend_from_slice(CACHE_MAGIC);
+    packed.extend_from_slice(&format.to_le_bytes());
+    packed.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    packed.extend_from_slice(&checksum(&body).to_le_bytes());
+    packed.extend_from_slice(&body);
+    Ok(packed)
+}
 
-    match read_binary(configs.join("alfad.bin").as_path()) {
-        Some(mut configs) => {
-            configs.extend(builtin.into_iter().map(TaskConfigYaml::into_config).filter_map(drop_errors));
-            configs
+/// Read a cache written by [`encode`] in [`CACHE_FORMAT`]
+pub fn decode(packed: &[u8]) -> Result<Vec<TaskConfig>, CacheError> {
+    if packed.len() > MAX_CACHE_SIZE {
+        return Err(CacheError::TooLarge(packed.len()));
+    }
+    let (header, body) = packed.split_at_checked(CACHE_HEADER).ok_or(CacheError::Magic)?;
+    let (magic, header) = header.split_at(CACHE_MAGIC.len());
+    if magic != CACHE_MAGIC {
+        return Err(CacheError::Magic);
+    }
+    let field = |index: usize| u32::from_le_bytes(header[4 * index..4 * index + 4].try_into().unwrap());
+    if field(0) != CACHE_FORMAT {
+        return Err(CacheError::Format(field(0)));
+    }
+    let expected = field(1) as usize;
+    if expected != body.len() {
+        return Err(CacheError::Length { expected, actual: body.len() });
+    }
+    if field(2) != checksum(body) {
+        return Err(CacheError::Checksum);
+    }
+
+    // Malformed bodies are errors, not panics: postcard checks every length
+    // against the input, and the cache types derive Deserialize or only
+    // keep or look up a string
+    let configs = postcard::from_bytes::<Vec<TaskConfig>>(body)?;
+
+    if configs.len() > MAX_TASKS {
+        return Err(CacheError::TooManyTasks(configs.len()));
+    }
+    for config in configs.iter() {
+        let names = [&config.name].into_iter().chain(&config.with).chain(&config.after).chain(&config.group);
+        if names.map(String::len).any(|len| len == 0 || len > MAX_NAME_LENGTH) {
+            return Err(CacheError::TooLong(config.name.chars().take(64).collect()));
         }
-        None => read_yaml_configs(configs.join("alfad.d").as_path(), builtin),
     }
+    Ok(configs)
+}
+
+/// Summary of a compiled configuration, to notice when it grows
+#[derive(Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub tasks: usize,
+    /// Size of the cache in bytes
+    pub size: usize,
+    /// Name and encoded size of the largest task
+    pub largest: Option<(String, usize)>,
+}
+
+impl CacheStats {
+    pub fn new(packed: &[u8]) -> Result<Self, CacheError> {
+        let configs = decode(packed)?;
+        let sizes = configs.iter().map(|config| (postcard::to_allocvec(config).map_or(0, |bytes| bytes.len()), &config.name));
+        let largest = sizes.max().map(|(size, name)| (name.clone(), size));
+        Ok(Self { tasks: configs.len(), size: packed.len(), largest })
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} tasks, {} bytes", self.tasks, self.size)?;
+        if let Some((name, size)) = &self.largest {
+            write!(f, ", largest task {name} with {size} bytes")?;
+        }
+        Ok(())
+    }
+}
+
+/// FNV-1a, enough to notice flipped bits
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| (hash ^ u32::from(*byte)).wrapping_mul(0x01000193))
+}
+
+/// Make task file paths relative to `dir`, so the cache stays valid when
+/// the configuration is built somewhere else than where it is used.
+pub fn strip_sources(configs: &mut [TaskConfig], dir: &Path) {
+    for config in configs {
+        if let Some(relative) = config.source.as_deref().and_then(|source| source.strip_prefix(dir).ok()) {
+            config.source = Some(relative.to_owned());
+        }
+    }
+}
+
+/// Undo [`strip_sources`] after loading the cache
+pub fn join_sources(configs: &mut [TaskConfig], dir: &Path) {
+    for config in configs {
+        if let Some(source) = config.source.as_mut().filter(|source| !is_synthetic(source)) {
+            *source = dir.join(&source);
+        }
+    }
+}
+
+/// Whether `source` is a placeholder rather than an actual file
+pub fn is_synthetic(source: &Path) -> bool {
+    source == Path::new(SRC_BUILTIN) || source == Path::new(SRC_GENERATED)
 }
 
+/// The configuration in the cache at `path` and the checksum in its header
 #[instrument]
-pub fn read_binary(path: &Path) -> Option<Vec<TaskConfig>> {
+pub fn read_binary(path: &Path) -> Option<(Vec<TaskConfig>, u32)> {
     let packed = fs::read(path).map_err(|error| error!("Can't find alfad.bin {error}")).ok()?;
-    let (version, res) = postcard::from_bytes::<(String, Vec<_>)>(&packed).map_err(|error| error!(?error)).ok()?;
-    if version == crate::VERSION {
-        Some(res)
-    } else {
-        error!("Wrong version {} != {}", version, crate::VERSION);
-        None
+    match decode(&packed) {
+        Ok(configs) => Some((configs, u32::from_le_bytes(packed[CACHE_HEADER - 4..CACHE_HEADER].try_into().unwrap()))),
+        Err(error) => {
+            let message = format!("Ignoring {path:?}: {error}, reading the task files instead");
+            error!("{message}");
+            kmsg(&message);
+            None
+        }
+    }
+}
+
+/// Also log to the kernel, the boot might not get far enough for anyone to
+/// read the log of alfad
+fn kmsg(message: &str) {
+    if cfg!(debug_assertions) {
+        return;
+    }
+    if let Ok(mut kmsg) = OpenOptions::new().write(true).open(FILE_KMSG) {
+        let _ = writeln!(kmsg, "<3>{APLT_MAIN}: {message}");
     }
 }
 
+/// Parse the task files in the directory `path`
 pub fn read_yaml_configs(path: &Path, builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
     let span = info_span!("Parsing task files");
     let _span = span.enter();
-    let dir_reader = match read_dir(path) {
-        Ok(rd) => rd,
+    let defaults = Defaults::load_in(path.parent().unwrap_or(path), "");
+    let configs = match parse_task_files(path, &defaults.limits, None) {
+        Ok(configs) => configs,
         Err(error) => {
             error!("Could not read config directory {path:?}: {}", error);
             return Vec::new();
         }
     };
-    let mut configs: Vec<_> = smol::block_on(async {
-        smol::stream::iter(dir_reader)
-            .filter_map(drop_errors)
-            .map(|file| OpenOptions::new().read(true).open(file.path()))
+    let configs = resolve(configs, builtin, &defaults);
+
+    drop(_span);
+    configs
+}
+
+/// The task files in `alfad.d` in `root` modified after the cache at
+/// `cache` was written, checked and with markers for their groups, see
+/// [`merge::merge`]
+fn newer_task_files(root: &Path, cache: &Path) -> Vec<TaskConfig> {
+    let Ok(stamp) = fs::metadata(cache).and_then(|metadata| metadata.modified()) else {
+        return Vec::new();
+    };
+    let defaults = Defaults::load_in(root, "");
+    let dir = root.join("alfad.d");
+    let mut configs = match parse_task_files(&dir, &defaults.limits, Some(stamp)) {
+        Ok(configs) => check(configs, &defaults.limits),
+        Err(error) => {
+            debug!("Not looking for task files newer than the cache in {dir:?}: {error}");
+            return Vec::new();
+        }
+    };
+    construct_markers(&mut configs, &defaults.groups).log();
+    inherit_from_groups(&mut configs);
+    configs.into_iter().map(TaskConfigYaml::into_config).filter_map(drop_errors).collect()
+}
+
+/// Read the task files in the directory `path`, only those modified after
+/// `since` if given
+fn parse_task_files(path: &Path, limits: &Limits, since: Option<SystemTime>) -> io::Result<Vec<TaskConfigYaml>> {
+    let mut entries: Vec<_> = read_dir(path)?
+        .filter_map(drop_errors)
+        .filter(|entry| match since {
+            Some(since) => entry.metadata().and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified > since),
+            None => true,
+        })
+        .map(|entry| entry.path())
+        .collect();
+    if let Err(error) = limits.check_count(entries.len()) {
+        error!("{path:?}: {error}, ignoring the rest");
+        entries.sort();
+        entries.truncate(limits.max_tasks);
+    }
+    let configs: Vec<_> = smol::block_on(async {
+        smol::stream::iter(entries)
+            .map(|path| OpenOptions::new().read(true).open(&path).map(|file| (path, file)))
             .filter_map(drop_errors)
-            .map(serde_yaml::from_reader)
+            .filter(|(path, file)| {
+                let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+                limits.check_file_size(size).map_err(|error| error!("Ignoring {path:?}: {error}")).is_ok()
+            })
+            .map(|(path, file)| {
+                serde_yaml::from_reader(file).map(|config| TaskConfigYaml { source: Some(path), ..config })
+            })
             .filter_map(drop_errors)
-            .inspect(|config: &TaskConfigYaml| debug!("{config:?}"))
             .collect()
             .await
     });
+    Ok(configs)
+}
 
-    configs.extend(builtin);
-    let groups = construct_markers(&configs);
-    configs.extend(groups);
+/// Turn the tasks of alfad.yaml at `path` into the configuration
+fn from_single_file(path: &Path, file: SingleFile, builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfig> {
+    let span = info_span!("Parsing the task file");
+    let _span = span.enter();
+    let defaults = file.defaults.unwrap_or_else(|| Defaults::load_from(&path.parent().unwrap_or(path).join(FILE_DEFAULTS), ""));
+    let mut tasks = file.tasks;
+    if let Err(error) = defaults.limits.check_count(tasks.len()) {
+        error!("{path:?}: {error}, ignoring the rest");
+        tasks.truncate(defaults.limits.max_tasks);
+    }
+    let configs = resolve(tasks, builtin, &defaults);
 
-    #[cfg(feature = "before")]
-    let configs = resolve_before(configs);
+    drop(_span);
+    configs
+}
 
-    let configs = configs.into_iter().map(TaskConfigYaml::into_config).filter_map(drop_errors).collect();
+/// Check the tasks read from the task files, add the builtins and markers
+/// and resolve the configuration
+fn resolve(configs: Vec<TaskConfigYaml>, builtin: Vec<TaskConfigYaml>, defaults: &Defaults) -> Vec<TaskConfig> {
+    let mut configs = check(configs, &defaults.limits);
+    let overridden: Vec<_> = configs.iter().filter_map(|config| config.cmd.builtin_key()).collect();
+    let builtin = builtin::without(builtin, &overridden);
+    configs.extend(builtin);
+    construct_markers(&mut configs, &defaults.groups).log();
+    inherit_from_groups(&mut configs);
 
-    #[cfg(feature = "validate")]
-    let configs = validate::validate(configs);
+    let configs = maybe_resolve_before(configs);
+    let configs = configs.into_iter().map(TaskConfigYaml::into_config).filter_map(drop_errors).collect();
+    let configs = validate::maybe_validate(configs);
+    sort(configs)
+}
 
-    let configs = sort(configs);
+/// Drop the tasks with invalid or reserved names and those over `limits`,
+/// and warn about names defined more than once
+fn check(mut configs: Vec<TaskConfigYaml>, limits: &Limits) -> Vec<TaskConfigYaml> {
+    configs.retain(|config| {
+        // Before anything logs the name
+        if let Err(error) = name::check(&config.name) {
+            error!("Ignoring {:?}: {error}", config.source);
+            return false;
+        }
+        if let Err(error) = limits.check_task(config) {
+            error!("Ignoring {:?}: {error}", config.source);
+            return false;
+        }
+        if let Some(prefix) = reserved_prefix(config) {
+            error!("Ignoring {:?}: {}", config.source, reserved_message(&config.name, prefix));
+            return false;
+        }
+        debug!("{config:?}");
+        true
+    });
 
-    drop(_span);
+    let mut sources = HashMap::new();
+    for config in configs.iter() {
+        if let Some(other) = sources.insert(&config.name, &config.source) {
+            warn!("{} is defined in {:?} and {:?}, only one of them is used", config.name, other, config.source);
+        }
+    }
     configs
 }
 
+pub(crate) fn reserved_message(name: &str, prefix: &str) -> String {
+    format!("{name} starts with '{prefix}', which is reserved for markers and builtins")
+}
+
 fn drop_errors<T, E: Error>(r: Result<T, E>) -> Option<T> {
     match r {
         Ok(x) => Some(x),
@@ -132,3 +807,422 @@ fn drop_errors<T, E: Error>(r: Result<T, E>) -> Option<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        compile, decode, encode, parse_payloads, payload::Payload, read_config_in, read_config_with, read_tasks,
+        read_yaml_configs, single::Origin, yaml::TaskConfigYaml, CacheError, CacheStats, Quorum, TaskConfig, CACHE_FORMAT,
+        CACHE_HEADER,
+    };
+    use crate::{
+        builtin,
+        def::{SRC_BUILTIN, SRC_GENERATED},
+    };
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    fn source<'a>(configs: &'a [TaskConfig], name: &str) -> Option<&'a Path> {
+        configs.iter().find(|config| config.name == name).unwrap().source.as_deref()
+    }
+
+    fn fixture() -> (tempfile::TempDir, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("alfad.d");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("mount.task"), "name: mount\ncmd: mount -a\ngroup: early\n").unwrap();
+        fs::write(dir.join("other-name.task"), "name: getty\ncmd: getty tty1\nafter: mount\n").unwrap();
+        (root, dir)
+    }
+
+    #[test]
+    fn sources_from_yaml() {
+        let (_root, dir) = fixture();
+        let configs = read_yaml_configs(&dir, builtin::all());
+        assert_eq!(source(&configs, "mount"), Some(dir.join("mount.task").as_path()));
+        assert_eq!(source(&configs, "getty"), Some(dir.join("other-name.task").as_path()));
+        assert_eq!(source(&configs, "group::early"), Some(Path::new(SRC_GENERATED)));
+        assert_eq!(source(&configs, "builtin::ctl::create"), Some(Path::new(SRC_BUILTIN)));
+    }
+
+    #[test]
+    fn sources_from_cache() {
+        let (root, dir) = fixture();
+        let packed = compile(&dir, builtin::all()).unwrap();
+        let cached = decode(&packed).unwrap();
+        assert_eq!(source(&cached, "getty"), Some(Path::new("alfad.d/other-name.task")));
+        assert!(cached.iter().all(|config| config.source.as_deref() != Some(Path::new(SRC_BUILTIN))));
+
+        // Loading from a different location resolves against that location
+        let moved = tempfile::tempdir().unwrap();
+        fs::write(moved.path().join("alfad.bin"), packed).unwrap();
+        drop(root);
+        let configs = read_config_in(moved.path(), builtin::all());
+        let moved = moved.path().join("alfad.d");
+        assert_eq!(source(&configs, "mount"), Some(moved.join("mount.task").as_path()));
+        assert_eq!(source(&configs, "getty"), Some(moved.join("other-name.task").as_path()));
+        assert_eq!(source(&configs, "group::early"), Some(Path::new(SRC_GENERATED)));
+        assert_eq!(source(&configs, "builtin::ctl::daemon"), Some(Path::new(SRC_BUILTIN)));
+    }
+
+    #[test]
+    fn optional_fields_in_cache() {
+        let (_root, dir) = fixture();
+        fs::write(dir.join("sshd.task"), "name: sshd\ncmd: sshd -D\nadopt: {match: sshd}\n").unwrap();
+        let cached = decode(&compile(&dir, builtin::all()).unwrap()).unwrap();
+        let sshd = cached.iter().find(|config| config.name == "sshd").unwrap();
+        assert_eq!(sshd.adopt.as_ref().map(|adopt| (adopt.pattern.as_str(), adopt.pidfile.as_deref())), Some(("sshd", None)));
+    }
+
+    /// Any change to how a task is encoded has to raise [`CACHE_FORMAT`],
+    /// then update the format and checksum here
+    #[test]
+    fn cache_layout() {
+        let yaml = "name: web\ncmd:\n  - nginx -t\n  - {run: nginx, timeout: 5s, ignore_return: true}\nwith: db\n\
+                    after: [mount, db:running]\nafter_stopped: fsck\nstop_dependency: true\nrestart_dependency: true\n\
+                    before: getty\nrespawn: 3\nrespawn_recheck: all\nrun_timeout: 1m\ngroup: net\ndescription: Web\n\
+                    doc_url: https://nginx.org\nenv_keep: [PATH]\nquorum: any\nadopt: {match: nginx, pidfile: /run/nginx.pid}\n\
+                    log_cmd: logger -t web\nlog_rate_limit: {lines: 100, per: 1s}\nlog_timestamps: true\n\
+                    requires_kernel: {min_version: \"5.10\", config: [CGROUPS]}\n\
+                    requires_resources: {min_free_mem_mb: 64, min_free_disk_mb: {path: /var, mb: 10}, resource_wait: 5s}\n\
+                    requires_privileges: [kill]\nlocale: {tz: UTC, lang: C.UTF-8}\nenv_file: /etc/web.env\n\
+                    env: {PORT: \"80\"}\nenv_export: true\nmask_args: [2]\nselinux_context: system_u:system_r:httpd_t:s0\n\
+                    apparmor_profile: nginx\nsecurity_required: true\ncollect_failure_data: true\nprotected: true\n";
+        let config = serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config().unwrap().parse_payload().unwrap();
+        let body = postcard::to_allocvec(&[&config]).unwrap();
+        assert_eq!((CACHE_FORMAT, super::checksum(&body)), (2, 1746804964), "{} bytes", body.len());
+        assert_eq!(decode(&encode(CACHE_FORMAT, std::slice::from_ref(&config)).unwrap()).unwrap(), [config]);
+    }
+
+    /// Header for `body` with a matching length and checksum
+    fn repack(body: &[u8]) -> Vec<u8> {
+        let mut packed = encode(CACHE_FORMAT, &[]).unwrap();
+        packed.truncate(12);
+        packed.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        packed.extend_from_slice(&super::checksum(body).to_le_bytes());
+        packed.extend_from_slice(body);
+        packed
+    }
+
+    #[test]
+    fn corrupted_cache() {
+        let (_root, dir) = fixture();
+        let packed = compile(&dir, builtin::all()).unwrap();
+        // The fixture's tasks, feature::ctl and feature::time::synchronized of
+        // the builtins
+        assert_eq!(decode(&packed).unwrap().len(), 5);
+
+        let truncated = &packed[..packed.len() - 1];
+        assert!(matches!(decode(truncated), Err(CacheError::Length { .. })));
+        assert!(matches!(decode(&packed[..4]), Err(CacheError::Magic)));
+        assert!(matches!(decode(&[]), Err(CacheError::Magic)));
+
+        for bit in [0, 7, 8 * CACHE_HEADER + 3, 8 * packed.len() - 1] {
+            let mut flipped = packed.clone();
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            decode(&flipped).unwrap_err();
+        }
+
+        // A header that claims 4 GiB
+        let mut absurd = packed.clone();
+        absurd[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decode(&absurd), Err(CacheError::Length { .. })));
+
+        // Valid header, but the task list claims billions of entries
+        let body = [0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert!(matches!(decode(&repack(&body)), Err(CacheError::Decode(_))));
+
+        // Valid header, but a name that claims to be longer than the file
+        let body = [1, 0xff, 0xff, 0xff, 0xff, 0x0f, b'a'];
+        assert!(matches!(decode(&repack(&body)), Err(CacheError::Decode(_))));
+
+        // Whatever a byte of the body is, decoding fails or succeeds
+        let body = &packed[CACHE_HEADER..];
+        for index in 0..body.len() {
+            for value in [0, 1, 0x7f, 0x80, 0xff] {
+                let mut corrupted = body.to_vec();
+                corrupted[index] = value;
+                let _ = decode(&repack(&corrupted));
+            }
+        }
+
+        let long = TaskConfig::new("x".repeat(5000));
+        assert!(matches!(decode(&encode(CACHE_FORMAT, &[long]).unwrap()), Err(CacheError::TooLong(_))));
+        assert!(matches!(decode(&encode(CACHE_FORMAT + 1, &[]).unwrap()), Err(CacheError::Format(_))));
+    }
+
+    #[test]
+    fn limits_skip_tasks() {
+        let (root, dir) = fixture();
+        fs::write(root.path().join("defaults.yaml"), "limits:\n  max_commands: 1\n").unwrap();
+        fs::write(dir.join("script.task"), "name: script\ncmd: |\n  true\n  true\n").unwrap();
+        let configs = read_yaml_configs(&dir, vec![]);
+        assert!(configs.iter().any(|config| config.name == "getty"));
+        assert!(!configs.iter().any(|config| config.name == "script"));
+    }
+
+    #[test]
+    fn stats() {
+        let (_root, dir) = fixture();
+        let packed = compile(&dir, Vec::new()).unwrap();
+        let stats = CacheStats::new(&packed).unwrap();
+        assert_eq!((stats.tasks, stats.size), (3, packed.len()));
+        assert_eq!(stats.largest.unwrap().0, "getty");
+    }
+
+    #[test]
+    fn corrupted_cache_falls_back_to_yaml() {
+        let (root, _dir) = fixture();
+        let mut packed = compile(&root.path().join("alfad.d"), builtin::all()).unwrap();
+        let last = packed.len() - 1;
+        packed[last] ^= 0xff;
+        fs::write(root.path().join("alfad.bin"), packed).unwrap();
+        let configs = read_config_in(root.path(), builtin::all());
+        assert!(configs.iter().any(|config| config.name == "getty"));
+        assert!(configs.iter().any(|config| config.name == "builtin::ctl::daemon"));
+    }
+
+    /// Names of the tasks that are not builtins or markers, sorted
+    fn names(configs: &[TaskConfig]) -> Vec<&str> {
+        let mut names: Vec<_> = configs.iter().map(|config| config.name.as_str()).filter(|name| !name.contains("::")).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn single_file() {
+        let (_root, dir) = fixture();
+        let path = dir.parent().unwrap().join("alfad.yaml");
+        let text = "tasks:\n  - name: mount\n    cmd: mount -a\n  - name: shell\n    cmd: sh\n    after: [mount]\n  \
+                    - name: builtin::nope\n    cmd: \"true\"\n";
+        fs::write(&path, text).unwrap();
+        let configs = read_tasks(&path, builtin::all());
+        assert_eq!(names(&configs), ["mount", "shell"]);
+        assert_eq!(source(&configs, "shell"), Some(path.as_path()));
+        assert_eq!(source(&configs, "builtin::ctl::daemon"), Some(Path::new(SRC_BUILTIN)));
+        assert_eq!(names(&read_tasks(&dir, builtin::all())), ["getty", "mount"]);
+
+        // Compiled, it is found next to the cache
+        let moved = tempfile::tempdir().unwrap();
+        fs::write(moved.path().join("alfad.bin"), compile(&path, builtin::all()).unwrap()).unwrap();
+        let configs = read_config_in(moved.path(), builtin::all());
+        assert_eq!(source(&configs, "shell"), Some(moved.path().join("alfad.yaml").as_path()));
+    }
+
+    #[test]
+    fn precedence() {
+        let (root, dir) = fixture();
+        let root = root.path();
+        fs::write(dir.join("cached.task"), "name: cached\ncmd: \"true\"\n").unwrap();
+        fs::write(root.join("alfad.bin"), compile(&dir, builtin::all()).unwrap()).unwrap();
+        fs::remove_file(dir.join("cached.task")).unwrap();
+        fs::write(root.join("alfad.yaml"), "tasks:\n  - name: single\n    cmd: \"true\"\n").unwrap();
+
+        // alfad.yaml, then the cache, then alfad.d
+        assert_eq!(names(&read_config_in(root, builtin::all())), ["single"]);
+        assert_eq!(names(&read_config_with(root, &[Origin::Cache], builtin::all())), ["cached", "getty", "mount"]);
+        assert_eq!(names(&read_config_with(root, &[Origin::Dir], builtin::all())), ["getty", "mount"]);
+
+        // The order comes from the defaults, in alfad.yaml if it has them
+        fs::write(root.join("defaults.yaml"), "config_order: [cache]\n").unwrap();
+        assert_eq!(names(&read_config_in(root, builtin::all())), ["cached", "getty", "mount"]);
+        fs::write(root.join("alfad.yaml"), "defaults:\n  config_order: [dir]\ntasks: []\n").unwrap();
+        assert_eq!(names(&read_config_in(root, builtin::all())), ["getty", "mount"]);
+
+        // What can't be read is skipped
+        fs::write(root.join("alfad.yaml"), "tasks: {name: broken}\n").unwrap();
+        assert_eq!(names(&read_config_with(root, &[], builtin::all())), ["cached", "getty", "mount"]);
+        fs::write(root.join("alfad.bin"), "ALFADBIN").unwrap();
+        assert_eq!(names(&read_config_with(root, &[], builtin::all())), ["getty", "mount"]);
+    }
+
+    #[test]
+    fn newer_than_cache() {
+        let (root, dir) = fixture();
+        let root = root.path();
+        fs::write(root.join("alfad.bin"), compile(&dir, builtin::all()).unwrap()).unwrap();
+        let stamp = fs::metadata(root.join("alfad.bin")).unwrap().modified().unwrap();
+        let touch = |name: &str, content: &str, modified: SystemTime| {
+            fs::write(dir.join(name), content).unwrap();
+            fs::File::options().write(true).open(dir.join(name)).unwrap().set_modified(modified).unwrap();
+        };
+        let later = stamp + Duration::from_secs(10);
+        touch("mount.task", "name: mount\ncmd: mount -a -t ext4\ngroup: early\n", later);
+        touch("udev.task", "name: udev\ncmd: udevd\ngroup: early\n", later);
+        // Stale, the cache wins
+        touch("other-name.task", "name: getty\ncmd: getty tty2\n", stamp - Duration::from_secs(10));
+
+        let configs = read_config_with(root, &[Origin::Cache], builtin::all());
+        assert_eq!(names(&configs), ["getty", "mount", "udev"]);
+        let find = |name: &str| configs.iter().find(|config| config.name == name).unwrap();
+        assert_eq!(find("mount").payload, "mount -a -t ext4".parse().unwrap());
+        assert_eq!(find("getty").payload, "getty tty1".parse().unwrap());
+        assert_eq!(find("getty").after, ["mount"]);
+        assert_eq!(find("group::early").after, ["mount", "udev"]);
+        assert_eq!(configs.iter().filter(|config| config.name == "builtin::ctl::create").count(), 1);
+    }
+
+    #[test]
+    fn builtin_from_yaml() {
+        let (root, dir) = fixture();
+        fs::write(dir.join("ctl.task"), "name: builtin::ctl::create\ncmd: {builtin: ctl::create}\nafter: mount\n").unwrap();
+        let assert_overridden = |configs: &[TaskConfig]| {
+            let create: Vec<_> = configs.iter().filter(|config| config.name == "builtin::ctl::create").collect();
+            assert_eq!(create.len(), 1);
+            assert_eq!(create[0].after, ["mount"]);
+            assert_eq!(create[0].payload.builtin_key(), Some("ctl::create"));
+            assert_eq!(create[0].source.as_deref(), Some(dir.join("ctl.task").as_path()));
+        };
+        assert_overridden(&read_yaml_configs(&dir, builtin::all()));
+
+        // The cache keeps the builtin by its key
+        fs::write(root.path().join("alfad.bin"), compile(&dir, builtin::all()).unwrap()).unwrap();
+        let configs = read_config_in(root.path(), builtin::all());
+        assert_overridden(&configs);
+        assert!(configs.iter().any(|config| config.name == "builtin::ctl::daemon"));
+    }
+
+    #[test]
+    fn unknown_builtin() {
+        let config: TaskConfigYaml = serde_yaml::from_str("name: x\ncmd: {builtin: ctl::nope}\n").unwrap();
+        assert!(config
+            .into_config()
+            .unwrap_err()
+            .to_string()
+            .starts_with("Unknown builtin 'ctl::nope', available are ctl::create, ctl::daemon, ctl::ready, boot::count, state::dir, boot::complete, sweep"));
+    }
+
+    #[test]
+    fn reserved_names() {
+        let (_root, dir) = fixture();
+        fs::write(dir.join("fake.task"), "name: group::early\ncmd: \"true\"\n").unwrap();
+        fs::write(dir.join("dns.task"), "name: feature::dns\ncmd: dnsmasq\n").unwrap();
+        fs::write(dir.join("ctl.task"), "name: builtin::ctl::daemon\ncmd: sleep 1000\n").unwrap();
+        let configs = read_yaml_configs(&dir, vec![]);
+        assert!(configs.iter().find(|config| config.name == "group::early").unwrap().payload.is_marker());
+        assert_eq!(source(&configs, "group::early"), Some(Path::new(SRC_GENERATED)));
+        assert!(!configs.iter().any(|config| config.name == "feature::dns" || config.name.starts_with("builtin::")));
+    }
+
+    #[test]
+    fn explicit_marker() {
+        let (_root, dir) = fixture();
+        fs::write(dir.join("early.task"), "name: group::early\ncmd: marker\nafter: [udev, mount]\nquorum: any\n").unwrap();
+        fs::write(dir.join("udev.task"), "name: udev\ncmd: udevd\ngroup: early\n").unwrap();
+        fs::write(dir.join("late.task"), "name: late\ncmd: \"true\"\n").unwrap();
+        let configs = read_yaml_configs(&dir, vec![]);
+        let markers: Vec<_> = configs.iter().filter(|config| config.name == "group::early").collect();
+        assert_eq!(markers.len(), 1);
+        let early = markers[0];
+        assert!(early.payload.is_marker());
+        assert_eq!(early.quorum, Quorum::Any);
+        assert_eq!(early.source.as_deref(), Some(dir.join("early.task").as_path()));
+        let (mut after, mut with) = (early.after.clone(), early.with.clone());
+        after.sort();
+        with.sort();
+        assert_eq!(after, ["mount", "udev"]);
+        assert_eq!(with, ["mount", "udev"]);
+    }
+
+    /// Dependencies are compared unordered, `before` is resolved in hash map
+    /// order and command lines are only parsed in the cache
+    fn normalized(configs: Vec<TaskConfig>) -> Vec<Vec<u8>> {
+        let mut configs = parse_payloads(configs);
+        configs.sort_by(|a, b| a.name.cmp(&b.name));
+        configs
+            .iter_mut()
+            .map(|config| {
+                config.after.sort();
+                config.with.sort();
+                postcard::to_allocvec(config).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let (root, dir) = fixture();
+        fs::write(dir.join("ctl.task"), "name: ctl\ncmd: {builtin: ctl::create}\nafter: mount\n").unwrap();
+        fs::write(dir.join("early.task"), "name: early\ncmd: \"true\"\nbefore: builtin::ctl::daemon\n").unwrap();
+        let direct = read_yaml_configs(&dir, builtin::all());
+        fs::write(root.path().join("alfad.bin"), compile(&dir, builtin::all()).unwrap()).unwrap();
+        let loaded = read_config_in(root.path(), builtin::all());
+        assert_eq!(normalized(loaded), normalized(direct));
+
+        // Only the builtins changed by a task file are cached
+        let cached = decode(&fs::read(root.path().join("alfad.bin")).unwrap()).unwrap();
+        let mut builtins: Vec<_> = cached.iter().filter_map(|config| config.payload.builtin_key()).collect();
+        builtins.sort();
+        let expected: &[&str] = if cfg!(feature = "before") { &["ctl::create", "ctl::daemon"] } else { &["ctl::create"] };
+        assert_eq!(builtins, expected);
+    }
+
+    /// Precedence is task > group > defaults.yaml
+    #[test]
+    fn group_settings() {
+        let (_root, dir) = fixture();
+        let web = "name: group::web\ncmd: marker\nenv_keep: [PATH, WEB_ROOT]\nlog_cmd: logger -t web\napparmor_profile: web\n";
+        fs::write(dir.join("group::web.task"), web).unwrap();
+        fs::write(dir.join("nginx.task"), "name: nginx\ncmd: nginx\ngroup: web\n").unwrap();
+        fs::write(dir.join("php.task"), "name: php\ncmd: php-fpm\ngroup: web\nenv_keep: [PATH]\napparmor_profile: php\n").unwrap();
+        let configs = read_yaml_configs(&dir, vec![]);
+        let task = |name: &str| configs.iter().find(|config| config.name == name).unwrap();
+        let log_cmd = |name: &str| task(name).log_cmd.as_ref().map(ToString::to_string);
+
+        assert_eq!(task("nginx").env_keep.as_deref(), Some(&["PATH".to_owned(), "WEB_ROOT".to_owned()][..]));
+        assert_eq!((log_cmd("nginx").as_deref(), task("nginx").apparmor_profile.as_deref()), (Some("logger -t web"), Some("web")));
+        assert_eq!(task("php").env_keep.as_deref(), Some(&["PATH".to_owned()][..]));
+        assert_eq!((log_cmd("php").as_deref(), task("php").apparmor_profile.as_deref()), (Some("logger -t web"), Some("php")));
+        // Without a group file, the defaults apply when the task runs
+        assert_eq!((task("mount").env_keep.as_ref(), log_cmd("mount")), (None, None));
+        // The marker keeps waiting for its members
+        assert_eq!(task("group::web").after.len(), 2);
+    }
+
+    #[test]
+    fn lazy_payloads() {
+        let (_root, dir) = fixture();
+        fs::write(dir.join("steps.task"), "name: steps\ncmd: |\n  :-env -i 'a b'\n  mount -a\n").unwrap();
+        fs::write(dir.join("broken.task"), "name: broken\ncmd: sh -c 'echo\n").unwrap();
+        let lazy = read_yaml_configs(&dir, vec![]);
+        let eager = parse_payloads(read_yaml_configs(&dir, vec![]));
+        let task = |configs: &[TaskConfig], name: &str| configs.iter().position(|config| config.name == name);
+
+        let (steps, parsed) = (&lazy[task(&lazy, "steps").unwrap()], &eager[task(&eager, "steps").unwrap()]);
+        assert!(matches!(steps.payload, Payload::Unparsed(_)));
+        assert!(matches!(parsed.payload, Payload::Service(_)));
+        assert_eq!(steps, parsed);
+        assert_eq!(steps.payload.command_count(), 2);
+        assert_eq!(steps.payload.commands().collect::<Vec<_>>(), parsed.payload.commands().collect::<Vec<_>>());
+        // Parsing does not depend on having looked at the lines before
+        let steps = lazy.into_iter().find(|config| config.name == "steps").unwrap();
+        assert_eq!(&steps.parse_payload().unwrap(), parsed);
+
+        // Invalid lines keep the task until it runs, but not in the cache
+        let lazy = read_yaml_configs(&dir, vec![]);
+        let broken = &lazy[task(&lazy, "broken").unwrap()];
+        assert!(broken.payload.lines().is_none());
+        assert!(broken.payload.has_step(0) && !broken.payload.has_step(1));
+        assert_eq!(task(&eager, "broken"), None);
+        assert_eq!(lazy.len(), eager.len() + 1);
+    }
+
+    /// Runs under every combination of the `before` and `validate` features
+    #[test]
+    fn feature_combination() {
+        let (root, dir) = fixture();
+        fs::write(dir.join("early.task"), "name: early\ncmd: \"true\"\nbefore: [mount, missing]\nafter: nowhere\n").unwrap();
+        let configs = read_yaml_configs(&dir, vec![]);
+        let task = |name: &str| configs.iter().find(|config| config.name == name).unwrap();
+        assert_eq!(task("early").before, ["mount", "missing"]);
+        assert_eq!(task("mount").after.contains(&"early".to_owned()), cfg!(feature = "before"));
+
+        // The cache does not depend on the features
+        fs::write(root.path().join("alfad.bin"), compile(&dir, vec![]).unwrap()).unwrap();
+        let loaded = read_config_in(root.path(), vec![]);
+        assert_eq!(normalized(loaded), normalized(configs));
+    }
+}
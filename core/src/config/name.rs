@@ -0,0 +1,189 @@
+//! Task names end up in log fields, file names below the state directory
+//! and the failure bundles, and in strings that live as long as alfad. They
+//! are restricted to a short, printable set of characters, and turned into
+//! file names with [`file_name`] so even a name that slipped through can't
+//! leave its directory.
+
+use super::WaitKind;
+use std::fmt::Write;
+use thiserror::Error;
+
+/// Longest task name in bytes
+pub const MAX_NAME_LENGTH: usize = 128;
+
+/// Longest file name [`file_name`] returns, below NAME_MAX with room for
+/// the suffixes of failure bundles and temporary state files
+pub const MAX_FILE_NAME: usize = 200;
+
+/// Characters shown of a rejected name, the rest is cut off
+const SHOWN: usize = 40;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NameError {
+    #[error("Task name is empty")]
+    Empty,
+    #[error("Task name {} has {length} bytes, only {max} are allowed", shown(.name))]
+    TooLong { name: String, length: usize, max: usize },
+    #[error("Task name {} contains {found:?}, only letters, digits and ':_@.-' are allowed", shown(.name))]
+    Character { name: String, found: char },
+    #[error("Task name {} starts with a dash", shown(.name))]
+    LeadingDash { name: String },
+    #[error("Task name {} starts with a dot, which would hide its files", shown(.name))]
+    LeadingDot { name: String },
+    #[error("Task name {} ends in :{kind}, which after and with read as the state to wait for", shown(.name))]
+    WaitSuffix { name: String, kind: WaitKind },
+}
+
+fn allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '@' | '.' | '-')
+}
+
+/// The start of `name`, quoted and with control characters escaped, for
+/// error messages
+fn shown(name: &str) -> String {
+    match name.char_indices().nth(SHOWN) {
+        Some((end, _)) => format!("{:?}...", &name[..end]),
+        None => format!("{name:?}"),
+    }
+}
+
+/// Whether `name` can be used for a task
+pub fn check(name: &str) -> Result<(), NameError> {
+    let owned = || name.to_owned();
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong { name: owned(), length: name.len(), max: MAX_NAME_LENGTH });
+    }
+    if let Some(found) = name.chars().find(|c| !allowed(*c)) {
+        return Err(NameError::Character { name: owned(), found });
+    }
+    if name.starts_with('-') {
+        return Err(NameError::LeadingDash { name: owned() });
+    }
+    if name.starts_with('.') {
+        return Err(NameError::LeadingDot { name: owned() });
+    }
+    // A dependency on it would be taken for one on the rest of the name
+    let (rest, kind) = WaitKind::split(name);
+    if rest != name {
+        return Err(NameError::WaitSuffix { name: owned(), kind });
+    }
+    Ok(())
+}
+
+/// A single path component for the task `name`, different for every name.
+/// Valid names stay as they are. Every other byte, and a leading dot or
+/// dash, is written as `%XX`, and names that end up too long are cut short
+/// and end with a hash of the whole name instead.
+pub fn file_name(name: &str) -> String {
+    if name.is_empty() {
+        return "%".to_owned();
+    }
+    let mut escaped = String::with_capacity(name.len());
+    for (index, c) in name.char_indices() {
+        if allowed(c) && !(index == 0 && matches!(c, '.' | '-')) {
+            escaped.push(c);
+        } else {
+            c.encode_utf8(&mut [0; 4]).bytes().for_each(|byte| _ = write!(escaped, "%{byte:02X}"));
+        }
+    }
+    if escaped.len() > MAX_FILE_NAME {
+        // Only ASCII is left, so any index is a character boundary
+        escaped.truncate(MAX_FILE_NAME - 17);
+        _ = write!(escaped, "~{:016x}", fnv1a(name.as_bytes()));
+    }
+    escaped
+}
+
+/// Stays the same across builds, unlike the hasher of the standard library
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, file_name, NameError, MAX_FILE_NAME, MAX_NAME_LENGTH};
+    use crate::config::WaitKind;
+    use std::collections::HashSet;
+
+    /// Names an attacker or a broken generator could come up with
+    fn hostile() -> Vec<String> {
+        let mut names: Vec<String> = [
+            "", ".", "..", "...", "/", "../../etc/passwd", "a/b", "-rf", "--help", ".hidden", "new\nline", "tab\t",
+            "nul\0byte", "esc\x1b[31m", "spa ce", "%41", "%", "ünïcödé", "emoji🦀", "back\\slash", "quote\"", "~", "a:b@c.d-e_f",
+        ]
+        .map(str::to_owned)
+        .into();
+        names.push("x".repeat(MAX_NAME_LENGTH));
+        names.push("x".repeat(MAX_NAME_LENGTH + 1));
+        names.push("/".repeat(4096));
+        names.push("🦀".repeat(1024));
+        // Every byte on its own and around a valid name
+        for byte in 1..=127u8 {
+            let c = char::from(byte);
+            names.extend([c.to_string(), format!("task{c}"), format!("{c}task")]);
+        }
+        names
+    }
+
+    #[test]
+    fn valid_names() {
+        for name in ["getty@tty1", "builtin::ctl::daemon", "group::web", "target::boot-complete", "a.b_c-d", "x"] {
+            check(name).unwrap();
+            assert_eq!(file_name(name), name);
+        }
+        check(&"x".repeat(MAX_NAME_LENGTH)).unwrap();
+    }
+
+    #[test]
+    fn rejected_names() {
+        assert_eq!(check(""), Err(NameError::Empty));
+        assert!(matches!(check("a/b"), Err(NameError::Character { found: '/', .. })));
+        assert!(matches!(check("new\nline"), Err(NameError::Character { found: '\n', .. })));
+        assert!(matches!(check("-rf"), Err(NameError::LeadingDash { .. })));
+        assert!(matches!(check(".."), Err(NameError::LeadingDot { .. })));
+        assert!(matches!(check(".hidden"), Err(NameError::LeadingDot { .. })));
+        assert!(matches!(check("db:running"), Err(NameError::WaitSuffix { kind: WaitKind::Running, .. })));
+        assert!(matches!(check("x:done"), Err(NameError::WaitSuffix { kind: WaitKind::Done, .. })));
+        for name in ["x::done", "db:run", "done", "a:b"] {
+            check(name).unwrap();
+        }
+        let long = check(&"x\n".repeat(2048)).unwrap_err();
+        assert!(matches!(long, NameError::TooLong { length: 4096, .. }));
+        // Messages stay on one line and short, whatever the name
+        let message = long.to_string();
+        assert!(!message.contains('\n') && message.len() < 150, "{message}");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(file_name("a/b"), "a%2Fb");
+        assert_eq!(file_name(".hidden"), "%2Ehidden");
+        assert_eq!(file_name("-rf"), "%2Drf");
+        assert_eq!(file_name(".."), "%2E.");
+        assert_eq!(file_name("%41"), "%2541");
+        assert_eq!(file_name("new\nline"), "new%0Aline");
+        assert_eq!(file_name("ü"), "%C3%BC");
+        assert_eq!(file_name(""), "%");
+    }
+
+    /// Every name is either rejected or used as it is, and every name gives
+    /// a distinct file name that stays in its directory
+    #[test]
+    fn hostile_names() {
+        let mut seen = HashSet::new();
+        for name in hostile().into_iter().collect::<HashSet<_>>() {
+            let file = file_name(&name);
+            if check(&name).is_ok() {
+                assert!(name.len() <= MAX_NAME_LENGTH && !name.contains(['/', '\n', '\0']), "{name:?}");
+                assert_eq!(file, name);
+            }
+            assert!(!file.is_empty() && file.len() <= MAX_FILE_NAME, "{name:?} -> {file:?}");
+            assert!(!file.starts_with(['.', '-']), "{name:?} -> {file:?}");
+            assert!(file.chars().all(|c| c.is_ascii_graphic() && c != '/' && c != '\\'), "{name:?} -> {file:?}");
+            assert!(seen.insert(file.clone()), "{name:?} -> {file:?} is not unique");
+        }
+    }
+}
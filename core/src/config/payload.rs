@@ -1,10 +1,12 @@
 use crate::{
     builtin::BuiltInService,
-    command_line::CommandLines,
+    command_line::{CommandLineError, CommandLines},
     task::{ContextMap, ExitReason, TaskContext, TaskState},
 };
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{fmt::Debug, ops::ControlFlow, str::FromStr};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt::Debug, ops::ControlFlow, str::FromStr, sync::OnceLock};
+use strum::Display;
+use tracing::error;
 
 #[async_trait::async_trait]
 pub trait Runnable {
@@ -15,12 +17,98 @@ pub trait Runnable {
     ) -> ControlFlow<TaskState>;
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Payload<T = CommandLines> {
     Marker,
     Service(T),
-    #[serde(skip)]
     Builtin(BuiltInService),
+    /// Command lines of a task file that are parsed when the task runs
+    /// first. Last, so caches written before keep their layout.
+    Unparsed(LazyLines),
+}
+
+/// Command lines as written in a task file. Parsing them is most of the
+/// work of reading a task file, and many tasks don't run on every boot.
+#[derive(Clone)]
+pub struct LazyLines {
+    raw: String,
+    parsed: OnceLock<Result<CommandLines, String>>,
+}
+
+impl LazyLines {
+    pub fn new(raw: String) -> Self {
+        Self { raw, parsed: OnceLock::new() }
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    /// Parsed on the first call, later calls get the same result
+    ///
+    /// ```
+    /// use alfad::config::payload::LazyLines;
+    ///
+    /// let lines = LazyLines::new("mount -a\n-swapon -a".to_owned());
+    /// assert_eq!(lines.get().unwrap().len(), 2);
+    /// assert!(LazyLines::new("sh -c 'echo".to_owned()).get().is_err());
+    /// ```
+    pub fn get(&self) -> Result<&CommandLines, &str> {
+        let parsed = self.parsed.get_or_init(|| self.raw.parse().map_err(|error: CommandLineError| error.to_string()));
+        parsed.as_ref().map_err(String::as_str)
+    }
+
+    /// The parsed command lines, from the first call to [`LazyLines::get`]
+    /// if there was one
+    pub fn parse(self) -> Result<CommandLines, CommandLineError> {
+        match self.parsed.into_inner() {
+            Some(Ok(lines)) => Ok(lines),
+            _ => self.raw.parse(),
+        }
+    }
+}
+
+impl Serialize for LazyLines {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.raw.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LazyLines {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// What a task runs, without what it contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PayloadKind {
+    Service,
+    Marker,
+    Builtin,
+}
+
+impl<T> Payload<T> {
+    /// ```
+    /// use alfad::config::payload::{Payload, PayloadKind};
+    ///
+    /// let payload: Payload = "mount -a".parse().unwrap();
+    /// assert_eq!(payload.kind(), PayloadKind::Service);
+    /// assert_eq!(Payload::<()>::Marker.kind().to_string(), "marker");
+    /// ```
+    pub fn kind(&self) -> PayloadKind {
+        match self {
+            Self::Marker => PayloadKind::Marker,
+            Self::Service(_) | Self::Unparsed(_) => PayloadKind::Service,
+            Self::Builtin(_) => PayloadKind::Builtin,
+        }
+    }
+
+    pub fn is_marker(&self) -> bool {
+        matches!(self, Self::Marker)
+    }
 }
 
 impl Payload {
@@ -30,18 +118,107 @@ impl Payload {
         context: &TaskContext,
         context_map: ContextMap<'static>,
     ) -> ControlFlow<TaskState> {
-        match self {
-            Payload::Service(command_lines) => match command_lines.get(x) {
-                Some(command_line) => command_line.run(context, context_map).await,
-                None => ControlFlow::Break(TaskState::Concluded(ExitReason::Done)),
+        let command_lines = match self {
+            Payload::Service(command_lines) => command_lines,
+            Payload::Unparsed(lines) => match lines.get() {
+                Ok(command_lines) => command_lines,
+                Err(error) => {
+                    error!(task = context.config.name, error, "Invalid command line");
+                    *context.exit.lock().unwrap() = Some(format!("Invalid command line: {error}"));
+                    return ControlFlow::Break(TaskState::Concluded(ExitReason::Failed));
+                }
             },
-            Payload::Builtin(runnable) if x == 0 => runnable.run(context, context_map).await,
-            _ => ControlFlow::Break(TaskState::Concluded(ExitReason::Done)),
+            Payload::Builtin(runnable) if x == 0 => return runnable.run(context, context_map).await,
+            _ => return ControlFlow::Break(TaskState::Concluded(ExitReason::Done)),
+        };
+        match command_lines.get(x) {
+            Some(command_line) => command_line.run(context, context_map).await,
+            None => ControlFlow::Break(TaskState::Concluded(ExitReason::Done)),
         }
     }
 
-    pub(crate) fn is_marker(&self) -> bool {
-        matches!(self, Self::Marker)
+    /// Command lines of a service, parsed if they were not yet. None for
+    /// command lines that don't parse.
+    pub fn lines(&self) -> Option<&CommandLines> {
+        match self {
+            Self::Service(lines) => Some(lines),
+            Self::Unparsed(lines) => lines.get().ok(),
+            _ => None,
+        }
+    }
+
+    /// Parse the command lines now, like alfad-compile does
+    ///
+    /// ```
+    /// use alfad::config::payload::{LazyLines, Payload};
+    ///
+    /// let payload = Payload::Unparsed(LazyLines::new("mount -a".to_owned()));
+    /// assert!(matches!(payload.parse(), Ok(Payload::Service(_))));
+    /// assert!(Payload::Unparsed(LazyLines::new("sh -c 'echo".to_owned())).parse().is_err());
+    /// ```
+    pub fn parse(self) -> Result<Self, CommandLineError> {
+        match self {
+            Self::Unparsed(lines) => lines.parse().map(Self::Service),
+            payload => Ok(payload),
+        }
+    }
+
+    /// Command lines a service runs one after the other, builtins and
+    /// markers have none
+    ///
+    /// ```
+    /// use alfad::config::payload::Payload;
+    ///
+    /// let payload: Payload = "mount -a\n-swapon -a".parse().unwrap();
+    /// assert_eq!(payload.command_count(), 2);
+    /// assert_eq!(Payload::Marker.command_count(), 0);
+    /// ```
+    pub fn command_count(&self) -> usize {
+        match self {
+            Self::Service(lines) => lines.len(),
+            // Invalid command lines still take a step to fail
+            Self::Unparsed(lines) => lines.get().map_or(1, |lines| lines.len()),
+            _ => 0,
+        }
+    }
+
+    /// Whether [`Payload::run`] has anything to run at step `x`
+    ///
+    /// ```
+    /// use alfad::config::payload::Payload;
+    ///
+    /// let payload: Payload = "mount -a\n-swapon -a".parse().unwrap();
+    /// assert!(payload.has_step(1));
+    /// assert!(!payload.has_step(2));
+    /// assert!(!Payload::Marker.has_step(0));
+    /// ```
+    pub fn has_step(&self, x: usize) -> bool {
+        match self {
+            Self::Service(lines) => x < lines.len(),
+            Self::Unparsed(lines) => x < lines.get().map_or(1, |lines| lines.len()),
+            Self::Builtin(_) => x == 0,
+            Self::Marker => false,
+        }
+    }
+
+    /// The command lines as they would be written in a task file
+    ///
+    /// ```
+    /// use alfad::config::payload::Payload;
+    ///
+    /// let payload: Payload = ":-env -i 'a b'\nmount -a".parse().unwrap();
+    /// assert_eq!(payload.commands().collect::<Vec<_>>(), [":-env -i 'a b'", "mount -a"]);
+    /// ```
+    pub fn commands(&self) -> impl Iterator<Item = String> + '_ {
+        self.lines().into_iter().flat_map(CommandLines::rendered)
+    }
+
+    /// Key of the builtin this runs, if any
+    pub fn builtin_key(&self) -> Option<&str> {
+        match self {
+            Self::Builtin(service) => Some(service.key()),
+            _ => None,
+        }
     }
 }
 
@@ -49,12 +226,31 @@ impl<T: Debug + DeserializeOwned> Debug for Payload<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Service(arg0) => f.debug_tuple("Service").field(arg0).finish(),
-            Self::Builtin(_) => f.write_str("<builtin>"),
+            Self::Unparsed(lines) => f.debug_tuple("Unparsed").field(&lines.raw).finish(),
+            Self::Builtin(service) => f.debug_tuple("Builtin").field(&service.key()).finish(),
             Self::Marker => f.write_str("<marker>"),
         }
     }
 }
 
+/// Command lines compare by what they parse to, a task read from its file
+/// equals the same task from the cache
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Marker, Self::Marker) => true,
+            (Self::Builtin(a), Self::Builtin(b)) => a == b,
+            (Self::Unparsed(a), Self::Unparsed(b)) if a.raw == b.raw => true,
+            (Self::Service(_) | Self::Unparsed(_), Self::Service(_) | Self::Unparsed(_)) => {
+                matches!((self.lines(), other.lines()), (Some(a), Some(b)) if a == b)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Payload {}
+
 impl<T: Default + DeserializeOwned> Default for Payload<T> {
     fn default() -> Self {
         Self::Service(T::default())
@@ -0,0 +1,482 @@
+//! JSON Schema of task files, for editors and linters, and a validator
+//! for the part of JSON Schema it uses. `alfad-compile schema` prints it,
+//! a copy is kept in core/schema/task.schema.json.
+//!
+//! The schema is stricter than alfad: fields alfad does not know are
+//! reported, serde skips them silently. Names of fields and enum values are
+//! taken from the types, a test makes sure no field is left out.
+
+use super::{Quorum, RespawnRecheck, UnknownKernel, UnmetResources};
+use crate::{perform_action::closest, privilege::Privilege};
+use regex::Regex;
+use serde::{
+    de::{self, value, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use serde_json::{json, Map, Value};
+use std::fmt::{self, Display};
+
+/// The schema of a task file
+pub fn task() -> Value {
+    let string_or_null = json!({ "type": ["string", "null"] });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "alfad task file",
+        "type": "object",
+        "required": ["name"],
+        "additionalProperties": false,
+        "properties": {
+            "name": { "type": "string", "description": "Unique name other tasks refer to the task by" },
+            "cmd": {
+                "description": "Command lines, one per line, `marker`, or a builtin",
+                "anyOf": [
+                    { "type": "string" },
+                    { "type": "array", "items": { "$ref": "#/$defs/command_line" } },
+                    {
+                        "type": "object",
+                        "required": ["builtin"],
+                        "additionalProperties": false,
+                        "properties": { "builtin": { "type": "string" } },
+                    },
+                ],
+            },
+            "before": { "$ref": "#/$defs/names" },
+            "with": { "$ref": "#/$defs/names" },
+            "after": {
+                "description": "Tasks to wait for until Done, or e.g. db:running for running, done, concluded or exists",
+                "$ref": "#/$defs/names",
+            },
+            "after_stopped": { "$ref": "#/$defs/names" },
+            "stop_dependency": { "type": "boolean" },
+            "restart_dependency": { "type": "boolean" },
+            "respawn": {
+                "description": "Restarts after the task ended, 0 for no limit, never if empty",
+                "anyOf": [{ "type": "null" }, { "type": "integer", "minimum": 0 }],
+            },
+            "respawn_recheck": { "enum": serde_names::<RespawnRecheck>() },
+            "run_timeout": { "anyOf": [{ "type": "null" }, { "$ref": "#/$defs/duration" }] },
+            "group": string_or_null,
+            "provides": { "$ref": "#/$defs/names" },
+            "description": string_or_null,
+            "doc_url": string_or_null,
+            "env_keep": { "type": ["array", "null"], "items": { "type": "string" } },
+            "quorum": { "enum": serde_names::<Quorum>() },
+            "adopt": {
+                "type": ["object", "null"],
+                "required": ["match"],
+                "additionalProperties": false,
+                "properties": { "match": { "type": "string" }, "pidfile": string_or_null },
+            },
+            "log_cmd": string_or_null,
+            "log_rate_limit": {
+                "type": ["object", "null"],
+                "required": ["lines", "per"],
+                "additionalProperties": false,
+                "properties": {
+                    "lines": { "type": "integer", "minimum": 1 },
+                    "per": { "$ref": "#/$defs/duration" },
+                },
+            },
+            "log_timestamps": { "type": "boolean" },
+            "requires_kernel": {
+                "type": ["object", "null"],
+                "additionalProperties": false,
+                "properties": {
+                    "min_version": string_or_null,
+                    "config": { "type": "array", "items": { "type": "string" } },
+                    "unknown": { "enum": serde_names::<UnknownKernel>() },
+                },
+            },
+            "requires_resources": {
+                "type": ["object", "null"],
+                "additionalProperties": false,
+                "properties": {
+                    "min_free_mem_mb": { "anyOf": [{ "type": "null" }, { "type": "integer", "minimum": 0 }] },
+                    "min_free_disk_mb": {
+                        "type": ["object", "null"],
+                        "required": ["path", "mb"],
+                        "additionalProperties": false,
+                        "properties": { "path": { "type": "string" }, "mb": { "type": "integer", "minimum": 0 } },
+                    },
+                    "unmet": { "enum": serde_names::<UnmetResources>() },
+                    "resource_wait": { "anyOf": [{ "type": "null" }, { "$ref": "#/$defs/duration" }] },
+                },
+            },
+            "requires_privileges": {
+                "anyOf": [
+                    { "enum": serde_names::<Privilege>() },
+                    { "type": "array", "items": { "enum": serde_names::<Privilege>() } },
+                ],
+            },
+            "locale": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": { "tz": string_or_null, "lang": string_or_null, "lc_all": string_or_null },
+            },
+            "env_file": string_or_null,
+            "env": { "type": "object", "additionalProperties": { "type": "string" } },
+            "env_export": { "type": "boolean" },
+            "mask_args": { "type": "array", "items": { "type": "integer", "minimum": 0 } },
+            "selinux_context": string_or_null,
+            "apparmor_profile": string_or_null,
+            "security_required": { "type": "boolean" },
+            "collect_failure_data": { "type": ["boolean", "null"] },
+            "protected": { "type": ["boolean", "null"] },
+        },
+        "$defs": {
+            "names": {
+                "description": "One task name or a list of them",
+                "anyOf": [{ "type": "string" }, { "type": "array", "items": { "type": "string" } }],
+            },
+            "duration": { "type": "string", "pattern": "^[0-9]+(ms|s|m|h)$" },
+            "command_line": {
+                "anyOf": [
+                    { "type": "string" },
+                    {
+                        "type": "object",
+                        "required": ["run"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "run": { "type": "string" },
+                            "ignore_env": { "type": "boolean" },
+                            "ignore_return": { "type": "boolean" },
+                            "timeout": { "$ref": "#/$defs/duration" },
+                        },
+                    },
+                ],
+            },
+        },
+    })
+}
+
+/// The schema as written to core/schema/task.schema.json
+pub fn render() -> String {
+    serde_json::to_string_pretty(&task()).unwrap_or_default() + "\n"
+}
+
+/// Names serde knows the fields of struct `T` or the variants of enum `T`
+/// by, as they are written in a task file
+pub fn serde_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut names = &[][..];
+    let _ = T::deserialize(Names(&mut names));
+    names
+}
+
+/// Takes note of what serde expects instead of deserializing anything
+struct Names<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for Names<'_> {
+    type Error = value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("neither a struct nor an enum"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self, _: &'static str, fields: &'static [&'static str], _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("only the fields were asked for"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self, _: &'static str, variants: &'static [&'static str], _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = variants;
+        Err(de::Error::custom("only the variants were asked for"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map identifier ignored_any
+    }
+}
+
+/// Where in a task file something is wrong, and what
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    /// Like `cmd[1].timeout`, empty for the file as a whole
+    pub path: String,
+    pub message: String,
+    /// A field alfad does not know, it ignores those
+    pub unknown_field: bool,
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.is_empty() {
+            true => f.write_str(&self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+/// Everything in `value` that does not match `schema`
+pub fn validate(schema: &Value, value: &Value) -> Vec<SchemaError> {
+    let mut errors = Vec::new();
+    Validator { root: schema, errors: &mut errors }.check(schema, value, "");
+    errors
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    errors: &'a mut Vec<SchemaError>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, path: &str, message: String) {
+        self.errors.push(SchemaError { path: path.to_owned(), message, unknown_field: false });
+    }
+
+    /// What `schema` refers to, `schema` itself if it is no reference
+    fn resolve(&self, schema: &'a Value) -> &'a Value {
+        let reference = schema.get("$ref").and_then(Value::as_str);
+        match reference.and_then(|reference| reference.strip_prefix("#/$defs/")) {
+            Some(name) => &self.root["$defs"][name],
+            None => schema,
+        }
+    }
+
+    /// JSON types `schema` allows, empty if it does not say
+    fn types(&self, schema: &'a Value) -> Vec<&'a str> {
+        let schema = self.resolve(schema);
+        let mut types: Vec<&str> = match (&schema["type"], &schema["anyOf"], &schema["enum"]) {
+            (Value::String(single), ..) => vec![single.as_str()],
+            (Value::Array(types), ..) => types.iter().filter_map(Value::as_str).collect(),
+            (_, Value::Array(branches), _) => branches.iter().flat_map(|branch| self.types(branch)).collect(),
+            (_, _, Value::Array(values)) => values.iter().map(type_of).collect(),
+            _ => Vec::new(),
+        };
+        types.dedup();
+        types
+    }
+
+    fn check(&mut self, schema: &'a Value, value: &Value, path: &str) {
+        let schema = self.resolve(schema);
+        if let Some(branches) = schema["anyOf"].as_array() {
+            return self.any_of(branches, value, path);
+        }
+        let types = self.types(schema);
+        if !types.is_empty() && !types.iter().any(|expected| has_type(value, expected)) {
+            return self.error(path, format!("Expected {}, found {}", types.join(" or "), describe(value)));
+        }
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                let names: Vec<_> = allowed.iter().filter_map(Value::as_str).collect();
+                let mut message = format!("{} is not one of {}", describe(value), names.join(", "));
+                if let Some(suggestion) = value.as_str().and_then(|value| closest(value, names.iter().copied())) {
+                    message += &format!(", did you mean '{suggestion}'?");
+                }
+                return self.error(path, message);
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema["minimum"].as_f64(), value.as_f64()) {
+            if number < minimum {
+                self.error(path, format!("{number} is less than {minimum}"));
+            }
+        }
+        if let (Some(pattern), Some(string)) = (schema["pattern"].as_str(), value.as_str()) {
+            if !Regex::new(pattern).is_ok_and(|regex| regex.is_match(string)) {
+                let example =
+                    if schema == &self.root["$defs"]["duration"] { ", expected something like 5s or 500ms" } else { "" };
+                self.error(path, format!("'{string}' is malformed{example}"));
+            }
+        }
+        match value {
+            Value::Object(object) => self.object(schema, object, path),
+            Value::Array(items) => {
+                for (index, item) in items.iter().enumerate() {
+                    self.check(&schema["items"], item, &format!("{path}[{index}]"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The errors of the branch that fits best: the first one without any,
+    /// else the first one of the right type
+    fn any_of(&mut self, branches: &'a [Value], value: &Value, path: &str) {
+        let mut best = None;
+        for branch in branches {
+            let errors = validate_in(self.root, branch, value, path);
+            if errors.is_empty() {
+                return;
+            }
+            let fits = self.types(branch).iter().any(|expected| has_type(value, expected));
+            if fits && best.is_none() {
+                best = Some(errors);
+            }
+        }
+        match best {
+            Some(errors) => self.errors.extend(errors),
+            None => {
+                let types: Vec<_> = branches.iter().flat_map(|branch| self.types(branch)).collect();
+                self.error(path, format!("Expected {}, found {}", types.join(" or "), describe(value)));
+            }
+        }
+    }
+
+    fn object(&mut self, schema: &'a Value, object: &Map<String, Value>, path: &str) {
+        let field = |name: &str| if path.is_empty() { name.to_owned() } else { format!("{path}.{name}") };
+        for required in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+            if !object.contains_key(required) {
+                self.error(path, format!("Missing {required}"));
+            }
+        }
+        let properties = schema["properties"].as_object();
+        for (name, value) in object {
+            match (properties.and_then(|properties| properties.get(name)), &schema["additionalProperties"]) {
+                (Some(property), _) => self.check(property, value, &field(name)),
+                (None, Value::Bool(false)) => {
+                    let mut message = format!("Unknown field {name}, alfad ignores it");
+                    if let Some(suggestion) = properties.and_then(|known| closest(name, known.keys().map(String::as_str))) {
+                        message += &format!(", did you mean {suggestion}?");
+                    }
+                    self.errors.push(SchemaError { path: field(name), message, unknown_field: true });
+                }
+                (None, additional @ Value::Object(_)) => self.check(additional, value, &field(name)),
+                (None, _) => {}
+            }
+        }
+    }
+}
+
+/// Errors of `value` against `schema`, a part of `root`
+fn validate_in(root: &Value, schema: &Value, value: &Value, path: &str) -> Vec<SchemaError> {
+    let mut errors = Vec::new();
+    Validator { root, errors: &mut errors }.check(schema, value, path);
+    errors
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    let actual = type_of(value);
+    actual == expected || (expected == "number" && actual == "integer")
+}
+
+/// `value` in an error message, short values as they are
+fn describe(value: &Value) -> String {
+    match value {
+        Value::String(string) => format!("'{string}'"),
+        Value::Array(_) | Value::Object(_) | Value::Null => type_of(value).to_owned(),
+        value => format!("{} {value}", type_of(value)),
+    }
+}
+
+/// Task files are YAML, the schema is about what they would be as JSON
+pub fn read(text: &str) -> Result<Value, serde_yaml::Error> {
+    serde_yaml::from_str(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read, render, serde_names, task, validate, SchemaError};
+    use crate::config::{yaml::TaskConfigYaml, Adopt, FreeDisk, Locale, LogRateLimit, RequiresKernel, RequiresResources};
+    use std::{fs, path::Path};
+
+    fn property_names(schema: &serde_json::Value) -> Vec<&str> {
+        let mut names: Vec<_> = schema["properties"].as_object().unwrap().keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn sorted(names: &[&'static str]) -> Vec<&'static str> {
+        let mut names = names.to_vec();
+        names.sort_unstable();
+        names
+    }
+
+    fn errors(yaml: &str) -> Vec<String> {
+        validate(&task(), &read(yaml).unwrap()).iter().map(SchemaError::to_string).collect()
+    }
+
+    /// A field added to the structs has to be added to the schema, and the
+    /// copy in the repository has to be regenerated
+    #[test]
+    fn in_sync() {
+        let schema = task();
+        let properties = &schema["properties"];
+        assert_eq!(property_names(&schema), sorted(serde_names::<TaskConfigYaml>()));
+        assert_eq!(property_names(&properties["adopt"]), sorted(serde_names::<Adopt>()));
+        assert_eq!(property_names(&properties["log_rate_limit"]), sorted(serde_names::<LogRateLimit>()));
+        assert_eq!(property_names(&properties["requires_kernel"]), sorted(serde_names::<RequiresKernel>()));
+        let resources = &properties["requires_resources"];
+        assert_eq!(property_names(resources), sorted(serde_names::<RequiresResources>()));
+        assert_eq!(property_names(&resources["properties"]["min_free_disk_mb"]), sorted(serde_names::<FreeDisk>()));
+        assert_eq!(property_names(&properties["locale"]), sorted(serde_names::<Locale>()));
+        assert_eq!(properties["quorum"]["enum"], serde_json::json!(["all", "any"]));
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/schema/task.schema.json");
+        let written = fs::read_to_string(path).unwrap_or_default();
+        assert!(written == render(), "{path} is outdated, regenerate it with alfad-compile schema");
+    }
+
+    #[test]
+    fn valid_files() {
+        let all = "name: web\ncmd:\n  - nginx -t\n  - {run: nginx, timeout: 5s, ignore_return: true}\nafter: [network, db]\n\
+                   with: db\nrespawn: 0\nrespawn_recheck: all\ngroup: web\nrequires_privileges: mount\n\
+                   requires_kernel: {min_version: \"5.10\", unknown: skip}\n\
+                   requires_resources: {min_free_mem_mb: 200, min_free_disk_mb: {path: /var, mb: 50}, resource_wait: 30s}\n\
+                   locale: {tz: UTC}\nenv: {A: b}\nrun_timeout: 2m\n\
+                   log_rate_limit: {lines: 10, per: 1s}\nadopt: {match: nginx, pidfile: ~}\ncollect_failure_data: ~\n";
+        assert_eq!(errors(all), Vec::<String>::new());
+        assert_eq!(errors("name: ctl\ncmd: {builtin: ctl::daemon}\nrespawn:\n"), Vec::<String>::new());
+        assert_eq!(errors("name: m\ncmd: marker\nquorum: any\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(errors("cmd: \"true\"\n"), ["Missing name"]);
+        assert_eq!(
+            errors("name: a\nrespawn_recheck: wiht\n"),
+            ["respawn_recheck: 'wiht' is not one of none, with, all, did you mean 'with'?"]
+        );
+        assert_eq!(errors("name: a\naftr: b\n"), ["aftr: Unknown field aftr, alfad ignores it, did you mean after?"]);
+        assert_eq!(errors("name: a\nrespawn: -1\n"), ["respawn: -1 is less than 0"]);
+        assert_eq!(errors("name: a\nrespawn: yes\n"), ["respawn: Expected null or integer, found 'yes'"]);
+        assert_eq!(errors("name: a\nafter: {b: c}\n"), ["after: Expected string or array, found object"]);
+        assert_eq!(errors("name: a\nafter: [b, 1]\n"), ["after[1]: Expected string, found integer 1"]);
+        assert_eq!(
+            errors("name: a\ncmd: [{run: a, timeout: 5 s}]\n"),
+            ["cmd[0].timeout: '5 s' is malformed, expected something like 5s or 500ms"]
+        );
+        assert_eq!(
+            errors("name: a\ncmd: [{rnu: a}]\n"),
+            ["cmd[0]: Missing run", "cmd[0].rnu: Unknown field rnu, alfad ignores it, did you mean run?"]
+        );
+        assert_eq!(
+            errors("name: a\nrequires_privileges: [kill, mounts]\n"),
+            ["requires_privileges[1]: 'mounts' is not one of kill, mount, reboot, did you mean 'mount'?"]
+        );
+        assert_eq!(errors("name: a\nenv: {A: 1}\n"), ["env.A: Expected string, found integer 1"]);
+        assert!(validate(&task(), &read("name: a\naftr: b\n").unwrap())[0].unknown_field);
+    }
+
+    /// What serde reads the schema accepts, and the other way round, except
+    /// for fields serde ignores
+    #[test]
+    fn agrees_with_serde() {
+        let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../test/alfad.d"));
+        let schema = task();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let text = fs::read_to_string(&path).unwrap();
+            let (Ok(value), serde) = (read(&text), serde_yaml::from_str::<TaskConfigYaml>(&text)) else {
+                continue;
+            };
+            let errors: Vec<_> = validate(&schema, &value).into_iter().filter(|error| !error.unknown_field).collect();
+            assert_eq!(errors.is_empty(), serde.is_ok(), "{}: {errors:?} {serde:?}", path.display());
+        }
+    }
+}
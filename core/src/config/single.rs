@@ -0,0 +1,141 @@
+//! All tasks in one file, `alfad.yaml` next to alfad.d, for an initramfs
+//! where a directory of small task files costs more in cpio headers and
+//! inodes than the tasks themselves:
+//!
+//! ```yaml
+//! defaults:
+//!   on_boot_failure: emergency
+//! tasks:
+//!   - name: mount
+//!     cmd: mount -a
+//!   - name: getty
+//!     cmd: getty tty1
+//!     after: mount
+//! ```
+//!
+//! `defaults` takes the place of defaults.yaml. Which of alfad.yaml, the
+//! cache and alfad.d the configuration is loaded from is set with
+//! `config_order` in the defaults, see [`Origin`].
+
+use super::{defaults::Defaults, yaml::TaskConfigYaml};
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+use strum::{Display, EnumString};
+use thiserror::Error;
+use tracing::error;
+
+/// Where the configuration can be loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Origin {
+    /// alfad.yaml
+    File,
+    /// alfad.bin, written by alfad-compile
+    Cache,
+    /// The task files in alfad.d
+    Dir,
+}
+
+impl Origin {
+    /// The order they are tried in unless configured otherwise
+    pub const DEFAULT: [Self; 3] = [Self::File, Self::Cache, Self::Dir];
+
+    /// `listed` followed by the origins it leaves out, in the default
+    /// order. alfad.yaml and the cache are skipped if they don't exist or
+    /// can't be read, alfad.d is used once it is reached.
+    pub fn order(listed: &[Self]) -> Vec<Self> {
+        let mut order = Vec::new();
+        for origin in listed.iter().chain(&Self::DEFAULT) {
+            if !order.contains(origin) {
+                order.push(*origin);
+            }
+        }
+        order
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SingleFileError {
+    #[error("Could not read it: {0}")]
+    Read(#[from] io::Error),
+    #[error("{0}")]
+    Parse(#[from] serde_yaml::Error),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SingleFile {
+    #[serde(default)]
+    pub tasks: Vec<TaskConfigYaml>,
+    /// Replaces defaults.yaml if set
+    #[serde(default)]
+    pub defaults: Option<Defaults>,
+}
+
+impl SingleFile {
+    /// Parse the file at `path`, which is the source of every task in it
+    pub fn read(path: &Path) -> Result<Self, SingleFileError> {
+        let mut file: Self = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+        for task in file.tasks.iter_mut() {
+            task.source = Some(path.to_owned());
+        }
+        Ok(file)
+    }
+}
+
+/// Only the `defaults` of the file at `path`, without parsing the tasks.
+/// `None` if there is no such file or it has no defaults.
+pub fn read_defaults(path: &Path) -> Option<Defaults> {
+    #[derive(Deserialize)]
+    struct DefaultsOnly {
+        #[serde(default)]
+        defaults: Option<Defaults>,
+    }
+
+    let text = fs::read_to_string(path).ok()?;
+    let file: DefaultsOnly =
+        serde_yaml::from_str(&text).map_err(|error| error!("Ignoring the defaults in {path:?}: {error}")).ok()?;
+    file.defaults
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_defaults, Origin, SingleFile, SingleFileError};
+    use crate::config::defaults::BootFailurePolicy;
+    use std::fs;
+
+    #[test]
+    fn parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alfad.yaml");
+        let text = "defaults:\n  on_boot_failure: emergency\ntasks:\n  - name: mount\n    cmd: mount -a\n  \
+                    - name: getty\n    cmd: getty tty1\n    after: mount\n";
+        fs::write(&path, text).unwrap();
+        let file = SingleFile::read(&path).unwrap();
+        let names: Vec<_> = file.tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(names, ["mount", "getty"]);
+        assert!(file.tasks.iter().all(|task| task.source.as_deref() == Some(path.as_path())));
+        assert_eq!(file.defaults.unwrap().on_boot_failure, BootFailurePolicy::Emergency);
+        assert_eq!(read_defaults(&path).unwrap().on_boot_failure, BootFailurePolicy::Emergency);
+
+        fs::write(&path, "tasks: []\n").unwrap();
+        assert!(SingleFile::read(&path).unwrap().defaults.is_none());
+        assert!(read_defaults(&path).is_none());
+        assert!(read_defaults(&dir.path().join("missing.yaml")).is_none());
+
+        fs::write(&path, "task:\n  - name: typo\n").unwrap();
+        assert!(matches!(SingleFile::read(&path), Err(SingleFileError::Parse(_))));
+        fs::write(&path, "defaults:\n  on_boot_failure: explode\ntasks: []\n").unwrap();
+        assert!(read_defaults(&path).is_none());
+        assert!(matches!(SingleFile::read(&dir.path().join("missing.yaml")), Err(SingleFileError::Read(_))));
+    }
+
+    #[test]
+    fn order() {
+        assert_eq!(Origin::order(&[]), Origin::DEFAULT);
+        assert_eq!(Origin::order(&[Origin::Cache]), [Origin::Cache, Origin::File, Origin::Dir]);
+        assert_eq!(Origin::order(&[Origin::Dir, Origin::Dir, Origin::File]), [Origin::Dir, Origin::File, Origin::Cache]);
+        assert_eq!("cache".parse::<Origin>().unwrap(), Origin::Cache);
+    }
+}
@@ -0,0 +1,287 @@
+use super::{
+    payload::PayloadKind,
+    yaml::{CommandLineYaml, Timeout},
+    Adopt, Locale, LogRateLimit, Quorum, RequiresKernel, RequiresResources, Respawn, RespawnRecheck, TaskConfig, WaitKind,
+};
+use crate::privilege::Privilege;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Format {
+    #[default]
+    Yaml,
+    Json,
+}
+
+/// Stable, human readable representation of an effective task. Command
+/// lines are rendered the way they are written in task files and
+/// dependencies are sorted, so the output can be diffed.
+#[derive(Debug, Serialize)]
+pub struct TaskView<'a> {
+    pub name: &'a str,
+    pub kind: PayloadKind,
+    /// Lines with a timeout are written as a map, like in task files
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<CommandLineYaml>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub with: Vec<&'a str>,
+    /// With the state they are waited for unless it is Done
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after_stopped: Vec<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stop_dependency: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub restart_dependency: bool,
+    /// Only shown for markers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quorum: Option<Quorum>,
+    /// Number of restarts, 0 means unlimited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub respawn: Option<usize>,
+    /// Only shown for respawning tasks that do not use the default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub respawn_recheck: Option<RespawnRecheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_timeout: Option<Timeout>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_keep: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adopt: Option<&'a Adopt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_cmd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_rate_limit: Option<&'a LogRateLimit>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub log_timestamps: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requires_kernel: Option<&'a RequiresKernel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requires_resources: Option<&'a RequiresResources>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub requires_privileges: &'a [Privilege],
+    /// Only what the task and its group set
+    #[serde(skip_serializing_if = "Locale::is_empty")]
+    pub locale: &'a Locale,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<&'a Path>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub env_export: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub mask_args: &'a [usize],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selinux_context: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apparmor_profile: Option<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub security_required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collect_failure_data: Option<bool>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<&'a Path>,
+}
+
+impl<'a> From<&'a TaskConfig> for TaskView<'a> {
+    fn from(config: &'a TaskConfig) -> Self {
+        let cmd = config.payload.lines().into_iter().flatten().map(CommandLineYaml::from).collect();
+        let sorted = |list: &'a [String]| {
+            let mut list: Vec<_> = list.iter().map(String::as_str).collect();
+            list.sort_unstable();
+            list
+        };
+        Self {
+            name: &config.name,
+            kind: config.payload.kind(),
+            cmd,
+            with: sorted(&config.with),
+            after: sorted(&config.after)
+                .into_iter()
+                .map(|name| match config.wait_kind(name) {
+                    WaitKind::Done => name.to_owned(),
+                    kind => format!("{name}:{kind}"),
+                })
+                .collect(),
+            after_stopped: sorted(&config.after_stopped),
+            stop_dependency: config.stop_dependency,
+            restart_dependency: config.restart_dependency,
+            quorum: config.payload.is_marker().then_some(config.quorum),
+            respawn: match config.respawn {
+                Respawn::No => None,
+                Respawn::Retry(attempts) => Some(attempts),
+            },
+            respawn_recheck: Some(config.respawn_recheck)
+                .filter(|recheck| config.respawn != Respawn::No && *recheck != RespawnRecheck::default()),
+            run_timeout: config.run_timeout,
+            group: config.group.as_deref(),
+            description: config.description.as_deref(),
+            doc_url: config.doc_url.as_deref(),
+            env_keep: config.env_keep.as_deref(),
+            adopt: config.adopt.as_ref(),
+            log_cmd: config.log_cmd.as_ref().map(ToString::to_string),
+            log_rate_limit: config.log_rate_limit.as_ref(),
+            log_timestamps: config.log_timestamps,
+            requires_kernel: config.requires_kernel.as_ref(),
+            requires_resources: config.requires_resources.as_ref(),
+            requires_privileges: &config.requires_privileges,
+            locale: &config.locale,
+            env_file: config.env_file.as_deref(),
+            env: &config.env,
+            env_export: config.env_export,
+            mask_args: &config.mask_args,
+            selinux_context: config.selinux_context.as_deref(),
+            apparmor_profile: config.apparmor_profile.as_deref(),
+            security_required: config.security_required,
+            collect_failure_data: config.collect_failure_data,
+            protected: config.protected,
+            source: config.source.as_deref(),
+        }
+    }
+}
+
+/// Render all tasks sorted by name
+pub fn dump(configs: &[TaskConfig], format: Format) -> Result<String> {
+    let mut views: Vec<_> = configs.iter().map(TaskView::from).collect();
+    views.sort_by_key(|view| view.name);
+    Ok(match format {
+        Format::Yaml => serde_yaml::to_string(&views)?,
+        Format::Json => serde_json::to_string_pretty(&views)? + "\n",
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dump, Format, TaskView};
+    use crate::config::{
+        payload::Payload,
+        read_yaml_configs, strip_sources,
+        yaml::{PayloadYaml, TaskConfigYaml},
+        TaskConfig,
+    };
+    use std::fs;
+
+    fn fixture() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            ("mount.task", "name: mount\ncmd: \"mount -a\"\nprovides: fs::run\ngroup: early\ndescription: Mount all filesystems\n"),
+            ("getty.task", "name: getty\ncmd: |\n  :-stty sane\n  getty 'tty 1'\nafter: [network, mount]\nrespawn: 0\n"),
+            ("network.task", "name: network\ncmd: ip link set eth0 up\nbefore: getty\nwith: mount\n"),
+        ];
+        for (name, content) in files {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn yaml_snapshot() {
+        let dir = fixture();
+        let mut configs = read_yaml_configs(dir.path(), vec![]);
+        strip_sources(&mut configs, dir.path());
+        assert_eq!(
+            dump(&configs, Format::Yaml).unwrap(),
+            r#"- name: feature::fs::run
+  kind: marker
+  with:
+  - mount
+  after:
+  - mount
+  quorum: any
+  source: <generated>
+- name: getty
+  kind: service
+  cmd:
+  - :-stty sane
+  - getty 'tty 1'
+  after:
+  - mount
+  - network
+  respawn: 0
+  source: getty.task
+- name: group::early
+  kind: marker
+  with:
+  - mount
+  after:
+  - mount
+  quorum: all
+  source: <generated>
+- name: mount
+  kind: service
+  cmd:
+  - mount -a
+  group: early
+  description: Mount all filesystems
+  source: mount.task
+- name: network
+  kind: service
+  cmd:
+  - ip link set eth0 up
+  with:
+  - mount
+  source: network.task
+"#
+        );
+    }
+
+    #[test]
+    fn json_snapshot() {
+        let dir = fixture();
+        let configs = read_yaml_configs(dir.path(), vec![]);
+        let json = dump(&configs, Format::Json).unwrap();
+        assert!(json.starts_with("[\n  {\n    \"name\": \"feature::fs::run\",\n    \"kind\": \"marker\",\n"));
+        assert!(json.contains("\"cmd\": [\n      \":-stty sane\",\n      \"getty 'tty 1'\"\n    ],"));
+        assert!(json.contains("\"respawn\": 0"));
+    }
+
+    #[test]
+    fn dump_is_stable() {
+        let dir = fixture();
+        let first = dump(&read_yaml_configs(dir.path(), vec![]), Format::Yaml).unwrap();
+        for _ in 0..10 {
+            assert_eq!(dump(&read_yaml_configs(dir.path(), vec![]), Format::Yaml).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn cmd_round_trip() {
+        let mut config = TaskConfig::new("tricky".to_owned());
+        config.payload = Payload::Service(":-env -i 'a b' \"c'd\" $HOME\n-false\nprintf ''".parse().unwrap());
+        let yaml = serde_yaml::to_string(&TaskView::from(&config)).unwrap();
+
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        let lines: Vec<_> = value["cmd"].as_sequence().unwrap().iter().map(|x| x.as_str().unwrap()).collect();
+        let mut parsed = TaskConfig::new("tricky".to_owned());
+        parsed.payload = Payload::Service(lines.join("\n").parse().unwrap());
+        assert_eq!(TaskView::from(&parsed).cmd, TaskView::from(&config).cmd);
+        assert_eq!(format!("{:?}", parsed.payload), format!("{:?}", config.payload));
+    }
+
+    #[test]
+    fn structured_round_trip() {
+        let yaml = "name: slow\ncmd:\n  - -true\n  - {run: -x 'a b', ignore_return: true, timeout: 1500ms}\n";
+        let config = serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config().unwrap();
+        let dumped = serde_yaml::to_string(&TaskView::from(&config)).unwrap();
+        assert!(dumped.contains("cmd:\n- -true\n- run: -x 'a b'\n  ignore_return: true\n  timeout: 1500ms\n"), "{dumped}");
+
+        // The dump is valid input again
+        let value: serde_yaml::Value = serde_yaml::from_str(&dumped).unwrap();
+        let mut parsed = TaskConfigYaml::new("slow".to_owned());
+        parsed.cmd = PayloadYaml::Lines(serde_yaml::from_value(value["cmd"].clone()).unwrap());
+        let parsed = parsed.into_config().unwrap();
+        assert_eq!(format!("{:?}", parsed.payload), format!("{:?}", config.payload));
+    }
+}
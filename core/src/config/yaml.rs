@@ -1,23 +1,65 @@
-use super::payload::Payload;
+use super::payload::{LazyLines, Payload};
 use crate::{
-    builtin::BuiltInService,
-    command_line,
-    config::{Respawn, TaskConfig},
+    builtin::{self, BuiltInService, UnknownBuiltin},
+    command_line::{
+        env::{self, InvalidVariable},
+        CommandLine, CommandLineError,
+    },
+    config::{
+        defaults::Inherited,
+        name::{self, NameError},
+        Adopt, Locale, LogRateLimit, Quorum, RequiresKernel, RequiresResources, Respawn, RespawnRecheck, TaskConfig, WaitKind,
+    },
+    kernel::{InvalidVersion, KernelVersion},
+    privilege::Privilege,
+    security::{self, SecurityError},
 };
+use regex::Regex;
 use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use smallvec::SmallVec;
-use std::fmt::Debug;
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Display},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use thiserror::Error;
 
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum PayloadYaml {
     // #[serde(deserialize_with = "T::deserialize")]
     Service(String),
+    /// `cmd` as a sequence, one command line per entry
+    Lines(Vec<CommandLineYaml>),
     #[serde(skip)]
     Builtin(BuiltInService),
+    /// `{builtin: ctl::daemon}` in a task file, replaces the default
+    /// configuration of that builtin
+    Reference { builtin: String },
     Marker,
 }
 
+impl PayloadYaml {
+    /// Registry key of the builtin this runs, if any
+    pub fn builtin_key(&self) -> Option<&str> {
+        match self {
+            Self::Builtin(service) => Some(service.key()),
+            Self::Reference { builtin } => Some(builtin),
+            _ => None,
+        }
+    }
+
+    /// Like the derived implementation, but `cmd: marker` is a marker
+    fn read<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match Self::deserialize(deserializer)? {
+            Self::Service(cmd) if cmd == "marker" => Self::Marker,
+            payload => payload,
+        })
+    }
+}
+
 impl Default for PayloadYaml {
     fn default() -> Self {
         Self::Service(String::new())
@@ -28,12 +70,114 @@ impl Debug for PayloadYaml {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Service(arg0) => f.debug_tuple("Service").field(arg0).finish(),
+            Self::Lines(lines) => f.debug_tuple("Lines").field(lines).finish(),
             Self::Builtin(_) => f.write_str("<builtin>"),
+            Self::Reference { builtin } => f.debug_struct("Reference").field("builtin", builtin).finish(),
             Self::Marker => f.write_str("<marker>"),
         }
     }
 }
 
+/// One entry of `cmd` written as a sequence
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandLineYaml {
+    /// Same as a line of the string form, including the `:` and `-` prefixes
+    Line(String),
+    /// The flags spelled out, `run` is taken without looking for prefixes
+    Structured {
+        run: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        ignore_env: bool,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        ignore_return: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<Timeout>,
+    },
+}
+
+impl CommandLineYaml {
+    /// The command as written, for the limits
+    pub fn run(&self) -> &str {
+        match self {
+            Self::Line(run) | Self::Structured { run, .. } => run,
+        }
+    }
+
+    pub fn into_command_line(self) -> Result<CommandLine, CommandLineError> {
+        match self {
+            Self::Line(line) => line.parse(),
+            Self::Structured { run, ignore_env, ignore_return, timeout } => {
+                Ok(CommandLine::new(&run, ignore_env, ignore_return)?.with_timeout(timeout.map(|timeout| timeout.0)))
+            }
+        }
+    }
+}
+
+/// The short form is used whenever nothing needs the structured one
+impl From<&CommandLine> for CommandLineYaml {
+    fn from(line: &CommandLine) -> Self {
+        match line.timeout() {
+            None => Self::Line(line.to_string()),
+            Some(timeout) => Self::Structured {
+                run: line.line(),
+                ignore_env: line.ignore_env(),
+                ignore_return: line.ignore_return(),
+                timeout: Some(Timeout(timeout)),
+            },
+        }
+    }
+}
+
+/// A duration like `500ms`, `5s`, `2m` or `1h`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timeout(pub Duration);
+
+impl FromStr for Timeout {
+    type Err = InvalidTimeout;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        let value: u64 = value.parse().map_err(|_| InvalidTimeout(s.to_owned()))?;
+        Ok(Self(match unit {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 60 * 60),
+            _ => return Err(InvalidTimeout(s.to_owned())),
+        }))
+    }
+}
+
+impl Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.subsec_millis() {
+            0 => write!(f, "{}s", self.0.as_secs()),
+            _ => write!(f, "{}ms", self.0.as_millis()),
+        }
+    }
+}
+
+impl TryFrom<String> for Timeout {
+    type Error = InvalidTimeout;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Timeout> for String {
+    fn from(value: Timeout) -> Self {
+        value.to_string()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid timeout '{}', expected a number followed by ms, s, m or h", .0)]
+pub struct InvalidTimeout(String);
+
 #[derive(Debug, Deserialize, Serialize, Eq, Clone, Hash, PartialEq)]
 #[serde(untagged)]
 pub enum RespawnYaml {
@@ -64,24 +208,110 @@ impl From<RespawnYaml> for Respawn {
 #[derive(Debug, Deserialize, Default)]
 pub struct TaskConfigYaml {
     pub name: String,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "PayloadYaml::read")]
     pub cmd: PayloadYaml,
-    #[cfg(feature = "before")]
+    /// Only resolved with the `before` feature, but always read so task
+    /// files and the cache look the same either way
     #[serde(default)]
     #[serde(deserialize_with = "OneOrMany::read")]
     pub before: Vec<String>,
     #[serde(default)]
     #[serde(deserialize_with = "OneOrMany::read")]
     pub with: Vec<String>,
+    /// Tasks to wait for until they are Done, or until the state after a
+    /// colon like `db:running`, see [`WaitKind`]
     #[serde(default)]
     #[serde(deserialize_with = "OneOrMany::read")]
     pub after: SmallVec<[String; 1]>,
+    /// Tasks that must not run while this one does, it waits until they
+    /// are Created or concluded
+    #[serde(default)]
+    #[serde(deserialize_with = "OneOrMany::read")]
+    pub after_stopped: Vec<String>,
+    /// Stop the tasks in `after_stopped` that run instead of waiting for
+    /// them to end
+    #[serde(default)]
+    pub stop_dependency: bool,
+    /// Start the tasks stopped for `stop_dependency` again once this one
+    /// is Done or Failed
+    #[serde(default)]
+    pub restart_dependency: bool,
     #[serde(default)]
     pub respawn: RespawnYaml,
+    /// Dependencies a respawn waits for again
+    #[serde(default)]
+    pub respawn_recheck: RespawnRecheck,
+    /// Kill the task and fail it if its command lines together take longer
+    pub run_timeout: Option<Timeout>,
     pub group: Option<String>,
     #[serde(default)]
     #[serde(deserialize_with = "OneOrMany::read")]
     pub provides: Vec<String>,
+    /// One line about what the task does, only shown to operators
+    pub description: Option<String>,
+    /// Where to find more about the task
+    pub doc_url: Option<String>,
+    /// Variables kept by command lines with the `:` prefix, replaces the
+    /// global `env_keep` from defaults.yaml
+    pub env_keep: Option<Vec<String>>,
+    /// For markers, whether all tasks in `after` have to be Done or any
+    #[serde(default)]
+    pub quorum: Quorum,
+    /// Processes started outside of alfad that the task may take over
+    pub adopt: Option<Adopt>,
+    /// Command line of a logger that gets stdout and stderr of the task.
+    /// It is restarted whenever it exits, until the task stops.
+    pub log_cmd: Option<String>,
+    /// Lines of output that may pass in a period, like `{lines: 1000, per: 1s}`.
+    /// What is over it is dropped and counted.
+    pub log_rate_limit: Option<LogRateLimit>,
+    /// Prefix every line of output with the time since boot and the wall
+    /// clock time it was read at
+    #[serde(default)]
+    pub log_timestamps: bool,
+    /// Kernel version and options the task needs, it is skipped otherwise
+    pub requires_kernel: Option<RequiresKernel>,
+    /// Free memory and disk space the task needs, checked right before
+    /// every start
+    pub requires_resources: Option<RequiresResources>,
+    /// What alfad has to be allowed to do for the task, it is skipped
+    /// otherwise
+    #[serde(default, deserialize_with = "OneOrMany::read")]
+    pub requires_privileges: Vec<Privilege>,
+    /// TZ, LANG and LC_ALL of the command lines, replaces what the group
+    /// or defaults.yaml set
+    #[serde(default)]
+    pub locale: Locale,
+    /// File with `VARIABLE=value` lines that are set for the command lines
+    pub env_file: Option<PathBuf>,
+    /// Variables set for the command lines, over everything else
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// stdout of the command lines is `VARIABLE=value` lines, set for the
+    /// command lines of every task that starts after this one is Done
+    #[serde(default)]
+    pub env_export: bool,
+    /// Indices in argv of arguments that logs and alfad-ctl list show as
+    /// `***`, 0 is the program
+    #[serde(default)]
+    pub mask_args: Vec<usize>,
+    /// SELinux context the command lines are executed with
+    pub selinux_context: Option<String>,
+    /// AppArmor profile the command lines are executed with
+    pub apparmor_profile: Option<String>,
+    /// Fail instead of running without a label if its LSM is not enabled
+    #[serde(default)]
+    pub security_required: bool,
+    /// Write a failure bundle when the task fails, overrides the global
+    /// `collect_failure_data` from defaults.yaml
+    pub collect_failure_data: Option<bool>,
+    /// Refuse kill, deactivate and restart without --force-protected, and
+    /// keep running while alfad powers off. Tasks named `builtin::` are
+    /// protected unless they say otherwise.
+    pub protected: Option<bool>,
+    /// File the task was read from
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
 }
 
 impl TaskConfigYaml {
@@ -97,22 +327,136 @@ impl TaskConfigYaml {
         self
     }
 
-    pub fn into_config(self) -> Result<TaskConfig, command_line::CommandLineError> {
+    /// The settings the task may leave to its group
+    pub fn inherited(&self) -> Inherited {
+        Inherited {
+            env_keep: self.env_keep.clone(),
+            locale: self.locale.clone(),
+            log_cmd: self.log_cmd.clone(),
+            selinux_context: self.selinux_context.clone(),
+            apparmor_profile: self.apparmor_profile.clone(),
+        }
+    }
+
+    /// Take the settings that are unset from `parent`
+    pub fn inherit(&mut self, parent: &Inherited) {
+        let Inherited { env_keep, locale, log_cmd, selinux_context, apparmor_profile } = self.inherited().inherit(parent);
+        (self.env_keep, self.locale, self.log_cmd) = (env_keep, locale, log_cmd);
+        (self.selinux_context, self.apparmor_profile) = (selinux_context, apparmor_profile);
+    }
+
+    pub fn into_config(self) -> Result<TaskConfig, ConfigError> {
+        name::check(&self.name)?;
+        if let Some(adopt) = &self.adopt {
+            Regex::new(&adopt.pattern).map_err(|error| ConfigError::AdoptPattern(error.to_string()))?;
+        }
+        if let Some(min_version) = self.requires_kernel.as_ref().and_then(|requires| requires.min_version.as_deref()) {
+            min_version.parse::<KernelVersion>()?;
+        }
+        for label in [&self.selinux_context, &self.apparmor_profile].into_iter().flatten() {
+            security::check_label(label)?;
+        }
+        for (name, value) in self.locale.vars() {
+            env::check(name, value)?;
+        }
+        for (name, value) in &self.env {
+            env::check(name, value)?;
+        }
+        if self.log_rate_limit.is_some_and(|limit| limit.lines == 0 || limit.per.0.is_zero()) {
+            return Err(ConfigError::LogRateLimit);
+        }
+        if self.requires_resources.as_ref().and_then(|requires| requires.resource_wait).is_some_and(|wait| wait.0.is_zero()) {
+            return Err(ConfigError::ResourceWait);
+        }
+        let protected = self.protected.unwrap_or_else(|| self.name.starts_with("builtin::"));
+        let (after, after_states) = split_after(&self.after);
         Ok(TaskConfig {
             name: self.name,
             payload: match self.cmd {
-                PayloadYaml::Service(x) => x.parse()?,
+                PayloadYaml::Service(x) => Payload::Unparsed(LazyLines::new(x)),
+                PayloadYaml::Lines(lines) => Payload::Service(
+                    lines.into_iter().map(CommandLineYaml::into_command_line).collect::<Result<_, _>>()?,
+                ),
                 PayloadYaml::Builtin(builtin) => Payload::Builtin(builtin),
+                PayloadYaml::Reference { builtin } => Payload::Builtin(builtin::lookup(&builtin)?),
                 PayloadYaml::Marker => Payload::Marker,
             },
             with: self.with,
-            after: self.after.into_vec(),
+            after,
+            after_states,
+            after_stopped: self.after_stopped,
+            stop_dependency: self.stop_dependency,
+            restart_dependency: self.restart_dependency,
+            before: self.before,
             respawn: self.respawn.into(),
+            respawn_recheck: self.respawn_recheck,
+            run_timeout: self.run_timeout,
             group: self.group,
+            description: self.description,
+            doc_url: self.doc_url,
+            env_keep: self.env_keep,
+            quorum: self.quorum,
+            adopt: self.adopt,
+            log_cmd: self.log_cmd.map(|line| line.parse()).transpose()?,
+            log_rate_limit: self.log_rate_limit,
+            log_timestamps: self.log_timestamps,
+            requires_kernel: self.requires_kernel,
+            requires_resources: self.requires_resources,
+            requires_privileges: self.requires_privileges,
+            locale: self.locale,
+            env_file: self.env_file,
+            env: self.env,
+            env_export: self.env_export,
+            mask_args: self.mask_args,
+            selinux_context: self.selinux_context,
+            apparmor_profile: self.apparmor_profile,
+            security_required: self.security_required,
+            collect_failure_data: self.collect_failure_data,
+            protected,
+            source: self.source,
         })
     }
 }
 
+/// The names in `after` and the states other than Done they are waited
+/// for. The first entry for a name wins.
+fn split_after(entries: &[String]) -> (Vec<String>, Vec<(String, WaitKind)>) {
+    let mut after: Vec<String> = Vec::with_capacity(entries.len());
+    let mut states = Vec::new();
+    for (name, kind) in entries.iter().map(|entry| WaitKind::split(entry)) {
+        if after.iter().any(|known| known == name) {
+            continue;
+        }
+        if kind != WaitKind::Done {
+            states.push((name.to_owned(), kind));
+        }
+        after.push(name.to_owned());
+    }
+    (after, states)
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    CommandLine(#[from] CommandLineError),
+    #[error(transparent)]
+    Builtin(#[from] UnknownBuiltin),
+    #[error("Invalid adopt pattern: {}", .0)]
+    AdoptPattern(String),
+    #[error(transparent)]
+    KernelVersion(#[from] InvalidVersion),
+    #[error(transparent)]
+    SecurityLabel(#[from] SecurityError),
+    #[error(transparent)]
+    Name(#[from] NameError),
+    #[error(transparent)]
+    Env(#[from] InvalidVariable),
+    #[error("Invalid log_rate_limit, lines and per have to be more than 0")]
+    LogRateLimit,
+    #[error("Invalid requires_resources, resource_wait has to be more than 0")]
+    ResourceWait,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum OneOrMany<One, Many> {
@@ -142,8 +486,135 @@ where
 mod test {
     use serde::Deserialize;
     use smallvec::SmallVec;
+    use std::time::Duration;
+
+    use super::{OneOrMany, TaskConfigYaml, Timeout};
+    use crate::{
+        builtin,
+        config::{decode, encode, payload::Payload, view::TaskView, UnknownKernel, UnmetResources, WaitKind},
+        privilege::Privilege,
+    };
+
+    /// Command lines of a task file, with their flags and timeouts
+    fn cmd(yaml: &str) -> Vec<String> {
+        let config = serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config().unwrap().parse_payload().unwrap();
+        let Payload::Service(lines) = config.payload else { panic!("{yaml} is not a service") };
+        lines.iter().map(|line| format!("{line} {:?}", line.timeout())).collect()
+    }
+
+    #[test]
+    fn cmd_shapes() {
+        let expected = [":-env -i None", "echo 'a b' None"];
+        assert_eq!(cmd("name: a\ncmd: |\n  :-env -i\n  echo 'a b'\n"), expected);
+        assert_eq!(cmd("name: a\ncmd:\n  - :-env -i\n  - echo 'a b'\n"), expected);
+        assert_eq!(
+            cmd("name: a\ncmd:\n  - {run: env -i, ignore_env: true, ignore_return: true}\n  - run: echo 'a b'\n"),
+            expected
+        );
+
+        // Quoting across lines only works in the list form
+        assert_eq!(cmd("name: a\ncmd:\n  - |\n    sh -c 'echo a\n    echo b'\n"), ["sh -c 'echo a\necho b' None"]);
+        // Without prefixes, a leading - is part of the program
+        assert_eq!(cmd("name: a\ncmd:\n  - run: -x\n    timeout: 5s\n"), ["'-x' Some(5s)"]);
+        assert!(serde_yaml::from_str::<TaskConfigYaml>("name: a\ncmd:\n  - run: x\n    timeout: soon\n").is_err());
+    }
+
+    #[test]
+    fn timeouts() {
+        for (text, duration) in [
+            ("500ms", Duration::from_millis(500)),
+            ("5s", Duration::from_secs(5)),
+            ("2m", Duration::from_secs(120)),
+            ("1h", Duration::from_secs(3600)),
+        ] {
+            assert_eq!(text.parse::<Timeout>().unwrap(), Timeout(duration));
+        }
+        assert_eq!(Timeout(Duration::from_millis(1500)).to_string(), "1500ms");
+        assert_eq!(Timeout(Duration::from_secs(120)).to_string(), "120s");
+        for invalid in ["5", "s", "5 s", "-5s", "5d"] {
+            invalid.parse::<Timeout>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn after_states() {
+        let read = |yaml: &str| serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config().unwrap();
+        let config =
+            read("name: a\nafter: [db:running, migrate:done, logs:concluded, tmp:exists, feature::fs::run, x:y, db, tmp:done]\n");
+        assert_eq!(config.after, ["db", "migrate", "logs", "tmp", "feature::fs::run", "x:y"]);
+        let expected = [("db", WaitKind::Running), ("logs", WaitKind::Concluded), ("tmp", WaitKind::Exists)];
+        assert_eq!(config.after_states, expected.map(|(name, kind)| (name.to_owned(), kind)));
+        for (name, kind) in [("db", WaitKind::Running), ("migrate", WaitKind::Done), ("x:y", WaitKind::Done)] {
+            assert_eq!(config.wait_kind(name), kind);
+        }
 
-    use super::OneOrMany;
+        // Through the cache and alfad-compile show and back
+        let cached = decode(&encode(crate::config::CACHE_FORMAT, std::slice::from_ref(&config)).unwrap()).unwrap();
+        assert_eq!(cached, std::slice::from_ref(&config));
+        let view = serde_yaml::to_value(TaskView::from(&config)).unwrap();
+        let shown: Vec<String> = serde_yaml::from_value(view["after"].clone()).unwrap();
+        assert_eq!(shown, ["db:running", "feature::fs::run", "logs:concluded", "migrate", "tmp:exists", "x:y"]);
+        let mut parsed = TaskConfigYaml::new("a".to_owned());
+        parsed.after = shown.into();
+        let parsed = parsed.into_config().unwrap();
+        let mut after = config.after.clone();
+        after.sort();
+        assert_eq!(parsed.after, after);
+        assert!(after.iter().all(|name| parsed.wait_kind(name) == config.wait_kind(name)));
+    }
+
+    #[test]
+    fn requires_kernel() {
+        let read = |yaml: &str| serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config();
+        let config = read("name: a\nrequires_kernel:\n  min_version: \"5.10\"\n  config: [CGROUPS]\n  unknown: skip\n").unwrap();
+        let requires = config.requires_kernel.unwrap();
+        assert_eq!((requires.min_version.as_deref(), requires.config), (Some("5.10"), vec!["CGROUPS".to_owned()]));
+        assert_eq!(requires.unknown, UnknownKernel::Skip);
+        let error = read("name: a\nrequires_kernel: {min_version: latest}\n").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid kernel version 'latest', expected something like \"5.10\"");
+    }
+
+    #[test]
+    fn requires_resources() {
+        let read = |yaml: &str| serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config();
+        let config = read("name: a\nrequires_resources:\n  min_free_disk_mb: {path: /var, mb: 50}\n  unmet: skip\n").unwrap();
+        let requires = config.requires_resources.unwrap();
+        assert_eq!(requires.min_free_disk_mb.map(|disk| (disk.path, disk.mb)), Some(("/var".into(), 50)));
+        assert_eq!((requires.min_free_mem_mb, requires.unmet, requires.resource_wait), (None, UnmetResources::Skip, None));
+        let error = read("name: a\nrequires_resources: {min_free_mem_mb: 200, resource_wait: 0s}\n").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid requires_resources, resource_wait has to be more than 0");
+    }
+
+    #[test]
+    fn requires_privileges() {
+        let read = |yaml: &str| serde_yaml::from_str::<TaskConfigYaml>(yaml).map(|config| config.requires_privileges);
+        assert_eq!(read("name: a\n").unwrap(), []);
+        assert_eq!(read("name: a\nrequires_privileges: mount\n").unwrap(), [Privilege::Mount]);
+        assert_eq!(read("name: a\nrequires_privileges: [kill, reboot]\n").unwrap(), [Privilege::Kill, Privilege::Reboot]);
+        assert!(read("name: a\nrequires_privileges: [root]\n").is_err());
+    }
+
+    #[test]
+    fn log_rate_limit() {
+        let read = |yaml: &str| serde_yaml::from_str::<TaskConfigYaml>(yaml).ok().and_then(|config| config.into_config().ok());
+        let config = read("name: a\nlog_rate_limit: {lines: 1000, per: 1s}\nlog_timestamps: true\n").unwrap();
+        let limit = config.log_rate_limit.unwrap();
+        assert_eq!((limit.lines, limit.per.0, config.log_timestamps), (1000, Duration::from_secs(1), true));
+        assert!(read("name: a\nlog_rate_limit: {lines: 0, per: 1s}\n").is_none());
+        assert!(read("name: a\nlog_rate_limit: {lines: 10, per: 0ms}\n").is_none());
+        assert!(read("name: a\nlog_rate_limit: {lines: 10}\n").is_none());
+    }
+
+    #[test]
+    fn protected() {
+        let read = |yaml: &str| serde_yaml::from_str::<TaskConfigYaml>(yaml).unwrap().into_config().unwrap().protected;
+        assert!(!read("name: a\n"));
+        assert!(read("name: a\nprotected: true\n"));
+        assert!(read("name: builtin::ctl::daemon\ncmd: {builtin: ctl::daemon}\n"));
+        assert!(!read("name: builtin::ctl::daemon\ncmd: {builtin: ctl::daemon}\nprotected: false\n"));
+        let builtins = builtin::all().into_iter().filter(|config| config.name.starts_with("builtin::"));
+        assert!(builtins.map(|config| config.into_config().unwrap()).all(|config| config.protected));
+    }
 
     #[test]
     fn one_or_many_from_string() {
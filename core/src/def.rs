@@ -10,17 +10,68 @@ pub const APLT_CTL: &str = "alfad-ctl";
 // Compilation applet
 pub const APLT_COMPILE: &str = "alfad-compile";
 
+// Configuration checker
+pub const APLT_CHECK: &str = "alfad-check";
+
 // The /sbin/init
 pub const APLT_INIT: &str = "init";
 
+// System command applets
+pub const APLT_POWEROFF: &str = "poweroff";
+pub const APLT_REBOOT: &str = "reboot";
+pub const APLT_HALT: &str = "halt";
+pub const APLT_SHUTDOWN: &str = "shutdown";
+
+// Link installer, only available as "alfad install"
+pub const APLT_INSTALL: &str = "install";
+
+// Single task runner, only available as "alfad run"
+pub const APLT_RUN: &str = "run";
+
+// Supervisor of a login session, only available as "alfad user-session"
+pub const APLT_USER_SESSION: &str = "user-session";
+
 /// Sockets
 pub const DIR_RUN: &str = "/run/var";
 
+/// FIFOs the daemon writes replies into
+pub const DIR_REPLY: &str = "/run/var/alfad-reply";
+
+/// Current state of every task, one file per task
+pub const DIR_STATE: &str = "/run/var/alfad/state";
+
+/// Tasks disabled with alfad-ctl, if they are kept across reboots
+pub const FILE_DISABLED: &str = "/var/lib/alfad/disabled";
+
+/// Failure bundles of tasks, one directory each
+pub const DIR_FAILURES: &str = "/var/lib/alfad/failures";
+
 /// Configuration directory
 pub const DIR_CFG: &str = "/etc/alfad";
 
 /// Directory for the run states
 pub const DIR_CFG_D: &str = "/etc/alfad/alfad.d";
 
-/// Configuration bytecode
-pub const FILE_CFG_BT: &str = "alfad.d.cache";
+/// All tasks in a single file instead of DIR_CFG_D, inside DIR_CFG
+pub const FILE_CFG_YAML: &str = "alfad.yaml";
+
+/// Global settings, inside DIR_CFG
+pub const FILE_DEFAULTS: &str = "defaults.yaml";
+
+/// Configuration bytecode, inside DIR_CFG
+pub const FILE_CFG_BT: &str = "alfad.bin";
+
+/// Logged in users and the current runlevel, for `who`
+pub const FILE_UTMP: &str = "/var/run/utmp";
+
+/// Every login, boot and shutdown, for `last`
+pub const FILE_WTMP: &str = "/var/log/wtmp";
+
+/// Kernel log, for errors that must not go unnoticed
+pub const FILE_KMSG: &str = "/dev/kmsg";
+
+/// Source of tasks that are compiled into alfad
+pub const SRC_BUILTIN: &str = "<builtin>";
+
+/// Source of group and feature markers
+pub const SRC_GENERATED: &str = "<generated>";
@@ -0,0 +1,104 @@
+use crate::{config::defaults::Defaults, instance::Instance};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+use strum::Display;
+
+/// What operators want a task to do, as opposed to the state it is in.
+/// The drive loop only (re)starts tasks that are enabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum DesiredState {
+    /// Run as configured
+    #[default]
+    Enabled,
+    /// Kept down until it is started again
+    Stopped,
+    /// Kept down until it is enabled again, across reboots if
+    /// `persist_disabled` is set
+    Disabled,
+}
+
+impl DesiredState {
+    /// The alfad-ctl command that asks for this
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Enabled => "enable",
+            Self::Stopped => "stop",
+            Self::Disabled => "disable",
+        }
+    }
+}
+
+/// Names of the disabled tasks, one per line
+#[derive(Debug)]
+pub struct DisabledFile {
+    path: PathBuf,
+}
+
+impl DisabledFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The file of this instance, if disabled tasks are remembered across reboots
+    pub fn configured() -> Option<Self> {
+        Defaults::load().persist_disabled.then(|| Self::new(Instance::current().disabled_file()))
+    }
+
+    /// A missing file means nothing is disabled
+    pub fn load(&self) -> io::Result<BTreeSet<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => Ok(text.lines().filter(|line| !line.is_empty()).map(str::to_owned).collect()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn set(&self, task: &str, disabled: bool) -> io::Result<()> {
+        let mut tasks = self.load()?;
+        let changed = if disabled { tasks.insert(task.to_owned()) } else { tasks.remove(task) };
+        if changed {
+            self.write(&tasks)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the file atomically, so a power loss never leaves half a list
+    fn write(&self, tasks: &BTreeSet<String>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for task in tasks {
+            writeln!(file, "{task}")?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DisabledFile;
+
+    #[test]
+    fn disable_and_enable() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = DisabledFile::new(dir.path().join("lib/disabled"));
+        assert!(file.load().unwrap().is_empty());
+
+        file.set("dhcp", true).unwrap();
+        file.set("ntp", true).unwrap();
+        file.set("dhcp", true).unwrap();
+        assert_eq!(file.load().unwrap().into_iter().collect::<Vec<_>>(), ["dhcp", "ntp"]);
+
+        file.set("dhcp", false).unwrap();
+        file.set("sshd", false).unwrap();
+        assert_eq!(file.load().unwrap().into_iter().collect::<Vec<_>>(), ["ntp"]);
+    }
+}
@@ -0,0 +1,183 @@
+//! Counters to tell a lost wakeup from lock contention when tasks stall,
+//! only built with the `diagnostics` feature. `alfad-ctl diag <task>`
+//! shows those of a task, `/metrics` of builtin::healthz how often states
+//! are updated.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the buckets of lock waits, the last bucket takes what
+/// is longer
+pub const LOCK_WAIT_BOUNDS: [Duration; 4] =
+    [Duration::from_micros(1), Duration::from_micros(10), Duration::from_micros(100), Duration::from_millis(1)];
+
+/// Kept by every [`crate::state_cell::StateCell`]
+#[derive(Debug, Default)]
+pub struct CellCounters {
+    waker_registrations: AtomicU64,
+    lock_waits: [AtomicU64; LOCK_WAIT_BOUNDS.len() + 1],
+}
+
+impl CellCounters {
+    pub const fn new() -> Self {
+        Self {
+            waker_registrations: AtomicU64::new(0),
+            lock_waits: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    pub fn registered_waker(&self) {
+        self.waker_registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a wait of `wait` for the lock, to change the value
+    pub fn waited_for_lock(&self, wait: Duration) {
+        let bucket = LOCK_WAIT_BOUNDS.iter().position(|bound| wait < *bound).unwrap_or(LOCK_WAIT_BOUNDS.len());
+        self.lock_waits[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn waker_registrations(&self) -> u64 {
+        self.waker_registrations.load(Ordering::Relaxed)
+    }
+
+    /// Waits by bucket, see [`LOCK_WAIT_BOUNDS`]
+    pub fn lock_waits(&self) -> Vec<u64> {
+        self.lock_waits.iter().map(|count| count.load(Ordering::Relaxed)).collect()
+    }
+}
+
+/// What `alfad-ctl diag` shows for a task
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskDiagnostics {
+    /// Changes of the state since alfad started
+    pub transitions: u64,
+    /// Times a waiter for the state registered to be woken
+    pub waker_registrations: u64,
+    /// Changes of the state by how long they waited for the lock, see
+    /// [`LOCK_WAIT_BOUNDS`]
+    pub lock_waits: Vec<u64>,
+}
+
+impl Display for TaskDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "transitions: {}", self.transitions)?;
+        writeln!(f, "waker registrations: {}", self.waker_registrations)?;
+        write!(f, "lock waits:")?;
+        for (index, count) in self.lock_waits.iter().enumerate() {
+            match LOCK_WAIT_BOUNDS.get(index) {
+                Some(bound) => write!(f, "\n  < {bound:?}: {count}")?,
+                None => write!(f, "\n  >= {:?}: {count}", LOCK_WAIT_BOUNDS[LOCK_WAIT_BOUNDS.len() - 1])?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls of [`crate::task::TaskContext::update_state`] and the like, of
+/// all tasks
+pub static UPDATES: Rate = Rate::new();
+
+/// Counts events, in total and per second
+#[derive(Debug, Default)]
+pub struct Rate {
+    total: AtomicU64,
+    /// Counts of the current second and the one before, by seconds since
+    /// the first event
+    seconds: Mutex<(u64, u64, u64)>,
+}
+
+impl Rate {
+    pub const fn new() -> Self {
+        Self { total: AtomicU64::new(0), seconds: Mutex::new((0, 0, 0)) }
+    }
+
+    pub fn record(&self) {
+        self.record_at(elapsed());
+    }
+
+    fn record_at(&self, second: u64) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let mut seconds = self.seconds.lock().unwrap();
+        let (current, count, _) = *seconds;
+        if second != current {
+            let previous = if second == current + 1 { count } else { 0 };
+            *seconds = (second, 0, previous);
+        }
+        seconds.1 += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Events in the last full second
+    pub fn per_second(&self) -> u64 {
+        self.per_second_at(elapsed())
+    }
+
+    fn per_second_at(&self, second: u64) -> u64 {
+        let (current, count, previous) = *self.seconds.lock().unwrap();
+        match second {
+            second if second == current => previous,
+            second if second == current + 1 => count,
+            _ => 0,
+        }
+    }
+}
+
+/// Whole seconds since the first call
+fn elapsed() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs()
+}
+
+/// Body of `/metrics`, in the Prometheus text format
+pub fn metrics() -> String {
+    format!(
+        "# TYPE alfad_state_updates_total counter\nalfad_state_updates_total {}\n\
+         # TYPE alfad_state_updates_per_second gauge\nalfad_state_updates_per_second {}\n",
+        UPDATES.total(),
+        UPDATES.per_second()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CellCounters, Rate, TaskDiagnostics};
+    use std::time::Duration;
+
+    #[test]
+    fn lock_wait_buckets() {
+        let counters = CellCounters::new();
+        for wait in [0, 5, 50, 500, 5000, 50_000] {
+            counters.waited_for_lock(Duration::from_micros(wait));
+        }
+        assert_eq!(counters.lock_waits(), [1, 1, 1, 1, 2]);
+
+        let diagnostics = TaskDiagnostics { transitions: 3, waker_registrations: 2, lock_waits: counters.lock_waits() };
+        assert_eq!(
+            diagnostics.to_string(),
+            "transitions: 3\nwaker registrations: 2\nlock waits:\n  < 1µs: 1\n  < 10µs: 1\n  < 100µs: 1\n  < 1ms: 1\n  >= 1ms: 2"
+        );
+    }
+
+    #[test]
+    fn rate() {
+        let rate = Rate::new();
+        (0..3).for_each(|_| rate.record_at(10));
+        assert_eq!((rate.per_second_at(10), rate.per_second_at(11), rate.per_second_at(12)), (0, 3, 0));
+        rate.record_at(11);
+        assert_eq!((rate.per_second_at(11), rate.per_second_at(12)), (3, 1));
+        // A quiet second in between
+        rate.record_at(13);
+        assert_eq!(rate.per_second_at(13), 0);
+        assert_eq!(rate.total(), 5);
+    }
+}
@@ -0,0 +1,386 @@
+//! `alfad-ctl doctor`: checks of the running system for states that should
+//! not come about, each with what is likely to fix it. The checks only read,
+//! from a single pass over the tasks so they all see the same states.
+
+use crate::{
+    builtin::state,
+    config::{defaults::Defaults, diff::ConfigDiff, name, Quorum, TaskConfig},
+    task::{ChildProcess, ContextMap, ExitReason, ProcessTable, TaskState, WaitingFor},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    fs,
+    path::PathBuf,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Severity {
+    /// Worth knowing, nothing is broken
+    Info,
+    Warning,
+    /// A task is stuck in a state it does not leave by itself
+    Error,
+}
+
+/// Something `alfad-ctl doctor` found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Task the finding is about, if it is about one
+    pub task: Option<String>,
+    pub message: String,
+    /// What is likely to fix it, usually an alfad-ctl command
+    pub remedy: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+        if let Some(task) = &self.task {
+            write!(f, "{task}: ")?;
+        }
+        write!(f, "{}\n  fix: {}", self.message, self.remedy)
+    }
+}
+
+/// What the checks look at besides the tasks
+pub struct Surroundings<'a> {
+    pub processes: &'a dyn ProcessTable,
+    /// The task files as they are on disk now
+    pub on_disk: &'a [TaskConfig],
+    /// The state directory and the names of the files in it, if alfad keeps one
+    pub state_dir: Option<(PathBuf, Vec<String>)>,
+}
+
+/// The state directory of the builtin, `None` unless `state_dir` is set
+/// and it could be read
+pub fn state_dir() -> Option<(PathBuf, Vec<String>)> {
+    if !Defaults::load().state_dir {
+        return None;
+    }
+    let dir = state::state_path();
+    let entries = fs::read_dir(&dir).ok()?;
+    let files = entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect();
+    Some((dir, files))
+}
+
+/// A task as the single pass read it
+struct Observed<'a> {
+    config: &'a TaskConfig,
+    state: TaskState,
+    child: Option<ChildProcess>,
+    waiting: Option<WaitingFor>,
+}
+
+type Tasks<'a> = BTreeMap<&'a str, Observed<'a>>;
+
+/// Every finding, the most severe first
+pub fn examine(context: ContextMap<'_>, around: &Surroundings) -> Vec<Finding> {
+    let tasks: Tasks = context
+        .0
+        .iter()
+        .map(|(name, task)| {
+            let observed = Observed {
+                config: &task.config,
+                state: task.state_now(),
+                child: task.child.get(),
+                waiting: task.waiting.lock().unwrap().clone(),
+            };
+            (*name, observed)
+        })
+        .collect();
+    let mut findings = lost_processes(&tasks, around.processes);
+    findings.extend(missed_wakeups(&tasks));
+    findings.extend(unbacked_markers(&tasks));
+    if let Some((dir, files)) = &around.state_dir {
+        findings.extend(stale_state_files(&tasks, dir, files));
+    }
+    findings.extend(config_drift(&tasks, around.on_disk));
+    // Stable, so the findings of a severity stay in the order of the checks
+    findings.sort_by_key(|finding| Reverse(finding.severity));
+    findings
+}
+
+/// The findings as alfad-ctl prints them
+pub fn report(findings: &[Finding]) -> String {
+    if findings.is_empty() {
+        return "No problems found".to_owned();
+    }
+    findings.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n")
+}
+
+fn finding(severity: Severity, task: &str, message: String, remedy: String) -> Finding {
+    Finding { severity, task: Some(task.to_owned()), message, remedy }
+}
+
+/// Running with a process that is gone, its driver missed the exit
+fn lost_processes(tasks: &Tasks, processes: &dyn ProcessTable) -> Vec<Finding> {
+    tasks
+        .iter()
+        .filter(|(_, task)| task.state.is_running())
+        .filter_map(|(name, task)| Some((name, task.child?)))
+        .filter(|(_, child)| !child.is_alive(processes))
+        .map(|(name, child)| {
+            let message = format!("Running, but its process {} is gone", child.pid);
+            finding(Severity::Error, name, message, format!("alfad-ctl restart {name}"))
+        })
+        .collect()
+}
+
+/// Waiting for a task that is where it is waited for already. Waiting for
+/// a task to stop is left out, it may be on its way down.
+fn missed_wakeups(tasks: &Tasks) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for (name, task) in tasks.iter().filter(|(_, task)| task.state.is_waiting()) {
+        let Some(waiting) = task.waiting.as_ref().filter(|waiting| !waiting.stop) else {
+            continue;
+        };
+        let Some(dependency) = tasks.get(waiting.task.as_str()) else {
+            continue;
+        };
+        let satisfied = match waiting.companion {
+            true => dependency.state.is_running(),
+            false => dependency.state.has_reached(waiting.until),
+        };
+        if satisfied {
+            let message = format!("Waiting for {}, which is {} already", waiting.task, dependency.state.name());
+            // Any state change of the dependency wakes its waiters
+            findings.push(finding(Severity::Warning, name, message, format!("alfad-ctl restart {}", waiting.task)));
+        }
+    }
+    findings
+}
+
+/// Markers that are Done although too many of their members failed
+fn unbacked_markers(tasks: &Tasks) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let done = |task: &&Observed| task.config.payload.is_marker() && task.state == TaskState::Concluded(ExitReason::Done);
+    for (name, marker) in tasks.iter().filter(|(_, task)| done(task)) {
+        let members = &marker.config.after;
+        let failed: Vec<_> = members
+            .iter()
+            .filter(|member| {
+                tasks.get(member.as_str()).is_some_and(|task| task.state == TaskState::Concluded(ExitReason::Failed))
+            })
+            .collect();
+        let unbacked = match marker.config.quorum {
+            Quorum::All => !failed.is_empty(),
+            Quorum::Any => !failed.is_empty() && failed.len() == members.len(),
+        };
+        if unbacked {
+            let list = failed.iter().map(|member| member.as_str()).collect::<Vec<_>>().join(", ");
+            let remedy = failed.iter().map(|member| format!("alfad-ctl restart {member}")).collect::<Vec<_>>().join("; ");
+            findings.push(finding(Severity::Warning, name, format!("Done, but {list} failed"), remedy));
+        }
+    }
+    findings
+}
+
+/// Files in the state directory of tasks that are not loaded. The
+/// temporary files written next to them are skipped.
+fn stale_state_files(tasks: &Tasks, dir: &std::path::Path, files: &[String]) -> Vec<Finding> {
+    let known: HashSet<_> = tasks.keys().map(|name| name::file_name(name)).collect();
+    files
+        .iter()
+        .filter(|file| !file.starts_with('.') && !known.contains(*file))
+        .map(|file| {
+            let path = dir.join(file);
+            Finding {
+                severity: Severity::Info,
+                task: None,
+                message: format!("{} belongs to no task", path.display()),
+                remedy: format!("rm {}", path.display()),
+            }
+        })
+        .collect()
+}
+
+/// Differences between the loaded tasks and their files
+fn config_drift(tasks: &Tasks, on_disk: &[TaskConfig]) -> Vec<Finding> {
+    let diff = ConfigDiff::new(tasks.values().map(|task| task.config), on_disk);
+    let remedy = || "reboot, changed task files take effect once alfad restarts".to_owned();
+    let added = diff.added.iter().map(|task| (task, "In the task files, but not loaded".to_owned()));
+    let removed = diff.removed.iter().map(|task| (task, "Loaded, but no longer in the task files".to_owned()));
+    let changed = diff.changed.iter().map(|change| (&change.task, format!("Changed in its file: {}", change.fields.join(", "))));
+    added.chain(removed).chain(changed).map(|(task, message)| finding(Severity::Info, task, message, remedy())).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{examine, report, Finding, Severity, Surroundings};
+    use crate::{
+        config::{name, payload::Payload, Quorum, TaskConfig, WaitKind},
+        task::{ChildProcess, ContextMap, ExitReason, ProcessTable, TaskContext, TaskState, WaitingFor},
+    };
+    use std::{collections::HashMap, path::PathBuf};
+
+    const DONE: TaskState = TaskState::Concluded(ExitReason::Done);
+    const FAILED: TaskState = TaskState::Concluded(ExitReason::Failed);
+
+    /// Only the pids given are alive
+    struct Alive(Vec<i32>);
+
+    impl ProcessTable for Alive {
+        fn start_time(&self, pid: i32) -> Option<u64> {
+            self.0.contains(&pid).then_some(1)
+        }
+
+        fn cmdline(&self, _: i32) -> Option<String> {
+            None
+        }
+
+        fn is_zombie(&self, _: i32) -> bool {
+            false
+        }
+
+        fn children(&self, _: i32) -> Vec<i32> {
+            Vec::new()
+        }
+    }
+
+    fn config(name: &str) -> TaskConfig {
+        TaskConfig::new(name.to_owned())
+    }
+
+    fn marker(name: &str, members: &[&str], quorum: Quorum) -> TaskConfig {
+        let mut config = config(name);
+        config.payload = Payload::Marker;
+        config.after = members.iter().map(|member| member.to_string()).collect();
+        config.quorum = quorum;
+        config
+    }
+
+    fn map(configs: &[TaskConfig]) -> ContextMap<'static> {
+        let tasks = configs.iter().map(|config| (&*config.name.clone().leak(), TaskContext::new(config.clone())));
+        ContextMap(Box::leak(Box::new(tasks.collect::<HashMap<_, _>>())))
+    }
+
+    fn set(map: ContextMap<'_>, task: &str, state: TaskState) {
+        map.0[task].update_state_silently(state);
+    }
+
+    /// What the checks find about the tasks alone
+    fn findings(map: ContextMap<'_>, alive: &[i32]) -> Vec<(Severity, Option<String>, String)> {
+        let configs: Vec<_> = map.0.values().map(|task| task.config.clone()).collect();
+        let around = Surroundings { processes: &Alive(alive.to_vec()), on_disk: &configs, state_dir: None };
+        examine(map, &around).into_iter().map(|finding| (finding.severity, finding.task, finding.message)).collect()
+    }
+
+    #[test]
+    fn healthy() {
+        let map = map(&[config("db"), config("web"), marker("group::app", &["db", "web"], Quorum::All)]);
+        set(map, "db", DONE);
+        set(map, "web", TaskState::Running(0));
+        map.0["web"].child.set(Some(ChildProcess { pid: 42, start_time: Some(1) }));
+        set(map, "group::app", DONE);
+        assert_eq!(findings(map, &[42]), []);
+    }
+
+    #[test]
+    fn lost_process() {
+        let map = map(&[config("web"), config("ctl")]);
+        set(map, "web", TaskState::Running(0));
+        map.0["web"].child.set(Some(ChildProcess { pid: 42, start_time: Some(1) }));
+        // Builtins run without a process
+        set(map, "ctl", TaskState::Running(0));
+        let error = (Severity::Error, Some("web".to_owned()), "Running, but its process 42 is gone".to_owned());
+        assert_eq!(findings(map, &[]), [error]);
+        // A new process with the same pid is no better
+        map.0["web"].child.set(Some(ChildProcess { pid: 42, start_time: Some(2) }));
+        assert_eq!(findings(map, &[42]).len(), 1);
+    }
+
+    #[test]
+    fn missed_wakeup() {
+        let map = map(&[config("db"), config("web"), config("app"), config("backup")]);
+        let waiting_until = |task: &str, on: &str, companion, until, stop| {
+            set(map, task, TaskState::Waiting);
+            *map.0[task].waiting.lock().unwrap() =
+                Some(WaitingFor { task: on.to_owned(), companion, until, respawn: false, stop });
+        };
+        let waiting = |task: &str, on: &str, companion, stop| waiting_until(task, on, companion, WaitKind::Done, stop);
+        set(map, "db", DONE);
+        waiting("web", "db", false, false);
+        waiting("backup", "db", false, true);
+        waiting("app", "web", true, false);
+        let missed = (Severity::Warning, Some("web".to_owned()), "Waiting for db, which is Done already".to_owned());
+        assert_eq!(findings(map, &[]), [missed]);
+
+        // Running is what a companion waits for, Done is not
+        set(map, "web", TaskState::Running(0));
+        assert_eq!(findings(map, &[])[0].1.as_deref(), Some("app"));
+        set(map, "web", DONE);
+        assert_eq!(findings(map, &[]), []);
+
+        // Or whatever `after` asks for
+        waiting_until("app", "web", false, WaitKind::Concluded, false);
+        assert_eq!(findings(map, &[])[0].2, "Waiting for web, which is Done already");
+        set(map, "web", TaskState::Running(0));
+        assert_eq!(findings(map, &[]), []);
+        waiting_until("app", "web", false, WaitKind::Running, false);
+        assert_eq!(findings(map, &[])[0].1.as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn unbacked_marker() {
+        let map = map(&[
+            config("a"),
+            config("b"),
+            marker("group::all", &["a", "b"], Quorum::All),
+            marker("feature::any", &["a", "b"], Quorum::Any),
+        ]);
+        set(map, "a", FAILED);
+        set(map, "b", DONE);
+        set(map, "group::all", DONE);
+        set(map, "feature::any", DONE);
+        let unbacked = (Severity::Warning, Some("group::all".to_owned()), "Done, but a failed".to_owned());
+        assert_eq!(findings(map, &[]), [unbacked]);
+
+        set(map, "b", FAILED);
+        let markers: Vec<_> = findings(map, &[]).into_iter().filter_map(|finding| finding.1).collect();
+        assert_eq!(markers, ["feature::any", "group::all"]);
+    }
+
+    #[test]
+    fn state_files_and_drift() {
+        let map = map(&[config("web"), config("group::a b")]);
+        let mut changed = config("web");
+        changed.description = Some("Web server".to_owned());
+        let on_disk = [changed, config("group::a b"), config("db")];
+        // Names are escaped for the file system
+        let files = vec![name::file_name("web"), name::file_name("group::a b"), ".web.tmp".to_owned(), "gone".to_owned()];
+        let around =
+            Surroundings { processes: &Alive(vec![]), on_disk: &on_disk, state_dir: Some((PathBuf::from("/state"), files)) };
+        let found = examine(map, &around);
+        let messages: Vec<_> = found.iter().map(|finding| finding.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "info: /state/gone belongs to no task\n  fix: rm /state/gone",
+                "info: db: In the task files, but not loaded\n  fix: reboot, changed task files take effect once alfad restarts",
+                "info: web: Changed in its file: description\n  fix: reboot, changed task files take effect once alfad restarts",
+            ]
+        );
+    }
+
+    #[test]
+    fn most_severe_first() {
+        let finding = |severity| Finding { severity, task: None, message: String::new(), remedy: String::new() };
+        let json = serde_json::to_string(&finding(Severity::Warning)).unwrap();
+        assert_eq!(json, r#"{"severity":"warning","task":null,"message":"","remedy":""}"#);
+        assert_eq!(report(&[]), "No problems found");
+
+        let map = map(&[config("web"), config("db")]);
+        set(map, "web", TaskState::Running(0));
+        map.0["web"].child.set(Some(ChildProcess { pid: 42, start_time: None }));
+        let on_disk = [config("web")];
+        let around = Surroundings { processes: &Alive(vec![]), on_disk: &on_disk, state_dir: None };
+        let severities: Vec<_> = examine(map, &around).iter().map(|finding| finding.severity).collect();
+        assert_eq!(severities, [Severity::Error, Severity::Info]);
+    }
+}
@@ -0,0 +1,184 @@
+use nix::{
+    fcntl::{fcntl, open, FcntlArg, OFlag},
+    mount::{mount, MsFlags},
+    sys::stat::{umask, Mode},
+    unistd::{close, dup2, getpid},
+};
+use std::{env, fs, io, path::Path};
+use tracing::{info, warn};
+
+pub const DEV: &str = "/dev";
+pub const CONSOLE: &str = "/dev/console";
+/// PATH for pid 1 and everything it starts, the kernel passes none
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+pub const DEFAULT_UMASK: u32 = 0o022;
+
+/// What pid 1 needs from the system before anything else runs
+pub trait EarlySystem {
+    fn exists(&self, path: &Path) -> bool;
+    fn mount_devtmpfs(&self, target: &Path) -> io::Result<()>;
+    /// Whether fd 0 is open at all
+    fn has_stdio(&self) -> bool;
+    /// Open `console` and make it fds 0, 1 and 2
+    fn attach_console(&self, console: &Path) -> io::Result<()>;
+    fn var(&self, key: &str) -> Option<String>;
+    fn set_var(&self, key: &str, value: &str);
+    fn umask(&self, mask: u32);
+}
+
+/// The real system, only to be touched as pid 1
+pub struct Linux;
+
+impl EarlySystem for Linux {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn mount_devtmpfs(&self, target: &Path) -> io::Result<()> {
+        fs::create_dir_all(target)?;
+        mount(Some("devtmpfs"), target, Some("devtmpfs"), MsFlags::MS_NOSUID, Some("mode=0755"))?;
+        Ok(())
+    }
+
+    fn has_stdio(&self) -> bool {
+        fcntl(0, FcntlArg::F_GETFD).is_ok()
+    }
+
+    fn attach_console(&self, console: &Path) -> io::Result<()> {
+        let fd = open(console, OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty())?;
+        for target in 0..=2 {
+            dup2(fd, target)?;
+        }
+        if fd > 2 {
+            close(fd)?;
+        }
+        Ok(())
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        env::var(key).ok().filter(|value| !value.is_empty())
+    }
+
+    fn set_var(&self, key: &str, value: &str) {
+        env::set_var(key, value);
+    }
+
+    fn umask(&self, mask: u32) {
+        umask(Mode::from_bits_truncate(mask));
+    }
+}
+
+/// Give pid 1 a /dev, a console on stdio, a PATH and a umask. Nothing here
+/// is fatal, a warning on a console nobody reads beats a kernel panic.
+pub fn prepare(system: &impl EarlySystem) {
+    let console = Path::new(CONSOLE);
+    let missing = !system.exists(console);
+    if missing {
+        match system.mount_devtmpfs(Path::new(DEV)) {
+            Ok(()) => info!("Mounted devtmpfs on {DEV}"),
+            Err(error) => warn!("Could not mount devtmpfs on {DEV}: {error}"),
+        }
+    }
+    if missing || !system.has_stdio() {
+        if let Err(error) = system.attach_console(console) {
+            warn!("Could not open {CONSOLE}: {error}");
+        }
+    }
+    if system.var("PATH").is_none() {
+        system.set_var("PATH", DEFAULT_PATH);
+    }
+    system.umask(DEFAULT_UMASK);
+}
+
+/// [`prepare`] the real system, if this is pid 1
+pub fn bootstrap() {
+    if getpid().as_raw() == 1 {
+        prepare(&Linux);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{prepare, EarlySystem, CONSOLE, DEFAULT_PATH};
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        io,
+        path::{Path, PathBuf},
+    };
+
+    #[derive(Default)]
+    struct FakeSystem {
+        files: RefCell<Vec<PathBuf>>,
+        stdio: bool,
+        mount_fails: bool,
+        env: RefCell<HashMap<String, String>>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl EarlySystem for FakeSystem {
+        fn exists(&self, path: &Path) -> bool {
+            self.files.borrow().iter().any(|file| file == path)
+        }
+
+        fn mount_devtmpfs(&self, target: &Path) -> io::Result<()> {
+            self.calls.borrow_mut().push(format!("mount {}", target.display()));
+            if self.mount_fails {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            self.files.borrow_mut().push(CONSOLE.into());
+            Ok(())
+        }
+
+        fn has_stdio(&self) -> bool {
+            self.stdio
+        }
+
+        fn attach_console(&self, console: &Path) -> io::Result<()> {
+            self.calls.borrow_mut().push(format!("console {}", console.display()));
+            match self.exists(console) {
+                true => Ok(()),
+                false => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn var(&self, key: &str) -> Option<String> {
+            self.env.borrow().get(key).cloned()
+        }
+
+        fn set_var(&self, key: &str, value: &str) {
+            self.env.borrow_mut().insert(key.to_owned(), value.to_owned());
+        }
+
+        fn umask(&self, mask: u32) {
+            self.calls.borrow_mut().push(format!("umask {mask:03o}"));
+        }
+    }
+
+    #[test]
+    fn without_dev() {
+        let system = FakeSystem::default();
+        prepare(&system);
+        assert_eq!(*system.calls.borrow(), ["mount /dev", "console /dev/console", "umask 022"]);
+        assert_eq!(system.var("PATH").unwrap(), DEFAULT_PATH);
+
+        // Carries on without a console
+        let system = FakeSystem { mount_fails: true, ..Default::default() };
+        prepare(&system);
+        assert_eq!(*system.calls.borrow(), ["mount /dev", "console /dev/console", "umask 022"]);
+    }
+
+    #[test]
+    fn with_dev() {
+        let system = FakeSystem { files: RefCell::new(vec![CONSOLE.into()]), stdio: true, ..Default::default() };
+        system.set_var("PATH", "/bin");
+        prepare(&system);
+        assert_eq!(*system.calls.borrow(), ["umask 022"]);
+        assert_eq!(system.var("PATH").unwrap(), "/bin");
+
+        // The kernel found no console to hand over, but devtmpfs is there
+        let system = FakeSystem { files: RefCell::new(vec![CONSOLE.into()]), ..Default::default() };
+        prepare(&system);
+        assert_eq!(*system.calls.borrow(), ["console /dev/console", "umask 022"]);
+    }
+}
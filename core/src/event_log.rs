@@ -0,0 +1,192 @@
+//! Every state change as a line of JSON, appended to the file `event_log`
+//! in the defaults names. `alfad-compile analyze` turns it into the boot
+//! report on any machine, see [`crate::analyze`]. The regenerations of
+//! the task cache go there as well.
+
+use crate::task::{ContextMap, StateEvent};
+use serde::{Deserialize, Serialize};
+use smol::{
+    channel::{self, Receiver},
+    future, Timer,
+};
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime},
+};
+use tracing::warn;
+
+/// A regeneration of the task cache, see [`crate::config::autogen`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEvent {
+    pub cache: PathBuf,
+    pub at: SystemTime,
+    /// What was written, or why nothing was
+    pub outcome: Result<String, String>,
+}
+
+impl Display for CacheEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            Ok(stats) => write!(f, "{} written for the next boot, {stats}", self.cache.display()),
+            Err(error) => write!(f, "{} not written: {error}", self.cache.display()),
+        }
+    }
+}
+
+/// Append `event` to the event log at `path`, in one write so it does not
+/// end up within a state change of the recorder
+pub fn append(path: &Path, event: &CacheEvent) -> io::Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)
+}
+
+/// The changes the recorder reads, and a channel that closes once it is done
+static RECORDER: Mutex<Option<(Receiver<StateEvent>, Receiver<()>)>> = Mutex::new(None);
+
+/// Append the state changes of `changes` to `path` until there are no more.
+/// The file is opened once it can be, the file system may not be mounted
+/// yet when the boot starts.
+pub async fn record(path: &Path, changes: Receiver<StateEvent>) -> io::Result<()> {
+    let mut pending = Vec::new();
+    let mut file = None;
+    while let Ok(change) = changes.recv().await {
+        pending.push(change);
+        if file.is_none() {
+            file = OpenOptions::new().create(true).append(true).open(path).ok().map(BufWriter::new);
+        }
+        let Some(file) = file.as_mut() else {
+            continue;
+        };
+        for event in pending.drain(..) {
+            serde_json::to_writer(&mut *file, &event)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+    }
+    match file {
+        Some(file) => file.get_ref().sync_data(),
+        None => Ok(()),
+    }
+}
+
+/// Log the state changes of all tasks of `context_map` to `path`. Call
+/// before the tasks are spawned, so no state change is missed.
+pub fn start(path: PathBuf, context_map: ContextMap<'static>) {
+    let changes = context_map.subscribe();
+    let (finished, done) = channel::bounded(1);
+    *RECORDER.lock().unwrap() = Some((changes.clone(), done));
+    thread::spawn(move || {
+        if let Err(error) = smol::block_on(record(&path, changes)) {
+            warn!(path = %path.display(), %error, "Could not write the event log");
+        }
+        drop(finished);
+    });
+}
+
+/// Stop taking state changes and wait until those taken so far are on
+/// disk, at most for `timeout`. Returns false if the log is not closed by
+/// then.
+pub async fn close(timeout: Duration) -> bool {
+    let Some((changes, done)) = RECORDER.lock().unwrap().take() else {
+        return true;
+    };
+    // What is queued is still received
+    changes.close();
+    let closed = async {
+        let _ = done.recv().await;
+        true
+    };
+    future::or(closed, async {
+        Timer::after(timeout).await;
+        false
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append, close, record, start, CacheEvent};
+    use crate::task::{ContextMap, ExitReason, StateEvent, TaskContext, TaskState};
+    use smol::channel;
+    use std::{
+        collections::HashMap,
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        fs::write(&path, "").unwrap();
+        let at = SystemTime::UNIX_EPOCH + Duration::from_millis(1500);
+        let events = [
+            StateEvent { task: "mount".into(), state: TaskState::Running(0), at, reason: None },
+            StateEvent {
+                task: "dhcp".into(),
+                state: TaskState::Concluded(ExitReason::Deactivated),
+                at,
+                reason: Some("deactivated by the operator".into()),
+            },
+        ];
+
+        let (sender, changes) = channel::unbounded();
+        events.iter().for_each(|event| sender.try_send(event.clone()).unwrap());
+        drop(sender);
+        smol::block_on(record(&path, changes)).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text.lines().next().unwrap(),
+            r#"{"task":"mount","state":{"Running":0},"at":{"secs_since_epoch":1,"nanos_since_epoch":500000000}}"#
+        );
+        let read: Vec<StateEvent> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(read, events);
+    }
+
+    #[test]
+    fn closing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([("halt", TaskContext::default())]))));
+        start(path.clone(), map);
+        smol::block_on(async {
+            map.0["halt"].update_state(TaskState::Running(0)).await;
+            map.0["halt"].update_state(TaskState::Concluded(ExitReason::Done)).await;
+            assert!(close(Duration::from_secs(10)).await);
+        });
+        // Everything up to the close is written, nothing after it
+        let states = || fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(states(), 2);
+        smol::block_on(map.0["halt"].update_state(TaskState::Waiting));
+        assert_eq!(states(), 2);
+        assert!(smol::block_on(close(Duration::ZERO)));
+    }
+
+    #[test]
+    fn cache_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(2);
+        let written = CacheEvent { cache: "/etc/alfad/alfad.bin".into(), at, outcome: Ok("3 tasks, 120 bytes".into()) };
+        let failed = CacheEvent { outcome: Err("Read-only file system".into()), ..written.clone() };
+        append(&path, &written).unwrap();
+        append(&path, &failed).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text.lines().next().unwrap(),
+            r#"{"cache":"/etc/alfad/alfad.bin","at":{"secs_since_epoch":2,"nanos_since_epoch":0},"outcome":{"Ok":"3 tasks, 120 bytes"}}"#
+        );
+        let read: Vec<CacheEvent> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(read, [written.clone(), failed.clone()]);
+        assert_eq!(written.to_string(), "/etc/alfad/alfad.bin written for the next boot, 3 tasks, 120 bytes");
+        assert_eq!(failed.to_string(), "/etc/alfad/alfad.bin not written: Read-only file system");
+    }
+}
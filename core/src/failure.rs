@@ -0,0 +1,251 @@
+//! Failure bundles, what is known about a task at the moment it failed:
+//! alfad's last log lines about it, its effective configuration, the state
+//! changes of the boot and how its last command line ended. Every bundle is
+//! a directory `<task>-<milliseconds since the epoch>` below the failures
+//! directory of the instance, only the newest ones of each task are kept.
+
+use crate::{
+    config::{defaults::Defaults, name, view::TaskView},
+    instance::Instance,
+    task::{ContextMap, StateEvent, TaskContext},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Write},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    info, warn, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Lines of alfad's log kept per task
+pub const LOG_LINES: usize = 100;
+
+fn logs() -> &'static Mutex<HashMap<String, VecDeque<String>>> {
+    static LOGS: OnceLock<Mutex<HashMap<String, VecDeque<String>>>> = OnceLock::new();
+    LOGS.get_or_init(Default::default)
+}
+
+/// The last [`LOG_LINES`] lines logged about `task`, oldest first
+pub fn log_lines(task: &str) -> Vec<String> {
+    logs().lock().unwrap().get(task).map(|lines| lines.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// Keeps the events with a `task` field for failure bundles
+pub struct LogRing;
+
+impl<S: Subscriber> Layer<S> for LogRing {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let Some(task) = fields.task else {
+            return;
+        };
+        let line = format!("{} {} {}{}", seconds(SystemTime::now()), event.metadata().level(), fields.message, fields.rest);
+        let mut logs = logs().lock().unwrap();
+        let lines = logs.entry(task).or_default();
+        if lines.len() == LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+#[derive(Default)]
+struct Fields {
+    task: Option<String>,
+    message: String,
+    rest: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "task" => self.task = Some(value.to_owned()),
+            name => _ = write!(self.rest, " {name}={value}"),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            "task" => self.task = Some(format!("{value:?}")),
+            name => _ = write!(self.rest, " {name}={value:?}"),
+        }
+    }
+}
+
+/// Seconds since the epoch, to the millisecond
+fn seconds(at: SystemTime) -> String {
+    let since = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}", since.as_secs(), since.subsec_millis())
+}
+
+/// What goes into the directory of a failure
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub task: String,
+    pub at: SystemTime,
+    /// The configuration as alfad-compile dump shows it
+    pub config: String,
+    pub log: Vec<String>,
+    /// State changes of all tasks, oldest first
+    pub events: Vec<StateEvent>,
+    pub exit: Option<String>,
+}
+
+impl Bundle {
+    /// Everything about `context` that is in memory right now
+    pub fn of(context: &TaskContext, context_map: ContextMap<'_>) -> Self {
+        let mut events: Vec<_> = context_map.0.values().flat_map(TaskContext::history).collect();
+        events.sort_by(|a, b| (a.at, &a.task).cmp(&(b.at, &b.task)));
+        Self {
+            task: context.config.name.clone(),
+            at: SystemTime::now(),
+            config: serde_yaml::to_string(&TaskView::from(&*context.config)).unwrap_or_else(|error| format!("# {error}\n")),
+            log: log_lines(&context.config.name),
+            events,
+            exit: context.exit.lock().unwrap().clone(),
+        }
+    }
+
+    /// Name of the directory, and the prefix shared by all bundles of the
+    /// task
+    fn prefix(&self) -> String {
+        format!("{}-", name::file_name(&self.task))
+    }
+
+    /// Write the bundle to a new directory below `dir` and remove the
+    /// oldest ones of the task beyond `keep`
+    pub fn write(&self, dir: &Path, keep: usize) -> io::Result<PathBuf> {
+        let millis = self.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let path = dir.join(format!("{}{millis}", self.prefix()));
+        fs::create_dir_all(dir)?;
+        fs::create_dir(&path)?;
+        fs::write(path.join("config.yaml"), &self.config)?;
+        fs::write(path.join("log"), self.log.iter().map(|line| format!("{line}\n")).collect::<String>())?;
+        let events: String = self
+            .events
+            .iter()
+            .map(|event| match &event.reason {
+                Some(reason) => format!("{} {} {:?}: {reason}\n", seconds(event.at), event.task, event.state),
+                None => format!("{} {} {:?}\n", seconds(event.at), event.task, event.state),
+            })
+            .collect();
+        fs::write(path.join("events"), events)?;
+        fs::write(path.join("exit"), format!("{}\n", self.exit.as_deref().unwrap_or("unknown")))?;
+        self.prune(dir, keep.max(1))?;
+        Ok(path)
+    }
+
+    /// Remove all but the newest `keep` bundles of the task
+    fn prune(&self, dir: &Path, keep: usize) -> io::Result<()> {
+        let prefix = self.prefix();
+        let mut bundles: Vec<(u128, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let millis = entry.file_name().to_str()?.strip_prefix(&prefix)?.parse().ok()?;
+                Some((millis, entry.path()))
+            })
+            .collect();
+        bundles.sort();
+        let old = bundles.len().saturating_sub(keep);
+        bundles.into_iter().take(old).try_for_each(|(_, path)| fs::remove_dir_all(path))
+    }
+}
+
+/// Write a bundle for `context` if the task or defaults.yaml asks for one.
+/// Only memory is read here, the disk is left to a thread so the failure
+/// is not held up.
+pub fn collect(context: &TaskContext, context_map: ContextMap<'_>) {
+    let wanted = context.config.collect_failure_data;
+    if wanted == Some(false) {
+        return;
+    }
+    let bundle = Bundle::of(context, context_map);
+    smol::spawn(smol::unblock(move || {
+        let defaults = Defaults::load();
+        if !wanted.unwrap_or(defaults.collect_failure_data) {
+            return;
+        }
+        match bundle.write(&Instance::current().failures_dir(), defaults.failure_bundles) {
+            Ok(path) => info!(task = bundle.task, path = %path.display(), "Collected failure data"),
+            Err(error) => warn!(task = bundle.task, %error, "Could not collect failure data"),
+        }
+    }))
+    .detach();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{log_lines, Bundle, LogRing, LOG_LINES};
+    use crate::{
+        config::TaskConfig,
+        task::{ContextMap, ExitReason, TaskContext, TaskState},
+    };
+    use std::{collections::HashMap, fs, time::Duration};
+    use tracing::{info, warn};
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    #[test]
+    fn log_ring() {
+        tracing::subscriber::with_default(Registry::default().with(LogRing), || {
+            warn!(task = "ring", step = 2, "Timed out");
+            info!("Not about a task");
+            for i in 0..LOG_LINES + 5 {
+                info!(task = "full", i);
+            }
+        });
+        let lines = log_lines("ring");
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(" WARN Timed out step=2"), "{lines:?}");
+        let lines = log_lines("full");
+        assert_eq!(lines.len(), LOG_LINES);
+        assert!(lines[0].ends_with(" i=5") && lines[LOG_LINES - 1].ends_with(&format!(" i={}", LOG_LINES + 4)));
+    }
+
+    #[test]
+    fn contents_and_retention() {
+        let config = TaskConfig { collect_failure_data: Some(true), ..TaskConfig::new("web/api".into()) };
+        let tasks = HashMap::from([("web/api", TaskContext::new(config)), ("db", TaskContext::new(TaskConfig::new("db".into())))]);
+        let (api, db) = (&tasks["web/api"], &tasks["db"]);
+        smol::block_on(async {
+            db.update_state(TaskState::Running(0)).await;
+            api.update_state(TaskState::Waiting).await;
+            api.update_state(TaskState::Running(0)).await;
+            api.update_state(TaskState::Concluded(ExitReason::Failed)).await;
+        });
+        *api.exit.lock().unwrap() = Some("exit status: 3".into());
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut bundle = Bundle::of(api, ContextMap(&tasks));
+        let path = bundle.write(dir.path(), 3).unwrap();
+        assert!(path.file_name().unwrap().to_str().unwrap().starts_with("web%2Fapi-"));
+        let read = |file: &str| fs::read_to_string(path.join(file)).unwrap();
+        assert_eq!(read("exit"), "exit status: 3\n");
+        assert!(read("config.yaml").contains("name: web/api\n") && read("config.yaml").contains("collect_failure_data: true\n"));
+        let events: Vec<_> = read("events").lines().map(|line| line.split_once(' ').unwrap().1.to_owned()).collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], "db Running(0)");
+        assert_eq!(events[3], "web/api Concluded(Failed)");
+
+        // Only the newest bundles of the task are kept
+        let first = path;
+        for _ in 0..4 {
+            bundle.at += Duration::from_millis(1);
+            bundle.write(dir.path(), 3).unwrap();
+        }
+        let other = Bundle { task: "db".into(), ..bundle.clone() }.write(dir.path(), 3).unwrap();
+        let mut left: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        left.sort();
+        assert_eq!(left.len(), 4);
+        assert!(!first.exists() && other.exists());
+        let names: Vec<_> = left.iter().map(|path| path.file_name().unwrap().to_str().unwrap().to_owned()).collect();
+        assert_eq!(names.iter().filter(|name| name.starts_with("web%2Fapi-")).count(), 3, "{names:?}");
+    }
+}
@@ -0,0 +1,130 @@
+//! File descriptors of alfad and what its children get of them. Everything
+//! alfad opens itself is close-on-exec already, std and `pipe2` see to that,
+//! but pid 1 may inherit others and libraries may leak some.
+
+use nix::libc::{self, c_int, c_long, c_uint, rlimit, RLIMIT_NOFILE};
+use std::{
+    fs, io,
+    os::{fd::RawFd, unix::process::CommandExt},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::warn;
+
+/// Children only get stdin, stdout and stderr
+const FIRST_PRIVATE: RawFd = 3;
+
+/// Share of the limit above which alfad is probably leaking descriptors
+const WARN_PERCENT: u64 = 75;
+
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Descriptors open in this process
+pub fn open_fds() -> io::Result<Vec<RawFd>> {
+    let mut fds: Vec<RawFd> =
+        fs::read_dir("/proc/self/fd")?.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect();
+    // The one used for reading the directory is closed by now
+    fds.retain(|fd| unsafe { libc::fcntl(*fd, libc::F_GETFD) } != -1);
+    fds.sort_unstable();
+    Ok(fds)
+}
+
+/// Soft limit on open descriptors of this process
+pub fn limit() -> io::Result<u64> {
+    let mut limit = rlimit { rlim_cur: 0, rlim_max: 0 };
+    match unsafe { libc::getrlimit(RLIMIT_NOFILE, &mut limit) } {
+        // rlim_t is 32 bit on 32 bit systems
+        #[allow(clippy::useless_conversion)]
+        0 => Ok(u64::from(limit.rlim_cur)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Whether `open` out of `limit` descriptors is close enough to run out
+pub fn near_limit(open: usize, limit: u64) -> bool {
+    open as u64 * 100 > limit.saturating_mul(WARN_PERCENT)
+}
+
+/// Warn once alfad uses most of its descriptors, and again after it went
+/// back below and up again
+pub fn check_usage() {
+    let (Ok(fds), Ok(limit)) = (open_fds(), limit()) else {
+        return;
+    };
+    let near = near_limit(fds.len(), limit);
+    if near && !WARNED.swap(true, Ordering::Relaxed) {
+        warn!(open = fds.len(), limit, "Running out of file descriptors");
+    } else if !near {
+        WARNED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Mark all descriptors from `first` on close-on-exec. Only makes system
+/// calls, so it is safe between fork and exec.
+fn cloexec_from(first: RawFd, max: c_int) -> io::Result<()> {
+    // Since Linux 5.11, the loop is for older kernels
+    let flag = libc::CLOSE_RANGE_CLOEXEC as c_long;
+    if unsafe { libc::syscall(libc::SYS_close_range, first as c_uint, c_uint::MAX, flag) } == 0 {
+        return Ok(());
+    }
+    for fd in first..max {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags != -1 && unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn max_fd() -> c_int {
+    limit().ok().and_then(|limit| c_int::try_from(limit).ok()).unwrap_or(c_int::MAX)
+}
+
+/// Mark whatever alfad inherited close-on-exec, so no child gets it
+pub fn seal_inherited() -> io::Result<()> {
+    cloexec_from(FIRST_PRIVATE, max_fd())
+}
+
+/// Let `command` keep nothing but stdin, stdout and stderr, even of
+/// descriptors leaked without close-on-exec
+pub fn stdio_only(command: &mut Command) {
+    let max = max_fd();
+    // Safe, `cloexec_from` neither allocates nor takes locks
+    unsafe { command.pre_exec(move || cloexec_from(FIRST_PRIVATE, max)) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{near_limit, open_fds, stdio_only};
+    use nix::unistd::{close, dup};
+    use std::{io, os::fd::AsRawFd, process::Command};
+
+    #[test]
+    fn listing() {
+        let fds = open_fds().unwrap();
+        assert!(fds.starts_with(&[0, 1, 2]), "{fds:?}");
+        let leaked = dup(io::stdin().as_raw_fd()).unwrap();
+        assert!(open_fds().unwrap().contains(&leaked));
+        close(leaked).unwrap();
+        assert!(!open_fds().unwrap().contains(&leaked));
+    }
+
+    #[test]
+    fn limits() {
+        assert!(!near_limit(100, 1024));
+        assert!(near_limit(800, 1024));
+        assert!(!near_limit(800, u64::MAX));
+    }
+
+    #[test]
+    fn children() {
+        // Not close-on-exec
+        let leaked = dup(io::stdin().as_raw_fd()).unwrap();
+        let check = format!("test -e /proc/self/fd/{leaked}");
+        assert!(Command::new("sh").args(["-c", &check]).status().unwrap().success());
+        let mut command = Command::new("sh");
+        stdio_only(command.args(["-c", &check]));
+        assert!(!command.status().unwrap().success());
+        close(leaked).unwrap();
+    }
+}
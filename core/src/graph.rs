@@ -0,0 +1,366 @@
+use crate::{
+    config::{payload::PayloadKind, TaskConfig},
+    task::{StateEvent, TaskState},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    time::{Duration, SystemTime},
+};
+
+/// Target that everything should be reachable from, if a task file defines it
+pub const DEFAULT_TARGET: &str = "target::default";
+
+/// Shape of the task tree, printed by alfad-compile
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub services: usize,
+    pub builtins: usize,
+    pub markers: usize,
+    pub groups: usize,
+    pub features: usize,
+    /// Tasks in the longest chain of dependencies
+    pub depth: usize,
+    /// Most tasks at the same distance from the tasks without dependencies
+    pub width: usize,
+    /// Tasks the default target does not wait for, sorted. Empty without
+    /// a default target.
+    pub unreachable: Vec<String>,
+}
+
+impl Summary {
+    pub fn new(configs: &[TaskConfig]) -> Self {
+        let mut summary = Self::default();
+        for config in configs {
+            match config.payload.kind() {
+                PayloadKind::Service => summary.services += 1,
+                PayloadKind::Builtin => summary.builtins += 1,
+                PayloadKind::Marker => summary.markers += 1,
+            }
+            if config.payload.is_marker() {
+                summary.groups += usize::from(config.name.starts_with("group::"));
+                summary.features += usize::from(config.name.starts_with("feature::"));
+            }
+        }
+        let levels = levels(configs);
+        summary.depth = levels.values().max().map_or(0, |level| level + 1);
+        let mut widths: HashMap<usize, usize> = HashMap::new();
+        levels.values().for_each(|level| *widths.entry(*level).or_default() += 1);
+        summary.width = widths.into_values().max().unwrap_or_default();
+        summary.unreachable = unreachable(configs, DEFAULT_TARGET).unwrap_or_default();
+        summary
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} services, {} builtins, {} markers", self.services, self.builtins, self.markers)?;
+        writeln!(f, "{} groups, {} features", self.groups, self.features)?;
+        write!(f, "dependency graph {} deep and {} wide", self.depth, self.width)?;
+        if !self.unreachable.is_empty() {
+            write!(f, "\nnot reachable from {DEFAULT_TARGET}: {}", self.unreachable.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Dependencies of `config` that exist
+fn dependencies<'a>(config: &'a TaskConfig, names: &'a HashSet<&str>) -> impl Iterator<Item = &'a str> {
+    config.after.iter().chain(config.with.iter()).map(String::as_str).filter(|name| names.contains(name))
+}
+
+/// Distance of every task from the tasks without dependencies, following
+/// the longest path. Tasks in or behind a loop have none.
+pub fn levels(configs: &[TaskConfig]) -> HashMap<&str, usize> {
+    let names: HashSet<&str> = configs.iter().map(|config| config.name.as_str()).collect();
+    let mut waiting: HashMap<&str, usize> = HashMap::new();
+    let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for config in configs {
+        let dependencies: HashSet<_> = dependencies(config, &names).collect();
+        waiting.insert(&config.name, dependencies.len());
+        for dependency in dependencies {
+            dependents.entry(dependency).or_default().push(&config.name);
+        }
+    }
+
+    // A task is ready once all its dependencies have their final level
+    let mut ready: Vec<_> = waiting.iter().filter(|(_, count)| **count == 0).map(|(name, _)| *name).collect();
+    let mut levels: HashMap<&str, usize> = ready.iter().map(|name| (*name, 0)).collect();
+    while let Some(name) = ready.pop() {
+        let level = levels[name];
+        for dependent in dependents.get(name).into_iter().flatten() {
+            let next = levels.entry(dependent).or_default();
+            *next = (*next).max(level + 1);
+            let count = waiting.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(dependent);
+            }
+        }
+    }
+    levels.retain(|name, _| waiting[name] == 0);
+    levels
+}
+
+/// Tasks `target` does not wait for, directly or through others, or `None`
+/// if there is no such target
+pub fn unreachable(configs: &[TaskConfig], target: &str) -> Option<Vec<String>> {
+    let map: HashMap<&str, &TaskConfig> = configs.iter().map(|config| (config.name.as_str(), config)).collect();
+    let names: HashSet<&str> = map.keys().copied().collect();
+    let mut reached = HashSet::from([*map.get_key_value(target)?.0]);
+    let mut queue = vec![target];
+    while let Some(name) = queue.pop() {
+        for dependency in dependencies(map[name], &names) {
+            if reached.insert(dependency) {
+                queue.push(dependency);
+            }
+        }
+    }
+    let mut unreachable: Vec<_> = names.difference(&reached).map(|name| name.to_string()).collect();
+    unreachable.sort();
+    Some(unreachable)
+}
+
+/// Tasks named in each list of the startup report
+pub const REPORT_LENGTH: usize = 5;
+
+/// When a task first waited, started and concluded, counted from the start
+/// of the boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeline {
+    pub waiting: Option<Duration>,
+    pub started: Option<Duration>,
+    pub concluded: Option<Duration>,
+}
+
+impl Timeline {
+    pub fn of(events: &[StateEvent], boot: SystemTime) -> Self {
+        let first = |state: fn(&TaskState) -> bool| {
+            events.iter().find(|event| state(&event.state)).map(|event| event.at.duration_since(boot).unwrap_or_default())
+        };
+        Self {
+            waiting: first(|state| matches!(state, TaskState::Waiting)),
+            started: first(|state| matches!(state, TaskState::Running(_))),
+            concluded: first(|state| matches!(state, TaskState::Concluded(_))),
+        }
+    }
+
+    /// How long the task ran, if it concluded after starting
+    fn ran(&self) -> Option<Duration> {
+        self.concluded?.checked_sub(self.started?)
+    }
+}
+
+/// Timelines of the tasks that changed state in `events`, counted from the
+/// first change
+pub fn timelines(events: &[StateEvent]) -> HashMap<&str, Timeline> {
+    let Some(boot) = events.iter().map(|event| event.at).min() else {
+        return HashMap::new();
+    };
+    let mut histories: HashMap<&str, Vec<StateEvent>> = HashMap::new();
+    for event in events {
+        histories.entry(event.task.as_str()).or_default().push(event.clone());
+    }
+    histories.into_iter().map(|(name, history)| (name, Timeline::of(&history, boot))).collect()
+}
+
+/// Time the dependents of each task spent waiting for it. A dependent waits
+/// from its first wait until it starts, every stretch of that is blamed on
+/// the dependency that became ready at its end: `after` dependencies once
+/// they concluded, `with` dependencies once they started. Dependencies
+/// that were not ready when the task started did not hold it up.
+pub fn delays<'a>(
+    configs: impl IntoIterator<Item = &'a TaskConfig>,
+    timelines: &HashMap<&str, Timeline>,
+) -> HashMap<&'a str, Duration> {
+    let mut delays: HashMap<&str, Duration> = HashMap::new();
+    for config in configs {
+        let Some((mut from, until)) = timelines.get(config.name.as_str()).and_then(|line| Some((line.waiting?, line.started?)))
+        else {
+            continue;
+        };
+        let after = config.after.iter().map(|name| (name, timelines.get(name.as_str()).and_then(|line| line.concluded)));
+        let with = config.with.iter().map(|name| (name, timelines.get(name.as_str()).and_then(|line| line.started)));
+        let mut ready: Vec<(Duration, &str)> = after.chain(with).filter_map(|(name, at)| Some((at?, name.as_str()))).collect();
+        ready.sort();
+        for (at, name) in ready.into_iter().filter(|(at, _)| *at <= until) {
+            if at > from {
+                *delays.entry(name).or_default() += at - from;
+                from = at;
+            }
+        }
+    }
+    delays
+}
+
+/// Answer to why the boot took as long as it did, logged once it is complete
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StartupReport {
+    /// Tasks that ran longest until they concluded
+    pub slowest: Vec<(String, Duration)>,
+    /// Tasks their dependents waited for longest, added up
+    pub delaying: Vec<(String, Duration)>,
+}
+
+impl StartupReport {
+    /// Markers only stand for other tasks and are left out
+    pub fn new<'a>(configs: impl IntoIterator<Item = &'a TaskConfig> + Clone, timelines: &HashMap<&str, Timeline>) -> Self {
+        let markers: HashSet<&str> =
+            configs.clone().into_iter().filter(|config| config.payload.is_marker()).map(|config| config.name.as_str()).collect();
+        let ran = configs
+            .clone()
+            .into_iter()
+            .filter(|config| !markers.contains(config.name.as_str()))
+            .filter_map(|config| Some((config.name.as_str(), timelines.get(config.name.as_str())?.ran()?)));
+        let delays = delays(configs, timelines).into_iter().filter(|(name, _)| !markers.contains(name));
+        Self { slowest: top(ran), delaying: top(delays) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slowest.is_empty() && self.delaying.is_empty()
+    }
+}
+
+/// The longest [`REPORT_LENGTH`] durations, ties by name
+fn top<'a>(durations: impl Iterator<Item = (&'a str, Duration)>) -> Vec<(String, Duration)> {
+    let mut durations: Vec<_> = durations.filter(|(_, duration)| !duration.is_zero()).collect();
+    durations.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    durations.into_iter().take(REPORT_LENGTH).map(|(name, duration)| (name.to_owned(), duration)).collect()
+}
+
+impl Display for StartupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |tasks: &[(String, Duration)]| {
+            tasks.iter().map(|(name, duration)| format!("{name} {duration:.1?}")).collect::<Vec<_>>().join(", ")
+        };
+        let lines = [("Slowest tasks", &self.slowest), ("Longest waited for", &self.delaying)];
+        let lines: Vec<_> =
+            lines.into_iter().filter(|(_, tasks)| !tasks.is_empty()).map(|(title, tasks)| format!("{title}: {}", list(tasks))).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{delays, levels, unreachable, StartupReport, Summary, Timeline};
+    use crate::config::{payload::Payload, TaskConfig};
+    use std::{collections::HashMap, time::Duration};
+
+    /// Tasks from `name: after, after` pairs
+    fn graph(tasks: &[(&str, &[&str])]) -> Vec<TaskConfig> {
+        tasks
+            .iter()
+            .map(|(name, after)| {
+                let mut config = TaskConfig::new(name.to_string());
+                after.iter().for_each(|dependency| {
+                    config.after(dependency);
+                });
+                config
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chain() {
+        let configs = graph(&[("c", &["b"]), ("a", &[]), ("b", &["a"])]);
+        let summary = Summary::new(&configs);
+        assert_eq!((summary.depth, summary.width), (3, 1));
+        assert_eq!(levels(&configs)["c"], 2);
+    }
+
+    #[test]
+    fn diamond() {
+        // The longest path decides, d comes after c although b is earlier
+        let configs = graph(&[("a", &[]), ("b", &["a"]), ("c", &["b"]), ("e", &["a"]), ("d", &["c", "e"]), ("x", &[])]);
+        let levels = levels(&configs);
+        assert_eq!((levels["a"], levels["e"], levels["d"], levels["x"]), (0, 1, 3, 0));
+        let summary = Summary::new(&configs);
+        assert_eq!((summary.depth, summary.width), (4, 2));
+    }
+
+    #[test]
+    fn loops_and_missing() {
+        let configs = graph(&[("a", &["b"]), ("b", &["a"]), ("c", &["a"]), ("d", &["missing"])]);
+        let levels = levels(&configs);
+        assert_eq!(levels.len(), 1);
+        assert_eq!(levels["d"], 0);
+        assert_eq!(Summary::new(&configs).depth, 1);
+    }
+
+    #[test]
+    fn reachability() {
+        let mut configs =
+            graph(&[("a", &[]), ("b", &["a"]), ("c", &[]), ("group::net", &["b"]), ("target::default", &["group::net"])]);
+        assert_eq!(unreachable(&configs, "target::default").unwrap(), ["c"]);
+        assert_eq!(unreachable(&configs, "target::rescue"), None);
+
+        configs.iter_mut().filter(|config| config.name.contains("::")).for_each(|config| config.payload = Payload::Marker);
+        let summary = Summary::new(&configs);
+        assert_eq!((summary.services, summary.markers, summary.groups, summary.features), (3, 2, 1, 0));
+        assert_eq!(summary.unreachable, ["c"]);
+        assert_eq!(
+            summary.to_string(),
+            "3 services, 0 builtins, 2 markers\n1 groups, 0 features\ndependency graph 4 deep and 2 wide\n\
+             not reachable from target::default: c"
+        );
+    }
+
+    /// Timeline from seconds since the boot, waiting, started, concluded
+    fn line(waiting: Option<u64>, started: Option<u64>, concluded: Option<u64>) -> Timeline {
+        let at = |seconds: Option<u64>| seconds.map(Duration::from_secs);
+        Timeline { waiting: at(waiting), started: at(started), concluded: at(concluded) }
+    }
+
+    #[test]
+    fn attribution() {
+        // c waits for a until 2s and then for b until 3s, d only needs b
+        // running, e never started waiting
+        let mut configs = graph(&[("a", &[]), ("b", &["a"]), ("c", &["a", "b"]), ("d", &[]), ("e", &["a"])]);
+        configs[3].with.push("b".into());
+        let timelines = HashMap::from([
+            ("a", line(None, Some(0), Some(2))),
+            ("b", line(Some(0), Some(2), Some(3))),
+            ("c", line(Some(0), Some(3), None)),
+            ("d", line(Some(1), Some(2), Some(6))),
+            ("e", line(None, Some(0), None)),
+        ]);
+        let delays = delays(&configs, &timelines);
+        let secs = |name: &str| delays.get(name).map(Duration::as_secs);
+        assert_eq!((secs("a"), secs("b"), secs("d")), (Some(4), Some(2), None));
+    }
+
+    #[test]
+    fn late_and_missing_dependencies() {
+        // Dependencies that became ready after the task started or never
+        // did are not to blame
+        let configs = graph(&[("a", &[]), ("b", &[]), ("c", &[]), ("d", &["a", "b", "c", "missing"])]);
+        let timelines = HashMap::from([
+            ("a", line(None, Some(0), Some(2))),
+            ("b", line(None, Some(0), Some(5))),
+            ("c", line(None, Some(0), None)),
+            ("d", line(Some(1), Some(3), None)),
+        ]);
+        assert_eq!(delays(&configs, &timelines), HashMap::from([("a", Duration::from_secs(1))]));
+    }
+
+    #[test]
+    fn report() {
+        let names = ["t0", "t1", "t2", "t3", "t4", "t5", "t6"];
+        let mut configs = graph(&names.map(|name| (name, &[] as &[&str])));
+        configs.extend(graph(&[("group::slow", &["t6"]), ("after", &["group::slow", "t0"])]));
+        configs[7].payload = Payload::Marker;
+        let mut timelines: HashMap<&str, Timeline> =
+            names.iter().zip(0..).map(|(name, i)| (*name, line(None, Some(0), Some(i)))).collect();
+        timelines.insert("group::slow", line(Some(0), Some(6), Some(6)));
+        timelines.insert("after", line(Some(0), Some(6), None));
+
+        let report = StartupReport::new(&configs, &timelines);
+        let slowest: Vec<_> = report.slowest.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(slowest, ["t6", "t5", "t4", "t3", "t2"]);
+        // The group is blamed on t6, t0 was ready right away
+        assert_eq!(report.delaying, [("t6".to_owned(), Duration::from_secs(6))]);
+        assert!(report.to_string().starts_with("Slowest tasks: t6 6.0s, t5 5.0s, t4 4.0s, "));
+        assert!(report.to_string().ends_with("\nLongest waited for: t6 6.0s"));
+        assert!(StartupReport::new(&configs, &HashMap::new()).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+//! Shutdown inhibitors. A backup job or a package upgrade takes a lease
+//! with `alfad-ctl inhibit`, which either delays a poweroff, reboot or halt
+//! until it is released or refuses the command unless it is forced.
+//!
+//! The control pipe has no connection a lease could end with, so every
+//! lease has a time to live and is gone once it expires.
+
+use clap::ValueEnum;
+use std::time::{Duration, Instant};
+use strum::Display;
+
+/// How long a lease lives unless the holder asks for longer
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// What a lease holds off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum InhibitWhat {
+    /// Poweroff, reboot and halt
+    #[default]
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum InhibitMode {
+    /// Refuse the command unless it is forced
+    Block,
+    /// Hold the command back until the lease is released, at most for
+    /// `inhibit_delay_max` seconds
+    #[default]
+    Delay,
+}
+
+/// Who holds off what and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inhibitor {
+    pub what: InhibitWhat,
+    pub mode: InhibitMode,
+    pub who: String,
+    pub why: String,
+    /// Of the client that took the lease, if it could be found out
+    pub uid: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub id: u64,
+    pub inhibitor: Inhibitor,
+    pub expires: Instant,
+}
+
+impl Lease {
+    /// One line for listings and messages, `now` for the time left
+    pub fn describe(&self, now: Instant) -> String {
+        let Inhibitor { what, mode, who, why, uid } = &self.inhibitor;
+        let uid = uid.map(|uid| format!(", uid {uid}")).unwrap_or_default();
+        let left = self.expires.saturating_duration_since(now).as_secs();
+        format!("#{} {who} ({mode} {what}{uid}, {left}s left): {why}", self.id)
+    }
+}
+
+/// Whether a system command may go ahead
+#[derive(Debug, PartialEq, Eq)]
+pub enum Gate {
+    Proceed,
+    /// Held back by leases in delay mode
+    Wait,
+    /// Refused because of leases in block mode, with their descriptions
+    Refuse(Vec<String>),
+}
+
+/// The leases the daemon knows about
+#[derive(Debug, Default)]
+pub struct Inhibitors {
+    leases: Vec<Lease>,
+    next_id: u64,
+}
+
+impl Inhibitors {
+    /// Take a lease that lives for `ttl`, returns its id
+    pub fn take(&mut self, inhibitor: Inhibitor, ttl: Duration, now: Instant) -> u64 {
+        self.next_id += 1;
+        self.leases.push(Lease { id: self.next_id, inhibitor, expires: now + ttl });
+        self.next_id
+    }
+
+    /// Give the lease `id` back, `false` if there is no such lease (anymore)
+    pub fn release(&mut self, id: u64, now: Instant) -> bool {
+        self.expire(now);
+        let before = self.leases.len();
+        self.leases.retain(|lease| lease.id != id);
+        self.leases.len() != before
+    }
+
+    /// The leases that have not expired yet, oldest first
+    pub fn active(&mut self, now: Instant) -> &[Lease] {
+        self.expire(now);
+        &self.leases
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.leases.retain(|lease| lease.expires > now);
+    }
+
+    /// Whether a shutdown may go ahead. Leases in delay mode are only
+    /// waited for until `waited` reaches `max`, nothing holds off a forced
+    /// shutdown.
+    pub fn gate(&mut self, force: bool, waited: Duration, max: Duration, now: Instant) -> Gate {
+        if force {
+            return Gate::Proceed;
+        }
+        self.expire(now);
+        let holding = |mode| self.leases.iter().filter(move |lease| lease.inhibitor.mode == mode);
+        let blocking: Vec<_> = holding(InhibitMode::Block).map(|lease| lease.describe(now)).collect();
+        if !blocking.is_empty() {
+            return Gate::Refuse(blocking);
+        }
+        match holding(InhibitMode::Delay).next() {
+            Some(_) if waited < max => Gate::Wait,
+            _ => Gate::Proceed,
+        }
+    }
+}
+
+/// The name of a holder is a single word, so it can't run into the reason
+pub fn holder_name(name: &str) -> Result<String, String> {
+    match name {
+        "" => Err("The holder needs a name".to_owned()),
+        _ if name.contains(char::is_whitespace) => Err(format!("'{name}' contains whitespace")),
+        _ => Ok(name.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{holder_name, Gate, InhibitMode, InhibitWhat, Inhibitor, Inhibitors};
+    use std::time::{Duration, Instant};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn inhibitor(who: &str, mode: InhibitMode) -> Inhibitor {
+        Inhibitor { what: InhibitWhat::Shutdown, mode, who: who.to_owned(), why: "testing".to_owned(), uid: Some(0) }
+    }
+
+    #[test]
+    fn leases() {
+        let now = Instant::now();
+        let mut inhibitors = Inhibitors::default();
+        let backup = inhibitors.take(inhibitor("backup", InhibitMode::Delay), 5 * MINUTE, now);
+        let upgrade = inhibitors.take(inhibitor("upgrade", InhibitMode::Block), MINUTE, now);
+        assert_ne!(backup, upgrade);
+        assert_eq!(inhibitors.active(now).len(), 2);
+        assert_eq!(
+            inhibitors.active(now)[1].describe(now + Duration::from_secs(20)),
+            format!("#{upgrade} upgrade (block shutdown, uid 0, 40s left): testing")
+        );
+
+        // Expired after its time to live, the other lease is still there
+        let later = now + MINUTE;
+        assert_eq!(inhibitors.active(later).iter().map(|lease| lease.id).collect::<Vec<_>>(), [backup]);
+        assert!(!inhibitors.release(upgrade, later));
+        assert!(inhibitors.release(backup, later));
+        assert!(!inhibitors.release(backup, later));
+        assert!(inhibitors.active(later).is_empty());
+    }
+
+    #[test]
+    fn gating() {
+        let now = Instant::now();
+        let max = 2 * MINUTE;
+        let mut inhibitors = Inhibitors::default();
+        assert_eq!(inhibitors.gate(false, Duration::ZERO, max, now), Gate::Proceed);
+
+        inhibitors.take(inhibitor("backup", InhibitMode::Delay), 10 * MINUTE, now);
+        assert_eq!(inhibitors.gate(false, Duration::ZERO, max, now), Gate::Wait);
+        // Delayed for at most the maximum, never when forced
+        assert_eq!(inhibitors.gate(false, max, max, now + max), Gate::Proceed);
+        assert_eq!(inhibitors.gate(true, Duration::ZERO, max, now), Gate::Proceed);
+
+        let upgrade = inhibitors.take(inhibitor("upgrade", InhibitMode::Block), MINUTE, now);
+        assert_eq!(
+            inhibitors.gate(false, Duration::ZERO, max, now),
+            Gate::Refuse(vec![format!("#{upgrade} upgrade (block shutdown, uid 0, 60s left): testing")])
+        );
+        assert_eq!(inhibitors.gate(true, Duration::ZERO, max, now), Gate::Proceed);
+        // Blocks until it expires, then only the delay is left
+        assert_eq!(inhibitors.gate(false, MINUTE, max, now + MINUTE), Gate::Wait);
+        assert_eq!(inhibitors.gate(false, Duration::ZERO, max, now + 10 * MINUTE), Gate::Proceed);
+    }
+
+    #[test]
+    fn holder_names() {
+        assert_eq!(holder_name("backup").unwrap(), "backup");
+        holder_name("").unwrap_err();
+        holder_name("nightly backup").unwrap_err();
+    }
+}
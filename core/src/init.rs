@@ -1,15 +1,9 @@
-use crate::task::ContextMap;
-use crate::{config::read_config, task::TaskContext};
+use crate::config::read_config;
+use crate::{builtin::signals, config::defaults::Defaults, early, fd, instance::Instance, privilege};
 use crate::{config::yaml::TaskConfigYaml, def::APLT_MAIN};
 use anyhow::Result;
-use futures::StreamExt;
-use nix::libc::{SIGABRT, SIGHUP, SIGPIPE, SIGTERM, SIGTSTP};
-use signal_hook::iterator::exfiltrator::WithOrigin;
-use signal_hook_async_std::SignalsInfo;
-use std::env;
-use tracing::info;
-
-const SIGS: &[i32] = &[SIGABRT, SIGTERM, SIGHUP, SIGPIPE, SIGTSTP];
+use std::{env, thread};
+use tracing::{info, warn};
 
 pub struct Alfad {
     pub builtin: Vec<TaskConfigYaml>,
@@ -17,25 +11,34 @@ pub struct Alfad {
 
 impl Alfad {
     pub fn run(self) -> Result<()> {
-        let mut signals = SignalsInfo::<WithOrigin>::new(SIGS).unwrap();
-
-        smol::spawn(async move {
-            loop {
-                signals.next().await;
-            }
-        })
-        .detach();
+        // Before anything can fail for lack of a console
+        early::bootstrap();
+        if let Err(error) = fd::seal_inherited() {
+            warn!(%error, "Could not keep inherited file descriptors from tasks");
+        }
+        // Queued until builtin::signals handles them
+        if let Err(error) = signals::register() {
+            warn!(%error, "Could not catch signals");
+        }
 
-        env::set_var("SMOL_THREADS", "8");
+        // Before the executor is first used, it reads this once
+        env::set_var("SMOL_THREADS", executor_threads(Defaults::load().threads).to_string());
+        let instance = Instance::current();
         info!("Starting {}", APLT_MAIN);
-        let configs = read_config(self.builtin);
-        let context: ContextMap = ContextMap(Box::leak(Box::new(
-            configs.into_iter().map(|config| (&*config.name.clone().leak(), TaskContext::new(config))).collect(),
-        )));
-        info!("Done parsing ({} tasks)", context.0.len());
-        context.0.values().for_each(|config| crate::task::spawn(config, context));
+        let configs = read_config(instance.builtins(self.builtin));
+        info!("Done parsing ({} tasks)", configs.len());
+        privilege::report(&configs);
+        crate::task::start(configs);
         // smol::block_on(async { wait_for_commands(context).await });
         smol::block_on(smol::Timer::never());
         Ok(())
     }
 }
+
+/// `configured` threads, or one per CPU if it is 0
+fn executor_threads(configured: usize) -> usize {
+    match configured {
+        0 => thread::available_parallelism().map_or(1, usize::from),
+        threads => threads,
+    }
+}
@@ -0,0 +1,263 @@
+use crate::applet::Applet;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::{
+    env, fs, io,
+    os::unix::fs::{symlink, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+/// Create the applet links pointing at this binary
+#[derive(Debug, Parser)]
+#[command(name = "alfad install")]
+pub struct InstallArgs {
+    /// Root directory to install the links into
+    #[arg(long, default_value = "/")]
+    prefix: PathBuf,
+    /// Create symbolic links (default)
+    #[arg(long, conflicts_with = "hardlink")]
+    symlink: bool,
+    /// Create hard links
+    #[arg(long)]
+    hardlink: bool,
+    /// Only print what would be done
+    #[arg(long)]
+    dry_run: bool,
+    /// Replace files that are not links to this binary
+    #[arg(long)]
+    force: bool,
+    /// Remove the links instead of creating them
+    #[arg(long)]
+    uninstall: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Symlink,
+    Hardlink,
+}
+
+/// What is currently at the location of an applet link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    Missing,
+    /// A link to the alfad binary
+    Ours(LinkKind),
+    /// Anything else
+    Foreign,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    Create(PathBuf),
+    Replace(PathBuf),
+    Keep(PathBuf),
+    Remove(PathBuf),
+    /// Leave a file alone that is not ours
+    Refuse(PathBuf),
+}
+
+/// Decide what to do for every applet link below `prefix`, based on what
+/// `probe` reports to be at each location.
+pub fn plan(prefix: &Path, kind: LinkKind, force: bool, uninstall: bool, probe: impl Fn(&Path) -> Existing) -> Vec<Step> {
+    Applet::linked()
+        .filter_map(|applet| Some(prefix.join(applet.install_dir()?).join(applet.name())))
+        .filter_map(|path| {
+            let step = match (probe(&path), uninstall) {
+                (Existing::Missing, false) => Step::Create(path),
+                (Existing::Missing, true) => return None,
+                (Existing::Ours(_), true) => Step::Remove(path),
+                (Existing::Ours(existing), false) if existing == kind => Step::Keep(path),
+                (Existing::Ours(_), false) => Step::Replace(path),
+                (Existing::Foreign, false) if force => Step::Replace(path),
+                (Existing::Foreign, _) => Step::Refuse(path),
+            };
+            Some(step)
+        })
+        .collect()
+}
+
+/// Find out whether `path` is a link to `target`
+pub fn probe(path: &Path, target: &Path) -> Existing {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return Existing::Missing,
+    };
+    if meta.is_symlink() {
+        let dest = match fs::read_link(path) {
+            Ok(dest) => dest,
+            Err(_) => return Existing::Foreign,
+        };
+        let dest = path.parent().map(|parent| parent.join(&dest)).unwrap_or(dest);
+        return match (fs::canonicalize(dest), fs::canonicalize(target)) {
+            (Ok(a), Ok(b)) if a == b => Existing::Ours(LinkKind::Symlink),
+            _ => Existing::Foreign,
+        };
+    }
+    match fs::metadata(target) {
+        Ok(target) if target.dev() == meta.dev() && target.ino() == meta.ino() => Existing::Ours(LinkKind::Hardlink),
+        _ => Existing::Foreign,
+    }
+}
+
+fn link(target: &Path, path: &Path, kind: LinkKind) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match kind {
+        LinkKind::Symlink => symlink(target, path),
+        LinkKind::Hardlink => fs::hard_link(target, path),
+    }
+}
+
+/// Carry out the planned steps, returns the number of refused links
+pub fn execute(steps: &[Step], target: &Path, kind: LinkKind, dry_run: bool) -> Result<usize> {
+    let mut refused = 0;
+    for step in steps {
+        match step {
+            Step::Create(path) => {
+                println!("create  {} -> {}", path.display(), target.display());
+                if !dry_run {
+                    link(target, path, kind).with_context(|| format!("Could not create {path:?}"))?;
+                }
+            }
+            Step::Replace(path) => {
+                println!("replace {} -> {}", path.display(), target.display());
+                if !dry_run {
+                    fs::remove_file(path).with_context(|| format!("Could not remove {path:?}"))?;
+                    link(target, path, kind).with_context(|| format!("Could not create {path:?}"))?;
+                }
+            }
+            Step::Keep(path) => println!("keep    {}", path.display()),
+            Step::Remove(path) => {
+                println!("remove  {}", path.display());
+                if !dry_run {
+                    fs::remove_file(path).with_context(|| format!("Could not remove {path:?}"))?;
+                }
+            }
+            Step::Refuse(path) => {
+                eprintln!("refuse  {} (not a link to {})", path.display(), target.display());
+                refused += 1;
+            }
+        }
+    }
+    Ok(refused)
+}
+
+pub fn run(args: Vec<String>) -> Result<()> {
+    let args = InstallArgs::parse_from(args);
+    let target = env::current_exe().context("Could not find the alfad binary")?;
+    let kind = if args.hardlink { LinkKind::Hardlink } else { LinkKind::Symlink };
+
+    let steps = plan(&args.prefix, kind, args.force, args.uninstall, |path| probe(path, &target));
+    let refused = execute(&steps, &target, kind, args.dry_run)?;
+    if refused > 0 && args.uninstall {
+        bail!("{refused} file(s) are not links to alfad and were left untouched");
+    } else if refused > 0 {
+        bail!("{refused} file(s) are not links to alfad, use --force to replace them");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{execute, plan, probe, Existing, LinkKind, Step};
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    fn path(s: &str) -> PathBuf {
+        PathBuf::from(s)
+    }
+
+    #[test]
+    fn plan_fresh_install() {
+        let steps = plan(Path::new("/"), LinkKind::Symlink, false, false, |_| Existing::Missing);
+        assert_eq!(
+            steps,
+            vec![
+                Step::Create(path("/sbin/init")),
+                Step::Create(path("/usr/bin/alfad-ctl")),
+                Step::Create(path("/usr/bin/alfad-compile")),
+                Step::Create(path("/usr/bin/alfad-check")),
+                Step::Create(path("/sbin/poweroff")),
+                Step::Create(path("/sbin/reboot")),
+                Step::Create(path("/sbin/halt")),
+                Step::Create(path("/sbin/shutdown")),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_existing_files() {
+        let probe = |p: &Path| match p.to_str().unwrap() {
+            "/tmp/sbin/init" => Existing::Foreign,
+            "/tmp/sbin/halt" => Existing::Ours(LinkKind::Hardlink),
+            "/tmp/sbin/reboot" => Existing::Ours(LinkKind::Symlink),
+            _ => Existing::Missing,
+        };
+
+        let steps = plan(Path::new("/tmp"), LinkKind::Symlink, false, false, probe);
+        assert!(steps.contains(&Step::Refuse(path("/tmp/sbin/init"))));
+        assert!(steps.contains(&Step::Replace(path("/tmp/sbin/halt"))));
+        assert!(steps.contains(&Step::Keep(path("/tmp/sbin/reboot"))));
+
+        let steps = plan(Path::new("/tmp"), LinkKind::Symlink, true, false, probe);
+        assert!(steps.contains(&Step::Replace(path("/tmp/sbin/init"))));
+
+        let steps = plan(Path::new("/tmp"), LinkKind::Symlink, true, true, probe);
+        assert_eq!(
+            steps,
+            vec![
+                Step::Refuse(path("/tmp/sbin/init")),
+                Step::Remove(path("/tmp/sbin/reboot")),
+                Step::Remove(path("/tmp/sbin/halt"))
+            ]
+        );
+    }
+
+    #[test]
+    fn install_and_uninstall() {
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("alfad");
+        fs::write(&target, "binary").unwrap();
+        let prefix = root.path().join("root");
+        let init = prefix.join("sbin/init");
+
+        let steps = plan(&prefix, LinkKind::Symlink, false, false, |p| probe(p, &target));
+        assert_eq!(execute(&steps, &target, LinkKind::Symlink, false).unwrap(), 0);
+        assert_eq!(fs::read_link(&init).unwrap(), target);
+        assert_eq!(probe(&prefix.join("usr/bin/alfad-ctl"), &target), Existing::Ours(LinkKind::Symlink));
+
+        // Switching to hard links replaces our own links
+        let steps = plan(&prefix, LinkKind::Hardlink, false, false, |p| probe(p, &target));
+        assert!(steps.iter().all(|step| matches!(step, Step::Replace(_))));
+        execute(&steps, &target, LinkKind::Hardlink, false).unwrap();
+        assert_eq!(probe(&init, &target), Existing::Ours(LinkKind::Hardlink));
+
+        // Foreign files are left alone
+        fs::remove_file(&init).unwrap();
+        fs::write(&init, "somebody else").unwrap();
+        let steps = plan(&prefix, LinkKind::Hardlink, false, false, |p| probe(p, &target));
+        assert_eq!(execute(&steps, &target, LinkKind::Hardlink, false).unwrap(), 1);
+        assert_eq!(fs::read_to_string(&init).unwrap(), "somebody else");
+
+        let steps = plan(&prefix, LinkKind::Hardlink, false, true, |p| probe(p, &target));
+        assert_eq!(execute(&steps, &target, LinkKind::Hardlink, false).unwrap(), 1);
+        assert!(init.exists());
+        assert!(!prefix.join("sbin/poweroff").exists());
+        assert!(target.exists());
+    }
+
+    #[test]
+    fn dry_run_does_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("alfad");
+        fs::write(&target, "binary").unwrap();
+
+        let steps = plan(root.path(), LinkKind::Symlink, false, false, |p| probe(p, &target));
+        execute(&steps, &target, LinkKind::Symlink, true).unwrap();
+        assert!(!root.path().join("sbin").exists());
+    }
+}
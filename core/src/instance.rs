@@ -0,0 +1,262 @@
+//! Which alfad this is. The system instance is init with the paths in
+//! [`crate::def`]. A user instance supervises the services of a login
+//! session, started with `alfad user-session`: its task files are in
+//! `~/.config/alfad/alfad.d`, its control pipe in `$XDG_RUNTIME_DIR/alfad`,
+//! what it keeps across sessions in `~/.local/state/alfad`, it tags its log lines with the user and it exits instead of taking the
+//! machine down.
+
+use crate::{
+    builtin,
+    config::yaml::{PayloadYaml, TaskConfigYaml},
+    def::{APLT_CTL, DIR_FAILURES, DIR_REPLY, DIR_RUN, FILE_DISABLED, SRC_BUILTIN},
+};
+use std::{
+    env,
+    fmt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use thiserror::Error;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// Builtins that look after the machine rather than a session
+const SYSTEM_ONLY: &[&str] = &["boot::count", "state::dir", "utmp"];
+
+/// The runtime directory of a session is there before anything starts
+const FS_RUN: &str = "feature::fs::run";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instance {
+    System,
+    User {
+        user: String,
+        /// Contains alfad.d and defaults.yaml
+        config: PathBuf,
+        /// Contains the control pipe and the reply FIFOs
+        runtime: PathBuf,
+        /// Contains the failure bundles and the disabled tasks
+        state: PathBuf,
+    },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)] // Each is a directory the session does not name
+pub enum InstanceError {
+    #[error("XDG_RUNTIME_DIR is not set, a user instance needs it for its control pipe")]
+    NoRuntimeDir,
+    #[error("Neither XDG_CONFIG_HOME nor HOME is set, a user instance needs one for its task files")]
+    NoConfigDir,
+    #[error("Neither XDG_STATE_HOME nor HOME is set, a user instance needs one for its failure bundles")]
+    NoStateDir,
+}
+
+static INSTANCE: OnceLock<Instance> = OnceLock::new();
+static SYSTEM: Instance = Instance::System;
+
+/// A variable of the environment, unset if empty
+pub fn var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+impl Instance {
+    /// The user instance of the session `var` describes
+    pub fn user(var: impl Fn(&str) -> Option<String>) -> Result<Self, InstanceError> {
+        let runtime = var("XDG_RUNTIME_DIR").ok_or(InstanceError::NoRuntimeDir)?;
+        let config = var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(Path::new(&var("HOME")?).join(".config")))
+            .ok_or(InstanceError::NoConfigDir)?;
+        let state = var("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(Path::new(&var("HOME")?).join(".local/state")))
+            .ok_or(InstanceError::NoStateDir)?;
+        let user = var("USER").or_else(|| var("LOGNAME")).unwrap_or_else(|| nix::unistd::getuid().to_string());
+        let runtime = Path::new(&runtime).join("alfad");
+        Ok(Self::User { user, config: config.join("alfad"), runtime, state: state.join("alfad") })
+    }
+
+    /// The instance alfad-ctl talks to: the user instance with `--user` or
+    /// if one is running and the caller is not root, otherwise the system
+    pub fn for_client(user: bool, root: bool, var: impl Fn(&str) -> Option<String>) -> Result<Self, InstanceError> {
+        if user {
+            return Self::user(var);
+        }
+        match Self::user(var) {
+            Ok(instance) if !root && instance.run_dir().join(APLT_CTL).exists() => Ok(instance),
+            _ => Ok(Self::System),
+        }
+    }
+
+    /// The instance of this process, the system unless set otherwise
+    pub fn current() -> &'static Self {
+        INSTANCE.get().unwrap_or(&SYSTEM)
+    }
+
+    /// Make this the instance of the process, only works once
+    pub fn set(self) -> Result<(), Self> {
+        INSTANCE.set(self)
+    }
+
+    pub fn is_user(&self) -> bool {
+        matches!(self, Self::User { .. })
+    }
+
+    /// Directory containing alfad.d, the cache and the defaults, `None` for
+    /// the system which has its own
+    pub fn config_dir(&self) -> Option<&Path> {
+        match self {
+            Self::System => None,
+            Self::User { config, .. } => Some(config),
+        }
+    }
+
+    /// Directory of the control pipe
+    pub fn run_dir(&self) -> &Path {
+        match self {
+            Self::System => Path::new(DIR_RUN),
+            Self::User { runtime, .. } => runtime,
+        }
+    }
+
+    /// Directory clients create their reply FIFOs in
+    pub fn reply_dir(&self) -> PathBuf {
+        match self {
+            Self::System => DIR_REPLY.into(),
+            Self::User { runtime, .. } => runtime.join("alfad-reply"),
+        }
+    }
+
+    /// Directory of the failure bundles
+    pub fn failures_dir(&self) -> PathBuf {
+        match self {
+            Self::System if cfg!(debug_assertions) => "test/failures".into(),
+            Self::System => DIR_FAILURES.into(),
+            Self::User { state, .. } => state.join("failures"),
+        }
+    }
+
+    /// Where the tasks disabled across reboots are remembered
+    pub fn disabled_file(&self) -> PathBuf {
+        match self {
+            Self::System if cfg!(debug_assertions) => "test/disabled".into(),
+            Self::System => FILE_DISABLED.into(),
+            Self::User { state, .. } => state.join("disabled"),
+        }
+    }
+
+    /// Prefix of the log lines, none for the system
+    pub fn tag(&self) -> Option<String> {
+        match self {
+            Self::System => None,
+            Self::User { user, .. } => Some(format!("[user:{user}]")),
+        }
+    }
+
+    /// The builtins this instance runs, a session has no machine to count
+    /// boots of or to record in utmp
+    pub fn builtins(&self, builtin: Vec<TaskConfigYaml>) -> Vec<TaskConfigYaml> {
+        if !self.is_user() {
+            return builtin;
+        }
+        let mut builtin = builtin::without(builtin, SYSTEM_ONLY);
+        let source = Some(SRC_BUILTIN.into());
+        builtin.push(TaskConfigYaml { name: FS_RUN.into(), cmd: PayloadYaml::Marker, source, ..Default::default() });
+        builtin
+    }
+}
+
+/// Log lines of `F` behind the tag of the instance
+pub struct Tagged<F> {
+    tag: Option<String>,
+    format: F,
+}
+
+impl<F> Tagged<F> {
+    pub fn new(instance: &Instance, format: F) -> Self {
+        Self { tag: instance.tag(), format }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for Tagged<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(&self, context: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        if let Some(tag) = &self.tag {
+            write!(writer, "{tag} ")?;
+        }
+        self.format.format_event(context, writer, event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Instance, InstanceError, FS_RUN};
+    use crate::{builtin, def::APLT_CTL};
+    use std::{collections::HashMap, fs, path::Path};
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        move |key| vars.get(key).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn user_paths() {
+        let instance = Instance::user(env(&[("XDG_RUNTIME_DIR", "/run/user/1000"), ("HOME", "/home/ada"), ("USER", "ada")]));
+        let instance = instance.unwrap();
+        assert_eq!(instance.config_dir(), Some(Path::new("/home/ada/.config/alfad")));
+        assert_eq!(instance.run_dir(), Path::new("/run/user/1000/alfad"));
+        assert_eq!(instance.reply_dir(), Path::new("/run/user/1000/alfad/alfad-reply"));
+        assert_eq!(instance.failures_dir(), Path::new("/home/ada/.local/state/alfad/failures"));
+        assert_eq!(instance.disabled_file(), Path::new("/home/ada/.local/state/alfad/disabled"));
+        assert_eq!(instance.tag().unwrap(), "[user:ada]");
+
+        let instance = Instance::user(env(&[("XDG_RUNTIME_DIR", "/run/user/1000"), ("XDG_CONFIG_HOME", "/cfg"), ("HOME", "/h")]));
+        assert_eq!(instance.unwrap().config_dir(), Some(Path::new("/cfg/alfad")));
+        let instance = Instance::user(env(&[("XDG_RUNTIME_DIR", "/run/user/1000"), ("XDG_STATE_HOME", "/st"), ("HOME", "/h")]));
+        assert_eq!(instance.unwrap().failures_dir(), Path::new("/st/alfad/failures"));
+        assert_eq!(Instance::user(env(&[("HOME", "/home/ada")])), Err(InstanceError::NoRuntimeDir));
+        assert_eq!(Instance::user(env(&[("XDG_RUNTIME_DIR", "/run/user/1000")])), Err(InstanceError::NoConfigDir));
+        let vars = [("XDG_RUNTIME_DIR", "/run/user/1000"), ("XDG_CONFIG_HOME", "/cfg")];
+        assert_eq!(Instance::user(env(&vars)), Err(InstanceError::NoStateDir));
+
+        assert_eq!(Instance::System.config_dir(), None);
+        assert_eq!(Instance::System.tag(), None);
+    }
+
+    #[test]
+    fn client_choice() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = dir.path().to_str().unwrap();
+        let vars = [("XDG_RUNTIME_DIR", runtime), ("HOME", "/home/ada")];
+        let choose = |user, root| Instance::for_client(user, root, env(&vars)).unwrap().is_user();
+        // No user instance running
+        assert!(!choose(false, false));
+        assert!(choose(true, true));
+
+        fs::create_dir(dir.path().join("alfad")).unwrap();
+        fs::write(dir.path().join("alfad").join(APLT_CTL), "").unwrap();
+        assert!(choose(false, false));
+        assert!(!choose(false, true));
+        assert_eq!(Instance::for_client(true, false, env(&[])), Err(InstanceError::NoRuntimeDir));
+    }
+
+    #[test]
+    fn session_builtins() {
+        let keys = |configs: &[crate::config::yaml::TaskConfigYaml]| -> Vec<String> {
+            configs.iter().map(|config| config.cmd.builtin_key().map_or(config.name.clone(), str::to_owned)).collect()
+        };
+        assert_eq!(keys(&Instance::System.builtins(builtin::all())), keys(&builtin::all()));
+
+        let user = Instance::user(env(&[("XDG_RUNTIME_DIR", "/run/user/1000"), ("HOME", "/home/ada")])).unwrap();
+        let session = keys(&user.builtins(builtin::all()));
+        assert!(session.contains(&"ctl::daemon".to_owned()) && session.contains(&FS_RUN.to_owned()));
+        assert!(!session.contains(&"boot::count".to_owned()) && !session.contains(&"state::dir".to_owned()));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config::{RequiresKernel, UnknownKernel};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::OnceLock,
+};
+use thiserror::Error;
+use tracing::debug;
+
+/// Compressed configuration of the running kernel, with CONFIG_IKCONFIG_PROC
+pub const PROC_CONFIG: &str = "/proc/config.gz";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid kernel version '{}', expected something like \"5.10\"", .0)]
+pub struct InvalidVersion(String);
+
+/// Why a task does not run on this kernel
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Unmet {
+    #[error("Kernel {version} is older than {min}")]
+    TooOld { version: KernelVersion, min: KernelVersion },
+    #[error("Kernel lacks {}", .0.join(", "))]
+    Missing(Vec<String>),
+    #[error("Kernel {} is unknown", .0)]
+    Unknown(&'static str),
+}
+
+/// Major, minor and patch level of a kernel release
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KernelVersion(pub u32, pub u32, pub u32);
+
+/// Reads releases like "5.10", "6.1.0-21-amd64" or "4.19.94+", anything
+/// after the numbers is ignored
+impl FromStr for KernelVersion {
+    type Err = InvalidVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidVersion(s.to_owned());
+        let end = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        let mut numbers = s[..end].split('.').map(str::parse::<u32>);
+        let mut next = || numbers.next().transpose().map_err(|_| invalid());
+        let (major, minor) = (next()?.ok_or_else(invalid)?, next()?.ok_or_else(invalid)?);
+        Ok(Self(major, minor, next()?.unwrap_or_default()))
+    }
+}
+
+impl Display for KernelVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Options of a kernel config file by name, without the `CONFIG_` prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelConfig(HashMap<String, String>);
+
+impl KernelConfig {
+    pub fn parse(text: &str) -> Self {
+        let options = text
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("CONFIG_")?.split_once('='))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        Self(options)
+    }
+
+    /// Whether `option` is built in or a module, with or without `CONFIG_`
+    pub fn enabled(&self, option: &str) -> bool {
+        let option = option.strip_prefix("CONFIG_").unwrap_or(option);
+        matches!(self.0.get(option).map(String::as_str), Some("y" | "m"))
+    }
+}
+
+/// Where the configuration of kernel `release` may be found, in order
+pub fn config_paths(release: &str) -> [PathBuf; 2] {
+    [PathBuf::from(format!("/boot/config-{release}")), PathBuf::from(PROC_CONFIG)]
+}
+
+/// Contents of a config file, compressed ones go through gzip
+fn read_config(path: &Path) -> io::Result<String> {
+    if path.extension().is_none_or(|extension| extension != "gz") {
+        return fs::read_to_string(path);
+    }
+    let output = Command::new("gzip").arg("-dc").arg(path).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("gzip exited with {}", output.status)));
+    }
+    String::from_utf8(output.stdout).map_err(io::Error::other)
+}
+
+/// The first of `paths` that can be read
+pub fn load_config(paths: &[PathBuf]) -> Option<KernelConfig> {
+    paths.iter().filter(|path| path.exists()).find_map(|path| {
+        read_config(path).map(|text| KernelConfig::parse(&text)).map_err(|error| debug!(?path, %error, "Unreadable")).ok()
+    })
+}
+
+/// What is known about a kernel
+#[derive(Debug, Default)]
+pub struct Kernel {
+    pub version: Option<KernelVersion>,
+    pub config: Option<KernelConfig>,
+}
+
+impl Kernel {
+    /// The running kernel, looked at once
+    pub fn running() -> &'static Kernel {
+        static RUNNING: OnceLock<Kernel> = OnceLock::new();
+        RUNNING.get_or_init(|| {
+            let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+            let release = release.trim();
+            Kernel { version: release.parse().ok(), config: load_config(&config_paths(release)) }
+        })
+    }
+}
+
+/// Whether `kernel` has everything in `requires`. What can't be checked
+/// is up to `requires.unknown`.
+pub fn check(requires: &RequiresKernel, kernel: &Kernel) -> Result<(), Unmet> {
+    let unknown = |what| match requires.unknown {
+        UnknownKernel::Run => {
+            debug!("Kernel {what} is unknown, assuming it is fine");
+            Ok(())
+        }
+        UnknownKernel::Skip => Err(Unmet::Unknown(what)),
+    };
+    // Checked when the task is read
+    if let Some(min) = requires.min_version.as_deref().and_then(|min| min.parse().ok()) {
+        match kernel.version {
+            Some(version) if version < min => return Err(Unmet::TooOld { version, min }),
+            Some(_) => {}
+            None => unknown("version")?,
+        }
+    }
+    if !requires.config.is_empty() {
+        match &kernel.config {
+            Some(config) => {
+                let missing: Vec<_> = requires.config.iter().filter(|option| !config.enabled(option)).cloned().collect();
+                if !missing.is_empty() {
+                    return Err(Unmet::Missing(missing));
+                }
+            }
+            None => unknown("configuration")?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, load_config, InvalidVersion, Kernel, KernelConfig, KernelVersion, Unmet};
+    use crate::config::{RequiresKernel, UnknownKernel};
+    use std::{fs, path::PathBuf};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/kernel");
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(FIXTURES).join(name)
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!("5.10".parse(), Ok(KernelVersion(5, 10, 0)));
+        assert_eq!("6.1.0-21-amd64".parse(), Ok(KernelVersion(6, 1, 0)));
+        assert_eq!("4.19.94+".parse(), Ok(KernelVersion(4, 19, 94)));
+        assert_eq!("6.8.0.1-rt".parse(), Ok(KernelVersion(6, 8, 0)));
+        assert_eq!("5".parse::<KernelVersion>(), Err(InvalidVersion("5".into())));
+        assert_eq!("five.ten".parse::<KernelVersion>(), Err(InvalidVersion("five.ten".into())));
+        assert_eq!("5..1".parse::<KernelVersion>(), Err(InvalidVersion("5..1".into())));
+
+        let sorted = ["4.19.94", "5.4", "5.10", "5.10.1", "6.1"].map(|x| x.parse::<KernelVersion>().unwrap());
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(KernelVersion(5, 10, 0).to_string(), "5.10.0");
+    }
+
+    #[test]
+    fn config_file() {
+        let config = KernelConfig::parse(&fs::read_to_string(fixture("config")).unwrap());
+        assert!(config.enabled("CGROUPS"));
+        assert!(config.enabled("CONFIG_FUSE_FS"));
+        assert!(!config.enabled("IO_URING"));
+        assert!(!config.enabled("CONFIG_LOCALVERSION"));
+        assert!(!config.enabled("NOT_IN_THE_FILE"));
+    }
+
+    #[test]
+    fn config_lookup() {
+        let compressed = load_config(&[fixture("missing"), fixture("config.gz")]).unwrap();
+        assert!(compressed.enabled("CGROUPS"));
+        assert!(!compressed.enabled("FUSE_FS"));
+
+        // The first one that exists wins
+        let plain = load_config(&[fixture("config"), fixture("config.gz")]).unwrap();
+        assert!(plain.enabled("FUSE_FS"));
+        assert_eq!(load_config(&[fixture("missing")]), None);
+    }
+
+    fn requires(min_version: Option<&str>, config: &[&str]) -> RequiresKernel {
+        RequiresKernel {
+            min_version: min_version.map(str::to_owned),
+            config: config.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn requirements() {
+        let kernel = Kernel {
+            version: Some(KernelVersion(5, 10, 0)),
+            config: Some(KernelConfig::parse(&fs::read_to_string(fixture("config")).unwrap())),
+        };
+        assert_eq!(check(&requires(Some("5.10"), &["CGROUPS", "CONFIG_FUSE_FS"]), &kernel), Ok(()));
+        assert_eq!(
+            check(&requires(Some("5.15"), &[]), &kernel),
+            Err(Unmet::TooOld { version: KernelVersion(5, 10, 0), min: KernelVersion(5, 15, 0) })
+        );
+        let missing = check(&requires(None, &["CGROUPS", "IO_URING", "BPF"]), &kernel).unwrap_err();
+        assert_eq!(missing.to_string(), "Kernel lacks IO_URING, BPF");
+
+        let unknown = Kernel::default();
+        assert_eq!(check(&requires(Some("5.10"), &["CGROUPS"]), &unknown), Ok(()));
+        let strict = RequiresKernel { unknown: UnknownKernel::Skip, ..requires(Some("5.10"), &[]) };
+        assert_eq!(check(&strict, &unknown), Err(Unmet::Unknown("version")));
+        let strict = RequiresKernel { unknown: UnknownKernel::Skip, ..requires(None, &["CGROUPS"]) };
+        assert_eq!(check(&strict, &unknown).unwrap_err().to_string(), "Kernel configuration is unknown");
+    }
+}
@@ -1,11 +1,51 @@
+// The task file schema is a single json! call
+#![recursion_limit = "256"]
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("alfad makes Linux system calls and only builds for Linux");
+
 pub mod action;
+pub mod adopt;
+pub mod analyze;
+pub mod applet;
 pub mod builtin;
+pub mod check;
+pub mod client;
+pub mod clock;
 pub mod command_line;
 pub mod config;
 pub mod def;
+pub mod desired;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+pub mod doctor;
+pub mod early;
+pub mod event_log;
+pub mod failure;
+pub mod fd;
+pub mod graph;
+pub mod inhibit;
+pub mod install;
+pub mod instance;
+pub mod kernel;
+pub mod log_filter;
+pub mod log_time;
+pub mod logger;
 pub mod ordering;
+pub mod state_cell;
 pub mod perform_action;
+pub mod privilege;
+pub mod protocol;
+pub mod resources;
+pub mod run;
+pub mod scaffold;
+pub mod security;
+pub mod shell;
+pub mod simulate;
+pub mod status;
 pub mod task;
+pub mod trace;
 pub mod validate;
+pub mod version;
 
 pub static VERSION: &str = "0.1";
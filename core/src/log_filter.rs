@@ -0,0 +1,196 @@
+//! What alfad does to the output of a task on its way to the logger, or to
+//! the console without one. Only tasks with `log_rate_limit` or
+//! `log_timestamps` go through here, the output of all others is passed on
+//! as is.
+//!
+//! The rate limit is a token bucket: up to `lines` lines may pass at once,
+//! and it refills at `lines` per `per`. Lines over it are dropped, and once
+//! a line passes again it is preceded by how many were. stdout and stderr
+//! are read from the same pipe, so they count against the same limit.
+
+use crate::config::{LogRateLimit, TaskConfig};
+use std::time::{Duration, Instant};
+
+/// Output without a newline is passed on in pieces of this size
+pub const MAX_LINE: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per second
+    rate: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket for `limit`
+    pub fn new(limit: LogRateLimit, now: Instant) -> Self {
+        let capacity = f64::from(limit.lines);
+        let per = limit.per.0.max(Duration::from_nanos(1));
+        Self { capacity, tokens: capacity, rate: capacity / per.as_secs_f64(), last: now }
+    }
+
+    /// Take a token at `now`, false if there is none left
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    bucket: Option<TokenBucket>,
+    timestamps: bool,
+    /// The start of a line that was not ended yet
+    partial: Vec<u8>,
+    /// Dropped since the last line that passed
+    pending: u64,
+    suppressed: u64,
+}
+
+impl LogFilter {
+    /// Whether the output of `config` has to go through a filter at all
+    pub fn is_needed(config: &TaskConfig) -> bool {
+        config.log_rate_limit.is_some() || config.log_timestamps
+    }
+
+    pub fn new(config: &TaskConfig, now: Instant) -> Self {
+        Self {
+            bucket: config.log_rate_limit.map(|limit| TokenBucket::new(limit, now)),
+            timestamps: config.log_timestamps,
+            partial: Vec::new(),
+            pending: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Lines dropped so far
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// What to pass on for `chunk`, read at `now`. `stamp` gives the
+    /// timestamp prefix and is only called with `log_timestamps`.
+    pub fn feed(&mut self, chunk: &[u8], now: Instant, stamp: impl FnOnce() -> String) -> Vec<u8> {
+        let stamp = self.timestamps.then(stamp).unwrap_or_default();
+        let mut out = Vec::new();
+        for piece in chunk.split_inclusive(|byte| *byte == b'\n') {
+            self.partial.extend_from_slice(piece);
+            while self.partial.len() > MAX_LINE {
+                let mut line: Vec<_> = self.partial.drain(..MAX_LINE).collect();
+                line.push(b'\n');
+                self.line(&line, now, &stamp, &mut out);
+            }
+            if self.partial.ends_with(b"\n") {
+                let line = std::mem::take(&mut self.partial);
+                self.line(&line, now, &stamp, &mut out);
+            }
+        }
+        out
+    }
+
+    /// What is left once the output ended: the last line if it had no
+    /// newline, and how many lines were dropped since the last one passed
+    pub fn finish(&mut self, now: Instant, stamp: impl FnOnce() -> String) -> Vec<u8> {
+        let stamp = self.timestamps.then(stamp).unwrap_or_default();
+        let mut out = Vec::new();
+        if !self.partial.is_empty() {
+            let mut line = std::mem::take(&mut self.partial);
+            line.push(b'\n');
+            self.line(&line, now, &stamp, &mut out);
+        }
+        self.summary(&stamp, &mut out);
+        out
+    }
+
+    fn line(&mut self, line: &[u8], now: Instant, stamp: &str, out: &mut Vec<u8>) {
+        if self.bucket.as_mut().is_some_and(|bucket| !bucket.take(now)) {
+            self.pending += 1;
+            self.suppressed += 1;
+            return;
+        }
+        self.summary(stamp, out);
+        out.extend_from_slice(stamp.as_bytes());
+        out.extend_from_slice(line);
+    }
+
+    fn summary(&mut self, stamp: &str, out: &mut Vec<u8>) {
+        if self.pending > 0 {
+            out.extend_from_slice(format!("{stamp}alfad: suppressed {} lines\n", self.pending).as_bytes());
+            self.pending = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LogFilter, TokenBucket, MAX_LINE};
+    use crate::config::{yaml::Timeout, LogRateLimit, TaskConfig};
+    use std::time::{Duration, Instant};
+
+    fn limit(lines: u32, per: Duration) -> LogRateLimit {
+        LogRateLimit { lines, per: Timeout(per) }
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut bucket = TokenBucket::new(limit(3, Duration::from_secs(1)), start);
+        assert_eq!((0..4).map(|_| bucket.take(start)).collect::<Vec<_>>(), [true, true, true, false]);
+        // One line every third of a second
+        assert!(!bucket.take(at(300)));
+        assert!(bucket.take(at(340)));
+        assert!(!bucket.take(at(340)));
+        // Never more than the burst, however long it was quiet
+        assert_eq!((0..4).filter(|_| bucket.take(at(60_000))).count(), 3);
+        // Time going backwards adds nothing
+        assert!(!bucket.take(at(1000)));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let start = Instant::now();
+        let config = TaskConfig { log_rate_limit: Some(limit(2, Duration::from_secs(1))), ..TaskConfig::new("chatty".into()) };
+        let mut filter = LogFilter::new(&config, start);
+        let out = filter.feed(b"one\ntwo\nthree\nfour\n", start, || unreachable!());
+        assert_eq!(out, b"one\ntwo\n");
+        assert_eq!(filter.suppressed(), 2);
+
+        // Lines split across reads are counted once
+        assert_eq!(filter.feed(b"fi", start, String::new), b"");
+        let later = start + Duration::from_millis(500);
+        assert_eq!(filter.feed(b"ve\nsix\n", later, String::new), b"alfad: suppressed 2 lines\nfive\n");
+        assert_eq!(filter.suppressed(), 3);
+        // What was dropped at the end is still reported
+        assert_eq!(filter.feed(b"seven\neight", later, String::new), b"");
+        assert_eq!(filter.finish(later, String::new), b"alfad: suppressed 3 lines\n");
+        assert_eq!(filter.suppressed(), 5);
+    }
+
+    #[test]
+    fn timestamps() {
+        let start = Instant::now();
+        let config = TaskConfig { log_timestamps: true, ..TaskConfig::new("a".into()) };
+        let mut filter = LogFilter::new(&config, start);
+        assert_eq!(filter.feed(b"one\ntwo\nthr", start, || "[1] ".into()), b"[1] one\n[1] two\n");
+        assert_eq!(filter.feed(b"ee\n", start, || "[2] ".into()), b"[2] three\n");
+        assert_eq!(filter.finish(start, || "[3] ".into()), b"");
+        assert_eq!(filter.feed(b"no newline", start, || "[4] ".into()), b"");
+        assert_eq!(filter.finish(start, || "[5] ".into()), b"[5] no newline\n");
+        assert_eq!(filter.suppressed(), 0);
+
+        let long = vec![b'x'; MAX_LINE * 2 + 1];
+        let out = filter.feed(&long, start, || "> ".into());
+        assert_eq!(out.len(), 2 * (MAX_LINE + 3));
+        assert!(LogFilter::is_needed(&config));
+        assert!(!LogFilter::is_needed(&TaskConfig::new("b".into())));
+    }
+}
@@ -0,0 +1,124 @@
+//! Timestamps of alfad's own messages. They are in UTC unless `utc_logs`
+//! is turned off in defaults.yaml, then they follow TZ or /etc/localtime
+//! of alfad, like those of tasks that don't set their own `locale`.
+
+use nix::libc;
+use std::{
+    fmt, mem,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing_subscriber::fmt::{
+    format::Writer,
+    time::{self, FormatTime},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogTime {
+    pub utc: bool,
+}
+
+impl FormatTime for LogTime {
+    fn format_time(&self, writer: &mut Writer<'_>) -> fmt::Result {
+        match (!self.utc).then(|| local(SystemTime::now())).flatten() {
+            Some(local) => write!(writer, "{local}"),
+            None => time::SystemTime.format_time(writer),
+        }
+    }
+}
+
+type Convert = unsafe extern "C" fn(*const libc::time_t, *mut libc::tm) -> *mut libc::tm;
+
+/// `time` in the local time zone, None if it can't be found out
+pub fn local(time: SystemTime) -> Option<String> {
+    civil(time, libc::localtime_r)
+}
+
+/// `time` in UTC, None if it is out of range
+pub fn utc(time: SystemTime) -> Option<String> {
+    civil(time, libc::gmtime_r)
+}
+
+fn civil(time: SystemTime, convert: Convert) -> Option<String> {
+    let since = time.duration_since(UNIX_EPOCH).ok()?;
+    let seconds = libc::time_t::try_from(since.as_secs()).ok()?;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    match unsafe { convert(&seconds, &mut tm) }.is_null() {
+        true => None,
+        false => Some(rfc3339(&tm, since.subsec_micros())),
+    }
+}
+
+/// Time since boot, as in the messages of the kernel
+pub fn monotonic() -> Duration {
+    let mut now: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+/// Prefix of a line of output, the time since boot and the wall clock
+/// time, like `[   12.345678 2024-05-01T14:03:07.123456+00:00] `
+pub fn stamp(since_boot: Duration, wall: SystemTime, utc: bool) -> String {
+    let wall = if utc { self::utc(wall) } else { local(wall) };
+    format!(
+        "[{:5}.{:06} {}] ",
+        since_boot.as_secs(),
+        since_boot.subsec_micros(),
+        wall.as_deref().unwrap_or("?")
+    )
+}
+
+/// Like 2024-05-01T14:03:07.123456+02:00
+fn rfc3339(tm: &libc::tm, micros: u32) -> String {
+    let offset = tm.tm_gmtoff / 60;
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{micros:06}{sign}{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        offset.abs() / 60,
+        offset.abs() % 60,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{local, monotonic, rfc3339, stamp};
+    use nix::libc;
+    use std::{
+        mem,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    fn tm(offset: i64) -> libc::tm {
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        (tm.tm_year, tm.tm_mon, tm.tm_mday) = (124, 4, 1);
+        (tm.tm_hour, tm.tm_min, tm.tm_sec) = (14, 3, 7);
+        tm.tm_gmtoff = offset as _;
+        tm
+    }
+
+    #[test]
+    fn formats_offset() {
+        assert_eq!(rfc3339(&tm(7200), 123456), "2024-05-01T14:03:07.123456+02:00");
+        assert_eq!(rfc3339(&tm(0), 0), "2024-05-01T14:03:07.000000+00:00");
+        assert_eq!(rfc3339(&tm(-12600), 999999), "2024-05-01T14:03:07.999999-03:30");
+    }
+
+    #[test]
+    fn local_now() {
+        let now = local(SystemTime::now()).unwrap();
+        assert_eq!(now.len(), "2024-05-01T14:03:07.123456+02:00".len(), "{now}");
+        assert!(now.starts_with("20"), "{now}");
+    }
+
+    #[test]
+    fn line_stamp() {
+        let wall = UNIX_EPOCH + Duration::new(1714572187, 123456789);
+        assert_eq!(stamp(Duration::new(12, 345678901), wall, true), "[   12.345678 2024-05-01T14:03:07.123456+00:00] ");
+        assert!(monotonic() <= monotonic());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    command_line::CommandLine,
+    config::defaults::Defaults,
+    log_filter::LogFilter,
+    log_time,
+    security::ExecLabels,
+    task::{ChildProcess, TaskContext},
+};
+use nix::{fcntl::OFlag, unistd::pipe2};
+use smol::{
+    future,
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    Async, Task, Timer, Unblock,
+};
+use std::{
+    fs::File,
+    io,
+    os::fd::OwnedFd,
+    process::Stdio,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{error, info, warn};
+
+/// Pause before a logger that exited is started again
+const RESPAWN_DELAY: Duration = Duration::from_secs(1);
+
+/// Time a logger gets to write out what is left in the pipe once the task
+/// stopped, it is killed afterwards
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pipe between the processes of a task and its logger. alfad keeps both
+/// ends open, so either side can restart without the other noticing.
+#[derive(Debug, Clone)]
+pub struct LogPipe {
+    read: Arc<OwnedFd>,
+    write: Arc<OwnedFd>,
+}
+
+impl LogPipe {
+    pub fn new() -> io::Result<Self> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+        Ok(Self { read: Arc::new(read), write: Arc::new(write) })
+    }
+
+    /// stdout or stderr of a process of the task
+    pub fn output(&self) -> io::Result<Stdio> {
+        Ok(self.write.try_clone()?.into())
+    }
+}
+
+/// The pipe the output of `context` goes to, if it has a logger
+pub fn pipe(context: &TaskContext) -> Option<LogPipe> {
+    context.log_pipe.lock().unwrap().clone()
+}
+
+/// Open the pipe and start supervising the logger of `context`, if it has
+/// one. Output goes where alfad's goes if the pipe can't be opened.
+///
+/// With a [`LogFilter`], alfad reads the pipe itself and passes what is
+/// left on to a second pipe to the logger, or to its own stdout.
+pub fn start(context: &'static TaskContext) -> Option<Task<()>> {
+    let filtered = LogFilter::is_needed(&context.config);
+    if context.config.log_cmd.is_none() && !filtered {
+        return None;
+    }
+    let pipe = open(context)?;
+    *context.log_pipe.lock().unwrap() = Some(pipe.clone());
+    // Only the read ends are kept, the task holds the write end
+    let (read, log_cmd) = (pipe.read, context.config.log_cmd.as_ref());
+    if !filtered {
+        return log_cmd.map(|log_cmd| smol::spawn(supervise(context, log_cmd, read)));
+    }
+    let (logger, output): (_, Box<dyn AsyncWrite + Send + Unpin>) = match log_cmd {
+        Some(log_cmd) => {
+            let logger = open(context)?;
+            let write = logger.write.try_clone().map(File::from).inspect_err(|error| {
+                error!(task = context.config.name, %error, "Could not open the log pipe");
+            });
+            (Some((log_cmd, logger.read)), Box::new(Unblock::new(write.ok()?)))
+        }
+        None => (None, Box::new(Unblock::new(io::stdout()))),
+    };
+    Some(smol::spawn(async move {
+        let supervised = async {
+            if let Some((log_cmd, read)) = logger {
+                supervise(context, log_cmd, read).await;
+            }
+        };
+        future::zip(copy(context, read, output), supervised).await;
+    }))
+}
+
+fn open(context: &TaskContext) -> Option<LogPipe> {
+    LogPipe::new().inspect_err(|error| error!(task = context.config.name, %error, "Could not open the log pipe")).ok()
+}
+
+/// Close the pipe, so the logger exits once it has read everything
+pub async fn stop(context: &TaskContext, logger: Task<()>) {
+    context.log_pipe.lock().unwrap().take();
+    let timeout = async {
+        Timer::after(DRAIN_TIMEOUT).await;
+        warn!(task = context.config.name, "Logger did not exit, killing it");
+    };
+    // Dropping the supervisor kills the logger
+    future::or(logger, timeout).await;
+    context.logger.set(None);
+}
+
+/// Keep the logger running for as long as the pipe is open. Only the read
+/// end is kept here: once the task stopped, a logger started during the
+/// respawn delay still gets what was written and then the end of the pipe.
+async fn supervise(context: &TaskContext, log_cmd: &CommandLine, read: Arc<OwnedFd>) {
+    let name = &context.config.name;
+    loop {
+        // The labels of the task are for its own command lines, not its logger
+        let env = log_cmd.env(&context.config).map_err(Into::into);
+        let spawned = env.and_then(|env| log_cmd.to_command(&env, &ExecLabels::default(), None)).and_then(|mut command| {
+            command.stdin(read.try_clone()?).kill_on_drop(true);
+            Ok(command.spawn()?)
+        });
+        let status = match spawned {
+            Ok(mut child) => {
+                context.logger.set(ChildProcess::from_id(child.id()));
+                let status = child.status().await;
+                context.logger.set(None);
+                status
+            }
+            Err(error) => {
+                error!(task = name, %error, "Could not start the logger");
+                Err(io::Error::other(error))
+            }
+        };
+        if pipe(context).is_none() {
+            info!(task = name, ?status, "Logger exited");
+            return;
+        }
+        warn!(task = name, ?status, "Logger exited, restarting it");
+        Timer::after(RESPAWN_DELAY).await;
+    }
+}
+
+/// Pass the output of the task from `read` on to `output` through its
+/// [`LogFilter`], until every process of the task closed the pipe
+async fn copy(context: &TaskContext, read: Arc<OwnedFd>, mut output: impl AsyncWrite + Unpin) {
+    let name = &context.config.name;
+    let utc = Defaults::load().utc_logs;
+    let stamp = || log_time::stamp(log_time::monotonic(), SystemTime::now(), utc);
+    let mut input = match read.try_clone().map(File::from).and_then(Async::new) {
+        Ok(input) => input,
+        Err(error) => {
+            error!(task = name, %error, "Could not read the log pipe");
+            return;
+        }
+    };
+    let mut filter = LogFilter::new(&context.config, Instant::now());
+    let mut buf = vec![0; 8192];
+    loop {
+        let read = match input.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => {
+                error!(task = name, %error, "Could not read the log pipe");
+                break;
+            }
+        };
+        let suppressed = filter.suppressed();
+        let filtered = filter.feed(&buf[..read], Instant::now(), stamp);
+        context.log_suppressed.fetch_add(filter.suppressed() - suppressed, Ordering::Relaxed);
+        if let Err(error) = output.write_all(&filtered).await {
+            warn!(task = name, %error, "Could not pass on output");
+        }
+    }
+    let rest = filter.finish(Instant::now(), stamp);
+    if let Err(error) = async { output.write_all(&rest).await.and(output.flush().await) }.await {
+        warn!(task = name, %error, "Could not pass on output");
+    }
+}
@@ -1,80 +1,437 @@
+// The task file schema is a single json! call
+#![recursion_limit = "256"]
+
 pub mod action;
+mod adopt;
 pub mod builtin;
+// Virtual time is only used by the integration tests
+#[allow(dead_code)]
+mod clock;
 pub mod command_line;
 pub mod config;
 pub mod def;
+pub mod desired;
+// The rates are only served by builtin::healthz
+#[cfg(feature = "diagnostics")]
+#[allow(dead_code)]
+mod diagnostics;
+// alfad-ctl prints the report with the library's doctor
+#[allow(dead_code)]
+mod doctor;
+mod event_log;
+mod failure;
+mod fd;
+// The binary only reports on the boot, summaries of task files come from
+// the library
+#[allow(dead_code)]
+mod graph;
+mod inhibit;
 mod init;
+// alfad-ctl chooses its instance with the library's client
+#[allow(dead_code)]
+mod instance;
+mod kernel;
+mod log_filter;
+mod log_time;
+mod logger;
 pub mod ordering;
 mod perform_action;
+#[allow(dead_code)]
+mod privilege;
+mod resources;
+mod security;
+pub mod state_cell;
 pub mod task;
+mod trace;
+// The binary only validates on boot, reports are built by the check applet
+#[allow(dead_code)]
 mod validate;
+mod version;
 
-use crate::builtin::{
-    ctl::{CreateCtlPipe, WaitForCommands},
-    IntoConfig,
-};
 use action::ActionError;
 use alfad::{
     action::{Action, SystemCommand},
-    def::{APLT_COMPILE, APLT_CTL, APLT_INIT, APLT_MAIN, DIR_CFG, DIR_CFG_D, DIR_RUN, FILE_CFG_BT},
+    applet::{self, Applet, Dispatch},
+    client::{self, ClientError, ExitCode},
+    config::view::{self, Format},
+    early,
+    def::{APLT_COMPILE, APLT_CTL, APLT_MAIN, DIR_CFG, DIR_CFG_D, FILE_CFG_BT, FILE_CFG_YAML},
+    protocol::{self, Reply},
+    scaffold, shell, simulate, status,
 };
-use anyhow::{Context, Result};
-use clap::Parser;
-use config::{read_yaml_configs, yaml::TaskConfigYaml, TaskConfig};
-use itertools::Itertools;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use config::{defaults::Defaults, single::Origin, yaml::TaskConfigYaml};
+use instance::{Instance, Tagged};
+use log_time::LogTime;
+use version::VersionInfo;
+use nix::unistd::{geteuid, sync};
 use std::{
-    env,
-    fs::{self, OpenOptions},
-    io::Write,
+    env, fs, io,
     path::{Path, PathBuf},
+    process::exit,
+    thread,
+    time::{Duration, Instant},
 };
-use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use tracing::{warn, Level};
+use tracing_subscriber::{layer::SubscriberExt, FmtSubscriber};
 
 pub static VERSION: &str = "0.1";
 
+/// How long alfad-ctl waits for the control pipe of an alfad that is still
+/// starting
+const STARTUP_WAIT: Duration = Duration::from_secs(5);
+const STARTUP_POLL: Duration = Duration::from_millis(100);
+
 fn main() -> Result<()> {
-    let name = env::args().next().unwrap();
-    let name = Path::new(&name).file_name().unwrap().to_str().unwrap();
+    let (applet, mut args) = match applet::dispatch(env::args()) {
+        Dispatch::Run(applet, args) => (applet, args),
+        Dispatch::ListApplets => {
+            print!("{}", applet::list());
+            return Ok(());
+        }
+        Dispatch::Unknown(_) => {
+            eprintln!("{}", ActionError::MainAppletCalled);
+            exit(ExitCode::Usage.into());
+        }
+    };
+    if let Err(error) = select_instance(applet, &mut args) {
+        eprintln!("Error: {error}");
+        exit(ExitCode::Usage.into());
+    }
 
-    tracing::subscriber::set_global_default(FmtSubscriber::builder().with_max_level(Level::TRACE).finish())
+    // Only the daemon reads defaults.yaml this early
+    let utc = !matches!(applet, Applet::Init | Applet::UserSession) || Defaults::load().utc_logs;
+    let format = Tagged::new(Instance::current(), tracing_subscriber::fmt::format().with_timer(LogTime { utc }));
+    let subscriber = FmtSubscriber::builder().with_max_level(Level::TRACE).with_writer(io::stderr).event_format(format).finish();
+    tracing::subscriber::set_global_default(subscriber.with(failure::LogRing))
         .expect("setting default subscriber failed");
 
-    let action = match name {
-        APLT_CTL => Action::parse_from(env::args()),
-        APLT_COMPILE => return compile(),
-        APLT_MAIN => {
-            return Err(ActionError::MainAppletCalled.into());
+    let action = match applet {
+        // Asks the daemon as well
+        Applet::Ctl if args.get(1).is_some_and(|arg| arg == "--version" || arg == "-V") => Action::Version,
+        Applet::Ctl if args.len() == 2 && args[1] == "shell" => return with_exit_code(shell::run()),
+        Applet::Ctl => Action::parse_from(args),
+        Applet::Compile => {
+            let args = CompileArgs::parse_from(args);
+            return match args.command {
+                Some(CompileCommand::Dump { format, dir }) => {
+                    let mut configs = alfad::config::parse_payloads(alfad::config::read_tasks(&dir, alfad::builtin::all()));
+                    alfad::config::strip_sources(&mut configs, &dir);
+                    print!("{}", view::dump(&configs, format)?);
+                    Ok(())
+                }
+                Some(CompileCommand::Simulate { durations, dir }) => simulate(durations.as_deref(), &dir),
+                Some(CompileCommand::Schema) => {
+                    print!("{}", alfad::config::schema::render());
+                    Ok(())
+                }
+                Some(CompileCommand::Analyze { events, tasks }) => analyze(&events, tasks.as_deref()),
+                Some(CompileCommand::Scaffold { template, out, name, binary }) => {
+                    scaffold(template, name.as_deref(), binary.as_deref(), &out)
+                }
+                None => compile(args.quiet, args.strict, &args.from.unwrap_or_else(default_input)),
+            }
+        }
+        Applet::Check => return alfad::check::run(args),
+        Applet::Init => return init::Alfad { builtin: get_built_in() }.run(),
+        Applet::Poweroff | Applet::Reboot | Applet::Halt | Applet::Shutdown => {
+            return with_exit_code(applet::system_command(applet, &args[1..]).map_err(Into::into).and_then(system));
+        }
+        Applet::Install => return alfad::install::run(args),
+        Applet::Run => return alfad::run::run(args),
+        Applet::UserSession => return init::Alfad { builtin: get_built_in() }.run(),
+    };
+
+    if matches!(action, Action::Version) {
+        return with_exit_code(version());
+    }
+    with_exit_code(request(&action))
+}
+
+/// A login session runs an instance of its own, alfad-ctl talks to it with
+/// `--user` or if it runs and the caller is not root
+fn select_instance(applet: Applet, args: &mut Vec<String>) -> Result<()> {
+    match applet {
+        Applet::UserSession => _ = Instance::user(instance::var)?.set(),
+        Applet::Ctl => {
+            let user = args.get(1).is_some_and(|arg| arg == "--user");
+            if user {
+                args.remove(1);
+            }
+            _ = alfad::instance::Instance::for_client(user, geteuid().is_root(), instance::var)?.set();
         }
-        APLT_INIT => return init::Alfad { builtin: get_built_in() }.run(),
-        _ => Action::System { command: SystemCommand::parse_from([String::new()].into_iter().chain(env::args())) },
+        _ => {}
+    }
+    Ok(())
+}
+
+fn request(action: &Action) -> Result<()> {
+    print!("{}", shell::render(action, request_starting(action)?)?);
+    Ok(())
+}
+
+/// What alfad-ctl can tell about alfad when its control pipe can't be opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Startup {
+    /// alfad runs, but has not opened its control pipe yet
+    Pending,
+    /// Nothing suggests that alfad runs
+    NotRunning,
+}
+
+impl Startup {
+    /// `init` are the names pid 1 goes by, `pipe` whether the control pipe
+    /// exists without anyone reading it yet. Its directory is no hint, the
+    /// client creates it for the replies.
+    fn detect(init: &[String], pipe: bool) -> Self {
+        match pipe || init.iter().any(|name| name == APLT_MAIN) {
+            true => Self::Pending,
+            false => Self::NotRunning,
+        }
+    }
+
+    fn message(self) -> String {
+        match self {
+            Self::Pending => {
+                format!("alfad is running but the control channel is not up yet, retrying for {}s", STARTUP_WAIT.as_secs())
+            }
+            Self::NotRunning => "alfad does not appear to be running".to_owned(),
+        }
+    }
+}
+
+/// The comm of pid 1 and the file names of its argv[0] and executable, as
+/// far as they can be read
+fn init_names() -> Vec<String> {
+    let file_name = |path: &Path| Some(path.file_name()?.to_string_lossy().into_owned());
+    let comm = fs::read_to_string("/proc/1/comm").ok().map(|comm| comm.trim_end().to_owned());
+    let argv0 = fs::read("/proc/1/cmdline").ok().and_then(|cmdline| {
+        let argv0 = String::from_utf8_lossy(cmdline.split(|byte| *byte == 0).next()?).into_owned();
+        file_name(Path::new(&argv0))
+    });
+    let exe = fs::read_link("/proc/1/exe").ok().and_then(|exe| file_name(&exe));
+    [comm, argv0, exe].into_iter().flatten().collect()
+}
+
+/// Send `action`, waiting a moment for the control pipe if alfad is still
+/// starting. If alfad can't be reached, tell whether it runs at all.
+fn request_starting(action: &Action) -> Result<Reply> {
+    let error = match client::request(action) {
+        Err(error @ ClientError::Unreachable(_)) => error,
+        result => return Ok(result?),
     };
+    let instance = alfad::instance::Instance::current();
+    let init = if instance.is_user() { Vec::new() } else { init_names() };
+    let startup = Startup::detect(&init, instance.run_dir().join(APLT_CTL).exists());
+    if startup == Startup::NotRunning {
+        return Err(error).context(startup.message());
+    }
+    eprintln!("{}", startup.message());
+    let deadline = Instant::now() + STARTUP_WAIT;
+    loop {
+        thread::sleep(STARTUP_POLL);
+        match client::request(action) {
+            Err(ClientError::Unreachable(_)) if Instant::now() < deadline => continue,
+            Err(error @ ClientError::Unreachable(_)) => {
+                let message = format!("The control channel of alfad did not come up within {}s", STARTUP_WAIT.as_secs());
+                return Err(error).context(message);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Exit with the code documented for `result`, so scripts can tell
+/// failures of the client apart
+fn with_exit_code(result: Result<()>) -> Result<()> {
+    if let Err(error) = &result {
+        eprintln!("Error: {error:?}");
+        exit(ExitCode::of(error).into());
+    }
+    result
+}
 
-    OpenOptions::new()
-        .write(true)
-        .open(PathBuf::from(DIR_RUN).join(APLT_CTL))
-        .context("alfad communication socket not found")?
-        .write_all(action.to_string().as_bytes())?;
+/// Print what the client and the daemon were built from, warn if they differ
+fn version() -> Result<()> {
+    let client = VersionInfo::current();
+    println!("alfad-ctl {client}");
+    let daemon: VersionInfo = match request_starting(&Action::Version)? {
+        Reply::Ok(message) => serde_json::from_str(&message)?,
+        Reply::Error { message, .. } => bail!("alfad does not report its version: {message}"),
+    };
+    println!("alfad {daemon}");
+    if client.differs(&daemon) {
+        eprintln!("warning: alfad-ctl and the running alfad were built from different versions");
+    }
     Ok(())
 }
 
+/// Ask the daemon to go down. If it can't be reached, root may still take
+/// the machine down directly, without stopping any tasks.
+fn system(command: SystemCommand) -> Result<()> {
+    let error = match client::request(&Action::System { command: command.clone(), when: None, force: false }).and_then(client::body) {
+        Ok(_) => return Ok(()),
+        Err(ClientError::Unreachable(error)) => error,
+        Err(error) => return Err(error.into()),
+    };
+    if !geteuid().is_root() {
+        bail!("alfad is not reachable ({error}), {command} was not performed");
+    }
+    warn!("alfad is not reachable ({error}), performing {command} without stopping tasks");
+    sync();
+    let error = alfad::perform_action::reboot(&command);
+    bail!("{command} failed: {error}")
+}
+
 fn get_built_in() -> Vec<TaskConfigYaml> {
-    vec![CreateCtlPipe.into_config(), WaitForCommands.into_config()]
+    builtin::all()
+}
+
+/// Byte-compile the configuration, or inspect it
+#[derive(Debug, Parser)]
+#[command(name = APLT_COMPILE)]
+struct CompileArgs {
+    #[command(subcommand)]
+    command: Option<CompileCommand>,
+    /// Only report problems, no statistics
+    #[arg(long)]
+    quiet: bool,
+    /// Fail on loops and missing dependencies instead of writing the cache
+    #[arg(long)]
+    strict: bool,
+    /// Directory of task files or single task file to compile, by default
+    /// alfad.yaml or alfad.d, whichever comes first in `config_order`
+    #[arg(long)]
+    from: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum CompileCommand {
+    /// Print the effective task set, including builtins and markers
+    Dump {
+        #[arg(long, value_enum, default_value_t)]
+        format: Format,
+        /// Directory containing the task files, or a single file like alfad.yaml
+        #[arg(default_value = DIR_CFG_D)]
+        dir: PathBuf,
+    },
+    /// Print the order tasks would start in, without running anything
+    Simulate {
+        /// How long tasks take, like `network: 2s`, the rest take no time
+        #[arg(long)]
+        durations: Option<PathBuf>,
+        /// Directory containing the task files, or a single file like alfad.yaml
+        #[arg(default_value = DIR_CFG_D)]
+        dir: PathBuf,
+    },
+    /// Print the JSON Schema of task files, for editors and linters
+    Schema,
+    /// Print the boot report of an event log, as alfad logs it once the
+    /// boot is complete
+    Analyze {
+        /// The event log, written to `event_log` of the defaults
+        events: PathBuf,
+        /// Task files of the machine, to tell who waited for whom
+        #[arg(long)]
+        tasks: Option<PathBuf>,
+    },
+    /// Write the task files of an example configuration to start from
+    Scaffold {
+        #[arg(value_enum)]
+        template: scaffold::Template,
+        /// Directory the task files are written to
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+        /// Name of the service, by default the one of the template
+        #[arg(long)]
+        name: Option<String>,
+        /// Program the service runs, by default the one of the template
+        #[arg(long)]
+        binary: Option<String>,
+    },
+}
+
+/// Boot the configuration in `dir` with stubs instead of the payloads
+fn simulate(durations: Option<&Path>, dir: &Path) -> Result<()> {
+    let durations = durations.map(simulate::read_durations).transpose()?.unwrap_or_default();
+    let configs = alfad::config::parse_payloads(alfad::config::read_tasks(dir, alfad::builtin::all()));
+    let simulation = simulate::simulate(configs, &durations);
+    print!("{simulation}");
+    match simulation.stuck.len() {
+        0 => Ok(()),
+        stuck => bail!("{stuck} task(s) would never finish"),
+    }
+}
+
+/// Replay the event log at `events` into the boot report
+fn analyze(events: &Path, tasks: Option<&Path>) -> Result<()> {
+    let events = alfad::analyze::read_events(events)?;
+    let configs = tasks.map(|dir| alfad::config::parse_payloads(alfad::config::read_tasks(dir, alfad::builtin::all())));
+    println!("{}", alfad::analyze::analyze(&events, configs.as_deref().unwrap_or_default()));
+    Ok(())
+}
+
+/// Write `template` to `out`, with the service of the template unless
+/// `name` or `binary` are given
+fn scaffold(template: scaffold::Template, name: Option<&str>, binary: Option<&str>, out: &Path) -> Result<()> {
+    let (default_name, default_binary) = template.service();
+    let name = name.unwrap_or(default_name);
+    alfad::config::name::check(name)?;
+    for path in scaffold::write(template, name, binary.unwrap_or(default_binary), out)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+/// alfad.yaml if it exists and comes before alfad.d in `config_order`,
+/// alfad.d otherwise
+fn default_input() -> PathBuf {
+    let file = Path::new(DIR_CFG).join(FILE_CFG_YAML);
+    let order = Origin::order(&Defaults::load().config_order);
+    let position = |origin| order.iter().position(|other| *other == origin);
+    match position(Origin::File) < position(Origin::Dir) && file.exists() {
+        true => file,
+        false => PathBuf::from(DIR_CFG_D),
+    }
 }
 
 /// Byte-compile configuration into a cache file for faster load.
 /// NOTE: Optional operation.
-fn compile() -> Result<()> {
+fn compile(quiet: bool, strict: bool, input: &Path) -> Result<()> {
     let tgt = PathBuf::from(DIR_CFG);
-    let data = postcard::to_allocvec(&(
-        VERSION,
-        read_yaml_configs(&PathBuf::from(DIR_CFG_D), get_built_in())
-            .into_iter()
-            .filter(|x| get_built_in().iter().all(|bi| bi.name != x.name))
-            .collect_vec(),
-    ))?;
-    let (_, _): (String, Vec<TaskConfig>) = postcard::from_bytes(data.as_ref())?;
-
-    fs::write(tgt.join(FILE_CFG_BT), data)?;
+    let configs = alfad::config::parse_payloads(alfad::config::read_tasks(input, alfad::builtin::all()));
+    let report = alfad::validate::report(&configs, strict);
+    for finding in report.findings.iter() {
+        eprintln!("{finding}");
+    }
+    if strict && report.errors() > 0 {
+        bail!("{} errors, not writing {FILE_CFG_BT}", report.errors());
+    }
+
+    let stats = config::write_cache(input, &tgt.join(FILE_CFG_BT), get_built_in())?;
+    if !quiet {
+        println!("{}", alfad::graph::Summary::new(&configs));
+        println!("{stats}");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::Startup;
+
+    #[test]
+    fn startup() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        // Started as /sbin/init, the executable still gives it away
+        assert_eq!(Startup::detect(&names(&["init", "init", "alfad"]), false), Startup::Pending);
+        assert_eq!(Startup::detect(&names(&["alfad"]), false), Startup::Pending);
+        assert_eq!(Startup::detect(&[], true), Startup::Pending);
+        assert_eq!(Startup::detect(&names(&["systemd", "init"]), false), Startup::NotRunning);
+        assert_eq!(Startup::detect(&names(&["alfad-ctl"]), false), Startup::NotRunning);
+
+        assert_eq!(Startup::Pending.message(), "alfad is running but the control channel is not up yet, retrying for 5s");
+        assert_eq!(Startup::NotRunning.message(), "alfad does not appear to be running");
+    }
+}
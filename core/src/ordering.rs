@@ -1,67 +1,170 @@
-use crate::config::{
-    yaml::{PayloadYaml, TaskConfigYaml},
-    TaskConfig,
+use crate::{
+    config::{
+        defaults::Inherited,
+        yaml::{PayloadYaml, TaskConfigYaml},
+        Quorum, TaskConfig,
+    },
+    def::SRC_GENERATED,
+    validate::{Severity, ValidationReport},
 };
 use itertools::Itertools;
-use std::collections::HashMap;
+use smallvec::SmallVec;
+use std::collections::{HashMap, HashSet};
 use tracing::warn;
 
-pub fn construct_markers(configs: &[TaskConfigYaml]) -> Vec<TaskConfigYaml> {
-    let mut map = HashMap::new();
-    configs.iter().for_each(|config| {
-        config
-            .group
-            .as_ref()
-            .map(|group| format!("group::{group}"))
-            .map(|name| {
-                map.entry(name.clone())
-                    .or_insert_with(|| TaskConfigYaml {
-                        name,
-                        cmd: PayloadYaml::Marker,
-                        ..Default::default()
-                    })
-                    .after(&config.name)
-            });
-    });
-    configs.iter().for_each(|config| {
+/// Prefixes of the names alfad generates or uses for itself
+pub const RESERVED_PREFIXES: [&str; 4] = ["group::", "feature::", "target::", "builtin::"];
+
+/// The reserved prefix `config` uses for its name, unless it may. Task
+/// files may name markers that way, and take over builtins by their name.
+pub fn reserved_prefix(config: &TaskConfigYaml) -> Option<&'static str> {
+    let prefix = RESERVED_PREFIXES.into_iter().find(|prefix| config.name.starts_with(prefix))?;
+    match (&config.cmd, prefix) {
+        (PayloadYaml::Marker, _) | (PayloadYaml::Reference { .. }, "builtin::") => None,
+        _ => Some(prefix),
+    }
+}
+
+/// Add a marker for every group and feature to `configs`. A marker waits
+/// for its members in both `with` and `after`: it runs once any member runs
+/// and is Done once the members are, all of them or any depending on its
+/// quorum. Groups wait for all members unless `groups` says otherwise,
+/// features for any provider. Markers from task files keep their own
+/// dependencies and quorum, the members are added to them.
+///
+/// Repeated `after` entries are dropped. Groups and features sharing a
+/// name are reported, their markers would be near duplicates that are
+/// easily mixed up.
+pub fn construct_markers(configs: &mut Vec<TaskConfigYaml>, groups: &HashMap<String, Quorum>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    for config in configs.iter_mut() {
+        let repeated = dedup(&mut config.after);
+        if !repeated.is_empty() {
+            let message = format!("{} lists {} in after more than once", config.name, repeated.join(", "));
+            report.push(Severity::Warning, Some(&config.name), config.source.as_deref(), message);
+        }
+    }
+
+    let group_names: HashSet<_> = configs.iter().filter_map(|config| config.group.as_deref()).collect();
+    let mut collisions = HashSet::new();
+    for config in configs.iter() {
+        for feature in config.provides.iter().filter(|feature| group_names.contains(feature.as_str())) {
+            let message = if config.group.as_ref() == Some(feature) {
+                format!("{} provides {feature}, which is also its group", config.name)
+            } else if collisions.insert(feature) {
+                format!("{} provides {feature}, which is also the name of a group", config.name)
+            } else {
+                continue;
+            };
+            report.push(Severity::Error, Some(&config.name), config.source.as_deref(), message);
+        }
+    }
+
+    let mut memberships = Vec::new();
+    for config in configs.iter() {
+        if let Some(group) = &config.group {
+            let quorum = groups.get(group).copied().unwrap_or_default();
+            memberships.push((format!("group::{group}"), config.name.clone(), quorum));
+        }
         for feature in config.provides.iter() {
-            let name = format!("feature::{feature}");
-            let mut conf = TaskConfigYaml {
-                name: name.clone(),
+            memberships.push((format!("feature::{feature}"), config.name.clone(), Quorum::Any));
+        }
+    }
+
+    let explicit: HashMap<_, _> = configs
+        .iter()
+        .enumerate()
+        .filter(|(_, config)| matches!(config.cmd, PayloadYaml::Marker))
+        .map(|(index, config)| (config.name.clone(), index))
+        .collect();
+    let mut generated: HashMap<String, TaskConfigYaml> = HashMap::new();
+    for (name, member, quorum) in memberships {
+        let marker = match explicit.get(&name) {
+            Some(index) => &mut configs[*index],
+            None => generated.entry(name.clone()).or_insert_with(|| TaskConfigYaml {
+                name,
                 cmd: PayloadYaml::Marker,
+                quorum,
+                source: Some(SRC_GENERATED.into()),
                 ..Default::default()
-            };
-            conf.after(&config.name);
-            if let Some(old) = map.insert(name, conf) {
-                warn!(
-                    "Overriding feature::{feature}, already provided by {}",
-                    old.name
-                )
-            }
+            }),
+        };
+        if !marker.after.contains(&member) {
+            marker.after(&member);
+        }
+        if !marker.with.contains(&member) {
+            marker.with.push(member);
         }
+    }
+    configs.extend(generated.into_values());
+    report
+}
+
+/// Let the members of a group take the settings they leave unset from the
+/// marker file of the group, see [`Inherited`]. Members that are markers
+/// themselves are left alone.
+pub fn inherit_from_groups(configs: &mut [TaskConfigYaml]) {
+    let groups: HashMap<String, Inherited> = configs
+        .iter()
+        .filter(|config| matches!(config.cmd, PayloadYaml::Marker))
+        .filter_map(|config| Some((config.name.strip_prefix("group::")?.to_owned(), config.inherited())))
+        .collect();
+    for config in configs.iter_mut().filter(|config| !matches!(config.cmd, PayloadYaml::Marker)) {
+        if let Some(group) = config.group.as_ref().and_then(|group| groups.get(group)) {
+            config.inherit(group);
+        }
+    }
+}
+
+/// Drop all but the first of equal entries, returns the ones dropped
+fn dedup(names: &mut SmallVec<[String; 1]>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut repeated = Vec::new();
+    names.retain(|name| {
+        if seen.insert(name.clone()) {
+            return true;
+        }
+        if !repeated.contains(name) {
+            repeated.push(name.clone());
+        }
+        false
     });
-    map.into_values().collect()
+    repeated
 }
 
-#[cfg(feature = "before")]
+/// [`resolve_before`] with the `before` feature, otherwise tasks that
+/// name some are only warned about
+pub fn maybe_resolve_before(configs: Vec<TaskConfigYaml>) -> Vec<TaskConfigYaml> {
+    if cfg!(feature = "before") {
+        return resolve_before(configs);
+    }
+    for config in configs.iter().filter(|config| !config.before.is_empty()) {
+        warn!("{} wants to run before {}, which is ignored without the before feature", config.name, config.before.join(", "));
+    }
+    configs
+}
+
+/// Add every task to the `after` of the tasks it runs before. Its own
+/// `before` is kept as written.
 pub fn resolve_before(configs: Vec<TaskConfigYaml>) -> Vec<TaskConfigYaml> {
     // TODO: this can probably be done faster with unsafe then with RefCells
     use std::cell::RefCell;
 
-    use tracing::warn;
-
     let map: HashMap<_, _> = configs
         .into_iter()
         .map(|config| (config.name.clone(), RefCell::new(config)))
         .collect();
 
     for (n, v) in map.iter() {
-        v.borrow_mut()
-            .before
-            .drain(..)
+        let before = v.borrow().before.clone();
+        before
+            .into_iter()
             .for_each(|name| match map.get(&name) {
                 Some(x) => {
-                    x.borrow_mut().after(n);
+                    let mut x = x.borrow_mut();
+                    if !x.after.contains(n) {
+                        x.after(n);
+                    }
                 }
                 None => warn!(
                     "{n} tried to run before {name}, which does not exist ({n} will still run)"
@@ -115,3 +218,96 @@ pub fn sort(configs: Vec<TaskConfig>) -> Vec<TaskConfig> {
     res.extend(map.into_values());
     res
 }
+
+#[cfg(test)]
+mod test {
+    use super::construct_markers;
+    use crate::{
+        config::{
+            yaml::{PayloadYaml, TaskConfigYaml},
+            Quorum,
+        },
+        validate::Severity,
+    };
+    use smallvec::SmallVec;
+    use std::collections::HashMap;
+
+    fn task(name: &str, group: Option<&str>, provides: &[&str], after: &[&str]) -> TaskConfigYaml {
+        TaskConfigYaml {
+            name: name.to_owned(),
+            cmd: PayloadYaml::Service("true".to_owned()),
+            group: group.map(str::to_owned),
+            provides: provides.iter().map(|x| x.to_string()).collect(),
+            after: after.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn marker<'a>(configs: &'a [TaskConfigYaml], name: &str) -> &'a TaskConfigYaml {
+        configs.iter().find(|config| config.name == name).unwrap()
+    }
+
+    fn sorted(names: &[String]) -> Vec<&str> {
+        let mut names: Vec<_> = names.iter().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn members() {
+        let mut configs = vec![
+            task("udev", Some("early"), &["dev"], &[]),
+            task("mount", Some("early"), &["fs", "dev"], &[]),
+            TaskConfigYaml { name: "group::late".to_owned(), quorum: Quorum::Any, ..task("", None, &[], &["mount"]) },
+            task("getty", Some("late"), &[], &[]),
+        ];
+        configs[2].cmd = PayloadYaml::Marker;
+        let report = construct_markers(&mut configs, &HashMap::from([("early".to_owned(), Quorum::Any)]));
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+
+        let early = marker(&configs, "group::early");
+        assert_eq!((sorted(&early.after), early.quorum), (vec!["mount", "udev"], Quorum::Any));
+        assert_eq!(sorted(&early.with), ["mount", "udev"]);
+        assert_eq!(sorted(&marker(&configs, "feature::dev").after), ["mount", "udev"]);
+        assert_eq!(marker(&configs, "feature::fs").with, ["mount"]);
+        // Written in a task file, so it keeps what it had
+        let late = marker(&configs, "group::late");
+        assert_eq!((late.after.to_vec(), late.quorum), (vec!["mount".to_owned(), "getty".to_owned()], Quorum::Any));
+        assert_eq!(configs.iter().filter(|config| config.name == "group::late").count(), 1);
+        assert_eq!(configs.len(), 7);
+    }
+
+    #[test]
+    fn repeated_after() {
+        let mut configs = vec![task("a", None, &[], &["b", "group::net", "b", "b", "group::net"]), task("b", Some("net"), &[], &[])];
+        let report = construct_markers(&mut configs, &HashMap::new());
+        let expected: SmallVec<[String; 1]> = ["b", "group::net"].into_iter().map(str::to_owned).collect();
+        assert_eq!(configs[0].after, expected);
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].severity, Severity::Warning);
+        assert_eq!(report.findings[0].message, "a lists b, group::net in after more than once");
+    }
+
+    #[test]
+    fn group_and_feature_collide() {
+        let mut configs = vec![
+            task("dhcp", Some("network"), &["network"], &[]),
+            task("wifi", Some("wireless"), &[], &[]),
+            task("iwd", None, &["wireless", "uplink"], &[]),
+            task("wpa", None, &["wireless"], &[]),
+        ];
+        let report = construct_markers(&mut configs, &HashMap::new());
+        let messages: Vec<_> = report.findings.iter().map(|finding| (finding.severity, finding.message.as_str())).collect();
+        assert_eq!(
+            messages,
+            [
+                (Severity::Error, "dhcp provides network, which is also its group"),
+                (Severity::Error, "iwd provides wireless, which is also the name of a group"),
+            ]
+        );
+        // Still generated, so tasks waiting for either one do not hang
+        for name in ["group::network", "feature::network", "group::wireless", "feature::wireless"] {
+            assert!(!marker(&configs, name).with.is_empty(), "{name}");
+        }
+    }
+}
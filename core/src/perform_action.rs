@@ -1,119 +1,894 @@
 use crate::{
-    action::{Action, ActionError, SystemCommand},
-    task::{ContextMap, ExitReason, TaskContext, TaskState},
+    action::{Action, ActionError, Delay, SystemCommand},
+    adopt,
+    builtin::{self, bootcount, state, timesync},
+    clock,
+    config::{self, defaults::Defaults, diff::ConfigDiff, view::TaskView, TaskConfig},
+    desired::{DesiredState, DisabledFile},
+    doctor::{self, Surroundings},
+    event_log,
+    inhibit::{Gate, Inhibitor, Inhibitors, DEFAULT_TTL},
+    instance::Instance,
+    status::{Deactivation, TaskStatus},
+    task::{self, ContextMap, ExitReason, ProcFs, SignalError, TaskContext, TaskState, WaitError},
+    version::VersionInfo,
 };
-use futures::{future::join_all, select, FutureExt};
+use futures::future::join_all;
+use lazy_static::lazy_static;
 use nix::{
-    libc::{
-        c_long, syscall, LINUX_REBOOT_CMD_HALT, LINUX_REBOOT_CMD_POWER_OFF,
-        LINUX_REBOOT_CMD_RESTART,
+    errno::Errno,
+    sys::{
+        reboot::{self as sys_reboot, RebootMode},
+        signal::Signal,
     },
-    sys::signal::Signal,
+    unistd::sync,
 };
-use std::{ffi::c_int, str::FromStr, time::Duration};
-use thiserror::Error;
-use tracing::{error, info};
+use std::{
+    collections::HashMap,
+    process,
+    str::FromStr,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+lazy_static! {
+    static ref SHUTDOWN_HOOKS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+    static ref REBOOT: Mutex<fn(&SystemCommand) -> Errno> = Mutex::new(reboot);
+}
+
+/// Register a function to be called right before the system goes down.
+pub fn on_shutdown(hook: fn()) {
+    let mut hooks = SHUTDOWN_HOOKS.lock().unwrap();
+    if !hooks.contains(&hook) {
+        hooks.push(hook);
+    }
+}
+
+/// Replace the syscall that finally takes the system down, so tests can go
+/// through a complete shutdown
+#[allow(dead_code)] // Only used by the integration tests
+pub fn set_reboot(reboot: fn(&SystemCommand) -> Errno) {
+    *REBOOT.lock().unwrap() = reboot;
+}
+
+fn run_shutdown_hooks() {
+    SHUTDOWN_HOOKS.lock().unwrap().iter().for_each(|hook| hook());
+}
+
+/// Perform an action, returns a message for the client.
+pub async fn perform<'a>(s: &'a str, context: ContextMap<'static>) -> Result<String, ActionError> {
+    perform_as(s, None, context).await
+}
 
-pub async fn perform<'a>(s: &'a str, context: ContextMap<'static>) -> Result<(), ActionError> {
+/// Perform an action for the client with `uid`, if it is known
+pub async fn perform_as(s: &str, uid: Option<u32>, context: ContextMap<'static>) -> Result<String, ActionError> {
     match Action::from_str(s)? {
-        Action::Kill { task, force } => kill_by_name(&task, force, context).await?,
-        Action::Deactivate { task, force } => {
-            kill_by_name(&task, force, context).await?;
-            get_context(context, &task)?
-                .update_state(TaskState::Concluded(ExitReason::Deactivated))
-                .await;
+        Action::Kill { task, force, force_protected } => {
+            check_protected(&task, "kill", force_protected, context)?;
+            return Ok(kill_by_name(&task, force, context).await?.unwrap_or_default());
+        }
+        Action::Deactivate { task, force, force_protected } => {
+            check_protected(&task, "deactivate", force_protected, context)?;
+            let note = kill_by_name(&task, force, context).await?;
+            get_context(context, &task)?.deactivate(Deactivation::operator("deactivate")).await;
+            return Ok(note.unwrap_or_default());
         }
-        Action::Restart { task, force } => {
+        Action::Restart { task, force, force_protected } => {
+            check_protected(&task, "restart", force_protected, context)?;
             kill_by_name(&task, force, context).await?;
-            context.wait_for_conclusion(&task).await;
-            start(task, force, context).await?;
-        }
-        Action::Start { task, force } => start(task, force, context).await?,
-        Action::System { command } => match command {
-            SystemCommand::Poweroff => {
-                info!("Powering off...");
-                kill_all(false, context).await;
-                let error = fee1dead(LINUX_REBOOT_CMD_POWER_OFF);
-                error!("Error {error}");
+            wait_stopped(&task, context).await?;
+            start(&task, force, context).await?;
+        }
+        Action::TryRestart { task } => return try_restart(&task, context).await,
+        Action::CondStart { task } => return cond_start(&task, context).await,
+        Action::Stop { task, force } => return stop(&task, DesiredState::Stopped, force, context).await,
+        Action::Disable { task, force } => return stop(&task, DesiredState::Disabled, force, context).await,
+        Action::Enable { task } => {
+            for name in targets(context, &task)? {
+                let task = get_context(context, name)?;
+                if !task.config.payload.is_marker() {
+                    task.desired.set(DesiredState::Enabled);
+                    persist(name, false).await?;
+                }
             }
-            SystemCommand::Restart => {
-                info!("Restarting...");
-                let error = fee1dead(LINUX_REBOOT_CMD_RESTART);
-                error!("Error {error}");
+        }
+        Action::Start { task, force } => {
+            for name in targets(context, &task)? {
+                start(name, force, context).await?;
             }
-            SystemCommand::Halt => {
-                info!("Halting...");
-                let error = fee1dead(LINUX_REBOOT_CMD_HALT);
-                error!("Error {error}");
+        }
+        Action::Adopt { task, pid } => adopt(&task, pid, context).await?,
+        Action::SetRespawn { task, policy } => {
+            let task = get_context(context, &task)?;
+            *task.respawn.write().await = policy;
+            *task.respawn_attempts.write().await = 0;
+            info!(task = task.config.name, %policy, "Respawn changed");
+        }
+        Action::Poweroff { when, force } => return schedule(SystemCommand::Poweroff, when, force, context),
+        Action::Reboot { when, force } => return schedule(SystemCommand::Restart, when, force, context),
+        Action::Halt { when, force } => return schedule(SystemCommand::Halt, when, force, context),
+        Action::System { command, when, force } => return schedule(command, when, force, context),
+        Action::Shutdown { cancel } => {
+            let mut schedule = SCHEDULE.lock().unwrap();
+            let pending = if cancel { schedule.cancel() } else { schedule.pending(clock::now()) };
+            return Ok(match (pending, cancel) {
+                (Some(pending), true) => format!("Cancelled {}", pending.command),
+                (Some(pending), false) => format!("{} in {}s", pending.command, pending.remaining.as_secs()),
+                (None, _) => "No shutdown scheduled".to_owned(),
+            });
+        }
+        Action::Inhibit { what, who, why, mode, ttl } => {
+            info!(who, %mode, "Shutdown inhibited: {why}");
+            let ttl = ttl.map_or(DEFAULT_TTL, |ttl| ttl.0);
+            let id = INHIBITORS.lock().unwrap().take(Inhibitor { what, mode, who, why, uid }, ttl, clock::now());
+            return Ok(id.to_string());
+        }
+        Action::Uninhibit { id } => {
+            if !INHIBITORS.lock().unwrap().release(id, clock::now()) {
+                return Err(ActionError::NoLease(id));
             }
-        },
+        }
+        Action::Inhibitors => {
+            let now = clock::now();
+            let leases: Vec<_> = INHIBITORS.lock().unwrap().active(now).iter().map(|lease| lease.describe(now)).collect();
+            return Ok(if leases.is_empty() { "No inhibitors".to_owned() } else { leases.join("\n") });
+        }
+        Action::Notify { assignments } => {
+            for assignment in assignments {
+                match assignment.as_str() {
+                    "TIME_SYNCHRONIZED=1" => timesync::notify_synchronized(),
+                    _ => debug!(assignment, "Ignoring notification"),
+                }
+            }
+        }
+        Action::List { .. } => return Ok(list(context)),
+        Action::Version => return Ok(serde_json::to_string(&VersionInfo::daemon(context.0.len())).unwrap_or_default()),
+        Action::MarkBootGood => {
+            let counter = bootcount::counter().ok_or(ActionError::NoBootCounter)?;
+            bootcount::mark_good(counter.as_ref())?;
+            return Ok("Boot marked as good".to_owned());
+        }
+        Action::Reload { apply, apply_now, restart_changed } => {
+            let on_disk = if apply {
+                let staged = STAGED_RELOAD.lock().unwrap().take(clock::now());
+                staged.ok_or(ActionError::NothingStaged(RELOAD_CONFIRM))?
+            } else {
+                smol::unblock(|| config::read_task_files(builtin::all())).await
+            };
+            if apply || apply_now {
+                return Ok(apply_reload(on_disk, restart_changed, context).await);
+            }
+            return Ok(stage_reload(on_disk, context).await);
+        }
+        Action::Doctor { .. } => {
+            let (on_disk, state_dir) = smol::unblock(|| (config::read_task_files(builtin::all()), doctor::state_dir())).await;
+            let findings = doctor::examine(context, &Surroundings { processes: &ProcFs, on_disk: &on_disk, state_dir });
+            return Ok(serde_json::to_string(&findings).unwrap_or_default());
+        }
+        Action::Cat { task } => {
+            let task = get_context(context, &task)?;
+            let yaml = serde_yaml::to_string(&TaskView::from(&*task.config)).unwrap_or_default();
+            return Ok(yaml.trim_end().to_owned());
+        }
+        #[cfg(feature = "diagnostics")]
+        Action::Diag { task } => return Ok(get_context(context, &task)?.diagnostics().to_string()),
+    }
+    Ok(String::new())
+}
+
+lazy_static! {
+    static ref SCHEDULE: Mutex<ShutdownSchedule> = Mutex::new(ShutdownSchedule::default());
+    static ref INHIBITORS: Mutex<Inhibitors> = Mutex::new(Inhibitors::default());
+    static ref STAGED_RELOAD: Mutex<StagedReload> = Mutex::new(StagedReload::default());
+}
+
+/// How long the changes found by a reload wait for reload --apply
+pub const RELOAD_CONFIRM: Duration = Duration::from_secs(300);
+
+/// How often a delayed shutdown checks whether its inhibitors are gone
+const INHIBIT_POLL: Duration = Duration::from_secs(1);
+
+/// The pending poweroff, reboot or halt. There is at most one, scheduling
+/// another command replaces it.
+#[derive(Debug, Default)]
+pub struct ShutdownSchedule {
+    pending: Option<(u64, SystemCommand, Instant)>,
+    next_id: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingShutdown {
+    pub command: SystemCommand,
+    pub remaining: Duration,
+}
+
+impl ShutdownSchedule {
+    /// Schedule `command` to be performed after `delay`, returns the id
+    /// the timer has to present to [`ShutdownSchedule::take`] once it expires.
+    pub fn schedule(&mut self, command: SystemCommand, delay: Duration, now: Instant) -> u64 {
+        self.next_id += 1;
+        if let Some((_, old, _)) = self.pending.replace((self.next_id, command, now + delay)) {
+            info!("Replacing scheduled {old}");
+        }
+        self.next_id
+    }
+
+    pub fn cancel(&mut self) -> Option<PendingShutdown> {
+        self.pending
+            .take()
+            .map(|(_, command, _)| PendingShutdown { command, remaining: Duration::ZERO })
+    }
+
+    pub fn pending(&self, now: Instant) -> Option<PendingShutdown> {
+        self.pending
+            .as_ref()
+            .map(|(_, command, at)| PendingShutdown { command: command.clone(), remaining: at.saturating_duration_since(now) })
+    }
+
+    /// Remove the scheduled command if it is still the one with `id`,
+    /// i.e. it has been neither cancelled nor replaced.
+    pub fn take(&mut self, id: u64) -> Option<SystemCommand> {
+        match self.pending {
+            Some((pending, _, _)) if pending == id => self.pending.take().map(|(_, command, _)| command),
+            _ => None,
+        }
+    }
+}
+
+/// The task files read by the last reload, until they are applied or
+/// [`RELOAD_CONFIRM`] is over
+#[derive(Debug, Default)]
+pub struct StagedReload {
+    staged: Option<(Vec<TaskConfig>, Instant)>,
+}
+
+impl StagedReload {
+    /// Replaces whatever an earlier reload staged
+    pub fn stage(&mut self, configs: Vec<TaskConfig>, now: Instant) {
+        self.staged = Some((configs, now + RELOAD_CONFIRM));
+    }
+
+    pub fn clear(&mut self) {
+        self.staged = None;
+    }
+
+    /// The staged task files, unless they expired
+    pub fn take(&mut self, now: Instant) -> Option<Vec<TaskConfig>> {
+        self.staged.take().filter(|(_, until)| now < *until).map(|(configs, _)| configs)
+    }
+}
+
+/// Perform `command` after `when`, unless it is cancelled or replaced in the
+/// meantime. Unless `force` is set, inhibitors in block mode refuse it and
+/// those in delay mode hold it back.
+pub fn schedule(
+    command: SystemCommand,
+    when: Option<Delay>,
+    force: bool,
+    context: ContextMap<'static>,
+) -> Result<String, ActionError> {
+    // Only blocking leases refuse right away, delaying ones are waited for
+    // once the command is due
+    let gate = INHIBITORS.lock().unwrap().gate(force, Duration::ZERO, Duration::ZERO, clock::now());
+    if let Gate::Refuse(holders) = gate {
+        return Err(ActionError::Inhibited(command, holders));
+    }
+    let delay = when.map(|when| when.0).unwrap_or_default();
+    let id = SCHEDULE.lock().unwrap().schedule(command.clone(), delay, clock::now());
+    let expired = clock::sleep(delay);
+    smol::spawn(async move {
+        expired.await;
+        let command = SCHEDULE.lock().unwrap().take(id);
+        if let Some(command) = command {
+            if !held_off(&command, force).await {
+                shutdown(command, context).await;
+            }
+        }
+    })
+    .detach();
+    if delay.is_zero() {
+        Ok(format!("{command} now"))
+    } else {
+        Ok(format!("{command} in {}s", delay.as_secs()))
     }
-    Ok(())
 }
 
-#[derive(Error, Debug)]
-#[error("{}", .0)]
-pub struct FailedToKill(&'static str);
+/// Wait until no inhibitor in delay mode is left, at most for
+/// `inhibit_delay_max`. Returns whether an inhibitor in block mode, taken
+/// after the command was scheduled, refuses it.
+async fn held_off(command: &SystemCommand, force: bool) -> bool {
+    let max = Duration::from_secs(Defaults::load().inhibit_delay_max);
+    let start = clock::now();
+    loop {
+        let now = clock::now();
+        let gate = INHIBITORS.lock().unwrap().gate(force, now.saturating_duration_since(start), max, now);
+        match gate {
+            Gate::Proceed => return false,
+            Gate::Refuse(holders) => {
+                error!("{}", ActionError::Inhibited(command.clone(), holders));
+                return true;
+            }
+            Gate::Wait if now == start => info!("Delaying {command} for up to {}s until inhibitors are released", max.as_secs()),
+            Gate::Wait => {}
+        }
+        clock::sleep(INHIBIT_POLL).await;
+    }
+}
 
-async fn kill_all(force: bool, context_map: ContextMap<'static>) -> Vec<Result<(), FailedToKill>> {
-    join_all(
+async fn shutdown(command: SystemCommand, context: ContextMap<'static>) {
+    // A user instance only has its session to end
+    if Instance::current().is_user() {
+        info!("Stopping the user instance...");
+        let stopped = kill_all(context).await;
+        run_shutdown_hooks();
+        epilogue(stopped, context).await;
+        process::exit(0);
+    }
+    let stopped = match command {
+        SystemCommand::Poweroff => {
+            info!("Powering off...");
+            kill_all(context).await
+        }
+        SystemCommand::Restart => {
+            info!("Restarting...");
+            Stopped::default()
+        }
+        SystemCommand::Halt => {
+            info!("Halting...");
+            Stopped::default()
+        }
+    };
+    #[cfg(feature = "utmp")]
+    crate::builtin::utmp::record_shutdown(&command);
+    run_shutdown_hooks();
+    epilogue(stopped, context).await;
+    sync();
+    let reboot = *REBOOT.lock().unwrap();
+    let error = reboot(&command);
+    error!("Error {error}");
+}
+
+/// How long a restart waits for the task to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long shutting down waits for each task before killing it
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long each step of the shutdown epilogue may take
+const EPILOGUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the tasks that were running went down
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stopped {
+    /// Concluded within [`KILL_TIMEOUT`] of SIGTERM
+    pub clean: usize,
+    /// Killed with SIGKILL after that
+    pub forced: usize,
+}
+
+/// Stop every task that is not protected, killing those that take longer
+/// than [`KILL_TIMEOUT`]
+async fn kill_all(context_map: ContextMap<'static>) -> Stopped {
+    let outcomes = join_all(
         context_map
             .0
             .iter()
-            .filter(|(name, _)| **name != "builtin::ctl::daemon")
+            // Protected tasks like the control pipe keep running until the end
+            .filter(|(_, context)| !context.config.protected)
             .map(|(name, context)| async move {
-                select! {
-                    _ = async {
-                        kill(context, force).await;
-                        context_map.wait_for_conclusion(name).await;
-                    }.fuse() => (),
-                    _ = smol::Timer::after(Duration::from_millis(1000)).fuse() => ()
+                let state = context.state().await;
+                if state.has_concluded() || state.is_waiting() {
+                    return None;
                 }
-                Ok(())
+                if let Err(error) = kill(context, false).await {
+                    error!(name, %error);
+                }
+                let Err(WaitError::Timeout(timeout)) =
+                    context_map.wait_until_timeout(name, TaskState::has_concluded, KILL_TIMEOUT).await
+                else {
+                    return Some(true);
+                };
+                warn!(name, "Still running after {timeout:?}, killing it");
+                if let Err(error) = kill(context, true).await {
+                    error!(name, %error);
+                }
+                Some(false)
             }),
     )
-    .await
+    .await;
+    let count = |clean| outcomes.iter().filter(|outcome| **outcome == Some(clean)).count();
+    Stopped { clean: count(true), forced: count(false) }
 }
 
-fn fee1dead(code: c_int) -> c_long {
-    unsafe { syscall(169, 0xfee1deadu32, 537993216, c_long::from(code)) }
+/// Record the end of a shutdown once the tasks are down, as the event log
+/// and the state files are not written by them anymore
+async fn epilogue(stopped: Stopped, context_map: ContextMap<'static>) {
+    if !event_log::close(EPILOGUE_TIMEOUT).await {
+        warn!("The event log is not closed after {EPILOGUE_TIMEOUT:?}");
+    }
+    // A user instance keeps no state directory
+    if Defaults::load().state_dir && !Instance::current().is_user() {
+        let dir = state::state_path();
+        if let Err(error) = state::write_final(&dir, context_map, EPILOGUE_TIMEOUT).await {
+            warn!("Could not write the final states to {}: {error}", dir.display());
+        }
+    }
+    info!("Shutdown complete, {} tasks stopped cleanly, {} forced", stopped.clean, stopped.forced);
 }
 
-async fn kill_by_name(task: &str, force: bool, context: ContextMap<'_>) -> Result<(), ActionError> {
-    kill(get_context(context, task)?, force).await;
+/// Reboot, power off or halt right away without stopping any tasks.
+/// Only returns if the syscall failed.
+pub fn reboot(command: &SystemCommand) -> Errno {
+    sys_reboot::reboot(reboot_mode(command)).unwrap_err()
+}
+
+/// What the kernel is asked to do for `command`
+pub fn reboot_mode(command: &SystemCommand) -> RebootMode {
+    match command {
+        SystemCommand::Poweroff => RebootMode::RB_POWER_OFF,
+        SystemCommand::Restart => RebootMode::RB_AUTOBOOT,
+        SystemCommand::Halt => RebootMode::RB_HALT_SYSTEM,
+    }
+}
+
+/// Refuse to `action` a protected task unless `force_protected` is set
+fn check_protected(task: &str, action: &'static str, force_protected: bool, context: ContextMap<'_>) -> Result<(), ActionError> {
+    if get_context(context, task)?.config.protected && !force_protected {
+        return Err(ActionError::Protected(task.to_owned(), action));
+    }
     Ok(())
 }
 
-async fn kill(task: &TaskContext, force: bool) {
+/// Returns a note for the client if there was nothing left to signal
+async fn kill_by_name(task: &str, force: bool, context: ContextMap<'_>) -> Result<Option<String>, ActionError> {
+    kill(get_context(context, task)?, force).await
+}
+
+pub(crate) async fn kill(task: &TaskContext, force: bool) -> Result<Option<String>, ActionError> {
     if task.state().await.has_concluded() || task.state().await.is_waiting() {
-        return;
+        return Ok(None);
     }
+    let note = signal(task, if force { Signal::SIGKILL } else { Signal::SIGTERM }).await?;
+    // The task may have concluded while it was signalled, then its driver
+    // is gone and would never move it on from Terminating
+    let live = |state: &TaskState| !state.has_concluded() && !state.is_waiting();
     if force {
-        task.send_signal(Signal::SIGKILL).await;
-        task.update_state(TaskState::Concluded(ExitReason::Terminated))
-            .await;
+        task.update_state_if(live, TaskState::Concluded(ExitReason::Terminated)).await;
     } else {
-        task.send_signal(Signal::SIGTERM).await;
-        task.update_state(TaskState::Terminating).await;
+        task.update_state_if(live, TaskState::Terminating).await;
+    }
+    Ok(note)
+}
+
+/// Returns a note for the client if there was nothing left to signal
+async fn signal(task: &TaskContext, signal: Signal) -> Result<Option<String>, ActionError> {
+    match task.send_signal(signal).await {
+        Ok(()) => Ok(None),
+        // Builtins have no process, they watch their state instead
+        Err(error @ SignalError::NoProcess(_)) => {
+            debug!(%error);
+            Ok(None)
+        }
+        Err(error @ SignalError::Gone { .. }) => {
+            info!(%error);
+            Ok(Some(error.to_string()))
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Restart `name` if it is running. It is claimed by moving it to
+/// Terminating in the same step as checking that it runs, so a task that
+/// concludes or is stopped in the meantime is left alone.
+async fn try_restart(name: &str, context: ContextMap<'static>) -> Result<String, ActionError> {
+    let task = get_context(context, name)?;
+    // Markers have nothing to restart, they follow their members
+    if task.config.payload.is_marker() {
+        return Ok(format!("{name} is a marker, not restarting"));
+    }
+    if let Err(state) = task.transition_if(TaskState::is_running, TaskState::Terminating) {
+        return Ok(format!("{name} is {}, not restarting", state.name()));
     }
+    let note = signal(task, Signal::SIGTERM).await?;
+    wait_stopped(name, context).await?;
+    start(name, false, context).await?;
+    Ok(note.unwrap_or_default())
 }
 
-async fn start(task: String, force: bool, context: ContextMap<'_>) -> Result<(), ActionError> {
-    let context = get_context(context, &task)?;
-    // let mut context = context.write().await;
+/// Wait up to [`STOP_TIMEOUT`] for `name` to conclude after it was
+/// signalled
+async fn wait_stopped(name: &str, context: ContextMap<'_>) -> Result<(), ActionError> {
+    match context.wait_until_timeout(name, TaskState::has_concluded, STOP_TIMEOUT).await {
+        Ok(_) => Ok(()),
+        Err(WaitError::Timeout(timeout)) => Err(ActionError::NotStopped(name.to_owned(), timeout)),
+        // Nothing cancels the wait of an action
+        Err(WaitError::UnknownTask(_) | WaitError::Cancelled) => get_context(context, name).map(drop),
+    }
+}
+
+/// Start the tasks `name` stands for, except those that are disabled. A
+/// task disabled right after the check is still kept down, its driver
+/// checks the desired state again before it starts.
+async fn cond_start(name: &str, context: ContextMap<'static>) -> Result<String, ActionError> {
+    let mut notes = Vec::new();
+    for name in targets(context, name)? {
+        let task = get_context(context, name)?;
+        let enabled = task.desired.replace_if(|desired| *desired != DesiredState::Disabled, DesiredState::Enabled);
+        if enabled == Err(DesiredState::Disabled) {
+            notes.push(format!("{name} is disabled, not starting"));
+            continue;
+        }
+        launch(task, false, context).await;
+    }
+    Ok(notes.join("\n"))
+}
+
+/// Keep the tasks down until they are started or enabled again. Stopping
+/// a disabled task leaves it disabled.
+async fn stop(name: &str, desired: DesiredState, force: bool, context: ContextMap<'_>) -> Result<String, ActionError> {
+    let mut notes = Vec::new();
+    for name in targets(context, name)? {
+        let task = get_context(context, name)?;
+        if task.config.payload.is_marker() {
+            // Driven again when it is started with its members
+            if task.state().await == TaskState::Concluded(ExitReason::Done) {
+                task.deactivate(Deactivation::operator(desired.command())).await;
+            }
+            continue;
+        }
+        if desired == DesiredState::Disabled {
+            persist(name, true).await?;
+            task.desired.set(desired);
+        } else if task.desired.get() == DesiredState::Enabled {
+            task.desired.set(desired);
+        }
+        notes.extend(kill(task, force).await?);
+    }
+    Ok(notes.join("\n"))
+}
+
+/// Remember across reboots whether `task` is disabled, if configured. The
+/// file is synced, so it is written off the executor.
+async fn persist(task: &str, disabled: bool) -> Result<(), ActionError> {
+    let Some(file) = DisabledFile::configured() else {
+        return Ok(());
+    };
+    let task = task.to_owned();
+    smol::unblock(move || file.set(&task, disabled)).await.map_err(ActionError::Persist)
+}
+
+/// All members of `group::<name>` followed by the marker itself, any other
+/// task on its own
+fn targets<'a>(context: ContextMap<'a>, name: &str) -> Result<Vec<&'a str>, ActionError> {
+    let task = get_context(context, name)?;
+    let name: &'a str = context.0.get_key_value(name).unwrap().0;
+    let Some(group) = name.strip_prefix("group::").filter(|_| task.config.payload.is_marker()) else {
+        return Ok(vec![name]);
+    };
+    let mut members: Vec<_> = context
+        .0
+        .iter()
+        .filter(|(_, task)| task.config.group.as_deref() == Some(group))
+        .map(|(member, _)| *member)
+        .collect();
+    members.sort_unstable();
+    members.push(name);
+    Ok(members)
+}
+
+/// Hand `pid` to the task, it is supervised as soon as the task would start
+async fn adopt(task: &str, pid: i32, context_map: ContextMap<'static>) -> Result<(), ActionError> {
+    let context = get_context(context_map, task)?;
+    adopt::hand_over(context, pid)?;
+    context.desired.set(DesiredState::Enabled);
+    if context.state().await.has_concluded() {
+        task::spawn(context, context_map);
+    }
+    Ok(())
+}
+
+pub(crate) async fn start(task: &str, force: bool, context_map: ContextMap<'static>) -> Result<(), ActionError> {
+    let context = get_context(context_map, task)?;
+    context.desired.set(DesiredState::Enabled);
+    launch(context, force, context_map).await;
+    Ok(())
+}
+
+/// Drive the task again, or have its driver check its conditions again
+async fn launch(context: &'static TaskContext, force: bool, context_map: ContextMap<'static>) {
+    // Nothing drives a concluded task anymore
+    if context.state().await.has_concluded() {
+        task::spawn(context, context_map);
+        return;
+    }
+    // A marker that is still driven follows its members by itself
+    if context.config.payload.is_marker() {
+        return;
+    }
     let new_state = if force {
         TaskState::Created
     } else {
         TaskState::Waiting
     };
     context.update_state(new_state).await;
-    Ok(())
+}
+
+/// Report what applying `on_disk` would change and stage it for
+/// [`RELOAD_CONFIRM`]
+async fn stage_reload(on_disk: Vec<TaskConfig>, context: ContextMap<'_>) -> String {
+    let diff = ConfigDiff::new(context.0.values().map(|task| &*task.config), &on_disk);
+    if diff.is_empty() {
+        STAGED_RELOAD.lock().unwrap().clear();
+        return "No changes".to_owned();
+    }
+    STAGED_RELOAD.lock().unwrap().stage(on_disk, clock::now());
+    let mut notes = HashMap::new();
+    for change in diff.changed.iter() {
+        if let Some(task) = context.0.get(change.task.as_str()) {
+            if matches!(task.state().await, TaskState::Running(_) | TaskState::Terminating) {
+                notes.insert(change.task.as_str(), "restart required".to_owned());
+            }
+        }
+    }
+    let footer = format!("Apply within {}s with reload --apply", RELOAD_CONFIRM.as_secs());
+    describe_diff(&diff, &notes, &footer)
+}
+
+/// Give the tasks that changed in `on_disk` their new configuration.
+/// Running ones get it once they are started again, or right away by a
+/// restart if `restart_changed` is set. The set of tasks stays as it is
+/// until alfad restarts.
+async fn apply_reload(on_disk: Vec<TaskConfig>, restart_changed: bool, context: ContextMap<'static>) -> String {
+    let diff = ConfigDiff::new(context.0.values().map(|task| &*task.config), &on_disk);
+    if diff.is_empty() {
+        return "No changes".to_owned();
+    }
+    let mut on_disk: HashMap<_, _> = on_disk.into_iter().map(|config| (config.name.clone(), config)).collect();
+    let mut notes = HashMap::new();
+    for change in diff.changed.iter() {
+        let (Some(task), Some(config)) = (context.0.get(change.task.as_str()), on_disk.remove(&change.task)) else {
+            continue;
+        };
+        let note = if !task.reconfigure(config).await {
+            "applied".to_owned()
+        } else if restart_changed {
+            match try_restart(&change.task, context).await {
+                Ok(note) if note.is_empty() => "restarted".to_owned(),
+                Ok(note) => format!("restart required, {note}"),
+                Err(error) => format!("restart required, {error}"),
+            }
+        } else {
+            "restart required".to_owned()
+        };
+        notes.insert(change.task.as_str(), note);
+    }
+    let footer = match diff.added.is_empty() && diff.removed.is_empty() {
+        true => "",
+        false => "Added and removed tasks take effect once alfad restarts",
+    };
+    describe_diff(&diff, &notes, footer)
+}
+
+/// One line per task, `+` for added, `-` for removed and `~` for changed
+/// with the fields that differ and its note, followed by `footer`
+fn describe_diff(diff: &ConfigDiff, notes: &HashMap<&str, String>, footer: &str) -> String {
+    let mut lines: Vec<_> = diff.added.iter().map(|task| format!("+ {task}")).collect();
+    lines.extend(diff.removed.iter().map(|task| format!("- {task}")));
+    for change in diff.changed.iter() {
+        let note = notes.get(change.task.as_str()).map(|note| format!(" ({note})")).unwrap_or_default();
+        lines.push(format!("~ {}: {}{note}", change.task, change.fields.join(", ")));
+    }
+    lines.extend(Some(footer.to_owned()).filter(|footer| !footer.is_empty()));
+    lines.join("\n")
+}
+
+/// State of all tasks sorted by name, as JSON
+fn list(context: ContextMap<'_>) -> String {
+    let tasks: Vec<_> = context
+        .snapshot()
+        .into_iter()
+        .map(|snapshot| {
+            let task = &context.0[snapshot.name.as_str()];
+            let payload = &task.config.payload;
+            let step = match snapshot.state {
+                TaskState::Running(index) if payload.has_step(index) && payload.command_count() > 0 => Some(index),
+                _ => None,
+            };
+            let cause = match snapshot.state {
+                TaskState::Concluded(ExitReason::Deactivated) => task.deactivated.lock().unwrap().clone(),
+                _ => None,
+            };
+            TaskStatus {
+                state: snapshot.state.name(),
+                respawn: snapshot.respawn.map(|respawn| respawn.to_string()).unwrap_or_default(),
+                attempts: snapshot.respawns.unwrap_or_default(),
+                kind: payload.kind().to_string(),
+                description: task.config.description.clone(),
+                doc_url: task.config.doc_url.clone(),
+                source: task.config.source.clone(),
+                step: step.map(|index| index + 1),
+                steps: step.map(|_| payload.command_count()),
+                command: step.and_then(|index| Some(payload.lines()?.get(index)?.render(&task.config.mask_args))),
+                reason: match snapshot.state {
+                    TaskState::Concluded(ExitReason::Skipped) => task.skipped.lock().unwrap().clone(),
+                    TaskState::Concluded(ExitReason::Deactivated) => cause.as_ref().map(ToString::to_string),
+                    _ => None,
+                },
+                cause,
+                waiting: match snapshot.state {
+                    TaskState::Waiting => task.waiting.lock().unwrap().as_ref().map(ToString::to_string).or_else(|| {
+                        let short_of = task.short_of.lock().unwrap();
+                        short_of.as_ref().map(|shortage| format!("waiting for resources ({shortage})"))
+                    }),
+                    _ => None,
+                },
+                suppressed_lines: Some(task.log_suppressed.load(Ordering::Relaxed)).filter(|lines| *lines > 0),
+                name: snapshot.name,
+            }
+        })
+        .collect();
+    serde_json::to_string(&tasks).unwrap_or_default()
 }
 
 fn get_context<'a>(context: ContextMap<'a>, name: &str) -> Result<&'a TaskContext, ActionError> {
     if let Some(context) = context.0.get(name) {
         Ok(context)
     } else {
-        Err(ActionError::TaskNotFound(name.to_owned()))
+        let suggestion = closest(name, context.0.keys().copied()).map(str::to_owned);
+        Err(ActionError::TaskNotFound(name.to_owned(), suggestion))
+    }
+}
+
+/// The candidate that is closest to `name`, if any is close enough to be a typo
+pub(crate) fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max = (name.chars().count() / 3).max(2);
+    candidates
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut row: Vec<_> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous + usize::from(a != *b);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        apply_reload, closest, distance, reboot_mode, stage_reload, PendingShutdown, ShutdownSchedule, StagedReload,
+        RELOAD_CONFIRM, STAGED_RELOAD,
+    };
+    use crate::{
+        action::SystemCommand,
+        clock,
+        config::TaskConfig,
+        task::{ContextMap, TaskContext, TaskState},
+    };
+    use nix::libc::{LINUX_REBOOT_CMD_HALT, LINUX_REBOOT_CMD_POWER_OFF, LINUX_REBOOT_CMD_RESTART};
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn schedule_and_expire() {
+        let now = Instant::now();
+        let mut schedule = ShutdownSchedule::default();
+        assert_eq!(schedule.pending(now), None);
+
+        let id = schedule.schedule(SystemCommand::Poweroff, 5 * MINUTE, now);
+        assert_eq!(
+            schedule.pending(now + MINUTE),
+            Some(PendingShutdown { command: SystemCommand::Poweroff, remaining: 4 * MINUTE })
+        );
+        assert_eq!(schedule.take(id), Some(SystemCommand::Poweroff));
+        assert_eq!(schedule.pending(now), None);
+        assert_eq!(schedule.take(id), None);
+    }
+
+    #[test]
+    fn cancel() {
+        let now = Instant::now();
+        let mut schedule = ShutdownSchedule::default();
+        assert_eq!(schedule.cancel(), None);
+
+        let id = schedule.schedule(SystemCommand::Halt, MINUTE, now);
+        assert_eq!(schedule.cancel().map(|pending| pending.command), Some(SystemCommand::Halt));
+        assert_eq!(schedule.pending(now), None);
+        // The timer fires, but there is nothing left to do
+        assert_eq!(schedule.take(id), None);
+    }
+
+    #[test]
+    fn reschedule_replaces() {
+        let now = Instant::now();
+        let mut schedule = ShutdownSchedule::default();
+
+        let first = schedule.schedule(SystemCommand::Poweroff, MINUTE, now);
+        let second = schedule.schedule(SystemCommand::Restart, 2 * MINUTE, now);
+        assert_eq!(schedule.take(first), None);
+        assert_eq!(schedule.pending(now).map(|pending| pending.command), Some(SystemCommand::Restart));
+        assert_eq!(schedule.take(second), Some(SystemCommand::Restart));
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(distance("network", "network"), 0);
+        assert_eq!(distance("netwrok", "network"), 2);
+        assert_eq!(distance("", "abc"), 3);
+        let tasks = ["network", "mount", "getty", "builtin::ctl::daemon"];
+        assert_eq!(closest("netwrk", tasks.into_iter()), Some("network"));
+        assert_eq!(closest("builtin::ctl::deamon", tasks.into_iter()), Some("builtin::ctl::daemon"));
+        assert_eq!(closest("xyz", tasks.into_iter()), None);
+    }
+
+    #[test]
+    fn reboot_modes() {
+        assert_eq!(reboot_mode(&SystemCommand::Poweroff) as i32, LINUX_REBOOT_CMD_POWER_OFF);
+        assert_eq!(reboot_mode(&SystemCommand::Restart) as i32, LINUX_REBOOT_CMD_RESTART);
+        assert_eq!(reboot_mode(&SystemCommand::Halt) as i32, LINUX_REBOOT_CMD_HALT);
+    }
+
+    #[test]
+    fn reload() {
+        let task =
+            |name: &str, group: Option<&str>| TaskConfig { group: group.map(str::to_owned), ..TaskConfig::new(name.into()) };
+        let tasks: HashMap<_, _> =
+            ["sshd", "ntp", "old"].into_iter().map(|name| (name, TaskContext::new(task(name, None)))).collect();
+        let context = ContextMap(Box::leak(Box::new(tasks)));
+        smol::block_on(context.0["sshd"].update_state(TaskState::Running(0)));
+        let group = |name: &str| context.0[name].config.group.clone();
+
+        let on_disk = vec![task("sshd", Some("net")), task("ntp", Some("net")), task("new", None)];
+        let report = smol::block_on(stage_reload(on_disk.clone(), context));
+        assert_eq!(report, "+ new\n- old\n~ ntp: group\n~ sshd: group (restart required)\nApply within 300s with reload --apply");
+        assert_eq!(group("ntp"), None);
+        assert_eq!(STAGED_RELOAD.lock().unwrap().take(clock::now()), Some(on_disk.clone()));
+
+        let report = smol::block_on(apply_reload(on_disk.clone(), false, context));
+        assert_eq!(
+            report,
+            "+ new\n- old\n~ ntp: group (applied)\n~ sshd: group (restart required)\n\
+             Added and removed tasks take effect once alfad restarts"
+        );
+        assert_eq!(group("ntp").as_deref(), Some("net"));
+        // Until sshd is started again
+        assert_eq!(group("sshd"), None);
+        assert!(context.0["sshd"].config.is_staged());
+        smol::block_on(context.0["sshd"].commit_config());
+        assert_eq!(group("sshd").as_deref(), Some("net"));
+
+        let report = smol::block_on(apply_reload(on_disk, false, context));
+        assert_eq!(report, "+ new\n- old\nAdded and removed tasks take effect once alfad restarts");
+        let running: Vec<_> = context.0.values().map(|task| (*task.config).clone()).collect();
+        assert_eq!(smol::block_on(stage_reload(running, context)), "No changes");
+        assert_eq!(STAGED_RELOAD.lock().unwrap().take(clock::now()), None);
+    }
+
+    #[test]
+    fn staged_reload_expires() {
+        let now = Instant::now();
+        let mut staged = StagedReload::default();
+        staged.stage(vec![TaskConfig::new("a".into())], now);
+        assert_eq!(staged.take(now + RELOAD_CONFIRM - Duration::from_secs(1)).map(|configs| configs.len()), Some(1));
+        assert_eq!(staged.take(now), None);
+        staged.stage(Vec::new(), now);
+        assert_eq!(staged.take(now + RELOAD_CONFIRM), None);
     }
 }
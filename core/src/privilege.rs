@@ -0,0 +1,179 @@
+//! What alfad may do as the user it runs as. Booted as PID 1 it may do
+//! anything, started by a normal user, e.g. to try task files or as a user
+//! instance, it may not signal other users' processes, mount or reboot.
+//! Tasks that need one of these say so with `requires_privileges` and are
+//! skipped without it, instead of failing with EPERM on every respawn.
+
+use crate::config::TaskConfig;
+use nix::unistd::geteuid;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, fs, sync::OnceLock};
+use strum::{EnumIter, IntoEnumIterator};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, EnumIter, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Privilege {
+    /// Signal processes of any user
+    Kill,
+    /// Mount filesystems, also needed for cgroups and switching namespaces
+    Mount,
+    /// Reboot, power off or halt the machine
+    Reboot,
+}
+
+impl Privilege {
+    /// Name of the capability that grants it
+    pub fn capability(self) -> &'static str {
+        match self {
+            Self::Kill => "CAP_KILL",
+            Self::Mount => "CAP_SYS_ADMIN",
+            Self::Reboot => "CAP_SYS_BOOT",
+        }
+    }
+
+    /// Bit of the capability in the sets of /proc/<pid>/status
+    fn bit(self) -> u32 {
+        match self {
+            Self::Kill => 5,
+            Self::Mount => 21,
+            Self::Reboot => 22,
+        }
+    }
+}
+
+/// The privileges a process holds, from its effective capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Privileges {
+    effective: u64,
+}
+
+impl Privileges {
+    pub const ALL: Self = Self { effective: u64::MAX };
+    pub const NONE: Self = Self { effective: 0 };
+
+    /// Read the `CapEff` line of /proc/<pid>/status
+    pub fn from_status(status: &str) -> Option<Self> {
+        let effective = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+        u64::from_str_radix(effective.trim(), 16).ok().map(|effective| Self { effective })
+    }
+
+    /// Ask the kernel what alfad may do. Without /proc, root is assumed to
+    /// be able to do anything and everyone else nothing.
+    pub fn probe() -> Self {
+        fs::read_to_string("/proc/self/status").ok().and_then(|status| Self::from_status(&status)).unwrap_or(
+            match geteuid().is_root() {
+                true => Self::ALL,
+                false => Self::NONE,
+            },
+        )
+    }
+
+    pub fn has(&self, privilege: Privilege) -> bool {
+        self.effective & (1 << privilege.bit()) != 0
+    }
+
+    pub fn held(&self) -> Vec<Privilege> {
+        Privilege::iter().filter(|privilege| self.has(*privilege)).collect()
+    }
+
+    /// Whether a task that needs `required` can run
+    pub fn check(&self, required: &[Privilege]) -> Result<(), Missing> {
+        let missing: Vec<_> = required.iter().copied().filter(|privilege| !self.has(*privilege)).collect();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(Missing(missing)),
+        }
+    }
+}
+
+/// Why a task does not run with the privileges alfad has
+#[derive(Debug, Error, PartialEq, Eq)]
+pub struct Missing(pub Vec<Privilege>);
+
+impl Display for Missing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let needed: Vec<_> = self.0.iter().map(|privilege| format!("{privilege} ({})", privilege.capability())).collect();
+        write!(f, "Needs {}, which alfad runs without", needed.join(", "))
+    }
+}
+
+static CURRENT: OnceLock<Privileges> = OnceLock::new();
+
+/// What alfad may do, probed the first time it is asked
+pub fn current() -> Privileges {
+    *CURRENT.get_or_init(Privileges::probe)
+}
+
+/// Act as if alfad held `privileges`, e.g. to test as a normal user while
+/// running as root. Only works before the first probe, returns
+/// `privileges` if alfad knows what it may do already.
+pub fn set_current(privileges: Privileges) -> Result<(), Privileges> {
+    CURRENT.set(privileges)
+}
+
+/// Log what alfad may do and which of `configs` will be skipped for it
+pub fn report(configs: &[TaskConfig]) {
+    let privileges = current();
+    let missing: Vec<_> = Privilege::iter().filter(|privilege| !privileges.has(*privilege)).map(|p| p.to_string()).collect();
+    if missing.is_empty() {
+        return;
+    }
+    info!("Running without privileges: {}", missing.join(", "));
+    for config in configs {
+        if let Err(missing) = privileges.check(&config.requires_privileges) {
+            warn!(task = config.name, %missing, "Will be skipped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Missing, Privilege, Privileges};
+    use std::{os::unix::process::CommandExt, process::Command};
+
+    const ROOT: &str = "Name:\tinit\nUid:\t0\t0\t0\t0\nCapInh:\t0000000000000000\nCapPrm:\t000001ffffffffff\n\
+                        CapEff:\t000001ffffffffff\nCapBnd:\t000001ffffffffff\n";
+    const USER: &str = "Name:\tsh\nUid:\t1000\t1000\t1000\t1000\nCapPrm:\t0000000000000000\nCapEff:\t0000000000000000\n";
+
+    #[test]
+    fn from_status() {
+        let root = Privileges::from_status(ROOT).unwrap();
+        assert_eq!(root.held(), [Privilege::Kill, Privilege::Mount, Privilege::Reboot]);
+        assert_eq!(root.check(&[Privilege::Mount, Privilege::Reboot]), Ok(()));
+        let user = Privileges::from_status(USER).unwrap();
+        assert_eq!(user, Privileges::NONE);
+        assert!(user.held().is_empty());
+        assert_eq!(user.check(&[]), Ok(()));
+
+        // A container that may kill and reboot, but not mount
+        let container = Privileges::from_status("CapEff:\t0000000000400020\n").unwrap();
+        assert_eq!(container.held(), [Privilege::Kill, Privilege::Reboot]);
+        let missing = container.check(&[Privilege::Kill, Privilege::Mount]).unwrap_err();
+        assert_eq!(missing, Missing(vec![Privilege::Mount]));
+        assert_eq!(missing.to_string(), "Needs mount (CAP_SYS_ADMIN), which alfad runs without");
+
+        assert_eq!(Privileges::from_status("Name:\tsh\n"), None);
+        assert_eq!(Privileges::from_status("CapEff:\tgarbage\n"), None);
+    }
+
+    #[test]
+    fn probe_as_normal_user() {
+        // Read by a process that runs as nobody, or as us if we are not root
+        let mut cat = Command::new("cat");
+        cat.arg("/proc/self/status");
+        if nix::unistd::geteuid().is_root() {
+            cat.uid(65534).gid(65534);
+        }
+        let output = cat.output().unwrap();
+        assert!(output.status.success());
+        let privileges = Privileges::from_status(&String::from_utf8(output.stdout).unwrap()).unwrap();
+        assert!(privileges.held().is_empty(), "{privileges:?}");
+        assert_eq!(
+            privileges.check(&[Privilege::Kill, Privilege::Reboot]).unwrap_err().to_string(),
+            "Needs kill (CAP_KILL), reboot (CAP_SYS_BOOT), which alfad runs without"
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, future::Future, ops::RangeInclusive, str::FromStr};
+use thiserror::Error;
+
+/// Versions of the control protocol, spoken by alfad-ctl and the daemon.
+///
+/// 1. The bare action as request, "ok" or "error" and the body as reply
+/// 2. The action after `proto 2` as request, the reply as JSON
+/// 3. Like 2, errors carry their kind in `code`
+pub type Version = u32;
+
+/// Versions this build understands, on both sides
+pub const SUPPORTED: RangeInclusive<Version> = 1..=3;
+
+/// What went wrong, so the client can tell failures apart without parsing
+/// messages. Older versions of the protocol only know `Failed`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The request could not be parsed
+    Usage,
+    TaskNotFound,
+    /// The action did not complete in time
+    Timeout,
+    /// The request was in a version of the protocol the daemon can't answer
+    Protocol,
+    /// Anything else, also kinds only newer daemons know
+    #[default]
+    #[serde(other)]
+    Failed,
+}
+
+/// Answer of the daemon to a request that asked for one
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Ok(String),
+    Error { message: String, kind: ErrorKind },
+}
+
+impl Reply {
+    /// An error that is none of the specific kinds
+    pub fn failed(message: impl Into<String>) -> Self {
+        Reply::Error { message: message.into(), kind: ErrorKind::Failed }
+    }
+}
+
+/// The reply in version 1, without the kind of an error
+impl Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reply::Ok(body) => write!(f, "ok\n{body}"),
+            Reply::Error { message, .. } => write!(f, "error\n{message}"),
+        }
+    }
+}
+
+impl FromStr for Reply {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (status, body) = s.split_once('\n').unwrap_or((s, ""));
+        match status {
+            "ok" => Ok(Reply::Ok(body.to_owned())),
+            "error" => Ok(Reply::failed(body)),
+            _ => Err(ProtocolError::InvalidReply(s.to_owned())),
+        }
+    }
+}
+
+/// The reply in version 2
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Untyped {
+    Ok(String),
+    Error(String),
+}
+
+/// The reply in version 3, `code` is only set for errors
+#[derive(Serialize, Deserialize)]
+struct Typed {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ok: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorKind>,
+}
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Invalid reply '{}'", .0)]
+    InvalidReply(String),
+    #[error("Invalid protocol version '{}'", .0)]
+    InvalidVersion(String),
+    #[error("alfad speaks protocol versions {theirs:?}, this client {ours:?}")]
+    Incompatible { ours: RangeInclusive<Version>, theirs: RangeInclusive<Version> },
+}
+
+/// A request without the framing that tells the daemon where to reply
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// `proto <N>`, asks for the versions the daemon speaks. Always
+    /// answered in version 1, the client does not know better yet.
+    Hello(Version),
+    Action {
+        version: Version,
+        action: &'a str,
+    },
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(body: &'a str) -> Result<Self, ProtocolError> {
+        let Some(rest) = body.strip_prefix("proto ") else {
+            return Ok(Request::Action { version: 1, action: body });
+        };
+        let (version, action) = match rest.split_once(' ') {
+            Some((version, action)) => (version, Some(action)),
+            None => (rest, None),
+        };
+        let version = version.parse().map_err(|_| ProtocolError::InvalidVersion(version.to_owned()))?;
+        Ok(match action {
+            Some(action) => Request::Action { version, action },
+            None => Request::Hello(version),
+        })
+    }
+}
+
+impl Display for Request<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Request::Hello(version) => write!(f, "proto {version}"),
+            Request::Action { version: 1, action } => f.write_str(action),
+            Request::Action { version, action } => write!(f, "proto {version} {action}"),
+        }
+    }
+}
+
+pub fn encode_reply(version: Version, reply: &Reply) -> String {
+    let json = match (version, reply) {
+        (1, _) => return reply.to_string(),
+        (2, Reply::Ok(body)) => serde_json::to_string(&Untyped::Ok(body.clone())),
+        (2, Reply::Error { message, .. }) => serde_json::to_string(&Untyped::Error(message.clone())),
+        (_, Reply::Ok(body)) => serde_json::to_string(&Typed { ok: Some(body.clone()), error: None, code: None }),
+        (_, Reply::Error { message, kind }) => {
+            serde_json::to_string(&Typed { ok: None, error: Some(message.clone()), code: Some(*kind) })
+        }
+    };
+    json.unwrap_or_default()
+}
+
+pub fn decode_reply(version: Version, s: &str) -> Result<Reply, ProtocolError> {
+    let invalid = || ProtocolError::InvalidReply(s.to_owned());
+    match version {
+        1 => s.parse(),
+        2 => match serde_json::from_str(s).map_err(|_| invalid())? {
+            Untyped::Ok(body) => Ok(Reply::Ok(body)),
+            Untyped::Error(message) => Ok(Reply::failed(message)),
+        },
+        _ => match serde_json::from_str(s).map_err(|_| invalid())? {
+            Typed { ok: Some(body), error: None, .. } => Ok(Reply::Ok(body)),
+            Typed { ok: None, error: Some(message), code } => Ok(Reply::Error { message, kind: code.unwrap_or_default() }),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+/// Answer a request on the daemon side, `perform` is only called for actions
+pub async fn answer<'a, F>(body: &'a str, perform: impl FnOnce(&'a str) -> F) -> String
+where
+    F: Future<Output = Reply>,
+{
+    match Request::parse(body) {
+        Ok(Request::Hello(_)) => encode_reply(1, &Reply::Ok(format!("{}-{}", SUPPORTED.start(), SUPPORTED.end()))),
+        Ok(Request::Action { version, action }) if SUPPORTED.contains(&version) => encode_reply(version, &perform(action).await),
+        Ok(Request::Action { version, .. }) => {
+            let message = format!("Unsupported protocol version {version}");
+            encode_reply(1, &Reply::Error { message, kind: ErrorKind::Protocol })
+        }
+        Err(error) => encode_reply(1, &Reply::Error { message: error.to_string(), kind: ErrorKind::Protocol }),
+    }
+}
+
+/// The highest version both sides speak, given the daemon's answer to
+/// [`Request::Hello`]. Daemons from before the handshake reject it as an
+/// unknown action and only speak version 1.
+pub fn negotiate(ours: RangeInclusive<Version>, hello: &str) -> Result<Version, ProtocolError> {
+    let range = match hello.parse()? {
+        Reply::Ok(range) => range,
+        Reply::Error { .. } => return Ok(1),
+    };
+    let parse = |x: &str| x.parse().map_err(|_| ProtocolError::InvalidVersion(range.clone()));
+    let (start, end) = range.split_once('-').ok_or_else(|| ProtocolError::InvalidVersion(range.clone()))?;
+    let theirs = parse(start)?..=parse(end)?;
+    let version = *ours.end().min(theirs.end());
+    if version < *ours.start().max(theirs.start()) {
+        return Err(ProtocolError::Incompatible { ours, theirs });
+    }
+    Ok(version)
+}
+
+/// Send `action` in the highest version both sides speak. `exchange`
+/// delivers a request to the daemon and returns its raw reply.
+pub fn converse<E: From<ProtocolError>>(action: &str, mut exchange: impl FnMut(&str) -> Result<String, E>) -> Result<Reply, E> {
+    let hello = exchange(&Request::Hello(*SUPPORTED.end()).to_string())?;
+    let version = negotiate(SUPPORTED, &hello)?;
+    let reply = exchange(&Request::Action { version, action }.to_string())?;
+    Ok(decode_reply(version, &reply)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{answer, converse, decode_reply, encode_reply, negotiate, ErrorKind, ProtocolError, Reply, Request, SUPPORTED};
+    use std::cell::RefCell;
+
+    fn perform(action: &str) -> Reply {
+        match action.strip_prefix("echo ") {
+            Some(text) => Reply::Ok(text.to_owned()),
+            None => Reply::Error { message: format!("Unknown action '{action}'"), kind: ErrorKind::Usage },
+        }
+    }
+
+    /// A daemon from before the handshake, every request is a bare action
+    fn old_daemon(body: &str) -> String {
+        perform(body).to_string()
+    }
+
+    /// A daemon from before the kinds of errors
+    fn json_daemon(body: &str) -> String {
+        match Request::parse(body).unwrap() {
+            Request::Hello(_) => "ok\n1-2".to_owned(),
+            Request::Action { version, action } => encode_reply(version, &perform(action)),
+        }
+    }
+
+    fn new_daemon(body: &str) -> String {
+        smol::block_on(answer(body, |action| async move { perform(action) }))
+    }
+
+    /// A client from before the handshake
+    fn old_client(action: &str, daemon: fn(&str) -> String) -> Reply {
+        daemon(action).parse().unwrap()
+    }
+
+    /// A current client, returns the reply and the requests it sent
+    fn new_client(action: &str, daemon: fn(&str) -> String) -> (Reply, Vec<String>) {
+        let sent = RefCell::new(Vec::new());
+        let reply = converse(action, |body| {
+            sent.borrow_mut().push(body.to_owned());
+            Ok::<_, ProtocolError>(daemon(body))
+        })
+        .unwrap();
+        (reply, sent.into_inner())
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        for reply in [Reply::Ok(String::new()), Reply::Ok("a\nb".into()), Reply::failed("nope")] {
+            assert_eq!(reply.to_string().parse::<Reply>().unwrap(), reply);
+            assert_eq!(decode_reply(2, &encode_reply(2, &reply)).unwrap(), reply);
+            assert_eq!(decode_reply(3, &encode_reply(3, &reply)).unwrap(), reply);
+        }
+        "garbage".parse::<Reply>().unwrap_err();
+    }
+
+    #[test]
+    fn error_kinds() {
+        let reply = Reply::Error { message: "Task does not exist 'foo'".into(), kind: ErrorKind::TaskNotFound };
+        assert_eq!(encode_reply(3, &reply), r#"{"error":"Task does not exist 'foo'","code":"task_not_found"}"#);
+        assert_eq!(decode_reply(3, &encode_reply(3, &reply)).unwrap(), reply);
+        assert_eq!(encode_reply(3, &Reply::Ok("hi".into())), r#"{"ok":"hi"}"#);
+        // Older versions only know that something failed
+        assert_eq!(decode_reply(2, &encode_reply(2, &reply)).unwrap(), Reply::failed("Task does not exist 'foo'"));
+        assert_eq!(encode_reply(1, &reply).parse::<Reply>().unwrap(), Reply::failed("Task does not exist 'foo'"));
+        // Kinds a newer daemon knows of
+        assert_eq!(decode_reply(3, r#"{"error":"x","code":"on_fire"}"#).unwrap(), Reply::failed("x"));
+        for invalid in [r#"{}"#, r#"{"ok":"a","error":"b"}"#, r#"{"error":"x","code":7}"#] {
+            decode_reply(3, invalid).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for request in [
+            Request::Hello(2),
+            Request::Action { version: 1, action: "kill foo" },
+            Request::Action { version: 2, action: "kill foo" },
+        ] {
+            assert_eq!(Request::parse(&request.to_string()).unwrap(), request);
+        }
+        assert_eq!(Request::Action { version: 1, action: "list" }.to_string(), "list");
+        Request::parse("proto x list").unwrap_err();
+    }
+
+    #[test]
+    fn compatibility_matrix() {
+        let hello = "ok\n1-3";
+        assert_eq!(new_daemon("proto 3"), hello);
+
+        let (reply, sent) = new_client("echo hi", new_daemon);
+        assert_eq!(reply, Reply::Ok("hi".into()));
+        assert_eq!(sent, ["proto 3", "proto 3 echo hi"]);
+
+        let (reply, sent) = new_client("echo hi", old_daemon);
+        assert_eq!(reply, Reply::Ok("hi".into()));
+        assert_eq!(sent, ["proto 3", "echo hi"]);
+
+        let (reply, sent) = new_client("unknown", json_daemon);
+        assert_eq!(reply, Reply::failed("Unknown action 'unknown'"));
+        assert_eq!(sent, ["proto 3", "proto 2 unknown"]);
+
+        assert_eq!(old_client("echo hi", new_daemon), Reply::Ok("hi".into()));
+        assert_eq!(old_client("echo hi", old_daemon), Reply::Ok("hi".into()));
+
+        let (reply, _) = new_client("unknown", new_daemon);
+        assert_eq!(reply, Reply::Error { message: "Unknown action 'unknown'".into(), kind: ErrorKind::Usage });
+        assert_eq!(new_daemon("proto 2 echo \"quoted\"\nline"), r#"{"ok":"\"quoted\"\nline"}"#);
+        assert_eq!(new_daemon("proto 9 echo hi"), "error\nUnsupported protocol version 9");
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(SUPPORTED, "ok\n1-2").unwrap(), 2);
+        assert_eq!(negotiate(SUPPORTED, "ok\n1-5").unwrap(), 3);
+        assert_eq!(negotiate(SUPPORTED, "ok\n2-5").unwrap(), 3);
+        assert_eq!(negotiate(1..=5, "ok\n1-2").unwrap(), 2);
+        assert_eq!(negotiate(SUPPORTED, "error\nUnknown action 'proto 2'").unwrap(), 1);
+        assert!(matches!(negotiate(SUPPORTED, "ok\n4-5"), Err(ProtocolError::Incompatible { .. })));
+        assert!(matches!(negotiate(SUPPORTED, "ok\nsoon"), Err(ProtocolError::InvalidVersion(_))));
+        assert!(matches!(negotiate(SUPPORTED, "garbage"), Err(ProtocolError::InvalidReply(_))));
+    }
+}
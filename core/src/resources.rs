@@ -0,0 +1,132 @@
+use crate::config::RequiresResources;
+use nix::sys::statvfs::statvfs;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::debug;
+
+/// Where the free memory is read from
+pub const MEMINFO: &str = "/proc/meminfo";
+
+const MB: u64 = 1024 * 1024;
+
+/// Why a task can't start yet
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum Shortage {
+    #[error("free mem {free} MB < {min} MB")]
+    Memory { free: u64, min: u64 },
+    #[error("free disk on {} {free} MB < {min} MB", path.display())]
+    Disk { path: PathBuf, free: u64, min: u64 },
+    /// Can't be measured, so it isn't known to be enough
+    #[error("free {} unknown", .0)]
+    Unknown(String),
+}
+
+/// Measures what is free, the system or a fake one in tests
+pub trait Probe {
+    /// Memory available for starting new applications in MB
+    fn free_mem_mb(&self) -> io::Result<u64>;
+    /// Space available to unprivileged users on the file system of `path` in MB
+    fn free_disk_mb(&self, path: &Path) -> io::Result<u64>;
+}
+
+/// /proc/meminfo and statvfs
+pub struct System;
+
+impl Probe for System {
+    fn free_mem_mb(&self) -> io::Result<u64> {
+        let text = fs::read_to_string(MEMINFO)?;
+        parse_meminfo(&text).ok_or_else(|| io::Error::other("MemAvailable is missing"))
+    }
+
+    fn free_disk_mb(&self, path: &Path) -> io::Result<u64> {
+        let stats = statvfs(path)?;
+        Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64 / MB)
+    }
+}
+
+/// MemAvailable of /proc/meminfo in MB
+pub fn parse_meminfo(text: &str) -> Option<u64> {
+    let line = text.lines().find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kb: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kb / 1024)
+}
+
+/// Whether everything in `requires` is free, as far as `probe` can tell
+pub fn check(requires: &RequiresResources, probe: &dyn Probe) -> Result<(), Shortage> {
+    let unknown = |what: String, error: io::Error| {
+        debug!(%error, "Free {what} is unknown");
+        Shortage::Unknown(what)
+    };
+    if let Some(min) = requires.min_free_mem_mb {
+        let free = probe.free_mem_mb().map_err(|error| unknown("mem".to_owned(), error))?;
+        if free < min {
+            return Err(Shortage::Memory { free, min });
+        }
+    }
+    if let Some(disk) = &requires.min_free_disk_mb {
+        let what = || format!("disk on {}", disk.path.display());
+        let free = probe.free_disk_mb(&disk.path).map_err(|error| unknown(what(), error))?;
+        if free < disk.mb {
+            return Err(Shortage::Disk { path: disk.path.clone(), free, min: disk.mb });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, parse_meminfo, Probe, Shortage, System};
+    use crate::config::{FreeDisk, RequiresResources};
+    use std::{io, path::Path};
+
+    /// Free memory and disk space in MB, `None` if it can't be measured
+    struct Fake(Option<u64>, Option<u64>);
+
+    impl Probe for Fake {
+        fn free_mem_mb(&self) -> io::Result<u64> {
+            self.0.ok_or_else(|| io::Error::other("unreadable"))
+        }
+
+        fn free_disk_mb(&self, _: &Path) -> io::Result<u64> {
+            self.1.ok_or_else(|| io::Error::other("unreadable"))
+        }
+    }
+
+    #[test]
+    fn meminfo() {
+        let text = "MemTotal:        2000000 kB\nMemFree:          100000 kB\nMemAvailable:     204800 kB\n";
+        assert_eq!(parse_meminfo(text), Some(200));
+        assert_eq!(parse_meminfo("MemTotal:        2000000 kB\n"), None);
+        assert_eq!(parse_meminfo("MemAvailable: lots\n"), None);
+    }
+
+    #[test]
+    fn requirements() {
+        let requires = RequiresResources {
+            min_free_mem_mb: Some(200),
+            min_free_disk_mb: Some(FreeDisk { path: "/var".into(), mb: 50 }),
+            ..Default::default()
+        };
+        assert_eq!(check(&requires, &Fake(Some(200), Some(50))), Ok(()));
+        let short = check(&requires, &Fake(Some(120), Some(50))).unwrap_err();
+        assert_eq!(short, Shortage::Memory { free: 120, min: 200 });
+        assert_eq!(short.to_string(), "free mem 120 MB < 200 MB");
+        let short = check(&requires, &Fake(Some(300), Some(10))).unwrap_err();
+        assert_eq!(short.to_string(), "free disk on /var 10 MB < 50 MB");
+        assert_eq!(check(&requires, &Fake(None, Some(50))), Err(Shortage::Unknown("mem".into())));
+        assert_eq!(check(&requires, &Fake(Some(300), None)).unwrap_err().to_string(), "free disk on /var unknown");
+
+        // Nothing asked for, nothing measured
+        assert_eq!(check(&RequiresResources::default(), &Fake(None, None)), Ok(()));
+    }
+
+    #[test]
+    fn system() {
+        assert!(System.free_mem_mb().is_ok());
+        assert!(System.free_disk_mb(Path::new("/")).is_ok());
+        assert!(System.free_disk_mb(Path::new("/does/not/exist")).is_err());
+    }
+}
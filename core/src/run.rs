@@ -0,0 +1,161 @@
+//! Run a single task file in the foreground, the way the engine would on
+//! boot but without the rest of the configuration
+
+use crate::{
+    builtin,
+    command_line::CommandLineError,
+    config::{payload::Payload, read_config_in, yaml::TaskConfigYaml, TaskConfig},
+    task::{self, ContextMap, ExitReason, TaskContext, TaskState},
+};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+/// Run one task file in the foreground, for trying out a new task
+#[derive(Debug, Parser)]
+#[command(name = "alfad run")]
+pub struct RunArgs {
+    /// The task file
+    file: PathBuf,
+    /// Directory containing alfad.d, `after` and `with` must name tasks in it
+    #[arg(long)]
+    context_dir: Option<PathBuf>,
+    /// Only print the command lines with variables expanded
+    #[arg(long)]
+    dry_run: bool,
+}
+
+pub fn run(args: Vec<String>) -> Result<()> {
+    let args = RunArgs::parse_from(args);
+    let config = load(&args.file)?;
+    let dependencies = dependencies(&config);
+    if let Some(dir) = &args.context_dir {
+        let known: HashSet<_> = read_config_in(dir, builtin::all()).into_iter().map(|config| config.name).collect();
+        let unknown: Vec<_> = dependencies.iter().filter(|name| !known.contains(*name)).cloned().collect();
+        if !unknown.is_empty() {
+            let verb = if unknown.len() == 1 { "is" } else { "are" };
+            bail!("{} depends on {}, which {verb} not defined in {}", config.name, unknown.join(", "), dir.display());
+        }
+    }
+    if !dependencies.is_empty() {
+        eprintln!("Not waiting for {}", dependencies.join(", "));
+    }
+    if args.dry_run {
+        print!("{}", dry_run(&config)?);
+        return Ok(());
+    }
+    let name = config.name.clone();
+    match smol::block_on(drive(config)) {
+        TaskState::Concluded(reason) => {
+            eprintln!("{name}: {reason}");
+            exit(exit_code(reason))
+        }
+        state => unreachable!("The drive loop returned while {name} is {state}"),
+    }
+}
+
+/// The task in `file`, as a service without dependencies
+pub fn load(file: &Path) -> Result<TaskConfig> {
+    let text = fs::read_to_string(file).with_context(|| format!("Could not read {}", file.display()))?;
+    let yaml: TaskConfigYaml = serde_yaml::from_str(&text).with_context(|| format!("Could not parse {}", file.display()))?;
+    let config = TaskConfigYaml { source: Some(file.to_owned()), ..yaml }.into_config()?.parse_payload()?;
+    match config.payload {
+        Payload::Service(_) | Payload::Unparsed(_) => Ok(config),
+        Payload::Marker => bail!("{} is a marker, there is nothing to run", config.name),
+        Payload::Builtin(_) => bail!("{} runs a builtin, which only runs inside alfad", config.name),
+    }
+}
+
+/// Everything the task would wait for on boot
+pub fn dependencies(config: &TaskConfig) -> Vec<String> {
+    config.with.iter().chain(config.after.iter()).cloned().collect()
+}
+
+/// Each command line of `config` as it would be run, with its prefixes
+pub fn dry_run(config: &TaskConfig) -> Result<String, CommandLineError> {
+    let Payload::Service(lines) = &config.payload else {
+        return Ok(String::new());
+    };
+    lines.iter().map(|line| Ok(format!("{}\n", line.expanded()?))).collect()
+}
+
+/// Run `config` through the drive loop on its own, respawns included
+pub async fn drive(mut config: TaskConfig) -> TaskState {
+    config.with.clear();
+    config.after.clear();
+    let name: &'static str = config.name.clone().leak();
+    let tasks = ContextMap(Box::leak(Box::new(HashMap::from([(name, TaskContext::new(config))]))));
+    let context = &tasks.0[name];
+    task::drive(context, tasks).await;
+    context.state().await
+}
+
+/// What `alfad run` exits with, 77 is what test suites use for skipped
+pub fn exit_code(reason: ExitReason) -> i32 {
+    match reason {
+        ExitReason::Done => 0,
+        ExitReason::Failed => 1,
+        ExitReason::Deactivated => 2,
+        ExitReason::Skipped => 77,
+        // Like a shell reports SIGTERM
+        ExitReason::Terminated => 143,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dependencies, drive, dry_run, exit_code, load};
+    use crate::task::{ExitReason, TaskState};
+    use std::{env, fs};
+
+    fn task_file(content: &str) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("test.task");
+        fs::write(&file, content).unwrap();
+        (dir, file)
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(ExitReason::Done), 0);
+        assert_eq!(exit_code(ExitReason::Failed), 1);
+        assert_eq!(exit_code(ExitReason::Deactivated), 2);
+        assert_eq!(exit_code(ExitReason::Skipped), 77);
+        assert_eq!(exit_code(ExitReason::Terminated), 143);
+    }
+
+    #[test]
+    fn rendering() {
+        env::set_var("ALFAD_RUN_TEST", "a b");
+        let (_dir, file) = task_file("name: test\ncmd:\n  - echo $ALFAD_RUN_TEST\n  - \":-false\"\nafter: [network]\nwith: [dbus]\n");
+        let config = load(&file).unwrap();
+        assert_eq!(dependencies(&config), ["dbus", "network"]);
+        let expected = if cfg!(feature = "complex_commands") { "echo 'a b'\n:-false\n" } else { "echo '$ALFAD_RUN_TEST'\n:-false\n" };
+        assert_eq!(dry_run(&config).unwrap(), expected);
+    }
+
+    #[test]
+    fn only_services() {
+        let (_dir, file) = task_file("name: test\ncmd: marker\n");
+        assert_eq!(load(&file).unwrap_err().to_string(), "test is a marker, there is nothing to run");
+        let (_dir, file) = task_file("name: test\ncmd: [");
+        assert!(load(&file).is_err());
+    }
+
+    #[test]
+    fn runs_without_dependencies() {
+        let marker = tempfile::tempdir().unwrap();
+        let ran = marker.path().join("ran");
+        let (_dir, file) = task_file(&format!("name: test\ncmd: touch {}\nafter: [never]\n", ran.display()));
+        assert_eq!(smol::block_on(drive(load(&file).unwrap())), TaskState::Concluded(ExitReason::Done));
+        assert!(ran.exists());
+
+        let (_dir, file) = task_file("name: test\ncmd: \"false\"\nrespawn: 1\n");
+        assert_eq!(smol::block_on(drive(load(&file).unwrap())), TaskState::Concluded(ExitReason::Failed));
+    }
+}
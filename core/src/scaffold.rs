@@ -0,0 +1,148 @@
+//! Example configurations to start from, written by `alfad-compile
+//! scaffold`. The task files of a template are part of the binary, with
+//! `{{name}}` and `{{binary}}` standing for its service.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The task file of the service, written as `<name>.task`
+const SERVICE: &str = "service.task";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Template {
+    /// File systems, a getty on the console and a task using alfad-ctl
+    BasicConsole,
+    /// A single service with respawn and logging, for alfad as the
+    /// entrypoint of a container
+    ContainerApp,
+    /// Loopback and a DHCP client before an SSH server
+    NetworkNode,
+}
+
+impl Template {
+    /// Task files by file name, before substitution
+    fn files(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::BasicConsole => &[
+                ("mount.task", include_str!("../templates/basic-console/mount.task")),
+                (SERVICE, include_str!("../templates/basic-console/service.task")),
+                ("tasks.task", include_str!("../templates/basic-console/tasks.task")),
+            ],
+            Self::ContainerApp => &[
+                ("run.task", include_str!("../templates/container-app/run.task")),
+                (SERVICE, include_str!("../templates/container-app/service.task")),
+            ],
+            Self::NetworkNode => &[
+                ("mount.task", include_str!("../templates/network-node/mount.task")),
+                ("loopback.task", include_str!("../templates/network-node/loopback.task")),
+                ("dhcp.task", include_str!("../templates/network-node/dhcp.task")),
+                (SERVICE, include_str!("../templates/network-node/service.task")),
+            ],
+        }
+    }
+
+    /// Name and binary of the service unless they are given
+    pub fn service(self) -> (&'static str, &'static str) {
+        match self {
+            Self::BasicConsole => ("getty", "/sbin/getty"),
+            Self::ContainerApp => ("app", "/usr/bin/app"),
+            Self::NetworkNode => ("sshd", "/usr/sbin/sshd"),
+        }
+    }
+
+    /// The task files by file name, with `name` and `binary` filled in
+    pub fn render(self, name: &str, binary: &str) -> Vec<(String, String)> {
+        self.files()
+            .iter()
+            .map(|(file, text)| {
+                let file = if *file == SERVICE { format!("{name}.task") } else { file.to_string() };
+                (file, text.replace("{{name}}", name).replace("{{binary}}", binary))
+            })
+            .collect()
+    }
+}
+
+/// Write the task files of `template` to `out`. Files that exist already
+/// are left alone and nothing is written then.
+pub fn write(template: Template, name: &str, binary: &str, out: &Path) -> Result<Vec<PathBuf>> {
+    let files = template.render(name, binary);
+    if let Some((file, _)) = files.iter().find(|(file, _)| out.join(file).exists()) {
+        bail!("{} exists already", out.join(file).display());
+    }
+    fs::create_dir_all(out).with_context(|| format!("Could not create {}", out.display()))?;
+    files
+        .into_iter()
+        .map(|(file, text)| {
+            let path = out.join(file);
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(text.as_bytes()))
+                .with_context(|| format!("Could not write {}", path.display()))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write, Template};
+    use crate::{
+        builtin,
+        check::{check, schema_check},
+    };
+    use clap::ValueEnum;
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::PermissionsExt,
+    };
+
+    /// A root file system with the programs of the templates
+    fn sysroot(binaries: &[&str]) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let programs = ["mount", "alfad-ctl", "mkdir", "logger", "ip", "udhcpc"].map(|program| format!("/bin/{program}"));
+        for program in programs.iter().map(String::as_str).chain(binaries.iter().copied()) {
+            let path = root.path().join(program.trim_start_matches('/'));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, Permissions::from_mode(0o755)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn templates_are_bootable() {
+        for template in Template::value_variants() {
+            let (name, binary) = template.service();
+            for (name, binary) in [(name, binary), ("web", "/opt/web/bin/server")] {
+                let dir = tempfile::tempdir().unwrap();
+                let out = dir.path().join("alfad.d");
+                let written = write(*template, name, binary, &out).unwrap();
+                assert!(written.contains(&out.join(format!("{name}.task"))), "{template:?}");
+
+                let report = check(&out, sysroot(&[binary]).path(), builtin::all());
+                assert!(report.findings.is_empty(), "{template:?}: {:?}", report.findings);
+                let report = schema_check(&out);
+                assert!(report.findings.is_empty(), "{template:?}: {:?}", report.findings);
+                let service = fs::read_to_string(out.join(format!("{name}.task"))).unwrap();
+                assert!(service.contains(&format!("name: {name}\n")) && service.contains(binary), "{service}");
+            }
+        }
+    }
+
+    #[test]
+    fn existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("mount.task"), "name: mine\ncmd: \"true\"\n").unwrap();
+        let error = write(Template::NetworkNode, "sshd", "/usr/sbin/sshd", dir.path()).unwrap_err();
+        assert!(error.to_string().ends_with("mount.task exists already"), "{error}");
+        assert_eq!(fs::read_to_string(dir.path().join("mount.task")).unwrap(), "name: mine\ncmd: \"true\"\n");
+        assert!(!dir.path().join("sshd.task").exists());
+    }
+}
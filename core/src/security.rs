@@ -0,0 +1,243 @@
+//! Security labels of services, the SELinux context or AppArmor profile a
+//! command is executed with. They are written to /proc/self/attr between
+//! fork and exec and the kernel applies them on exec. Without the
+//! `security_labels` feature no LSM is taken to be enabled.
+
+use crate::config::TaskConfig;
+use std::{ffi::CString, path::Path, process::Command};
+use strum::Display;
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Lsm {
+    #[strum(serialize = "SELinux")]
+    SeLinux,
+    #[strum(serialize = "AppArmor")]
+    AppArmor,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SecurityError {
+    #[error("{} needs its {} label, but {} is not enabled", .1, .0, .0)]
+    NotEnabled(Lsm, String),
+    #[error("Invalid security label {:?}", .0)]
+    InvalidLabel(String),
+}
+
+/// Labels are passed on as C strings in a single write
+pub fn check_label(label: &str) -> Result<(), SecurityError> {
+    match label.is_empty() || label.contains(['\0', '\n']) {
+        true => Err(SecurityError::InvalidLabel(label.to_owned())),
+        false => Ok(()),
+    }
+}
+
+/// A file in /proc and what is written to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(feature = "security_labels"), allow(dead_code))]
+struct Attr {
+    path: CString,
+    value: Vec<u8>,
+}
+
+/// Everything written before a command is executed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecLabels(Vec<Attr>);
+
+impl ExecLabels {
+    /// Labels of `config` for the LSMs enabled on this system. A label for
+    /// an LSM that is not enabled is an error if the task sets
+    /// `security_required`, otherwise it is left out with a warning.
+    pub fn of(config: &TaskConfig) -> Result<Self, SecurityError> {
+        Self::of_in(config, Path::new("/"))
+    }
+
+    /// Like [`ExecLabels::of`], with /proc and /sys below `root`
+    pub fn of_in(config: &TaskConfig, root: &Path) -> Result<Self, SecurityError> {
+        let labels = [(Lsm::SeLinux, &config.selinux_context), (Lsm::AppArmor, &config.apparmor_profile)];
+        let mut attrs = Vec::new();
+        for (lsm, label) in labels.into_iter().filter_map(|(lsm, label)| Some((lsm, label.as_deref()?))) {
+            if enabled(lsm, root) {
+                attrs.push(attr(lsm, label, root)?);
+            } else if config.security_required {
+                return Err(SecurityError::NotEnabled(lsm, config.name.clone()));
+            } else {
+                warn!(task = config.name, label, "{lsm} is not enabled, running without the label");
+            }
+        }
+        Ok(Self(attrs))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Have the child of `command` write the labels right before exec, it
+    /// fails to spawn if one is rejected
+    #[cfg(feature = "security_labels")]
+    pub fn apply(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        if self.is_empty() {
+            return;
+        }
+        let attrs = self.0.clone();
+        // Safe, `write_attr` neither allocates nor takes locks
+        unsafe { command.pre_exec(move || attrs.iter().try_for_each(write_attr)) };
+    }
+
+    #[cfg(not(feature = "security_labels"))]
+    pub fn apply(&self, _command: &mut Command) {}
+}
+
+/// Where and what to write for `label`, AppArmor prefers its own directory
+/// of stacking kernels over the shared file
+fn attr(lsm: Lsm, label: &str, root: &Path) -> Result<Attr, SecurityError> {
+    check_label(label)?;
+    let attr = root.join("proc/self/attr");
+    let (path, value) = match lsm {
+        Lsm::SeLinux => (attr.join("exec"), label.to_owned()),
+        Lsm::AppArmor => {
+            let own = attr.join("apparmor/exec");
+            (if own.exists() { own } else { attr.join("exec") }, format!("exec {label}"))
+        }
+    };
+    let path = CString::new(path.into_os_string().into_encoded_bytes())
+        .map_err(|_| SecurityError::InvalidLabel(label.to_owned()))?;
+    Ok(Attr { path, value: value.into_bytes() })
+}
+
+/// SELinux only registers its filesystem if it is enabled, AppArmor says
+/// so in a module parameter
+#[cfg(feature = "security_labels")]
+pub fn enabled(lsm: Lsm, root: &Path) -> bool {
+    match lsm {
+        Lsm::SeLinux => std::fs::read_to_string(root.join("proc/filesystems"))
+            .is_ok_and(|filesystems| filesystems.lines().any(|line| line.split_whitespace().last() == Some("selinuxfs"))),
+        Lsm::AppArmor => std::fs::read_to_string(root.join("sys/module/apparmor/parameters/enabled"))
+            .is_ok_and(|enabled| enabled.trim() == "Y"),
+    }
+}
+
+#[cfg(not(feature = "security_labels"))]
+pub fn enabled(_lsm: Lsm, _root: &Path) -> bool {
+    false
+}
+
+/// Only makes system calls, so it is safe between fork and exec
+#[cfg(feature = "security_labels")]
+fn write_attr(attr: &Attr) -> std::io::Result<()> {
+    use nix::libc;
+    use std::io;
+
+    let fd = unsafe { libc::open(attr.path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let written = unsafe { libc::write(fd, attr.value.as_ptr().cast(), attr.value.len()) };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    match usize::try_from(written) {
+        Ok(written) if written == attr.value.len() => Ok(()),
+        Ok(_) => Err(io::ErrorKind::WriteZero.into()),
+        Err(_) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_label, ExecLabels, Lsm, SecurityError};
+    use crate::config::TaskConfig;
+    use std::fs;
+
+    /// A root with /proc and /sys the way they look with `lsms` enabled
+    fn fake_root(lsms: &[Lsm], stacking: bool) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let attr = root.path().join("proc/self/attr");
+        fs::create_dir_all(attr.join("apparmor")).unwrap();
+        fs::write(attr.join("exec"), "").unwrap();
+        if stacking {
+            fs::write(attr.join("apparmor/exec"), "").unwrap();
+        }
+        let selinux = if lsms.contains(&Lsm::SeLinux) { "nodev\tselinuxfs\n" } else { "" };
+        fs::write(root.path().join("proc/filesystems"), format!("nodev\tproc\n{selinux}\text4\n")).unwrap();
+        let parameters = root.path().join("sys/module/apparmor/parameters");
+        fs::create_dir_all(&parameters).unwrap();
+        fs::write(parameters.join("enabled"), if lsms.contains(&Lsm::AppArmor) { "Y\n" } else { "N\n" }).unwrap();
+        root
+    }
+
+    fn task(selinux: Option<&str>, apparmor: Option<&str>, required: bool) -> TaskConfig {
+        TaskConfig {
+            selinux_context: selinux.map(str::to_owned),
+            apparmor_profile: apparmor.map(str::to_owned),
+            security_required: required,
+            ..TaskConfig::new("sshd".into())
+        }
+    }
+
+    #[test]
+    fn labels() {
+        check_label("system_u:system_r:sshd_t:s0").unwrap();
+        check_label("/usr/sbin/sshd").unwrap();
+        for invalid in ["", "a\nb", "a\0b"] {
+            assert_eq!(check_label(invalid), Err(SecurityError::InvalidLabel(invalid.to_owned())));
+        }
+    }
+
+    #[test]
+    fn not_enabled() {
+        let root = fake_root(&[], true);
+        let of = |config: &TaskConfig| ExecLabels::of_in(config, root.path());
+        assert!(of(&task(None, None, true)).unwrap().is_empty());
+        assert!(of(&task(Some("sshd_t"), Some("sshd"), false)).unwrap().is_empty());
+        assert_eq!(of(&task(Some("sshd_t"), None, true)), Err(SecurityError::NotEnabled(Lsm::SeLinux, "sshd".into())));
+        let error = of(&task(None, Some("sshd"), true)).unwrap_err();
+        assert_eq!(error.to_string(), "sshd needs its AppArmor label, but AppArmor is not enabled");
+    }
+
+    #[cfg(feature = "security_labels")]
+    #[test]
+    fn detection() {
+        use super::enabled;
+        use std::path::Path;
+
+        let root = fake_root(&[Lsm::SeLinux], false);
+        assert!(enabled(Lsm::SeLinux, root.path()));
+        assert!(!enabled(Lsm::AppArmor, root.path()));
+        let root = fake_root(&[Lsm::AppArmor], false);
+        assert!(!enabled(Lsm::SeLinux, root.path()));
+        assert!(enabled(Lsm::AppArmor, root.path()));
+        assert!(!enabled(Lsm::SeLinux, Path::new("/nonexistent")));
+    }
+
+    #[cfg(feature = "security_labels")]
+    #[test]
+    fn written_by_child() {
+        use std::{path::Path, process::Command};
+
+        let attr = |root: &Path, file: &str| fs::read_to_string(root.join("proc/self/attr").join(file)).unwrap();
+        let run = |labels: &ExecLabels| {
+            let mut command = Command::new("true");
+            labels.apply(&mut command);
+            command.status()
+        };
+
+        let root = fake_root(&[Lsm::SeLinux, Lsm::AppArmor], true);
+        let labels = ExecLabels::of_in(&task(Some("system_u:system_r:sshd_t:s0"), Some("sshd"), true), root.path()).unwrap();
+        assert!(run(&labels).unwrap().success());
+        assert_eq!(attr(root.path(), "exec"), "system_u:system_r:sshd_t:s0");
+        assert_eq!(attr(root.path(), "apparmor/exec"), "exec sshd");
+
+        // Kernels without stacking only have the shared file
+        let root = fake_root(&[Lsm::AppArmor], false);
+        let labels = ExecLabels::of_in(&task(None, Some("sshd"), true), root.path()).unwrap();
+        assert!(run(&labels).unwrap().success());
+        assert_eq!(attr(root.path(), "exec"), "exec sshd");
+
+        // Like a kernel that rejects the label
+        fs::remove_file(root.path().join("proc/self/attr/exec")).unwrap();
+        assert!(run(&labels).is_err());
+    }
+}
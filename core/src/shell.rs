@@ -0,0 +1,617 @@
+use crate::{
+    action::Action,
+    client::{self, ClientError},
+    def::APLT_CTL,
+    doctor::{self, Finding},
+    protocol::Reply,
+    status::{self, TaskStatus},
+    version::VersionInfo,
+};
+use anyhow::Result;
+use clap::{CommandFactory, Parser};
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::termios::{tcgetattr, tcsetattr, InputFlags, LocalFlags, SetArg, Termios},
+};
+use std::{
+    io::{self, BufRead, IsTerminal, Read, Write},
+    iter,
+    os::fd::AsFd,
+    time::Duration,
+};
+
+/// How often `watch` redraws
+const REFRESH: Duration = Duration::from_secs(1);
+
+const PROMPT: &str = "alfad> ";
+
+const HELP: &str = "Commands are the same as for alfad-ctl, e.g. \"start getty\" or \"list --verbose\".
+  status             same as list
+  watch status       show the status every second until a key is pressed
+  help <command>     details on a command
+  exit               leave the shell, so does Ctrl-D
+Tab completes commands and task names, Up and Down go through the history.
+";
+
+/// Where requests go, the daemon or a stand-in for tests
+pub trait Connection {
+    fn request(&mut self, action: &Action) -> Result<Reply, ClientError>;
+}
+
+/// The running alfad
+pub struct Daemon;
+
+impl Connection for Daemon {
+    fn request(&mut self, action: &Action) -> Result<Reply, ClientError> {
+        client::request(action)
+    }
+}
+
+/// What alfad-ctl prints for `reply`, the status list as a table unless
+/// JSON was asked for
+pub fn render(action: &Action, reply: Reply) -> Result<String> {
+    let message = client::body(reply)?;
+    Ok(match action {
+        Action::List { json: false, color, verbose } => {
+            let tasks: Vec<TaskStatus> = serde_json::from_str(&message)?;
+            status::table(&tasks, status::use_color(*color), *verbose)
+        }
+        Action::List { .. } => {
+            let tasks: Vec<TaskStatus> = serde_json::from_str(&message)?;
+            serde_json::to_string_pretty(&tasks)? + "\n"
+        }
+        Action::Doctor { json: false } => doctor::report(&serde_json::from_str::<Vec<Finding>>(&message)?) + "\n",
+        Action::Doctor { .. } => serde_json::to_string_pretty(&serde_json::from_str::<Vec<Finding>>(&message)?)? + "\n",
+        Action::Version => format!("alfad {}\n", serde_json::from_str::<VersionInfo>(&message)?),
+        _ if message.is_empty() => message,
+        _ => message + "\n",
+    })
+}
+
+/// A line typed into the shell
+#[derive(Debug)]
+pub enum Command {
+    Empty,
+    Help,
+    Exit,
+    Run(Action),
+    /// Run the status list over and over
+    Watch(Action),
+}
+
+/// Read `line` the way alfad-ctl reads its arguments. The error is what to
+/// show instead, including clap's help output.
+pub fn parse(line: &str) -> Result<Command, String> {
+    let mut words = shlex::split(line).ok_or_else(|| "Unbalanced quotes".to_owned())?;
+    let watch = words.first().is_some_and(|word| word == "watch");
+    if watch {
+        words.remove(0);
+    }
+    match words.first().map(String::as_str) {
+        None if watch => return Err("watch what? Try \"watch status\"".to_owned()),
+        None => return Ok(Command::Empty),
+        Some("help" | "?") if words.len() == 1 => return Ok(Command::Help),
+        Some("exit" | "quit") if !watch => return Ok(Command::Exit),
+        Some("status") => words[0] = "list".to_owned(),
+        _ => {}
+    }
+    let action = Action::try_parse_from(iter::once(APLT_CTL.to_owned()).chain(words)).map_err(|error| error.to_string())?;
+    match action {
+        Action::List { .. } if watch => Ok(Command::Watch(action)),
+        _ if watch => Err("Only the status can be watched".to_owned()),
+        _ => Ok(Command::Run(action)),
+    }
+}
+
+/// Commands of alfad-ctl and the shell, for completion
+fn verbs() -> Vec<String> {
+    let ctl = Action::command();
+    let ctl = ctl.get_subcommands().map(|command| command.get_name().to_owned());
+    let mut verbs: Vec<_> = ctl.chain(["status", "watch", "exit"].map(str::to_owned)).collect();
+    verbs.sort();
+    verbs.dedup();
+    verbs
+}
+
+/// Completions for the word that ends `line`: commands for the first one,
+/// task names after that
+pub fn candidates(line: &str, verbs: &[String], tasks: &[String]) -> Vec<String> {
+    let words: Vec<_> = line.split(' ').collect();
+    let word = words.last().copied().unwrap_or_default();
+    let first = words.iter().filter(|word| !word.is_empty()).count() <= 1 && !line.ends_with(' ');
+    let pool = match (first, words.first()) {
+        (true, _) => verbs,
+        (false, Some(&"watch")) => &["status".to_owned()][..],
+        _ if word.starts_with('-') => &[],
+        _ => tasks,
+    };
+    pool.iter().filter(|candidate| candidate.starts_with(word)).cloned().collect()
+}
+
+fn task_names(connection: &mut dyn Connection) -> Vec<String> {
+    let list = Action::List { json: true, color: Default::default(), verbose: false };
+    match connection.request(&list) {
+        Ok(Reply::Ok(message)) => serde_json::from_str::<Vec<TaskStatus>>(&message)
+            .map(|tasks| tasks.into_iter().map(|task| task.name).collect())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Where lines come from
+pub trait Input {
+    /// The next line, `None` at the end of the input
+    fn read_line(&mut self, prompt: &str, complete: &mut dyn FnMut(&str) -> Vec<String>) -> io::Result<Option<String>>;
+
+    /// Wait up to `timeout` for a key press, returns whether there was one
+    fn key_pressed(&mut self, timeout: Duration) -> io::Result<bool>;
+}
+
+/// Lines from a pipe or file, without prompts or editing
+pub struct Lines<R>(pub R);
+
+impl<R: BufRead> Input for Lines<R> {
+    fn read_line(&mut self, _: &str, _: &mut dyn FnMut(&str) -> Vec<String>) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        Ok(match self.0.read_line(&mut line)? {
+            0 => None,
+            _ => Some(line.trim_end_matches('\n').to_owned()),
+        })
+    }
+
+    /// Nobody is there to press a key, so watching shows the status once
+    fn key_pressed(&mut self, _: Duration) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Keys the line editor knows, everything else is ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Tab,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// Ctrl-C, drops the line
+    Interrupt,
+    /// Ctrl-D
+    Eof,
+}
+
+/// Decode what a terminal sends for key presses
+pub fn keys(input: &str) -> Vec<Key> {
+    let mut chars = input.chars().peekable();
+    let mut keys = Vec::new();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\t' => Key::Tab,
+            '\x01' => Key::Home,
+            '\x03' => Key::Interrupt,
+            '\x04' => Key::Eof,
+            '\x05' => Key::End,
+            '\x1b' => {
+                // CSI or SS3 sequence, up to and including its final byte
+                let mut sequence = String::new();
+                if chars.next_if(|c| *c == '[' || *c == 'O').is_some() {
+                    for c in chars.by_ref() {
+                        sequence.push(c);
+                        if !c.is_ascii_digit() && c != ';' {
+                            break;
+                        }
+                    }
+                }
+                // Modifiers like Ctrl in "1;5C" make no difference
+                let number = sequence.split([';', '~']).next().unwrap_or_default();
+                match sequence.chars().last() {
+                    Some('A') => Key::Up,
+                    Some('B') => Key::Down,
+                    Some('C') => Key::Right,
+                    Some('D') => Key::Left,
+                    Some('H') => Key::Home,
+                    Some('F') => Key::End,
+                    Some('~') => match number {
+                        "1" | "7" => Key::Home,
+                        "4" | "8" => Key::End,
+                        "3" => Key::Delete,
+                        _ => continue,
+                    },
+                    _ => continue,
+                }
+            }
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+/// What a key press did to the line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Edit {
+    Continue,
+    Submit(String),
+    /// Tab found several candidates, to be listed
+    Candidates(Vec<String>),
+    Eof,
+}
+
+/// A line being edited, with the history to go through
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    pub history: Vec<String>,
+    chars: Vec<char>,
+    cursor: usize,
+    /// Entry of the history shown, `history.len()` for the new line
+    browsing: usize,
+    /// The new line, kept while going through the history
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    pub fn line(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    fn set(&mut self, line: Vec<char>) {
+        self.cursor = line.len();
+        self.chars = line;
+    }
+
+    fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    /// One entry further back or forth in the history
+    fn browse(&mut self, up: bool) {
+        let target = match up {
+            true if self.browsing > 0 => self.browsing - 1,
+            false if self.browsing < self.history.len() => self.browsing + 1,
+            _ => return,
+        };
+        if self.browsing == self.history.len() {
+            self.draft = self.chars.clone();
+        }
+        self.browsing = target;
+        let line = self.history.get(target).map(|line| line.chars().collect()).unwrap_or_else(|| self.draft.clone());
+        self.set(line);
+    }
+
+    fn complete(&mut self, complete: &mut dyn FnMut(&str) -> Vec<String>) -> Edit {
+        let before: String = self.chars[..self.cursor].iter().collect();
+        let word = before.rsplit(' ').next().unwrap_or_default().chars().count();
+        let candidates = complete(&before);
+        let Some(first) = candidates.first() else {
+            return Edit::Continue;
+        };
+        // Whatever all candidates agree on
+        let common = candidates.iter().fold(first.chars().count(), |common, candidate| {
+            first.chars().zip(candidate.chars()).take(common).take_while(|(a, b)| a == b).count()
+        });
+        self.insert(&first.chars().skip(word).take(common.saturating_sub(word)).collect::<String>());
+        match candidates.len() {
+            1 => {
+                self.insert(" ");
+                Edit::Continue
+            }
+            _ if common > word => Edit::Continue,
+            _ => Edit::Candidates(candidates),
+        }
+    }
+
+    pub fn key(&mut self, key: Key, complete: &mut dyn FnMut(&str) -> Vec<String>) -> Edit {
+        match key {
+            Key::Char(c) => self.insert(&c.to_string()),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.chars.len(),
+            Key::Up | Key::Down => self.browse(key == Key::Up),
+            Key::Tab => return self.complete(complete),
+            Key::Interrupt => {
+                self.set(Vec::new());
+                self.browsing = self.history.len();
+            }
+            Key::Eof if self.chars.is_empty() => return Edit::Eof,
+            Key::Enter => {
+                let line = self.line();
+                if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                self.set(Vec::new());
+                self.browsing = self.history.len();
+                return Edit::Submit(line);
+            }
+            _ => {}
+        }
+        Edit::Continue
+    }
+}
+
+/// Puts the terminal back the way it was when dropped
+struct RawMode(Termios);
+
+impl RawMode {
+    /// Keys reach alfad-ctl one by one and unechoed, Ctrl-C included
+    fn enable() -> io::Result<Self> {
+        let original = tcgetattr(io::stdin())?;
+        let mut raw = original.clone();
+        raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN);
+        raw.input_flags.remove(InputFlags::IXON | InputFlags::ICRNL);
+        tcsetattr(io::stdin(), SetArg::TCSANOW, &raw)?;
+        Ok(Self(original))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(io::stdin(), SetArg::TCSANOW, &self.0);
+    }
+}
+
+/// The terminal on stdin and stdout, with line editing
+#[derive(Default)]
+pub struct Terminal {
+    editor: LineEditor,
+}
+
+impl Terminal {
+    fn redraw(&self, prompt: &str) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "\r\x1b[K{prompt}{}", self.editor.line())?;
+        let behind = self.editor.line().chars().count() - self.editor.cursor();
+        if behind > 0 {
+            write!(stdout, "\x1b[{behind}D")?;
+        }
+        stdout.flush()
+    }
+}
+
+impl Input for Terminal {
+    fn read_line(&mut self, prompt: &str, complete: &mut dyn FnMut(&str) -> Vec<String>) -> io::Result<Option<String>> {
+        let _raw = RawMode::enable()?;
+        let mut buf = [0; 64];
+        self.redraw(prompt)?;
+        loop {
+            let read = io::stdin().lock().read(&mut buf)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            for key in keys(&String::from_utf8_lossy(&buf[..read])) {
+                match self.editor.key(key, complete) {
+                    Edit::Continue => {}
+                    Edit::Submit(line) => {
+                        println!("\r");
+                        return Ok(Some(line));
+                    }
+                    Edit::Candidates(candidates) => println!("\r\n{}\r", candidates.join("  ")),
+                    Edit::Eof => {
+                        println!("\r");
+                        return Ok(None);
+                    }
+                }
+            }
+            self.redraw(prompt)?;
+        }
+    }
+
+    fn key_pressed(&mut self, timeout: Duration) -> io::Result<bool> {
+        let _raw = RawMode::enable()?;
+        let stdin = io::stdin();
+        let mut fds = [PollFd::new(stdin.as_fd(), PollFlags::POLLIN)];
+        let ready = poll(&mut fds, u16::try_from(timeout.as_millis()).unwrap_or(u16::MAX))?;
+        if ready > 0 {
+            let _ = stdin.lock().read(&mut [0; 64])?;
+        }
+        Ok(ready > 0)
+    }
+}
+
+/// Run commands from `input` until it ends or the user leaves
+pub fn session(connection: &mut dyn Connection, input: &mut dyn Input, out: &mut dyn Write) -> io::Result<()> {
+    let verbs = verbs();
+    loop {
+        let mut complete = |line: &str| candidates(line, &verbs, &task_names(&mut *connection));
+        let Some(line) = input.read_line(PROMPT, &mut complete)? else {
+            return Ok(());
+        };
+        let action = match parse(&line) {
+            Ok(Command::Empty) => continue,
+            Ok(Command::Exit) => return Ok(()),
+            Ok(Command::Help) => {
+                write!(out, "{HELP}")?;
+                continue;
+            }
+            Ok(Command::Run(action)) => action,
+            Ok(Command::Watch(action)) => {
+                watch(connection, input, out, &action)?;
+                continue;
+            }
+            Err(message) => {
+                writeln!(out, "{}", message.trim_end())?;
+                continue;
+            }
+        };
+        match connection.request(&action).map_err(anyhow::Error::from).and_then(|reply| render(&action, reply)) {
+            Ok(text) => write!(out, "{text}")?,
+            Err(error) => writeln!(out, "error: {error}")?,
+        }
+    }
+}
+
+fn watch(connection: &mut dyn Connection, input: &mut dyn Input, out: &mut dyn Write, action: &Action) -> io::Result<()> {
+    loop {
+        let text = connection.request(action).map_err(anyhow::Error::from).and_then(|reply| render(action, reply));
+        // Clear the screen, then the same view as `list`
+        write!(out, "\x1b[H\x1b[2J")?;
+        match text {
+            Ok(text) => write!(out, "{text}")?,
+            Err(error) => writeln!(out, "error: {error}")?,
+        }
+        out.flush()?;
+        if input.key_pressed(REFRESH)? {
+            return Ok(());
+        }
+    }
+}
+
+/// `alfad-ctl shell`, with line editing if stdin is a terminal
+pub fn run() -> Result<()> {
+    let mut out = io::stdout();
+    if io::stdin().is_terminal() {
+        writeln!(out, "Type help for a list of commands, Ctrl-D to leave")?;
+        session(&mut Daemon, &mut Terminal::default(), &mut out)?;
+    } else {
+        session(&mut Daemon, &mut Lines(io::stdin().lock()), &mut out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{candidates, keys, parse, session, verbs, Command, Connection, Edit, Key, LineEditor, Lines};
+    use crate::{
+        action::Action,
+        client::ClientError,
+        protocol::{ErrorKind, Reply},
+    };
+    use std::io;
+
+    /// Answers `list` with two tasks and everything else with "ok"
+    #[derive(Default)]
+    struct FakeDaemon {
+        requests: Vec<String>,
+    }
+
+    impl Connection for FakeDaemon {
+        fn request(&mut self, action: &Action) -> Result<Reply, ClientError> {
+            self.requests.push(action.to_string());
+            Ok(match action {
+                Action::List { .. } => Reply::Ok(
+                    r#"[{"name": "getty", "state": "Running"}, {"name": "gpsd", "state": "Failed"}]"#.to_owned(),
+                ),
+                Action::Kill { task, .. } if task == "nope" => {
+                    Reply::Error { message: "Task does not exist 'nope'".to_owned(), kind: ErrorKind::TaskNotFound }
+                }
+                _ => Reply::Ok(String::new()),
+            })
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(matches!(parse("  "), Ok(Command::Empty)));
+        assert!(matches!(parse("help"), Ok(Command::Help)));
+        assert!(matches!(parse("exit"), Ok(Command::Exit)));
+        assert!(matches!(parse("kill getty"), Ok(Command::Run(Action::Kill { task, force: false, .. })) if task == "getty"));
+        assert!(matches!(parse("start 'my task' --force"), Ok(Command::Run(Action::Start { task, force: true })) if task == "my task"));
+        assert!(matches!(parse("status"), Ok(Command::Run(Action::List { verbose: false, .. }))));
+        assert!(matches!(parse("watch status --verbose"), Ok(Command::Watch(Action::List { verbose: true, .. }))));
+
+        assert_eq!(parse("watch kill getty").unwrap_err(), "Only the status can be watched");
+        assert_eq!(parse("kill 'getty").unwrap_err(), "Unbalanced quotes");
+        assert!(parse("frobnicate").unwrap_err().contains("unrecognized subcommand 'frobnicate'"));
+        // clap's own help for a command
+        assert!(parse("help kill").unwrap_err().contains("Send SIGKILL instead of SIGTERM"));
+    }
+
+    #[test]
+    fn completion() {
+        let verbs = verbs();
+        let tasks = ["getty".to_owned(), "gpsd".to_owned(), "sshd".to_owned()];
+        assert_eq!(candidates("st", &verbs, &tasks), ["start", "status", "stop"]);
+        assert_eq!(candidates("kill g", &verbs, &tasks), ["getty", "gpsd"]);
+        assert_eq!(candidates("kill ", &verbs, &tasks), tasks);
+        assert_eq!(candidates("kill --f", &verbs, &tasks), Vec::<String>::new());
+        assert_eq!(candidates("watch s", &verbs, &tasks), ["status"]);
+    }
+
+    fn type_in(editor: &mut LineEditor, keys: &[Key]) -> Vec<Edit> {
+        let tasks = ["getty".to_owned(), "gpsd".to_owned(), "sshd".to_owned()];
+        let mut complete = |line: &str| candidates(line, &verbs(), &tasks);
+        keys.iter().map(|key| editor.key(*key, &mut complete)).filter(|edit| *edit != Edit::Continue).collect()
+    }
+
+    #[test]
+    fn key_decoding() {
+        assert_eq!(
+            keys("ä\x1b[D\x1b[3~\x1bOH\x1b[1;5C\x7f\t\r\x03\x04\x1b[Z"),
+            [
+                Key::Char('ä'),
+                Key::Left,
+                Key::Delete,
+                Key::Home,
+                Key::Right,
+                Key::Backspace,
+                Key::Tab,
+                Key::Enter,
+                Key::Interrupt,
+                Key::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn editing() {
+        let mut editor = LineEditor::default();
+        // Completed up to where the candidates differ, listed after that
+        assert_eq!(type_in(&mut editor, &keys("res\tg\t")), [Edit::Candidates(vec!["getty".into(), "gpsd".into()])]);
+        assert_eq!(editor.line(), "restart g");
+        assert_eq!(type_in(&mut editor, &keys("e\t\r")), [Edit::Submit("restart getty ".into())]);
+
+        assert_eq!(type_in(&mut editor, &keys("kil getty\x1b[H\x1b[C\x1b[C\x1b[Cl\r")), [Edit::Submit("kill getty".into())]);
+        assert_eq!(editor.history, ["restart getty ", "kill getty"]);
+
+        // Through the history and back to what was typed
+        type_in(&mut editor, &keys("li"));
+        type_in(&mut editor, &[Key::Up, Key::Up, Key::Up]);
+        assert_eq!(editor.line(), "restart getty ");
+        type_in(&mut editor, &[Key::Down]);
+        assert_eq!(editor.line(), "kill getty");
+        type_in(&mut editor, &[Key::Down, Key::Down]);
+        assert_eq!(editor.line(), "li");
+
+        type_in(&mut editor, &[Key::Interrupt]);
+        assert_eq!(editor.line(), "");
+        assert_eq!(type_in(&mut editor, &[Key::Eof]), [Edit::Eof]);
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut daemon = FakeDaemon::default();
+        let script = "status\nkill getty\nkill nope\nbogus\n\nwatch status\nhelp\nexit\nkill never\n";
+        let mut out = Vec::new();
+        session(&mut daemon, &mut Lines(io::Cursor::new(script)), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(daemon.requests, ["list", "kill getty", "kill nope", "list"]);
+        assert!(out.starts_with("NAME   STATE    RESPAWN  DESCRIPTION\ngetty  Running\ngpsd   Failed\n"), "{out}");
+        assert!(out.contains("error: Task does not exist 'nope'\n"), "{out}");
+        assert!(out.contains("unrecognized subcommand 'bogus'"), "{out}");
+        assert!(out.contains("\x1b[H\x1b[2JNAME"), "{out}");
+        assert!(out.ends_with("Ctrl-D\nTab completes commands and task names, Up and Down go through the history.\n"), "{out}");
+    }
+}
@@ -0,0 +1,296 @@
+//! Boot a configuration without running anything. Every payload is
+//! replaced with a stub that succeeds after a simulated duration, the tasks
+//! go through the real drive loop and time is virtual.
+
+use crate::{
+    builtin::BuiltInService,
+    clock::VirtualClock,
+    config::{payload::Payload, payload::Runnable, yaml::Timeout, Respawn, TaskConfig},
+    task::{self, ContextMap, ExitReason, TaskContext, TaskState},
+};
+use anyhow::{Context, Result};
+use smol::{channel::Receiver, future, Timer};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    ops::ControlFlow,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Without a state change for this long, the engine is taken to wait for
+/// virtual time or to be stuck
+const QUIET: Duration = Duration::from_millis(20);
+
+/// Simulated durations by task, from a file like `network: 2s`
+pub fn read_durations(path: &Path) -> Result<HashMap<String, Duration>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let durations: HashMap<String, Timeout> =
+        serde_yaml::from_str(&text).with_context(|| format!("Could not parse {}", path.display()))?;
+    Ok(durations.into_iter().map(|(task, Timeout(duration))| (task, duration)).collect())
+}
+
+/// When a payload ran, in time since the simulated boot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slot {
+    pub task: String,
+    pub started: Duration,
+    pub finished: Option<Duration>,
+}
+
+/// A task that never got to run, or never finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stuck {
+    pub task: String,
+    pub state: TaskState,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Simulation {
+    /// In the order the tasks started, at the same time by name
+    pub slots: Vec<Slot>,
+    /// By name
+    pub stuck: Vec<Stuck>,
+}
+
+impl Simulation {
+    /// When `task` started, if it ever did
+    pub fn started(&self, task: &str) -> Option<Duration> {
+        self.slots.iter().find(|slot| slot.task == task).map(|slot| slot.started)
+    }
+
+    /// Names of the tasks in the order they started
+    pub fn order(&self) -> Vec<&str> {
+        self.slots.iter().map(|slot| slot.task.as_str()).collect()
+    }
+}
+
+impl Display for Simulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:>10}  {:>10}  TASK", "START", "DONE")?;
+        for slot in self.slots.iter() {
+            let finished = slot.finished.map(|finished| format!("{:.3}s", finished.as_secs_f64())).unwrap_or_default();
+            writeln!(f, "{:>10}  {finished:>10}  {}", format!("{:.3}s", slot.started.as_secs_f64()), slot.task)?;
+        }
+        if !self.stuck.is_empty() {
+            writeln!(f, "\nNever finished:")?;
+        }
+        for stuck in self.stuck.iter() {
+            let state = match stuck.state {
+                TaskState::Concluded(reason) => reason.to_string(),
+                state => state.to_string(),
+            };
+            writeln!(f, "  {}: {state}, {}", stuck.task, stuck.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stands in for the payload of a task
+struct Stub {
+    clock: &'static VirtualClock,
+    duration: Duration,
+    slots: &'static Mutex<Vec<Slot>>,
+}
+
+#[async_trait::async_trait]
+impl Runnable for Stub {
+    async fn run<'a>(&'a self, context: &'a TaskContext, _context_map: ContextMap<'static>) -> ControlFlow<TaskState> {
+        let task = context.config.name.clone();
+        self.slots.lock().unwrap().push(Slot { task: task.clone(), started: self.clock.elapsed(), finished: None });
+        self.clock.sleep(self.duration).await;
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.iter_mut().find(|slot| slot.task == task) {
+            slot.finished = Some(self.clock.elapsed());
+        }
+        ControlFlow::Break(TaskState::Concluded(ExitReason::Done))
+    }
+}
+
+/// Boot `configs`, tasks not in `durations` finish right away. Nothing
+/// outside the configuration is looked at: respawns, loggers, adoption and
+/// kernel requirements are left out and every task is enabled.
+pub fn simulate(configs: Vec<TaskConfig>, durations: &HashMap<String, Duration>) -> Simulation {
+    let clock: &'static VirtualClock = Box::leak(Box::default());
+    let slots: &'static Mutex<Vec<Slot>> = Box::leak(Box::default());
+    let configs = configs.into_iter().map(|config| {
+        let payload = match config.payload {
+            Payload::Marker => Payload::Marker,
+            Payload::Service(_) | Payload::Builtin(_) | Payload::Unparsed(_) => {
+                let duration = durations.get(&config.name).copied().unwrap_or_default();
+                let stub: &'static Stub = Box::leak(Box::new(Stub { clock, duration, slots }));
+                Payload::Builtin(BuiltInService::register(format!("simulate::stub@{stub:p}"), stub))
+            }
+        };
+        TaskConfig {
+            payload,
+            respawn: Respawn::No,
+            log_cmd: None,
+            log_rate_limit: None,
+            log_timestamps: false,
+            adopt: None,
+            requires_kernel: None,
+            requires_resources: None,
+            requires_privileges: Vec::new(),
+            ..config
+        }
+    });
+    let tasks = ContextMap(Box::leak(Box::new(
+        configs.map(|config| (&*config.name.clone().leak(), TaskContext::new(config))).collect(),
+    )));
+    let changes = tasks.subscribe();
+    tasks.0.values().for_each(|context| smol::spawn(task::drive(context, tasks)).detach());
+
+    smol::block_on(async {
+        loop {
+            settle(&changes).await;
+            match clock.next_deadline() {
+                Some(deadline) => clock.advance(deadline.saturating_sub(clock.elapsed())),
+                None => break,
+            }
+        }
+    });
+    let mut slots = slots.lock().unwrap().clone();
+    slots.sort_by(|a, b| (a.started, &a.task).cmp(&(b.started, &b.task)));
+    Simulation { slots, stuck: stuck(tasks) }
+}
+
+/// Wait until nothing changes any more without time passing
+async fn settle<T>(changes: &Receiver<T>) {
+    let quiet = || async {
+        Timer::after(QUIET).await;
+        false
+    };
+    while future::or(async { changes.recv().await.is_ok() }, quiet()).await {}
+}
+
+/// Tasks that did not finish with Done, and what they waited for
+fn stuck(tasks: ContextMap<'static>) -> Vec<Stuck> {
+    let state = |name: &str| tasks.0.get(name).map(|context| smol::block_on(context.state()));
+    let waiting_for = |context: &TaskContext| -> Option<String> {
+        if let Some(waiting) = context.waiting.lock().unwrap().as_ref() {
+            return Some(waiting.task.clone());
+        }
+        // Markers don't say, it is any member that is not Done
+        let done = Some(TaskState::Concluded(ExitReason::Done));
+        context.config.after.iter().chain(context.config.with.iter()).find(|name| state(name) != done).cloned()
+    };
+    let blocked_by: HashMap<&str, String> =
+        tasks.0.iter().filter_map(|(name, context)| Some((*name, waiting_for(context)?))).collect();
+
+    let mut stuck: Vec<_> = tasks
+        .0
+        .iter()
+        .filter_map(|(name, context)| {
+            let current = smol::block_on(context.state());
+            if current == TaskState::Concluded(ExitReason::Done) {
+                return None;
+            }
+            let reason = match blocked_by.get(name) {
+                None => "nothing to wait for".to_owned(),
+                Some(other) if !tasks.0.contains_key(other.as_str()) => format!("waiting for {other}, which does not exist"),
+                Some(other) if in_loop(name, &blocked_by) => format!("waiting for {other}, in a loop"),
+                Some(other) => format!("waiting for {other}"),
+            };
+            Some(Stuck { task: name.to_string(), state: current, reason })
+        })
+        .collect();
+    stuck.sort_by(|a, b| a.task.cmp(&b.task));
+    stuck
+}
+
+/// Whether following what `task` waits for leads back to it
+fn in_loop(task: &str, blocked_by: &HashMap<&str, String>) -> bool {
+    let mut seen = HashSet::new();
+    let mut current = task;
+    while let Some(next) = blocked_by.get(current) {
+        if next == task {
+            return true;
+        }
+        if !seen.insert(next.as_str()) {
+            return false;
+        }
+        current = next;
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_durations, simulate, Simulation};
+    use crate::config::read_yaml_configs;
+    use std::{collections::HashMap, fs, time::Duration};
+
+    fn run(files: &[(&str, &str)], durations: &str) -> Simulation {
+        let dir = tempfile::tempdir().unwrap();
+        let tasks = dir.path().join("alfad.d");
+        fs::create_dir(&tasks).unwrap();
+        for (name, content) in files {
+            fs::write(tasks.join(name), content).unwrap();
+        }
+        fs::write(dir.path().join("durations.yaml"), durations).unwrap();
+        let durations = read_durations(&dir.path().join("durations.yaml")).unwrap();
+        simulate(read_yaml_configs(&tasks, Vec::new()), &durations)
+    }
+
+    fn secs(secs: f64) -> Option<Duration> {
+        Some(Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn durations_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("durations.yaml");
+        fs::write(&path, "network: 2s\nmount: 500ms\n").unwrap();
+        let expected = HashMap::from([("network".to_owned(), Duration::from_secs(2)), ("mount".to_owned(), Duration::from_millis(500))]);
+        assert_eq!(read_durations(&path).unwrap(), expected);
+        fs::write(&path, "network: soon\n").unwrap();
+        assert!(read_durations(&path).is_err());
+    }
+
+    #[test]
+    fn schedule() {
+        let simulation = run(
+            &[
+                ("mount.task", "name: mount\ncmd: mount -a\n"),
+                ("network.task", "name: network\ncmd: ifup -a\nafter: [mount]\n"),
+                ("ntp.task", "name: ntp\ncmd: ntpd\nafter: [network]\n"),
+                ("syslog.task", "name: syslog\ncmd: syslogd\nafter: [mount]\n"),
+                ("klog.task", "name: klog\ncmd: klogd\nwith: [syslog]\n"),
+                ("login.task", "name: login\ncmd: getty\nafter: [ntp, syslog]\n"),
+            ],
+            "mount: 1s\nnetwork: 2s\nntp: 500ms\nsyslog: 1s\n",
+        );
+        assert_eq!(simulation.order(), ["mount", "klog", "network", "syslog", "ntp", "login"]);
+        assert_eq!(simulation.started("mount"), secs(0.0));
+        // As soon as its companion runs
+        assert_eq!(simulation.started("klog"), secs(1.0));
+        assert_eq!(simulation.started("ntp"), secs(3.0));
+        assert_eq!(simulation.started("login"), secs(3.5));
+        assert!(simulation.stuck.is_empty(), "{simulation}");
+        let table = simulation.to_string();
+        assert!(table.starts_with("     START        DONE  TASK\n    0.000s      1.000s  mount\n    1.000s      1.000s  klog\n"), "{table}");
+    }
+
+    #[test]
+    fn stuck() {
+        let simulation = run(
+            &[
+                ("a.task", "name: a\ncmd: a\nafter: [b]\n"),
+                ("b.task", "name: b\ncmd: b\nafter: [a]\n"),
+                ("c.task", "name: c\ncmd: c\nafter: [nope]\n"),
+                ("d.task", "name: d\ncmd: d\n"),
+            ],
+            "",
+        );
+        assert_eq!(simulation.order(), ["d"]);
+        let stuck: Vec<_> = simulation.stuck.iter().map(|stuck| (stuck.task.as_str(), stuck.reason.as_str())).collect();
+        assert_eq!(
+            stuck,
+            [("a", "waiting for b, in a loop"), ("b", "waiting for a, in a loop"), ("c", "waiting for nope, which does not exist")]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+#[cfg(feature = "diagnostics")]
+use crate::diagnostics::CellCounters;
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+/// A value that can be awaited, like a watch channel: it always holds the
+/// current value and wakes everyone waiting when it changes. The lock is
+/// never held across an await, so a waiter can not miss a change between
+/// checking the value and registering itself.
+#[derive(Debug, Default)]
+pub struct StateCell<T> {
+    inner: Mutex<Inner<T>>,
+    #[cfg(feature = "diagnostics")]
+    counters: CellCounters,
+}
+
+#[derive(Debug, Default)]
+struct Inner<T> {
+    value: T,
+    wakers: Vec<Waker>,
+}
+
+impl<T: Copy + PartialEq> StateCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(Inner { value, wakers: Vec::new() }),
+            #[cfg(feature = "diagnostics")]
+            counters: CellCounters::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<T>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// [`lock`](Self::lock) to change the value, the wait is counted with
+    /// the `diagnostics` feature
+    fn lock_to_write(&self) -> MutexGuard<'_, Inner<T>> {
+        #[cfg(feature = "diagnostics")]
+        let start = std::time::Instant::now();
+        let inner = self.lock();
+        #[cfg(feature = "diagnostics")]
+        self.counters.waited_for_lock(start.elapsed());
+        inner
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub fn counters(&self) -> &CellCounters {
+        &self.counters
+    }
+
+    pub fn get(&self) -> T {
+        self.lock().value
+    }
+
+    /// Replace the value and wake all waiters, returns false if it did not change
+    pub fn set(&self, value: T) -> bool {
+        self.set_if(|_| true, value)
+    }
+
+    /// Like [`StateCell::set`], but only if `predicate` holds for the
+    /// current value. Checked under the same lock, so nothing can change the
+    /// value in between.
+    pub fn set_if(&self, predicate: impl FnOnce(&T) -> bool, value: T) -> bool {
+        self.replace_if(predicate, value).is_ok()
+    }
+
+    /// Like [`StateCell::set_if`], but returns the value it replaced, or
+    /// the current value if it was left alone
+    pub fn replace_if(&self, predicate: impl FnOnce(&T) -> bool, value: T) -> Result<T, T> {
+        let (old, wakers) = {
+            let mut inner = self.lock_to_write();
+            if inner.value == value || !predicate(&inner.value) {
+                return Err(inner.value);
+            }
+            (mem::replace(&mut inner.value, value), mem::take(&mut inner.wakers))
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        Ok(old)
+    }
+
+    /// Wake all waiters without changing the value, for a waiter that
+    /// should have been woken before
+    pub fn wake(&self) {
+        let wakers = mem::take(&mut self.lock().wakers);
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Change the value without waking anyone, to test recovering from a
+    /// lost wakeup
+    #[cfg(test)]
+    pub fn set_silently(&self, value: T) {
+        self.lock().value = value;
+    }
+
+    /// Resolves with the value as soon as `predicate` holds for it. Values
+    /// that are replaced before the waiter gets to run are not seen.
+    pub fn wait_until<F: Fn(&T) -> bool>(&self, predicate: F) -> WaitUntil<'_, T, F> {
+        WaitUntil { cell: self, predicate }
+    }
+}
+
+pub struct WaitUntil<'a, T, F> {
+    cell: &'a StateCell<T>,
+    predicate: F,
+}
+
+impl<'a, T: Copy + PartialEq, F: Fn(&T) -> bool> Future for WaitUntil<'a, T, F> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.cell.lock();
+        if (self.predicate)(&inner.value) {
+            return Poll::Ready(inner.value);
+        }
+        let waker = cx.waker();
+        if !inner.wakers.iter().any(|other| other.will_wake(waker)) {
+            inner.wakers.push(waker.clone());
+            #[cfg(feature = "diagnostics")]
+            self.cell.counters.registered_waker();
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StateCell;
+    use futures::task::noop_waker;
+    use smol::{future, Timer};
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        task::{Context, Poll},
+        thread,
+        time::Duration,
+    };
+
+    /// Fail the test instead of hanging it
+    async fn timeout<T>(future: impl Future<Output = T>) -> T {
+        let expired = async {
+            Timer::after(Duration::from_secs(10)).await;
+            panic!("stalled");
+        };
+        future::or(future, expired).await
+    }
+
+    #[test]
+    fn set_and_wait() {
+        let cell = StateCell::new(0);
+        assert!(!cell.set(0));
+        assert!(cell.set(1));
+        assert_eq!(cell.get(), 1);
+        assert_eq!(smol::block_on(cell.wait_until(|x| *x == 1)), 1);
+        assert!(!cell.set_if(|x| *x == 0, 2));
+        assert!(cell.set_if(|x| *x == 1, 2));
+        assert_eq!(cell.get(), 2);
+        assert_eq!(cell.replace_if(|x| *x == 2, 3), Ok(2));
+        assert_eq!(cell.replace_if(|x| *x == 2, 4), Err(3));
+        assert_eq!(cell.replace_if(|_| true, 3), Err(3));
+
+        let cell: &'static StateCell<i32> = Box::leak(Box::new(StateCell::new(0)));
+        let waiter = smol::spawn(cell.wait_until(|x| *x >= 3));
+        smol::block_on(async {
+            for x in 1..=3 {
+                cell.set(x);
+                future::yield_now().await;
+            }
+            assert_eq!(timeout(waiter).await, 3);
+        });
+    }
+
+    #[test]
+    fn waker_registered_once() {
+        let cell = StateCell::new(0);
+        let mut waiter = pin!(cell.wait_until(|x| *x == 1));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..100 {
+            assert!(waiter.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(cell.lock().wakers.len(), 1);
+        cell.set(1);
+        assert!(cell.lock().wakers.is_empty());
+        assert_eq!(waiter.as_mut().poll(&mut cx), Poll::Ready(1));
+    }
+
+    #[test]
+    fn dropped_waiter() {
+        let cell: &'static StateCell<i32> = Box::leak(Box::new(StateCell::new(0)));
+        smol::block_on(async {
+            // Polled once, then dropped without ever being woken
+            let _ = future::or(cell.wait_until(|x| *x == 1), async { 0 }).await;
+            let waiter = smol::spawn(cell.wait_until(|x| *x == 1));
+            future::yield_now().await;
+            cell.set(1);
+            assert_eq!(timeout(waiter).await, 1);
+        });
+    }
+
+    /// Waiters and setters on different threads while readers keep the lock busy
+    #[test]
+    fn contention() {
+        let cell: &'static StateCell<usize> = Box::leak(Box::new(StateCell::new(0)));
+        let stop: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        cell.get();
+                    }
+                })
+            })
+            .collect();
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..8 {
+            let sender = sender.clone();
+            thread::spawn(move || sender.send(smol::block_on(cell.wait_until(|x| *x == 1000))));
+        }
+
+        for x in 1..=1000 {
+            cell.set(x);
+        }
+        for _ in 0..8 {
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(1000));
+        }
+        stop.store(true, Ordering::Relaxed);
+        readers.into_iter().for_each(|reader| reader.join().unwrap());
+    }
+}
@@ -0,0 +1,411 @@
+use crate::action::ColorChoice;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fmt::Write,
+    io::{self, IsTerminal},
+    path::PathBuf,
+};
+
+/// Descriptions longer than this are cut off in the table view
+pub const DESCRIPTION_WIDTH: usize = 48;
+/// Same for task names, in terminal columns
+pub const NAME_WIDTH: usize = 32;
+/// Same for the command shown with `--verbose`
+pub const COMMAND_WIDTH: usize = 40;
+
+/// State of a single task as reported by `alfad-ctl list`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: String,
+    /// Respawn policy in effect, "no" or "retry:N"
+    #[serde(default)]
+    pub respawn: String,
+    /// Respawns since alfad started or the policy was changed
+    #[serde(default)]
+    pub attempts: usize,
+    /// "service", "marker" or "builtin", empty from older daemons
+    #[serde(default)]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc_url: Option<String>,
+    /// File the task was read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    /// Command line being run, counted from 1, while a service runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    /// Command lines of the service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub steps: Option<usize>,
+    /// The line being run, as written in the task file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Why a Skipped task did not start, or a Deactivated one was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// What a Waiting task waits for, e.g. "respawning: waiting for companion dbus"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waiting: Option<String>,
+    /// Lines of output dropped over the task's `log_rate_limit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suppressed_lines: Option<u64>,
+    /// Why a Deactivated task was, `reason` has it in words
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cause: Option<Deactivation>,
+}
+
+/// Why a task was Deactivated, see [`crate::task::TaskContext::deactivate`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cause", rename_all = "snake_case")]
+pub enum Deactivation {
+    /// A task in `with` or `after` does not exist, a typo more often than not
+    MissingDependency { name: String },
+    /// Deactivated, stopped or disabled with alfad-ctl, `action` is the command
+    OperatorRequest { action: String },
+}
+
+impl std::fmt::Display for Deactivation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingDependency { name } => write!(f, "{name} does not exist"),
+            Self::OperatorRequest { action } => write!(f, "requested with alfad-ctl {action}"),
+        }
+    }
+}
+
+impl Deactivation {
+    pub fn missing(name: &str) -> Self {
+        Self::MissingDependency { name: name.to_owned() }
+    }
+
+    pub fn operator(action: &str) -> Self {
+        Self::OperatorRequest { action: action.to_owned() }
+    }
+}
+
+/// Whether the table printed to stdout gets colors. `auto` also honors
+/// the `NO_COLOR` convention.
+pub fn use_color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Auto => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    }
+}
+
+/// Terminal columns taken by `c`: 0 for combining marks, 2 for wide East
+/// Asian characters and emoji. Close enough for task names and descriptions.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F | 0x200B..=0x200F | 0x20D0..=0x20FF | 0xFE00..=0xFE0F | 0xFE20..=0xFE2F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Terminal columns taken by `s`
+pub fn width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+/// Shorten `s` to at most `width` columns, marking the cut with "…"
+pub fn truncate(s: &str, width: usize) -> String {
+    if self::width(s) <= width {
+        return s.to_owned();
+    }
+    let mut kept = String::new();
+    let mut used = 0;
+    for c in s.chars() {
+        used += char_width(c);
+        if used > width.saturating_sub(1) {
+            break;
+        }
+        kept.push(c);
+    }
+    format!("{}…", kept.trim_end())
+}
+
+/// `s` followed by spaces up to `width` columns
+fn pad(s: &str, width: usize) -> String {
+    format!("{s}{}", " ".repeat(width.saturating_sub(self::width(s))))
+}
+
+/// SGR codes of the table
+mod sgr {
+    pub const BOLD: &str = "1";
+    pub const DIM: &str = "2";
+    pub const RED: &str = "31";
+    pub const GREEN: &str = "32";
+    pub const YELLOW: &str = "33";
+}
+
+fn paint(s: &str, code: &str) -> String {
+    format!("\x1b[{code}m{s}\x1b[0m")
+}
+
+fn state_color(state: &str) -> Option<&'static str> {
+    match state {
+        "Running" | "Done" => Some(sgr::GREEN),
+        "Failed" => Some(sgr::RED),
+        "Waiting" | "Terminating" => Some(sgr::YELLOW),
+        _ => None,
+    }
+}
+
+/// Attempts out of the maximum, e.g. "2/5", or the policy if there are none
+fn respawn(task: &TaskStatus) -> String {
+    match task.respawn.strip_prefix("retry:") {
+        Some(max) => format!("{}/{max}", task.attempts),
+        None => task.respawn.clone(),
+    }
+}
+
+/// The state with the progress of services that run several lines, e.g.
+/// "Running step 7/12", what a Waiting task waits for, why a Deactivated
+/// one was, and how much of its output was dropped
+fn state(task: &TaskStatus) -> String {
+    let state = progress(task);
+    match task.suppressed_lines {
+        Some(lines) => format!("{state}, {lines} lines suppressed"),
+        None => state,
+    }
+}
+
+fn progress(task: &TaskStatus) -> String {
+    match (task.step, task.steps, &task.waiting) {
+        (Some(step), Some(steps), _) if steps > 1 => format!("{} step {step}/{steps}", task.state),
+        (_, _, Some(waiting)) if task.state == "Waiting" => {
+            let mut chars = waiting.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_else(|| task.state.clone())
+        }
+        _ => match &task.cause {
+            Some(cause) if task.state == "Deactivated" => format!("Deactivated: {cause}"),
+            _ => task.state.clone(),
+        },
+    }
+}
+
+/// Render the status list as an aligned table, one task per line. With
+/// `color`, states are colored and markers dimmed, `verbose` adds the
+/// command lines being run.
+pub fn table(tasks: &[TaskStatus], color: bool, verbose: bool) -> String {
+    let names: Vec<_> = tasks.iter().map(|task| truncate(&task.name, NAME_WIDTH)).collect();
+    let states: Vec<_> = tasks.iter().map(state).collect();
+    let respawns: Vec<_> = tasks.iter().map(respawn).collect();
+    let commands: Vec<_> =
+        tasks.iter().map(|task| task.command.as_deref().map(|x| truncate(x, COMMAND_WIDTH)).unwrap_or_default()).collect();
+    let name_width = names.iter().map(|x| width(x)).chain([4]).max().unwrap_or_default();
+    let state_width = states.iter().map(|x| width(x)).chain([5]).max().unwrap_or_default();
+    let respawn_width = respawns.iter().map(|x| width(x)).chain([7]).max().unwrap_or_default();
+    let command_width = commands.iter().map(|x| width(x)).chain([7]).max().unwrap_or_default();
+    let mut header = format!("{}  {}  {}  ", pad("NAME", name_width), pad("STATE", state_width), pad("RESPAWN", respawn_width));
+    if verbose {
+        header += &format!("{}  ", pad("COMMAND", command_width));
+    }
+    header += "DESCRIPTION";
+    let mut table = String::new();
+    let _ = writeln!(table, "{}", if color { paint(&header, sgr::BOLD) } else { header });
+    for (i, task) in tasks.iter().enumerate() {
+        let description = task.description.as_deref().map(|x| truncate(x, DESCRIPTION_WIDTH)).unwrap_or_default();
+        let marker = task.kind == "marker";
+        let state = match state_color(&task.state) {
+            Some(code) if color && !marker => paint(&pad(&states[i], state_width), code),
+            _ => pad(&states[i], state_width),
+        };
+        let mut line = format!("{}  {state}  {}  ", pad(&names[i], name_width), pad(&respawns[i], respawn_width));
+        if verbose {
+            line += &format!("{}  ", pad(&commands[i], command_width));
+        }
+        line += &description;
+        let line = line.trim_end();
+        let _ = writeln!(table, "{}", if color && marker { paint(line, sgr::DIM) } else { line.to_owned() });
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::{table, truncate, width, Deactivation, TaskStatus};
+
+    fn status(name: &str, state: &str, description: Option<&str>) -> TaskStatus {
+        TaskStatus {
+            name: name.to_owned(),
+            state: state.to_owned(),
+            respawn: "no".to_owned(),
+            attempts: 0,
+            kind: "service".to_owned(),
+            description: description.map(str::to_owned),
+            doc_url: None,
+            source: None,
+            step: None,
+            steps: None,
+            command: None,
+            reason: None,
+            waiting: None,
+            suppressed_lines: None,
+            cause: None,
+        }
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly 10", 10), "exactly 10");
+        assert_eq!(truncate("a bit too long", 10), "a bit too…");
+        assert_eq!(truncate("äöüäöüäöüäöü", 4), "äöü…");
+        assert_eq!(truncate("網絡網絡網絡", 6), "網絡…");
+        assert_eq!(width("網絡…"), 5);
+        assert_eq!(width("e\u{301}"), 1);
+    }
+
+    #[test]
+    fn table_view() {
+        let long = "Brings up all network interfaces configured in /etc/network/interfaces";
+        let getty = TaskStatus { respawn: "retry:5".to_owned(), attempts: 2, ..status("getty", "Running", None) };
+        let tasks = [status("network", "Running", Some(long)), status("a", "Done", None), getty];
+        assert_eq!(
+            table(&tasks, false, false),
+            "NAME     STATE    RESPAWN  DESCRIPTION
+network  Running  no       Brings up all network interfaces configured in…
+a        Done     no
+getty    Running  2/5\n"
+        );
+    }
+
+    /// A reply as sent by the daemon
+    const REPLY: &str = r#"[
+        {"name": "feature::net", "state": "Done", "respawn": "no", "attempts": 0, "kind": "marker"},
+        {"name": "dhcp", "state": "Failed", "respawn": "retry:3", "attempts": 3, "kind": "service"},
+        {"name": "網絡", "state": "Running", "respawn": "no", "attempts": 0, "kind": "service", "description": "Network"},
+        {"name": "x", "state": "Created"}
+    ]"#;
+
+    #[test]
+    fn plain_rendering() {
+        let tasks: Vec<TaskStatus> = serde_json::from_str(REPLY).unwrap();
+        assert_eq!(
+            table(&tasks, false, false),
+            "NAME          STATE    RESPAWN  DESCRIPTION
+feature::net  Done     no
+dhcp          Failed   3/3
+網絡          Running  no       Network
+x             Created\n"
+        );
+    }
+
+    #[test]
+    fn colored_rendering() {
+        let tasks: Vec<TaskStatus> = serde_json::from_str(REPLY).unwrap();
+        assert_eq!(
+            table(&tasks, true, false),
+            "\x1b[1mNAME          STATE    RESPAWN  DESCRIPTION\x1b[0m
+\x1b[2mfeature::net  Done     no\x1b[0m
+dhcp          \x1b[31mFailed \x1b[0m  3/3
+網絡          \x1b[32mRunning\x1b[0m  no       Network
+x             Created\n"
+        );
+    }
+
+    #[test]
+    fn step_progress() {
+        let step = |step, steps| TaskStatus {
+            step: Some(step),
+            steps: Some(steps),
+            command: Some("sh -c 'for disk in /dev/sd?; do wipefs --all $disk; done'".to_owned()),
+            ..status("provision", "Running", None)
+        };
+        let tasks = [step(7, 12), TaskStatus { name: "sshd".to_owned(), ..step(1, 1) }, status("a", "Done", None)];
+        assert_eq!(
+            table(&tasks, false, false),
+            "NAME       STATE              RESPAWN  DESCRIPTION
+provision  Running step 7/12  no
+sshd       Running            no
+a          Done               no\n"
+        );
+        assert_eq!(
+            table(&tasks, false, true),
+            "NAME       STATE              RESPAWN  COMMAND                                  DESCRIPTION
+provision  Running step 7/12  no       sh -c 'for disk in /dev/sd?; do wipefs…
+sshd       Running            no       sh -c 'for disk in /dev/sd?; do wipefs…
+a          Done               no\n"
+        );
+    }
+
+    #[test]
+    fn waiting() {
+        let waiting = |name: &str, waiting: &str| TaskStatus { waiting: Some(waiting.to_owned()), ..status(name, "Waiting", None) };
+        let tasks = [
+            waiting("web", "respawning: waiting for companion db"),
+            waiting("mount", "waiting for udev"),
+            status("db", "Failed", None),
+        ];
+        assert_eq!(
+            table(&tasks, false, false),
+            "NAME   STATE                                 RESPAWN  DESCRIPTION
+web    Respawning: waiting for companion db  no
+mount  Waiting for udev                      no
+db     Failed                                no\n"
+        );
+    }
+
+    #[test]
+    fn deactivated() {
+        let deactivated = |name: &str, cause| TaskStatus { cause: Some(cause), ..status(name, "Deactivated", None) };
+        let tasks = [deactivated("web", Deactivation::missing("dbb")), deactivated("db", Deactivation::operator("stop"))];
+        assert_eq!(
+            table(&tasks, false, false),
+            "NAME  STATE                                       RESPAWN  DESCRIPTION
+web   Deactivated: dbb does not exist             no
+db    Deactivated: requested with alfad-ctl stop  no\n"
+        );
+        let json = serde_json::to_value(&tasks[0]).unwrap();
+        assert_eq!(json["cause"], serde_json::json!({"cause": "missing_dependency", "name": "dbb"}));
+        let parsed: TaskStatus = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.cause, Some(Deactivation::missing("dbb")));
+    }
+
+    #[test]
+    fn suppressed_lines() {
+        let chatty = TaskStatus { suppressed_lines: Some(1200), ..status("chatty", "Running", None) };
+        assert_eq!(
+            table(&[chatty, status("quiet", "Running", None)], false, false),
+            "NAME    STATE                           RESPAWN  DESCRIPTION
+chatty  Running, 1200 lines suppressed  no
+quiet   Running                         no\n"
+        );
+    }
+
+    #[test]
+    fn long_names() {
+        let name = "service::".to_owned() + &"網".repeat(20);
+        let rendered = table(&[status(&name, "Done", None)], false, false);
+        let line = rendered.lines().nth(1).unwrap();
+        assert!(line.starts_with("service::網網網網網網網網網網網…  Done"), "{line}");
+    }
+
+    #[test]
+    fn json_keeps_full_description() {
+        let long = "x".repeat(200);
+        let tasks = vec![status("a", "Done", Some(&long))];
+        let json = serde_json::to_string(&tasks).unwrap();
+        assert!(json.contains(&long));
+        assert_eq!(serde_json::from_str::<Vec<TaskStatus>>(&json).unwrap(), tasks);
+    }
+}
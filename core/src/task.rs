@@ -1,89 +1,221 @@
-use crate::config::{payload::Payload, Respawn, TaskConfig};
-use nix::{sys::signal::Signal, unistd::Pid};
-use serde::Deserialize;
+#[cfg(feature = "diagnostics")]
+use crate::diagnostics::{self, TaskDiagnostics};
+use crate::{
+    adopt,
+    command_line::env,
+    config::{defaults::Defaults, Quorum, RequiresResources, Respawn, RespawnRecheck, TaskConfig, UnmetResources, WaitKind},
+    desired::{DesiredState, DisabledFile},
+    event_log, failure,
+    kernel::{self, Kernel},
+    logger::{self, LogPipe},
+    perform_action, privilege,
+    resources::{self, Probe, Shortage},
+    state_cell::{StateCell, WaitUntil},
+    status::Deactivation,
+    trace,
+};
+use futures::{select_biased, FutureExt};
+use nix::{errno::Errno, libc, sys::signal::Signal, unistd::Pid};
+use serde::{Deserialize, Serialize};
 use smol::{
-    lock::{RwLock, RwLockUpgradableReadGuard},
-    ready,
+    channel::{self, Receiver, Sender, TrySendError},
+    future,
+    lock::RwLock,
+    Timer,
 };
+#[cfg(feature = "diagnostics")]
+use std::sync::atomic::Ordering;
 use std::{
-    collections::HashMap,
-    future::Future,
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs, mem,
     ops::{ControlFlow, Deref},
-    pin::{pin, Pin},
-    task::{Context, Poll, Waker},
+    sync::{atomic::AtomicU64, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 use strum::Display;
-use tracing::{debug, error, info, trace, trace_span};
+use thiserror::Error;
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Debug, Clone, Copy)]
 pub struct ContextMap<'a>(pub &'a HashMap<&'a str, TaskContext>);
 
-pub struct TaskWaiter<'a, F: Fn(&TaskState) -> bool> {
-    context: &'a TaskContext,
-    predicate: F,
-}
+impl<'a> ContextMap<'a> {
+    pub async fn wait_for(&self, other: &str, state: TaskState) -> Result<TaskState, WaitError> {
+        self.wait_until(other, |x| *x == state).await
+    }
+
+    pub async fn wait_for_running(&self, other: &str) -> Result<TaskState, WaitError> {
+        self.wait_until(other, TaskState::is_running).await
+    }
 
-impl<'a, F: Fn(&TaskState) -> bool> Future for TaskWaiter<'a, F> {
-    type Output = TaskState;
+    pub async fn wait_until(&self, other: &str, predicate: impl Fn(&TaskState) -> bool) -> Result<TaskState, WaitError> {
+        Ok(self.get(other)?.wait_until(predicate).await)
+    }
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let _x = trace_span!("TaskWaiter").entered();
-        trace!("Checking {}", self.context.config.name);
-        let mut state_manager = ready!(pin!(self.context.state_manager.write()).poll(cx));
-        trace!("{} is {}", self.context.config.name, state_manager.state);
-        if (self.predicate)(&state_manager.state) {
-            Poll::Ready(state_manager.state)
-        } else {
-            let waker = cx.waker().clone();
-            trace!(?waker, "Waiting");
-            state_manager.wakers.push(waker);
-            Poll::Pending
+    /// [`wait_until`](Self::wait_until) on behalf of `waiter`, which gives
+    /// up once it is stopped. The caller looks at its desired state again.
+    pub async fn wait_until_enabled(
+        &self, waiter: &TaskContext, other: &str, predicate: impl Fn(&TaskState) -> bool,
+    ) -> Result<TaskState, WaitError> {
+        let task = self.get(other)?;
+        select_biased! {
+            state = task.wait_until(predicate).fuse() => Ok(state),
+            _ = waiter.desired.wait_until(|desired| *desired != DesiredState::Enabled).fuse() => Err(WaitError::Cancelled),
         }
     }
-}
 
-impl<'a> ContextMap<'a> {
-    pub async fn wait_for(&self, other: &str, state: TaskState) -> Option<TaskState> {
-        match self.0.get(other) {
-            Some(task) => Some(
-                TaskWaiter {
-                    context: task,
-                    predicate: |x| *x == state,
-                }
-                .await,
-            ),
-            None => None,
+    /// [`wait_until`](Self::wait_until), but give up after `timeout`. A
+    /// state reached already wins, even over a timeout of zero.
+    pub async fn wait_until_timeout(
+        &self, other: &str, predicate: impl Fn(&TaskState) -> bool, timeout: Duration,
+    ) -> Result<TaskState, WaitError> {
+        let task = self.get(other)?;
+        select_biased! {
+            state = task.wait_until(predicate).fuse() => Ok(state),
+            _ = Timer::after(timeout).fuse() => Err(WaitError::Timeout(timeout)),
         }
     }
 
-    pub async fn wait_for_running(&self, other: &str) -> Option<TaskState> {
-        match self.0.get(other) {
-            Some(task) => Some(
-                TaskWaiter {
-                    context: task,
-                    predicate: TaskState::is_running,
-                }
-                .await,
-            ),
-            None => None,
+    pub async fn wait_for_timeout(&self, other: &str, state: TaskState, timeout: Duration) -> Result<TaskState, WaitError> {
+        self.wait_until_timeout(other, |x| *x == state, timeout).await
+    }
+
+    pub async fn wait_for_conclusion(&self, other: &str) -> Result<TaskState, WaitError> {
+        self.wait_until(other, TaskState::has_concluded).await
+    }
+
+    /// Wait until any of `others` matches `predicate`, an error if none of
+    /// them exist
+    pub async fn wait_for_any(
+        &self, others: &[String], predicate: impl Fn(&TaskState) -> bool + Copy,
+    ) -> Result<TaskState, WaitError> {
+        let waiting: Vec<_> =
+            others.iter().filter_map(|other| self.0.get(other.as_str())).map(|task| Box::pin(task.wait_until(predicate))).collect();
+        if waiting.is_empty() {
+            return Err(WaitError::UnknownTask(others.join(", ")));
         }
+        Ok(futures::future::select_all(waiting).await.0)
     }
 
-    pub async fn wait_for_conclusion(&self, other: &str) -> Option<TaskState> {
-        match self.0.get(other) {
-            Some(task) => Some(
-                TaskWaiter {
-                    context: task,
-                    predicate: TaskState::has_concluded,
-                }
-                .await,
-            ),
-            None => None,
+    fn get(&self, name: &str) -> Result<&'a TaskContext, WaitError> {
+        self.0.get(name).ok_or_else(|| WaitError::UnknownTask(name.to_owned()))
+    }
+
+    /// All tasks as they are right now, sorted by name. Never waits for an
+    /// async lock for long, so it can be called from anywhere.
+    ///
+    /// ```
+    /// use alfad::{config::TaskConfig, task::{ContextMap, TaskContext, TaskState}};
+    /// use std::collections::HashMap;
+    ///
+    /// let tasks = HashMap::from([("sshd", TaskContext::new(TaskConfig::new("sshd".into())))]);
+    /// let snapshot = ContextMap(&tasks).snapshot();
+    /// assert_eq!(snapshot[0].name, "sshd");
+    /// assert_eq!(snapshot[0].state, TaskState::Created);
+    /// ```
+    pub fn snapshot(&self) -> Vec<TaskSnapshot> {
+        let mut tasks: Vec<_> = self.0.values().map(TaskContext::snapshot).collect();
+        tasks.sort_by(|a, b| a.name.cmp(&b.name));
+        tasks
+    }
+
+    /// Every state change of every task from now on. Events are dropped
+    /// while the receiver lags [`EVENT_BUFFER`] behind.
+    ///
+    /// ```
+    /// use alfad::{config::TaskConfig, task::{ContextMap, TaskContext, TaskState}};
+    /// use std::collections::HashMap;
+    ///
+    /// let tasks = HashMap::from([("sshd", TaskContext::new(TaskConfig::new("sshd".into())))]);
+    /// let events = ContextMap(&tasks).subscribe();
+    /// smol::block_on(async {
+    ///     tasks["sshd"].update_state(TaskState::Waiting).await;
+    ///     let event = events.recv().await.unwrap();
+    ///     assert_eq!((event.task.as_str(), event.state), ("sshd", TaskState::Waiting));
+    /// });
+    /// ```
+    pub fn subscribe(&self) -> Receiver<StateEvent> {
+        let (sender, receiver) = channel::bounded(EVENT_BUFFER);
+        for task in self.0.values() {
+            task.subscribers.lock().unwrap().push(sender.clone());
         }
+        receiver
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Display, Hash)]
+/// State changes a subscriber may fall behind by
+pub const EVENT_BUFFER: usize = 256;
+
+/// State changes kept per task for failure bundles
+pub const HISTORY: usize = 64;
+
+/// How long a task in `after_stopped` gets to stop before it is signalled
+/// again
+const STOP_RETRY: Duration = Duration::from_secs(1);
+
+/// How often a snapshot tries an async lock before it leaves the value out
+const SNAPSHOT_TRIES: usize = 10;
+
+/// A task entering `state`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateEvent {
+    pub task: String,
+    pub state: TaskState,
+    pub at: SystemTime,
+    /// Why a task was Deactivated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A task at one point in time, see [`ContextMap::snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskSnapshot {
+    pub name: String,
+    pub state: TaskState,
+    /// Of the running or adopted process
+    pub pid: Option<i32>,
+    pub group: Option<String>,
+    /// When the task entered its state, `None` if it never changed
+    pub since: Option<SystemTime>,
+    /// When the task last started running
+    pub started: Option<SystemTime>,
+    /// Respawn policy in effect, `None` if it was being changed
+    pub respawn: Option<Respawn>,
+    /// Respawns so far, `None` if they were being counted
+    pub respawns: Option<usize>,
+}
+
+/// When the state of a task changed
+#[derive(Debug, Clone, Copy, Default)]
+struct StateTimes {
+    since: Option<SystemTime>,
+    started: Option<SystemTime>,
+}
+
+/// The value behind `lock`, unless it stays locked for writing
+fn try_read<T: Copy>(lock: &RwLock<T>) -> Option<T> {
+    (0..SNAPSHOT_TRIES).find_map(|_| lock.try_read().map(|value| *value).or_else(|| {
+        thread::yield_now();
+        None
+    }))
+}
+
+/// Why a wait for another task ended before it got to the state waited for
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum WaitError {
+    /// There is no task of that name
+    #[error("{} does not exist", .0)]
+    UnknownTask(String),
+    /// The waiting task was stopped in the meantime
+    #[error("The wait was cancelled")]
+    Cancelled,
+    #[error("Not reached within {:?}", .0)]
+    Timeout(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display, Hash)]
 pub enum TaskState {
     Created,
     Waiting,
@@ -92,12 +224,15 @@ pub enum TaskState {
     Terminating,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Display, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display, Hash)]
 pub enum ExitReason {
     Done,
     Failed,
     Terminated,
     Deactivated,
+    /// Not started, the kernel lacks what the task requires, or gave up
+    /// waiting for something that never came, see [`TaskContext::skipped`]
+    Skipped,
 }
 
 impl TaskState {
@@ -109,9 +244,27 @@ impl TaskState {
         matches!(self, Self::Running(_))
     }
 
+    /// Whether a task waiting for this one in `after` may go on
+    pub fn has_reached(&self, kind: WaitKind) -> bool {
+        match kind {
+            WaitKind::Running => self.is_running() || *self == Self::Concluded(ExitReason::Done),
+            WaitKind::Done => *self == Self::Concluded(ExitReason::Done),
+            WaitKind::Concluded => self.has_concluded(),
+            WaitKind::Exists => true,
+        }
+    }
+
     pub fn is_waiting(&self) -> bool {
         *self == Self::Waiting
     }
+
+    /// The state as shown to users, concluded tasks show why they ended
+    pub fn name(&self) -> String {
+        match self {
+            Self::Concluded(reason) => reason.to_string(),
+            state => state.to_string(),
+        }
+    }
 }
 
 impl Default for TaskState {
@@ -120,83 +273,177 @@ impl Default for TaskState {
     }
 }
 
+/// Create a context for every task and start driving all of them, except
+/// those disabled during an earlier boot
+pub fn start(configs: Vec<TaskConfig>) -> ContextMap<'static> {
+    let context = ContextMap(Box::leak(Box::new(
+        configs.into_iter().map(|config| (&*config.name.clone().leak(), TaskContext::new(config))).collect(),
+    )));
+    let disabled = DisabledFile::configured().map(|file| file.load()).transpose().unwrap_or_else(|error| {
+        warn!("Could not read the disabled tasks: {error}");
+        None
+    });
+    for name in disabled.unwrap_or_default() {
+        if let Some(task) = context.0.get(name.as_str()) {
+            task.desired.set(DesiredState::Disabled);
+        }
+    }
+    if let Some(path) = trace::configured() {
+        trace::start(path, context);
+    }
+    if let Some(path) = Defaults::load().event_log {
+        event_log::start(path, context);
+    }
+    context.0.values().for_each(|task| spawn(task, context));
+    context
+}
+
 pub fn spawn(context: &'static TaskContext, context_map: ContextMap<'static>) {
-    if matches!(
-        context.config.payload,
-        Payload::Service(_) | Payload::Builtin(_)
-    ) {
+    if !context.config.payload.is_marker() {
         info!("Spawning {}", context.config.name);
     }
-    smol::spawn(async move { drive(context, context_map).await }).detach()
+    smol::spawn(drive(context, context_map)).detach()
 }
 
 pub async fn drive(context: &'static TaskContext, context_map: ContextMap<'static>) {
+    context.commit_config().await;
+    if context.config.payload.is_marker() {
+        return drive_marker(context, context_map).await;
+    }
+    // The logger outlives respawns, but not the driver
+    let logger = logger::start(context);
+    drive_service(context, context_map).await;
+    if let Some(logger) = logger {
+        logger::stop(context, logger).await;
+    }
+}
+
+async fn drive_service(context: &'static TaskContext, context_map: ContextMap<'static>) {
+    let mut respawning = false;
     loop {
+        // A reload of a running task takes effect when it respawns
+        context.commit_config().await;
         context.update_state(TaskState::Waiting).await;
-        for task in context.config.with.iter() {
-            trace!("{} waiting for {task} to be Running", context.config.name);
-            if context_map.wait_for_running(task).await.is_none() {
-                context
-                    .update_state(TaskState::Concluded(ExitReason::Deactivated))
-                    .await;
+        match wait_for_dependencies(context, context_map, respawning).await {
+            Ok(()) => {}
+            Err(WaitError::UnknownTask(task)) => {
+                context.deactivate(Deactivation::missing(&task)).await;
                 return;
             }
+            // Started again right after it was stopped, so it waits anew
+            Err(_) if context.desired.get() == DesiredState::Enabled => continue,
+            // Deactivated by the check of the desired state below
+            Err(_) => {}
         }
+        context.waiting.lock().unwrap().take();
 
-        if context.config.payload.is_marker() {
-            context.update_state(TaskState::Running(0)).await;
+        if let Some(requires) = &context.config.requires_resources {
+            if !wait_for_resources(context, requires, &resources::System).await {
+                return;
+            }
         }
 
-        for task in context.config.after.iter() {
-            trace!("{} waiting for {task} to be Done", context.config.name);
-            if context_map
-                .wait_for(task, TaskState::Concluded(ExitReason::Done))
-                .await
-                .is_none()
-            {
-                context
-                    .update_state(TaskState::Concluded(ExitReason::Deactivated))
-                    .await;
+        // Checked right before every (re)start, so a stopped task neither
+        // respawns nor starts once its dependencies are done
+        let desired = context.desired.get();
+        if desired != DesiredState::Enabled {
+            context.deactivate(Deactivation::operator(desired.command())).await;
+            return;
+        }
+
+        if let Err(missing) = privilege::current().check(&context.config.requires_privileges) {
+            info!(task = context.config.name, %missing, "Skipping");
+            *context.skipped.lock().unwrap() = Some(missing.to_string());
+            context.update_state(TaskState::Concluded(ExitReason::Skipped)).await;
+            return;
+        }
+
+        if let Some(requires) = &context.config.requires_kernel {
+            // Reading the kernel configuration may take a moment the first time
+            if let Err(unmet) = kernel::check(requires, smol::unblock(Kernel::running).await) {
+                info!(task = context.config.name, %unmet, "Skipping");
+                *context.skipped.lock().unwrap() = Some(unmet.to_string());
+                context.update_state(TaskState::Concluded(ExitReason::Skipped)).await;
                 return;
             }
         }
 
-        if context.config.payload.is_marker() {
-            context
-                .update_state(TaskState::Concluded(ExitReason::Done))
-                .await;
-            break;
-        }
+        let stopped = stop_dependencies(context, context_map, respawning).await;
 
-        // Running
+        // Running, or supervising a process started by someone else
         let mut index = 0;
+        let adopted = adopt::pending(context);
+        let steps = context.config.payload.command_count();
+        let lines = context.config.payload.lines().filter(|_| adopted.is_none());
+        let mask = &context.config.mask_args;
+        if let Some(lines) = lines.filter(|lines| lines.len() > 1) {
+            debug!(task = context.config.name, lines = %lines.render(mask));
+        }
+        let started = Instant::now();
         loop {
-            debug!(task = context.config.name, cmd = index);
-            context.update_state(TaskState::Running(index)).await;
-            match context
-                .config
-                .payload
-                .run(index, context, context_map)
-                .await
-            {
+            // Past the last line there is nothing to report as running
+            if adopted.is_some() || context.config.payload.has_step(index) {
+                let cmd = lines.and_then(|lines| lines.get(index)).map(|line| line.render(mask));
+                match steps {
+                    2.. => info!(task = context.config.name, step = index + 1, steps, cmd, "Running step"),
+                    _ => debug!(task = context.config.name, cmd),
+                }
+                context.update_state(TaskState::Running(index)).await;
+            }
+            let flow = match adopted {
+                Some(child) => guard(context, adopt::watch(context, child), started).await,
+                None => guard(context, context.config.payload.run(index, context, context_map), started).await,
+            };
+            match flow {
                 ControlFlow::Continue(_) => {
                     index += 1;
+                    // Let other tasks run between the lines of a long one
+                    future::yield_now().await;
                 }
                 ControlFlow::Break(payload_state) => {
                     let current_state = context.state().await;
                     let state = match (current_state, payload_state) {
                         (TaskState::Terminating, _) => TaskState::Concluded(ExitReason::Terminated),
+                        // Force killed or deactivated while running
+                        (state @ TaskState::Concluded(_), _) => state,
                         (_, state) => state,
                     };
+                    // Before the tasks after this one are woken up
+                    let output = mem::take(&mut *context.exported.lock().unwrap());
+                    let state = match state {
+                        TaskState::Concluded(ExitReason::Done) if context.config.env_export => export(context, &output),
+                        state => state,
+                    };
                     context.update_state(state).await;
-                    info!(task = context.config.name, %state ,"Breaking");
+                    info!(task = context.config.name, %state, "Breaking");
+                    if state == TaskState::Concluded(ExitReason::Failed) {
+                        failure::collect(context, context_map);
+                    }
                     break;
                 }
             }
         }
 
-        // Respawn
-        match context.config.respawn {
+        // Not while shutting down, or if the task itself was stopped
+        let concluded = context.state().await;
+        if context.config.restart_dependency && matches!(concluded, TaskState::Concluded(ExitReason::Done | ExitReason::Failed)) {
+            for name in stopped {
+                info!(task = context.config.name, dependency = name, "Restarting");
+                if let Err(error) = perform_action::start(name, false, context_map).await {
+                    warn!(task = context.config.name, dependency = name, %error, "Could not restart");
+                }
+            }
+        }
+
+        // Respawn, unless the task was stopped on purpose
+        if matches!(
+            context.state().await,
+            TaskState::Concluded(ExitReason::Terminated | ExitReason::Deactivated)
+        ) {
+            break;
+        }
+        let respawn = *context.respawn.read().await;
+        match respawn {
             Respawn::Retry(max_attempts) => {
                 let mut attempts = context.respawn_attempts.write().await;
                 if *attempts < max_attempts {
@@ -207,69 +454,1019 @@ pub async fn drive(context: &'static TaskContext, context_map: ContextMap<'stati
             }
             Respawn::No => break,
         }
+        respawning = true;
     }
 }
 
-#[derive(Debug, Default)]
-pub struct TaskContext {
-    pub config: TaskConfig,
-    state_manager: RwLock<StateManager>,
-    pub child: RwLock<Option<i32>>,
-    pub respawn_attempts: RwLock<usize>,
+/// Done once the variables in `output` are exported, Failed without
+/// exporting anything if it has other lines
+fn export(context: &TaskContext, output: &str) -> TaskState {
+    let task = &context.config.name;
+    match env::export(task, output) {
+        Ok(count) => {
+            info!(task, count, "Exported variables");
+            TaskState::Concluded(ExitReason::Done)
+        }
+        Err(line) => {
+            error!(task, line, "Not exporting anything, stdout is not only VARIABLE=value lines");
+            *context.exit.lock().unwrap() = Some(format!("line {line} of stdout is no VARIABLE=value"));
+            TaskState::Concluded(ExitReason::Failed)
+        }
+    }
+}
+
+/// How long the payload of a killed task may go on before it is given up
+const TERM_GRACE: Duration = Duration::from_secs(5);
+
+/// Run `payload` until it ends. It should end soon after the task is
+/// killed, builtins watch for Terminating and command lines end with their
+/// process. One that still runs TERM_GRACE after the kill, or past the
+/// `run_timeout` counted from `started`, is dropped once its process got
+/// SIGKILL, a process that ignores SIGTERM does not hold up the driver.
+async fn guard(
+    context: &TaskContext, payload: impl future::Future<Output = ControlFlow<TaskState>>, started: Instant,
+) -> ControlFlow<TaskState> {
+    let killed = async {
+        context.wait_until(|state| matches!(state, TaskState::Terminating | TaskState::Concluded(_))).await;
+        Timer::after(TERM_GRACE).await;
+    };
+    let expired = async {
+        match context.config.run_timeout {
+            Some(timeout) => {
+                Timer::at(started + timeout.0).await;
+                timeout
+            }
+            None => future::pending().await,
+        }
+    };
+    let task = &context.config.name;
+    let (state, how) = select_biased! {
+        flow = payload.fuse() => return flow,
+        _ = killed.fuse() => {
+            warn!(task, grace = ?TERM_GRACE, "Still running after it was killed, giving up on it");
+            (ExitReason::Terminated, format!("still running {TERM_GRACE:?} after it was killed"))
+        }
+        timeout = expired.fuse() => {
+            error!(task, %timeout, "Ran longer than run_timeout, killing it");
+            (ExitReason::Failed, format!("ran longer than the run_timeout of {timeout}"))
+        }
+    };
+    if let Err(error) = context.send_signal(Signal::SIGKILL).await {
+        debug!(task, %error);
+    }
+    context.child.set(None);
+    *context.exit.lock().unwrap() = Some(how);
+    ControlFlow::Break(TaskState::Concluded(state))
+}
+
+/// Wait until no task in `after_stopped` runs, with `stop_dependency` the
+/// ones that run are killed first. Tasks that are Created or concluded
+/// count as stopped, tasks that don't exist never run. Returns the tasks
+/// that were killed.
+async fn stop_dependencies(context: &TaskContext, context_map: ContextMap<'static>, respawning: bool) -> Vec<&'static str> {
+    let config = &context.config;
+    let is_stopped = |state: &TaskState| state.has_concluded() || *state == TaskState::Created;
+    let mut stopped = Vec::new();
+    for name in config.after_stopped.iter() {
+        let Some((name, dependency)) = context_map.0.get_key_value(name.as_str()) else {
+            continue;
+        };
+        *context.waiting.lock().unwrap() =
+            Some(WaitingFor { task: name.to_string(), companion: false, until: WaitKind::Done, respawn: respawning, stop: true });
+        let mut stop = config.stop_dependency;
+        loop {
+            trace!(task = config.name, after_stopped = name, "Waiting until stopped");
+            let state = dependency.wait_until(|state| is_stopped(state) || (stop && !state.is_waiting())).await;
+            if is_stopped(&state) {
+                break;
+            }
+            info!(task = config.name, dependency = name, "Stopping");
+            match perform_action::kill(dependency, false).await {
+                Ok(_) if !stopped.contains(name) => stopped.push(*name),
+                Ok(_) => {}
+                Err(error) => {
+                    warn!(task = config.name, dependency = name, %error, "Could not stop, waiting for it to end");
+                    stop = false;
+                }
+            }
+            // Signalled again if its process was about to start
+            let stopping = async {
+                dependency.wait_until(is_stopped).await;
+                true
+            };
+            if future::or(stopping, async {
+                Timer::after(STOP_RETRY).await;
+                false
+            })
+            .await
+            {
+                break;
+            }
+        }
+    }
+    context.waiting.lock().unwrap().take();
+    stopped
+}
+
+/// Wait for the tasks in `with` to run and those in `after` to be Done, as
+/// far as `respawn_recheck` asks for when `respawning`
+async fn wait_for_dependencies(
+    context: &'static TaskContext, context_map: ContextMap<'static>, respawning: bool,
+) -> Result<(), WaitError> {
+    let recheck = if respawning { context.config.respawn_recheck } else { RespawnRecheck::All };
+    let waiting_for = |task: &str, companion, until| {
+        let waiting = WaitingFor { task: task.to_owned(), companion, until, respawn: respawning, stop: false };
+        *context.waiting.lock().unwrap() = Some(waiting);
+    };
+    for task in context.config.with.iter().filter(|_| recheck != RespawnRecheck::None) {
+        trace!(task = context.config.name, with = task, "Waiting until Running");
+        waiting_for(task, true, WaitKind::Running);
+        context_map.wait_until_enabled(context, task, TaskState::is_running).await?;
+    }
+
+    for task in context.config.after.iter().filter(|_| recheck == RespawnRecheck::All) {
+        let kind = context.config.wait_kind(task);
+        trace!(task = context.config.name, after = task, until = %kind, "Waiting");
+        waiting_for(task, false, kind);
+        context_map.wait_until_enabled(context, task, |state| state.has_reached(kind)).await?;
+    }
+    Ok(())
+}
+
+/// Markers stand for their members: they run once any task in `with` has
+/// started and are Done once the tasks in `after` are, all of them or any
+/// depending on the quorum.
+async fn drive_marker(context: &'static TaskContext, context_map: ContextMap<'static>) {
+    let config = &context.config;
+    let started = |state: &TaskState| state.is_running() || *state == TaskState::Concluded(ExitReason::Done);
+    let done = |state: &TaskState| *state == TaskState::Concluded(ExitReason::Done);
+
+    context.update_state(TaskState::Waiting).await;
+    trace!(task = config.name, with = ?config.with, "Waiting until any is Running");
+    if !config.with.is_empty() {
+        if let Err(WaitError::UnknownTask(tasks)) = context_map.wait_for_any(&config.with, started).await {
+            return context.deactivate(Deactivation::missing(&tasks)).await;
+        }
+    }
+    context.update_state(TaskState::Running(0)).await;
+
+    trace!(task = config.name, after = ?config.after, quorum = ?config.quorum, "Waiting until Done");
+    match config.quorum {
+        Quorum::All => {
+            for task in config.after.iter() {
+                if let Err(WaitError::UnknownTask(task)) = context_map.wait_until(task, done).await {
+                    return context.deactivate(Deactivation::missing(&task)).await;
+                }
+            }
+        }
+        Quorum::Any if config.after.is_empty() => {}
+        Quorum::Any => {
+            if let Err(WaitError::UnknownTask(tasks)) = context_map.wait_for_any(&config.after, done).await {
+                return context.deactivate(Deactivation::missing(&tasks)).await;
+            }
+        }
+    }
+    context.update_state(TaskState::Concluded(ExitReason::Done)).await;
+}
+
+/// How often `requires_resources` is looked at again without a `resource_wait`
+const RESOURCE_WAIT: Duration = Duration::from_secs(60);
+
+/// Stay Waiting until `probe` finds what `requires` asks for, looking again
+/// every `resource_wait` and as soon as the task is stopped. Returns false
+/// if the task was Skipped instead.
+async fn wait_for_resources(context: &TaskContext, requires: &RequiresResources, probe: &(dyn Probe + Sync)) -> bool {
+    let wait = requires.resource_wait.map_or(RESOURCE_WAIT, |wait| wait.0);
+    while let Err(shortage) = resources::check(requires, probe) {
+        if requires.unmet == UnmetResources::Skip {
+            info!(task = context.config.name, %shortage, "Skipping");
+            *context.skipped.lock().unwrap() = Some(shortage.to_string());
+            context.update_state(TaskState::Concluded(ExitReason::Skipped)).await;
+            return false;
+        }
+        match context.short_of.lock().unwrap().replace(shortage.clone()) {
+            None => info!(task = context.config.name, %shortage, "Waiting for resources"),
+            Some(_) => debug!(task = context.config.name, %shortage, "Still waiting for resources"),
+        }
+        select_biased! {
+            // The caller deals with the task being stopped
+            _ = context.desired.wait_until(|desired| *desired != DesiredState::Enabled).fuse() => break,
+            _ = Timer::after(wait).fuse() => {}
+        }
+    }
+    context.short_of.lock().unwrap().take();
+    true
+}
+
+/// A task a Waiting task waits for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitingFor {
+    pub task: String,
+    /// In `with`, the task waits for it to run rather than to be Done
+    pub companion: bool,
+    /// What a task in `after` is waited for
+    pub until: WaitKind,
+    /// The task is respawning, rather than starting for the first time
+    pub respawn: bool,
+    /// In `after_stopped`, the task waits for it to stop
+    pub stop: bool,
+}
+
+impl Display for WaitingFor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.respawn {
+            f.write_str("respawning: ")?;
+        }
+        f.write_str("waiting for ")?;
+        if self.companion {
+            f.write_str("companion ")?;
+        }
+        f.write_str(&self.task)?;
+        if self.stop {
+            f.write_str(" to stop")?;
+        } else if !self.companion && self.until != WaitKind::Done {
+            write!(f, " ({})", self.until)?;
+        }
+        Ok(())
+    }
+}
+
+/// Configuration of a task, reads as the [`TaskConfig`] in effect. A reload
+/// replaces it, or stages it until the task is started again if it is
+/// running. Configurations are leaked like the task names, so one that is
+/// replaced stays valid for whoever still looks at it.
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: Mutex<&'static TaskConfig>,
+    staged: Mutex<Option<&'static TaskConfig>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: TaskConfig) -> Self {
+        Self { current: Mutex::new(Box::leak(Box::new(config))), staged: Mutex::new(None) }
+    }
+
+    /// Whether a new configuration waits for the task to be started again
+    pub fn is_staged(&self) -> bool {
+        self.staged.lock().unwrap().is_some()
+    }
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        Self::new(TaskConfig::default())
+    }
+}
+
+impl Deref for LiveConfig {
+    type Target = TaskConfig;
+
+    fn deref(&self) -> &TaskConfig {
+        *self.current.lock().unwrap()
+    }
 }
 
 #[derive(Debug, Default)]
-pub struct StateManager {
-    pub state: TaskState,
-    pub wakers: Vec<Waker>,
-    pub waker: Option<Waker>,
+pub struct TaskContext {
+    pub config: LiveConfig,
+    state: StateCell<TaskState>,
+    pub desired: StateCell<DesiredState>,
+    pub child: StateCell<Option<ChildProcess>>,
+    /// Process to supervise instead of starting the payload next time
+    pub adoptee: StateCell<Option<ChildProcess>>,
+    /// Where the output goes while the task has a logger
+    pub log_pipe: Mutex<Option<LogPipe>>,
+    pub logger: StateCell<Option<ChildProcess>>,
+    /// Lines of output dropped over `log_rate_limit`
+    pub log_suppressed: AtomicU64,
+    /// Why the task was Skipped
+    pub skipped: Mutex<Option<String>>,
+    /// Why the task was Deactivated
+    pub deactivated: Mutex<Option<Deactivation>>,
+    /// How the last command line ended
+    pub exit: Mutex<Option<String>>,
+    /// stdout of the command lines so far if the task has `env_export`
+    pub exported: Mutex<String>,
+    /// What the task waits for while it is Waiting
+    pub waiting: Mutex<Option<WaitingFor>>,
+    /// What is not free while the task waits for `requires_resources`
+    pub short_of: Mutex<Option<Shortage>>,
+    /// Starts out as configured, alfad-ctl can change it at runtime
+    pub respawn: RwLock<Respawn>,
+    pub respawn_attempts: RwLock<usize>,
+    times: Mutex<StateTimes>,
+    /// The last [`HISTORY`] state changes
+    history: Mutex<VecDeque<StateEvent>>,
+    subscribers: Mutex<Vec<Sender<StateEvent>>>,
+    /// State changes since alfad started
+    #[cfg(feature = "diagnostics")]
+    transitions: AtomicU64,
 }
 
 impl TaskContext {
     pub fn new(config: TaskConfig) -> Self {
-        Self {
-            config,
-            ..Default::default()
+        Self { respawn: RwLock::new(config.respawn), config: LiveConfig::new(config), ..Default::default() }
+    }
+
+    /// Use `config` from now on, or from the next start if the task is
+    /// running. Returns whether it waits for that start.
+    pub async fn reconfigure(&self, config: TaskConfig) -> bool {
+        *self.config.staged.lock().unwrap() = Some(Box::leak(Box::new(config)));
+        let running = matches!(self.state().await, TaskState::Running(_) | TaskState::Terminating);
+        if !running {
+            self.commit_config().await;
+        }
+        running
+    }
+
+    /// Swap in the configuration staged by [`TaskContext::reconfigure`], if
+    /// any. A changed respawn policy replaces the one set at runtime.
+    pub async fn commit_config(&self) {
+        let Some(config) = self.config.staged.lock().unwrap().take() else {
+            return;
+        };
+        let old = mem::replace(&mut *self.config.current.lock().unwrap(), config);
+        if old.respawn != config.respawn {
+            *self.respawn.write().await = config.respawn;
+            *self.respawn_attempts.write().await = 0;
         }
+        info!(task = config.name, "Configuration applied");
     }
 
     pub async fn update_state(&self, state: TaskState) {
-        let manager = self.state_manager.upgradable_read().await;
-        if manager.state != state {
-            let mut manager = RwLockUpgradableReadGuard::upgrade(manager).await;
-            manager.state = state;
-            manager.wakers.drain(..).for_each(Waker::wake);
+        self.update_state_if(|_| true, state).await;
+    }
+
+    /// Conclude as Deactivated because of `cause`, which is kept for
+    /// alfad-ctl list and passed on with the state change
+    pub async fn deactivate(&self, cause: Deactivation) {
+        warn!(task = self.config.name, reason = %cause, "Deactivated");
+        *self.deactivated.lock().unwrap() = Some(cause);
+        self.update_state(TaskState::Concluded(ExitReason::Deactivated)).await;
+    }
+
+    /// Update the state unless it changed in a way `predicate` rejects,
+    /// returns whether it was updated
+    pub async fn update_state_if(&self, predicate: impl FnOnce(&TaskState) -> bool, state: TaskState) -> bool {
+        self.transition_if(predicate, state).is_ok()
+    }
+
+    /// Change to `state` if `predicate` holds for the current state. Both
+    /// happen under the same lock, so an action can claim a task in the
+    /// state it checked for. Returns the state that was replaced, or the
+    /// current one if the task was left alone.
+    pub fn transition_if(&self, predicate: impl FnOnce(&TaskState) -> bool, state: TaskState) -> Result<TaskState, TaskState> {
+        #[cfg(feature = "diagnostics")]
+        diagnostics::UPDATES.record();
+        let reason = match state {
+            TaskState::Concluded(ExitReason::Deactivated) => self.deactivated.lock().unwrap().as_ref().map(ToString::to_string),
+            _ => None,
+        };
+        // Timestamps and history first, so a waiter woken by the change sees them
+        let mut times = self.times.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        let previous = self.state.replace_if(predicate, state)?;
+        #[cfg(feature = "diagnostics")]
+        self.transitions.fetch_add(1, Ordering::Relaxed);
+        let at = SystemTime::now();
+        times.since = Some(at);
+        if state.is_running() {
+            times.started = Some(at);
+        }
+        drop(times);
+        let event = StateEvent { task: self.config.name.clone(), state, at, reason };
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        drop(history);
+        self.publish(event);
+        Ok(previous)
+    }
+
+    /// How often the state changed and was waited for, see
+    /// [`diagnostics`]
+    #[cfg(feature = "diagnostics")]
+    pub fn diagnostics(&self) -> TaskDiagnostics {
+        let counters = self.state.counters();
+        TaskDiagnostics {
+            transitions: self.transitions.load(Ordering::Relaxed),
+            waker_registrations: counters.waker_registrations(),
+            lock_waits: counters.lock_waits(),
         }
     }
 
+    /// The last [`HISTORY`] state changes, oldest first
+    pub fn history(&self) -> Vec<StateEvent> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Hand `event` to all subscribers that are still there
+    fn publish(&self, event: StateEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!(task = event.task, "Subscriber lags behind, dropping a state change");
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+    }
+
+    /// The task as it is right now, see [`ContextMap::snapshot`]
+    pub fn snapshot(&self) -> TaskSnapshot {
+        // The same lock as state changes, so the state and its time match
+        let (times, state) = {
+            let times = self.times.lock().unwrap();
+            (*times, self.state.get())
+        };
+        TaskSnapshot {
+            name: self.config.name.clone(),
+            state,
+            pid: self.child.get().map(|child| child.pid),
+            group: self.config.group.clone(),
+            since: times.since,
+            started: times.started,
+            respawn: try_read(&self.respawn),
+            respawns: try_read(&self.respawn_attempts),
+        }
+    }
+
+    pub fn wait_until<F: Fn(&TaskState) -> bool>(&self, predicate: F) -> WaitUntil<'_, TaskState, F> {
+        self.state.wait_until(predicate)
+    }
+
     pub async fn state(&self) -> TaskState {
-        self.state_manager.read().await.state
+        self.state.get()
+    }
+
+    /// The state for synchronous code, it never blocks for long
+    pub fn state_now(&self) -> TaskState {
+        self.state.get()
+    }
+
+    /// Make everyone waiting for this task check its state again
+    pub fn wake(&self) {
+        self.state.wake();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn update_state_silently(&self, state: TaskState) {
+        self.state.set_silently(state);
+    }
+
+    /// Signal the running child, unless it has exited and its pid might
+    /// belong to another process by now
+    pub async fn send_signal(&self, signal: Signal) -> Result<(), SignalError> {
+        self.send_signal_with(signal, &ProcFs).await
+    }
+
+    async fn send_signal_with(&self, signal: Signal, processes: &dyn ProcessTable) -> Result<(), SignalError> {
+        let child = self.child.get().ok_or_else(|| SignalError::NoProcess(self.config.name.clone()))?;
+        let gone = || SignalError::Gone { task: self.config.name.clone(), pid: child.pid };
+        if !child.is_alive(processes) {
+            return Err(gone());
+        }
+        match nix::sys::signal::kill(Pid::from_raw(child.pid), signal) {
+            Err(Errno::ESRCH) => Err(gone()),
+            result => Ok(result?),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SignalError {
+    #[error("{} has no running process", .0)]
+    NoProcess(String),
+    #[error("Process {pid} of {task} is already gone")]
+    Gone { task: String, pid: i32 },
+    #[error(transparent)]
+    Errno(#[from] Errno),
+}
+
+/// A spawned process. The start time tells it apart from a later process
+/// that got the same pid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChildProcess {
+    pub pid: i32,
+    pub start_time: Option<u64>,
+}
+
+// Pids are kept as i32 and handed to nix as `pid_t`
+const _: () = assert!(mem::size_of::<libc::pid_t>() == mem::size_of::<i32>());
+
+impl ChildProcess {
+    pub fn new(pid: i32) -> Self {
+        Self { pid, start_time: ProcFs.start_time(pid) }
+    }
+
+    /// The process std or smol spawned, `None` if `id` is no valid pid
+    pub fn from_id(id: u32) -> Option<Self> {
+        i32::try_from(id).ok().filter(|pid| *pid > 0).map(Self::new)
+    }
+
+    pub fn is_alive(&self, processes: &dyn ProcessTable) -> bool {
+        match (processes.start_time(self.pid), self.start_time) {
+            (None, _) => false,
+            (Some(current), Some(start_time)) => current == start_time,
+            (Some(_), None) => true,
+        }
+    }
+}
+
+pub trait ProcessTable: Sync {
+    /// Start time of `pid` in clock ticks after boot, `None` if there is no such process
+    fn start_time(&self, pid: i32) -> Option<u64>;
+    /// Arguments of `pid` joined by spaces
+    fn cmdline(&self, pid: i32) -> Option<String>;
+    /// Whether `pid` has exited and waits for its parent to reap it
+    fn is_zombie(&self, pid: i32) -> bool;
+    /// Processes whose parent is `parent`
+    fn children(&self, parent: i32) -> Vec<i32>;
+}
+
+/// The real process table in /proc
+pub struct ProcFs;
+
+impl ProcessTable for ProcFs {
+    fn start_time(&self, pid: i32) -> Option<u64> {
+        let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        parse_start_time(&stat)
+    }
+
+    fn cmdline(&self, pid: i32) -> Option<String> {
+        let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+        parse_cmdline(&cmdline)
+    }
+
+    fn is_zombie(&self, pid: i32) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| parse_process_state(&stat) == Some('Z'))
+    }
+
+    fn children(&self, parent: i32) -> Vec<i32> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|pid| fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| parse_parent(&stat) == Some(parent)))
+            .collect()
+    }
+}
+
+/// The command name in /proc/<pid>/stat may contain spaces and parentheses,
+/// the fields after it are counted from the last ')'. starttime is field 22.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The state is the first field after the command name
+fn parse_process_state(stat: &str) -> Option<char> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().next()?.chars().next()
+}
+
+/// The parent pid follows the state
+fn parse_parent(stat: &str) -> Option<i32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Arguments are separated and terminated by NUL, kernel threads have none
+fn parse_cmdline(cmdline: &[u8]) -> Option<String> {
+    let cmdline = String::from_utf8_lossy(cmdline.strip_suffix(b"\0").unwrap_or(cmdline)).replace('\0', " ");
+    (!cmdline.is_empty()).then_some(cmdline)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        parse_cmdline, parse_parent, parse_process_state, parse_start_time, wait_for_resources, ChildProcess, ContextMap,
+        ExitReason, ProcFs, ProcessTable, SignalError, TaskContext, TaskState, WaitError, EVENT_BUFFER,
+    };
+    use crate::{
+        config::{yaml::Timeout, RequiresResources, Respawn, TaskConfig, UnmetResources},
+        desired::DesiredState,
+        resources::{Probe, Shortage},
+    };
+    use nix::sys::signal::Signal;
+    use smol::{future, Timer};
+    use std::{
+        collections::HashMap,
+        io,
+        path::Path,
+        sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    struct FakeTable(HashMap<i32, u64>);
+
+    impl ProcessTable for FakeTable {
+        fn start_time(&self, pid: i32) -> Option<u64> {
+            self.0.get(&pid).copied()
+        }
+
+        fn cmdline(&self, _: i32) -> Option<String> {
+            None
+        }
+
+        fn is_zombie(&self, _: i32) -> bool {
+            false
+        }
+
+        fn children(&self, _: i32) -> Vec<i32> {
+            Vec::new()
+        }
+    }
+
+    /// Fail the test instead of hanging it
+    async fn timeout<T>(what: &str, future: impl std::future::Future<Output = T>) -> T {
+        let expired = async {
+            Timer::after(Duration::from_secs(10)).await;
+            panic!("{what} stalled");
+        };
+        future::or(future, expired).await
+    }
+
+    /// Dependents wait on a task whose state lock is hammered by readers,
+    /// while the task runs through its states
+    #[test]
+    fn wakeups_under_contention() {
+        for _ in 0..100 {
+            let map = ContextMap(Box::leak(Box::new(HashMap::from([("a", TaskContext::default())]))));
+            let context = &map.0["a"];
+            let stop: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    thread::spawn(move || {
+                        while !stop.load(Ordering::Relaxed) {
+                            smol::block_on(context.state());
+                        }
+                    })
+                })
+                .collect();
+            let running = smol::spawn(async move { map.wait_for_running("a").await });
+            let waiters = [
+                smol::spawn(async move { map.wait_until("a", TaskState::has_concluded).await }),
+                smol::spawn(async move { map.wait_for("a", TaskState::Concluded(ExitReason::Done)).await }),
+                smol::spawn(async move { map.wait_for_conclusion("a").await }),
+            ];
+            smol::block_on(async {
+                context.update_state(TaskState::Waiting).await;
+                context.update_state(TaskState::Running(0)).await;
+                assert!(timeout("wait_for_running", running).await.is_ok());
+                for index in 1..3 {
+                    context.update_state(TaskState::Running(index)).await;
+                    future::yield_now().await;
+                }
+                context.update_state(TaskState::Concluded(ExitReason::Done)).await;
+                for waiter in waiters {
+                    assert!(timeout("waiter", waiter).await.is_ok());
+                }
+            });
+            stop.store(true, Ordering::Relaxed);
+            readers.into_iter().for_each(|reader| reader.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn bounded_waits() {
+        const DONE: TaskState = TaskState::Concluded(ExitReason::Done);
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([("a", TaskContext::default())]))));
+        let context = &map.0["a"];
+        let short = Duration::from_millis(20);
+        smol::block_on(async {
+            assert_eq!(map.wait_for_timeout("b", DONE, short).await, Err(WaitError::UnknownTask("b".into())));
+            assert_eq!(map.wait_for_timeout("a", DONE, short).await, Err(WaitError::Timeout(short)));
+            assert_eq!(map.wait_for_timeout("a", DONE, Duration::ZERO).await, Err(WaitError::Timeout(Duration::ZERO)));
+
+            // Satisfied already, so not even a zero timeout gets in the way
+            let created = map.wait_for_timeout("a", TaskState::Created, Duration::ZERO).await;
+            assert_eq!(created, Ok(TaskState::Created));
+
+            let waiter = smol::spawn(async move {
+                map.wait_until_timeout("a", TaskState::has_concluded, Duration::from_secs(10)).await
+            });
+            Timer::after(short).await;
+            context.update_state(TaskState::Running(0)).await;
+            context.update_state(DONE).await;
+            assert_eq!(timeout("waiter", waiter).await, Ok(DONE));
+        });
+    }
+
+    #[test]
+    fn failed_waits() {
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([("a", TaskContext::default()), ("b", TaskContext::default())]))));
+        let unknown = |name: &str| Err(WaitError::UnknownTask(name.into()));
+        smol::block_on(async {
+            assert_eq!(map.wait_for_running("c").await, unknown("c"));
+            assert_eq!(map.wait_for_conclusion("c").await, unknown("c"));
+            assert_eq!(map.wait_for_any(&["c".into(), "d".into()], TaskState::is_running).await, unknown("c, d"));
+            assert_eq!(map.wait_until_enabled(&map.0["b"], "c", TaskState::is_running).await, unknown("c"));
+
+            // Stopping the waiting task ends the wait, even one that started stopped
+            let waiter = smol::spawn(async move { map.wait_until_enabled(&map.0["b"], "a", TaskState::is_running).await });
+            Timer::after(Duration::from_millis(20)).await;
+            map.0["b"].desired.set(DesiredState::Stopped);
+            assert_eq!(timeout("waiter", waiter).await, Err(WaitError::Cancelled));
+            assert_eq!(map.wait_until_enabled(&map.0["b"], "a", TaskState::is_running).await, Err(WaitError::Cancelled));
+
+            // Unless the task is there already
+            map.0["a"].update_state(TaskState::Running(0)).await;
+            assert_eq!(map.wait_until_enabled(&map.0["b"], "a", TaskState::is_running).await, Ok(TaskState::Running(0)));
+        });
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn diagnostics() {
+        let map = ContextMap(Box::leak(Box::new(HashMap::from([("a", TaskContext::default())]))));
+        let context = &map.0["a"];
+        let updates = crate::diagnostics::UPDATES.total();
+        smol::block_on(async {
+            let running = smol::spawn(async move { map.wait_for_running("a").await });
+            timeout("registration", async {
+                while context.diagnostics().waker_registrations == 0 {
+                    Timer::after(Duration::from_millis(1)).await;
+                }
+            })
+            .await;
+            context.update_state(TaskState::Waiting).await;
+            context.update_state(TaskState::Running(0)).await;
+            assert!(timeout("wait_for_running", running).await.is_ok());
+            // Neither a repeated state nor a rejected one is a transition
+            context.update_state(TaskState::Running(0)).await;
+            assert!(!context.update_state_if(TaskState::is_waiting, TaskState::Terminating).await);
+            context.update_state(TaskState::Concluded(ExitReason::Done)).await;
+        });
+
+        let diagnostics = context.diagnostics();
+        assert_eq!(diagnostics.transitions, 3);
+        assert!(diagnostics.waker_registrations >= 1, "{diagnostics}");
+        // Every update took the lock, changed or not
+        assert_eq!(diagnostics.lock_waits.iter().sum::<u64>(), 5, "{diagnostics}");
+        assert!(crate::diagnostics::UPDATES.total() >= updates + 5);
     }
 
-    pub async fn send_signal(&self, signal: Signal) {
-        if let Some(child) = self.child.read().await.deref() {
-            let pid = Pid::from_raw(*child);
-            if let Err(error) = nix::sys::signal::kill(pid, signal) {
-                error!("{error}");
+    fn tasks(names: &[&'static str]) -> ContextMap<'static> {
+        let tasks = names.iter().map(|name| (*name, TaskContext::new(TaskConfig::new(name.to_string()))));
+        ContextMap(Box::leak(Box::new(tasks.collect())))
+    }
+
+    #[test]
+    fn snapshots() {
+        let map = tasks(&["b", "a"]);
+        let before = map.snapshot();
+        assert_eq!(before.iter().map(|task| task.name.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!((before[0].since, before[0].started, before[0].respawns), (None, None, Some(0)));
+
+        smol::block_on(map.0["a"].update_state(TaskState::Running(0)));
+        map.0["a"].child.set(Some(ChildProcess { pid: 42, start_time: None }));
+        smol::block_on(map.0["a"].update_state(TaskState::Concluded(ExitReason::Failed)));
+        let after = map.snapshot();
+        assert_eq!(after[0].state, TaskState::Concluded(ExitReason::Failed));
+        assert_eq!(after[0].pid, Some(42));
+        assert!(after[0].started.is_some() && after[0].started <= after[0].since);
+        assert_eq!(after[1], before[1]);
+
+        // Left out rather than waited for
+        let _changing = smol::block_on(map.0["a"].respawn.write());
+        let snapshot = map.0["a"].snapshot();
+        assert_eq!((snapshot.respawn, snapshot.respawns), (None, Some(0)));
+        assert_eq!(map.0["b"].snapshot().respawn, Some(Respawn::No));
+    }
+
+    /// A task changes its state and respawn count as fast as it can while
+    /// others take snapshots without async
+    #[test]
+    fn snapshots_under_contention() {
+        let map = tasks(&["a"]);
+        let context = &map.0["a"];
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                smol::block_on(async {
+                    for index in 0..10_000 {
+                        context.update_state(TaskState::Running(index)).await;
+                        *context.respawn_attempts.write().await += 1;
+                    }
+                });
+                stop.store(true, Ordering::Relaxed);
+            });
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut last = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        let snapshot = map.snapshot().remove(0);
+                        // Never goes back
+                        if let TaskState::Running(index) = snapshot.state {
+                            assert!(index >= last);
+                            last = index;
+                        }
+                    }
+                });
             }
-        } else {
-            error!("{} has no running process", self.config.name)
+            writer.join().unwrap();
+        });
+        assert_eq!(map.snapshot()[0].respawns, Some(10_000));
+    }
+
+    #[test]
+    fn transitions() {
+        let map = tasks(&["a"]);
+        let context = &map.0["a"];
+        let events = map.subscribe();
+        assert_eq!(context.transition_if(TaskState::is_running, TaskState::Terminating), Err(TaskState::Created));
+        assert_eq!(context.transition_if(|_| true, TaskState::Running(0)), Ok(TaskState::Created));
+        // Already there, so nothing changed
+        assert_eq!(context.transition_if(|_| true, TaskState::Running(0)), Err(TaskState::Running(0)));
+        assert_eq!(context.transition_if(TaskState::is_running, TaskState::Terminating), Ok(TaskState::Running(0)));
+        assert_eq!(context.state_now(), TaskState::Terminating);
+        let states: Vec<_> = context.history().into_iter().map(|event| event.state).collect();
+        assert_eq!(states, [TaskState::Running(0), TaskState::Terminating]);
+        assert_eq!(events.len(), 2);
+    }
+
+    /// Actions race to claim a running task while its driver moves it on,
+    /// every change is made by exactly one of them
+    #[test]
+    fn transitions_under_contention() {
+        let map = tasks(&["a"]);
+        let context = &map.0["a"];
+        for round in 0..200 {
+            let claimed = AtomicUsize::new(0);
+            let concluded = AtomicUsize::new(0);
+            context.transition_if(|_| true, TaskState::Running(round)).unwrap();
+            thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        if context.transition_if(TaskState::is_running, TaskState::Terminating).is_ok() {
+                            claimed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    });
+                }
+                scope.spawn(|| {
+                    if context.transition_if(|state| !state.has_concluded(), TaskState::Concluded(ExitReason::Done)).is_ok() {
+                        concluded.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            });
+            // The driver concluded it, either before or after one action claimed it
+            assert_eq!(concluded.load(Ordering::Relaxed), 1);
+            assert!(claimed.load(Ordering::Relaxed) <= 1);
+            assert_eq!(context.state_now(), TaskState::Concluded(ExitReason::Done));
+            let last: Vec<_> = context.history().into_iter().rev().take(3).map(|event| event.state).collect();
+            let expected = match claimed.load(Ordering::Relaxed) {
+                1 => vec![TaskState::Concluded(ExitReason::Done), TaskState::Terminating, TaskState::Running(round)],
+                _ => vec![TaskState::Concluded(ExitReason::Done), TaskState::Running(round)],
+            };
+            assert_eq!(last[..expected.len()], expected);
         }
     }
 
-    pub async fn set_waker(&self, waker: &Waker) {
-        self.state_manager
-            .write()
-            .await
-            .waker
-            .get_or_insert_with(|| waker.clone())
-            .clone_from(waker);
+    #[test]
+    fn state_events() {
+        let map = tasks(&["a", "b"]);
+        let events = map.subscribe();
+        let gone = map.subscribe();
+        drop(gone);
+        smol::block_on(async {
+            map.0["a"].update_state(TaskState::Waiting).await;
+            // Not a change
+            map.0["a"].update_state(TaskState::Waiting).await;
+            map.0["b"].update_state(TaskState::Running(0)).await;
+            let seen: Vec<_> = [events.recv().await.unwrap(), events.recv().await.unwrap()]
+                .into_iter()
+                .map(|event| (event.task, event.state))
+                .collect();
+            assert_eq!(seen, [("a".to_owned(), TaskState::Waiting), ("b".to_owned(), TaskState::Running(0))]);
+            assert!(events.is_empty());
+            // The dropped receiver is forgotten on the next change
+            assert_eq!(map.0["a"].subscribers.lock().unwrap().len(), 1);
+
+            // A receiver that does not keep up misses changes, the task carries on
+            for index in 0..EVENT_BUFFER * 2 {
+                map.0["a"].update_state(TaskState::Running(index)).await;
+            }
+            assert_eq!(events.len(), EVENT_BUFFER);
+            assert_eq!(map.0["a"].state().await, TaskState::Running(EVENT_BUFFER * 2 - 1));
+        });
     }
 
-    pub async fn wake(&self) {
-        let x = &self.state_manager.read().await.waker;
-        if let Some(waker) = x {
-            waker.wake_by_ref();
+    #[test]
+    fn start_time_from_stat() {
+        let stat = "4242 (tricky) name) S 1 4242 4242 0 -1 4194560 107 0 0 0 0 0 0 0 20 0 1 0 12345 2539520 230 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(12345));
+        assert_eq!(parse_start_time("4242 (truncated"), None);
+        assert!(ChildProcess::from_id(std::process::id()).unwrap().start_time.is_some());
+        assert_eq!(parse_process_state(stat), Some('S'));
+        assert_eq!(parse_process_state("4242 (x) Z 1"), Some('Z'));
+        assert_eq!(parse_parent(stat), Some(1));
+        assert_eq!(parse_parent("4242 (x) Z"), None);
+    }
+
+    #[test]
+    fn pids_from_ids() {
+        assert_eq!(ChildProcess::from_id(std::process::id()).map(|child| child.pid), Some(std::process::id() as i32));
+        // Would signal a whole process group or every process
+        assert_eq!(ChildProcess::from_id(0), None);
+        assert_eq!(ChildProcess::from_id(u32::MAX), None);
+        assert_eq!(ChildProcess::from_id(1 << 31), None);
+    }
+
+    #[test]
+    fn cmdline_from_proc() {
+        assert_eq!(parse_cmdline(b"sleep\0infinity\0").unwrap(), "sleep infinity");
+        assert_eq!(parse_cmdline(b"sh\0-c\0echo a b\0").unwrap(), "sh -c echo a b");
+        assert_eq!(parse_cmdline(b""), None);
+        assert!(ProcFs.cmdline(std::process::id() as i32).is_some());
+        assert!(!ProcFs.is_zombie(std::process::id() as i32));
+        let mut child = std::process::Command::new("sleep").arg("5").spawn().unwrap();
+        let children = ProcFs.children(std::process::id() as i32);
+        child.kill().and_then(|_| child.wait()).unwrap();
+        assert!(children.contains(&(child.id() as i32)));
+    }
+
+    #[test]
+    fn recycled_pid() {
+        let child = ChildProcess { pid: 100, start_time: Some(5) };
+        assert!(child.is_alive(&FakeTable(HashMap::from([(100, 5)]))));
+        assert!(!child.is_alive(&FakeTable(HashMap::from([(100, 9)]))));
+        assert!(!child.is_alive(&FakeTable(HashMap::new())));
+
+        let unknown = ChildProcess { pid: 100, start_time: None };
+        assert!(unknown.is_alive(&FakeTable(HashMap::from([(100, 9)]))));
+    }
+
+    #[test]
+    fn signal_gone_process() {
+        let context = TaskContext::default();
+        let table = FakeTable(HashMap::new());
+        let result = smol::block_on(context.send_signal_with(Signal::SIGTERM, &table));
+        assert!(matches!(result, Err(SignalError::NoProcess(_))));
+
+        context.child.set(Some(ChildProcess { pid: i32::MAX, start_time: Some(1) }));
+        let result = smol::block_on(context.send_signal_with(Signal::SIGTERM, &table));
+        assert!(matches!(result, Err(SignalError::Gone { pid: i32::MAX, .. })));
+    }
+
+    /// As much free memory as the test says, in MB
+    struct FakeMemory(AtomicU64);
+
+    impl Probe for FakeMemory {
+        fn free_mem_mb(&self) -> io::Result<u64> {
+            Ok(self.0.load(Ordering::Relaxed))
+        }
+
+        fn free_disk_mb(&self, _: &Path) -> io::Result<u64> {
+            Err(io::Error::other("not measured"))
+        }
+    }
+
+    #[test]
+    fn resource_waits() {
+        let requires = |unmet| RequiresResources {
+            min_free_mem_mb: Some(200),
+            unmet,
+            resource_wait: Some(Timeout(Duration::from_millis(10))),
+            ..Default::default()
         };
+        let short: &'static FakeMemory = Box::leak(Box::new(FakeMemory(AtomicU64::new(120))));
+        let plenty = FakeMemory(AtomicU64::new(300));
+
+        let context = TaskContext::default();
+        assert!(smol::block_on(wait_for_resources(&context, &requires(UnmetResources::Skip), &plenty)));
+        assert!(!smol::block_on(wait_for_resources(&context, &requires(UnmetResources::Skip), short)));
+        assert_eq!(smol::block_on(context.state()), TaskState::Concluded(ExitReason::Skipped));
+        assert_eq!(context.skipped.lock().unwrap().as_deref(), Some("free mem 120 MB < 200 MB"));
+
+        // Looks again until there is enough
+        let context: &'static TaskContext = Box::leak(Box::default());
+        let waiter = smol::spawn(wait_for_resources(context, Box::leak(Box::new(requires(UnmetResources::Wait))), short));
+        smol::block_on(async {
+            while context.short_of.lock().unwrap().is_none() {
+                Timer::after(Duration::from_millis(1)).await;
+            }
+            assert_eq!(*context.short_of.lock().unwrap(), Some(Shortage::Memory { free: 120, min: 200 }));
+            short.0.store(200, Ordering::Relaxed);
+            assert!(timeout("resource wait", waiter).await);
+        });
+        assert_eq!(*context.short_of.lock().unwrap(), None);
+
+        // A stop ends the wait at once
+        let requires =
+            RequiresResources { resource_wait: Some(Timeout(Duration::from_secs(1000))), ..requires(UnmetResources::Wait) };
+        let stopped = FakeMemory(AtomicU64::new(0));
+        smol::block_on(async {
+            let wait = wait_for_resources(context, &requires, &stopped);
+            let stop = async {
+                Timer::after(Duration::from_millis(20)).await;
+                context.desired.set(DesiredState::Stopped);
+                future::pending().await
+            };
+            assert!(timeout("stopping", future::or(wait, stop)).await);
+        });
     }
 }
@@ -0,0 +1,310 @@
+//! Chrome trace of the boot, opens in about:tracing and Perfetto. Every
+//! state of a task is a slice on the task's own track, with the task and
+//! its group as categories. Events are written as they happen, from a
+//! thread of their own so state changes never wait for the disk, and the
+//! file is finalized once the boot is complete.
+
+use crate::{
+    builtin::boot::BOOT_COMPLETE,
+    config::defaults::Defaults,
+    task::{ContextMap, StateEvent, TaskState},
+};
+use futures::{future, select, Future, FutureExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use smol::channel::Receiver;
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    pin::pin,
+    thread,
+    time::SystemTime,
+};
+use tracing::{info, warn};
+
+/// Overrides `trace_out` of defaults.yaml and the kernel command line
+pub const ENV_TRACE_OUT: &str = "ALFAD_TRACE_OUT";
+
+/// Where to write the trace, if anywhere
+pub fn configured() -> Option<PathBuf> {
+    env::var_os(ENV_TRACE_OUT).filter(|path| !path.is_empty()).map(PathBuf::from).or_else(|| Defaults::load().trace_out)
+}
+
+/// Kind of a trace event, only slices and the names of tracks are used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    #[serde(rename = "B")]
+    Begin,
+    #[serde(rename = "E")]
+    End,
+    #[serde(rename = "M")]
+    Metadata,
+}
+
+/// An event in the Chrome trace event format
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    pub name: String,
+    /// Comma separated categories
+    pub cat: String,
+    pub ph: Phase,
+    /// Microseconds since the trace started
+    pub ts: u64,
+    pub pid: u32,
+    pub tid: u32,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub args: serde_json::Value,
+}
+
+/// A track per task
+#[derive(Debug)]
+struct Track {
+    tid: u32,
+    cat: String,
+    /// Slice that has begun but not ended yet
+    open: Option<String>,
+}
+
+/// Turns state changes into trace events
+#[derive(Debug)]
+pub struct Tracer {
+    start: SystemTime,
+    tracks: HashMap<String, Track>,
+}
+
+impl Tracer {
+    pub fn new(context_map: ContextMap<'_>, start: SystemTime) -> Self {
+        let mut names: Vec<_> = context_map.0.keys().collect();
+        names.sort();
+        let tracks = names
+            .into_iter()
+            .zip(1..)
+            .map(|(name, tid)| {
+                let config = &context_map.0[name].config;
+                let cat = config.group.iter().fold(name.to_string(), |cat, group| format!("{cat},{group}"));
+                (name.to_string(), Track { tid, cat, open: None })
+            })
+            .collect();
+        Self { start, tracks }
+    }
+
+    fn event(start: SystemTime, name: String, track: &Track, ph: Phase, at: SystemTime) -> TraceEvent {
+        let ts = at.duration_since(start).unwrap_or_default().as_micros().try_into().unwrap_or(u64::MAX);
+        TraceEvent { name, cat: track.cat.clone(), ph, ts, pid: 1, tid: track.tid, args: Default::default() }
+    }
+
+    /// Names of the tracks, ordered like the tasks
+    pub fn metadata(&self) -> Vec<TraceEvent> {
+        let mut tracks: Vec<_> = self.tracks.iter().collect();
+        tracks.sort_by_key(|(_, track)| track.tid);
+        tracks
+            .into_iter()
+            .map(|(name, track)| TraceEvent {
+                args: json!({ "name": name }),
+                ..Self::event(self.start, "thread_name".into(), track, Phase::Metadata, self.start)
+            })
+            .collect()
+    }
+
+    /// End the slice of the previous state and begin one for the new state
+    pub fn change(&mut self, change: &StateEvent) -> Vec<TraceEvent> {
+        let Some(mut track) = self.tracks.remove(&change.task) else {
+            return Vec::new();
+        };
+        let mut events: Vec<_> =
+            track.open.take().map(|name| Self::event(self.start, name, &track, Phase::End, change.at)).into_iter().collect();
+        if change.state != TaskState::Created {
+            let name = change.state.name();
+            events.push(Self::event(self.start, name.clone(), &track, Phase::Begin, change.at));
+            track.open = Some(name);
+        }
+        self.tracks.insert(change.task.clone(), track);
+        events
+    }
+
+    /// End all slices that are still open
+    pub fn finish(&mut self, at: SystemTime) -> Vec<TraceEvent> {
+        let mut events = Vec::new();
+        for track in self.tracks.values_mut() {
+            if let Some(name) = track.open.take() {
+                events.push(Self::event(self.start, name, track, Phase::End, at));
+            }
+        }
+        events.sort_by_key(|event| event.tid);
+        events
+    }
+}
+
+/// The trace file, created once it can be. Until then events are kept, the
+/// file system may not be mounted yet when the boot starts.
+struct Output {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    pending: Vec<TraceEvent>,
+    written: usize,
+}
+
+impl Output {
+    fn new(path: &Path) -> Self {
+        Self { path: path.to_owned(), file: None, pending: Vec::new(), written: 0 }
+    }
+
+    fn open(&mut self) -> io::Result<&mut BufWriter<File>> {
+        if self.file.is_none() {
+            let mut file = BufWriter::new(File::create(&self.path)?);
+            file.write_all(b"[")?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn write(&mut self, events: Vec<TraceEvent>) -> io::Result<()> {
+        self.pending.extend(events);
+        if self.pending.is_empty() || self.open().is_err() {
+            return Ok(());
+        }
+        let file = self.file.as_mut().unwrap();
+        for event in self.pending.drain(..) {
+            // The closing bracket is optional in the array format, so the
+            // file is usable while it is written
+            file.write_all(if self.written == 0 { b"\n" } else { b",\n" })?;
+            serde_json::to_writer(&mut *file, &event)?;
+            self.written += 1;
+        }
+        file.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.write(Vec::new())?;
+        let file = self.open()?;
+        file.write_all(b"\n]\n")?;
+        file.flush()
+    }
+}
+
+/// Write the events of `changes` to `path` until `done`
+pub async fn record(
+    path: &Path, mut tracer: Tracer, changes: Receiver<StateEvent>, done: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let mut output = Output::new(path);
+    output.write(tracer.metadata())?;
+    let mut done = pin!(done.fuse());
+    loop {
+        select! {
+            change = changes.recv().fuse() => match change {
+                Ok(change) => output.write(tracer.change(&change))?,
+                Err(_) => break,
+            },
+            _ = done => break,
+        }
+    }
+    while let Ok(change) = changes.try_recv() {
+        output.write(tracer.change(&change))?;
+    }
+    output.write(tracer.finish(SystemTime::now()))?;
+    output.finish()
+}
+
+/// Trace all tasks of `context_map` to `path` until the boot is complete.
+/// Call before the tasks are spawned, so no state change is missed.
+pub fn start(path: PathBuf, context_map: ContextMap<'static>) {
+    let changes = context_map.subscribe();
+    let tracer = Tracer::new(context_map, SystemTime::now());
+    let done = async move {
+        match context_map.0.contains_key(BOOT_COMPLETE) {
+            true => _ = context_map.wait_until(BOOT_COMPLETE, |state| matches!(state, TaskState::Concluded(_))).await,
+            false => future::pending().await,
+        }
+    };
+    thread::spawn(move || match smol::block_on(record(&path, tracer, changes, done)) {
+        Ok(()) => info!(path = %path.display(), "Wrote the boot trace"),
+        Err(error) => warn!(path = %path.display(), %error, "Could not write the boot trace"),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{record, Phase, TraceEvent, Tracer};
+    use crate::{
+        config::TaskConfig,
+        task::{ContextMap, ExitReason, TaskContext, TaskState},
+    };
+    use smol::channel;
+    use std::{collections::HashMap, fs, time::SystemTime};
+
+    #[test]
+    fn chrome_trace() {
+        let mount = TaskConfig { group: Some("fs".into()), ..TaskConfig::new("mount".into()) };
+        let tasks =
+            HashMap::from([("mount", TaskContext::new(mount)), ("getty", TaskContext::new(TaskConfig::new("getty".into())))]);
+        let map = ContextMap(&tasks);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json");
+
+        let changes = map.subscribe();
+        let tracer = Tracer::new(map, SystemTime::now());
+        let (finish, done) = channel::bounded::<()>(1);
+        smol::block_on(futures::future::join(
+            async {
+                record(&path, tracer, changes, async { _ = done.recv().await }).await.unwrap();
+            },
+            async {
+                let (mount, getty) = (&tasks["mount"], &tasks["getty"]);
+                mount.update_state(TaskState::Waiting).await;
+                getty.update_state(TaskState::Waiting).await;
+                mount.update_state(TaskState::Running(0)).await;
+                mount.update_state(TaskState::Concluded(ExitReason::Done)).await;
+                getty.update_state(TaskState::Running(0)).await;
+                // Unfinished until now, but finalized
+                let partial = fs::read_to_string(&path).unwrap();
+                assert!(partial.starts_with("[\n{") && !partial.trim_end().ends_with(']'), "{partial}");
+                finish.send(()).await.unwrap();
+            },
+        ));
+
+        // Valid JSON in the array format, every event with the fields the
+        // format requires
+        let events: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        for event in &events {
+            let object = event.as_object().unwrap();
+            assert!(object["name"].is_string() && object["cat"].is_string(), "{event}");
+            assert!(["B", "E", "M"].contains(&object["ph"].as_str().unwrap()), "{event}");
+            assert!(object["ts"].is_u64() && object["pid"].is_u64() && object["tid"].is_u64(), "{event}");
+        }
+        let events: Vec<TraceEvent> = events.into_iter().map(|event| serde_json::from_value(event).unwrap()).collect();
+
+        // getty is track 1, mount track 2
+        let names: Vec<_> = events.iter().filter(|event| event.ph == Phase::Metadata).map(|event| &event.args["name"]).collect();
+        assert_eq!(names, ["getty", "mount"]);
+        let track = |tid: u32| -> Vec<(Phase, &str, &str)> {
+            events
+                .iter()
+                .filter(|event| event.tid == tid && event.ph != Phase::Metadata)
+                .map(|event| (event.ph, event.name.as_str(), event.cat.as_str()))
+                .collect()
+        };
+        use Phase::{Begin, End};
+        assert_eq!(
+            track(2),
+            [
+                (Begin, "Waiting", "mount,fs"),
+                (End, "Waiting", "mount,fs"),
+                (Begin, "Running", "mount,fs"),
+                (End, "Running", "mount,fs"),
+                (Begin, "Done", "mount,fs"),
+                (End, "Done", "mount,fs"),
+            ]
+        );
+        assert_eq!(
+            track(1),
+            [(Begin, "Waiting", "getty"), (End, "Waiting", "getty"), (Begin, "Running", "getty"), (End, "Running", "getty")]
+        );
+        for tid in [1, 2] {
+            let times: Vec<_> = events.iter().filter(|event| event.tid == tid).map(|event| event.ts).collect();
+            assert!(times.windows(2).all(|pair| pair[0] <= pair[1]), "{times:?}");
+        }
+    }
+}
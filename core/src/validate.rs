@@ -1,8 +1,95 @@
-use crate::config::TaskConfig;
-use std::collections::HashMap;
+use crate::config::{defaults::Defaults, TaskConfig};
+use itertools::Itertools;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
 use tracing::{error, warn};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A single problem found in the configuration
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Task the finding is about, if it could be parsed
+    pub task: Option<String>,
+    /// File the task (or the error) comes from, `None` for builtins and markers
+    pub file: Option<PathBuf>,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Warning => f.write_str("warning: ")?,
+            Severity::Error => f.write_str("error: ")?,
+        }
+        match (&self.file, &self.task) {
+            (Some(file), _) => write!(f, "{}: ", file.display())?,
+            (None, Some(task)) => write!(f, "<{task}>: ")?,
+            (None, None) => {}
+        }
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    pub fn push(&mut self, severity: Severity, task: Option<&str>, file: Option<&Path>, message: String) {
+        self.findings.push(Finding { severity, task: task.map(str::to_owned), file: file.map(Path::to_owned), message });
+    }
+
+    pub fn push_file(&mut self, severity: Severity, file: &Path, message: String) {
+        self.findings.push(Finding { severity, task: None, file: Some(file.to_owned()), message });
+    }
+
+    pub fn errors(&self) -> usize {
+        self.findings.iter().filter(|finding| finding.severity == Severity::Error).count()
+    }
+
+    /// Emit every finding as a log message
+    pub fn log(&self) {
+        for finding in self.findings.iter() {
+            let location = finding.file.as_ref().map(|file| format!("{}: ", file.display())).unwrap_or_default();
+            match finding.severity {
+                Severity::Warning => warn!("{location}{}", finding.message),
+                Severity::Error => error!("{location}{}", finding.message),
+            }
+        }
+    }
+}
+
+/// [`validate`] with the `validate` feature, otherwise nothing is checked
+pub fn maybe_validate(configs: Vec<TaskConfig>) -> Vec<TaskConfig> {
+    if cfg!(feature = "validate") {
+        return validate(configs);
+    }
+    configs
+}
+
 pub fn validate(configs: Vec<TaskConfig>) -> Vec<TaskConfig> {
+    report(&configs, false).log();
+    let defaults = Defaults::load();
+    programs(&configs, &Resolver::new(Path::new("/"), &defaults.path), Severity::Warning).log();
+    configs
+    // configs.into_iter().filter(|task| !has_loop(task.name.clone(), &map, &vec![])).collect()
+}
+
+/// Check the task tree for missing dependencies and loops. With `strict`,
+/// tasks that will never run because of a loop are errors instead of warnings.
+pub fn report(configs: &[TaskConfig], strict: bool) -> ValidationReport {
     let map: HashMap<_, _> = configs
         .iter()
         .map(|e| {
@@ -11,31 +98,159 @@ pub fn validate(configs: Vec<TaskConfig>) -> Vec<TaskConfig> {
             (e.name.clone(), deps)
         })
         .collect();
+    let mut report = ValidationReport::default();
+    let loop_severity = if strict { Severity::Error } else { Severity::Warning };
     configs.iter().for_each(|task| {
-        has_loop(task.name.clone(), &map, &[]);
+        for dependency in map[&task.name].iter().filter(|x| !map.contains_key(*x)) {
+            let message = format!("{} waits for {dependency}, which does not exist", task.name);
+            report.push(Severity::Error, Some(&task.name), task.source.as_deref(), message);
+        }
+        // A task that does not exist is never running, so this is only a warning
+        for dependency in task.after_stopped.iter().filter(|x| !map.contains_key(*x)) {
+            let message = format!("{} waits for {dependency} to stop, which does not exist", task.name);
+            report.push(Severity::Warning, Some(&task.name), task.source.as_deref(), message);
+        }
+        if let Some(message) = has_loop(task.name.clone(), &map, &[]) {
+            report.push(loop_severity, Some(&task.name), task.source.as_deref(), message);
+        }
     });
-    configs
-    // configs.into_iter().filter(|task| !has_loop(task.name.clone(), &map, &vec![])).collect()
+    report
+}
+
+/// Find programs the way execvp does, but inside `root`
+pub struct Resolver<'a> {
+    root: &'a Path,
+    path: Vec<&'a str>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(root: &'a Path, path: &'a str) -> Self {
+        Self { root, path: path.split(':').filter(|dir| !dir.is_empty()).collect() }
+    }
+
+    /// Returns false if `program` can not be found. Relative paths depend on
+    /// the working directory and are assumed to exist.
+    pub fn exists(&self, program: &str) -> bool {
+        if let Some(absolute) = program.strip_prefix('/') {
+            return self.executable(&self.root.join(absolute));
+        }
+        if program.contains('/') {
+            return true;
+        }
+        self.path.iter().any(|dir| self.executable(&self.root.join(dir.trim_start_matches('/')).join(program)))
+    }
+
+    /// Symlinks are not followed, they may point to an absolute path that
+    /// only makes sense on the target
+    fn executable(&self, path: &Path) -> bool {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_symlink() => true,
+            Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Check that the program of every command line can be found. Programs
+/// taken from a variable are skipped.
+pub fn programs(configs: &[TaskConfig], resolver: &Resolver, severity: Severity) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    for task in configs {
+        let Some(lines) = task.payload.lines() else {
+            continue;
+        };
+        let missing: Vec<_> = (0..)
+            .map_while(|index| lines.get(index))
+            .filter_map(|line| line.program())
+            .filter(|program| !program.contains('$') && !resolver.exists(program))
+            .unique()
+            .collect();
+        if !missing.is_empty() {
+            let message = format!("{} runs {}, which can not be found", task.name, missing.join(", "));
+            report.push(severity, Some(&task.name), task.source.as_deref(), message);
+        }
+    }
+    report
 }
 
-fn has_loop(name: String, map: &HashMap<String, Vec<String>>, visited: &[String]) -> bool {
+fn has_loop(name: String, map: &HashMap<String, Vec<String>>, visited: &[String]) -> Option<String> {
     if visited.contains(&name) {
-        if visited.len() == 1 {
-            warn!("{name} is waiting for itself and will never run")
+        return Some(if visited.len() == 1 {
+            format!("{name} is waiting for itself and will never run")
         } else {
-            warn!(
-                "{name} is waiting for a loop and will never run ({} -> {name})",
-                visited.join(" -> ")
-            );
-        }
-        return true;
+            format!("{name} is waiting for a loop and will never run ({} -> {name})", visited.join(" -> "))
+        });
     }
     let mut visited = visited.to_owned();
     visited.push(name.clone());
-    if let Some(list) = map.get(&name) {
-        list.iter().any(|b| has_loop(b.clone(), map, &visited))
-    } else {
-        error!("No task named {name}");
-        false
+    map.get(&name)?.iter().find_map(|b| has_loop(b.clone(), map, &visited))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{programs, report, Resolver, Severity};
+    use crate::config::{payload::Payload, TaskConfig};
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::{symlink, PermissionsExt},
+    };
+
+    fn task(name: &str, after: &[&str]) -> TaskConfig {
+        let mut task = TaskConfig::new(name.to_owned());
+        for x in after {
+            task.after(x);
+        }
+        task
+    }
+
+    #[test]
+    fn clean_tree() {
+        let configs = [task("a", &[]), task("b", &["a"]), task("c", &["a", "b"])];
+        assert!(report(&configs, true).findings.is_empty());
+    }
+
+    #[test]
+    fn missing_dependency() {
+        let report = report(&[task("a", &["nope"])], false);
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.findings[0].task.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn loops_are_errors_when_strict() {
+        let configs = [task("a", &["b"]), task("b", &["a"]), task("c", &["c"])];
+        let lenient = report(&configs, false);
+        assert_eq!(lenient.findings.len(), 3);
+        assert_eq!(lenient.errors(), 0);
+        assert!(lenient.findings.iter().all(|finding| finding.severity == Severity::Warning));
+        assert_eq!(report(&configs, true).errors(), 3);
+    }
+
+    #[test]
+    fn resolve_programs() {
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["usr/bin", "bin", "sbin"] {
+            fs::create_dir_all(root.path().join(dir)).unwrap();
+        }
+        fs::write(root.path().join("usr/bin/env"), "").unwrap();
+        fs::set_permissions(root.path().join("usr/bin/env"), Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.path().join("bin/data"), "").unwrap();
+        symlink("/bin/busybox", root.path().join("sbin/getty")).unwrap();
+
+        let resolver = Resolver::new(root.path(), "/usr/bin:/bin:/sbin");
+        assert!(resolver.exists("env"));
+        assert!(resolver.exists("/usr/bin/env"));
+        assert!(resolver.exists("getty"));
+        assert!(resolver.exists("./relative"));
+        assert!(!resolver.exists("data"));
+        assert!(!resolver.exists("ip"));
+        assert!(!resolver.exists("/bin/env"));
+        assert!(!Resolver::new(root.path(), "/bin:/sbin").exists("env"));
+
+        let mut task = TaskConfig::new("network".to_owned());
+        task.payload = Payload::Service("env\nip link\n-ip addr\n:$SHELL\nifup -a".parse().unwrap());
+        let report = programs(&[task], &resolver, Severity::Error);
+        assert_eq!(report.errors(), 1);
+        assert_eq!(report.findings[0].message, "network runs ip, ifup, which can not be found");
     }
 }
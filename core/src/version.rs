@@ -0,0 +1,155 @@
+use crate::{
+    privilege::{self, Privilege},
+    protocol::{self, Version},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, ops::RangeInclusive, path::PathBuf, sync::Mutex};
+
+/// Commit alfad was built from, embedded by build.rs
+pub const GIT_HASH: &str = env!("ALFAD_GIT_HASH");
+
+/// When alfad was built, in seconds since the epoch, embedded by build.rs
+pub const BUILD_TIME: &str = env!("ALFAD_BUILD_TIME");
+
+/// Where the running configuration came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    /// Parsed from the task files in this directory
+    Yaml { dir: PathBuf },
+    /// Parsed from a single file with all tasks
+    File { path: PathBuf },
+    /// Loaded from a compiled cache, `format` and `checksum` are the ones in
+    /// its header
+    Cache { path: PathBuf, format: u32, checksum: u32 },
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Yaml { dir } => write!(f, "task files in {}", dir.display()),
+            Self::File { path } => write!(f, "{}", path.display()),
+            Self::Cache { path, format, checksum } => write!(f, "{} (format {format}, checksum {checksum:08x})", path.display()),
+        }
+    }
+}
+
+static LOADED: Mutex<Option<ConfigSource>> = Mutex::new(None);
+
+/// Remember where the configuration was loaded from
+pub fn set_loaded(source: ConfigSource) {
+    *LOADED.lock().unwrap() = Some(source);
+}
+
+pub fn loaded() -> Option<ConfigSource> {
+    LOADED.lock().unwrap().clone()
+}
+
+/// What a build of alfad is, as answer to the version action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    pub git_hash: String,
+    /// Protocol versions spoken
+    pub protocol: RangeInclusive<Version>,
+    /// Only known to the daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tasks: Option<usize>,
+    /// What the daemon may do, see [privilege]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub privileges: Option<Vec<Privilege>>,
+}
+
+impl VersionInfo {
+    pub fn new(git_hash: &str) -> Self {
+        Self {
+            version: crate::VERSION.to_owned(),
+            git_hash: git_hash.to_owned(),
+            protocol: protocol::SUPPORTED,
+            config: None,
+            tasks: None,
+            privileges: None,
+        }
+    }
+
+    /// This build
+    pub fn current() -> Self {
+        Self::new(GIT_HASH)
+    }
+
+    /// This build as the daemon running `tasks`
+    pub fn daemon(tasks: usize) -> Self {
+        Self { config: loaded(), tasks: Some(tasks), privileges: Some(privilege::current().held()), ..Self::current() }
+    }
+
+    /// Whether `other` was built from something else
+    pub fn differs(&self, other: &VersionInfo) -> bool {
+        self.version != other.version || self.git_hash != other.git_hash
+    }
+}
+
+impl Display for VersionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}), protocol {}-{}", self.version, self.git_hash, self.protocol.start(), self.protocol.end())?;
+        if let Some(tasks) = self.tasks {
+            write!(f, ", {tasks} tasks")?;
+        }
+        if let Some(config) = &self.config {
+            write!(f, " from {config}")?;
+        }
+        match self.privileges.as_deref() {
+            None => {}
+            Some([]) => write!(f, ", no privileges")?,
+            Some(privileges) => {
+                let privileges: Vec<_> = privileges.iter().map(ToString::to_string).collect();
+                write!(f, ", privileges {}", privileges.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConfigSource, VersionInfo};
+    use crate::privilege::Privilege;
+
+    #[test]
+    fn reply_structure() {
+        let info = VersionInfo {
+            config: Some(ConfigSource::Cache { path: "/etc/alfad/alfad.bin".into(), format: 1, checksum: 0xbeef }),
+            tasks: Some(12),
+            privileges: Some(vec![Privilege::Kill, Privilege::Reboot]),
+            ..VersionInfo::new("0123abcd")
+        };
+        let json: serde_json::Value = serde_json::to_value(&info).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": crate::VERSION,
+                "git_hash": "0123abcd",
+                "protocol": {"start": 1, "end": 3},
+                "config": {"cache": {"path": "/etc/alfad/alfad.bin", "format": 1, "checksum": 0xbeef}},
+                "tasks": 12,
+                "privileges": ["kill", "reboot"],
+            })
+        );
+        assert_eq!(serde_json::from_value::<VersionInfo>(json).unwrap(), info);
+        assert_eq!(
+            info.to_string(),
+            format!(
+                "{} (0123abcd), protocol 1-3, 12 tasks from /etc/alfad/alfad.bin (format 1, checksum 0000beef), \
+                 privileges kill, reboot",
+                crate::VERSION
+            )
+        );
+
+        // The client knows neither, older clients ignore what they don't know
+        let client = VersionInfo::new("0123abcd");
+        assert_eq!(serde_json::to_value(&client).unwrap().as_object().unwrap().len(), 3);
+        assert!(!client.differs(&info));
+        assert!(client.differs(&VersionInfo::new("fedc")));
+    }
+}
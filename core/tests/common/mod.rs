@@ -0,0 +1,124 @@
+//! Runs the engine in-process on task files in a temporary directory
+
+// Each test binary uses a different part of the harness
+#![allow(dead_code)]
+
+use alfad::{
+    action::ActionError,
+    config::{read_config_in, yaml::TaskConfigYaml},
+    perform_action::perform,
+    task::{self, ContextMap, TaskContext, TaskState},
+};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use smol::{future, Timer};
+use std::{
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tempfile::TempDir;
+
+/// How long any single wait may take before the test fails
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Sandbox {
+    dir: TempDir,
+    pub tasks: ContextMap<'static>,
+}
+
+impl Sandbox {
+    /// Boot the given task files, `(file name, content)`
+    pub fn boot(files: &[(&str, &str)]) -> Self {
+        Self::boot_with(files, Vec::new())
+    }
+
+    /// Boot the given task files and builtins, e.g. fakes defined with `builtin_fn!`
+    pub fn boot_with(files: &[(&str, &str)], builtin: Vec<TaskConfigYaml>) -> Self {
+        Self::boot_in(tempfile::tempdir().unwrap(), files, builtin)
+    }
+
+    /// Boot the given task files with `defaults` as defaults.yaml
+    pub fn boot_with_defaults(defaults: &str, files: &[(&str, &str)]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("defaults.yaml"), defaults).unwrap();
+        Self::boot_in(dir, files, Vec::new())
+    }
+
+    fn boot_in(dir: TempDir, files: &[(&str, &str)], builtin: Vec<TaskConfigYaml>) -> Self {
+        fs::create_dir(dir.path().join("alfad.d")).unwrap();
+        for (name, content) in files {
+            let content = content.replace("$SANDBOX", &dir.path().to_string_lossy());
+            fs::write(dir.path().join("alfad.d").join(name), content).unwrap();
+        }
+        let tasks = task::start(read_config_in(dir.path(), builtin));
+        Self { dir, tasks }
+    }
+
+    /// Directory the task files can write to as `$SANDBOX`
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Content of a file written by the tasks, empty if it does not exist
+    pub fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.file(name)).unwrap_or_default()
+    }
+
+    pub fn task(&self, name: &str) -> &'static TaskContext {
+        self.tasks.0.get(name).unwrap_or_else(|| panic!("{name} does not exist"))
+    }
+
+    pub fn state(&self, name: &str) -> TaskState {
+        smol::block_on(self.task(name).state())
+    }
+
+    /// Block until `predicate` holds for the state of `name`
+    pub fn wait_until(&self, name: &str, predicate: impl Fn(&TaskState) -> bool) -> TaskState {
+        let task = self.task(name);
+        block_on_timeout(&format!("waiting for {name}"), task.wait_until(predicate))
+    }
+
+    pub fn wait_for(&self, name: &str, state: TaskState) {
+        self.wait_until(name, |x| *x == state);
+    }
+
+    /// Send an action the way alfad-ctl does, e.g. `kill sleeper`
+    pub fn perform(&self, action: &str) -> Result<String, ActionError> {
+        block_on_timeout(action, perform(action, self.tasks))
+    }
+}
+
+/// The engine keeps running when a test ends, so at least leave no processes behind
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        for task in self.tasks.0.values() {
+            if let Some(child) = task.child.get() {
+                let _ = kill(Pid::from_raw(child.pid), Signal::SIGKILL);
+            }
+        }
+    }
+}
+
+pub fn block_on_timeout<T>(what: &str, future: impl Future<Output = T>) -> T {
+    smol::block_on(future::or(future, async {
+        Timer::after(TIMEOUT).await;
+        panic!("Timed out {what}");
+    }))
+}
+
+/// Poll `condition` until it holds
+pub fn eventually(what: &str, condition: impl Fn() -> bool) {
+    block_on_timeout(what, async {
+        while !condition() {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+    })
+}
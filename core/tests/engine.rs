@@ -0,0 +1,825 @@
+mod common;
+
+use alfad::{
+    action::ActionError,
+    adopt::AdoptError,
+    builtin::{self, ctl, IntoConfig},
+    config,
+    status::{Deactivation, TaskStatus},
+    task::{self, ChildProcess, ExitReason, TaskState},
+    version::VersionInfo,
+};
+use common::{block_on_timeout, eventually, Sandbox};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use std::{
+    cell::Cell,
+    fs,
+    process::{Child, Command},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+const DONE: TaskState = TaskState::Concluded(ExitReason::Done);
+
+#[test]
+fn dependency_chain() {
+    let sandbox = Sandbox::boot(&[
+        ("c.task", "name: c\ncmd: sh -c 'echo c >> $SANDBOX/order'\nafter: b\n"),
+        ("a.task", "name: a\ncmd: sh -c 'sleep 0.1; echo a >> $SANDBOX/order'\n"),
+        ("b.task", "name: b\ncmd: sh -c 'echo b >> $SANDBOX/order'\nafter: a\n"),
+    ]);
+    sandbox.wait_for("c", DONE);
+    assert_eq!(sandbox.read("order"), "a\nb\nc\n");
+    assert_eq!(sandbox.state("a"), DONE);
+}
+
+#[test]
+fn failure_blocks_dependents() {
+    let sandbox = Sandbox::boot(&[
+        ("broken.task", "name: broken\ncmd: \"false\"\n"),
+        ("after.task", "name: after\ncmd: touch $SANDBOX/ran\nafter: broken\n"),
+        ("ignored.task", "name: ignored\ncmd: -false\n"),
+    ]);
+    sandbox.wait_for("broken", TaskState::Concluded(ExitReason::Failed));
+    sandbox.wait_for("ignored", DONE);
+    sandbox.wait_for("after", TaskState::Waiting);
+    assert!(!sandbox.file("ran").exists());
+}
+
+#[test]
+fn invalid_command_line() {
+    // Command lines are parsed when the task runs, it fails then
+    let sandbox = Sandbox::boot(&[
+        ("broken.task", "name: broken\ncmd: sh -c 'echo\n"),
+        ("after.task", "name: after\ncmd: touch $SANDBOX/ran\nafter: broken\n"),
+    ]);
+    sandbox.wait_for("broken", TaskState::Concluded(ExitReason::Failed));
+    sandbox.wait_for("after", TaskState::Waiting);
+    assert!(!sandbox.file("ran").exists());
+}
+
+#[test]
+fn after_stopped_waits() {
+    let sandbox = Sandbox::boot(&[
+        ("service.task", "name: service\ncmd: sh -c 'sleep 0.3; touch $SANDBOX/stopped'\n"),
+        ("fsck.task", "name: fsck\ncmd: sh -c 'test -f $SANDBOX/stopped && touch $SANDBOX/ran'\nwith: service\nafter_stopped: service\n"),
+        ("missing.task", "name: missing\ncmd: touch $SANDBOX/missing\nafter_stopped: nope\n"),
+    ]);
+    sandbox.wait_until("fsck", TaskState::has_concluded);
+    assert_eq!(sandbox.state("fsck"), DONE);
+    assert!(sandbox.file("ran").exists());
+    // A task that does not exist never runs
+    sandbox.wait_for("missing", DONE);
+}
+
+#[test]
+fn after_stopped_stop_and_restart() {
+    let sandbox = Sandbox::boot(&[
+        ("service.task", "name: service\ncmd: sh -c 'echo service >> $SANDBOX/order; exec sleep 30'\n"),
+        (
+            "migrate.task",
+            "name: migrate\ncmd: sh -c 'echo migrate >> $SANDBOX/order'\nwith: service\nafter_stopped: service\n\
+             stop_dependency: true\nrestart_dependency: true\n",
+        ),
+    ]);
+    sandbox.wait_for("migrate", DONE);
+    // The service may have been stopped before it got to write anything
+    eventually("service restarted", || sandbox.read("order").ends_with("migrate\nservice\n"));
+    assert_eq!(sandbox.read("order").matches("migrate").count(), 1);
+    sandbox.wait_until("service", TaskState::is_running);
+}
+
+#[test]
+fn after_stopped_not_restarted_when_stopped() {
+    let sandbox = Sandbox::boot(&[
+        ("service.task", "name: service\ncmd: sh -c 'echo service >> $SANDBOX/order; exec sleep 30'\n"),
+        (
+            "migrate.task",
+            "name: migrate\ncmd: sh -c 'echo migrate >> $SANDBOX/order; exec sleep 30'\nwith: service\n\
+             after_stopped: service\nstop_dependency: true\nrestart_dependency: true\n",
+        ),
+    ]);
+    eventually("migration running", || sandbox.read("order").ends_with("migrate\n"));
+    sandbox.wait_until("migrate", TaskState::is_running);
+    let order = sandbox.read("order");
+    // Like a shutdown, the service stays down
+    sandbox.perform("kill migrate").unwrap();
+    sandbox.wait_for("migrate", TaskState::Concluded(ExitReason::Terminated));
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert!(sandbox.state("service").has_concluded());
+    assert_eq!(sandbox.read("order"), order);
+}
+
+#[test]
+fn respawn() {
+    let sandbox = Sandbox::boot(&[("again.task", "name: again\ncmd: sh -c 'echo x >> $SANDBOX/runs'\nrespawn: 2\n")]);
+    eventually("three runs", || sandbox.read("runs") == "x\nx\nx\n");
+    sandbox.wait_for("again", DONE);
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(sandbox.read("runs"), "x\nx\nx\n");
+}
+
+#[test]
+fn set_respawn() {
+    let sandbox = Sandbox::boot(&[(
+        "crash.task",
+        "name: crash\ncmd: sh -c 'echo x >> $SANDBOX/runs; sleep 0.05; exit 1'\nrespawn: 1000\n",
+    )]);
+    let runs = || sandbox.read("runs").lines().count();
+    eventually("a few respawns", || runs() >= 3);
+
+    sandbox.perform("set-respawn crash no").unwrap();
+    sandbox.wait_for("crash", TaskState::Concluded(ExitReason::Failed));
+    // The run in progress may have decided to respawn before the change
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let stopped = runs();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(runs(), stopped);
+    assert_eq!(smol::block_on(sandbox.task("crash").respawn.read()).to_string(), "no");
+
+    sandbox.perform("set-respawn crash retry:2").unwrap();
+    sandbox.perform("start crash").unwrap();
+    eventually("two more respawns", || runs() == stopped + 3);
+    sandbox.wait_for("crash", TaskState::Concluded(ExitReason::Failed));
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(runs(), stopped + 3);
+    assert_eq!(*smol::block_on(sandbox.task("crash").respawn_attempts.read()), 2);
+}
+
+#[test]
+fn timeout() {
+    let sandbox = Sandbox::boot(&[
+        ("slow.task", "name: slow\ncmd:\n  - {run: sleep 10, timeout: 100ms}\n  - touch $SANDBOX/slow\n"),
+        (
+            "tolerant.task",
+            "name: tolerant\ncmd:\n  - {run: sleep 10, timeout: 100ms, ignore_return: true}\n  - touch $SANDBOX/tolerant\n",
+        ),
+    ]);
+    sandbox.wait_for("slow", TaskState::Concluded(ExitReason::Failed));
+    sandbox.wait_for("tolerant", DONE);
+    assert!(!sandbox.file("slow").exists());
+    assert!(sandbox.file("tolerant").exists());
+}
+
+#[test]
+fn stuck_payloads() {
+    let sandbox = Sandbox::boot(&[
+        // SIGTERM stays ignored across the exec
+        ("stubborn.task", "name: stubborn\ncmd: sh -c 'trap \"\" TERM; exec sleep 1000'\n"),
+        ("bounded.task", "name: bounded\ncmd:\n  - sleep 1000\n  - touch $SANDBOX/bounded\nrun_timeout: 200ms\n"),
+    ]);
+    sandbox.wait_for("bounded", TaskState::Concluded(ExitReason::Failed));
+    assert!(!sandbox.file("bounded").exists());
+    let exit = sandbox.task("bounded").exit.lock().unwrap().clone();
+    assert_eq!(exit.as_deref(), Some("ran longer than the run_timeout of 200ms"));
+
+    eventually("stubborn to start", || sandbox.task("stubborn").child.get().is_some());
+    let pid = sandbox.task("stubborn").child.get().unwrap().pid;
+    sandbox.perform("kill stubborn").unwrap();
+    assert_eq!(sandbox.state("stubborn"), TaskState::Terminating);
+    sandbox.wait_for("stubborn", TaskState::Concluded(ExitReason::Terminated));
+    let exit = sandbox.task("stubborn").exit.lock().unwrap().clone();
+    assert_eq!(exit.as_deref(), Some("still running 5s after it was killed"));
+    let alive = || fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| !stat.contains(") Z "));
+    eventually("stubborn to be gone", || !alive());
+}
+
+#[test]
+fn kill_and_restart() {
+    let sandbox =
+        Sandbox::boot(&[("sleeper.task", "name: sleeper\ncmd: sh -c 'echo started >> $SANDBOX/runs; exec sleep 1000'\n")]);
+    eventually("first start", || sandbox.read("runs") == "started\n");
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+
+    sandbox.perform("kill sleeper").unwrap();
+    sandbox.wait_for("sleeper", TaskState::Concluded(ExitReason::Terminated));
+
+    sandbox.perform("start sleeper").unwrap();
+    eventually("second start", || sandbox.read("runs") == "started\nstarted\n");
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+
+    sandbox.perform("force-kill sleeper").unwrap();
+    sandbox.wait_for("sleeper", TaskState::Concluded(ExitReason::Terminated));
+}
+
+#[test]
+fn conditional_restart_and_start() {
+    let sandbox =
+        Sandbox::boot(&[("sleeper.task", "name: sleeper\ncmd: sh -c 'echo started >> $SANDBOX/runs; exec sleep 1000'\n")]);
+    eventually("first start", || sandbox.read("runs") == "started\n");
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+    let first = sandbox.task("sleeper").child.get().unwrap().pid;
+
+    sandbox.perform("try-restart sleeper").unwrap();
+    eventually("second start", || sandbox.read("runs") == "started\nstarted\n");
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some_and(|child| child.pid != first));
+
+    // Left alone once it is not running anymore
+    sandbox.perform("stop sleeper").unwrap();
+    sandbox.wait_for("sleeper", TaskState::Concluded(ExitReason::Terminated));
+    assert_eq!(sandbox.perform("try-restart sleeper").unwrap(), "sleeper is Terminated, not restarting");
+    assert_eq!(sandbox.state("sleeper"), TaskState::Concluded(ExitReason::Terminated));
+
+    sandbox.perform("disable sleeper").unwrap();
+    assert_eq!(sandbox.perform("cond-start sleeper").unwrap(), "sleeper is disabled, not starting");
+    assert_eq!(sandbox.state("sleeper"), TaskState::Concluded(ExitReason::Terminated));
+    sandbox.perform("enable sleeper").unwrap();
+    sandbox.perform("cond-start sleeper").unwrap();
+    eventually("third start", || sandbox.read("runs") == "started\nstarted\nstarted\n");
+    // Recorded only after the process started, it would be left running otherwise
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+}
+
+/// A reload applies to a running task once it is started again
+#[test]
+fn reconfigure() {
+    let sleeper = "name: sleeper\ncmd: sh -c 'echo started >> $SANDBOX/runs; exec sleep 1000'\n";
+    let sandbox = Sandbox::boot(&[("sleeper.task", sleeper), ("once.task", "name: once\ncmd: \"true\"\n")]);
+    eventually("first start", || sandbox.read("runs") == "started\n");
+    sandbox.wait_for("once", DONE);
+
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("alfad.d")).unwrap();
+    let reloaded = sleeper.replace("$SANDBOX", &sandbox.path().to_string_lossy()).replace("started", "reloaded");
+    fs::write(dir.path().join("alfad.d/sleeper.task"), reloaded).unwrap();
+    fs::write(dir.path().join("alfad.d/once.task"), "name: once\ncmd: \"true\"\ndescription: Once\n").unwrap();
+    let mut on_disk = config::read_config_in(dir.path(), Vec::new());
+    on_disk.sort_by(|a, b| a.name.cmp(&b.name));
+    let [once, sleeper] = <[_; 2]>::try_from(on_disk).unwrap();
+
+    assert!(!block_on_timeout("reconfigure once", sandbox.task("once").reconfigure(once)));
+    assert_eq!(sandbox.task("once").config.description.as_deref(), Some("Once"));
+    assert!(block_on_timeout("reconfigure sleeper", sandbox.task("sleeper").reconfigure(sleeper)));
+    assert!(sandbox.task("sleeper").config.is_staged());
+    sandbox.perform("try-restart sleeper").unwrap();
+    eventually("restart with the new command", || sandbox.read("runs") == "started\nreloaded\n");
+    assert!(!sandbox.task("sleeper").config.is_staged());
+}
+
+#[test]
+fn deactivate() {
+    let sandbox = Sandbox::boot(&[
+        ("sleeper.task", "name: sleeper\ncmd: sleep 1000\nrespawn: 0\n"),
+        ("after.task", "name: after\ncmd: touch $SANDBOX/ran\nafter: sleeper\n"),
+    ]);
+    sandbox.wait_until("sleeper", TaskState::is_running);
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+
+    sandbox.perform("deactivate sleeper").unwrap();
+    sandbox.wait_for("sleeper", TaskState::Concluded(ExitReason::Deactivated));
+    sandbox.wait_for("after", TaskState::Waiting);
+    assert_eq!(cause(&sandbox, "sleeper"), Some(Deactivation::operator("deactivate")));
+}
+
+#[test]
+fn protected() {
+    let sandbox = Sandbox::boot(&[("guarded.task", "name: guarded\ncmd: sleep 1000\nprotected: true\n")]);
+    sandbox.wait_until("guarded", TaskState::is_running);
+    eventually("guarded to start", || sandbox.task("guarded").child.get().is_some());
+
+    for action in ["kill", "force-deactivate", "restart"] {
+        let error = sandbox.perform(&format!("{action} guarded")).unwrap_err();
+        assert!(matches!(error, ActionError::Protected(..)), "{error}");
+    }
+    assert_eq!(
+        sandbox.perform("kill guarded").unwrap_err().to_string(),
+        "guarded is protected, try again with --force-protected to kill it anyway"
+    );
+    assert!(sandbox.state("guarded").is_running());
+
+    sandbox.perform("protected-deactivate guarded").unwrap();
+    sandbox.wait_for("guarded", TaskState::Concluded(ExitReason::Deactivated));
+}
+
+#[test]
+fn env_export() {
+    let sandbox = Sandbox::boot(&[
+        ("board.task", "name: board\ncmd: printf 'TEST_EXPORT_BOARD=rev2\\n# detected\\n'\nenv_export: true\n"),
+        ("bad.task", "name: bad\ncmd: printf 'TEST_EXPORT_BAD=1\\nnot a variable\\n'\nenv_export: true\n"),
+        ("app.task", "name: app\ncmd: sh -c 'printenv TEST_EXPORT_BOARD > $SANDBOX/board'\nafter: board\n"),
+        ("plain.task", "name: plain\ncmd: \":sh -c 'printenv TEST_EXPORT_BOARD > $SANDBOX/plain'\"\nafter: board\n"),
+    ]);
+    sandbox.wait_for("app", TaskState::Concluded(ExitReason::Done));
+    assert_eq!(fs::read_to_string(sandbox.path().join("board")).unwrap(), "rev2\n");
+    // Opted out with the `:` prefix
+    sandbox.wait_for("plain", TaskState::Concluded(ExitReason::Failed));
+    assert_eq!(fs::read_to_string(sandbox.path().join("plain")).unwrap(), "");
+
+    sandbox.wait_for("bad", TaskState::Concluded(ExitReason::Failed));
+    assert_eq!(alfad::command_line::env::overlay().get("TEST_EXPORT_BAD"), None);
+}
+
+#[test]
+fn internal_tasks() {
+    let sandbox = Sandbox::boot_with(&[], vec![builtin::signals::Signals.into_config(), builtin::reaper::Reaper.into_config()]);
+    for name in ["builtin::signals", "builtin::reaper"] {
+        sandbox.wait_until(name, TaskState::is_running);
+        let error = sandbox.perform(&format!("kill {name}")).unwrap_err();
+        assert!(matches!(error, ActionError::Protected(..)), "{error}");
+    }
+}
+
+/// The cause alfad-ctl list gives for `name`, checked against its reason
+/// and the state event
+fn cause(sandbox: &Sandbox, name: &str) -> Option<Deactivation> {
+    let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+    let task = tasks.into_iter().find(|task| task.name == name).unwrap();
+    let event = sandbox.task(name).history().pop().unwrap();
+    assert_eq!(task.reason, task.cause.as_ref().map(ToString::to_string));
+    assert_eq!(event.reason, task.reason);
+    task.cause
+}
+
+#[test]
+fn deactivation_causes() {
+    let sandbox = Sandbox::boot(&[
+        ("typo.task", "name: typo\ncmd: \"true\"\nafter: dbb\n"),
+        ("companion.task", "name: companion\ncmd: \"true\"\nwith: dbus\n"),
+        ("group.task", "name: group::net\ncmd: marker\nafter: dhcp\n"),
+        ("gate.task", &format!("name: gate\ncmd: {}\n", gated("open"))),
+        ("held.task", "name: held\ncmd: touch $SANDBOX/ran\nafter: gate\n"),
+        ("done.task", "name: done\ncmd: marker\n"),
+    ]);
+    let deactivated = TaskState::Concluded(ExitReason::Deactivated);
+    for (task, missing) in [("typo", "dbb"), ("companion", "dbus"), ("group::net", "dhcp")] {
+        sandbox.wait_for(task, deactivated);
+        assert_eq!(cause(&sandbox, task), Some(Deactivation::missing(missing)), "{task}");
+    }
+    assert_eq!(cause(&sandbox, "typo").unwrap().to_string(), "dbb does not exist");
+
+    // Stopped while it waits, it gives up on its dependency right away
+    sandbox.wait_for("held", TaskState::Waiting);
+    sandbox.perform("stop held").unwrap();
+    sandbox.wait_for("held", deactivated);
+    assert_eq!(cause(&sandbox, "held"), Some(Deactivation::operator("stop")));
+    assert!(sandbox.state("gate").is_running());
+
+    // Started again, it waits anew
+    sandbox.perform("start held").unwrap();
+    sandbox.wait_for("held", TaskState::Waiting);
+    assert!(!sandbox.file("ran").exists());
+    fs::write(sandbox.file("open"), "").unwrap();
+    sandbox.wait_for("held", DONE);
+    assert!(sandbox.file("ran").exists());
+
+    sandbox.wait_for("done", DONE);
+    sandbox.perform("disable done").unwrap();
+    sandbox.wait_for("done", deactivated);
+    assert_eq!(cause(&sandbox, "done"), Some(Deactivation::operator("disable")));
+}
+
+#[test]
+fn after_states() {
+    let sandbox = Sandbox::boot(&[
+        ("db.task", &format!("name: db\ncmd: {}\n", gated("stop-db"))),
+        ("web.task", "name: web\ncmd: \"true\"\nafter: db:running\n"),
+        ("backup.task", "name: backup\ncmd: \"true\"\nafter: db:done\n"),
+        ("broken.task", "name: broken\ncmd: \"false\"\n"),
+        ("cleanup.task", "name: cleanup\ncmd: \"true\"\nafter: broken:concluded\n"),
+        ("probe.task", "name: probe\ncmd: \"true\"\nafter: [db:exists, web:running]\n"),
+        ("typo.task", "name: typo\ncmd: \"true\"\nafter: dbb:exists\n"),
+        ("report.task", "name: report\ncmd: \"true\"\nafter: db:concluded\n"),
+    ]);
+    sandbox.wait_for("web", DONE);
+    // Done counts as having run
+    sandbox.wait_for("probe", DONE);
+    assert!(sandbox.state("db").is_running());
+    sandbox.wait_for("cleanup", DONE);
+    assert_eq!(sandbox.state("broken"), TaskState::Concluded(ExitReason::Failed));
+    sandbox.wait_for("typo", TaskState::Concluded(ExitReason::Deactivated));
+    assert_eq!(cause(&sandbox, "typo"), Some(Deactivation::missing("dbb")));
+
+    let waiting = |name: &str| {
+        let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+        tasks.into_iter().find(|task| task.name == name).unwrap().waiting
+    };
+    eventually("backup and report waiting", || {
+        waiting("backup").as_deref() == Some("waiting for db")
+            && waiting("report").as_deref() == Some("waiting for db (concluded)")
+    });
+    fs::write(sandbox.file("stop-db"), "").unwrap();
+    sandbox.wait_for("backup", DONE);
+    sandbox.wait_for("report", DONE);
+}
+
+#[test]
+fn group_markers() {
+    let sandbox = Sandbox::boot(&[
+        ("mount.task", "name: mount\ncmd: sh -c 'sleep 0.1; touch $SANDBOX/mounted'\ngroup: early\n"),
+        ("udev.task", "name: udev\ncmd: touch $SANDBOX/udev\ngroup: early\n"),
+        ("getty.task", "name: getty\ncmd: sh -c 'test -e $SANDBOX/mounted && test -e $SANDBOX/udev'\nafter: group::early\n"),
+    ]);
+    sandbox.wait_for("getty", DONE);
+    assert_eq!(sandbox.state("group::early"), DONE);
+}
+
+/// Runs until the file `$SANDBOX/<name>` exists
+fn gated(name: &str) -> String {
+    format!("sh -c 'while [ ! -e $SANDBOX/{name} ]; do sleep 0.01; done'")
+}
+
+#[test]
+fn task_environment() {
+    let print = "sh -c 'printenv TZ LANG LC_ALL GREETING > $SANDBOX/env'";
+    let php = format!(
+        "name: php\ngroup: web\nlocale: {{tz: UTC}}\nenv_file: $SANDBOX/php.env\nenv: {{GREETING: hello}}\n\
+         cmd: \":{print}\"\nafter: gate\n"
+    );
+    let sandbox = Sandbox::boot(&[
+        ("gate.task", &format!("name: gate\ncmd: {}\n", gated("go"))),
+        ("web.task", "name: group::web\ncmd: marker\nlocale: {tz: Europe/Berlin, lang: de_DE.UTF-8, lc_all: de_DE.UTF-8}\n"),
+        ("php.task", &php),
+        ("broken.task", "name: broken\ncmd: \"true\"\nenv_file: $SANDBOX/missing.env\n"),
+    ]);
+    // Read when a line runs, not when the task file is loaded
+    fs::write(sandbox.file("php.env"), "# Written by the installer\nLC_ALL=C\nGREETING=hi\n").unwrap();
+    fs::write(sandbox.file("go"), "").unwrap();
+    sandbox.wait_for("php", DONE);
+    // TZ of the task over the group's, LANG from the group, LC_ALL from the
+    // env file over the group's and GREETING of the task over the env file
+    assert_eq!(sandbox.read("env"), "UTC\nde_DE.UTF-8\nC\nhello\n");
+
+    sandbox.wait_for("broken", TaskState::Concluded(ExitReason::Failed));
+    let exit = sandbox.task("broken").exit.lock().unwrap().clone();
+    assert!(exit.as_ref().is_some_and(|exit| exit.starts_with("Could not read")), "{exit:?}");
+}
+
+#[test]
+fn group_follows_members() {
+    let gate = gated("go");
+    let blocked = gated("never");
+    let sandbox = Sandbox::boot(&[
+        ("blocker.task", &format!("name: blocker\ncmd: {blocked}\n")),
+        ("late.task", "name: late\ncmd: \"true\"\ngroup: later\nafter: blocker\n"),
+        ("slow.task", &format!("name: slow\ncmd: {gate}\ngroup: early\n")),
+        ("fast.task", "name: fast\ncmd: \"true\"\ngroup: early\n"),
+    ]);
+    // Running as soon as a member is, Done only once all of them are
+    sandbox.wait_until("group::early", TaskState::is_running);
+    sandbox.wait_for("fast", DONE);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert!(sandbox.state("slow").is_running());
+    assert!(sandbox.state("group::early").is_running());
+    std::fs::write(sandbox.file("go"), "").unwrap();
+    sandbox.wait_for("group::early", DONE);
+
+    // No member has started
+    assert_eq!(sandbox.state("group::later"), TaskState::Waiting);
+}
+
+#[test]
+fn step_progress() {
+    let steps = [gated("1"), gated("2"), gated("3")];
+    let task = format!("name: provision\ncmd:\n  - {}\n  - {}\n  - {}\n", steps[0], steps[1], steps[2]);
+    let sandbox = Sandbox::boot(&[("provision.task", &task)]);
+    let status = || {
+        let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+        tasks.into_iter().find(|task| task.name == "provision").unwrap()
+    };
+    for (index, command) in steps.iter().enumerate() {
+        sandbox.wait_for("provision", TaskState::Running(index));
+        let status = status();
+        assert_eq!((status.step, status.steps), (Some(index + 1), Some(3)));
+        // Variables are expanded when the task is read
+        let command = command.replace("$SANDBOX", &sandbox.path().to_string_lossy());
+        assert_eq!(status.command, Some(command));
+        fs::write(sandbox.file(&(index + 1).to_string()), "").unwrap();
+    }
+    sandbox.wait_for("provision", DONE);
+    let status = status();
+    assert_eq!((status.step, status.steps, status.command), (None, None, None));
+}
+
+#[test]
+fn responsive_under_load() {
+    // Many lines that each take no time keep the executor busy in between
+    let task = |index| format!("name: burst-{index}\ncmd:\n{}", "  - \"true\"\n".repeat(40));
+    let files: Vec<_> = (0..24).map(|index| (format!("burst-{index}.task"), task(index))).collect();
+    let files: Vec<_> = files.iter().map(|(name, content)| (name.as_str(), content.as_str())).collect();
+    let sandbox = Sandbox::boot(&files);
+    let slowest = Cell::new(Duration::ZERO);
+    eventually("the burst to finish", || {
+        let started = Instant::now();
+        sandbox.perform("list").unwrap();
+        sandbox.perform("cat burst-0").unwrap();
+        slowest.set(slowest.get().max(started.elapsed()));
+        (0..24).all(|index| sandbox.state(&format!("burst-{index}")) == DONE)
+    });
+    assert!(slowest.get() < Duration::from_secs(1), "Answering took up to {:?}", slowest.get());
+}
+
+#[test]
+fn requires_kernel() {
+    let sandbox = Sandbox::boot(&[
+        ("future.task", "name: future\ncmd: touch $SANDBOX/future\nrequires_kernel:\n  min_version: \"999.0\"\n"),
+        ("after.task", "name: after\ncmd: \"true\"\nafter: future\n"),
+        ("old.task", "name: old\ncmd: touch $SANDBOX/old\nrequires_kernel: {min_version: \"2.6\"}\n"),
+    ]);
+    sandbox.wait_for("future", TaskState::Concluded(ExitReason::Skipped));
+    sandbox.wait_for("old", DONE);
+    sandbox.wait_for("after", TaskState::Waiting);
+    assert!(!sandbox.file("future").exists());
+
+    let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+    let future = tasks.iter().find(|task| task.name == "future").unwrap();
+    assert_eq!(future.state, "Skipped");
+    assert!(future.reason.as_ref().unwrap().ends_with("is older than 999.0.0"), "{:?}", future.reason);
+}
+
+#[test]
+fn requires_resources() {
+    let sandbox = Sandbox::boot(&[
+        ("huge.task", "name: huge\ncmd: touch $SANDBOX/huge\nrequires_resources: {min_free_mem_mb: 100000000, unmet: skip}\n"),
+        ("small.task", "name: small\ncmd: \"true\"\nrequires_resources: {min_free_mem_mb: 1}\n"),
+        (
+            "data.task",
+            "name: data\ncmd: \"true\"\n\
+             requires_resources: {min_free_disk_mb: {path: $SANDBOX/data, mb: 0}, resource_wait: 10ms}\n",
+        ),
+    ]);
+    sandbox.wait_for("huge", TaskState::Concluded(ExitReason::Skipped));
+    sandbox.wait_for("small", DONE);
+    assert!(!sandbox.file("huge").exists());
+
+    let status = |name: &str| {
+        let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+        tasks.into_iter().find(|task| task.name == name).unwrap()
+    };
+    assert!(status("huge").reason.unwrap().starts_with("free mem "));
+
+    // Its disk can't be measured before it exists
+    let waiting = format!("waiting for resources (free disk on {} unknown)", sandbox.file("data").display());
+    eventually("waiting for the disk", || status("data").waiting.as_deref() == Some(waiting.as_str()));
+    fs::create_dir(sandbox.file("data")).unwrap();
+    sandbox.wait_for("data", DONE);
+}
+
+#[test]
+fn respawn_with_dead_companion() {
+    let web = |recheck: &str| {
+        let cmd = format!("sh -c 'echo run >> $SANDBOX/{recheck}; while [ ! -e $SANDBOX/crash ]; do sleep 0.01; done; exit 1'");
+        format!("name: {recheck}\ncmd: {cmd}\nwith: db\nrespawn: 1\nrespawn_recheck: {recheck}\n")
+    };
+    let sandbox = Sandbox::boot(&[
+        ("db.task", &format!("name: db\ncmd: {}\n", gated("db-down"))),
+        ("none.task", &web("none")),
+        ("with.task", &web("with")),
+        ("all.task", &web("all")),
+    ]);
+    for recheck in ["none", "with", "all"] {
+        eventually("first run", || sandbox.read(recheck) == "run\n");
+    }
+    fs::write(sandbox.file("db-down"), "").unwrap();
+    sandbox.wait_for("db", DONE);
+    fs::write(sandbox.file("crash"), "").unwrap();
+
+    // Restarted without its companion, crashes again and gives up
+    eventually("respawn", || sandbox.read("none") == "run\nrun\n");
+    sandbox.wait_for("none", TaskState::Concluded(ExitReason::Failed));
+
+    let waiting = |name: &str| {
+        let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+        tasks.into_iter().find(|task| task.name == name).unwrap().waiting
+    };
+    for recheck in ["with", "all"] {
+        eventually("respawn to wait", || waiting(recheck).as_deref() == Some("respawning: waiting for companion db"));
+        assert_eq!(sandbox.read(recheck), "run\n");
+    }
+    assert_eq!(waiting("db"), None);
+}
+
+#[test]
+fn any_member() {
+    let gate = gated("go");
+    let sandbox = Sandbox::boot_with_defaults(
+        "groups:\n  net: any\n",
+        &[
+            ("slow.task", &format!("name: slow\ncmd: {gate}\ngroup: net\nprovides: uplink\n")),
+            ("fast.task", "name: fast\ncmd: sh -c 'sleep 0.1'\ngroup: net\nprovides: uplink\n"),
+            ("user.task", "name: user\ncmd: \"true\"\nafter: feature::uplink\n"),
+        ],
+    );
+    sandbox.wait_for("group::net", DONE);
+    sandbox.wait_for("user", DONE);
+    assert_eq!(sandbox.state("feature::uplink"), DONE);
+    assert!(sandbox.state("slow").is_running());
+    std::fs::write(sandbox.file("go"), "").unwrap();
+    sandbox.wait_for("slow", DONE);
+}
+
+#[test]
+fn stop_and_start_group() {
+    let sandbox = Sandbox::boot(&[
+        ("looper.task", "name: looper\ncmd: sh -c 'echo x >> $SANDBOX/runs; sleep 0.05'\nrespawn: 1000\ngroup: net\n"),
+        ("once.task", "name: once\ncmd: touch $SANDBOX/once\ngroup: net\n"),
+    ]);
+    eventually("a few runs", || sandbox.read("runs").len() >= 6);
+    sandbox.wait_for("once", DONE);
+
+    // Respawning is not enough to come back
+    sandbox.perform("stop group::net").unwrap();
+    sandbox.wait_until("looper", TaskState::has_concluded);
+    let runs = sandbox.read("runs");
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(sandbox.read("runs"), runs);
+    assert!(sandbox.state("looper").has_concluded());
+
+    // Members that concluded before run again
+    std::fs::remove_file(sandbox.file("once")).unwrap();
+    sandbox.perform("start group::net").unwrap();
+    eventually("the next run", || sandbox.read("runs").len() > runs.len());
+    eventually("once to run again", || sandbox.file("once").exists());
+    sandbox.wait_until("group::net", |state| state.is_running() || *state == DONE);
+}
+
+#[test]
+fn unknown_task() {
+    let sandbox = Sandbox::boot(&[("sleeper.task", "name: sleeper\ncmd: sleep 1000\n")]);
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+    let error = sandbox.perform("kill sleepr").unwrap_err();
+    assert_eq!(error.to_string(), "Task does not exist 'sleepr', did you mean 'sleeper'?");
+}
+
+#[test]
+fn version() {
+    let sandbox = Sandbox::boot(&[("a.task", "name: a\ncmd: \"true\"\n")]);
+    let info: VersionInfo = serde_json::from_str(&sandbox.perform("version").unwrap()).unwrap();
+    assert_eq!(info.tasks, Some(sandbox.tasks.0.len()));
+    assert!(info.config.is_some());
+    assert!(!info.differs(&VersionInfo::current()));
+}
+
+/// A process alfad did not start, `sleep 1000` or the like. Killed when
+/// dropped, unless alfad reaped it already.
+struct Outsider(Child);
+
+impl Outsider {
+    fn spawn(seconds: &str) -> Self {
+        let child = Command::new("sleep").arg(seconds).spawn().unwrap();
+        let cmdline = format!("/proc/{}/cmdline", child.id());
+        eventually("exec", || fs::read(&cmdline).unwrap_or_default().starts_with(b"sleep"));
+        Self(child)
+    }
+
+    fn pid(&self) -> i32 {
+        self.0.id() as i32
+    }
+}
+
+impl Drop for Outsider {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+#[test]
+fn adopt_and_respawn() {
+    let sandbox = Sandbox::boot(&[(
+        "sleeper.task",
+        "name: sleeper\ncmd: sh -c 'echo started >> $SANDBOX/runs; exec sleep 1000'\nrespawn: 1\nadopt: {match: '^sleep 100[01]$'}\n",
+    )]);
+    eventually("first start", || sandbox.read("runs") == "started\n");
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+    let own = sandbox.task("sleeper").child.get().unwrap().pid;
+    sandbox.perform("stop sleeper").unwrap();
+    sandbox.wait_until("sleeper", TaskState::has_concluded);
+
+    let wrong = Outsider::spawn("999");
+    let result = sandbox.perform(&format!("adopt sleeper {}", wrong.pid()));
+    assert!(matches!(result, Err(ActionError::Adopt(AdoptError::Mismatch { .. }))));
+
+    let outsider = Outsider::spawn("1001");
+    let pid = outsider.pid();
+    sandbox.perform(&format!("adopt sleeper {pid}")).unwrap();
+    eventually("adoption", || sandbox.task("sleeper").child.get().map(|child| child.pid) == Some(pid));
+    assert!(sandbox.state("sleeper").is_running());
+    assert!(matches!(sandbox.perform(&format!("adopt sleeper {pid}")), Err(ActionError::Adopt(AdoptError::Running(_)))));
+    assert_eq!(sandbox.read("runs"), "started\n");
+
+    // Exits like any other process, the respawn starts the payload again
+    kill(Pid::from_raw(pid), Signal::SIGKILL).unwrap();
+    eventually("respawn", || sandbox.read("runs") == "started\nstarted\n");
+    let respawned = |child: ChildProcess| child.pid != pid && child.pid != own;
+    eventually("respawned child", || sandbox.task("sleeper").child.get().is_some_and(respawned));
+}
+
+#[test]
+fn adopt_from_pidfile() {
+    let run = tempfile::tempdir().unwrap();
+    let outsider = Outsider::spawn("1000");
+    let pid = outsider.pid();
+    fs::write(run.path().join("sleeper.pid"), format!("{pid}\n")).unwrap();
+    let sandbox = Sandbox::boot(&[(
+        "sleeper.task",
+        &format!(
+            "name: sleeper\ncmd: touch $SANDBOX/started\nadopt: {{match: '^sleep 1000$', pidfile: {}/sleeper.pid}}\n",
+            run.path().display()
+        ),
+    )]);
+    eventually("adoption", || sandbox.task("sleeper").child.get().map(|child| child.pid) == Some(pid));
+    sandbox.perform("kill sleeper").unwrap();
+    sandbox.wait_for("sleeper", TaskState::Concluded(ExitReason::Terminated));
+    assert!(!sandbox.file("started").exists());
+}
+
+#[test]
+fn log_cmd() {
+    let sandbox = Sandbox::boot(&[(
+        "yes.task",
+        "name: yes\ncmd: sh -c 'yes | head -n 1000; echo done >&2'\nlog_cmd: sh -c 'cat > $SANDBOX/log'\n",
+    )]);
+    sandbox.wait_for("yes", DONE);
+    // The logger gets the end of the pipe once the task is done
+    eventually("whole output", || sandbox.read("log").lines().count() == 1001);
+    assert!(sandbox.read("log").starts_with("y\ny\n"));
+    assert!(sandbox.read("log").ends_with("y\ndone\n"));
+    eventually("logger to exit", || sandbox.task("yes").logger.get().is_none());
+}
+
+#[test]
+fn log_rate_limit() {
+    let sandbox = Sandbox::boot(&[(
+        "yes.task",
+        "name: yes\ncmd: sh -c 'yes | head -n 1000; echo done >&2'\nlog_cmd: sh -c 'cat > $SANDBOX/log'\n\
+         log_rate_limit: {lines: 10, per: 1h}\nlog_timestamps: true\n",
+    )]);
+    sandbox.wait_for("yes", DONE);
+    eventually("summary", || sandbox.read("log").ends_with("alfad: suppressed 991 lines\n"));
+    let log = sandbox.read("log");
+    let lines: Vec<_> = log.lines().map(|line| line.split_once("] ").unwrap().1).collect();
+    assert_eq!(lines, [vec!["y"; 10], vec!["alfad: suppressed 991 lines"]].concat());
+    assert!(log.starts_with('['), "{log}");
+    assert_eq!(sandbox.task("yes").log_suppressed.load(Ordering::Relaxed), 991);
+    assert!(sandbox.perform("list").unwrap().contains(r#""suppressed_lines":991"#));
+}
+
+#[test]
+fn logger_restart() {
+    let sandbox = Sandbox::boot(&[(
+        "talker.task",
+        "name: talker\ncmd: sh -c 'echo one; while [ ! -e $SANDBOX/go ]; do sleep 0.01; done; echo two'\n\
+         log_cmd: sh -c 'exec cat >> $SANDBOX/log'\n",
+    )]);
+    eventually("first line", || sandbox.read("log") == "one\n");
+    eventually("logger", || sandbox.task("talker").logger.get().is_some());
+    let logger = sandbox.task("talker").logger.get().unwrap().pid;
+    kill(Pid::from_raw(logger), Signal::SIGKILL).unwrap();
+    eventually("logger to die", || sandbox.task("talker").logger.get().is_none());
+
+    // Written and the task done while no logger runs, the pipe keeps it
+    fs::write(sandbox.file("go"), "").unwrap();
+    sandbox.wait_for("talker", DONE);
+    eventually("second line", || sandbox.read("log") == "one\ntwo\n");
+}
+
+#[test]
+fn fd_hygiene() {
+    // Neither close-on-exec nor passed on on purpose
+    let leaked = nix::unistd::dup(2).unwrap();
+    let sandbox = Sandbox::boot(&[("fds.task", "name: fds\ncmd: sh -c 'exec ls /proc/self/fd > $SANDBOX/fds'\n")]);
+    sandbox.wait_for("fds", DONE);
+    nix::unistd::close(leaked).unwrap();
+    // 3 is where ls reads the directory
+    assert_eq!(sandbox.read("fds"), "0\n1\n2\n3\n");
+}
+
+/// Task files run builtins by their key, and copies of the configuration
+/// run them the same way
+#[test]
+fn builtin_references() {
+    let sandbox = Sandbox::boot(&[
+        ("clock.task", "name: clock\ncmd: {builtin: time-sync-wait}\n"),
+        ("sweeper.task", "name: sweeper\ncmd: {builtin: sweep}\n"),
+    ]);
+    sandbox.wait_for("clock", DONE);
+    sandbox.wait_until("sweeper", TaskState::is_running);
+    sandbox.perform("stop sweeper").unwrap();
+    sandbox.wait_for("sweeper", TaskState::Concluded(ExitReason::Terminated));
+
+    let copy = task::start(vec![sandbox.task("clock").config.clone()]);
+    assert_eq!(*copy.0["clock"].config, *sandbox.task("clock").config);
+    block_on_timeout("copy of clock", copy.wait_for("clock", DONE)).unwrap();
+}
+
+#[test]
+fn task_uses_ctl() {
+    // The only test with the control pipe, its location is set once per process
+    let run = tempfile::tempdir().unwrap();
+    ctl::set_ctl_dir(run.path().to_owned()).unwrap();
+    let ctl_builtins = builtin::all().into_iter().filter(|config| config.name.starts_with("builtin::ctl::")).collect();
+    let pipe = run.path().join("alfad-ctl").to_string_lossy().into_owned();
+    let sandbox = Sandbox::boot_with(
+        &[
+            ("run.task", "name: run\ncmd: \"true\"\nprovides: fs::run\n"),
+            ("victim.task", &format!("name: victim\ncmd: {}\n", gated("never"))),
+            ("killer.task", &format!("name: killer\ncmd: sh -c 'echo kill victim > {pipe}'\nafter: feature::ctl\n")),
+        ],
+        ctl_builtins,
+    );
+    sandbox.wait_for("killer", DONE);
+    sandbox.wait_for("victim", TaskState::Concluded(ExitReason::Terminated));
+    assert_eq!(sandbox.state("builtin::ctl::ready"), DONE);
+}
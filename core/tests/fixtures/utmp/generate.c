@@ -0,0 +1,33 @@
+/*
+ * Writes the utmp records alfad is expected to produce with glibc's own
+ * struct utmp and updwtmp(), one record per file:
+ *
+ *     cc -o /tmp/generate generate.c && (cd tests/fixtures/utmp && /tmp/generate)
+ */
+#include <fcntl.h>
+#include <string.h>
+#include <unistd.h>
+#include <utmp.h>
+
+static void record(const char *file, short type, pid_t pid, const char *user) {
+    struct utmp ut;
+    memset(&ut, 0, sizeof(ut));
+    ut.ut_type = type;
+    ut.ut_pid = pid;
+    strncpy(ut.ut_line, "~", sizeof(ut.ut_line));
+    strncpy(ut.ut_id, "~~", sizeof(ut.ut_id));
+    strncpy(ut.ut_user, user, sizeof(ut.ut_user));
+    strncpy(ut.ut_host, "6.1.0-alfad", sizeof(ut.ut_host));
+    ut.ut_tv.tv_sec = 1700000000;
+    ut.ut_tv.tv_usec = 123456;
+    /* updwtmp() only appends to existing files */
+    close(open(file, O_WRONLY | O_CREAT | O_TRUNC, 0644));
+    updwtmp(file, &ut);
+}
+
+int main(void) {
+    record("boot.bin", BOOT_TIME, 0, "reboot");
+    record("runlevel.bin", RUN_LVL, 'N' * 256 + '5', "runlevel");
+    record("shutdown.bin", RUN_LVL, '5' * 256 + '0', "shutdown");
+    return 0;
+}
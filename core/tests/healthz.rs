@@ -0,0 +1,72 @@
+//! The probes of builtin::healthz over a socket, while the sandbox boots
+#![cfg(feature = "healthz")]
+
+mod common;
+
+use alfad::builtin::{
+    self,
+    boot::BOOT_COMPLETE,
+    ctl,
+    healthz::{self, Readiness},
+};
+use common::{eventually, Sandbox};
+use smol::net::TcpListener;
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+/// Status and body of `GET path`
+fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {path} HTTP/1.0\r\nUser-Agent: kube-probe/1.30\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.strip_prefix("HTTP/1.0 ").and_then(|line| line[..3].parse().ok()).unwrap();
+    (status, body.to_owned())
+}
+
+fn readiness(addr: SocketAddr) -> (u16, Readiness) {
+    let (status, body) = get(addr, "/readyz");
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+#[test]
+fn probes_across_boot() {
+    let run = tempfile::tempdir().unwrap();
+    ctl::set_ctl_dir(run.path().to_owned()).unwrap();
+    let builtins = builtin::all()
+        .into_iter()
+        .filter(|config| config.name.starts_with("builtin::ctl::") || config.name == BOOT_COMPLETE)
+        .collect();
+    let sandbox = Sandbox::boot_with(
+        &[
+            ("run.task", "name: run\ncmd: \"true\"\nprovides: fs::run\n"),
+            ("db.task", "name: db\ncmd: sh -c 'while [ ! -e $SANDBOX/go ]; do sleep 0.01; done'\n"),
+            ("web.task", "name: web\ncmd: \"true\"\nafter: db\n"),
+        ],
+        builtins,
+    );
+    let listener = smol::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    smol::spawn(healthz::serve(listener, sandbox.tasks, BOOT_COMPLETE.to_owned())).detach();
+
+    // Alive once alfad can be controlled, not ready while web waits for db
+    eventually("ctl daemon", || get(addr, "/healthz").0 == 200);
+    let (status, waiting) = readiness(addr);
+    assert_eq!(status, 503);
+    assert_eq!(waiting, Readiness { ready: false, marker: BOOT_COMPLETE.into(), unfinished: vec!["web".into()] });
+
+    fs::write(sandbox.file("go"), "").unwrap();
+    eventually("boot to complete", || readiness(addr).0 == 200);
+    assert!(readiness(addr).1.unfinished.is_empty());
+    assert_eq!(get(addr, "/nope").0, 404);
+
+    // Still ready, but no longer alive without the control pipe
+    sandbox.perform(&format!("stop {}", ctl::DAEMON)).unwrap();
+    eventually("ctl daemon to stop", || get(addr, "/healthz").0 == 503);
+    assert_eq!(get(addr, "/healthz").1, "builtin::ctl::daemon is Terminated\n");
+    assert_eq!(readiness(addr).0, 200);
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use alfad::{action::SystemCommand, clock, perform_action, task::TaskState};
+use common::{eventually, Sandbox};
+use nix::errno::Errno;
+use std::{sync::Mutex, thread, time::Duration};
+
+static REBOOTED: Mutex<Option<SystemCommand>> = Mutex::new(None);
+
+fn reboot(command: &SystemCommand) -> Errno {
+    *REBOOTED.lock().unwrap() = Some(command.clone());
+    Errno::UnknownErrno
+}
+
+/// Let the timers that expired run, then check nothing was rebooted
+fn still_up() {
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(*REBOOTED.lock().unwrap(), None);
+}
+
+#[test]
+fn inhibited_poweroff() {
+    clock::use_virtual_time();
+    perform_action::set_reboot(reboot);
+    let sandbox = Sandbox::boot(&[("sleeper.task", "name: sleeper\ncmd: sleep 1000\n")]);
+    sandbox.wait_until("sleeper", TaskState::is_running);
+
+    // Refused while blocked, until the lease expires
+    let upgrade = sandbox.perform("inhibit shutdown block +10s upgrade Installing packages").unwrap();
+    let error = sandbox.perform("system poweroff").unwrap_err().to_string();
+    assert!(error.starts_with(&format!("poweroff is inhibited by #{upgrade} upgrade (block shutdown")), "{error}");
+    assert!(sandbox.perform("inhibitors").unwrap().contains("Installing packages"));
+    clock::advance(Duration::from_secs(10));
+    assert_eq!(sandbox.perform("inhibitors").unwrap(), "No inhibitors");
+    assert!(sandbox.perform(&format!("uninhibit {upgrade}")).is_err());
+
+    // Held back by a delaying lease until its time to live is over, which
+    // is shorter than the default maximum delay
+    sandbox.perform("inhibit shutdown delay +20s backup Nightly backup").unwrap();
+    assert_eq!(sandbox.perform("system poweroff").unwrap(), "poweroff now");
+    still_up();
+    clock::advance(Duration::from_secs(10));
+    still_up();
+    for _ in 0..10 {
+        clock::advance(Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(20));
+    }
+    eventually("poweroff", || REBOOTED.lock().unwrap().is_some());
+    assert_eq!(*REBOOTED.lock().unwrap(), Some(SystemCommand::Poweroff));
+}
@@ -0,0 +1,38 @@
+//! alfad as a normal user, with tasks that need more than that
+
+mod common;
+
+use alfad::{
+    privilege::{self, Privileges},
+    status::TaskStatus,
+    task::{ExitReason, TaskState},
+    version::VersionInfo,
+};
+use common::Sandbox;
+
+#[test]
+fn skipped_without_privileges() {
+    // Whatever the tests run as, the probe found a normal user
+    privilege::set_current(Privileges::NONE).unwrap();
+    let sandbox = Sandbox::boot(&[
+        ("mount.task", "name: mount\ncmd: touch $SANDBOX/mounted\nrequires_privileges: mount\nrespawn: 0\n"),
+        ("after.task", "name: after\ncmd: \"true\"\nafter: mount\n"),
+        ("plain.task", "name: plain\ncmd: touch $SANDBOX/plain\n"),
+    ]);
+    sandbox.wait_for("mount", TaskState::Concluded(ExitReason::Skipped));
+    sandbox.wait_for("plain", TaskState::Concluded(ExitReason::Done));
+    sandbox.wait_for("after", TaskState::Waiting);
+    assert!(!sandbox.file("mounted").exists());
+    assert!(sandbox.file("plain").exists());
+
+    let tasks: Vec<TaskStatus> = serde_json::from_str(&sandbox.perform("list").unwrap()).unwrap();
+    let mount = tasks.iter().find(|task| task.name == "mount").unwrap();
+    assert_eq!(mount.state, "Skipped");
+    assert_eq!(mount.reason.as_deref(), Some("Needs mount (CAP_SYS_ADMIN), which alfad runs without"));
+    // Not respawned
+    assert_eq!(sandbox.state("mount"), TaskState::Concluded(ExitReason::Skipped));
+
+    let info: VersionInfo = serde_json::from_str(&sandbox.perform("version").unwrap()).unwrap();
+    assert_eq!(info.privileges, Some(Vec::new()));
+    assert!(info.to_string().ends_with(", no privileges"), "{info}");
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use alfad::{
+    action::SystemCommand,
+    clock, perform_action,
+    task::{ContextMap, TaskState},
+};
+use common::{eventually, Sandbox};
+use nix::errno::Errno;
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+static TASKS: OnceLock<ContextMap<'static>> = OnceLock::new();
+/// The command the stub was called with and whether all tasks had concluded by then
+static REBOOTED: Mutex<Option<(SystemCommand, bool)>> = Mutex::new(None);
+
+fn reboot(command: &SystemCommand) -> Errno {
+    let concluded = TASKS.get().unwrap().0.values().all(|task| smol::block_on(task.state()).has_concluded());
+    *REBOOTED.lock().unwrap() = Some((command.clone(), concluded));
+    Errno::UnknownErrno
+}
+
+#[test]
+fn delayed_poweroff() {
+    clock::use_virtual_time();
+    perform_action::set_reboot(reboot);
+    let sandbox = Sandbox::boot(&[("sleeper.task", "name: sleeper\ncmd: sleep 1000\n")]);
+    TASKS.set(sandbox.tasks).unwrap();
+    sandbox.wait_until("sleeper", TaskState::is_running);
+    eventually("sleeper to start", || sandbox.task("sleeper").child.get().is_some());
+
+    assert_eq!(sandbox.perform("system poweroff +60s").unwrap(), "poweroff in 60s");
+    clock::advance(Duration::from_secs(59));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(*REBOOTED.lock().unwrap(), None);
+    assert!(sandbox.state("sleeper").is_running());
+
+    clock::advance(Duration::from_secs(1));
+    eventually("poweroff", || REBOOTED.lock().unwrap().is_some());
+    assert_eq!(*REBOOTED.lock().unwrap(), Some((SystemCommand::Poweroff, true)));
+}
@@ -0,0 +1,180 @@
+//! The binary as a user instance, in a temporary XDG directory
+
+mod common;
+
+use common::eventually;
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        stat::Mode,
+    },
+    unistd::{mkfifo, Pid},
+};
+use std::{
+    fs::{self, File},
+    path::Path,
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const ALFAD: &str = env!("CARGO_BIN_EXE_alfad");
+
+/// `alfad` with the environment of a session in `dir`
+fn alfad(dir: &Path) -> Command {
+    let mut command = Command::new(ALFAD);
+    command
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("HOME", dir.join("home"))
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("XDG_RUNTIME_DIR", dir.join("runtime"))
+        .env("USER", "tester")
+        .current_dir(dir);
+    command
+}
+
+fn ctl(dir: &Path, args: &[&str]) -> Output {
+    alfad(dir).args(["alfad-ctl", "--user"]).args(args).output().unwrap()
+}
+
+/// Kills the instance if the test fails before stopping it
+struct Session(Child);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn wait_exit(child: &mut Child, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if child.try_wait().unwrap().is_some() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn user_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let tasks = path.join("config/alfad/alfad.d");
+    fs::create_dir_all(&tasks).unwrap();
+    fs::create_dir_all(path.join("runtime")).unwrap();
+    let file = |name: &str| path.join(name).to_string_lossy().into_owned();
+    fs::write(tasks.join("once.task"), format!("name: once\ncmd: sh -c 'echo run >> {}'\n", file("runs"))).unwrap();
+    fs::write(tasks.join("sleeper.task"), "name: sleeper\ncmd: sleep 1000\n").unwrap();
+
+    let log = File::create(path.join("log")).unwrap();
+    let mut session = Session(alfad(path).arg("user-session").stderr(log).spawn().unwrap());
+    eventually("the first run", || fs::read_to_string(path.join("runs")).is_ok_and(|runs| runs == "run\n"));
+    eventually("the control pipe", || ctl(path, &["cat", "sleeper"]).status.success());
+
+    let started = ctl(path, &["start", "once"]);
+    assert!(started.status.success(), "{}", String::from_utf8_lossy(&started.stderr));
+    eventually("the second run", || fs::read_to_string(path.join("runs")).is_ok_and(|runs| runs == "run\nrun\n"));
+
+    // The end of the session stops the tasks and the instance
+    kill(Pid::from_raw(session.0.id() as i32), Signal::SIGTERM).unwrap();
+    assert!(wait_exit(&mut session.0, Duration::from_secs(10)), "The user instance did not exit");
+    assert!(session.0.wait().unwrap().success());
+    assert!(!ctl(path, &["cat", "sleeper"]).status.success());
+
+    // Every record starts with the tag, an untagged one with its timestamp
+    let log = fs::read_to_string(path.join("log")).unwrap();
+    assert!(log.lines().any(|line| line.starts_with("[user:tester] ") && line.contains("Stopping the user instance")), "{log}");
+    assert!(!log.lines().any(|line| line.trim_start_matches("\x1b[2m").starts_with(|c: char| c.is_ascii_digit())), "{log}");
+}
+
+/// Failure bundles and disabled tasks stay in the session's state directory
+#[test]
+fn state_in_home() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let tasks = path.join("config/alfad/alfad.d");
+    fs::create_dir_all(&tasks).unwrap();
+    fs::create_dir_all(path.join("runtime")).unwrap();
+    let defaults = "collect_failure_data: true\npersist_disabled: true\n";
+    fs::write(path.join("config/alfad/defaults.yaml"), defaults).unwrap();
+    fs::write(tasks.join("fails.task"), "name: fails\ncmd: sh -c 'exit 3'\n").unwrap();
+    fs::write(tasks.join("sleeper.task"), "name: sleeper\ncmd: sleep 1000\n").unwrap();
+
+    let log = File::create(path.join("log")).unwrap();
+    let _session = Session(alfad(path).arg("user-session").stderr(log).spawn().unwrap());
+    let state = path.join("home/.local/state/alfad");
+    eventually("the failure bundle", || {
+        fs::read_dir(state.join("failures"))
+            .is_ok_and(|mut bundles| bundles.any(|bundle| bundle.unwrap().file_name().to_string_lossy().starts_with("fails-")))
+    });
+    eventually("the control pipe", || ctl(path, &["cat", "sleeper"]).status.success());
+    let disabled = ctl(path, &["disable", "sleeper"]);
+    assert!(disabled.status.success(), "{}", String::from_utf8_lossy(&disabled.stderr));
+    assert_eq!(fs::read_to_string(state.join("disabled")).unwrap(), "sleeper\n");
+}
+
+/// What happened at the end is on disk once the instance is gone
+#[test]
+fn shutdown_epilogue() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let tasks = path.join("config/alfad/alfad.d");
+    fs::create_dir_all(&tasks).unwrap();
+    fs::create_dir_all(path.join("runtime")).unwrap();
+    let events = path.join("events.log");
+    fs::write(path.join("config/alfad/defaults.yaml"), format!("event_log: {}\n", events.display())).unwrap();
+    fs::write(tasks.join("sleeper.task"), "name: sleeper\ncmd: sleep 1000\n").unwrap();
+    let trapped = path.join("trapped");
+    let stubborn = format!("name: stubborn\ncmd: sh -c 'trap \"\" TERM; touch {}; exec sleep 1000'\n", trapped.display());
+    fs::write(tasks.join("stubborn.task"), stubborn).unwrap();
+
+    let log = File::create(path.join("log")).unwrap();
+    let mut session = Session(alfad(path).arg("user-session").stderr(log).spawn().unwrap());
+    let running = |name: &str| {
+        String::from_utf8_lossy(&ctl(path, &["list"]).stdout)
+            .lines()
+            .any(|line| line.split_whitespace().take(2).eq([name, "Running"]))
+    };
+    eventually("the tasks to run", || running("sleeper") && trapped.exists());
+
+    kill(Pid::from_raw(session.0.id() as i32), Signal::SIGTERM).unwrap();
+    assert!(wait_exit(&mut session.0, Duration::from_secs(10)), "The user instance did not exit");
+
+    let events = fs::read_to_string(&events).unwrap();
+    let last = |task: &str| events.lines().rfind(|line| line.contains(&format!("\"task\":\"{task}\""))).unwrap_or_default();
+    for task in ["sleeper", "stubborn"] {
+        assert!(last(task).contains(r#""state":{"Concluded":"Terminated"}"#), "{events}");
+    }
+    let log = fs::read_to_string(path.join("log")).unwrap();
+    assert!(log.contains("Shutdown complete, 1 tasks stopped cleanly, 1 forced"), "{log}");
+}
+
+#[test]
+fn ctl_while_starting() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path();
+    let tasks = path.join("config/alfad/alfad.d");
+    fs::create_dir_all(&tasks).unwrap();
+    fs::create_dir_all(path.join("runtime")).unwrap();
+    fs::write(tasks.join("once.task"), "name: once\ncmd: echo once\n").unwrap();
+
+    let not_running = ctl(path, &["cat", "once"]);
+    assert_eq!(not_running.status.code(), Some(5));
+    assert!(String::from_utf8_lossy(&not_running.stderr).contains("alfad does not appear to be running"));
+
+    // A control pipe nobody reads yet, alfad takes it over once it is up
+    mkfifo(&path.join("runtime/alfad/alfad-ctl"), Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+    let mut early = alfad(path);
+    let early = early.args(["alfad-ctl", "--user", "cat", "once"]).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let _session = Session(alfad(path).arg("user-session").stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+    let output = early.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("alfad is running but the control channel is not up yet, retrying for 5s"), "{stderr}");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("echo once"));
+}
@@ -0,0 +1,16 @@
+//! Lines as they arrive on the ctl pipe
+#![no_main]
+
+use alfad::action::Action;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|line: &str| {
+    let Ok(action) = line.parse::<Action>() else {
+        return;
+    };
+    // Aliases like poweroff are sent as `system`, so only the rendered
+    // form has to survive another round
+    let rendered = action.to_string();
+    let reparsed: Action = rendered.parse().unwrap_or_else(|error| panic!("{rendered:?} does not parse: {error}"));
+    assert_eq!(reparsed.to_string(), rendered);
+});
@@ -0,0 +1,15 @@
+//! A single `cmd` line from a task file
+#![no_main]
+
+use alfad::command_line::CommandLine;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|line: &str| {
+    let Ok(parsed) = line.parse::<CommandLine>() else {
+        return;
+    };
+    let rendered = parsed.to_string();
+    let reparsed: CommandLine = rendered.parse().unwrap_or_else(|error| panic!("{rendered:?} does not parse: {error}"));
+    assert_eq!(reparsed.to_string(), rendered);
+    assert_eq!(reparsed.program(), parsed.program());
+});
@@ -0,0 +1,11 @@
+//! A whole `cmd` block from a task file
+#![no_main]
+
+use alfad::command_line::CommandLines;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|block: &str| {
+    if let Ok(lines) = block.parse::<CommandLines>() {
+        assert_eq!(lines.len(), block.lines().count());
+    }
+});
@@ -0,0 +1,23 @@
+//! Variable expansion with variables that refer to each other
+#![no_main]
+
+use alfad::command_line::{insert_envvars, MAX_ARG_LENGTH};
+use libfuzzer_sys::fuzz_target;
+use std::{env, sync::Once};
+
+static ENV: Once = Once::new();
+
+fuzz_target!(|s: &str| {
+    ENV.call_once(|| {
+        env::set_var("FUZZ_EMPTY", "");
+        env::set_var("FUZZ_WORD", "word");
+        env::set_var("FUZZ_SELF", "$FUZZ_SELF");
+        env::set_var("FUZZ_DOUBLE", "$FUZZ_DOUBLE$FUZZ_DOUBLE");
+        env::set_var("FUZZ_PING", "a$FUZZ_PONG");
+        env::set_var("FUZZ_PONG", "b$FUZZ_PING");
+        env::set_var("FUZZ_NEST", "$FUZZ_WORD$FUZZ_EMPTY$FUZZ_");
+    });
+    if let Ok(expanded) = insert_envvars(s) {
+        assert!(expanded.len() <= MAX_ARG_LENGTH);
+    }
+});
@@ -0,0 +1,15 @@
+//! The `name` of a task file, which also names its files
+#![no_main]
+
+use alfad::config::name::{check, file_name, MAX_FILE_NAME};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|name: &str| {
+    let file = file_name(name);
+    if check(name).is_ok() {
+        assert_eq!(file, name);
+    }
+    assert!(!file.is_empty() && file.len() <= MAX_FILE_NAME, "{name:?} -> {file:?}");
+    assert!(!file.starts_with(['.', '-']), "{name:?} -> {file:?}");
+    assert!(file.chars().all(|c| c.is_ascii_graphic() && c != '/'), "{name:?} -> {file:?}");
+});